@@ -53,7 +53,7 @@ if [ -f "Cargo.toml.main" ]; then
 fi
 
 # Build with wasm-pack (skip wasm-opt if it's incompatible)
-WASM_PACK_NO_OPT_COMPATIBILITY_MODE=1 wasm-pack build --target web --out-dir wasm-pkg --no-opt
+WASM_PACK_NO_OPT_COMPATIBILITY_MODE=1 wasm-pack build --target web --out-dir wasm-pkg --no-opt -- --features json
 
 log_info "Attempting to optimize WASM binary..."
 if wasm-opt -Oz \
@@ -0,0 +1,63 @@
+//! Audio output of the browser frontends
+//!
+//! The SPU output is resampled to the rate of the browser's `AudioContext` and, when the emulation
+//! speed is locked to the display (see `display_sync`), stretched to match the actual speed.
+
+use crate::resampler::{OutputResampler, RateControl};
+
+pub struct AudioProcessor {
+    buffer: Vec<i16>,
+    resampler: OutputResampler,
+}
+
+impl AudioProcessor {
+    /// Create a processor outputting at `output_rate`, use `resampler::NATIVE_RATE` to get the
+    /// samples untouched. The input is always the SPU's native 44.1kHz.
+    pub fn with_output_rate(output_rate: u32) -> Self {
+        Self {
+            buffer: Vec::with_capacity(4096),
+            resampler: OutputResampler::new(output_rate),
+        }
+    }
+
+    pub fn set_output_rate(&mut self, output_rate: u32) {
+        if output_rate != self.resampler.output_rate() {
+            self.resampler.set_output_rate(output_rate);
+        }
+    }
+
+    /// Enable or disable dynamic rate control, see `update_fill`
+    pub fn set_rate_control(&mut self, enable: bool) {
+        self.resampler.set_rate_control(if enable {
+            Some(RateControl::default())
+        } else {
+            None
+        });
+    }
+
+    /// Set the emulation speed relative to real time so that the audio keeps playing in real time
+    pub fn set_speed(&mut self, speed: f64) {
+        self.resampler.set_speed(speed);
+    }
+
+    /// Feed the fill level of the audio ring buffer (in samples) to the rate control
+    pub fn update_fill(&mut self, fill: usize, target: usize) {
+        self.resampler.update_fill(fill, target);
+    }
+
+    /// Push native 44.1kHz stereo samples, they're resampled to the output rate right away
+    pub fn push_samples(&mut self, samples: &[i16]) {
+        if let Err(e) = self.resampler.process(samples, &mut self.buffer) {
+            log::warn!("Audio resampling failed: {}", e);
+            self.resampler.reset();
+        }
+    }
+
+    pub fn get_output_samples(&mut self) -> Vec<f32> {
+        self.buffer.iter().map(|&s| s as f32 / 32768.0).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+}
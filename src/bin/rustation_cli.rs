@@ -0,0 +1,6 @@
+//! Command line front-end, see `rustation_ng::cli`. It's a binary of the libretro core crate
+//! (`src/lib.rs`) and needs its `cli` feature, the WASM package doesn't build it.
+
+fn main() {
+    std::process::exit(rustation_ng::cli::main());
+}
@@ -0,0 +1,142 @@
+//! Command line tools
+//!
+//! Offline front-end for the tasks that don't need a libretro frontend. The BIOS and CDC
+//! firmware are looked for in the directory given with `--system-dir` (the current directory by
+//! default), exactly like the libretro core does with the frontend's system directory.
+
+mod movie;
+mod psf;
+mod replay;
+mod rip;
+
+use crate::error::{PsxError, Result};
+use std::path::PathBuf;
+
+const USAGE: &str = "\
+Usage: rustation-cli <command> [options]
+
+Commands:
+  movie <disc> --file <path> [-o <dir>] [--format png|raw] [--channel <n>]
+      Decode an STR movie from the disc's filesystem to a PNG sequence, or to a single raw
+      RGB24 stream with --format raw. The interleaved XA audio is written to a WAV file.
+  psf <file> [-o <out.wav>] [--length <seconds>] [--fade <seconds>] [--rate <hz>]
+      Render a PSF or MINIPSF music rip to a WAV file
+  replay <disc>... --input <movie> [--state <file>]
+      Play an input movie back from power-on or from its starting save state and check
+      that it stays in sync with the recording. Multi-disc games need every disc, in the
+      playlist order. --state gives the starting save state when it's not embedded in
+      the movie (default: <movie>.rsxm.state).
+  rip <disc> [-o <dir>] [--rate native|<hz>] [--only cdda|xa]
+      Dump the CD-DA tracks and XA-ADPCM streams of a disc to WAV files, at their native
+      rate by default. With --rate XA streams go through the CD controller's 44.1kHz filter
+      first.
+
+Common options:
+  --system-dir <dir>   Directory containing the BIOS and CDC firmware (default: .)
+";
+
+/// Entry point for the `rustation-cli` binary, returns the process exit code
+pub fn main() -> i32 {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match run(&args) {
+        Ok(()) => 0,
+        Err(PsxError::BadCommandLine(m)) => {
+            eprintln!("{}\n\n{}", m, USAGE);
+            2
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let (command, args) = match args.split_first() {
+        Some((c, a)) => (c.as_str(), Args::parse(a)?),
+        None => return Err(bad_command_line("missing command")),
+    };
+
+    match command {
+        "movie" => movie::run(args),
+        "psf" => psf::run(args),
+        "replay" => replay::run(args),
+        "rip" => rip::run(args),
+        "help" | "-h" | "--help" => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(bad_command_line(&format!("unknown command '{}'", command))),
+    }
+}
+
+fn bad_command_line(m: &str) -> PsxError {
+    PsxError::BadCommandLine(m.to_string())
+}
+
+/// Command arguments: positional arguments and `--name value` (or `-n value`) options
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Args> {
+        let mut positional = Vec::new();
+        let mut options = Vec::new();
+
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            if arg.starts_with('-') && arg.len() > 1 {
+                let name = arg.trim_start_matches('-').to_string();
+
+                match args.next() {
+                    Some(value) => options.push((name, value.clone())),
+                    None => return Err(bad_command_line(&format!("missing value for {}", arg))),
+                }
+            } else {
+                positional.push(arg.clone());
+            }
+        }
+
+        Ok(Args {
+            positional,
+            options,
+        })
+    }
+
+    /// Returns the single positional argument, named `what` in error messages
+    fn input(&self, what: &str) -> Result<PathBuf> {
+        match self.positional.as_slice() {
+            [p] => Ok(PathBuf::from(p)),
+            [] => Err(bad_command_line(&format!("missing {}", what))),
+            _ => Err(bad_command_line("too many arguments")),
+        }
+    }
+
+    /// Returns the value of the option `long` (or its short form `short`)
+    fn option(&self, long: &str, short: Option<&str>) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(n, _)| n == long || Some(n.as_str()) == short)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Parse the value of option `long`, if present
+    fn parsed_option<T: std::str::FromStr>(&self, long: &str) -> Result<Option<T>> {
+        match self.option(long, None) {
+            Some(v) => v
+                .parse()
+                .map(Some)
+                .map_err(|_| bad_command_line(&format!("invalid value '{}' for --{}", v, long))),
+            None => Ok(None),
+        }
+    }
+
+    fn system_dir(&self) -> PathBuf {
+        PathBuf::from(self.option("system-dir", None).unwrap_or("."))
+    }
+}
@@ -0,0 +1,135 @@
+//! `movie` command: decode an STR movie to a PNG sequence or a raw video stream
+
+use super::{bad_command_line, Args};
+use crate::error::{PsxError, Result};
+use crate::psx::cd::audio_rip;
+use crate::psx::cd::disc::Disc;
+use crate::psx::movie::StrReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+pub fn run(args: Args) -> Result<()> {
+    let path = args.input("disc image")?;
+    let file = match args.option("file", Some("f")) {
+        Some(f) => f.to_string(),
+        None => return Err(bad_command_line("missing --file")),
+    };
+    let out_dir = PathBuf::from(args.option("output", Some("o")).unwrap_or("."));
+
+    let raw = match args.option("format", None) {
+        None | Some("png") => false,
+        Some("raw") => true,
+        Some(f) => {
+            let m = format!("invalid value '{}' for --format", f);
+            return Err(bad_command_line(&m));
+        }
+    };
+
+    let mut disc = Disc::load(&path)?;
+    let mut reader = StrReader::open(&mut disc, &file)?;
+
+    if let Some(channel) = args.parsed_option::<u8>("channel")? {
+        reader.set_audio_channel(channel);
+    }
+
+    std::fs::create_dir_all(&out_dir)?;
+
+    let stem = file
+        .rsplit(|c: char| c == '/' || c == '\\')
+        .next()
+        .unwrap_or("movie")
+        .split(|c: char| c == '.' || c == ';')
+        .next()
+        .unwrap_or("movie")
+        .to_lowercase();
+
+    let duration = reader.duration();
+    let mut raw_out = None;
+    let mut size = None;
+    let mut frame_count = 0u32;
+    let mut audio = Vec::new();
+
+    println!("Decoding {} ({:.1}s)", file, duration);
+
+    for frame in &mut reader {
+        let frame = frame?;
+
+        let dimensions = (frame.width, frame.height);
+        match size {
+            None => size = Some(dimensions),
+            Some(s) if s != dimensions && raw => {
+                let m = format!(
+                    "frame {} is {}x{}, can't change resolution in a raw stream",
+                    frame.number, frame.width, frame.height
+                );
+                return Err(PsxError::BadMovie(m));
+            }
+            Some(_) => (),
+        }
+
+        if raw {
+            if raw_out.is_none() {
+                let out = File::create(out_dir.join(format!("{}.rgb", stem)))?;
+                raw_out = Some(BufWriter::new(out));
+            }
+
+            if let Some(out) = raw_out.as_mut() {
+                out.write_all(&frame.pixels)?;
+            }
+        } else {
+            let out = out_dir.join(format!("{}_{:05}.png", stem, frame.number));
+
+            image::save_buffer(
+                out,
+                &frame.pixels,
+                u32::from(frame.width),
+                u32::from(frame.height),
+                image::ColorType::Rgb8,
+            )?;
+        }
+
+        audio.extend_from_slice(&frame.audio);
+        frame_count += 1;
+    }
+
+    if let Some(mut out) = raw_out {
+        out.flush()?;
+    }
+
+    let (width, height) = match size {
+        Some(s) => s,
+        None => return Err(PsxError::BadMovie("no video frame found".to_string())),
+    };
+
+    // STR movies don't store their frame rate, estimate it from the stream's length
+    let fps = frame_count as f32 / duration;
+
+    println!(
+        "{} {}x{} frames, about {:.2} frames per second",
+        frame_count, width, height, fps
+    );
+
+    if raw {
+        println!(
+            "Convert with: ffmpeg -f rawvideo -pixel_format rgb24 -video_size {}x{} \
+             -framerate {:.3} -i {}.rgb {}.mp4",
+            width, height, fps, stem, stem
+        );
+    }
+
+    if let Some((rate, channels)) = reader.audio_format() {
+        let out = out_dir.join(format!("{}.wav", stem));
+
+        println!(
+            "Audio: {}Hz {} -> {}",
+            rate,
+            if channels == 2 { "stereo" } else { "mono" },
+            out.display()
+        );
+
+        audio_rip::write_wav(&out, channels, rate, &audio)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,88 @@
+//! `psf` command: render a PSF music rip to a WAV file
+
+use super::Args;
+use crate::error::Result;
+use crate::psx::psf::{Fader, Psf};
+use crate::psx::Psx;
+use crate::resampler::{OutputResampler, NATIVE_RATE};
+use crate::{find_bios_in, find_cdc_firmware_in};
+
+pub fn run(args: Args) -> Result<()> {
+    let path = args.input("PSF file")?;
+    let out = match args.option("output", Some("o")) {
+        Some(o) => o.into(),
+        None => path.with_extension("wav"),
+    };
+
+    let psf = Psf::load(&path)?;
+
+    let length_ms = match args.parsed_option::<f32>("length")? {
+        Some(s) => (s * 1000.) as u32,
+        None => psf.length_ms(),
+    };
+    let fade_ms = match args.parsed_option::<f32>("fade")? {
+        Some(s) => (s * 1000.) as u32,
+        None => psf.fade_ms(),
+    };
+    let mut resampler = OutputResampler::new(args.parsed_option("rate")?.unwrap_or(NATIVE_RATE));
+
+    let tags = psf.tags();
+    for (name, value) in tags.iter().filter(|(n, _)| !n.starts_with('_')) {
+        println!("{:>10}: {}", name, value.replace('\n', "\n            "));
+    }
+
+    let system_dir = args.system_dir();
+    let region = psf.region();
+    let bios = find_bios_in(&system_dir, |md| Some(md.region) == region)
+        .or_else(|_| find_bios_in(&system_dir, |_| true))?;
+    let cdc_firmware = find_cdc_firmware_in(&system_dir)?;
+
+    let mut psx = Psx::new_with_psf(&psf, bios, cdc_firmware)?;
+    let mut fader = Fader::new(length_ms, fade_ms);
+
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: resampler.output_rate(),
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut wav = hound::WavWriter::create(&out, spec)?;
+
+    let total = fader.total_samples() * 2;
+    let mut written = 0;
+    let mut output = Vec::new();
+
+    println!(
+        "Rendering {:.1}s to {}",
+        (length_ms + fade_ms) as f32 / 1000.,
+        out.display()
+    );
+
+    while written < total {
+        psx.run_frame();
+
+        let mut samples = psx.get_audio_samples().to_vec();
+        psx.clear_audio_samples();
+
+        let remaining = (total - written) as usize;
+        samples.truncate(remaining);
+        written += samples.len() as u64;
+
+        fader.process(&mut samples);
+        resampler.process(&samples, &mut output)?;
+
+        for &s in &output {
+            wav.write_sample(s)?;
+        }
+        output.clear();
+    }
+
+    resampler.flush(&mut output)?;
+    for &s in &output {
+        wav.write_sample(s)?;
+    }
+
+    wav.finalize()?;
+
+    Ok(())
+}
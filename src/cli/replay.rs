@@ -0,0 +1,153 @@
+//! `replay` command: play an input movie back without a frontend and check that it stays in sync
+
+use super::{bad_command_line, Args};
+use crate::box_array::BoxArray;
+use crate::error::{PsxError, Result};
+use crate::input_movie::{self, DiscEvent, MovieMode, MovieSession};
+use crate::psx::cd::disc::Disc;
+use crate::psx::pad_memcard::devices::memory_card::{MemoryCard, FLASH_SIZE};
+use crate::psx::pad_memcard::devices::multitap;
+use crate::psx::Psx;
+use crate::{find_bios_in, find_cdc_firmware_in, player_slot, save_state_root, LoadedSaveState};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+pub fn run(args: Args) -> Result<()> {
+    let discs: Vec<PathBuf> = args.positional.iter().map(PathBuf::from).collect();
+    if discs.is_empty() {
+        return Err(bad_command_line("missing disc image"));
+    }
+
+    let movie_path = match args.option("input", Some("i")) {
+        Some(p) => PathBuf::from(p),
+        None => return Err(bad_command_line("missing --input")),
+    };
+
+    let movie = input_movie::Movie::load(&movie_path)?;
+    let header = &movie.header;
+
+    let disc_path = match discs.get(header.disc_index) {
+        Some(p) => p,
+        None => {
+            let m = format!("the movie starts with disc {}", header.disc_index + 1);
+            return Err(bad_command_line(&m));
+        }
+    };
+
+    let system_dir = args.system_dir();
+    let bios = find_bios_in(&system_dir, |md| md.sha256 == header.bios_sha256)?;
+    let cdc_firmware = find_cdc_firmware_in(&system_dir)?;
+
+    let mut disc = Disc::load(disc_path)?;
+    header.check_setup(bios.get_rom(), &mut disc)?;
+
+    let external = match args.option("state", None) {
+        Some(p) => Some(std::fs::read(p)?),
+        None => match &header.start {
+            input_movie::StartState::SaveState { data: None, .. } => Some(std::fs::read(
+                input_movie::external_state_path(&movie_path),
+            )?),
+            _ => None,
+        },
+    };
+    let state = header.start_state(external.as_deref())?;
+
+    let mut psx = Box::new(Psx::new_with_disc(disc, bios, cdc_firmware)?);
+
+    // Configure the ports like they were when the movie was recorded
+    for (port, &tap) in header.multitap.iter().enumerate() {
+        if tap {
+            psx.pad_memcard.connect_multitap(port);
+        }
+    }
+
+    for (player, &ty) in header.controllers.iter().enumerate() {
+        if let Some((port, slot)) = player_slot(header.multitap, player) {
+            if let Some(gp) = psx.pad_memcard.gamepad_mut(port, slot) {
+                gp.connect_device(ty.new_device(psx.video_standard()));
+            }
+        }
+    }
+
+    for (index, memory) in header.memory_cards.iter().enumerate() {
+        let memory = match memory {
+            Some(m) if m.len() == FLASH_SIZE => m,
+            Some(_) => return Err(PsxError::BadInputMovie("bad memory card size".to_string())),
+            None => continue,
+        };
+
+        let (port, slot) = (index / multitap::SLOTS, index % multitap::SLOTS);
+
+        if let Some(m) = psx.pad_memcard.memory_card_mut(port, slot) {
+            let mc = MemoryCard::new_with_memory(BoxArray::from_vec(memory.clone()));
+            m.connect_device(Box::new(mc));
+        }
+    }
+
+    if let Some(state) = state {
+        let fbr = save_state_root(state)?;
+
+        match LoadedSaveState::deserialize(fbr.clone()) {
+            Ok(state) => psx.load(Box::new(state.psx))?,
+            Err(_) => psx.deserialize_and_load(fbr)?,
+        }
+    }
+
+    header.settings.apply(&mut psx);
+
+    println!(
+        "Replaying {} ({} frames, {} rerecords)",
+        movie_path.display(),
+        movie.frames().len(),
+        movie.rerecords()
+    );
+
+    let multitap = header.multitap;
+    let mut session = MovieSession::new(movie, movie_path, MovieMode::PlayReadOnly);
+
+    while let Some(input) = session.frame_input(Default::default()) {
+        for event in input.disc {
+            match event {
+                DiscEvent::Eject => {
+                    psx.cd.eject_disc();
+                }
+                DiscEvent::Insert(index) => {
+                    let path = match discs.get(index) {
+                        Some(p) => p,
+                        None => {
+                            let m = format!("the movie inserts disc {}", index + 1);
+                            return Err(bad_command_line(&m));
+                        }
+                    };
+
+                    psx.cd.load_disc(Disc::load(path)?);
+                }
+            }
+        }
+
+        for (player, pad) in input.pads.iter().enumerate() {
+            if let Some((port, slot)) = player_slot(multitap, player) {
+                if let Some(gp) = psx.pad_memcard.gamepad_mut(port, slot) {
+                    pad.apply(gp.device_mut());
+                }
+            }
+        }
+
+        psx.run_frame();
+
+        // Nobody's watching
+        let _frame = psx.take_frame();
+        psx.clear_audio_samples();
+
+        psx.pad_memcard.new_frame();
+        session.end_frame(psx.ram());
+    }
+
+    match session.desync() {
+        Some(frame) => Err(PsxError::MovieDesync(frame)),
+        None => {
+            println!("Replayed {} frames in sync", session.frame());
+            Ok(())
+        }
+    }
+}
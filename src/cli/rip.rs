@@ -0,0 +1,104 @@
+//! `rip` command: dump the CD-DA tracks and XA-ADPCM streams of a disc to WAV files
+
+use super::{bad_command_line, Args};
+use crate::error::Result;
+use crate::psx::cd::audio_rip::{self, CDDA_RATE};
+use crate::psx::cd::disc::Disc;
+use crate::resampler::OutputResampler;
+use std::path::{Path, PathBuf};
+
+pub fn run(args: Args) -> Result<()> {
+    let path = args.input("disc image")?;
+    let out_dir = PathBuf::from(args.option("output", Some("o")).unwrap_or("."));
+
+    // By default everything is dumped at its native rate
+    let rate = match args.option("rate", None) {
+        None | Some("native") => None,
+        Some(_) => args.parsed_option::<u32>("rate")?,
+    };
+
+    let (cdda, xa) = match args.option("only", None) {
+        None => (true, true),
+        Some("cdda") => (true, false),
+        Some("xa") => (false, true),
+        Some(o) => {
+            let m = format!("invalid value '{}' for --only", o);
+            return Err(bad_command_line(&m));
+        }
+    };
+
+    let mut disc = Disc::load(&path)?;
+
+    std::fs::create_dir_all(&out_dir)?;
+
+    if cdda {
+        for track in audio_rip::audio_tracks(&disc) {
+            let out = out_dir.join(format!("track{}.wav", track.number));
+
+            println!(
+                "Track {}: {:.1}s -> {}",
+                track.number,
+                track.duration(),
+                out.display()
+            );
+
+            let samples = audio_rip::rip_cdda_track(&mut disc, &track)?;
+
+            match rate {
+                Some(rate) => write_resampled(&out, rate, &samples)?,
+                None => audio_rip::write_wav(&out, 2, CDDA_RATE, &samples)?,
+            }
+        }
+    }
+
+    if xa {
+        println!("Looking for XA streams...");
+
+        for stream in audio_rip::find_xa_streams(&mut disc)? {
+            let name = format!(
+                "{}_{}_{}.wav",
+                stream.path.trim_start_matches('/').replace('/', "_"),
+                stream.file,
+                stream.channel
+            );
+            let out = out_dir.join(name);
+
+            println!(
+                "{} file {} channel {}: {}Hz {}bpp {}, {:.1}s -> {}",
+                stream.path,
+                stream.file,
+                stream.channel,
+                stream.sample_rate(),
+                stream.bits_per_sample(),
+                if stream.stereo() { "stereo" } else { "mono" },
+                stream.duration(),
+                out.display()
+            );
+
+            let samples = audio_rip::decode_xa_stream(&mut disc, &stream)?;
+
+            match rate {
+                Some(rate) => {
+                    let samples = audio_rip::resample_xa_44100(&stream, &samples);
+                    write_resampled(&out, rate, &samples)?;
+                }
+                None => {
+                    audio_rip::write_wav(&out, stream.channels(), stream.sample_rate(), &samples)?
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Resample 44.1kHz stereo `samples` to `rate` and write them to `out`
+fn write_resampled(out: &Path, rate: u32, samples: &[i16]) -> Result<()> {
+    let mut resampler = OutputResampler::new(rate);
+    let mut output = Vec::with_capacity(samples.len());
+
+    resampler.process(samples, &mut output)?;
+    resampler.flush(&mut output)?;
+
+    audio_rip::write_wav(out, 2, resampler.output_rate(), &output)
+}
@@ -9,6 +9,7 @@ use crate::debugger::Debugger;
 
 use self::reply::Reply;
 
+mod monitor;
 pub(super) mod reply;
 
 pub type GdbResult = Result<(), ()>;
@@ -167,7 +168,7 @@ impl GdbRemote {
             b's' => self.step(debugger, psx, args),
             b'Z' => self.add_breakpoint(debugger, args),
             b'z' => self.del_breakpoint(debugger, args),
-            b'q' => self.handle_query(debugger, args),
+            b'q' => self.handle_query(debugger, psx, args),
             b'Q' => self.handle_set(args),
             b'k' => self.kill(),
             b'D' => self.detach(debugger),
@@ -520,7 +521,7 @@ impl GdbRemote {
     }
 
     /// Handle query packets
-    fn handle_query(&mut self, debugger: &mut Debugger, args: &[u8]) -> GdbResult {
+    fn handle_query(&mut self, debugger: &mut Debugger, psx: &mut Psx, args: &[u8]) -> GdbResult {
         if args.starts_with(b"Supported") {
             // Report supported features
             self.send_string(b"PacketSize=1000;qXfer:features:read+;qXfer:threads:read+;QStartNoAckMode+;multiprocess+;swbreak+;hwbreak+")
@@ -544,11 +545,30 @@ impl GdbRemote {
         } else if args.starts_with(b"Offsets") {
             // Report no offset
             self.send_string(b"Text=0;Data=0;Bss=0")
+        } else if let Some(cmd) = args.strip_prefix(b"Rcmd,") {
+            self.monitor(psx, cmd)
         } else {
             self.send_empty_reply()
         }
     }
 
+    /// Run a "monitor" command. The command and its output are hex-encoded
+    fn monitor(&mut self, psx: &mut Psx, cmd: &[u8]) -> GdbResult {
+        let cmd = match self.decode_hex_string(cmd) {
+            Ok(c) => c,
+            Err(()) => return self.send_error(),
+        };
+
+        let output = monitor::run(psx, &cmd);
+
+        let mut reply = Reply::new();
+        for b in output.bytes() {
+            reply.push_u8(b);
+        }
+
+        self.send_reply(reply)
+    }
+
     /// Handle set packets
     fn handle_set(&mut self, args: &[u8]) -> GdbResult {
         if args.starts_with(b"StartNoAckMode") {
@@ -0,0 +1,56 @@
+//! GDB "monitor" commands, sent by the remote through `qRcmd` packets
+//!
+//! Usage from GDB: `monitor spu` dumps the state of the SPU as JSON, `monitor spu adpcm <address>
+//! <blocks>` decodes ADPCM blocks from SPU RAM and dumps the PCM samples as a JSON array. Both
+//! need the `json` feature.
+
+use crate::psx::snapshot::MAX_ADPCM_BLOCKS;
+use crate::psx::Psx;
+
+const HELP: &str = "\
+Available commands:
+  spu                            dump the SPU state as JSON
+  spu adpcm <address> <blocks>   decode ADPCM blocks from SPU RAM to PCM
+";
+
+/// Run monitor command `cmd` and return its output
+pub fn run(psx: &mut Psx, cmd: &str) -> String {
+    let args: Vec<&str> = cmd.split_whitespace().collect();
+
+    match args.as_slice() {
+        ["spu"] => to_json(&psx.spu_snapshot()),
+        ["spu", "adpcm", address, blocks] => {
+            let address = match parse_u32(address) {
+                Some(a) => a,
+                None => return format!("Invalid address '{}'\n", address),
+            };
+
+            let blocks = match parse_u32(blocks) {
+                Some(b) if (b as usize) <= MAX_ADPCM_BLOCKS => b as usize,
+                _ => return format!("Invalid block count '{}'\n", blocks),
+            };
+
+            to_json(&psx.decode_spu_adpcm(address, blocks))
+        }
+        _ => HELP.to_string(),
+    }
+}
+
+#[cfg(feature = "json")]
+fn to_json<T: serde::Serialize>(v: &T) -> String {
+    // Can't fail: we only dump plain data without non-string map keys
+    format!("{}\n", serde_json::to_string(v).unwrap())
+}
+
+#[cfg(not(feature = "json"))]
+fn to_json<T: serde::Serialize>(_v: &T) -> String {
+    "JSON output isn't available, the core must be built with the `json` feature\n".to_string()
+}
+
+/// Parse a decimal or 0x-prefixed hexadecimal number
+fn parse_u32(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
@@ -27,6 +27,23 @@ pub enum PsxError {
     FrontendError(String),
     #[error("CD layer error: {0}")]
     CdError(#[from] CdError),
+    #[cfg(feature = "wav-export")]
+    #[error("WAV file error: {0}")]
+    WavError(#[from] hound::Error),
+    #[error("Audio resampling error: {0}")]
+    ResampleError(#[from] rubato::ResampleError),
+    #[error("Invalid PS-X EXE: {0}")]
+    BadExe(String),
+    #[error("Invalid PSF file: {0}")]
+    BadPsf(String),
+    #[error("Invalid STR movie: {0}")]
+    BadMovie(String),
+    #[error("Invalid input movie: {0}")]
+    BadInputMovie(String),
+    #[error("Input movie desynchronized after frame {0}")]
+    MovieDesync(usize),
+    #[error("Image encoding error: {0}")]
+    ImageError(#[from] image::ImageError),
     #[error("The disc format was incorrect (i.e. probably not a valid PSX disc image): `{0}`")]
     BadDiscFormat(String),
     #[error("CD ISO filesystem error: `{0}`")]
@@ -88,4 +105,6 @@ pub enum PsxError {
     WebEnvironmentError,
     #[error("Memory forensics error: {0}")]
     ForensicsError(String),
+    #[error("Invalid command line: {0}")]
+    BadCommandLine(String),
 }
@@ -0,0 +1,51 @@
+//! Golden file comparisons shared by the tests.
+//!
+//! Tests that check bit-exact output (post-processing filters, SPU traces...) serialize it and
+//! compare it with a reference file checked into the repository. After an intentional change to
+//! that output the reference files can be regenerated with:
+//!
+//! `UPDATE_GOLDEN=1 cargo test`
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Return the path of `file`, relative to the root of the repository
+pub fn path(file: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(file)
+}
+
+/// Compare `data` with the contents of the golden file at `path`, or overwrite the file if
+/// `UPDATE_GOLDEN` is set. `data` is made of `record_size`-byte records (a sample, a pixel...),
+/// on mismatch the first diverging record is reported.
+pub fn check(path: &Path, data: &[u8], record_size: usize) {
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(path, data).unwrap();
+        return;
+    }
+
+    let golden = fs::read(path)
+        .unwrap_or_else(|e| panic!("Can't read golden file {}: {}", path.display(), e));
+
+    let record_size = record_size.max(1);
+
+    for (i, (d, g)) in data
+        .chunks(record_size)
+        .zip(golden.chunks(record_size))
+        .enumerate()
+    {
+        assert!(
+            d == g,
+            "Output diverges from {} at record {} (offset 0x{:x})",
+            path.display(),
+            i,
+            i * record_size
+        );
+    }
+
+    assert_eq!(
+        data.len(),
+        golden.len(),
+        "Output length differs from {}",
+        path.display()
+    );
+}
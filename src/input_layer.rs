@@ -0,0 +1,762 @@
+//! Input processing layer sitting between the frontend's inputs and the emulated gamepads
+//!
+//! It provides per-player button remapping, turbo, hold-toggle, conversion between the analog
+//! sticks and the D-pad, adjustable stick deadzone and response curve and recorded button macros.
+//! The configuration is loaded for each game from `<save dir>/input/<serial>.toml`, falling back
+//! on `<save dir>/input/default.toml`.
+//!
+//! The output only depends on the raw inputs of the frame and on `LayerState`, which is stored in
+//! the save states, so the processing is deterministic: input movies record the processed inputs
+//! and netplay peers using the same configuration stay in sync after a rollback.
+//!
+//! Example configuration:
+//!
+//! ```toml
+//! # Player 1
+//! [[player]]
+//! # Swap cross and circle, "none" disables a button
+//! remap = { cross = "circle", circle = "cross" }
+//! turbo = ["square"]
+//! # Length of a turbo press/release cycle, in frames
+//! turbo_period = 4
+//! # Press once to hold, press again to release
+//! toggle = ["r1"]
+//! stick_to_dpad = "left"
+//! deadzone = 0.2
+//! curve = 1.5
+//!
+//! [[player.macro]]
+//! trigger = "l3"
+//! # One step per frame, "*N" repeats a step N times
+//! steps = ["cross*2", "none*3", "circle+square"]
+//! ```
+
+use crate::input_movie::PadInput;
+use crate::ControllerType;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Names of the gamepad buttons, indexed by `Button` value
+const BUTTON_NAMES: [&str; 16] = [
+    "select", "l3", "r3", "start", "up", "right", "down", "left", "l2", "r2", "l1", "r1",
+    "triangle", "circle", "cross", "square",
+];
+
+/// Mask of the gamepad buttons in `PadInput::buttons`, the analog button is never processed
+const BUTTONS_MASK: u32 = 0xffff;
+
+const UP: u32 = 1 << 4;
+const RIGHT: u32 = 1 << 5;
+const DOWN: u32 = 1 << 6;
+const LEFT: u32 = 1 << 7;
+const DPAD_MASK: u32 = UP | RIGHT | DOWN | LEFT;
+
+/// Maximum length of a macro in frames (10 minutes at 60fps)
+const MAX_MACRO_STEPS: usize = 60 * 60 * 10;
+
+/// Analog stick of the DualShock
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Stick {
+    Left,
+    Right,
+}
+
+impl Stick {
+    /// Index of the X axis of the stick in `PadInput::axes`, Y is the next one
+    fn axis(self) -> usize {
+        match self {
+            Stick::Left => 0,
+            Stick::Right => 2,
+        }
+    }
+}
+
+/// Input configuration of a game, as stored in the TOML files
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// Configuration of each player, starting with player 1
+    pub player: Vec<PlayerConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let toml = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        toml::from_str(&toml).map_err(|e| e.to_string())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let toml = toml::to_string_pretty(self).map_err(|e| e.to_string())?;
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+
+        crate::memory_card::backup::write_atomic(path, toml.as_bytes()).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlayerConfig {
+    /// Button pressed on the emulated gamepad for each button of the frontend. The target can be
+    /// a combination ("cross+circle") or "none".
+    pub remap: std::collections::BTreeMap<String, String>,
+    /// Buttons that are repeatedly pressed and released while held
+    pub turbo: Vec<String>,
+    /// Length of a turbo press/release cycle, in frames
+    pub turbo_period: u32,
+    /// Buttons latched by a first press and released by the next one
+    pub toggle: Vec<String>,
+    /// Stick controlling the D-pad
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stick_to_dpad: Option<Stick>,
+    /// Fraction of the stick's range past which the D-pad is pressed
+    pub stick_threshold: f32,
+    /// Stick controlled by the D-pad (DualShock only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dpad_to_stick: Option<Stick>,
+    /// Fraction of the sticks' range ignored around the center
+    pub deadzone: f32,
+    /// Exponent applied to the sticks' position past the deadzone. Values above 1 give more
+    /// precision around the center.
+    pub curve: f32,
+    #[serde(rename = "macro")]
+    pub macros: Vec<MacroConfig>,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> PlayerConfig {
+        PlayerConfig {
+            remap: Default::default(),
+            turbo: Vec::new(),
+            turbo_period: 4,
+            toggle: Vec::new(),
+            stick_to_dpad: None,
+            stick_threshold: 0.5,
+            dpad_to_stick: None,
+            deadzone: 0.,
+            curve: 1.,
+            macros: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MacroConfig {
+    /// Button starting the macro. It's never passed to the console.
+    pub trigger: String,
+    /// Buttons pressed on each frame of the macro
+    pub steps: Vec<String>,
+}
+
+/// Configuration of a player, validated and converted to button masks
+struct Player {
+    /// Mask of the buttons pressed for each frontend button
+    remap: [u32; 16],
+    turbo: u32,
+    turbo_period: u32,
+    toggle: u32,
+    stick_to_dpad: Option<Stick>,
+    stick_threshold: f32,
+    dpad_to_stick: Option<Stick>,
+    deadzone: f32,
+    curve: f32,
+    macros: Vec<Macro>,
+    /// Mask of all the macro triggers
+    triggers: u32,
+}
+
+impl Player {
+    fn new(config: &PlayerConfig) -> Result<Player, String> {
+        let mut remap = [0; 16];
+
+        for (bit, r) in remap.iter_mut().enumerate() {
+            *r = 1 << bit;
+        }
+
+        for (from, to) in &config.remap {
+            let from = parse_button(from)?;
+
+            remap[from.trailing_zeros() as usize] = parse_buttons(to)?;
+        }
+
+        let mask = |buttons: &[String]| -> Result<u32, String> {
+            buttons
+                .iter()
+                .try_fold(0, |mask, b| Ok(mask | parse_button(b)?))
+        };
+
+        if config.turbo_period < 2 {
+            return Err("turbo_period must be at least 2 frames".to_string());
+        }
+
+        if !(0. ..1.).contains(&config.deadzone) {
+            return Err("deadzone must be between 0 and 1".to_string());
+        }
+
+        if !(0. ..1.).contains(&config.stick_threshold) {
+            return Err("stick_threshold must be between 0 and 1".to_string());
+        }
+
+        if config.curve.is_nan() || config.curve <= 0. {
+            return Err("curve must be positive".to_string());
+        }
+
+        let macros = config
+            .macros
+            .iter()
+            .map(Macro::new)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Player {
+            remap,
+            turbo: mask(&config.turbo)?,
+            turbo_period: config.turbo_period,
+            toggle: mask(&config.toggle)?,
+            stick_to_dpad: config.stick_to_dpad,
+            stick_threshold: config.stick_threshold,
+            dpad_to_stick: config.dpad_to_stick,
+            deadzone: config.deadzone,
+            curve: config.curve,
+            triggers: macros.iter().fold(0, |m, mac| m | mac.trigger),
+            macros,
+        })
+    }
+}
+
+struct Macro {
+    trigger: u32,
+    steps: Vec<u32>,
+}
+
+impl Macro {
+    fn new(config: &MacroConfig) -> Result<Macro, String> {
+        let mut steps = Vec::new();
+
+        for step in &config.steps {
+            let (buttons, count) = match step.split_once('*') {
+                Some((b, n)) => {
+                    let n: usize = n
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Invalid macro step '{}'", step))?;
+
+                    (b, n)
+                }
+                None => (step.as_str(), 1),
+            };
+
+            let buttons = parse_buttons(buttons)?;
+
+            if count > MAX_MACRO_STEPS - steps.len() {
+                return Err(format!("Macro longer than {} frames", MAX_MACRO_STEPS));
+            }
+
+            steps.resize(steps.len() + count, buttons);
+        }
+
+        Ok(Macro {
+            trigger: parse_button(&config.trigger)?,
+            steps,
+        })
+    }
+}
+
+/// State of the input layer, stored in the save states
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct LayerState {
+    /// Number of frames processed, used for the turbo
+    frame: u32,
+    players: Vec<PlayerState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+struct PlayerState {
+    /// Buttons held on the previous frame after remapping, to detect new presses
+    held: u32,
+    /// Buttons currently latched by a toggle
+    toggled: u32,
+    /// Index of the macro being played back and of its next step
+    playing: Option<(usize, usize)>,
+}
+
+/// Macro being recorded
+struct Recording {
+    player: usize,
+    steps: Vec<u32>,
+}
+
+pub struct InputLayer {
+    config: Config,
+    players: Vec<Player>,
+    state: LayerState,
+    recording: Option<Recording>,
+}
+
+impl Default for InputLayer {
+    /// Input layer passing the inputs through unchanged
+    fn default() -> InputLayer {
+        InputLayer {
+            config: Config::default(),
+            players: Vec::new(),
+            state: LayerState::default(),
+            recording: None,
+        }
+    }
+}
+
+impl InputLayer {
+    pub fn with_config(config: Config) -> Result<InputLayer, String> {
+        let players = config
+            .player
+            .iter()
+            .enumerate()
+            .map(|(i, p)| Player::new(p).map_err(|e| format!("Player {}: {}", i + 1, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(InputLayer {
+            config,
+            players,
+            state: LayerState::default(),
+            recording: None,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn state(&self) -> &LayerState {
+        &self.state
+    }
+
+    pub fn set_state(&mut self, state: LayerState) {
+        self.state = state;
+    }
+
+    /// Process the inputs of every player for one frame. `types` contains the type of controller
+    /// of each player, only the gamepads are processed.
+    pub fn process(&mut self, pads: &mut [PadInput], types: &[ControllerType]) {
+        let frame = self.state.frame;
+        self.state.frame = frame.wrapping_add(1);
+
+        if self.state.players.len() < pads.len() {
+            self.state
+                .players
+                .resize(pads.len(), PlayerState::default());
+        }
+
+        for (player, (pad, &ty)) in pads.iter_mut().zip(types).enumerate() {
+            let has_sticks = match ty {
+                ControllerType::Digital => false,
+                ControllerType::DualShock => true,
+                _ => continue,
+            };
+
+            let state = &mut self.state.players[player];
+
+            if let Some(config) = self.players.get(player) {
+                process_pad(config, state, frame, pad, has_sticks);
+            }
+
+            if !has_sticks {
+                pad.axes = [0; 4];
+            }
+
+            if let Some(r) = self.recording.as_mut().filter(|r| r.player == player) {
+                r.steps.push(pad.buttons & BUTTONS_MASK);
+            }
+        }
+    }
+
+    /// Start recording the buttons pressed by `player` on every frame
+    pub fn start_recording(&mut self, player: usize) {
+        self.recording = Some(Recording {
+            player,
+            steps: Vec::new(),
+        });
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Stop recording and add the macro to the configuration, triggered by the button
+    /// `trigger` ("l3", "select"...). Returns the player and the length of the macro in frames.
+    pub fn stop_recording(&mut self, trigger: &str) -> Result<(usize, usize), String> {
+        let recording = match self.recording.take() {
+            Some(r) => r,
+            None => return Err("No macro being recorded".to_string()),
+        };
+
+        // Ignore the frames before the first press and after the last release
+        let steps = &recording.steps;
+        let start = steps.iter().position(|&s| s != 0);
+        let end = steps.iter().rposition(|&s| s != 0);
+
+        let steps = match (start, end) {
+            (Some(s), Some(e)) => &steps[s..=e],
+            _ => return Err("No button pressed during the macro".to_string()),
+        };
+
+        let mut config = self.config.clone();
+
+        if config.player.len() <= recording.player {
+            config
+                .player
+                .resize(recording.player + 1, PlayerConfig::default());
+        }
+
+        config.player[recording.player].macros.push(MacroConfig {
+            trigger: trigger.to_string(),
+            steps: format_steps(steps),
+        });
+
+        let mut layer = InputLayer::with_config(config)?;
+        layer.state = std::mem::take(&mut self.state);
+
+        *self = layer;
+
+        Ok((recording.player, steps.len()))
+    }
+}
+
+fn process_pad(
+    config: &Player,
+    state: &mut PlayerState,
+    frame: u32,
+    pad: &mut PadInput,
+    has_sticks: bool,
+) {
+    let analog = pad.buttons & !BUTTONS_MASK;
+    let mut raw = pad.buttons & BUTTONS_MASK;
+
+    if config.deadzone > 0. || config.curve != 1. {
+        for stick in [Stick::Left, Stick::Right] {
+            let a = stick.axis();
+            let (x, y) = shape(pad.axes[a], pad.axes[a + 1], config.deadzone, config.curve);
+
+            pad.axes[a] = x;
+            pad.axes[a + 1] = y;
+        }
+    }
+
+    if let Some(stick) = config.stick_to_dpad {
+        let a = stick.axis();
+        let threshold = config.stick_threshold * f32::from(i16::MAX);
+        let (x, y) = (f32::from(pad.axes[a]), f32::from(pad.axes[a + 1]));
+
+        if x < -threshold {
+            raw |= LEFT;
+        }
+        if x > threshold {
+            raw |= RIGHT;
+        }
+        if y < -threshold {
+            raw |= UP;
+        }
+        if y > threshold {
+            raw |= DOWN;
+        }
+    }
+
+    let mut buttons = (0..16)
+        .filter(|bit| raw & (1 << bit) != 0)
+        .fold(0, |b, bit| b | config.remap[bit]);
+
+    let pressed = buttons & !state.held;
+    state.held = buttons;
+
+    state.toggled ^= pressed & config.toggle;
+    buttons = (buttons & !config.toggle) | state.toggled;
+
+    if frame % config.turbo_period >= config.turbo_period / 2 {
+        buttons &= !config.turbo;
+    }
+
+    if state.playing.is_none() {
+        state.playing = config
+            .macros
+            .iter()
+            .position(|m| m.trigger & pressed != 0)
+            .map(|m| (m, 0));
+    }
+
+    buttons &= !config.triggers;
+
+    if let Some((m, step)) = state.playing {
+        // The state could come from a save state made with another configuration
+        let steps = config.macros.get(m).map_or(&[][..], |m| &m.steps[..]);
+
+        buttons |= steps.get(step).copied().unwrap_or(0);
+
+        state.playing = if step + 1 < steps.len() {
+            Some((m, step + 1))
+        } else {
+            None
+        };
+    }
+
+    if let (Some(stick), true) = (config.dpad_to_stick, has_sticks) {
+        if buttons & DPAD_MASK != 0 {
+            let dir = |neg, pos| {
+                let mut v = 0;
+                if buttons & neg != 0 {
+                    v -= i16::MAX;
+                }
+                if buttons & pos != 0 {
+                    v += i16::MAX;
+                }
+                v
+            };
+
+            let a = stick.axis();
+            pad.axes[a] = dir(LEFT, RIGHT);
+            pad.axes[a + 1] = dir(UP, DOWN);
+
+            buttons &= !DPAD_MASK;
+        }
+    }
+
+    pad.buttons = buttons | analog;
+}
+
+/// Apply `deadzone` and `curve` to the stick position (`x`, `y`)
+fn shape(x: i16, y: i16, deadzone: f32, curve: f32) -> (i16, i16) {
+    let max = f32::from(i16::MAX);
+    let (fx, fy) = (f32::from(x) / max, f32::from(y) / max);
+
+    let magnitude = (fx * fx + fy * fy).sqrt();
+
+    if magnitude <= deadzone {
+        return (0, 0);
+    }
+
+    let scaled = ((magnitude.min(1.) - deadzone) / (1. - deadzone)).powf(curve);
+    let ratio = scaled / magnitude;
+
+    let axis = |v: f32| (v * ratio * max).clamp(f32::from(i16::MIN), max) as i16;
+
+    (axis(fx), axis(fy))
+}
+
+/// Parse a single button name
+fn parse_button(name: &str) -> Result<u32, String> {
+    let name = name.trim().to_ascii_lowercase();
+
+    match BUTTON_NAMES.iter().position(|&n| n == name) {
+        Some(bit) => Ok(1 << bit),
+        None => Err(format!("Unknown button '{}'", name)),
+    }
+}
+
+/// Parse a combination of buttons ("cross+circle"). "none" and the empty string mean no button.
+fn parse_buttons(buttons: &str) -> Result<u32, String> {
+    let buttons = buttons.trim();
+
+    if buttons.is_empty() || buttons.eq_ignore_ascii_case("none") {
+        return Ok(0);
+    }
+
+    buttons
+        .split('+')
+        .try_fold(0, |mask, b| Ok(mask | parse_button(b)?))
+}
+
+/// Format macro steps the way `Macro::new` parses them, merging the identical steps
+fn format_steps(steps: &[u32]) -> Vec<String> {
+    let mut formatted = Vec::new();
+    let mut i = 0;
+
+    while i < steps.len() {
+        let buttons = steps[i];
+        let count = steps[i..].iter().take_while(|&&s| s == buttons).count();
+
+        let mut step = if buttons == 0 {
+            "none".to_string()
+        } else {
+            let names: Vec<&str> = (0..16)
+                .filter(|bit| buttons & (1 << bit) != 0)
+                .map(|bit| BUTTON_NAMES[bit])
+                .collect();
+
+            names.join("+")
+        };
+
+        if count > 1 {
+            step = format!("{}*{}", step, count);
+        }
+
+        formatted.push(step);
+        i += count;
+    }
+
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CROSS: u32 = 1 << 14;
+    const CIRCLE: u32 = 1 << 13;
+    const SQUARE: u32 = 1 << 15;
+    const R1: u32 = 1 << 11;
+    const L3: u32 = 1 << 1;
+
+    fn layer(toml: &str) -> InputLayer {
+        InputLayer::with_config(toml::from_str(toml).unwrap()).unwrap()
+    }
+
+    fn run(layer: &mut InputLayer, ty: ControllerType, buttons: u32, axes: [i16; 4]) -> PadInput {
+        let mut pads = [PadInput { buttons, axes }];
+
+        layer.process(&mut pads, &[ty]);
+
+        pads[0]
+    }
+
+    fn buttons(layer: &mut InputLayer, frames: &[u32]) -> Vec<u32> {
+        frames
+            .iter()
+            .map(|&b| run(layer, ControllerType::Digital, b, [0; 4]).buttons)
+            .collect()
+    }
+
+    #[test]
+    fn remap_turbo_toggle() {
+        let mut l = layer(
+            r#"
+            [[player]]
+            remap = { cross = "circle", circle = "cross+square" }
+            turbo = ["circle"]
+            turbo_period = 4
+            toggle = ["r1"]
+            "#,
+        );
+
+        // Cross is remapped to circle, which has turbo
+        assert_eq!(buttons(&mut l, &[CROSS; 5]), [CIRCLE, CIRCLE, 0, 0, CIRCLE]);
+        // Turbo applies to the buttons seen by the console
+        assert_eq!(buttons(&mut l, &[CIRCLE; 3]), [CROSS | SQUARE; 3]);
+
+        assert_eq!(
+            buttons(&mut l, &[R1, 0, 0, R1, R1, 0]),
+            [R1, R1, R1, 0, 0, 0]
+        );
+
+        // The analog button and peripherals are left alone
+        let analog = 1 << 16;
+        assert_eq!(buttons(&mut l, &[analog]), [analog]);
+
+        let negcon = run(&mut l, ControllerType::NeGcon, CROSS, [1, 2, 3, 4]);
+        assert_eq!(negcon.buttons, CROSS);
+        assert_eq!(negcon.axes, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn macros_and_state() {
+        let mut l = layer(
+            r#"
+            [[player]]
+            [[player.macro]]
+            trigger = "l3"
+            steps = ["cross*2", "none", "circle+square"]
+            "#,
+        );
+
+        assert_eq!(
+            buttons(&mut l, &[L3, L3, L3, R1, 0, L3]),
+            [CROSS, CROSS, 0, R1 | CIRCLE | SQUARE, 0, CROSS]
+        );
+
+        // Restoring the state replays the same outputs
+        let state = l.state().clone();
+        let a = buttons(&mut l, &[0, 0, L3, 0]);
+        l.set_state(state);
+        let b = buttons(&mut l, &[0, 0, L3, 0]);
+        assert_eq!(a, b);
+        assert_eq!(a, [CROSS, 0, CIRCLE | SQUARE, 0]);
+
+        // A state saved with another configuration can refer to a macro that doesn't exist
+        let mut state = l.state().clone();
+        state.players[0].playing = Some((3, 0));
+        l.set_state(state);
+        assert_eq!(buttons(&mut l, &[R1, 0]), [R1, 0]);
+
+        let long = "[[player]]\n[[player.macro]]\ntrigger = \"l3\"\nsteps = [\"cross*4000000000\"]";
+        let long: Config = toml::from_str(long).unwrap();
+        assert!(InputLayer::with_config(long).is_err());
+    }
+
+    #[test]
+    fn record_macro() {
+        let mut l = InputLayer::default();
+
+        l.start_recording(1);
+
+        for &b in &[0, CROSS, CROSS, 0, 0, CIRCLE, 0] {
+            let mut pads = [
+                PadInput::default(),
+                PadInput {
+                    buttons: b,
+                    axes: [0; 4],
+                },
+            ];
+            l.process(&mut pads, &[ControllerType::Digital; 2]);
+        }
+
+        assert_eq!(l.stop_recording("select"), Ok((1, 5)));
+        assert!(!l.is_recording());
+
+        let macros = &l.config().player[1].macros;
+        assert_eq!(macros[0].steps, ["cross*2", "none*2", "circle"]);
+
+        // The new configuration survives a round trip through TOML
+        let toml = toml::to_string_pretty(l.config()).unwrap();
+        let config: Config = toml::from_str(&toml).unwrap();
+        assert_eq!(&config, l.config());
+    }
+
+    #[test]
+    fn analog_conversion() {
+        let mut l = layer(
+            r#"
+            [[player]]
+            stick_to_dpad = "left"
+            dpad_to_stick = "right"
+            deadzone = 0.25
+            "#,
+        );
+
+        let pad = run(&mut l, ControllerType::DualShock, 0, [-12000, 0, 5000, 0]);
+        assert_eq!(pad.buttons, 0);
+        assert!(pad.axes[0] < -4000 && pad.axes[0] > -6000);
+        assert_eq!(pad.axes[1..], [0, 0, 0]);
+
+        // The stick presses the D-pad which in turns moves the right stick
+        let pad = run(&mut l, ControllerType::DualShock, 0, [-32767, 0, 0, 0]);
+        assert_eq!(pad.buttons, 0);
+        assert_eq!(pad.axes, [-32767, 0, -32767, 0]);
+
+        // Digital pads only get the D-pad
+        let pad = run(&mut l, ControllerType::Digital, 0, [0, -32767, 0, 0]);
+        assert_eq!(pad.buttons, UP);
+        assert_eq!(pad.axes, [0; 4]);
+
+        assert_eq!(shape(8000, 0, 0.25, 1.), (0, 0));
+        assert_eq!(shape(0, 32767, 0.25, 2.), (0, 32767));
+        assert_eq!(shape(0, -32767, 0., 1.), (0, -32767));
+
+        let bad: Config = toml::from_str("[[player]]\nturbo = [\"x\"]").unwrap();
+        assert!(InputLayer::with_config(bad).is_err());
+
+        let bad: Config = toml::from_str("[[player]]\ncurve = nan").unwrap();
+        assert!(InputLayer::with_config(bad).is_err());
+    }
+}
@@ -0,0 +1,694 @@
+//! Deterministic input movies, used to replay a game session frame by frame for bug reports,
+//! regression tests or tool-assisted runs.
+//!
+//! A movie starts either from a freshly powered-on console or from a save state and contains the
+//! inputs of every player for each frame, along with the disc swaps. The header identifies the
+//! BIOS and disc the movie was recorded with as well as the core options that change the behaviour
+//! of the console (see `EmulationSettings`), and a checksum of the RAM is stored every
+//! `CHECKSUM_INTERVAL` frames so that we can tell exactly when a replay starts diverging from the
+//! recording.
+//!
+//! Movie files start with a small uncompressed header (magic and format version) followed by the
+//! zstd-compressed, bincode-serialized `Movie`.
+
+use crate::error::{PsxError, Result};
+use crate::memory_card::backup::write_atomic;
+use crate::psx::cd::audio_rip::read_track1_sector;
+use crate::psx::disc::Disc;
+use crate::psx::gpu::RasterizerOption;
+use crate::psx::interpolation::InterpolationMethod;
+use crate::psx::pad_memcard::devices::gamepad::{Button, ButtonState};
+use crate::psx::pad_memcard::devices::DeviceInterface;
+use crate::psx::Psx;
+use crate::sha::sha256;
+use crate::ControllerType;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Magic at the start of movie files
+const MAGIC: [u8; 4] = *b"RSXM";
+
+/// Version of the movie format, incremented every time the layout of `Movie` changes
+const FORMAT_VERSION: u32 = 2;
+
+/// Maximum size of a decompressed movie. That's days of inputs with an embedded save state and
+/// all the memory cards, anything bigger is corrupted.
+const MAX_MOVIE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Number of frames between two RAM checksums
+pub const CHECKSUM_INTERVAL: usize = 60;
+
+/// Number of sectors at the start of the data track hashed in `disc_sha256`. That covers the
+/// license area, the volume descriptors and generally the root directory.
+const DISC_HASH_SECTORS: u32 = 32;
+
+/// Number of peripheral buttons stored in a `PadInput`
+const PERIPHERAL_BUTTONS: usize = 16;
+
+/// Gamepad buttons stored in a `PadInput`, the analog button is handled separately
+const GAMEPAD_BUTTONS: [Button; 16] = [
+    Button::Select,
+    Button::L3,
+    Button::R3,
+    Button::Start,
+    Button::DUp,
+    Button::DRight,
+    Button::DDown,
+    Button::DLeft,
+    Button::L2,
+    Button::R2,
+    Button::L1,
+    Button::R1,
+    Button::Triangle,
+    Button::Circle,
+    Button::Cross,
+    Button::Square,
+];
+
+/// Bit used for the analog button in `PadInput::buttons`
+const ANALOG_BUTTON_BIT: u32 = 16;
+
+/// State of the inputs of a single player during one frame
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct PadInput {
+    /// Bit N is set if button N is pressed. For the gamepads N is the value of the `Button`, with
+    /// bit 16 for the analog button. For the other peripherals it's the index passed to
+    /// `set_button`.
+    pub buttons: u32,
+    /// For the gamepads the left stick X and Y followed by the right stick X and Y. For the other
+    /// peripherals the values passed to `set_axis`.
+    pub axes: [i16; 4],
+}
+
+impl PadInput {
+    /// Set the state of a gamepad button
+    pub fn set_button(&mut self, button: Button, state: ButtonState) {
+        let bit = match button {
+            Button::Analog => ANALOG_BUTTON_BIT,
+            b => b as u32,
+        };
+
+        self.set_bit(bit, state == ButtonState::Pressed);
+    }
+
+    /// Set the state of button `index` of a peripheral
+    pub fn set_peripheral_button(&mut self, index: usize, pressed: bool) {
+        if index < PERIPHERAL_BUTTONS {
+            self.set_bit(index as u32, pressed);
+        }
+    }
+
+    fn set_bit(&mut self, bit: u32, set: bool) {
+        if set {
+            self.buttons |= 1 << bit;
+        } else {
+            self.buttons &= !(1 << bit);
+        }
+    }
+
+    fn is_set(&self, bit: u32) -> bool {
+        self.buttons & (1 << bit) != 0
+    }
+
+    /// Feed the inputs to `device`. Must be called exactly once per frame since some devices
+    /// (like the DualShock) run their per-frame logic when their axes are set.
+    pub fn apply(&self, device: &mut dyn DeviceInterface) {
+        if let Some(p) = device.peripheral_mut() {
+            // The axes must be set before the buttons since the light guns go back on screen
+            // when they're aimed
+            for (axis, &v) in self.axes.iter().enumerate() {
+                p.set_axis(axis, v);
+            }
+
+            for b in 0..PERIPHERAL_BUTTONS {
+                p.set_button(b, self.is_set(b as u32));
+            }
+
+            return;
+        }
+
+        let state = |pressed| {
+            if pressed {
+                ButtonState::Pressed
+            } else {
+                ButtonState::Released
+            }
+        };
+
+        for &b in &GAMEPAD_BUTTONS {
+            device.set_button_state(b, state(self.is_set(b as u32)));
+        }
+
+        device.set_button_state(Button::Analog, state(self.is_set(ANALOG_BUTTON_BIT)));
+
+        let [lx, ly, rx, ry] = self.axes;
+        device.set_axis_state((lx, ly), (rx, ry));
+    }
+}
+
+/// Disc tray operation
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiscEvent {
+    /// Open the tray and remove the disc
+    Eject,
+    /// Insert the disc image at this index in the playlist and close the tray
+    Insert(usize),
+}
+
+/// Inputs for a single frame
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct FrameInput {
+    /// Input of each player
+    pub pads: Vec<PadInput>,
+    /// Disc operations performed before the frame
+    pub disc: Vec<DiscEvent>,
+}
+
+/// Point the movie starts from
+#[derive(Serialize, Deserialize, Clone)]
+pub enum StartState {
+    /// Freshly powered-on console
+    PowerOn,
+    /// Save state made by the libretro core
+    SaveState {
+        sha256: [u8; 32],
+        /// The state itself if it's embedded in the movie. Otherwise it must be provided
+        /// separately and is checked against `sha256`.
+        data: Option<Vec<u8>>,
+    },
+}
+
+/// Core options that change the behaviour of the emulated console. A movie must be replayed with
+/// the settings it was recorded with to stay in sync.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EmulationSettings {
+    pub gte_overclock: bool,
+    /// CD loading speed multiplier, 1 for the native 2x speed
+    pub cd_loading_speed: u8,
+    pub reverb_enable: bool,
+    pub reverb_enhanced: bool,
+    pub spu_interpolation: InterpolationMethod,
+    pub force_transparency: bool,
+}
+
+impl EmulationSettings {
+    pub fn apply(&self, psx: &mut Psx) {
+        psx.gte.set_overclock(self.gte_overclock);
+        psx.cd.set_cd_loading_speed(self.cd_loading_speed);
+        psx.spu.set_reverb_enable(self.reverb_enable);
+        psx.set_spu_reverb_enhanced(self.reverb_enhanced);
+        psx.set_spu_interpolation_method(self.spu_interpolation);
+        psx.gpu
+            .set_rasterizer_option(RasterizerOption::ForceTransparency(self.force_transparency));
+    }
+}
+
+/// Everything needed to reproduce the state of the console at the start of the movie
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MovieHeader {
+    /// Version of the core that recorded the movie
+    pub core_version: String,
+    /// SHA-256 of the BIOS
+    pub bios_sha256: [u8; 32],
+    /// Serial number of the disc the movie starts with
+    pub disc_serial: String,
+    /// Checksum of the disc the movie starts with, see `disc_sha256`
+    pub disc_sha256: [u8; 32],
+    /// Index of that disc in the playlist
+    pub disc_index: usize,
+    /// Controller used by each player
+    pub controllers: Vec<ControllerType>,
+    /// Multitaps plugged in ports 1 and 2
+    pub multitap: [bool; 2],
+    /// Contents of the memory cards when the movie starts, indexed like the libretro core's
+    /// memory card files (port 1 slots A to D, then port 2)
+    pub memory_cards: Vec<Option<Vec<u8>>>,
+    pub settings: EmulationSettings,
+    pub start: StartState,
+}
+
+impl MovieHeader {
+    /// Check that the movie can be replayed with the BIOS `bios` and starting disc `disc`
+    pub fn check_setup(&self, bios: &[u8], disc: &mut Disc) -> Result<()> {
+        if sha256(bios) != self.bios_sha256 {
+            return Err(bad_movie("recorded with a different BIOS"));
+        }
+
+        if disc_sha256(disc)? != self.disc_sha256 {
+            let m = format!("recorded with a different disc ({})", self.disc_serial);
+            return Err(bad_movie(&m));
+        }
+
+        if self.core_version != crate::version::VERSION {
+            warn!(
+                "Movie recorded with core version {}, it might not replay correctly",
+                self.core_version
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the save state the movie starts from, if any. `external` is used if the state
+    /// isn't embedded in the movie.
+    pub fn start_state<'a>(&'a self, external: Option<&'a [u8]>) -> Result<Option<&'a [u8]>> {
+        let (sha, data) = match &self.start {
+            StartState::PowerOn => return Ok(None),
+            StartState::SaveState { sha256, data } => (sha256, data.as_deref()),
+        };
+
+        let state = match data.or(external) {
+            Some(s) => s,
+            None => return Err(bad_movie("the starting save state is missing")),
+        };
+
+        if sha256(state) != *sha {
+            return Err(bad_movie("the starting save state doesn't match"));
+        }
+
+        Ok(Some(state))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Movie {
+    pub header: MovieHeader,
+    /// Number of times a save state was loaded while recording
+    rerecords: u32,
+    /// Inputs for each frame
+    frames: Vec<FrameInput>,
+    /// Checksum of the RAM after every `CHECKSUM_INTERVAL` frames
+    checksums: Vec<u32>,
+}
+
+impl Movie {
+    pub fn new(header: MovieHeader) -> Movie {
+        Movie {
+            header,
+            rerecords: 0,
+            frames: Vec::new(),
+            checksums: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Movie> {
+        let mut raw = Vec::new();
+        File::open(path)?.read_to_end(&mut raw)?;
+
+        if raw.len() < 8 || raw[0..4] != MAGIC {
+            return Err(bad_movie("bad magic"));
+        }
+
+        let version = u32::from_le_bytes(*array_ref![raw, 4, 4]);
+        if version != FORMAT_VERSION {
+            let m = format!("unsupported format version {}", version);
+            return Err(bad_movie(&m));
+        }
+
+        let mut data = Vec::new();
+        zstd::stream::Decoder::new(&raw[8..])?
+            .take(MAX_MOVIE_SIZE + 1)
+            .read_to_end(&mut data)?;
+
+        if data.len() as u64 > MAX_MOVIE_SIZE {
+            return Err(bad_movie("file too big"));
+        }
+
+        bincode_options()
+            .deserialize(&data)
+            .map_err(|e| bad_movie(&e.to_string()))
+    }
+
+    /// Save the movie to `path`. The file is replaced atomically so that a crash while saving
+    /// can't destroy the previous recording.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let data = bincode_options()
+            .serialize(self)
+            .map_err(|e| bad_movie(&e.to_string()))?;
+        let data = zstd::encode_all(&data[..], 9)?;
+
+        let mut file = Vec::with_capacity(8 + data.len());
+
+        file.extend_from_slice(&MAGIC);
+        file.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        file.extend_from_slice(&data);
+
+        write_atomic(path, &file)?;
+
+        Ok(())
+    }
+
+    pub fn frames(&self) -> &[FrameInput] {
+        &self.frames
+    }
+
+    pub fn rerecords(&self) -> u32 {
+        self.rerecords
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MovieMode {
+    Record,
+    /// Playback. Loading a save state seeks in the movie.
+    PlayReadOnly,
+    /// Playback. Loading a save state truncates the movie and resumes recording from there.
+    PlayReadWrite,
+}
+
+/// Movie being recorded or played back
+pub struct MovieSession {
+    movie: Movie,
+    /// File the movie is saved to
+    path: PathBuf,
+    mode: MovieMode,
+    /// Index of the next frame
+    frame: usize,
+    /// First frame after which the RAM checksum didn't match the recording
+    desync: Option<usize>,
+}
+
+impl MovieSession {
+    pub fn new(movie: Movie, path: PathBuf, mode: MovieMode) -> MovieSession {
+        MovieSession {
+            movie,
+            path,
+            mode,
+            frame: 0,
+            desync: None,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn mode(&self) -> MovieMode {
+        self.mode
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.mode == MovieMode::Record
+    }
+
+    /// Index of the next frame
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn desync(&self) -> Option<usize> {
+        self.desync
+    }
+
+    /// Returns the inputs to use for the next frame. When recording `live` is stored and returned,
+    /// during playback `live` is ignored and the recorded inputs are returned. Returns `None` once
+    /// the end of the movie has been reached.
+    pub fn frame_input(&mut self, live: FrameInput) -> Option<FrameInput> {
+        if self.is_recording() {
+            self.movie.frames.truncate(self.frame);
+            self.movie.frames.push(live.clone());
+
+            Some(live)
+        } else {
+            self.movie.frames.get(self.frame).cloned()
+        }
+    }
+
+    /// Must be called once the frame is over with the contents of the RAM
+    pub fn end_frame(&mut self, ram: &[u8]) {
+        self.frame += 1;
+
+        if self.frame % CHECKSUM_INTERVAL != 0 {
+            return;
+        }
+
+        let index = self.frame / CHECKSUM_INTERVAL - 1;
+        let checksum = crc32fast::hash(ram);
+
+        if self.is_recording() {
+            self.movie.checksums.truncate(index);
+            self.movie.checksums.push(checksum);
+        } else if self.desync.is_none() {
+            if let Some(&expected) = self.movie.checksums.get(index) {
+                if checksum != expected {
+                    warn!("Movie desync detected after frame {}", self.frame);
+                    self.desync = Some(self.frame);
+                }
+            }
+        }
+    }
+
+    /// Called when a save state made at movie frame `frame` has been loaded
+    pub fn state_loaded(&mut self, frame: usize) {
+        self.frame = frame;
+        self.desync = None;
+
+        match self.mode {
+            MovieMode::PlayReadOnly => (),
+            MovieMode::Record | MovieMode::PlayReadWrite => {
+                let movie = &mut self.movie;
+
+                movie.frames.truncate(frame);
+                movie.checksums.truncate(frame / CHECKSUM_INTERVAL);
+                movie.rerecords += 1;
+
+                if self.mode != MovieMode::Record {
+                    info!("Resuming movie recording at frame {}", frame);
+                    self.mode = MovieMode::Record;
+                }
+            }
+        }
+    }
+
+    /// Write the movie to its file if we were recording
+    pub fn save(&self) -> Result<()> {
+        if !self.is_recording() {
+            return Ok(());
+        }
+
+        info!(
+            "Saving movie to {} ({} frames, {} rerecords)",
+            self.path.display(),
+            self.movie.frames.len(),
+            self.movie.rerecords
+        );
+
+        self.movie.save(&self.path)
+    }
+}
+
+/// Returns the path of the file holding the starting save state of the movie at `movie` when it's
+/// not embedded in the movie itself
+pub fn external_state_path(movie: &Path) -> PathBuf {
+    movie.with_extension("rsxm.state")
+}
+
+/// Compute a SHA-256 identifying `disc`. Hashing a complete disc image would take a while so we
+/// only hash its serial number, table of contents and the first `DISC_HASH_SECTORS` sectors of
+/// the data track.
+pub fn disc_sha256(disc: &mut Disc) -> Result<[u8; 32]> {
+    let mut data = disc.serial_number().to_string().into_bytes();
+
+    let toc = bincode::serialize(disc.toc()).map_err(|e| bad_movie(&e.to_string()))?;
+    data.extend_from_slice(&toc);
+
+    for index in 0..DISC_HASH_SECTORS {
+        let sector = read_track1_sector(disc.cache_mut(), index)?;
+
+        data.extend_from_slice(sector.data_2352());
+    }
+
+    Ok(sha256(&data))
+}
+
+/// Same encoding as `bincode::serialize` with a size limit, so that a corrupted length can't make
+/// us allocate unbounded amounts of memory
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_MOVIE_SIZE)
+}
+
+fn bad_movie(m: &str) -> PsxError {
+    PsxError::BadInputMovie(m.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psx::pad_memcard::devices::gamepad::{DigitalPad, DualShock};
+    use crate::psx::pad_memcard::devices::{negcon, PeripheralAdapter};
+
+    fn header() -> MovieHeader {
+        MovieHeader {
+            core_version: crate::version::VERSION.to_string(),
+            bios_sha256: [0; 32],
+            disc_serial: "SCUS-94163".to_string(),
+            disc_sha256: [0; 32],
+            disc_index: 0,
+            controllers: vec![ControllerType::DualShock],
+            multitap: [false; 2],
+            memory_cards: vec![None; 8],
+            settings: EmulationSettings {
+                gte_overclock: false,
+                cd_loading_speed: 1,
+                reverb_enable: true,
+                reverb_enhanced: false,
+                spu_interpolation: InterpolationMethod::Gaussian,
+                force_transparency: false,
+            },
+            start: StartState::PowerOn,
+        }
+    }
+
+    fn frame(buttons: u32) -> FrameInput {
+        FrameInput {
+            pads: vec![PadInput {
+                buttons,
+                axes: [0; 4],
+            }],
+            disc: Vec::new(),
+        }
+    }
+
+    /// Read the digital buttons of `device`
+    fn read_buttons(device: &mut dyn DeviceInterface) -> u16 {
+        device.select();
+
+        let reply: Vec<u8> = [0x01, 0x42, 0, 0, 0]
+            .iter()
+            .enumerate()
+            .map(|(seq, &b)| device.handle_command(seq as u8, b).0)
+            .collect();
+
+        u16::from_le_bytes([reply[3], reply[4]])
+    }
+
+    #[test]
+    fn apply_pad_input() {
+        let mut input = PadInput::default();
+
+        input.set_button(Button::Start, ButtonState::Pressed);
+        input.set_button(Button::Cross, ButtonState::Pressed);
+        input.set_button(Button::Cross, ButtonState::Released);
+        input.set_button(Button::Circle, ButtonState::Pressed);
+
+        let mut pad = DigitalPad::new();
+        input.apply(&mut pad);
+        assert_eq!(read_buttons(&mut pad), !((1 << 3) | (1 << 13)));
+
+        let mut ds = DualShock::new();
+        input.apply(&mut ds);
+        assert_eq!(read_buttons(&mut ds), !((1 << 3) | (1 << 13)));
+
+        // NeGcon Start button
+        let mut input = PadInput::default();
+        input.set_peripheral_button(7, true);
+
+        let mut negcon = PeripheralAdapter::new(negcon::negcon());
+        input.apply(&mut negcon);
+        assert_eq!(read_buttons(&mut negcon), !(1 << 3));
+    }
+
+    #[test]
+    fn record_and_rerecord() {
+        let mut session = MovieSession::new(
+            Movie::new(header()),
+            PathBuf::from("test.rsxm"),
+            MovieMode::Record,
+        );
+
+        let ram = vec![0u8; 1024];
+
+        for f in 0..CHECKSUM_INTERVAL * 2 {
+            assert_eq!(session.frame_input(frame(f as u32)), Some(frame(f as u32)));
+            session.end_frame(&ram);
+        }
+
+        assert_eq!(session.movie().frames().len(), CHECKSUM_INTERVAL * 2);
+        assert_eq!(session.movie().checksums.len(), 2);
+
+        // Load a state made in the middle of the first checksum interval and record again
+        session.state_loaded(10);
+        assert_eq!(session.movie().rerecords(), 1);
+        assert_eq!(session.movie().frames().len(), 10);
+        assert!(session.movie().checksums.is_empty());
+
+        session.frame_input(frame(1000));
+        session.end_frame(&ram);
+
+        assert_eq!(session.frame(), 11);
+        assert_eq!(session.movie().frames()[10], frame(1000));
+    }
+
+    #[test]
+    fn playback_desync() {
+        let mut movie = Movie::new(header());
+
+        movie.frames = (0..CHECKSUM_INTERVAL * 2)
+            .map(|f| frame(f as u32))
+            .collect();
+        movie.checksums = vec![crc32fast::hash(&[0; 16]), crc32fast::hash(&[1; 16])];
+
+        let mut session = MovieSession::new(movie, PathBuf::new(), MovieMode::PlayReadOnly);
+
+        for f in 0..CHECKSUM_INTERVAL * 2 {
+            // The live inputs are ignored during playback
+            assert_eq!(session.frame_input(frame(0xffff)), Some(frame(f as u32)));
+            session.end_frame(&[0; 16]);
+        }
+
+        assert_eq!(session.desync(), Some(CHECKSUM_INTERVAL * 2));
+        assert_eq!(session.frame_input(frame(0)), None);
+
+        // Seeking in read-only mode doesn't change the movie
+        session.state_loaded(5);
+        assert_eq!(session.frame_input(frame(0)), Some(frame(5)));
+        assert_eq!(session.movie().rerecords(), 0);
+        assert_eq!(session.mode(), MovieMode::PlayReadOnly);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.rsxm");
+
+        let mut movie = Movie::new(header());
+        movie.frames = (0..100).map(frame).collect();
+        movie.rerecords = 3;
+
+        movie.save(&path).unwrap();
+
+        let loaded = Movie::load(&path).unwrap();
+        assert_eq!(loaded.frames(), movie.frames());
+        assert_eq!(loaded.rerecords(), 3);
+        assert_eq!(loaded.header.settings, movie.header.settings);
+
+        // A bogus length must be rejected, not allocated
+        let mut bogus = MAGIC.to_vec();
+        bogus.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bogus.extend_from_slice(&zstd::encode_all(&[0xff; 64][..], 0).unwrap());
+        std::fs::write(&path, bogus).unwrap();
+
+        assert!(Movie::load(&path).is_err());
+    }
+
+    #[test]
+    fn read_write_playback_resumes_recording() {
+        let mut movie = Movie::new(header());
+        movie.frames = (0..100).map(frame).collect();
+
+        let mut session = MovieSession::new(movie, PathBuf::new(), MovieMode::PlayReadWrite);
+
+        session.state_loaded(50);
+
+        assert_eq!(session.mode(), MovieMode::Record);
+        assert_eq!(session.movie().frames().len(), 50);
+        assert_eq!(session.movie().rerecords(), 1);
+        assert_eq!(session.frame_input(frame(7)), Some(frame(7)));
+    }
+}
@@ -44,12 +44,20 @@ mod accessibility;
 mod assembler;
 mod bitwise;
 mod box_array;
+#[cfg(feature = "cli")]
+pub mod cli;
 mod disc_control;
 mod error;
 pub mod frame_pacing;
+#[cfg(test)]
+mod golden;
+mod input_layer;
+mod input_movie;
 mod memory_card;
 mod memory_card_manager;
+mod post_process;
 mod psx_memory_card_integration;
+mod resampler;
 mod rewind;
 mod rewind_integration;
 mod achievement;
@@ -84,17 +92,28 @@ use crate::sha::sha256;
 use box_array::BoxArray;
 use cdimage::cue::Cue;
 use error::{PsxError, Result};
+use input_movie::{
+    DiscEvent, EmulationSettings, FrameInput, MovieMode, MovieSession, PadInput, StartState,
+};
+use memory_card::filesystem::Card;
 use memory_card::MemoryCardFile;
 use psx::bios::Metadata;
 use psx::bios::{Bios, BIOS_SIZE};
 use psx::cd::CdcState;
 use psx::disc::Disc;
-use psx::gpu::RasterizerOption;
+use psx::psf::{Fader, Psf};
+use psx::gpu::{CropMode, DeinterlaceMode, Frame, OverscanCrop, RasterizerOption};
+use psx::interpolation::InterpolationMethod;
 use psx::pad_memcard::devices::gamepad::{Button, ButtonState, DigitalPad, DualShock};
-use psx::pad_memcard::devices::{DeviceInterface, DisconnectedDevice};
-use psx::{CDC_ROM_SHA256, CDC_ROM_SIZE};
+use psx::pad_memcard::devices::memory_card::{MemoryCard, FLASH_SIZE};
+use psx::pad_memcard::devices::{
+    dance_mat, fishing, guncon, justifier, mouse, multitap, negcon, DeviceInterface,
+    DisconnectedDevice, PeripheralAdapter,
+};
+use psx::{ChromaUpsampling, MdecEnhancement, CDC_ROM_SHA256, CDC_ROM_SIZE};
 use serde::{Serialize, Deserialize};
-use std::ffi::OsStr;
+use std::cell::OnceCell;
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
@@ -104,11 +123,31 @@ mod version {
     include!(concat!(env!("OUT_DIR"), "/version.rs"));
 }
 
+/// Returns the root of the flexbuffer in save state `buf`
+fn save_state_root(buf: &[u8]) -> Result<flexbuffers::Reader<&[u8]>> {
+    let bad_state = |reason: &str| PsxError::SaveStateError {
+        operation: "load".to_string(),
+        reason: reason.to_string(),
+    };
+
+    if buf.len() < 8 || buf[0..4] != *b"RSX1" {
+        return Err(bad_state("bad magic"));
+    }
+
+    let len = u32::from_le_bytes(*array_ref![buf, 4, 4]) as usize;
+
+    let buf = buf
+        .get(8..(8 + len))
+        .ok_or_else(|| bad_state("truncated"))?;
+
+    flexbuffers::Reader::get_root(buf).map_err(|e| bad_state(&e.to_string()))
+}
+
 /// Static system information sent to the frontend on request
 const SYSTEM_INFO: libretro::SystemInfo = libretro::SystemInfo {
     library_name: cstring!("rustation-ng"),
     library_version: version::VERSION_CSTR as *const _ as *const libc::c_char,
-    valid_extensions: cstring!("cue|zip"),
+    valid_extensions: cstring!("cue|zip|psf|minipsf"),
     need_fullpath: true,
     block_extract: true,
 };
@@ -119,6 +158,10 @@ struct SaveState<'a> {
     psx: &'a psx::Psx,
     disc_manager: &'a disc_control::MultiDiscManager,
     current_disc_index: usize,
+    /// Frame of the input movie being recorded or played back when the state was saved
+    movie_frame: Option<usize>,
+    /// Turbo phase, toggled buttons and macros being played back
+    input_layer: &'a input_layer::LayerState,
 }
 
 /// Structure for deserializing save states with disc information
@@ -127,6 +170,10 @@ struct LoadedSaveState {
     psx: psx::Psx,
     disc_manager: disc_control::MultiDiscManager,
     current_disc_index: usize,
+    #[serde(default)]
+    movie_frame: Option<usize>,
+    #[serde(default)]
+    input_layer: input_layer::LayerState,
 }
 
 /// Emulation context containing the emulator state
@@ -138,12 +185,24 @@ struct Context {
     cur_image: usize,
     /// Multi-disc manager for handling disc swaps
     disc_manager: disc_control::MultiDiscManager,
-    /// The type of controller connected on the two input ports
-    controller_type: [libretro::InputDevice; 2],
+    /// The type of controller configured on each libretro input port
+    controller_type: [ControllerType; MAX_PLAYERS],
+    /// Controllers requested by the frontend while an input movie session is active. The ports
+    /// keep the configuration of the movie until the session ends.
+    saved_controller_type: Option<[ControllerType; MAX_PLAYERS]>,
+    /// True if a multitap is plugged in the console's port 1 and/or 2
+    multitap: [bool; 2],
     /// The type of MemoryCards connected to the console (user-configurable)
     memcard_types: [options::MemoryCardType; 2],
-    /// Objects used to deal with reading/storing the memory card images to files
-    memcard_files: [MemoryCardFile; 2],
+    /// True if memory cards are connected to the slots B to D of the multitaps
+    multitap_memory_cards: bool,
+    /// True if the common memory cards only show the saves of the current game
+    memcard_filter: bool,
+    /// Number of backups kept for every memory card
+    memcard_backups: usize,
+    /// Objects used to deal with reading/storing the memory card images to files, indexed by
+    /// `memcard_index`
+    memcard_files: [MemoryCardFile; 2 * multitap::SLOTS],
     /// Internal frame width
     internal_width: u32,
     /// Internal frame height
@@ -160,12 +219,22 @@ struct Context {
     analog_compensation: f32,
     /// CD state overlay mode
     cd_overlay: CdOverlay,
+    /// Post-processing filters applied to the output frames
+    post_process: post_process::FilterChain,
+    /// Limits the playback length and fades out the audio when playing a PSF music rip
+    psf_fader: Option<Fader>,
+    /// Converts the SPU output to the frontend's sample rate
+    resampler: resampler::OutputResampler,
     /// Current position of the CD spin indicator
     cd_spin_pos: f32,
     /// Accessibility features manager
     accessibility_manager: accessibility::AccessibilityManager,
-    /// Input remapper for accessibility
-    input_remapper: accessibility::input_remapping::InputRemapper,
+    /// Remapping, turbo and macros applied to the frontend's inputs
+    input_layer: input_layer::InputLayer,
+    /// Path of the input configuration of the game, new macros are saved there
+    input_config_path: Option<PathBuf>,
+    /// Value of the macro recording option last time we looked
+    macro_recording: Option<usize>,
     /// Visual indicator system
     visual_indicators: accessibility::audio_visual_indicators::VisualIndicatorSystem,
     /// Screen reader system
@@ -180,12 +249,23 @@ struct Context {
     /// Mod management system
     #[cfg(feature = "mod-support")]
     mod_manager: Option<mod_management::ModManager>,
+    /// Input movie being recorded or played back
+    movie: Option<MovieSession>,
+    /// Value of the input movie option last time we looked
+    movie_option: options::MovieOption,
+    /// Input movie option changed by the user, applied at the beginning of the next frame
+    pending_movie: Option<options::MovieOption>,
+    /// Disc operations requested by the frontend since the last frame
+    disc_events: Vec<DiscEvent>,
+    /// Value of the memory card manager "execute" option last time we looked. The selected action
+    /// only runs when it's switched on, not while the user scrolls through the actions.
+    memcard_execute: bool,
 }
 
 impl Context {
     fn new(disc: &Path) -> Result<Context> {
         let image = DiscImage::new(disc);
-        let psx = Context::load_disc(&image)?;
+        let (psx, psf_fader) = Context::load_disc(&image)?;
 
         // Initialize disc manager with the first disc
         let disc_info = disc_control::DiscInfo::new(disc, 1);
@@ -198,9 +278,14 @@ impl Context {
             disc_manager,
             // Start with both port disconnected and wait for the frontend to tell us what to use
             // in `set_controller`
-            controller_type: [libretro::InputDevice::None; 2],
+            controller_type: [ControllerType::None; MAX_PLAYERS],
+            saved_controller_type: None,
+            multitap: [false; 2],
             memcard_types: [options::MemoryCardType::Disconnected; 2],
-            memcard_files: [MemoryCardFile::dummy(), MemoryCardFile::dummy()],
+            multitap_memory_cards: false,
+            memcard_filter: false,
+            memcard_backups: 0,
+            memcard_files: std::array::from_fn(|_| MemoryCardFile::dummy()),
             internal_width: 640,
             internal_height: 480,
             max_width: 640,
@@ -209,9 +294,16 @@ impl Context {
             analog_combo: AnalogCombo::SelectR3,
             analog_compensation: 1.,
             cd_overlay: CdOverlay::Disabled,
+            post_process: post_process::FilterChain::new(),
+            psf_fader,
+            // Use the configured rate right away so that we don't have to change the AV info
+            // while the game is being loaded
+            resampler: resampler::OutputResampler::new(options::CoreOptions::audio_output_rate()),
             cd_spin_pos: 0.,
             accessibility_manager: accessibility::AccessibilityManager::new(),
-            input_remapper: accessibility::input_remapping::InputRemapper::new(),
+            input_layer: input_layer::InputLayer::default(),
+            input_config_path: None,
+            macro_recording: options::CoreOptions::input_macro_record(),
             visual_indicators: accessibility::audio_visual_indicators::VisualIndicatorSystem::new(),
             screen_reader: accessibility::screen_reader::ScreenReader::new(),
             slow_motion: accessibility::settings::SlowMotionController::new(),
@@ -220,12 +312,19 @@ impl Context {
             streaming_manager: None,
             #[cfg(feature = "mod-support")]
             mod_manager: None,
+            movie: None,
+            movie_option: options::MovieOption::Disabled,
+            pending_movie: None,
+            disc_events: Vec::new(),
+            // Don't run the action left over from the last session
+            memcard_execute: options::CoreOptions::memory_card_manager_execute(),
         };
 
         libretro::Context::refresh_variables(&mut ctx);
 
         ctx.setup_memory_cards();
-        
+        ctx.load_input_config();
+
         #[cfg(feature = "mod-support")]
         ctx.initialize_mod_manager();
 
@@ -250,250 +349,1048 @@ impl Context {
     }
 
     fn poll_controllers(&mut self) {
-        let gamepads = self.psx.pad_memcard.gamepads_mut();
-        
-        // Get current frame count for input processing
-        static mut FRAME_COUNT: u32 = 0;
-        unsafe { FRAME_COUNT += 1; }
-        let frame_count = unsafe { FRAME_COUNT };
+        let mut pads: Vec<PadInput> = (0..MAX_PLAYERS)
+            .map(|player| self.read_pad_input(player))
+            .collect();
+
+        // Movies record the processed inputs so that they can be played back without the input
+        // configuration
+        self.input_layer.process(&mut pads, &self.controller_type);
+
+        let live = FrameInput {
+            pads,
+            disc: std::mem::take(&mut self.disc_events),
+        };
+
+        let input = match self.movie.as_mut().map(|m| m.frame_input(live.clone())) {
+            None => live,
+            Some(Some(input)) => input,
+            Some(None) => {
+                info!("End of the input movie");
+                self.stop_movie();
+                live
+            }
+        };
+
+        // During playback the disc swaps come from the movie
+        if self.is_playing_movie() {
+            for &event in &input.disc {
+                if self.apply_disc_event(event).is_err() {
+                    warn!("Movie disc operation failed: {:?}", event);
+                }
+            }
+        }
+
+        for (player, pad) in input.pads.iter().enumerate() {
+            let (port, slot) = match self.player_slot(player) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let gamepad = match self.psx.pad_memcard.gamepad_mut(port, slot) {
+                Some(gp) => gp,
+                None => continue,
+            };
+
+            let device = gamepad.device_mut();
+
+            pad.apply(device);
+
+            if self.controller_type[player] == ControllerType::DualShock {
+                let (strong, weak) = device.get_rumble();
+
+                // Values are 8 bits on the PSX
+                let mut strong = strong as u16;
+                strong |= strong << 8;
+                libretro::set_rumble(player, libretro::RumbleEffect::Strong, strong);
+
+                let mut weak = weak as u16;
+                weak |= weak << 8;
+                libretro::set_rumble(player, libretro::RumbleEffect::Weak, weak);
+            }
+        }
+    }
+
+    /// Read the frontend's inputs for `player`
+    fn read_pad_input(&mut self, player: usize) -> PadInput {
+        let mut input = PadInput::default();
+
+        if self.player_slot(player).is_none() {
+            return input;
+        }
+
+        let ty = self.controller_type[player];
+
+        // Update buttons
+        let has_buttons = ty == ControllerType::Digital || ty == ControllerType::DualShock;
+
+        let mut select_pressed = false;
+        let mut l3_pressed = false;
+        let mut r3_pressed = false;
+
+        if has_buttons {
+            for &(retrobutton, psxbutton) in &BUTTON_MAP {
+                let state = if libretro::button_pressed(player, retrobutton) {
+                    ButtonState::Pressed
+                } else {
+                    ButtonState::Released
+                };
+
+                // Track special buttons for analog combo
+                if state == ButtonState::Pressed {
+                    match retrobutton {
+                        libretro::JoyPadButton::Select => select_pressed = true,
+                        libretro::JoyPadButton::R3 => r3_pressed = true,
+                        libretro::JoyPadButton::L3 => l3_pressed = true,
+                        _ => (),
+                    }
+                }
+
+                input.set_button(psxbutton, state);
+            }
+        }
+
+        if ty == ControllerType::DualShock {
+            // Special combo for the Analog button
+            let analog_pressed = match self.analog_combo {
+                AnalogCombo::SelectR3 => select_pressed && r3_pressed,
+                AnalogCombo::SelectL3 => select_pressed && l3_pressed,
+                AnalogCombo::L3R3 => l3_pressed && r3_pressed,
+            };
+
+            input.set_button(
+                Button::Analog,
+                if analog_pressed {
+                    ButtonState::Pressed
+                } else {
+                    ButtonState::Released
+                },
+            );
+        }
+
+        // Update analog sticks. The digital pad doesn't have any but the input layer can map them
+        // to its D-pad.
+        if has_buttons {
+            let comp = self.analog_compensation;
+
+            let compensate = |v: i16| {
+                let v = f32::from(v) * comp;
+
+                if v > f32::from(i16::MAX) {
+                    i16::MAX
+                } else if v < f32::from(i16::MIN) {
+                    i16::MIN
+                } else {
+                    v as i16
+                }
+            };
+
+            let axis = |stick, axis| compensate(libretro::axis_state(player, stick, axis));
+
+            input.axes = [
+                axis(libretro::AnalogInput::Left, libretro::AnalogAxis::X),
+                axis(libretro::AnalogInput::Left, libretro::AnalogAxis::Y),
+                axis(libretro::AnalogInput::Right, libretro::AnalogAxis::X),
+                axis(libretro::AnalogInput::Right, libretro::AnalogAxis::Y),
+            ];
+        }
+
+        // Other peripherals (NeGcon, mouse, GunCon...)
+        poll_peripheral(player, ty, &mut input);
+
+        input
+    }
+
+    fn load_image(image: &DiscImage) -> Result<Disc> {
+        let path = image.path();
+
+        let disc = if path.extension().and_then(|ext| ext.to_str()) == Some("cue") {
+            Cue::new(path)
+        } else {
+            Cue::new_from_zip(path)
+        }?;
+
+        let disc = Disc::new(Box::new(disc))?;
+
+        let serial = disc.serial_number();
+        let region = disc.region();
+
+        info!("Disc serial number: {}", serial);
+        info!("Detected disc region: {:?}", region);
+
+        Ok(disc)
+    }
+
+    /// Build a new console for `image`. If it's a PSF music rip the `Fader` used to limit the
+    /// playback length is returned alongside.
+    fn load_disc(image: &DiscImage) -> Result<(Box<psx::Psx>, Option<Fader>)> {
+        if image.is_psf() {
+            return Context::load_psf(image);
+        }
+
+        let disc = Context::load_image(image)?;
+        let region = disc.region();
+
+        let bios = find_bios(|md| md.region == region)?;
+
+        let cdc_firmware = find_cdc_firmware()?;
+
+        let psx = Box::new(psx::Psx::new_with_disc(disc, bios, cdc_firmware)?);
+
+        Ok((psx, None))
+    }
+
+    fn load_psf(image: &DiscImage) -> Result<(Box<psx::Psx>, Option<Fader>)> {
+        let psf = Psf::load(image.path())?;
+        let tags = psf.tags();
+
+        info!(
+            "PSF: {} - {} ({})",
+            tags.game().unwrap_or("?"),
+            tags.title().unwrap_or("?"),
+            tags.artist().unwrap_or("?")
+        );
+
+        // Any BIOS should do but try to match the region of the rip first
+        let region = psf.region();
+        let bios = find_bios(|md| Some(md.region) == region).or_else(|_| find_bios(|_| true))?;
+
+        let cdc_firmware = find_cdc_firmware()?;
+
+        let psx = Box::new(psx::Psx::new_with_psf(&psf, bios, cdc_firmware)?);
+
+        Ok((psx, Some(psf.fader())))
+    }
+
+    /// Returns the console port and slot (multitap slots A to D) used by libretro port `player`
+    fn player_slot(&self, player: usize) -> Option<(usize, usize)> {
+        player_slot(self.multitap, player)
+    }
+
+    /// Connect a new controller of the type configured for `player`, if the player currently has
+    /// a slot on the console
+    fn connect_controller(&mut self, player: usize) {
+        let (port, slot) = match self.player_slot(player) {
+            Some(s) => s,
+            None => return,
+        };
+
+        let new_pad = self.controller_type[player].new_device(self.psx.video_standard());
+
+        info!(
+            "New controller for player {} on port {}{}: {}",
+            player + 1,
+            port + 1,
+            slot_name(self.multitap[port], slot),
+            new_pad.description()
+        );
+
+        if let Some(gp) = self.psx.pad_memcard.gamepad_mut(port, slot) {
+            gp.connect_device(new_pad);
+        }
+    }
+
+    /// Plug or unplug the multitaps and reconnect the controllers, since the players' slots
+    /// depend on the multitap configuration
+    fn set_multitaps(&mut self, multitap: [bool; 2]) {
+        for (port, &tap) in multitap.iter().enumerate() {
+            if tap {
+                info!("Multitap connected to port {}", port + 1);
+                self.psx.pad_memcard.connect_multitap(port);
+            } else {
+                self.psx.pad_memcard.disconnect_multitap(port);
+            }
+        }
+
+        self.multitap = multitap;
+
+        for player in 0..MAX_PLAYERS {
+            self.connect_controller(player);
+        }
 
+        libretro::set_input_descriptors(&input_descriptors(&self.controller_type));
+    }
+
+    /// Disconnect any configured Memory Card
+    fn disconnect_memory_cards(&mut self) {
         for port in 0..2 {
-            let ty = self.controller_type[port];
-
-            // Update buttons
-            let has_buttons =
-                ty == libretro::InputDevice::JoyPad || ty == libretro::InputDevice::Analog;
-
-            let mut select_pressed = false;
-            let mut l3_pressed = false;
-            let mut r3_pressed = false;
-
-            if has_buttons {
-                let device = gamepads[port].device_mut();
-
-                for &(retrobutton, psxbutton) in &BUTTON_MAP {
-                    let raw_pressed = libretro::button_pressed(port, retrobutton);
-                    
-                    // Track special buttons for analog combo
-                    if raw_pressed {
-                        match retrobutton {
-                            libretro::JoyPadButton::Select => select_pressed = true,
-                            libretro::JoyPadButton::R3 => r3_pressed = true,
-                            libretro::JoyPadButton::L3 => l3_pressed = true,
-                            _ => (),
+            for slot in 0..multitap::SLOTS {
+                if let Some(m) = self.psx.pad_memcard.memory_card_mut(port, slot) {
+                    m.connect_device(Box::new(DisconnectedDevice));
+                }
+            }
+        }
+
+        for m in self.memcard_files.iter_mut() {
+            *m = MemoryCardFile::dummy();
+        }
+    }
+
+    /// Initialize the memory cards connected to the emulated console
+    fn setup_memory_cards(&mut self) {
+        // Start by disconnecting everything to make sure we never half-configure the memory card.
+        // We want to make it hard for the user to lose their saves...
+        self.disconnect_memory_cards();
+
+        let save_path = match libretro::get_save_directory() {
+            Some(p) => p,
+            None => {
+                warn!("No save directory defined, disabling memory cards");
+                return;
+            }
+        };
+
+        let serials = self.game_serials();
+
+        // Per-game cards are named after the serial number of the game so that every disc of a
+        // set uses the same card. We fall back on the name of the image if we don't have a
+        // serial number, for instance for PSF rips.
+        let game = match serials.first() {
+            Some(s) => OsString::from(s),
+            None => self.cur_image().basename().to_owned(),
+        };
+
+        for port in 0..2 {
+            for slot in 0..multitap::SLOTS {
+                let filename = if slot == 0 {
+                    match self.memcard_types[port] {
+                        options::MemoryCardType::Disconnected => continue,
+                        options::MemoryCardType::Common(idx) => {
+                            let p = format!("rustation_common.{}.mcr", idx);
+
+                            save_path.join(p)
+                        }
+                        options::MemoryCardType::PerGame(idx) => {
+                            let ext = format!("{}.mcr", idx);
+
+                            self.per_game_memory_card(&save_path, &game, &ext)
                         }
                     }
-                    
-                    // Process through input remapper for accessibility
-                    let input_button = Self::retro_to_input_button(retrobutton);
-                    if let Some(input_btn) = input_button {
-                        let remapped = self.input_remapper.process_input(input_btn, raw_pressed, frame_count);
-                        
-                        // Apply remapped inputs
-                        for (mapped_btn, pressed) in remapped {
-                            if let Some(psx_btn) = Self::input_to_psx_button(mapped_btn) {
-                                let state = if pressed {
-                                    ButtonState::Pressed
-                                } else {
-                                    ButtonState::Released
-                                };
-                                device.set_button_state(psx_btn, state);
-                            }
+                } else {
+                    // The multitap slots B to D always use per-game cards, named after the
+                    // slot (`game.1B.mcr` etc...)
+                    if !self.multitap[port] || !self.multitap_memory_cards {
+                        continue;
+                    }
+
+                    let ext = format!("{}{}.mcr", port + 1, slot_name(true, slot));
+
+                    self.per_game_memory_card(&save_path, &game, &ext)
+                };
+
+                let filtered = slot == 0
+                    && self.memcard_filter
+                    && !serials.is_empty()
+                    && matches!(self.memcard_types[port], options::MemoryCardType::Common(_));
+
+                let res = if filtered {
+                    MemoryCardFile::load_filtered(&filename, &serials)
+                } else {
+                    MemoryCardFile::load_or_create(&filename)
+                };
+
+                match res {
+                    Ok((mut mcf, mc)) => {
+                        mcf.set_backups(self.memcard_backups);
+
+                        // Success
+                        info!(
+                            "Memory Card {}{} is {}",
+                            port + 1,
+                            slot_name(self.multitap[port], slot),
+                            mcf.path().display()
+                        );
+
+                        if let Some(m) = self.psx.pad_memcard.memory_card_mut(port, slot) {
+                            m.connect_device(Box::new(mc));
+                            self.memcard_files[memcard_index(port, slot)] = mcf;
                         }
-                    } else {
-                        // Normal input processing for unmapped buttons
-                        let state = if raw_pressed {
-                            ButtonState::Pressed
-                        } else {
-                            ButtonState::Released
-                        };
-                        device.set_button_state(psxbutton, state);
+                    }
+                    Err(e) => {
+                        error!(
+                            "Can't load or create memory card '{}': {}",
+                            filename.display(),
+                            e
+                        );
                     }
                 }
             }
+        }
+    }
 
-            // Update analog sticks
-            let has_sticks = ty == libretro::InputDevice::Analog;
+    /// Returns the path of the per-game memory card with extension `ext`. Per-game cards used to
+    /// be named after the disc image, if we find one of those and the new card doesn't exist yet
+    /// we start with a copy of the old one.
+    fn per_game_memory_card(&self, save_path: &Path, game: &OsStr, ext: &str) -> PathBuf {
+        let filename = save_path.join(Path::new(game).with_extension(ext));
+
+        let basename: &Path = self.cur_image().basename().as_ref();
+        let legacy = save_path.join(basename.with_extension(ext));
+
+        if legacy != filename && !filename.exists() && legacy.exists() {
+            match std::fs::copy(&legacy, &filename) {
+                Ok(_) => info!(
+                    "Memory Card '{}' copied to '{}'",
+                    legacy.display(),
+                    filename.display()
+                ),
+                Err(e) => error!(
+                    "Can't copy memory card '{}' to '{}': {}",
+                    legacy.display(),
+                    filename.display(),
+                    e
+                ),
+            }
+        }
 
-            if has_sticks {
-                let device = gamepads[port].device_mut();
+        filename
+    }
 
-                // Special combo for the Analog button
-                let analog_pressed = match self.analog_combo {
-                    AnalogCombo::SelectR3 => select_pressed && r3_pressed,
-                    AnalogCombo::SelectL3 => select_pressed && l3_pressed,
-                    AnalogCombo::L3R3 => l3_pressed && r3_pressed,
-                };
+    /// Returns the serial numbers of the game: the one of the first disc of the set, which
+    /// identifies the game, followed by the one of the current disc if it's different. Empty if
+    /// we're not running a disc image.
+    fn game_serials(&self) -> Vec<String> {
+        let mut serials = Vec::new();
 
-                device.set_button_state(
-                    Button::Analog,
-                    if analog_pressed {
-                        ButtonState::Pressed
-                    } else {
-                        ButtonState::Released
-                    },
-                );
+        let mut images = vec![0, self.cur_image];
+        images.dedup();
+
+        for index in images {
+            let serial = match self.images.get(index).and_then(DiscImage::serial_number) {
+                Some(s) => s.to_string(),
+                None => continue,
+            };
+
+            if !serials.contains(&serial) {
+                serials.push(serial);
+            }
+        }
+
+        serials
+    }
+
+    /// Load the input configuration of the game from `<save dir>/input/<serial>.toml`, or from
+    /// `<save dir>/input/default.toml` if the game doesn't have one
+    fn load_input_config(&mut self) {
+        self.input_layer = input_layer::InputLayer::default();
+        self.input_config_path = None;
+
+        let dir = match libretro::get_save_directory() {
+            Some(d) => d.join("input"),
+            None => {
+                warn!("No save directory defined, using the default input configuration");
+                return;
+            }
+        };
+
+        let game = match self.game_serials().into_iter().next() {
+            Some(s) => OsString::from(s),
+            None => self.cur_image().basename().to_owned(),
+        };
+
+        let path = dir.join(Path::new(&game).with_extension("toml"));
+        let default = dir.join("default.toml");
+
+        if let Some(source) = [&path, &default].into_iter().find(|p| p.exists()) {
+            match input_layer::Config::load(source).and_then(input_layer::InputLayer::with_config) {
+                Ok(layer) => {
+                    info!("Loaded input configuration '{}'", source.display());
+                    self.input_layer = layer;
+                }
+                Err(e) => {
+                    error!(
+                        "Can't load input configuration '{}': {}",
+                        source.display(),
+                        e
+                    );
+                    libretro::set_message(3000, &format!("Invalid input configuration: {}", e));
+                }
+            }
+        }
+
+        self.input_config_path = Some(path);
+    }
+
+    /// Stop the macro being recorded, if any, and start recording one for `player`
+    fn record_macro(&mut self, player: Option<usize>) {
+        if self.input_layer.is_recording() {
+            let trigger = options::CoreOptions::input_macro_trigger();
+
+            let res = self
+                .input_layer
+                .stop_recording(&trigger)
+                .and_then(|(player, len)| {
+                    if let Some(path) = &self.input_config_path {
+                        self.input_layer.config().save(path)?;
+                    }
+
+                    Ok(format!(
+                        "Macro of {} frames assigned to {} for player {}",
+                        len,
+                        trigger.to_ascii_uppercase(),
+                        player + 1
+                    ))
+                });
+
+            let message = match res {
+                Ok(m) => m,
+                Err(e) => {
+                    warn!("Macro recording failed: {}", e);
+                    format!("Macro recording failed: {}", e)
+                }
+            };
+
+            libretro::set_message(3000, &message);
+        }
+
+        if let Some(player) = player {
+            self.input_layer.start_recording(player);
+
+            libretro::set_message(
+                3000,
+                &format!("Recording a macro for player {}", player + 1),
+            );
+        }
+    }
+
+    /// Connect the multitaps and memory cards configured in the core options. If `force` is true
+    /// everything is reconnected even if the configuration didn't change.
+    fn refresh_ports(&mut self, force: bool) {
+        let mut memcard_types = [
+            options::CoreOptions::memory_card_1_type(),
+            options::CoreOptions::memory_card_2_type(),
+        ];
+
+        if memcard_types[0] == memcard_types[1] {
+            warn!("Both memory cards are set to the same type, disconnecting slot 2");
+            memcard_types[1] = options::MemoryCardType::Disconnected;
+        }
+
+        let multitap = options::CoreOptions::multitap();
+        let multitap_memory_cards = options::CoreOptions::multitap_memory_cards();
+        let memcard_filter = options::CoreOptions::memory_card_filter();
+
+        let memcard_backups = options::CoreOptions::memory_card_backups();
+        if memcard_backups != self.memcard_backups {
+            self.memcard_backups = memcard_backups;
+
+            for m in self.memcard_files.iter_mut() {
+                m.set_backups(memcard_backups);
+            }
+        }
+
+        let multitap_changed = force || multitap != self.multitap;
+
+        if multitap_changed {
+            // The memory cards in the slots B to D are dropped with the multitap
+            self.flush_memory_cards();
+            self.set_multitaps(multitap);
+        }
+
+        if multitap_changed
+            || memcard_types != self.memcard_types
+            || multitap_memory_cards != self.multitap_memory_cards
+            || memcard_filter != self.memcard_filter
+        {
+            self.flush_memory_cards();
+            self.memcard_types = memcard_types;
+            self.multitap_memory_cards = multitap_memory_cards;
+            self.memcard_filter = memcard_filter;
+            self.setup_memory_cards();
+        }
+    }
+
+    /// Open the tray and remove the disc
+    fn eject(&mut self) -> ::std::result::Result<(), ()> {
+        // Start the ejection animation
+        if let Err(e) = self.disc_manager.start_eject() {
+            error!("Failed to start disc ejection: {}", e);
+            return Err(());
+        }
+        
+        // Actually eject the disc from the emulator
+        if let Some(disc) = self.psx.cd.eject_disc() {
+            // Update disc metadata in the manager
+            if let Some(current_info) = self.disc_manager.current_disc_info() {
+                self.disc_manager.load_disc_metadata(&disc, &current_info.path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Insert the disc image `cur_image` and close the tray
+    fn insert(&mut self) -> ::std::result::Result<(), ()> {
+        // Start the insertion animation
+        if let Err(e) = self.disc_manager.start_insert() {
+            error!("Failed to start disc insertion: {}", e);
+            return Err(());
+        }
+        
+        let disc = match Context::load_image(self.cur_image()) {
+            Ok(disc) => disc,
+            Err(e) => {
+                error!("Couldn't load {:?}: {}", self.cur_image().path(), e);
+                return Err(());
+            }
+        };
+
+        // Update disc metadata in the manager
+        self.disc_manager.load_disc_metadata(&disc, self.cur_image().path());
+
+        // Swap the memory cards if necessary since they depend on the game. The memory cards of
+        // input movies are only loaded at the start of the movie.
+        if self.saved_controller_type.is_none() {
+            self.flush_memory_cards();
+            self.setup_memory_cards();
+        }
+
+        self.psx.cd.load_disc(disc);
+
+        Ok(())
+    }
+
+    /// Select the disc image inserted next time the tray is closed
+    fn select_image(&mut self, index: usize) -> ::std::result::Result<(), ()> {
+        if index >= self.images.len() {
+            // The libretro spec says that this can mean that we want to load no disc, but for us
+            // there's not really any difference between no disc loaded and disc ejected, so I'm
+            // not sure if it's worth handling this case
+            error!("Ignoring set_image_index with an index out of range");
+            return Err(());
+        }
+
+        if !libretro::Context::is_disc_ejected(self) && index != self.cur_image {
+            error!("Refusing to change the image index while the CD is not ejected");
+            return Err(());
+        }
+
+        // Validate the disc swap with the manager
+        if let Err(e) = self.disc_manager.validate_disc_swap(Some(self.cur_image), index) {
+            error!("Invalid disc swap: {}", e);
+            return Err(());
+        }
+
+        // Update the disc manager's selection
+        if self.disc_manager.is_tray_open() {
+            if let Err(e) = self.disc_manager.select_disc(index) {
+                error!("Failed to select disc: {}", e);
+                return Err(());
+            }
+        }
+
+        self.cur_image = index;
+
+        Ok(())
+    }
+
+    /// Perform a disc operation requested by the frontend. The operation is stored in the input
+    /// movie if we're recording one. The frontend can't touch the disc during movie playback.
+    fn disc_event(&mut self, event: DiscEvent) -> ::std::result::Result<(), ()> {
+        if self.is_playing_movie() {
+            error!("Refusing to change the disc during input movie playback");
+            return Err(());
+        }
+
+        self.apply_disc_event(event)?;
+
+        if self.movie.is_some() {
+            self.disc_events.push(event);
+        }
+
+        Ok(())
+    }
+
+    fn apply_disc_event(&mut self, event: DiscEvent) -> ::std::result::Result<(), ()> {
+        match event {
+            DiscEvent::Eject => self.eject(),
+            DiscEvent::Insert(index) => {
+                if index != self.cur_image {
+                    self.select_image(index)?;
+                }
+
+                self.insert()
+            }
+        }
+    }
+
+    fn is_playing_movie(&self) -> bool {
+        self.movie.as_ref().map_or(false, |m| !m.is_recording())
+    }
+
+    /// Apply a new value of the input movie option
+    fn set_movie(&mut self, option: options::MovieOption) {
+        self.stop_movie();
+
+        let mode = match option {
+            options::MovieOption::Disabled => return,
+            options::MovieOption::RecordPowerOn | options::MovieOption::RecordState => {
+                MovieMode::Record
+            }
+            options::MovieOption::PlayReadOnly => MovieMode::PlayReadOnly,
+            options::MovieOption::PlayReadWrite => MovieMode::PlayReadWrite,
+        };
+
+        let path = match libretro::get_save_directory() {
+            Some(dir) => {
+                let p: &Path = self.cur_image().basename().as_ref();
+                dir.join(p.with_extension("rsxm"))
+            }
+            None => {
+                error!("No save directory defined, can't use input movies");
+                return;
+            }
+        };
+
+        // The ports are configured by the movie until the end of the session
+        self.saved_controller_type = Some(self.controller_type);
+
+        let res = if mode == MovieMode::Record {
+            self.record_movie(path, option == options::MovieOption::RecordPowerOn)
+        } else {
+            self.play_movie(path, mode)
+        };
+
+        match res {
+            Ok(session) => self.movie = Some(session),
+            Err(e) => {
+                error!("Couldn't start input movie: {}", e);
+                self.restore_ports();
+            }
+        }
+    }
+
+    fn record_movie(&mut self, path: PathBuf, power_on: bool) -> Result<MovieSession> {
+        let start = if power_on {
+            libretro::Context::reset(self);
+            // Reconnect everything to start with fresh devices, like a real power-on
+            self.refresh_ports(true);
+            StartState::PowerOn
+        } else {
+            let state = self.save_state()?;
+            let sha = sha256(&state);
+
+            let data = if options::CoreOptions::input_movie_embed_state() {
+                Some(state)
+            } else {
+                let state_path = input_movie::external_state_path(&path);
+                std::fs::write(&state_path, &state)?;
+                info!("Movie starting state saved to {}", state_path.display());
+                None
+            };
+
+            StartState::SaveState { sha256: sha, data }
+        };
+
+        let mut disc = Context::load_image(self.cur_image())?;
+
+        let mut memory_cards = Vec::with_capacity(self.memcard_files.len());
+        for port in 0..2 {
+            for slot in 0..multitap::SLOTS {
+                let memory = self
+                    .psx
+                    .pad_memcard
+                    .memory_card(port, slot)
+                    .and_then(|mc| mc.device().get_memory())
+                    .map(|m| m.to_vec());
+
+                memory_cards.push(memory);
+            }
+        }
+
+        let header = input_movie::MovieHeader {
+            core_version: version::VERSION.to_string(),
+            bios_sha256: sha256(self.psx.bios()),
+            disc_serial: disc.serial_number().to_string(),
+            disc_sha256: input_movie::disc_sha256(&mut disc)?,
+            disc_index: self.cur_image,
+            controllers: self.controller_type.to_vec(),
+            multitap: self.multitap,
+            memory_cards,
+            settings: self.emulation_settings(),
+            start,
+        };
+
+        info!("Recording input movie to {}", path.display());
+
+        Ok(MovieSession::new(
+            input_movie::Movie::new(header),
+            path,
+            MovieMode::Record,
+        ))
+    }
+
+    fn play_movie(&mut self, path: PathBuf, mode: MovieMode) -> Result<MovieSession> {
+        let movie = input_movie::Movie::load(&path)?;
+        let header = &movie.header;
+
+        if header.disc_index >= self.images.len() {
+            return Err(PsxError::BadInputMovie(format!(
+                "the movie starts with disc {} which isn't loaded",
+                header.disc_index + 1
+            )));
+        }
+
+        let external = match &header.start {
+            StartState::SaveState { data: None, .. } => {
+                Some(std::fs::read(input_movie::external_state_path(&path))?)
+            }
+            _ => None,
+        };
+        let state = header.start_state(external.as_deref())?;
+
+        // Make sure that the movie can be replayed before we touch the running game
+        let mut disc = Context::load_image(&self.images[header.disc_index])?;
+        header.check_setup(self.psx.bios(), &mut disc)?;
+
+        if header
+            .memory_cards
+            .iter()
+            .flatten()
+            .any(|m| m.len() != FLASH_SIZE)
+        {
+            return Err(PsxError::BadInputMovie("bad memory card size".to_string()));
+        }
+
+        // Power the console on with the movie's disc
+        self.cur_image = header.disc_index;
+        libretro::Context::reset(self);
+
+        // Configure the ports like they were when the movie was recorded. The memory cards are
+        // only kept in memory, the movie must not touch the user's saves.
+        for (player, &ty) in header.controllers.iter().take(MAX_PLAYERS).enumerate() {
+            self.controller_type[player] = ty;
+        }
+
+        self.flush_memory_cards();
+        self.disconnect_memory_cards();
+        self.set_multitaps(header.multitap);
+
+        for (index, memory) in header.memory_cards.iter().enumerate() {
+            let memory = match memory {
+                Some(m) => m,
+                None => continue,
+            };
+
+            let (port, slot) = (index / multitap::SLOTS, index % multitap::SLOTS);
+
+            if let Some(m) = self.psx.pad_memcard.memory_card_mut(port, slot) {
+                let mc = MemoryCard::new_with_memory(BoxArray::from_vec(memory.clone()));
+                m.connect_device(Box::new(mc));
+            }
+        }
+
+        if let Some(state) = state {
+            if libretro::Context::unserialize(self, state).is_err() {
+                let m = "couldn't load the starting save state".to_string();
+                return Err(PsxError::BadInputMovie(m));
+            }
+        }
+
+        header.settings.apply(&mut self.psx);
+
+        info!(
+            "Playing input movie {} ({} frames, {} rerecords)",
+            path.display(),
+            movie.frames().len(),
+            movie.rerecords()
+        );
+
+        Ok(MovieSession::new(movie, path, mode))
+    }
+
+    /// End the input movie session, saving the movie if we were recording
+    fn stop_movie(&mut self) {
+        let movie = match self.movie.take() {
+            Some(m) => m,
+            None => return,
+        };
+
+        if let Some(desync) = movie.desync() {
+            warn!("The input movie desynchronized after frame {}", desync);
+        }
 
-                let comp = self.analog_compensation;
+        if let Err(e) = movie.save() {
+            error!("Couldn't save input movie: {}", e);
+        }
 
-                let compensate = |v: i16| {
-                    let v = f32::from(v) * comp;
+        self.restore_ports();
+    }
 
-                    if v > f32::from(i16::MAX) {
-                        i16::MAX
-                    } else if v < f32::from(i16::MIN) {
-                        i16::MIN
-                    } else {
-                        v as i16
-                    }
-                };
+    /// Give the ports back to the frontend's configuration at the end of an input movie session
+    fn restore_ports(&mut self) {
+        if let Some(controller_type) = self.saved_controller_type.take() {
+            self.controller_type = controller_type;
+        }
 
-                let left_x = compensate(libretro::axis_state(
-                    port,
-                    libretro::AnalogInput::Left,
-                    libretro::AnalogAxis::X,
-                ));
-                let left_y = compensate(libretro::axis_state(
-                    port,
-                    libretro::AnalogInput::Left,
-                    libretro::AnalogAxis::Y,
-                ));
-                let right_x = compensate(libretro::axis_state(
-                    port,
-                    libretro::AnalogInput::Right,
-                    libretro::AnalogAxis::X,
-                ));
-                let right_y = compensate(libretro::axis_state(
-                    port,
-                    libretro::AnalogInput::Right,
-                    libretro::AnalogAxis::Y,
-                ));
-
-                device.set_axis_state((left_x, left_y), (right_x, right_y));
+        // Reconnects the memory card files if we were playing back a movie
+        self.refresh_ports(true);
 
-                let (strong, weak) = device.get_rumble();
+        // The movie's settings are only kept for the duration of the session
+        self.emulation_settings().apply(&mut self.psx);
+    }
 
-                // Values are 8 bits on the PSX
-                let mut strong = strong as u16;
-                strong |= strong << 8;
-                libretro::set_rumble(port, libretro::RumbleEffect::Strong, strong);
+    /// Returns the settings affecting the emulation: the ones the current input movie was
+    /// recorded with if there's one, the core options otherwise
+    fn emulation_settings(&self) -> EmulationSettings {
+        if let Some(movie) = &self.movie {
+            return movie.movie().header.settings;
+        }
 
-                let mut weak = weak as u16;
-                weak |= weak << 8;
-                libretro::set_rumble(port, libretro::RumbleEffect::Weak, weak);
-            }
+        EmulationSettings {
+            gte_overclock: options::CoreOptions::gte_overclock(),
+            cd_loading_speed: options::CoreOptions::cd_speed() / 2,
+            reverb_enable: options::CoreOptions::reverb_enable(),
+            reverb_enhanced: options::CoreOptions::enhanced_reverb(),
+            spu_interpolation: options::CoreOptions::spu_interpolation(),
+            force_transparency: options::CoreOptions::force_transparency(),
         }
     }
 
-    fn load_image(image: &DiscImage) -> Result<Disc> {
-        let path = image.path();
+    /// Returns a save state of the current console
+    fn save_state(&mut self) -> Result<Vec<u8>> {
+        let mut state = vec![0; libretro::Context::serialize_size(self)];
 
-        let disc = if path.extension().and_then(|ext| ext.to_str()) == Some("cue") {
-            Cue::new(path)
-        } else {
-            Cue::new_from_zip(path)
-        }?;
+        if libretro::Context::serialize(self, &mut state).is_err() {
+            return Err(PsxError::SaveStateError {
+                operation: "serialize".to_string(),
+                reason: "see the log".to_string(),
+            });
+        }
 
-        let disc = Disc::new(Box::new(disc))?;
+        let len = u32::from_le_bytes(*array_ref![state, 4, 4]) as usize;
+        state.truncate(8 + len);
 
-        let serial = disc.serial_number();
-        let region = disc.region();
+        Ok(state)
+    }
 
-        info!("Disc serial number: {}", serial);
-        info!("Detected disc region: {:?}", region);
+    /// Run an operation of the memory card manager on the card selected in the core options
+    fn run_memory_card_action(&mut self, action: options::MemoryCardAction) {
+        if action == options::MemoryCardAction::None {
+            return;
+        }
 
-        Ok(disc)
-    }
+        if self.movie.is_some() {
+            libretro::set_message(
+                3000,
+                "The memory card manager is disabled during input movies",
+            );
+            return;
+        }
 
-    fn load_disc(image: &DiscImage) -> Result<Box<psx::Psx>> {
-        let disc = Context::load_image(image)?;
-        let region = disc.region();
+        let save_dir = match libretro::get_save_directory() {
+            Some(d) => d,
+            None => {
+                warn!("No save directory defined, can't run the memory card manager");
+                return;
+            }
+        };
 
-        let bios = find_bios(|md| md.region == region)?;
+        let port = options::CoreOptions::memory_card_manager_card();
 
-        let cdc_firmware = find_cdc_firmware()?;
+        if matches!(
+            action,
+            options::MemoryCardAction::ListBackups | options::MemoryCardAction::RestoreBackup
+        ) {
+            self.run_memory_card_backup_action(action, port);
+            return;
+        }
 
-        let psx = Box::new(psx::Psx::new_with_disc(disc, bios, cdc_firmware)?);
+        let memory = match self
+            .psx
+            .pad_memcard
+            .memory_card(port, 0)
+            .and_then(|m| m.device().get_memory())
+        {
+            Some(m) => m.to_vec(),
+            None => {
+                libretro::set_message(3000, &format!("No memory card in slot {}", port + 1));
+                return;
+            }
+        };
 
-        Ok(psx)
-    }
+        let mut card = Card::new(memory.clone()).expect("Bad memory card size");
 
-    /// Disconnect any configured Memory Card
-    fn disconnect_memory_cards(&mut self) {
-        let mut memory_cards = self.psx.pad_memcard.memory_cards_mut();
+        let save_index = options::CoreOptions::memory_card_manager_save();
+
+        let message = match memory_card_manager::run(action, &mut card, save_index, &save_dir) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Memory card manager: {}", e);
+                e
+            }
+        };
+
+        libretro::set_message(3000, &message);
 
-        for m in &mut memory_cards {
-            let device = Box::new(DisconnectedDevice);
-            m.connect_device(device);
+        if card.memory() == &memory[..] {
+            return;
         }
 
-        for m in self.memcard_files.iter_mut() {
-            *m = MemoryCardFile::dummy();
+        // Swap the card for one with the new contents, as far as the game is concerned the card
+        // has been removed and reinserted
+        let mc = MemoryCard::new_with_memory(BoxArray::from_vec(card.into_memory()));
+
+        if let Some(m) = self.psx.pad_memcard.memory_card_mut(port, 0) {
+            m.connect_device(Box::new(mc));
+            self.memcard_files[memcard_index(port, 0)].save_modified(m.device());
         }
     }
 
-    /// Initialize the memory cards connected to the emulated console
-    fn setup_memory_cards(&mut self) {
-        // Start by disconnecting everything to make sure we never half-configure the memory card.
-        // We want to make it hard for the user to lose their saves...
-        self.disconnect_memory_cards();
-
-        let save_path = match libretro::get_save_directory() {
-            Some(p) => p,
-            None => {
-                warn!("No save directory defined, disabling memory cards");
+    fn run_memory_card_backup_action(&mut self, action: options::MemoryCardAction, port: usize) {
+        let mc = match self.psx.pad_memcard.memory_card(port, 0) {
+            Some(m) if m.device().get_memory().is_some() => m.device(),
+            _ => {
+                libretro::set_message(3000, &format!("No memory card in slot {}", port + 1));
                 return;
             }
         };
 
-        for slot in 0..=1 {
-            let filename = match self.memcard_types[slot] {
-                options::MemoryCardType::Disconnected => continue,
-                options::MemoryCardType::Common(idx) => {
-                    let p = format!("rustation_common.{}.mcr", idx);
+        let mcf = &mut self.memcard_files[memcard_index(port, 0)];
+        let backup_index = options::CoreOptions::memory_card_manager_backup();
 
-                    save_path.join(p)
-                }
-                options::MemoryCardType::PerGame(idx) => {
-                    let p: &Path = self.cur_image().basename().as_ref();
-                    let p = p.with_extension(format!("{}.mcr", idx));
+        let res = memory_card_manager::run_backup(action, mcf, mc, backup_index);
+        let restored = action == options::MemoryCardAction::RestoreBackup && res.is_ok();
 
-                    save_path.join(p)
-                }
-            };
+        let message = match res {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Memory card manager: {}", e);
+                e
+            }
+        };
 
-            match MemoryCardFile::load_or_create(&filename) {
-                Ok((mcf, mc)) => {
-                    // Success
-                    info!("Memory Card {} is {}", slot, mcf.path().display());
+        libretro::set_message(3000, &message);
 
-                    let memory_cards = self.psx.pad_memcard.memory_cards_mut();
-                    memory_cards[slot].connect_device(Box::new(mc));
-                    self.memcard_files[slot] = mcf;
-                }
-                Err(e) => {
-                    error!(
-                        "Can't load or create memory card '{}': {}",
-                        filename.display(),
-                        e
-                    );
-                }
-            }
+        if restored {
+            // Reload the card with the restored contents
+            self.flush_memory_cards();
+            self.setup_memory_cards();
         }
     }
 
-    /// Called when we're about to quit to force-flush any pending Memory Card write
+    /// Called when we're about to quit or reconfigure the memory cards to force-flush any pending
+    /// Memory Card write
     fn flush_memory_cards(&mut self) {
-        let memory_cards = self.psx.pad_memcard.memory_cards();
+        for port in 0..2 {
+            for slot in 0..multitap::SLOTS {
+                if let Some(mc) = self.psx.pad_memcard.memory_card(port, slot) {
+                    self.memcard_files[memcard_index(port, slot)].force_dump(mc.device());
+                }
+            }
+        }
+    }
 
-        self.memcard_files[0].force_dump(memory_cards[0].device());
-        self.memcard_files[1].force_dump(memory_cards[1].device());
+    /// Returns the rate at which we output frames. It's the emulated console's refresh rate,
+    /// unless we're locked to the host's display.
+    fn video_output_framerate(&self) -> f32 {
+        (self.native_framerate() as f64 * self.resampler.speed()) as f32
     }
 
     // Precise FPS values for the video output for the given VideoClock. It's actually possible to
     // configure the PlayStation GPU to output with NTSC timings with the PAL clock (and vice-versa)
     // which would make this code invalid but it wouldn't make a lot of sense for a game to do that.
-    fn video_output_framerate(&self) -> f32 {
+    fn native_framerate(&self) -> f32 {
         match self.psx.video_standard() {
             // 53.690MHz GPU clock frequency, 263 lines per field, 3413 cycles per line
             psx::VideoStandard::Ntsc => 59.81,
@@ -502,51 +1399,39 @@ impl Context {
         }
     }
     
-    // Helper function to convert libretro button to accessibility InputButton
-    fn retro_to_input_button(button: libretro::JoyPadButton) -> Option<accessibility::input_remapping::InputButton> {
-        use accessibility::input_remapping::InputButton;
-        match button {
-            libretro::JoyPadButton::B => Some(InputButton::Cross),
-            libretro::JoyPadButton::A => Some(InputButton::Circle),
-            libretro::JoyPadButton::Y => Some(InputButton::Square),
-            libretro::JoyPadButton::X => Some(InputButton::Triangle),
-            libretro::JoyPadButton::Up => Some(InputButton::DPadUp),
-            libretro::JoyPadButton::Down => Some(InputButton::DPadDown),
-            libretro::JoyPadButton::Left => Some(InputButton::DPadLeft),
-            libretro::JoyPadButton::Right => Some(InputButton::DPadRight),
-            libretro::JoyPadButton::L => Some(InputButton::L1),
-            libretro::JoyPadButton::L2 => Some(InputButton::L2),
-            libretro::JoyPadButton::L3 => Some(InputButton::L3),
-            libretro::JoyPadButton::R => Some(InputButton::R1),
-            libretro::JoyPadButton::R2 => Some(InputButton::R2),
-            libretro::JoyPadButton::R3 => Some(InputButton::R3),
-            libretro::JoyPadButton::Start => Some(InputButton::Start),
-            libretro::JoyPadButton::Select => Some(InputButton::Select),
+    /// Lock the emulation speed to the frontend's refresh rate if possible. When the speed changes
+    /// the audio resampler compensates for it and the frontend is told about the new framerate.
+    fn refresh_display_sync(&mut self) {
+        let native = f64::from(self.native_framerate());
+
+        if let Some(ds) = self.psx.display_sync.as_mut() {
+            ds.set_emulated_refresh(native);
         }
-    }
-    
-    // Helper function to convert accessibility InputButton to PSX button
-    fn input_to_psx_button(button: accessibility::input_remapping::InputButton) -> Option<Button> {
-        use accessibility::input_remapping::InputButton;
-        match button {
-            InputButton::Cross => Some(Button::Cross),
-            InputButton::Circle => Some(Button::Circle),
-            InputButton::Square => Some(Button::Square),
-            InputButton::Triangle => Some(Button::Triangle),
-            InputButton::DPadUp => Some(Button::DUp),
-            InputButton::DPadDown => Some(Button::DDown),
-            InputButton::DPadLeft => Some(Button::DLeft),
-            InputButton::DPadRight => Some(Button::DRight),
-            InputButton::L1 => Some(Button::L1),
-            InputButton::L2 => Some(Button::L2),
-            InputButton::L3 => Some(Button::L3),
-            InputButton::R1 => Some(Button::R1),
-            InputButton::R2 => Some(Button::R2),
-            InputButton::R3 => Some(Button::R3),
-            InputButton::Start => Some(Button::Start),
-            InputButton::Select => Some(Button::Select),
-            InputButton::Analog => Some(Button::Analog),
-            _ => None,
+
+        if let Some(rate) = libretro::get_target_refresh_rate() {
+            self.psx.set_refresh_rate(rate);
+        }
+
+        let speed = match self.psx.display_sync_stats() {
+            Some(stats) => stats.speed,
+            None => 1.,
+        };
+
+        if speed != self.resampler.speed() {
+            self.resampler.set_speed(speed);
+
+            if speed != 1. {
+                info!(
+                    "Locked to host refresh rate: {:.3}Hz (speed {:.4})",
+                    self.video_output_framerate(),
+                    speed
+                );
+            }
+
+            let av_info = libretro::Context::get_system_av_info(self);
+            if !unsafe { libretro::set_system_av_info(&av_info) } {
+                warn!("Frontend refused the new framerate");
+            }
         }
     }
 
@@ -574,6 +1459,11 @@ impl Context {
             max_height = h as libc::c_uint;
         }
 
+        // The post-processing filters can upscale the output further
+        let post_scale = self.post_process.scale().max(1.);
+        let max_width = (max_width as f32 * post_scale).ceil() as libc::c_uint;
+        let max_height = (max_height as f32 * post_scale).ceil() as libc::c_uint;
+
         libretro::GameGeometry {
             base_width: self.internal_width,
             base_height: self.internal_height,
@@ -667,6 +1557,32 @@ impl Context {
         }
     }
 
+    /// Rebuild the post-processing filter chain from the core options
+    fn refresh_post_process(&mut self) {
+        let mut chain = post_process::FilterChain::new();
+
+        // Composite artifacts must be simulated at the native resolution, the CRT effects after
+        // upscaling so that they're not smoothed out by the scaler
+        if options::CoreOptions::post_ntsc() {
+            chain.push(post_process::Filter::Ntsc);
+        }
+
+        let filters = [
+            options::CoreOptions::post_color(),
+            options::CoreOptions::post_scaler(),
+            options::CoreOptions::post_crt(),
+        ];
+
+        for filter in filters.iter().flatten() {
+            chain.push(filter.clone());
+        }
+
+        if chain != self.post_process {
+            info!("Post-processing filters: [{}]", chain);
+            self.post_process = chain;
+        }
+    }
+
     fn output_frame(&mut self) {
         let draw_cd_state = match self.cd_overlay {
             CdOverlay::Disabled => None,
@@ -694,6 +1610,20 @@ impl Context {
             }
         };
 
+        if !self.post_process.is_empty() {
+            let image = self.post_process.process(post_process::Image {
+                pixels: frame.pixels,
+                width: frame.width,
+                height: frame.height,
+            });
+
+            frame = Frame {
+                pixels: image.pixels,
+                width: image.width,
+                height: image.height,
+            };
+        }
+
         if frame.width != self.internal_width || frame.height != self.internal_height {
             // Internal resolution changed, see if we need to adjust the geometry
             self.internal_width = frame.width;
@@ -848,47 +1778,51 @@ impl ::std::ops::Drop for Context {
     fn drop(&mut self) {
         info!("Shutting down");
         self.flush_memory_cards();
+
+        if let Some(movie) = &self.movie {
+            if let Err(e) = movie.save() {
+                error!("Couldn't save input movie: {}", e);
+            }
+        }
     }
 }
 
 impl libretro::Context for Context {
-    fn set_controller(&mut self, port: usize, mut device: libretro::InputDevice, subclass: u32) {
-        if port > 1 {
+    fn set_controller(&mut self, port: usize, device: libretro::InputDevice, subclass: u32) {
+        if port >= MAX_PLAYERS {
             warn!(
-                "Can't configure controller for port {}, only 2 supported",
-                port + 1
+                "Can't configure controller for port {}, only {} supported",
+                port + 1,
+                MAX_PLAYERS
             );
             return;
         }
 
-        let gamepads = self.psx.pad_memcard.gamepads_mut();
-
-        let new_pad: Box<dyn DeviceInterface> = match subclass {
-            PSX_CONTROLLER_DIGITAL => Box::new(DigitalPad::new()),
-            PSX_CONTROLLER_DUALSHOCK => Box::new(DualShock::new()),
-            _ => {
-                // Try to match the generic class instead
-                match device {
-                    libretro::InputDevice::None => Box::new(DisconnectedDevice),
-                    libretro::InputDevice::JoyPad => Box::new(DigitalPad::new()),
-                    libretro::InputDevice::Analog => Box::new(DualShock::new()),
-                    _ => {
-                        error!(
-                            "Received bogus controller config for port {}: {:?}.0x{:x}.\
-                               Disconnecting it",
-                            port, device, subclass
-                        );
-                        device = libretro::InputDevice::None;
-                        Box::new(DisconnectedDevice)
-                    }
-                }
+        let ty = match ControllerType::from_subclass(device, subclass) {
+            Some(ty) => ty,
+            None => {
+                error!(
+                    "Received bogus controller config for port {}: {:?}.0x{:x}.\
+                       Disconnecting it",
+                    port, device, subclass
+                );
+                ControllerType::None
             }
         };
 
-        info!("New controller on port {}: {}", port, new_pad.description());
+        if let Some(saved) = &mut self.saved_controller_type {
+            info!(
+                "Controller change for port {} postponed until the end of the input movie",
+                port + 1
+            );
+            saved[port] = ty;
+            return;
+        }
 
-        self.controller_type[port] = device;
-        gamepads[port].connect_device(new_pad);
+        self.controller_type[port] = ty;
+        self.connect_controller(port);
+
+        libretro::set_input_descriptors(&input_descriptors(&self.controller_type));
     }
 
     fn render_frame(&mut self) {
@@ -896,7 +1830,11 @@ impl libretro::Context for Context {
         if !self.slow_motion.should_process_frame() {
             return;
         }
-        
+
+        if let Some(option) = self.pending_movie.take() {
+            self.set_movie(option);
+        }
+
         self.poll_controllers();
 
         // Apply time multiplier for slow motion
@@ -906,6 +1844,8 @@ impl libretro::Context for Context {
             // This would need to be properly integrated with the PSX timing
         }
 
+        self.refresh_display_sync();
+
         self.psx.run_frame();
         
         // Update disc manager animation (assuming ~60fps, so ~16ms per frame)
@@ -918,7 +1858,25 @@ impl libretro::Context for Context {
         self.output_frame();
 
         // Send sound samples
-        let samples = self.psx.get_audio_samples();
+        let mut samples = if self.resampler.rate_control().is_some() {
+            // Only take what the frontend needs for this frame and let the rate control keep the
+            // audio buffer's fill level on target
+            let stats = self.psx.audio_buffer_stats();
+            self.resampler
+                .update_fill(stats.current_fill, stats.target_latency);
+
+            let out_frames =
+                (self.resampler.output_rate() as f32 / self.video_output_framerate()).round();
+            let frames = self.resampler.input_frames_for(out_frames as usize);
+
+            self.psx.pop_audio_samples(frames)
+        } else {
+            self.psx.get_audio_samples().to_vec()
+        };
+
+        if let Some(fader) = &mut self.psf_fader {
+            fader.process(&mut samples);
+        }
         
         // Process audio for streaming if enabled
         #[cfg(feature = "streaming")]
@@ -939,25 +1897,31 @@ impl libretro::Context for Context {
             samples
         };
         
-        libretro::send_audio_samples(adjusted_samples);
+        let mut output_samples = Vec::with_capacity(adjusted_samples.len() * 2);
+        if let Err(e) = self
+            .resampler
+            .process(&adjusted_samples, &mut output_samples)
+        {
+            warn!("Audio resampling failed: {}", e);
+            self.resampler.reset();
+        }
+
+        libretro::send_audio_samples(&output_samples);
         // Clear the emulator's buffer for next frame
         self.psx.clear_audio_samples();
 
-        // Refresh memory cards
-        let mut memory_cards = self.psx.pad_memcard.memory_cards_mut();
-        for (file, mc) in self.memcard_files.iter_mut().zip(memory_cards.iter_mut()) {
-            let device = mc.device_mut();
+        self.psx.pad_memcard.new_frame();
 
-            device.new_frame();
-            file.maybe_dump(device);
+        if let Some(movie) = &mut self.movie {
+            movie.end_frame(self.psx.ram());
         }
 
-        // Refresh pads
-        let mut gamepads = self.psx.pad_memcard.gamepads_mut();
-        for gp in gamepads.iter_mut() {
-            let device = gp.device_mut();
-
-            device.new_frame();
+        for port in 0..2 {
+            for slot in 0..multitap::SLOTS {
+                if let Some(mc) = self.psx.pad_memcard.memory_card(port, slot) {
+                    self.memcard_files[memcard_index(port, slot)].maybe_dump(mc.device());
+                }
+            }
         }
     }
 
@@ -966,7 +1930,7 @@ impl libretro::Context for Context {
             geometry: self.get_geometry(),
             timing: libretro::SystemTiming {
                 fps: self.video_output_framerate() as f64,
-                sample_rate: 44_100.,
+                sample_rate: self.resampler.output_rate() as f64,
             },
         }
     }
@@ -975,17 +1939,13 @@ impl libretro::Context for Context {
         self.analog_combo = options::CoreOptions::analog_combo();
         self.analog_compensation = options::CoreOptions::analog_compensation();
         self.cd_overlay = options::CoreOptions::cd_overlay();
+        self.refresh_post_process();
 
         let vram_display_mode = options::CoreOptions::display_full_vram();
         self.psx
             .gpu
             .set_rasterizer_option(RasterizerOption::VRamDisplayMode(vram_display_mode));
 
-        let force_transparency = options::CoreOptions::force_transparency();
-        self.psx
-            .gpu
-            .set_rasterizer_option(RasterizerOption::ForceTransparency(force_transparency));
-
         let color_depth = options::CoreOptions::internal_color_depth();
 
         let d24 = match color_depth {
@@ -1018,40 +1978,120 @@ impl libretro::Context for Context {
             .gpu
             .set_rasterizer_option(RasterizerOption::UpscaleShift(upscale_shift));
 
+        let threads = options::CoreOptions::rasterizer_threads();
         self.psx
-            .cd
-            .set_cd_loading_speed(options::CoreOptions::cd_speed() / 2);
+            .gpu
+            .set_rasterizer_option(RasterizerOption::WorkerThreads(threads));
 
+        let deinterlace = options::CoreOptions::deinterlacing();
         self.psx
-            .spu
-            .set_reverb_enable(options::CoreOptions::reverb_enable());
+            .gpu
+            .set_rasterizer_option(RasterizerOption::Deinterlace(deinterlace));
 
+        let progressive_hack = options::CoreOptions::progressive_hack();
         self.psx
-            .gte
-            .set_overclock(options::CoreOptions::gte_overclock());
+            .gpu
+            .set_rasterizer_option(RasterizerOption::ProgressiveHack(progressive_hack));
 
-        let mut memcard_types = [
-            options::CoreOptions::memory_card_1_type(),
-            options::CoreOptions::memory_card_2_type(),
-        ];
+        let crop_mode = options::CoreOptions::crop_mode();
+        self.psx
+            .gpu
+            .set_rasterizer_option(RasterizerOption::CropMode(crop_mode));
 
-        if memcard_types[0] == memcard_types[1] {
-            warn!("Both memory cards are set to the same type, disconnecting slot 2");
-            memcard_types[1] = options::MemoryCardType::Disconnected;
+        let auto_center = options::CoreOptions::auto_center();
+        self.psx
+            .gpu
+            .set_rasterizer_option(RasterizerOption::AutoCenter(auto_center));
+
+        let overscan_crop = OverscanCrop {
+            ntsc: options::CoreOptions::overscan_crop_ntsc(),
+            pal: options::CoreOptions::overscan_crop_pal(),
+        };
+        self.psx
+            .gpu
+            .set_rasterizer_option(RasterizerOption::OverscanCrop(overscan_crop));
+
+        let mdec_enhancement = options::CoreOptions::fmv_chroma().map(|chroma| MdecEnhancement {
+            chroma,
+            deblocking: options::CoreOptions::fmv_deblocking(),
+        });
+        self.psx.set_mdec_enhancement(mdec_enhancement);
+
+        // Input movies keep the settings they were recorded with until the end of the session
+        self.emulation_settings().apply(&mut self.psx);
+
+        let rate_control = if options::CoreOptions::audio_rate_control() {
+            Some(resampler::RateControl::default())
+        } else {
+            None
+        };
+        self.resampler.set_rate_control(rate_control);
+
+        self.psx
+            .set_display_sync_enabled(options::CoreOptions::display_sync());
+
+        let output_rate = options::CoreOptions::audio_output_rate();
+        if output_rate != self.resampler.output_rate() {
+            self.resampler.set_output_rate(output_rate);
+
+            // The sample rate can only be changed by resetting the whole AV info
+            let av_info = libretro::Context::get_system_av_info(self);
+            if !unsafe { libretro::set_system_av_info(&av_info) } {
+                warn!("Frontend refused the new audio output rate");
+            }
         }
 
-        if memcard_types != self.memcard_types {
-            self.memcard_types = memcard_types;
-            self.setup_memory_cards();
+        // The ports keep the movie's configuration until the end of the session
+        if self.saved_controller_type.is_none() {
+            self.refresh_ports(false);
+        }
+
+        let movie_option = options::CoreOptions::input_movie();
+        if movie_option != self.movie_option {
+            // Applied at the beginning of the next frame, once the frontend is done configuring
+            // the controllers
+            self.movie_option = movie_option;
+            self.pending_movie = Some(movie_option);
+        }
+
+        let memcard_execute = options::CoreOptions::memory_card_manager_execute();
+        if memcard_execute != self.memcard_execute {
+            self.memcard_execute = memcard_execute;
+
+            if memcard_execute {
+                self.run_memory_card_action(options::CoreOptions::memory_card_manager_action());
+
+                // Switch the option back off so that the next action can be run the same way. If
+                // the frontend can't do it the user has to do it manually.
+                let reset = unsafe {
+                    libretro::set_variable(
+                        cstring!("rustation_memory_card_manager_execute"),
+                        cstring!("off"),
+                    )
+                };
+
+                if reset {
+                    self.memcard_execute = false;
+                }
+            }
+        }
+
+        let macro_recording = options::CoreOptions::input_macro_record();
+        if macro_recording != self.macro_recording {
+            self.macro_recording = macro_recording;
+            self.record_macro(macro_recording);
         }
     }
 
     fn reset(&mut self) {
         match Context::load_disc(self.cur_image()) {
-            Ok(mut psx) => {
+            Ok((mut psx, psf_fader)) => {
                 info!("Game reset");
                 std::mem::swap(&mut self.psx.pad_memcard, &mut psx.pad_memcard);
                 self.psx = psx;
+                self.psf_fader = psf_fader;
+                self.resampler.reset();
+                self.emulation_settings().apply(&mut self.psx);
             }
             Err(_) => warn!("Couldn't reset game"),
         }
@@ -1079,6 +2119,8 @@ impl libretro::Context for Context {
             psx: &self.psx,
             disc_manager: &self.disc_manager,
             current_disc_index: self.cur_image,
+            movie_frame: self.movie.as_ref().map(|m| m.frame()),
+            input_layer: self.input_layer.state(),
         };
 
         let mut fb = flexbuffers::FlexbufferSerializer::new();
@@ -1117,16 +2159,7 @@ impl libretro::Context for Context {
     }
 
     fn unserialize(&mut self, buf: &[u8]) -> ::std::result::Result<(), ()> {
-        if buf[0..4] != *b"RSX1" {
-            error!("Failed to load savestate: bad magic");
-            return Err(());
-        }
-
-        let len = u32::from_le_bytes(*array_ref![buf, 4, 4]) as usize;
-
-        let buf = &buf[8..(8 + len)];
-
-        let fbr = match flexbuffers::Reader::get_root(buf) {
+        let fbr = match save_state_root(buf) {
             Ok(r) => r,
             Err(e) => {
                 error!("Failed to load savestate: {}", e);
@@ -1134,13 +2167,20 @@ impl libretro::Context for Context {
             }
         };
 
+        let mut movie_frame = None;
+
         // Try to deserialize the new format with disc manager
         match LoadedSaveState::deserialize(fbr.clone()) {
             Ok(state) => {
                 // Successfully loaded new format
-                *self.psx = Box::new(state.psx);
+                if let Err(e) = self.psx.load(Box::new(state.psx)) {
+                    error!("Failed to load savestate: {}", e);
+                    return Err(());
+                }
                 self.disc_manager = state.disc_manager;
                 self.cur_image = state.current_disc_index;
+                movie_frame = state.movie_frame;
+                self.input_layer.set_state(state.input_layer);
             }
             Err(_) => {
                 // Fall back to old format (just PSX state)
@@ -1153,6 +2193,15 @@ impl libretro::Context for Context {
 
         libretro::Context::refresh_variables(self);
 
+        match (&mut self.movie, movie_frame) {
+            (Some(movie), Some(frame)) => movie.state_loaded(frame),
+            (Some(_), None) => {
+                warn!("Save state made outside of the input movie, stopping the movie");
+                self.stop_movie();
+            }
+            (None, _) => (),
+        }
+
         Ok(())
     }
 
@@ -1166,87 +2215,27 @@ impl libretro::Context for Context {
 
     fn get_image_path(&self, index: usize) -> Option<&Path> {
         self.images.get(index).map(|d| d.path())
-    }
-
-    fn is_disc_ejected(&self) -> bool {
-        !self.psx.cd.disc_present() || self.disc_manager.is_tray_open()
-    }
-
-    fn eject_disc(&mut self) -> ::std::result::Result<(), ()> {
-        // Start the ejection animation
-        if let Err(e) = self.disc_manager.start_eject() {
-            error!("Failed to start disc ejection: {}", e);
-            return Err(());
-        }
-        
-        // Actually eject the disc from the emulator
-        if let Some(disc) = self.psx.cd.eject_disc() {
-            // Update disc metadata in the manager
-            if let Some(current_info) = self.disc_manager.current_disc_info() {
-                self.disc_manager.load_disc_metadata(&disc, &current_info.path);
-            }
-        }
-
-        Ok(())
-    }
-
-    fn insert_disc(&mut self) -> ::std::result::Result<(), ()> {
-        // Start the insertion animation
-        if let Err(e) = self.disc_manager.start_insert() {
-            error!("Failed to start disc insertion: {}", e);
-            return Err(());
-        }
-        
-        let disc = match Context::load_image(self.cur_image()) {
-            Ok(disc) => disc,
-            Err(e) => {
-                error!("Couldn't load {:?}: {}", self.cur_image().path(), e);
-                return Err(());
-            }
-        };
-
-        // Update disc metadata in the manager
-        self.disc_manager.load_disc_metadata(&disc, self.cur_image().path());
+    }
 
-        // Swap the memory cards if necessary since they depend on the disc name
-        self.setup_memory_cards();
+    fn is_disc_ejected(&self) -> bool {
+        !self.psx.cd.disc_present() || self.disc_manager.is_tray_open()
+    }
 
-        self.psx.cd.load_disc(disc);
+    fn eject_disc(&mut self) -> ::std::result::Result<(), ()> {
+        self.disc_event(DiscEvent::Eject)
+    }
 
-        Ok(())
+    fn insert_disc(&mut self) -> ::std::result::Result<(), ()> {
+        self.disc_event(DiscEvent::Insert(self.cur_image))
     }
 
     fn set_image_index(&mut self, index: usize) -> ::std::result::Result<(), ()> {
-        if index >= self.get_num_images() {
-            // The libretro spec says that this can mean that we want to load no disc, but for us
-            // there's not really any difference between no disc loaded and disc ejected, so I'm
-            // not sure if it's worth handling this case
-            error!("Ignoring set_image_index with an index out of range");
-            return Err(());
-        }
-
-        if !self.is_disc_ejected() && index != self.cur_image {
-            error!("Refusing to change the image index while the CD is not ejected");
-            return Err(());
-        }
-
-        // Validate the disc swap with the manager
-        if let Err(e) = self.disc_manager.validate_disc_swap(Some(self.cur_image), index) {
-            error!("Invalid disc swap: {}", e);
+        if self.is_playing_movie() {
+            error!("Refusing to change the disc during input movie playback");
             return Err(());
         }
 
-        // Update the disc manager's selection
-        if self.disc_manager.is_tray_open() {
-            if let Err(e) = self.disc_manager.select_disc(index) {
-                error!("Failed to select disc: {}", e);
-                return Err(());
-            }
-        }
-
-        self.cur_image = index;
-
-        Ok(())
+        self.select_image(index)
     }
 
     fn get_image_label(&self, index: usize) -> Option<String> {
@@ -1307,11 +2296,17 @@ fn find_bios<F>(predicate: F) -> Result<Bios>
 where
     F: Fn(&Metadata) -> bool,
 {
-    let system_directory = get_system_directory()?;
+    find_bios_in(&get_system_directory()?, predicate)
+}
 
+/// Look for a BIOS matching `predicate` in `system_directory`
+fn find_bios_in<F>(system_directory: &Path, predicate: F) -> Result<Bios>
+where
+    F: Fn(&Metadata) -> bool,
+{
     info!("Looking for a suitable BIOS in {:?}", system_directory);
 
-    let dir = ::std::fs::read_dir(&system_directory)?;
+    let dir = ::std::fs::read_dir(system_directory)?;
 
     for entry in dir {
         match entry {
@@ -1378,14 +2373,17 @@ where
 
 /// Attempt to find the CDC firmware in the system directory
 fn find_cdc_firmware() -> Result<[u8; CDC_ROM_SIZE]> {
-    let system_directory = get_system_directory()?;
+    find_cdc_firmware_in(&get_system_directory()?)
+}
 
+/// Look for the CDC firmware in `system_directory`
+fn find_cdc_firmware_in(system_directory: &Path) -> Result<[u8; CDC_ROM_SIZE]> {
     info!(
         "Looking for a suitable CDC firmware in {:?}",
         system_directory
     );
 
-    let dir = ::std::fs::read_dir(&system_directory)?;
+    let dir = ::std::fs::read_dir(system_directory)?;
 
     for entry in dir {
         match entry {
@@ -1493,7 +2491,11 @@ pub enum CdOverlay {
 mod options {
     //! Core options
 
-    use super::{AnalogCombo, CdOverlay, VRamDisplayMode};
+    use super::post_process::{Filter, FilterChain};
+    use super::{
+        AnalogCombo, CdOverlay, ChromaUpsampling, CropMode, DeinterlaceMode, InterpolationMethod,
+        VRamDisplayMode,
+    };
     use std::str::FromStr;
 
     #[derive(PartialEq, Eq)]
@@ -1503,6 +2505,33 @@ mod options {
         Exclusive,
     }
 
+    /// Input movie mode. The movie is stored in the save directory, named after the game.
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub enum MovieOption {
+        Disabled,
+        /// Reset the console and record from there
+        RecordPowerOn,
+        /// Record starting from a save state of the current console
+        RecordState,
+        PlayReadOnly,
+        PlayReadWrite,
+    }
+
+    /// Operation of the memory card manager, run when the "execute" option is switched on
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub enum MemoryCardAction {
+        None,
+        List,
+        Export,
+        ExportAll,
+        Import,
+        Delete,
+        Undelete,
+        Repair,
+        ListBackups,
+        RestoreBackup,
+    }
+
     #[derive(PartialEq, Eq, Copy, Clone)]
     pub enum MemoryCardType {
         Disconnected,
@@ -1516,6 +2545,32 @@ mod options {
             => "Internal upscaling factor; 1x (native)|2x|4x";
         internal_color_depth: u8, parse_u8
             => "Internal color depth; dithered 15bpp (native)|24bpp";
+        rasterizer_threads: u8, parse_threads
+            => "Rasterizer threads; 1|2|3|4|6|8|auto";
+        deinterlacing: DeinterlaceMode, parse_deinterlace
+            => "Deinterlacing; weave|bob|blend|adaptive";
+        progressive_hack: bool, parse_bool
+            => "Progressive output for interlaced games (hack); disabled|enabled";
+        crop_mode: CropMode, parse_crop_mode
+            => "Display area; game settings|overscan|auto-crop borders";
+        auto_center: bool, parse_bool
+            => "Auto-center display area; disabled|enabled";
+        overscan_crop_ntsc: u8, parse_percent
+            => "Overscan crop (NTSC); disabled|2%|4%|6%|8%|10%";
+        overscan_crop_pal: u8, parse_percent
+            => "Overscan crop (PAL); disabled|2%|4%|6%|8%|10%";
+        fmv_chroma: Option<ChromaUpsampling>, parse_fmv_chroma
+            => "FMV output; hardware exact|enhanced bilinear|enhanced bicubic";
+        fmv_deblocking: bool, parse_bool
+            => "FMV deblocking (enhanced output only); disabled|enabled";
+        post_scaler: Vec<Filter>, parse_post_scaler
+            => "Post-processing upscaler; disabled|xbr|scalefx|bilinear|sharp-bilinear";
+        post_crt: Vec<Filter>, parse_post_crt
+            => "CRT effect; disabled|scanlines|aperture grille|scanlines + aperture grille";
+        post_ntsc: bool, parse_bool
+            => "NTSC composite artifacts; disabled|enabled";
+        post_color: Vec<Filter>, parse_post_color
+            => "Color correction; disabled|crt gamma";
         display_full_vram: VRamDisplayMode, parse_full_vram
             => "Display full VRAM; disabled|16bpp|8bpp|4bpp";
         force_transparency: bool, parse_bool
@@ -1530,6 +2585,16 @@ mod options {
             => "CD overlay; disabled|enabled|dynamic";
         reverb_enable: bool, parse_bool
             => "Enable audio reverberation; enabled|disabled";
+        enhanced_reverb: bool, parse_reverb_quality
+            => "Reverb quality; accurate|enhanced (44.1kHz)";
+        spu_interpolation: InterpolationMethod, parse_interpolation
+            => "Audio interpolation; gaussian (native)|cubic|hermite|sinc|none";
+        audio_output_rate: u32, parse_sample_rate
+            => "Audio output rate; 44100Hz (native)|48000Hz|32000Hz|96000Hz";
+        audio_rate_control: bool, parse_bool
+            => "Dynamic audio rate control; disabled|enabled";
+        display_sync: bool, parse_bool
+            => "Sync emulation speed to display refresh rate; disabled|enabled";
         analog_combo: AnalogCombo, parse_analog_combo
             => "Analog toggle button combo; \
             Select + R3|Select + L3|L3 + R3";
@@ -1550,6 +2615,35 @@ mod options {
             per-game.0|per-game.1|per-game.2|per-game.3|per-game.4|per-game.5|per-game.6|per-game.7|per-game.8|per-game.9|\
             per-game.10|per-game.11|per-game.12|per-game.13|per-game.14|per-game.15|per-game.16|per-game.17|per-game.18|per-game.19|\
             disconnected";
+        multitap: [bool; 2], parse_multitap
+            => "Multitap; disabled|port 1|port 2|both ports";
+        multitap_memory_cards: bool, parse_bool
+            => "Memory cards in multitap slots B to D; disabled|enabled";
+        memory_card_filter: bool, parse_bool
+            => "Common memory cards only show the current game's saves; disabled|enabled";
+        memory_card_backups: usize, parse_backups
+            => "Memory card backups; 5|10|20|disabled";
+        input_movie: MovieOption, parse_input_movie
+            => "Input movie; disabled|record from power-on|record from current state|\
+            play (read-only)|play (read-write)";
+        input_movie_embed_state: bool, parse_bool
+            => "Embed starting save state in input movies; enabled|disabled";
+        memory_card_manager_card: usize, parse_manager_card
+            => "Memory card manager: card; slot 1|slot 2";
+        memory_card_manager_save: usize, parse_manager_index
+            => "Memory card manager: save; 1|2|3|4|5|6|7|8|9|10|11|12|13|14|15";
+        memory_card_manager_action: MemoryCardAction, parse_manager_action
+            => "Memory card manager: action; none|list saves|export save|export all saves|\
+            import saves|delete save|undelete save|repair card|list backups|restore backup";
+        memory_card_manager_backup: usize, parse_manager_index
+            => "Memory card manager: backup; 1|2|3|4|5|6|7|8|9|10|11|12|13|14|15|16|17|18|19|20";
+        memory_card_manager_execute: bool, parse_bool
+            => "Memory card manager: execute action; off|on";
+        input_macro_record: Option<usize>, parse_macro_record
+            => "Record input macro; disabled|player 1|player 2|player 3|player 4|player 5|\
+            player 6|player 7|player 8";
+        input_macro_trigger: String, parse_macro_trigger
+            => "Input macro trigger button; L3|R3|Select|L2|R2";
     });
 
     fn parse_memcard_index(opt: &str) -> Result<MemoryCardType, ()> {
@@ -1574,6 +2668,80 @@ mod options {
         }
     }
 
+    fn parse_multitap(opt: &str) -> Result<[bool; 2], ()> {
+        match opt {
+            "disabled" => Ok([false, false]),
+            "port 1" => Ok([true, false]),
+            "port 2" => Ok([false, true]),
+            "both ports" => Ok([true, true]),
+            _ => Err(()),
+        }
+    }
+
+    fn parse_input_movie(opt: &str) -> Result<MovieOption, ()> {
+        match opt {
+            "disabled" => Ok(MovieOption::Disabled),
+            "record from power-on" => Ok(MovieOption::RecordPowerOn),
+            "record from current state" => Ok(MovieOption::RecordState),
+            "play (read-only)" => Ok(MovieOption::PlayReadOnly),
+            "play (read-write)" => Ok(MovieOption::PlayReadWrite),
+            _ => Err(()),
+        }
+    }
+
+    fn parse_manager_card(opt: &str) -> Result<usize, ()> {
+        match opt {
+            "slot 1" => Ok(0),
+            "slot 2" => Ok(1),
+            _ => Err(()),
+        }
+    }
+
+    fn parse_manager_index(opt: &str) -> Result<usize, <usize as FromStr>::Err> {
+        opt.parse()
+    }
+
+    fn parse_backups(opt: &str) -> Result<usize, <usize as FromStr>::Err> {
+        match opt {
+            "disabled" => Ok(0),
+            _ => opt.parse(),
+        }
+    }
+
+    fn parse_manager_action(opt: &str) -> Result<MemoryCardAction, ()> {
+        match opt {
+            "none" => Ok(MemoryCardAction::None),
+            "list saves" => Ok(MemoryCardAction::List),
+            "export save" => Ok(MemoryCardAction::Export),
+            "export all saves" => Ok(MemoryCardAction::ExportAll),
+            "import saves" => Ok(MemoryCardAction::Import),
+            "delete save" => Ok(MemoryCardAction::Delete),
+            "undelete save" => Ok(MemoryCardAction::Undelete),
+            "repair card" => Ok(MemoryCardAction::Repair),
+            "list backups" => Ok(MemoryCardAction::ListBackups),
+            "restore backup" => Ok(MemoryCardAction::RestoreBackup),
+            _ => Err(()),
+        }
+    }
+
+    fn parse_macro_record(opt: &str) -> Result<Option<usize>, ()> {
+        if opt == "disabled" {
+            return Ok(None);
+        }
+
+        let player = opt
+            .strip_prefix("player ")
+            .and_then(|p| p.parse::<usize>().ok())
+            .and_then(|p| p.checked_sub(1))
+            .ok_or(())?;
+
+        Ok(Some(player))
+    }
+
+    fn parse_macro_trigger(opt: &str) -> Result<String, ()> {
+        Ok(opt.to_ascii_lowercase())
+    }
+
     fn parse_upscale(opt: &str) -> Result<u8, <u8 as FromStr>::Err> {
         let num = opt.trim_matches(|c: char| !c.is_numeric());
 
@@ -1590,12 +2758,26 @@ mod options {
         Ok((percent as f32) / 100.)
     }
 
+    fn parse_sample_rate(opt: &str) -> Result<u32, <u32 as FromStr>::Err> {
+        let num = opt.trim_end_matches(|c: char| !c.is_numeric());
+
+        num.parse()
+    }
+
     fn parse_u8(opt: &str) -> Result<u8, <u8 as FromStr>::Err> {
         let num = opt.trim_matches(|c: char| !c.is_numeric());
 
         num.parse()
     }
 
+    /// Parse the number of rasterizer threads, "auto" is 0
+    fn parse_threads(opt: &str) -> Result<u8, <u8 as FromStr>::Err> {
+        match opt {
+            "auto" => Ok(0),
+            _ => opt.parse(),
+        }
+    }
+
     fn parse_bool(opt: &str) -> Result<bool, ()> {
         match opt {
             "true" | "enabled" | "on" => Ok(true),
@@ -1604,6 +2786,15 @@ mod options {
         }
     }
 
+    fn parse_fmv_chroma(opt: &str) -> Result<Option<ChromaUpsampling>, ()> {
+        match opt {
+            "hardware exact" => Ok(None),
+            "enhanced bilinear" => Ok(Some(ChromaUpsampling::Bilinear)),
+            "enhanced bicubic" => Ok(Some(ChromaUpsampling::Bicubic)),
+            _ => Err(()),
+        }
+    }
+
     fn parse_full_vram(opt: &str) -> Result<VRamDisplayMode, ()> {
         let mode = match opt {
             "disabled" => VRamDisplayMode::Native,
@@ -1616,6 +2807,94 @@ mod options {
         Ok(mode)
     }
 
+    fn parse_deinterlace(opt: &str) -> Result<DeinterlaceMode, ()> {
+        let mode = match opt {
+            "weave" => DeinterlaceMode::Weave,
+            "bob" => DeinterlaceMode::Bob,
+            "blend" => DeinterlaceMode::Blend,
+            "adaptive" => DeinterlaceMode::Adaptive,
+            _ => return Err(()),
+        };
+
+        Ok(mode)
+    }
+
+    fn parse_interpolation(opt: &str) -> Result<InterpolationMethod, ()> {
+        let method = match opt {
+            "gaussian (native)" => InterpolationMethod::Gaussian,
+            "cubic" => InterpolationMethod::Cubic,
+            "hermite" => InterpolationMethod::Hermite,
+            "sinc" => InterpolationMethod::Sinc,
+            "none" => InterpolationMethod::None,
+            _ => return Err(()),
+        };
+
+        Ok(method)
+    }
+
+    fn parse_reverb_quality(opt: &str) -> Result<bool, ()> {
+        match opt {
+            "accurate" => Ok(false),
+            "enhanced (44.1kHz)" => Ok(true),
+            _ => Err(()),
+        }
+    }
+
+    fn parse_crop_mode(opt: &str) -> Result<CropMode, ()> {
+        let mode = match opt {
+            "game settings" => CropMode::DisplayArea,
+            "overscan" => CropMode::Overscan,
+            "auto-crop borders" => CropMode::Borders,
+            _ => return Err(()),
+        };
+
+        Ok(mode)
+    }
+
+    fn parse_percent(opt: &str) -> Result<u8, <u8 as FromStr>::Err> {
+        match opt {
+            "disabled" => Ok(0),
+            _ => opt.trim_end_matches('%').parse(),
+        }
+    }
+
+    /// Parse a list of post-processing filters
+    fn parse_filters(filters: &str) -> Result<Vec<Filter>, String> {
+        filters
+            .parse::<FilterChain>()
+            .map(|chain| chain.filters().to_vec())
+    }
+
+    fn parse_post_scaler(opt: &str) -> Result<Vec<Filter>, String> {
+        match opt {
+            "disabled" => Ok(Vec::new()),
+            // The option values match the filter names
+            _ => parse_filters(opt),
+        }
+    }
+
+    fn parse_post_crt(opt: &str) -> Result<Vec<Filter>, String> {
+        let filters = match opt {
+            "disabled" => "",
+            "scanlines" => "scanlines",
+            "aperture grille" => "aperture",
+            "scanlines + aperture grille" => "scanlines,aperture",
+            _ => return Err(format!("Unknown CRT effect '{}'", opt)),
+        };
+
+        parse_filters(filters)
+    }
+
+    fn parse_post_color(opt: &str) -> Result<Vec<Filter>, String> {
+        let filters = match opt {
+            "disabled" => "",
+            "crt gamma" => "gamma",
+            _ => return Err(format!("Unknown color correction '{}'", opt)),
+        };
+
+        parse_filters(filters)
+    }
+
     fn parse_cd_overlay(opt: &str) -> Result<CdOverlay, ()> {
         let mode = match opt {
             "disabled" => CdOverlay::Disabled,
@@ -1679,13 +2958,19 @@ fn load_game(disc: PathBuf) -> Option<Box<dyn libretro::Context>> {
 #[derive(Clone)]
 struct DiscImage {
     path: PathBuf,
+    /// Serial number of the disc, read the first time it's needed since that means opening the
+    /// image. `None` if it couldn't be read.
+    serial: OnceCell<Option<String>>,
 }
 
 impl DiscImage {
     fn new<P: AsRef<Path>>(path: P) -> DiscImage {
         let path = path.as_ref().to_path_buf();
 
-        DiscImage { path }
+        DiscImage {
+            path,
+            serial: OnceCell::new(),
+        }
     }
 
     fn path(&self) -> &Path {
@@ -1695,6 +2980,30 @@ impl DiscImage {
     fn basename(&self) -> &OsStr {
         self.path.file_stem().expect("Couldn't get disc image stem")
     }
+
+    /// Returns true if this is a PSF music rip instead of a disc image
+    fn is_psf(&self) -> bool {
+        psx::psf::is_psf_path(&self.path)
+    }
+
+    /// Returns the serial number of the disc, `None` for PSF rips or if it can't be read
+    fn serial_number(&self) -> Option<&str> {
+        self.serial
+            .get_or_init(|| {
+                if self.is_psf() {
+                    return None;
+                }
+
+                match Context::load_image(self) {
+                    Ok(disc) => Some(disc.serial_number().to_string()),
+                    Err(e) => {
+                        warn!("Can't get the serial number of {:?}: {}", self.path, e);
+                        None
+                    }
+                }
+            })
+            .as_deref()
+    }
 }
 
 /// Libretro to PlayStation button mapping. Libretro's mapping is based on the SNES controller so
@@ -1718,124 +3027,434 @@ static BUTTON_MAP: [(libretro::JoyPadButton, Button); 16] = [
     (libretro::JoyPadButton::R3, Button::R3),
 ];
 
-static INPUT_DESCRIPTORS: [libretro::InputDescriptor; 41] = [
-    // Port 0
-    libretro::InputDescriptor::joypad_button(
-        0,
-        libretro::JoyPadButton::Left,
-        cstring!("D-Pad Left"),
-    ),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::Up, cstring!("D-Pad Up")),
-    libretro::InputDescriptor::joypad_button(
-        0,
-        libretro::JoyPadButton::Down,
-        cstring!("D-Pad Down"),
-    ),
-    libretro::InputDescriptor::joypad_button(
-        0,
-        libretro::JoyPadButton::Right,
-        cstring!("D-Pad Right"),
-    ),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::B, cstring!("Cross")),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::A, cstring!("Circle")),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::X, cstring!("Triangle")),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::Y, cstring!("Square")),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::L, cstring!("L1")),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::L2, cstring!("L2")),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::L3, cstring!("L3")),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::R, cstring!("R1")),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::R2, cstring!("R2")),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::R3, cstring!("R3")),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::Select, cstring!("Select")),
-    libretro::InputDescriptor::joypad_button(0, libretro::JoyPadButton::Start, cstring!("Start")),
-    libretro::InputDescriptor::analog_axis(
-        0,
-        libretro::AnalogInput::Left,
-        libretro::AnalogAxis::X,
-        cstring!("Left Analog X"),
-    ),
-    libretro::InputDescriptor::analog_axis(
-        0,
-        libretro::AnalogInput::Left,
-        libretro::AnalogAxis::Y,
-        cstring!("Left Analog Y"),
-    ),
-    libretro::InputDescriptor::analog_axis(
-        0,
-        libretro::AnalogInput::Right,
-        libretro::AnalogAxis::X,
-        cstring!("Right Analog X"),
-    ),
-    libretro::InputDescriptor::analog_axis(
-        0,
-        libretro::AnalogInput::Right,
-        libretro::AnalogAxis::Y,
-        cstring!("Right Analog Y"),
-    ),
-    // Port 1
-    libretro::InputDescriptor::joypad_button(
-        1,
-        libretro::JoyPadButton::Left,
-        cstring!("D-Pad Left"),
-    ),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::Up, cstring!("D-Pad Up")),
-    libretro::InputDescriptor::joypad_button(
-        1,
-        libretro::JoyPadButton::Down,
-        cstring!("D-Pad Down"),
-    ),
-    libretro::InputDescriptor::joypad_button(
-        1,
-        libretro::JoyPadButton::Right,
-        cstring!("D-Pad Right"),
-    ),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::B, cstring!("Cross")),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::A, cstring!("Circle")),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::X, cstring!("Triangle")),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::Y, cstring!("Square")),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::L, cstring!("L1")),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::L2, cstring!("L2")),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::L3, cstring!("L3")),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::R, cstring!("R1")),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::R2, cstring!("R2")),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::R3, cstring!("R3")),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::Select, cstring!("Select")),
-    libretro::InputDescriptor::joypad_button(1, libretro::JoyPadButton::Start, cstring!("Start")),
-    libretro::InputDescriptor::analog_axis(
-        1,
-        libretro::AnalogInput::Left,
-        libretro::AnalogAxis::X,
-        cstring!("Left Analog X"),
-    ),
-    libretro::InputDescriptor::analog_axis(
-        1,
-        libretro::AnalogInput::Left,
-        libretro::AnalogAxis::Y,
-        cstring!("Left Analog Y"),
-    ),
-    libretro::InputDescriptor::analog_axis(
-        1,
-        libretro::AnalogInput::Right,
-        libretro::AnalogAxis::X,
-        cstring!("Right Analog X"),
-    ),
-    libretro::InputDescriptor::analog_axis(
-        1,
-        libretro::AnalogInput::Right,
-        libretro::AnalogAxis::Y,
-        cstring!("Right Analog Y"),
-    ),
-    // End of table
-    libretro::InputDescriptor::end_of_table(),
+/// NeGcon button mapping, see `NeGcon::set_button`
+static NEGCON_BUTTON_MAP: [(libretro::JoyPadButton, usize); 8] = [
+    (libretro::JoyPadButton::A, 0),
+    (libretro::JoyPadButton::X, 1),
+    (libretro::JoyPadButton::R, 2),
+    (libretro::JoyPadButton::Up, 3),
+    (libretro::JoyPadButton::Right, 4),
+    (libretro::JoyPadButton::Down, 5),
+    (libretro::JoyPadButton::Left, 6),
+    (libretro::JoyPadButton::Start, 7),
+];
+
+/// Fishing controller button mapping, see `FishingController::set_button`
+static FISHING_BUTTON_MAP: [(libretro::JoyPadButton, usize); 8] = [
+    (libretro::JoyPadButton::B, 0),
+    (libretro::JoyPadButton::A, 1),
+    (libretro::JoyPadButton::Select, 2),
+    (libretro::JoyPadButton::Start, 3),
+    (libretro::JoyPadButton::Up, 4),
+    (libretro::JoyPadButton::Right, 5),
+    (libretro::JoyPadButton::Down, 6),
+    (libretro::JoyPadButton::Left, 7),
+];
+
+/// Dance mat mapping, see `DanceMat::set_button`. The corner pads use the same buttons as on the
+/// PlayStation controller.
+static DANCE_MAT_BUTTON_MAP: [(libretro::JoyPadButton, usize); 10] = [
+    (libretro::JoyPadButton::Down, 0),
+    (libretro::JoyPadButton::Right, 1),
+    (libretro::JoyPadButton::Left, 2),
+    (libretro::JoyPadButton::Up, 3),
+    (libretro::JoyPadButton::L, 4),
+    (libretro::JoyPadButton::R, 5),
+    (libretro::JoyPadButton::L2, 6),
+    (libretro::JoyPadButton::R2, 7),
+    (libretro::JoyPadButton::Select, 8),
+    (libretro::JoyPadButton::Start, 9),
 ];
 
+/// Number of libretro input ports: two multitaps with four slots each
+const MAX_PLAYERS: usize = 2 * multitap::SLOTS;
+
+/// Index of the memory card in `slot` of `port` in `Context::memcard_files`
+fn memcard_index(port: usize, slot: usize) -> usize {
+    port * multitap::SLOTS + slot
+}
+
+/// Letter of multitap `slot` ("A" to "D"), empty if there's no multitap
+fn slot_name(multitap: bool, slot: usize) -> &'static str {
+    if multitap {
+        ["A", "B", "C", "D"][slot]
+    } else {
+        ""
+    }
+}
+
+/// Returns the console port and slot (multitap slots A to D) used by `player` when multitaps are
+/// plugged in the ports set in `multitap`. Players are assigned to port 1 first, then port 2.
+/// Without a multitap a port only has a single slot.
+fn player_slot(multitap: [bool; 2], player: usize) -> Option<(usize, usize)> {
+    let mut player = player;
+
+    for (port, &tap) in multitap.iter().enumerate() {
+        let slots = if tap { multitap::SLOTS } else { 1 };
+
+        if player < slots {
+            return Some((port, player));
+        }
+
+        player -= slots;
+    }
+
+    None
+}
+
+/// Read the frontend's inputs for the peripherals that don't behave like a standard gamepad. See
+/// the `set_button` and `set_axis` implementations of each device for the meaning of the indices.
+fn poll_peripheral(port: usize, ty: ControllerType, input: &mut PadInput) {
+    let button_map: &[(libretro::JoyPadButton, usize)] = match ty {
+        ControllerType::NeGcon => &NEGCON_BUTTON_MAP,
+        ControllerType::FishingController => &FISHING_BUTTON_MAP,
+        ControllerType::DanceMat => &DANCE_MAT_BUTTON_MAP,
+        _ => &[],
+    };
+
+    for &(retrobutton, index) in button_map {
+        input.set_peripheral_button(index, libretro::button_pressed(port, retrobutton));
+    }
+
+    let stick = |input, axis| libretro::axis_state(port, input, axis);
+
+    match ty {
+        ControllerType::NeGcon => {
+            // Use the full range if the frontend doesn't support analog buttons
+            let analog = |b| match libretro::button_analog(port, b) {
+                0 if libretro::button_pressed(port, b) => i16::MAX,
+                v => v,
+            };
+
+            let i = analog(libretro::JoyPadButton::R2).max(analog(libretro::JoyPadButton::B));
+            let ii = analog(libretro::JoyPadButton::L2).max(analog(libretro::JoyPadButton::Y));
+
+            let twist = stick(libretro::AnalogInput::Left, libretro::AnalogAxis::X);
+
+            input.axes[0] = twist;
+            input.axes[1] = i.saturating_sub(ii);
+            input.axes[2] = analog(libretro::JoyPadButton::L);
+        }
+        ControllerType::FishingController => {
+            let (lx, ly) = (
+                stick(libretro::AnalogInput::Left, libretro::AnalogAxis::X),
+                stick(libretro::AnalogInput::Left, libretro::AnalogAxis::Y),
+            );
+            let (rx, ry) = (
+                stick(libretro::AnalogInput::Right, libretro::AnalogAxis::X),
+                stick(libretro::AnalogInput::Right, libretro::AnalogAxis::Y),
+            );
+
+            input.axes[0] = lx;
+            // Casting is done by pushing the stick up, libretro's Y axis points down
+            input.axes[1] = ly.saturating_neg();
+            input.axes[2] = rx;
+            input.axes[3] = ry;
+        }
+        ControllerType::Mouse => {
+            let state = |input| libretro::mouse_state(port, input);
+
+            input.set_peripheral_button(0, state(libretro::MouseInput::Left) != 0);
+            input.set_peripheral_button(1, state(libretro::MouseInput::Right) != 0);
+
+            // The mouse scales the axes down by 256, we want one count per frontend pixel
+            input.axes[0] = state(libretro::MouseInput::X).saturating_mul(256);
+            input.axes[1] = state(libretro::MouseInput::Y).saturating_mul(256);
+        }
+        ControllerType::GunCon | ControllerType::Justifier => {
+            let aim = |input| libretro::lightgun_state(port, input);
+            let state = |input| aim(input) != 0;
+
+            // Reloading is done by shooting away from the screen
+            let reload = state(libretro::LightGunInput::Reload);
+
+            // The Justifier's second button is Start
+            let b = if ty == ControllerType::Justifier {
+                libretro::LightGunInput::Start
+            } else {
+                libretro::LightGunInput::AuxB
+            };
+
+            input.axes[0] = aim(libretro::LightGunInput::ScreenX);
+            input.axes[1] = aim(libretro::LightGunInput::ScreenY);
+            input.set_peripheral_button(0, state(libretro::LightGunInput::Trigger) || reload);
+            input.set_peripheral_button(1, state(libretro::LightGunInput::AuxA));
+            input.set_peripheral_button(2, state(b));
+            input.set_peripheral_button(3, state(libretro::LightGunInput::IsOffscreen) || reload);
+        }
+        ControllerType::GunConPointer => {
+            let state = |input| libretro::pointer_state(port, input);
+
+            input.axes[0] = state(libretro::PointerInput::X);
+            input.axes[1] = state(libretro::PointerInput::Y);
+            input.set_peripheral_button(0, state(libretro::PointerInput::Pressed) != 0);
+            input.set_peripheral_button(
+                1,
+                libretro::button_pressed(port, libretro::JoyPadButton::A),
+            );
+            input.set_peripheral_button(
+                2,
+                libretro::button_pressed(port, libretro::JoyPadButton::B),
+            );
+            input.set_peripheral_button(3, state(libretro::PointerInput::IsOffscreen) != 0);
+        }
+        _ => (),
+    }
+}
+
+/// Emulated device connected to a controller port
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+enum ControllerType {
+    None,
+    Digital,
+    DualShock,
+    NeGcon,
+    FishingController,
+    DanceMat,
+    Mouse,
+    /// GunCon aimed with a lightgun
+    GunCon,
+    /// GunCon aimed with a touchscreen or the mouse cursor
+    GunConPointer,
+    /// Konami Justifier aimed with a lightgun
+    Justifier,
+}
+
+impl ControllerType {
+    fn from_subclass(device: libretro::InputDevice, subclass: u32) -> Option<ControllerType> {
+        let ty = match subclass {
+            PSX_CONTROLLER_DIGITAL => ControllerType::Digital,
+            PSX_CONTROLLER_DUALSHOCK => ControllerType::DualShock,
+            PSX_CONTROLLER_NEGCON => ControllerType::NeGcon,
+            PSX_CONTROLLER_FISHING => ControllerType::FishingController,
+            PSX_CONTROLLER_DANCE_MAT => ControllerType::DanceMat,
+            PSX_CONTROLLER_MOUSE => ControllerType::Mouse,
+            PSX_CONTROLLER_GUNCON => ControllerType::GunCon,
+            PSX_CONTROLLER_GUNCON_POINTER => ControllerType::GunConPointer,
+            PSX_CONTROLLER_JUSTIFIER => ControllerType::Justifier,
+            // Try to match the generic class instead
+            _ => match device {
+                libretro::InputDevice::None => ControllerType::None,
+                libretro::InputDevice::JoyPad => ControllerType::Digital,
+                libretro::InputDevice::Analog => ControllerType::DualShock,
+                libretro::InputDevice::Mouse => ControllerType::Mouse,
+                libretro::InputDevice::LightGun => ControllerType::GunCon,
+                libretro::InputDevice::Pointer => ControllerType::GunConPointer,
+                libretro::InputDevice::Keyboard => return None,
+            },
+        };
+
+        Some(ty)
+    }
+
+    fn new_device(self, standard: psx::VideoStandard) -> Box<dyn DeviceInterface> {
+        let peripheral = match self {
+            ControllerType::None => return Box::new(DisconnectedDevice),
+            ControllerType::Digital => return Box::new(DigitalPad::new()),
+            ControllerType::DualShock => return Box::new(DualShock::new()),
+            ControllerType::NeGcon => negcon::negcon(),
+            ControllerType::FishingController => fishing::fishing_controller(),
+            ControllerType::DanceMat => dance_mat::dance_mat(),
+            ControllerType::Mouse => mouse::mouse(),
+            ControllerType::GunCon | ControllerType::GunConPointer => guncon::guncon(standard),
+            ControllerType::Justifier => justifier::justifier(standard),
+        };
+
+        Box::new(PeripheralAdapter::new(peripheral))
+    }
+}
+
+/// Returns the descriptors of the inputs used by the controllers configured on each libretro port
+fn input_descriptors(types: &[ControllerType]) -> Vec<libretro::InputDescriptor> {
+    use libretro::InputDescriptor as Desc;
+    use libretro::JoyPadButton as Pad;
+
+    let mut descriptors = Vec::new();
+
+    for (port, &ty) in types.iter().enumerate() {
+        let port = port as libc::c_uint;
+
+        let stick = |stick, axis, description| Desc::analog_axis(port, stick, axis, description);
+
+        let dpad = [
+            Desc::joypad_button(port, Pad::Left, cstring!("D-Pad Left")),
+            Desc::joypad_button(port, Pad::Up, cstring!("D-Pad Up")),
+            Desc::joypad_button(port, Pad::Down, cstring!("D-Pad Down")),
+            Desc::joypad_button(port, Pad::Right, cstring!("D-Pad Right")),
+        ];
+
+        match ty {
+            ControllerType::None => (),
+            ControllerType::Digital | ControllerType::DualShock => {
+                descriptors.extend(dpad);
+                descriptors.extend([
+                    Desc::joypad_button(port, Pad::B, cstring!("Cross")),
+                    Desc::joypad_button(port, Pad::A, cstring!("Circle")),
+                    Desc::joypad_button(port, Pad::X, cstring!("Triangle")),
+                    Desc::joypad_button(port, Pad::Y, cstring!("Square")),
+                    Desc::joypad_button(port, Pad::L, cstring!("L1")),
+                    Desc::joypad_button(port, Pad::L2, cstring!("L2")),
+                    Desc::joypad_button(port, Pad::R, cstring!("R1")),
+                    Desc::joypad_button(port, Pad::R2, cstring!("R2")),
+                    Desc::joypad_button(port, Pad::Select, cstring!("Select")),
+                    Desc::joypad_button(port, Pad::Start, cstring!("Start")),
+                ]);
+
+                if ty == ControllerType::DualShock {
+                    descriptors.extend([
+                        Desc::joypad_button(port, Pad::L3, cstring!("L3")),
+                        Desc::joypad_button(port, Pad::R3, cstring!("R3")),
+                        stick(
+                            libretro::AnalogInput::Left,
+                            libretro::AnalogAxis::X,
+                            cstring!("Left Analog X"),
+                        ),
+                        stick(
+                            libretro::AnalogInput::Left,
+                            libretro::AnalogAxis::Y,
+                            cstring!("Left Analog Y"),
+                        ),
+                        stick(
+                            libretro::AnalogInput::Right,
+                            libretro::AnalogAxis::X,
+                            cstring!("Right Analog X"),
+                        ),
+                        stick(
+                            libretro::AnalogInput::Right,
+                            libretro::AnalogAxis::Y,
+                            cstring!("Right Analog Y"),
+                        ),
+                    ]);
+                }
+            }
+            ControllerType::NeGcon => {
+                descriptors.extend(dpad);
+                descriptors.extend([
+                    Desc::joypad_button(port, Pad::A, cstring!("A")),
+                    Desc::joypad_button(port, Pad::X, cstring!("B")),
+                    Desc::joypad_button(port, Pad::B, cstring!("I")),
+                    Desc::joypad_button(port, Pad::Y, cstring!("II")),
+                    Desc::joypad_button(port, Pad::L, cstring!("L")),
+                    Desc::joypad_button(port, Pad::R, cstring!("R")),
+                    Desc::joypad_button(port, Pad::Start, cstring!("Start")),
+                    Desc::analog_button(port, Pad::R2, cstring!("I (analog)")),
+                    Desc::analog_button(port, Pad::L2, cstring!("II (analog)")),
+                    stick(
+                        libretro::AnalogInput::Left,
+                        libretro::AnalogAxis::X,
+                        cstring!("Twist"),
+                    ),
+                ]);
+            }
+            ControllerType::FishingController => {
+                descriptors.extend(dpad);
+                descriptors.extend([
+                    Desc::joypad_button(port, Pad::B, cstring!("Cast")),
+                    Desc::joypad_button(port, Pad::A, cstring!("Reel")),
+                    Desc::joypad_button(port, Pad::Select, cstring!("Select")),
+                    Desc::joypad_button(port, Pad::Start, cstring!("Start")),
+                    stick(
+                        libretro::AnalogInput::Left,
+                        libretro::AnalogAxis::X,
+                        cstring!("Reel Rotation"),
+                    ),
+                    stick(
+                        libretro::AnalogInput::Left,
+                        libretro::AnalogAxis::Y,
+                        cstring!("Rod Motion"),
+                    ),
+                    stick(
+                        libretro::AnalogInput::Right,
+                        libretro::AnalogAxis::X,
+                        cstring!("Rod Tilt X"),
+                    ),
+                    stick(
+                        libretro::AnalogInput::Right,
+                        libretro::AnalogAxis::Y,
+                        cstring!("Rod Tilt Y"),
+                    ),
+                ]);
+            }
+            ControllerType::DanceMat => {
+                descriptors.extend([
+                    Desc::joypad_button(port, Pad::Left, cstring!("Left Arrow")),
+                    Desc::joypad_button(port, Pad::Up, cstring!("Up Arrow")),
+                    Desc::joypad_button(port, Pad::Down, cstring!("Down Arrow")),
+                    Desc::joypad_button(port, Pad::Right, cstring!("Right Arrow")),
+                    Desc::joypad_button(port, Pad::L, cstring!("Upper Left Pad")),
+                    Desc::joypad_button(port, Pad::R, cstring!("Upper Right Pad")),
+                    Desc::joypad_button(port, Pad::L2, cstring!("Lower Left Pad")),
+                    Desc::joypad_button(port, Pad::R2, cstring!("Lower Right Pad")),
+                    Desc::joypad_button(port, Pad::Select, cstring!("Select")),
+                    Desc::joypad_button(port, Pad::Start, cstring!("Start")),
+                ]);
+            }
+            ControllerType::Mouse => {
+                descriptors.extend([
+                    Desc::mouse(port, libretro::MouseInput::Left, cstring!("Left Button")),
+                    Desc::mouse(port, libretro::MouseInput::Right, cstring!("Right Button")),
+                ]);
+            }
+            ControllerType::GunCon => {
+                descriptors.extend([
+                    Desc::lightgun(port, libretro::LightGunInput::Trigger, cstring!("Trigger")),
+                    Desc::lightgun(port, libretro::LightGunInput::AuxA, cstring!("A")),
+                    Desc::lightgun(port, libretro::LightGunInput::AuxB, cstring!("B")),
+                    Desc::lightgun(
+                        port,
+                        libretro::LightGunInput::Reload,
+                        cstring!("Shoot Off-Screen"),
+                    ),
+                ]);
+            }
+            ControllerType::Justifier => {
+                descriptors.extend([
+                    Desc::lightgun(port, libretro::LightGunInput::Trigger, cstring!("Trigger")),
+                    Desc::lightgun(port, libretro::LightGunInput::AuxA, cstring!("Back")),
+                    Desc::lightgun(port, libretro::LightGunInput::Start, cstring!("Start")),
+                    Desc::lightgun(
+                        port,
+                        libretro::LightGunInput::Reload,
+                        cstring!("Shoot Off-Screen"),
+                    ),
+                ]);
+            }
+            ControllerType::GunConPointer => {
+                descriptors.extend([
+                    Desc::pointer(port, libretro::PointerInput::Pressed, cstring!("Trigger")),
+                    Desc::joypad_button(port, Pad::A, cstring!("A")),
+                    Desc::joypad_button(port, Pad::B, cstring!("B")),
+                ]);
+            }
+        }
+    }
+
+    descriptors.push(Desc::end_of_table());
+
+    descriptors
+}
+
 /// Standard, digital-only controller (SCPH-1080)
 const PSX_CONTROLLER_DIGITAL: libc::c_uint = libretro::InputDevice::JoyPad.subclass(0);
 /// DualShock analog controller (SCPH-1200)
 const PSX_CONTROLLER_DUALSHOCK: libc::c_uint = libretro::InputDevice::Analog.subclass(0);
+/// NeGcon racing controller (NPC-101)
+const PSX_CONTROLLER_NEGCON: libc::c_uint = libretro::InputDevice::Analog.subclass(1);
+/// Fishing controller
+const PSX_CONTROLLER_FISHING: libc::c_uint = libretro::InputDevice::Analog.subclass(2);
+/// Dance mat
+const PSX_CONTROLLER_DANCE_MAT: libc::c_uint = libretro::InputDevice::JoyPad.subclass(1);
+/// PlayStation Mouse (SCPH-1090)
+const PSX_CONTROLLER_MOUSE: libc::c_uint = libretro::InputDevice::Mouse.subclass(0);
+/// GunCon lightgun (NPC-103)
+const PSX_CONTROLLER_GUNCON: libc::c_uint = libretro::InputDevice::LightGun.subclass(0);
+/// GunCon aimed with a pointer
+const PSX_CONTROLLER_GUNCON_POINTER: libc::c_uint = libretro::InputDevice::Pointer.subclass(0);
+/// Konami Justifier lightgun (SLUH-00017)
+const PSX_CONTROLLER_JUSTIFIER: libc::c_uint = libretro::InputDevice::LightGun.subclass(1);
 
 /// All supported controller types
-static CONTROLLER_DESCRIPTIONS: [libretro::ControllerDescription; 2] = [
+static CONTROLLER_DESCRIPTIONS: [libretro::ControllerDescription; 9] = [
     libretro::ControllerDescription {
         desc: cstring!("PlayStation Digital Controller"),
         id: PSX_CONTROLLER_DIGITAL,
@@ -1844,10 +3463,38 @@ static CONTROLLER_DESCRIPTIONS: [libretro::ControllerDescription; 2] = [
         desc: cstring!("PlayStation DualShock Analog Controller"),
         id: PSX_CONTROLLER_DUALSHOCK,
     },
+    libretro::ControllerDescription {
+        desc: cstring!("NeGcon"),
+        id: PSX_CONTROLLER_NEGCON,
+    },
+    libretro::ControllerDescription {
+        desc: cstring!("Fishing Controller"),
+        id: PSX_CONTROLLER_FISHING,
+    },
+    libretro::ControllerDescription {
+        desc: cstring!("Dance Mat"),
+        id: PSX_CONTROLLER_DANCE_MAT,
+    },
+    libretro::ControllerDescription {
+        desc: cstring!("PlayStation Mouse"),
+        id: PSX_CONTROLLER_MOUSE,
+    },
+    libretro::ControllerDescription {
+        desc: cstring!("GunCon"),
+        id: PSX_CONTROLLER_GUNCON,
+    },
+    libretro::ControllerDescription {
+        desc: cstring!("GunCon (Touchscreen/Pointer)"),
+        id: PSX_CONTROLLER_GUNCON_POINTER,
+    },
+    libretro::ControllerDescription {
+        desc: cstring!("Konami Justifier"),
+        id: PSX_CONTROLLER_JUSTIFIER,
+    },
 ];
 
-/// Controller settings for two player mode (no multitap)
-static CONTROLLER_INFO_2P: [libretro::ControllerInfo; 3] = [
+/// Controller settings for the 8 players. Players 3 to 8 can only be used with a multitap.
+static CONTROLLER_INFO: [libretro::ControllerInfo; MAX_PLAYERS + 1] = [
     // Player 1
     libretro::ControllerInfo {
         types: &CONTROLLER_DESCRIPTIONS as *const _,
@@ -1858,13 +3505,43 @@ static CONTROLLER_INFO_2P: [libretro::ControllerInfo; 3] = [
         types: &CONTROLLER_DESCRIPTIONS as *const _,
         num_types: CONTROLLER_DESCRIPTIONS.len() as _,
     },
+    // Player 3
+    libretro::ControllerInfo {
+        types: &CONTROLLER_DESCRIPTIONS as *const _,
+        num_types: CONTROLLER_DESCRIPTIONS.len() as _,
+    },
+    // Player 4
+    libretro::ControllerInfo {
+        types: &CONTROLLER_DESCRIPTIONS as *const _,
+        num_types: CONTROLLER_DESCRIPTIONS.len() as _,
+    },
+    // Player 5
+    libretro::ControllerInfo {
+        types: &CONTROLLER_DESCRIPTIONS as *const _,
+        num_types: CONTROLLER_DESCRIPTIONS.len() as _,
+    },
+    // Player 6
+    libretro::ControllerInfo {
+        types: &CONTROLLER_DESCRIPTIONS as *const _,
+        num_types: CONTROLLER_DESCRIPTIONS.len() as _,
+    },
+    // Player 7
+    libretro::ControllerInfo {
+        types: &CONTROLLER_DESCRIPTIONS as *const _,
+        num_types: CONTROLLER_DESCRIPTIONS.len() as _,
+    },
+    // Player 8
+    libretro::ControllerInfo {
+        types: &CONTROLLER_DESCRIPTIONS as *const _,
+        num_types: CONTROLLER_DESCRIPTIONS.len() as _,
+    },
     // End of table marker
     libretro::ControllerInfo::end_of_table(),
 ];
 
 fn init_controllers() {
-    libretro::set_controller_info(&CONTROLLER_INFO_2P);
-    libretro::set_input_descriptors(&INPUT_DESCRIPTORS);
+    libretro::set_controller_info(&CONTROLLER_INFO);
+    libretro::set_input_descriptors(&input_descriptors(&[ControllerType::DualShock; 2]));
     
     // Register disc control interface for multi-disc support
     if !libretro::disc_control::register_disc_control() {
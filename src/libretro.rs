@@ -174,8 +174,10 @@ pub enum Environment {
     SetSystemAvInfo = 32,
     SetControllerInfo = 35,
     SetGeometry = 37,
+    GetTargetRefreshRate = 50,
     GetDiskControlInterfaceVersion = 57,
     SetDiskControlExtInterface = 58,
+    SetVariable = 70,
 }
 
 /// Controller types supported by libretro
@@ -303,6 +305,66 @@ impl InputDescriptor {
         }
     }
 
+    /// Describe the analog value of a button (analog triggers)
+    pub const fn analog_button(
+        port: c_uint,
+        button: JoyPadButton,
+        description: *const c_char,
+    ) -> InputDescriptor {
+        InputDescriptor {
+            port,
+            device: InputDevice::Analog as _,
+            index: AnalogInput::Button as _,
+            id: button as _,
+            description,
+        }
+    }
+
+    /// Describe a mouse input
+    pub const fn mouse(
+        port: c_uint,
+        input: MouseInput,
+        description: *const c_char,
+    ) -> InputDescriptor {
+        InputDescriptor {
+            port,
+            device: InputDevice::Mouse as _,
+            index: 0,
+            id: input as _,
+            description,
+        }
+    }
+
+    /// Describe a lightgun input
+    pub const fn lightgun(
+        port: c_uint,
+        input: LightGunInput,
+        description: *const c_char,
+    ) -> InputDescriptor {
+        InputDescriptor {
+            port,
+            device: InputDevice::LightGun as _,
+            index: 0,
+            id: input as _,
+            description,
+        }
+    }
+
+    /// Describe a pointer (touchscreen) input
+    pub const fn pointer(
+        port: c_uint,
+        input: PointerInput,
+        description: *const c_char,
+    ) -> InputDescriptor {
+        InputDescriptor {
+            port,
+            device: InputDevice::Pointer as _,
+            index: 0,
+            id: input as _,
+            description,
+        }
+    }
+
     /// End of table marker
     pub const fn end_of_table() -> InputDescriptor {
         InputDescriptor {
@@ -319,7 +381,9 @@ impl InputDescriptor {
 // this should be safe, although of course we don't enforce it here so it's a bit dirty.
 unsafe impl Sync for InputDescriptor {}
 
-pub fn set_input_descriptors(descriptors: &'static [InputDescriptor]) -> bool {
+/// The table itself can be freed once this returns but the frontend keeps pointers to the
+/// descriptions, they must be static.
+pub fn set_input_descriptors(descriptors: &[InputDescriptor]) -> bool {
     assert!(
         !descriptors.is_empty() && descriptors[descriptors.len() - 1].description.is_null(),
         "Non-NULL terminated input descriptors!"
@@ -505,6 +569,38 @@ pub enum AnalogAxis {
     Y = 1,
 }
 
+/// RETRO_DEVICE_ID_MOUSE_* constants
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MouseInput {
+    X = 0,
+    Y = 1,
+    Left = 2,
+    Right = 3,
+}
+
+/// RETRO_DEVICE_ID_LIGHTGUN_* constants
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LightGunInput {
+    Trigger = 2,
+    AuxA = 3,
+    AuxB = 4,
+    Start = 6,
+    Select = 7,
+    ScreenX = 13,
+    ScreenY = 14,
+    IsOffscreen = 15,
+    Reload = 16,
+}
+
+/// RETRO_DEVICE_ID_POINTER_* constants
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PointerInput {
+    X = 0,
+    Y = 1,
+    Pressed = 2,
+    IsOffscreen = 15,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum PixelFormat {
     Xrgb1555 = 0,
@@ -989,6 +1085,34 @@ pub fn axis_state(port: usize, input: AnalogInput, axis: AnalogAxis) -> i16 {
     unsafe { INPUT_STATE(port as _, InputDevice::Analog as _, input as _, axis as _) }
 }
 
+/// Analog value of a button, between 0 and 0x7fff. Returns 0 if the frontend doesn't support
+/// analog buttons.
+pub fn button_analog(port: usize, b: JoyPadButton) -> i16 {
+    unsafe {
+        INPUT_STATE(
+            port as _,
+            InputDevice::Analog as _,
+            AnalogInput::Button as _,
+            b as _,
+        )
+    }
+}
+
+/// For the X and Y axes returns the relative movement since the last poll
+pub fn mouse_state(port: usize, input: MouseInput) -> i16 {
+    unsafe { INPUT_STATE(port as _, InputDevice::Mouse as _, 0, input as _) }
+}
+
+/// For the screen axes returns the aimed position between -0x7fff and 0x7fff
+pub fn lightgun_state(port: usize, input: LightGunInput) -> i16 {
+    unsafe { INPUT_STATE(port as _, InputDevice::LightGun as _, 0, input as _) }
+}
+
+/// For the X and Y axes returns the touched position between -0x7fff and 0x7fff
+pub fn pointer_state(port: usize, input: PointerInput) -> i16 {
+    unsafe { INPUT_STATE(port as _, InputDevice::Pointer as _, 0, input as _) }
+}
+
 pub fn set_rumble(port: usize, effect: RumbleEffect, strength: u16) -> bool {
     unsafe { SET_RUMBLE_STATE(port as _, effect, strength) }
 }
@@ -1024,6 +1148,19 @@ pub fn get_save_directory() -> Option<PathBuf> {
     }
 }
 
+/// Returns the refresh rate of the frontend's display, if it knows it
+pub fn get_target_refresh_rate() -> Option<f32> {
+    let mut rate: f32 = 0.;
+
+    let success = unsafe { call_environment_mut(Environment::GetTargetRefreshRate, &mut rate) };
+
+    if success && rate > 0. {
+        Some(rate)
+    } else {
+        None
+    }
+}
+
 pub fn set_pixel_format(format: PixelFormat) -> bool {
     let f = format as c_uint;
 
@@ -1077,6 +1214,20 @@ pub unsafe fn register_variables(variables: &[Variable]) -> bool {
     call_environment_slice(Environment::SetVariables, variables)
 }
 
+/// Change the value of a core option. Not all frontends support it, returns false if the value
+/// couldn't be changed.
+///
+/// # Safety
+/// `var_cstr` and `value_cstr` must be NUL-terminated strings
+pub unsafe fn set_variable(var_cstr: *const c_char, value_cstr: *const c_char) -> bool {
+    let v = Variable {
+        key: var_cstr,
+        value: value_cstr,
+    };
+
+    call_environment(Environment::SetVariable, &v)
+}
+
 unsafe fn call_environment_mut<T>(which: Environment, var: *mut T) -> bool {
     ENVIRONMENT(which as c_uint, var as *mut c_void)
 }
@@ -0,0 +1,300 @@
+//! Crash-safe memory card writes and rotating backups
+//!
+//! Memory card files are never overwritten in place: the new contents are written to a temporary
+//! file which is then renamed over the old one, so a crash mid-write leaves either the old or the
+//! new version on disc. On top of that we keep the last few versions of every card in a
+//! `backups` directory next to it, named `<card file>.<unix time>.bak`. Backups start with a small
+//! header containing a CRC of the data in order to detect corrupt backups.
+//!
+//! The CRC of the card file itself is stored in `<card file>.crc` so that a card modified outside
+//! of the emulator (bad sector, sync tool, save editor...) can be detected when it's loaded.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A backup of a memory card file
+#[derive(Clone, Debug)]
+pub struct Backup {
+    pub path: PathBuf,
+    /// Creation time of the backup, in seconds since the UNIX epoch
+    pub timestamp: u64,
+}
+
+impl Backup {
+    /// Read the contents of the backup, returns an error if it's corrupt
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let data = fs::read(&self.path)?;
+
+        if data.len() < HEADER_SIZE || &data[0..4] != MAGIC {
+            return Err(invalid("Not a memory card backup"));
+        }
+
+        let len = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let crc = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+
+        let payload = &data[HEADER_SIZE..];
+
+        if payload.len() != len || crc32fast::hash(payload) != crc {
+            return Err(invalid("Corrupt memory card backup"));
+        }
+
+        Ok(payload.to_vec())
+    }
+
+    /// Returns a short description of the age of the backup ("5 minutes ago")
+    pub fn age(&self) -> String {
+        let elapsed = now().saturating_sub(self.timestamp);
+
+        let (n, unit) = match elapsed {
+            0..=59 => (elapsed, "second"),
+            60..=3599 => (elapsed / 60, "minute"),
+            3600..=86399 => (elapsed / 3600, "hour"),
+            _ => (elapsed / 86400, "day"),
+        };
+
+        format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" })
+    }
+}
+
+/// Write `data` to `path` atomically: the data is written to a temporary file and synced to disc
+/// before replacing `path`.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let res = File::create(&tmp).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+
+    if let Err(e) = res.and_then(|_| fs::rename(&tmp, path)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+
+    // Make sure that the rename itself hits the disc. This isn't possible on all platforms so
+    // we don't treat it as an error.
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
+
+/// Write `data` to the memory card file `card` along with its checksum. The checksum is written
+/// first: if we're interrupted in between the card is left untouched and merely fails the
+/// checksum test, which is then updated when it's loaded.
+pub fn write_card(card: &Path, data: &[u8]) -> io::Result<()> {
+    write_checksum(card, data)?;
+
+    write_atomic(card, data)
+}
+
+/// Replace the checksum stored for the memory card file `card` with the one of `data`
+pub fn write_checksum(card: &Path, data: &[u8]) -> io::Result<()> {
+    write_atomic(&checksum_path(card), &crc32fast::hash(data).to_le_bytes())
+}
+
+/// Check `data` against the checksum stored for the memory card file `card`. Cards without a
+/// checksum (created before checksums were introduced or by another program) are accepted.
+pub fn checksum_matches(card: &Path, data: &[u8]) -> bool {
+    let crc = match fs::read(checksum_path(card)) {
+        Ok(crc) => crc,
+        Err(_) => return true,
+    };
+
+    crc[..] == crc32fast::hash(data).to_le_bytes()
+}
+
+/// Returns the backups of the memory card file `card`, newest first
+pub fn list(card: &Path) -> Vec<Backup> {
+    let dir = match backup_dir(card) {
+        Some(d) => d,
+        None => return Vec::new(),
+    };
+
+    let prefix = match card.file_name().and_then(|n| n.to_str()) {
+        Some(n) => format!("{}.", n),
+        None => return Vec::new(),
+    };
+
+    let entries = match fs::read_dir(&dir) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut backups: Vec<Backup> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name();
+            let timestamp = name
+                .to_str()?
+                .strip_prefix(&prefix)?
+                .strip_suffix(".bak")?
+                .parse()
+                .ok()?;
+
+            Some(Backup {
+                path: e.path(),
+                timestamp,
+            })
+        })
+        .collect();
+
+    backups.sort_by_key(|b| std::cmp::Reverse(b.timestamp));
+
+    backups
+}
+
+/// Save `data` as a new backup of the memory card file `card` and delete the oldest backups to
+/// keep at most `keep` of them
+pub fn create(card: &Path, data: &[u8], keep: usize) -> io::Result<()> {
+    let dir = match backup_dir(card) {
+        Some(d) => d,
+        None => return Err(invalid("Invalid memory card path")),
+    };
+
+    let name = match card.file_name() {
+        Some(n) => n.to_string_lossy(),
+        None => return Err(invalid("Invalid memory card path")),
+    };
+
+    fs::create_dir_all(&dir)?;
+
+    let mut backup = Vec::with_capacity(HEADER_SIZE + data.len());
+    backup.extend_from_slice(MAGIC);
+    backup.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    backup.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+    backup.extend_from_slice(data);
+
+    // If we already have a backup from this very second it's simply replaced
+    let path = dir.join(format!("{}.{}.bak", name, now()));
+
+    write_atomic(&path, &backup)?;
+
+    for old in list(card).iter().skip(keep) {
+        if let Err(e) = fs::remove_file(&old.path) {
+            warn!("Can't remove old backup '{}': {}", old.path.display(), e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the newest backup of `card` that isn't corrupt, along with its 1-based position in
+/// the list returned by `list`
+pub fn newest_valid(card: &Path) -> Option<(usize, Backup)> {
+    list(card)
+        .into_iter()
+        .enumerate()
+        .find_map(|(i, b)| match b.read() {
+            Ok(_) => Some((i + 1, b)),
+            Err(e) => {
+                warn!("Ignoring backup '{}': {}", b.path.display(), e);
+                None
+            }
+        })
+}
+
+fn checksum_path(card: &Path) -> PathBuf {
+    let mut path = card.as_os_str().to_owned();
+    path.push(".crc");
+
+    PathBuf::from(path)
+}
+
+fn backup_dir(card: &Path) -> Option<PathBuf> {
+    card.parent().map(|p| p.join("backups"))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn invalid(m: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, m)
+}
+
+/// Magic at the start of the backup files
+const MAGIC: &[u8] = b"RSXB";
+
+/// Size of the backup header: magic, length of the data and CRC32 of the data
+const HEADER_SIZE: usize = 12;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_and_detect_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let card = dir.path().join("game.0.mcr");
+
+        write_atomic(&card, b"first").unwrap();
+        assert_eq!(fs::read(&card).unwrap(), b"first");
+        assert!(!dir.path().join("game.0.mcr.tmp").exists());
+
+        // Backups are named after their creation time, fake older ones
+        let backups = dir.path().join("backups");
+        fs::create_dir_all(&backups).unwrap();
+
+        for t in 1..=4u64 {
+            let path = backups.join(format!("game.0.mcr.{}.bak", t));
+            let mut backup = MAGIC.to_vec();
+            backup.extend_from_slice(&1u32.to_le_bytes());
+            backup.extend_from_slice(&crc32fast::hash(&[t as u8]).to_le_bytes());
+            backup.push(t as u8);
+            fs::write(path, backup).unwrap();
+        }
+
+        // Unrelated file
+        fs::write(backups.join("game.1.mcr.5.bak"), b"").unwrap();
+
+        create(&card, b"second", 3).unwrap();
+
+        let list = list(&card);
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[0].read().unwrap(), b"second");
+        assert_eq!(list[1].timestamp, 4);
+        assert_eq!(list[2].timestamp, 3);
+        assert!(backups.join("game.1.mcr.5.bak").exists());
+
+        // Corrupt the newest backup
+        let mut data = fs::read(&list[0].path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&list[0].path, data).unwrap();
+        assert!(list[0].read().is_err());
+
+        let (index, backup) = newest_valid(&card).unwrap();
+        assert_eq!(index, 2);
+        assert_eq!(backup.timestamp, 4);
+        assert_eq!(backup.read().unwrap(), vec![4]);
+    }
+
+    #[test]
+    fn card_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let card = dir.path().join("game.0.mcr");
+
+        // No checksum yet
+        fs::write(&card, b"old").unwrap();
+        assert!(checksum_matches(&card, b"old"));
+
+        write_card(&card, b"new").unwrap();
+        assert_eq!(fs::read(&card).unwrap(), b"new");
+        assert!(checksum_matches(&card, b"new"));
+        assert!(!checksum_matches(&card, b"nex"));
+
+        write_checksum(&card, b"nex").unwrap();
+        assert!(checksum_matches(&card, b"nex"));
+        assert_eq!(fs::read(&card).unwrap(), b"new");
+    }
+}
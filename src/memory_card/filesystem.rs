@@ -0,0 +1,784 @@
+//! Parsed view of the memory card filesystem
+//!
+//! A memory card is made of 16 blocks of 8KiB. Block 0 contains the header frame, one directory
+//! frame for each of the 15 other blocks and the broken sector list. The other blocks hold the
+//! saves: a save spans one or more blocks chained through the "next block" pointer of their
+//! directory frames. The first frame of a save contains its title and the palette of its icon,
+//! the following frames contain the icon's animation frames.
+//!
+//! `wasm_unified.rs` builds this file on its own for the browser's memory card manager, so it can
+//! only use `std`, serde and `encoding_rs`.
+
+use serde::Serialize;
+
+/// Size of a single frame (sector) in bytes
+pub const FRAME_SIZE: usize = 128;
+
+/// Size of a single block in bytes
+pub const BLOCK_SIZE: usize = 64 * FRAME_SIZE;
+
+/// Total size of a memory card in bytes
+pub const CARD_SIZE: usize = 16 * BLOCK_SIZE;
+
+/// Number of blocks available for saves, numbered 1 to 15
+pub const DATA_BLOCKS: usize = 15;
+
+/// Width and height of the save icons in pixels
+pub const ICON_SIZE: usize = 16;
+
+/// Magic at the start of the first frame of every save
+const SAVE_MAGIC: &[u8] = b"SC";
+
+/// Maximum length of a filename in the directory
+const NAME_LEN: usize = 20;
+
+/// Length of the Shift-JIS title in the first frame of a save
+const TITLE_LEN: usize = 64;
+
+/// Value of the next block pointer for the last block of a save
+const NO_NEXT_BLOCK: u16 = 0xffff;
+
+/// Allocation state of a data block, stored in its directory frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub enum BlockState {
+    Free,
+    /// First block of a save
+    First,
+    /// Block in the middle of a multi-block save
+    Middle,
+    /// Last block of a multi-block save
+    Last,
+    DeletedFirst,
+    DeletedMiddle,
+    DeletedLast,
+    Unknown(u8),
+}
+
+impl BlockState {
+    fn from_raw(v: u8) -> BlockState {
+        match v {
+            0xa0 => BlockState::Free,
+            0x51 => BlockState::First,
+            0x52 => BlockState::Middle,
+            0x53 => BlockState::Last,
+            0xa1 => BlockState::DeletedFirst,
+            0xa2 => BlockState::DeletedMiddle,
+            0xa3 => BlockState::DeletedLast,
+            _ => BlockState::Unknown(v),
+        }
+    }
+
+    fn raw(self) -> u8 {
+        match self {
+            BlockState::Free => 0xa0,
+            BlockState::First => 0x51,
+            BlockState::Middle => 0x52,
+            BlockState::Last => 0x53,
+            BlockState::DeletedFirst => 0xa1,
+            BlockState::DeletedMiddle => 0xa2,
+            BlockState::DeletedLast => 0xa3,
+            BlockState::Unknown(v) => v,
+        }
+    }
+
+    /// True if the block belongs to a live save
+    fn is_used(self) -> bool {
+        matches!(
+            self,
+            BlockState::First | BlockState::Middle | BlockState::Last
+        )
+    }
+
+    fn is_deleted(self) -> bool {
+        matches!(
+            self,
+            BlockState::DeletedFirst | BlockState::DeletedMiddle | BlockState::DeletedLast
+        )
+    }
+
+    fn deleted(self) -> BlockState {
+        match self {
+            BlockState::First => BlockState::DeletedFirst,
+            BlockState::Middle => BlockState::DeletedMiddle,
+            BlockState::Last => BlockState::DeletedLast,
+            s => s,
+        }
+    }
+
+    fn undeleted(self) -> BlockState {
+        match self {
+            BlockState::DeletedFirst => BlockState::First,
+            BlockState::DeletedMiddle => BlockState::Middle,
+            BlockState::DeletedLast => BlockState::Last,
+            s => s,
+        }
+    }
+}
+
+/// A save found on the card
+#[derive(Clone, Debug, Serialize)]
+pub struct Save {
+    /// Every block of the save in order, starting with the one holding its directory entry
+    pub blocks: Vec<usize>,
+    /// Filename stored in the directory, such as "BASCUS-9416300000"
+    pub name: String,
+    /// Product code of the game, taken from the filename ("SCUS-94163")
+    pub product_code: String,
+    /// Region of the game, taken from the filename
+    pub region: &'static str,
+    /// Title displayed by the BIOS, decoded from Shift-JIS
+    pub title: String,
+    /// Size of the save according to the directory, in bytes
+    pub size: u32,
+    /// True if the save has been deleted but its blocks haven't been reused yet
+    pub deleted: bool,
+    /// Number of icon animation frames
+    pub icon_frames: usize,
+}
+
+impl Save {
+    /// Block holding the directory entry of the save
+    pub fn first_block(&self) -> usize {
+        self.blocks[0]
+    }
+}
+
+/// Memory card image along with the filesystem operations
+#[derive(Clone)]
+pub struct Card {
+    memory: Vec<u8>,
+}
+
+impl Card {
+    pub fn new(memory: Vec<u8>) -> Result<Card, String> {
+        if memory.len() != CARD_SIZE {
+            return Err(format!(
+                "Invalid memory card size (expected {}B, got {}B)",
+                CARD_SIZE,
+                memory.len()
+            ));
+        }
+
+        Ok(Card { memory })
+    }
+
+    /// Returns a freshly formatted card
+    pub fn formatted() -> Card {
+        let mut card = Card {
+            memory: vec![0; CARD_SIZE],
+        };
+
+        card.memory[0] = b'M';
+        card.memory[1] = b'C';
+        card.update_checksum(0);
+
+        for block in 1..=DATA_BLOCKS {
+            card.free_block(block);
+        }
+
+        // Broken sector list
+        for s in 0..20 {
+            let frame = card.frame_mut(0, 16 + s);
+
+            frame[0..4].copy_from_slice(&[0xff; 4]);
+            frame[8..10].copy_from_slice(&[0xff; 2]);
+        }
+
+        card
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn into_memory(self) -> Vec<u8> {
+        self.memory
+    }
+
+    /// Returns all the saves on the card, including the deleted ones that can still be recovered
+    pub fn saves(&self) -> Vec<Save> {
+        (1..=DATA_BLOCKS)
+            .filter(|&b| {
+                let state = self.block_state(b);
+
+                state == BlockState::First || state == BlockState::DeletedFirst
+            })
+            .map(|b| self.save(b))
+            .collect()
+    }
+
+    /// Returns the number of blocks available for new saves
+    pub fn free_blocks(&self) -> usize {
+        self.available_blocks().len()
+    }
+
+    /// Returns animation frame `frame` of the icon of `save` as 16x16 RGBA pixels
+    pub fn icon(&self, save: &Save, frame: usize) -> Option<Vec<u8>> {
+        if frame >= save.icon_frames {
+            return None;
+        }
+
+        let block = save.first_block();
+        let clut = &self.frame(block, 0)[0x60..0x80];
+        let bitmap = self.frame(block, 1 + frame);
+
+        let mut rgba = Vec::with_capacity(ICON_SIZE * ICON_SIZE * 4);
+
+        for &b in bitmap {
+            // 4bpp, leftmost pixel in the low nibble
+            for &index in &[b & 0xf, b >> 4] {
+                let index = index as usize * 2;
+                let color = u16::from_le_bytes([clut[index], clut[index + 1]]);
+
+                let component = |shift: u16| {
+                    let c = ((color >> shift) & 0x1f) as u8;
+                    (c << 3) | (c >> 2)
+                };
+
+                rgba.push(component(0));
+                rgba.push(component(5));
+                rgba.push(component(10));
+                // Black is transparent
+                rgba.push(if color == 0 { 0 } else { 0xff });
+            }
+        }
+
+        Some(rgba)
+    }
+
+    /// Export `save` in the MCS format: its directory frame followed by its data
+    pub fn export_mcs(&self, save: &Save) -> Vec<u8> {
+        let mut mcs = self.dir_frame(save.first_block()).to_vec();
+
+        // Export deleted saves as live ones
+        mcs[0] = BlockState::First.raw();
+        mcs[127] = checksum(&mcs[..127]);
+
+        for &block in &save.blocks {
+            mcs.extend_from_slice(self.block(block));
+        }
+
+        mcs
+    }
+
+    /// Import a save named `name` with contents `data`. Blocks are allocated from the free ones
+    /// first so that deleted saves can be recovered as long as possible. Returns the first block
+    /// of the new save.
+    pub fn import(&mut self, name: &str, data: &[u8]) -> Result<usize, String> {
+        if data.is_empty() || data.len() % BLOCK_SIZE != 0 || !data.starts_with(SAVE_MAGIC) {
+            return Err("Invalid save data".to_string());
+        }
+
+        if name.is_empty() || name.len() > NAME_LEN || !name.is_ascii() {
+            return Err(format!("Invalid save name '{}'", name));
+        }
+
+        if self.saves().iter().any(|s| !s.deleted && s.name == name) {
+            return Err(format!("Save '{}' already exists", name));
+        }
+
+        let needed = data.len() / BLOCK_SIZE;
+        let available = self.available_blocks();
+
+        if needed > available.len() {
+            return Err(format!(
+                "Not enough free blocks ({} needed, {} available)",
+                needed,
+                available.len()
+            ));
+        }
+
+        let blocks = &available[..needed];
+
+        for (i, &block) in blocks.iter().enumerate() {
+            let last = i == needed - 1;
+
+            let state = match i {
+                0 => BlockState::First,
+                _ if last => BlockState::Last,
+                _ => BlockState::Middle,
+            };
+
+            let next = if last {
+                NO_NEXT_BLOCK
+            } else {
+                blocks[i + 1] as u16 - 1
+            };
+
+            let frame = self.dir_frame_mut(block);
+
+            for b in frame.iter_mut() {
+                *b = 0;
+            }
+
+            frame[0] = state.raw();
+            frame[8..10].copy_from_slice(&next.to_le_bytes());
+
+            if i == 0 {
+                frame[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                frame[10..(10 + name.len())].copy_from_slice(name.as_bytes());
+            }
+
+            self.update_checksum(block);
+
+            self.block_mut(block)
+                .copy_from_slice(&data[(i * BLOCK_SIZE)..((i + 1) * BLOCK_SIZE)]);
+        }
+
+        Ok(blocks[0])
+    }
+
+    /// Import a save in the MCS format, as generated by `export_mcs`
+    pub fn import_mcs(&mut self, mcs: &[u8]) -> Result<usize, String> {
+        if mcs.len() <= FRAME_SIZE || mcs[0] != BlockState::First.raw() {
+            return Err("Invalid MCS file".to_string());
+        }
+
+        let name = c_string(&mcs[10..(10 + NAME_LEN)]);
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        self.import(&name, &mcs[FRAME_SIZE..])
+    }
+
+    /// Copy `save` from the card `source`, returns the first block of the copy
+    pub fn copy_save(&mut self, source: &Card, save: &Save) -> Result<usize, String> {
+        self.import_mcs(&source.export_mcs(save))
+    }
+
+    /// Delete the save starting at `first_block`. Like the BIOS we only change the state of the
+    /// blocks so that the save can be recovered with `undelete` until the blocks are reused.
+    pub fn delete(&mut self, first_block: usize) -> Result<(), String> {
+        if !self.is_data_block(first_block) || self.block_state(first_block) != BlockState::First {
+            return Err(format!("No save starts at block {}", first_block));
+        }
+
+        for block in self.chain(first_block) {
+            let state = self.block_state(block).deleted();
+
+            self.set_block_state(block, state);
+        }
+
+        Ok(())
+    }
+
+    /// Recover the deleted save starting at `first_block`
+    pub fn undelete(&mut self, first_block: usize) -> Result<(), String> {
+        if !self.is_data_block(first_block)
+            || self.block_state(first_block) != BlockState::DeletedFirst
+        {
+            return Err(format!("No deleted save starts at block {}", first_block));
+        }
+
+        let save = self.save(first_block);
+
+        if save.blocks.len() * BLOCK_SIZE != save.size as usize {
+            return Err(format!(
+                "Save '{}' has been partially overwritten",
+                save.name
+            ));
+        }
+
+        if self
+            .saves()
+            .iter()
+            .any(|s| !s.deleted && s.name == save.name)
+        {
+            return Err(format!("Save '{}' already exists", save.name));
+        }
+
+        for &block in &save.blocks {
+            let state = self.block_state(block).undeleted();
+
+            self.set_block_state(block, state);
+        }
+
+        Ok(())
+    }
+
+    /// Check the consistency of the filesystem and fix what can be fixed: bad checksums, broken
+    /// or looping block chains, bad save sizes and orphan blocks. Returns a description of every
+    /// fix.
+    pub fn repair(&mut self) -> Vec<String> {
+        let mut fixes = Vec::new();
+
+        if &self.memory[0..2] != b"MC" {
+            self.memory[0] = b'M';
+            self.memory[1] = b'C';
+            fixes.push("Fixed the header magic".to_string());
+        }
+
+        // Walk the chain of every live save, truncating them where they break
+        let mut owned = [false; DATA_BLOCKS + 1];
+
+        for block in 1..=DATA_BLOCKS {
+            if self.block_state(block) != BlockState::First {
+                continue;
+            }
+
+            let mut chain = self.chain(block);
+
+            // Blocks can't be shared between two saves
+            if let Some(pos) = chain.iter().position(|&b| owned[b]) {
+                chain.truncate(pos.max(1));
+            }
+
+            for &b in &chain {
+                owned[b] = true;
+            }
+
+            let last = *chain.last().unwrap();
+
+            let next = u16::from_le_bytes([self.dir_frame(last)[8], self.dir_frame(last)[9]]);
+
+            let last_state = if chain.len() == 1 {
+                BlockState::First
+            } else {
+                BlockState::Last
+            };
+
+            if next != NO_NEXT_BLOCK || self.block_state(last) != last_state {
+                let frame = self.dir_frame_mut(last);
+
+                frame[0] = last_state.raw();
+                frame[8..10].copy_from_slice(&NO_NEXT_BLOCK.to_le_bytes());
+                self.update_checksum(last);
+
+                fixes.push(format!("Truncated the broken save at block {}", block));
+            }
+
+            let size = (chain.len() * BLOCK_SIZE) as u32;
+            let frame = self.dir_frame_mut(block);
+
+            if frame[4..8] != size.to_le_bytes() {
+                frame[4..8].copy_from_slice(&size.to_le_bytes());
+                self.update_checksum(block);
+                fixes.push(format!("Fixed the size of the save at block {}", block));
+            }
+        }
+
+        // Blocks in use that don't belong to any save
+        for (block, &owned) in owned.iter().enumerate().skip(1) {
+            let state = self.block_state(block);
+            let orphan = state.is_used() && !owned;
+
+            if orphan || matches!(state, BlockState::Unknown(_)) {
+                self.free_block(block);
+                fixes.push(format!("Freed orphan block {}", block));
+            }
+        }
+
+        // The frames we've modified above already have a valid checksum
+        for frame in 0..=DATA_BLOCKS {
+            let f = self.frame(0, frame);
+
+            if checksum(&f[..127]) != f[127] {
+                self.update_checksum(frame);
+                fixes.push(format!("Fixed the checksum of directory frame {}", frame));
+            }
+        }
+
+        fixes
+    }
+
+    fn save(&self, first_block: usize) -> Save {
+        let dir = self.dir_frame(first_block);
+        let title_frame = self.frame(first_block, 0);
+
+        let name = String::from_utf8_lossy(c_string(&dir[10..(10 + NAME_LEN)])).into_owned();
+
+        let region = match name.get(0..2) {
+            Some("BA") => "America",
+            Some("BE") => "Europe",
+            Some("BI") => "Japan",
+            _ => "Unknown",
+        };
+
+        let icon_frames = if title_frame.starts_with(SAVE_MAGIC) {
+            match title_frame[2] {
+                0x11 => 1,
+                0x12 => 2,
+                0x13 => 3,
+                _ => 0,
+            }
+        } else {
+            0
+        };
+
+        Save {
+            blocks: self.chain(first_block),
+            product_code: name.get(2..12).unwrap_or("").to_string(),
+            region,
+            name,
+            title: decode_title(&title_frame[4..(4 + TITLE_LEN)]),
+            size: u32::from_le_bytes([dir[4], dir[5], dir[6], dir[7]]),
+            deleted: self.block_state(first_block).is_deleted(),
+            icon_frames,
+        }
+    }
+
+    /// Returns the blocks chained from `first`. Stops at the end of the chain or as soon as the
+    /// chain looks broken (loop, pointer out of range or block in an unexpected state).
+    fn chain(&self, first: usize) -> Vec<usize> {
+        let deleted = self.block_state(first).is_deleted();
+        let mut chain = vec![first];
+        let mut block = first;
+
+        loop {
+            let dir = self.dir_frame(block);
+            let next = u16::from_le_bytes([dir[8], dir[9]]);
+
+            if next == NO_NEXT_BLOCK {
+                break;
+            }
+
+            let next = next as usize + 1;
+
+            if !self.is_data_block(next) || chain.contains(&next) {
+                break;
+            }
+
+            let state = self.block_state(next);
+            let state = if deleted { state.undeleted() } else { state };
+
+            if state != BlockState::Middle && state != BlockState::Last {
+                break;
+            }
+
+            chain.push(next);
+
+            if state == BlockState::Last {
+                break;
+            }
+
+            block = next;
+        }
+
+        chain
+    }
+
+    /// Returns the blocks that can be allocated to a new save: the free ones followed by the ones
+    /// from deleted saves
+    fn available_blocks(&self) -> Vec<usize> {
+        let free = (1..=DATA_BLOCKS).filter(|&b| self.block_state(b) == BlockState::Free);
+        let deleted = (1..=DATA_BLOCKS).filter(|&b| self.block_state(b).is_deleted());
+
+        free.chain(deleted).collect()
+    }
+
+    fn is_data_block(&self, block: usize) -> bool {
+        (1..=DATA_BLOCKS).contains(&block)
+    }
+
+    fn block_state(&self, block: usize) -> BlockState {
+        BlockState::from_raw(self.dir_frame(block)[0])
+    }
+
+    fn set_block_state(&mut self, block: usize, state: BlockState) {
+        self.dir_frame_mut(block)[0] = state.raw();
+        self.update_checksum(block);
+    }
+
+    fn free_block(&mut self, block: usize) {
+        let frame = self.dir_frame_mut(block);
+
+        for b in frame.iter_mut() {
+            *b = 0;
+        }
+
+        frame[0] = BlockState::Free.raw();
+        frame[8..10].copy_from_slice(&NO_NEXT_BLOCK.to_le_bytes());
+
+        self.update_checksum(block);
+    }
+
+    /// Recompute the checksum of frame `frame` of block 0
+    fn update_checksum(&mut self, frame: usize) {
+        let frame = self.frame_mut(0, frame);
+
+        frame[127] = checksum(&frame[..127]);
+    }
+
+    fn block(&self, block: usize) -> &[u8] {
+        &self.memory[(block * BLOCK_SIZE)..((block + 1) * BLOCK_SIZE)]
+    }
+
+    fn block_mut(&mut self, block: usize) -> &mut [u8] {
+        &mut self.memory[(block * BLOCK_SIZE)..((block + 1) * BLOCK_SIZE)]
+    }
+
+    fn frame(&self, block: usize, frame: usize) -> &[u8] {
+        &self.block(block)[(frame * FRAME_SIZE)..((frame + 1) * FRAME_SIZE)]
+    }
+
+    fn frame_mut(&mut self, block: usize, frame: usize) -> &mut [u8] {
+        &mut self.block_mut(block)[(frame * FRAME_SIZE)..((frame + 1) * FRAME_SIZE)]
+    }
+
+    /// Directory frame of data block `block`
+    fn dir_frame(&self, block: usize) -> &[u8] {
+        self.frame(0, block)
+    }
+
+    fn dir_frame_mut(&mut self, block: usize) -> &mut [u8] {
+        self.frame_mut(0, block)
+    }
+}
+
+/// Basic 8bit XOR checksum used by the memory card
+fn checksum(d: &[u8]) -> u8 {
+    d.iter().fold(0, |c, b| c ^ b)
+}
+
+/// Returns `s` up to its first NUL byte
+fn c_string(s: &[u8]) -> &[u8] {
+    let len = s.iter().position(|&b| b == 0).unwrap_or(s.len());
+
+    &s[..len]
+}
+
+/// Decode a save title. Titles are generally made of full-width Shift-JIS characters, we convert
+/// the full-width ASCII range back to regular ASCII to make them easier to read.
+fn decode_title(title: &[u8]) -> String {
+    let (title, _, _) = encoding_rs::SHIFT_JIS.decode(c_string(title));
+
+    title
+        .chars()
+        .map(|c| match c {
+            '\u{3000}' => ' ',
+            '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xff01 + 0x21).unwrap_or(c),
+            _ => c,
+        })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a save of `blocks` blocks with a one-frame icon
+    fn save_data(blocks: usize, title: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; blocks * BLOCK_SIZE];
+
+        data[0..2].copy_from_slice(SAVE_MAGIC);
+        data[2] = 0x11;
+        data[3] = blocks as u8;
+        data[4..(4 + title.len())].copy_from_slice(title);
+
+        // CLUT: color 1 is pure red
+        data[0x62..0x64].copy_from_slice(&0x001fu16.to_le_bytes());
+        // First pixel uses color 1, the second one color 0
+        data[FRAME_SIZE] = 0x01;
+
+        data
+    }
+
+    #[test]
+    fn list_and_icon() {
+        let mut card = Card::formatted();
+
+        // "ＡＢ１" in Shift-JIS
+        let title = [0x82, 0x60, 0x82, 0x61, 0x82, 0x50];
+
+        let first = card
+            .import("BASCUS-94163GAME", &save_data(2, &title))
+            .unwrap();
+        assert_eq!(first, 1);
+        assert_eq!(card.free_blocks(), 13);
+
+        let saves = card.saves();
+        assert_eq!(saves.len(), 1);
+
+        let save = &saves[0];
+        assert_eq!(save.blocks, vec![1, 2]);
+        assert_eq!(save.name, "BASCUS-94163GAME");
+        assert_eq!(save.product_code, "SCUS-94163");
+        assert_eq!(save.region, "America");
+        assert_eq!(save.title, "AB1");
+        assert_eq!(save.size, 2 * BLOCK_SIZE as u32);
+        assert!(!save.deleted);
+
+        let icon = card.icon(save, 0).unwrap();
+        assert_eq!(icon.len(), ICON_SIZE * ICON_SIZE * 4);
+        assert_eq!(&icon[0..8], &[0xff, 0, 0, 0xff, 0, 0, 0, 0]);
+        assert!(card.icon(save, 1).is_none());
+    }
+
+    #[test]
+    fn delete_undelete_and_reuse() {
+        let mut card = Card::formatted();
+
+        let a = card.import("BESLES-00001A", &save_data(3, b"A")).unwrap();
+        let b = card.import("BESLES-00002B", &save_data(1, b"B")).unwrap();
+        assert_eq!((a, b), (1, 4));
+
+        card.delete(a).unwrap();
+        assert!(card.delete(a).is_err());
+        assert!(card.saves()[0].deleted);
+        assert_eq!(card.free_blocks(), 14);
+
+        card.undelete(a).unwrap();
+        assert!(!card.saves()[0].deleted);
+        assert_eq!(card.free_blocks(), 11);
+
+        // Fill the card, the deleted blocks are only reused once the free ones are exhausted
+        card.delete(a).unwrap();
+        let c = card.import("BESLES-00003C", &save_data(12, b"C")).unwrap();
+        assert_eq!(c, 5);
+        // The first block of the deleted save is gone, it can't be listed anymore
+        let saves = card.saves();
+        assert_eq!(saves.len(), 2);
+        assert_eq!(
+            saves[1].blocks,
+            vec![5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1]
+        );
+        assert!(card.undelete(a).is_err());
+
+        assert!(card.import("BESLES-00004D", &save_data(3, b"D")).is_err());
+        assert!(card.repair().is_empty());
+    }
+
+    #[test]
+    fn mcs_round_trip() {
+        let mut card = Card::formatted();
+        card.import("BISLPS-00001SAVE", &save_data(2, b"X"))
+            .unwrap();
+
+        let save = &card.saves()[0];
+        let mcs = card.export_mcs(save);
+        assert_eq!(mcs.len(), FRAME_SIZE + 2 * BLOCK_SIZE);
+
+        let mut other = Card::formatted();
+        other
+            .import("BISLPS-00002SAVE", &save_data(1, b"Y"))
+            .unwrap();
+        assert_eq!(other.import_mcs(&mcs), Ok(2));
+        assert_eq!(other.saves()[1].name, "BISLPS-00001SAVE");
+        assert_eq!(other.block(2), card.block(1));
+
+        // Already there
+        assert!(other.import_mcs(&mcs).is_err());
+    }
+
+    #[test]
+    fn repair() {
+        let mut card = Card::formatted();
+        card.import("BASLUS-00001A", &save_data(3, b"A")).unwrap();
+
+        // Break the chain after the second block and leave the third one orphaned
+        card.dir_frame_mut(2)[8] = 0x20;
+        card.update_checksum(2);
+        // Corrupt a checksum
+        card.dir_frame_mut(10)[127] ^= 1;
+
+        let fixes = card.repair();
+        assert_eq!(fixes.len(), 4);
+
+        let save = &card.saves()[0];
+        assert_eq!(save.blocks, vec![1, 2]);
+        assert_eq!(save.size, 2 * BLOCK_SIZE as u32);
+        assert_eq!(card.free_blocks(), 13);
+        assert!(card.repair().is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+//! Shared memory card filtered by game
+//!
+//! The console only sees the saves of the current game, the writes are merged back into the
+//! complete card when it's dumped. That way a single card can be shared by every game without
+//! running out of blocks or having to scroll through the saves of every other game.
+
+use super::filesystem::{Card, Save};
+
+pub struct Filter {
+    /// Serial number of the game, as given to `new`
+    game: String,
+    /// Serial numbers of the current game, compared to the product code of the saves
+    serials: Vec<String>,
+    /// Complete contents of the card, including the saves of the other games
+    master: Card,
+}
+
+impl Filter {
+    /// Build a filter for the game with the serial numbers `serials`. Returns the filter along
+    /// with the card the console should see.
+    pub fn new(serials: &[String], master: Card) -> (Filter, Card) {
+        let game = serials.first().cloned().unwrap_or_default();
+        let serials: Vec<String> = serials.iter().map(|s| normalize(s)).collect();
+
+        let mut view = Card::formatted();
+
+        for save in master.saves() {
+            if save.deleted || !is_game_save(&serials, &save) {
+                continue;
+            }
+
+            if let Err(e) = view.copy_save(&master, &save) {
+                warn!(
+                    "Can't copy save '{}' to the filtered card: {}",
+                    save.name, e
+                );
+            }
+        }
+
+        let filter = Filter {
+            game,
+            serials,
+            master,
+        };
+
+        (filter, view)
+    }
+
+    pub fn game(&self) -> &str {
+        &self.game
+    }
+
+    /// Replace the saves of the game on the master card with the ones on `view` and return the
+    /// updated master card. On error the master card is left untouched.
+    pub fn merge(&mut self, view: &Card) -> Result<&Card, String> {
+        let mut master = self.master.clone();
+
+        for save in master.saves() {
+            if !save.deleted && is_game_save(&self.serials, &save) {
+                master.delete(save.first_block())?;
+            }
+        }
+
+        for save in view.saves() {
+            if !save.deleted {
+                master.copy_save(view, &save)?;
+            }
+        }
+
+        self.master = master;
+
+        Ok(&self.master)
+    }
+}
+
+/// Returns true if `save` belongs to the game with one of the (normalized) serial numbers
+/// `serials`
+fn is_game_save(serials: &[String], save: &Save) -> bool {
+    serials.contains(&normalize(&save.product_code))
+}
+
+/// Some games don't put a dash in the product code of their saves ("SLUS_005.94", "SLUS00594")
+/// so we only compare the letters and digits
+fn normalize(serial: &str) -> String {
+    serial
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::filesystem::BLOCK_SIZE;
+    use super::*;
+
+    fn save(blocks: usize, tag: u8) -> Vec<u8> {
+        let mut save = vec![tag; blocks * BLOCK_SIZE];
+        save[0..2].copy_from_slice(b"SC");
+        save
+    }
+
+    #[test]
+    fn filter_and_merge() {
+        let mut master = Card::formatted();
+        master.import("BASCUS-94163FF7", &save(1, 1)).unwrap();
+        master.import("BASLUS-00594MGS", &save(2, 2)).unwrap();
+        master.import("BASCUS-94163FF7B", &save(1, 3)).unwrap();
+
+        let serials = ["SCUS-94163".to_string(), "SCUS-94164".to_string()];
+        let (mut filter, mut view) = Filter::new(&serials, master);
+
+        let names: Vec<String> = view.saves().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["BASCUS-94163FF7", "BASCUS-94163FF7B"]);
+
+        // The game deletes a save and creates a new one
+        view.delete(1).unwrap();
+        view.import("BASCUS-94164FF7C", &save(3, 4)).unwrap();
+
+        let merged = filter.merge(&view).unwrap();
+        let mut names: Vec<String> = merged
+            .saves()
+            .into_iter()
+            .filter(|s| !s.deleted)
+            .map(|s| s.name)
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["BASCUS-94163FF7B", "BASCUS-94164FF7C", "BASLUS-00594MGS"]
+        );
+        assert_eq!(merged.free_blocks(), 15 - 6);
+
+        // Not enough room left on the master card
+        view.import("BASCUS-94163FF7D", &save(10, 5)).unwrap();
+        assert!(filter.merge(&view).is_err());
+        assert_eq!(filter.master.free_blocks(), 15 - 6);
+    }
+}
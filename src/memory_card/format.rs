@@ -0,0 +1,344 @@
+//! Memory card image formats
+//!
+//! Besides our own raw images we can import the formats used by other emulators and save
+//! management tools. Complete card images are written back in their original format when we know
+//! how to, single saves are installed on a freshly formatted card. The format is detected from the
+//! contents of the file, not its extension.
+
+use super::filesystem::Card;
+use crate::box_array::BoxArray;
+use crate::psx::pad_memcard::devices::memory_card::{BLOCK_SIZE, FLASH_SIZE, SECTOR_SIZE};
+use std::io;
+
+/// Memory card image and save file formats
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// Raw dump of the flash (.mcr, .mcd, .psm...)
+    Raw,
+    /// DexDrive image (.gme)
+    DexDrive,
+    /// Connectix Virtual Game Station image (.vgs, .mem)
+    Vgs,
+    /// PSP/PS3 virtual memory card (.vmp). The header contains a signature made with Sony's keys,
+    /// we can't write it back.
+    Vmp,
+    /// Single save preceded by its directory frame (.mcs)
+    Mcs,
+    /// PS3 single save (.psv)
+    Psv,
+    /// Action Replay/GameShark/Xploder single save (.psx, .mcb)
+    ActionReplay,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Raw => "raw",
+            Format::DexDrive => "DexDrive",
+            Format::Vgs => "Virtual Game Station",
+            Format::Vmp => "PSP virtual memory card",
+            Format::Mcs => "MCS single save",
+            Format::Psv => "PSV single save",
+            Format::ActionReplay => "Action Replay single save",
+        }
+    }
+}
+
+/// Format of a memory card file along with the parts of the file that aren't card data, so that
+/// we can write it back
+pub struct Container {
+    format: Format,
+    /// Everything preceding the card data in the original file
+    header: Vec<u8>,
+}
+
+impl Container {
+    /// Container for our own raw images
+    pub fn raw() -> Container {
+        Container {
+            format: Format::Raw,
+            header: Vec::new(),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Detect the format of `data` and extract the memory card image. The image still needs to be
+    /// validated by the caller.
+    pub fn import(data: &[u8]) -> io::Result<(Container, BoxArray<u8, FLASH_SIZE>)> {
+        let card = |format, header_len| {
+            if data.len() != header_len + FLASH_SIZE {
+                let m = format!(
+                    "Invalid {} image size (expected {}B, got {}B)",
+                    Format::name(format),
+                    header_len + FLASH_SIZE,
+                    data.len()
+                );
+                return Err(invalid(&m));
+            }
+
+            let (header, memory) = data.split_at(header_len);
+
+            let container = Container {
+                format,
+                header: header.to_vec(),
+            };
+
+            Ok((container, BoxArray::from_vec(memory.to_vec())))
+        };
+
+        if data.starts_with(DEXDRIVE_MAGIC) {
+            return card(Format::DexDrive, DEXDRIVE_HEADER_SIZE);
+        }
+
+        if data.starts_with(VGS_MAGIC) {
+            return card(Format::Vgs, VGS_HEADER_SIZE);
+        }
+
+        if data.starts_with(VMP_MAGIC) {
+            return card(Format::Vmp, VMP_HEADER_SIZE);
+        }
+
+        if data.starts_with(PSV_MAGIC) && is_save(data, PSV_HEADER_SIZE) {
+            if data[PSV_TYPE_OFFSET] != 1 {
+                return Err(invalid("Not a PlayStation save"));
+            }
+
+            let name = &data[PSV_NAME_OFFSET..(PSV_NAME_OFFSET + SAVE_NAME_LEN)];
+
+            return single_save(Format::Psv, name, &data[PSV_HEADER_SIZE..]);
+        }
+
+        if data.len() == FLASH_SIZE {
+            return card(Format::Raw, 0);
+        }
+
+        // The directory frame of the first block of the save
+        if is_save(data, SECTOR_SIZE) && data[0] == 0x51 {
+            let name = &data[10..(10 + SAVE_NAME_LEN)];
+
+            return single_save(Format::Mcs, name, &data[SECTOR_SIZE..]);
+        }
+
+        if is_save(data, ACTION_REPLAY_HEADER_SIZE) {
+            let name = &data[..SAVE_NAME_LEN];
+
+            return single_save(
+                Format::ActionReplay,
+                name,
+                &data[ACTION_REPLAY_HEADER_SIZE..],
+            );
+        }
+
+        Err(invalid("Unknown memory card format"))
+    }
+
+    /// Encode `memory` in the container's format. Returns `None` if we can't write this format.
+    pub fn encode(&self, memory: &[u8; FLASH_SIZE]) -> Option<Vec<u8>> {
+        let mut header = match self.format {
+            Format::Raw | Format::Vgs => self.header.clone(),
+            Format::DexDrive => {
+                let mut header = self.header.clone();
+
+                // The DexDrive header contains a copy of the state and next block pointer of each
+                // directory frame
+                for i in 0..15 {
+                    let frame = &memory[((i + 1) * SECTOR_SIZE)..];
+
+                    header[22 + i] = frame[0];
+                    header[38 + i] = frame[8];
+                }
+
+                header
+            }
+            Format::Vmp | Format::Mcs | Format::Psv | Format::ActionReplay => return None,
+        };
+
+        header.extend_from_slice(memory);
+
+        Some(header)
+    }
+}
+
+/// Returns true if `data` looks like a single save preceded by a header of `header_len` bytes
+fn is_save(data: &[u8], header_len: usize) -> bool {
+    data.len() > header_len
+        && (data.len() - header_len) % BLOCK_SIZE == 0
+        && data[header_len..].starts_with(SAVE_MAGIC)
+}
+
+/// Create a freshly formatted card containing the single save `save` named `name`
+fn single_save(
+    format: Format,
+    name: &[u8],
+    save: &[u8],
+) -> io::Result<(Container, BoxArray<u8, FLASH_SIZE>)> {
+    // The name is NUL-terminated in the directory frame
+    let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    let name = String::from_utf8_lossy(&name[..name_len]);
+
+    let mut card = Card::formatted();
+
+    card.import(&name, save).map_err(|e| invalid(&e))?;
+
+    let container = Container {
+        format,
+        header: Vec::new(),
+    };
+
+    Ok((container, BoxArray::from_vec(card.into_memory())))
+}
+
+fn invalid(m: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, m)
+}
+
+/// Magic at the start of the first frame of every save
+const SAVE_MAGIC: &[u8] = b"SC";
+
+/// Maximum length of a save's name
+const SAVE_NAME_LEN: usize = 20;
+
+const DEXDRIVE_MAGIC: &[u8] = b"123-456-STD";
+/// The DexDrive header contains 64 bytes of metadata followed by a 256 byte comment for each block
+const DEXDRIVE_HEADER_SIZE: usize = 64 + 15 * 256;
+
+const VGS_MAGIC: &[u8] = b"VgsM";
+const VGS_HEADER_SIZE: usize = 64;
+
+const VMP_MAGIC: &[u8] = b"\0PMV";
+const VMP_HEADER_SIZE: usize = 0x80;
+
+const PSV_MAGIC: &[u8] = b"\0VSP";
+const PSV_HEADER_SIZE: usize = 0x84;
+/// Set to 1 for PlayStation saves, 2 for PlayStation 2 saves
+const PSV_TYPE_OFFSET: usize = 0x3c;
+const PSV_NAME_OFFSET: usize = 0x64;
+
+/// The Action Replay header starts with the save name, followed by its title
+const ACTION_REPLAY_HEADER_SIZE: usize = 54;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psx::pad_memcard::devices::memory_card::MemoryCard;
+    use crate::psx::pad_memcard::devices::DeviceInterface;
+
+    fn save(blocks: usize) -> Vec<u8> {
+        let mut save: Vec<u8> = (0..(blocks * BLOCK_SIZE)).map(|b| b as u8).collect();
+        save[0..2].copy_from_slice(SAVE_MAGIC);
+        save
+    }
+
+    fn raw_card() -> Vec<u8> {
+        let (_, memory) = single_save(Format::Raw, b"BASCUS-94163GAME", &save(2)).unwrap();
+
+        memory.to_vec()
+    }
+
+    #[test]
+    fn dexdrive_round_trip() {
+        let card = raw_card();
+
+        let mut header = vec![0; DEXDRIVE_HEADER_SIZE];
+        header[..DEXDRIVE_MAGIC.len()].copy_from_slice(DEXDRIVE_MAGIC);
+        // Block comment
+        header[64..68].copy_from_slice(b"test");
+
+        let mut file = header.clone();
+        file.extend_from_slice(&card);
+
+        let (container, memory) = Container::import(&file).unwrap();
+        assert_eq!(container.format(), Format::DexDrive);
+        assert_eq!(&memory[..], &card[..]);
+
+        let encoded = container.encode(&memory).unwrap();
+        assert_eq!(&encoded[64..68], b"test");
+        assert_eq!(encoded[22], 0x51);
+        assert_eq!(encoded[23], 0x53);
+        assert_eq!(encoded[24], 0xa0);
+        assert_eq!(encoded[38], 0x01);
+        assert_eq!(&encoded[DEXDRIVE_HEADER_SIZE..], &card[..]);
+    }
+
+    #[test]
+    fn raw_and_vmp() {
+        let card = raw_card();
+
+        let (container, _) = Container::import(&card).unwrap();
+        assert_eq!(container.format(), Format::Raw);
+
+        let mut vmp = vec![0; VMP_HEADER_SIZE];
+        vmp[..4].copy_from_slice(VMP_MAGIC);
+        vmp.extend_from_slice(&card);
+
+        let (container, memory) = Container::import(&vmp).unwrap();
+        assert_eq!(container.format(), Format::Vmp);
+        assert_eq!(&memory[..], &card[..]);
+        // Can't sign the image
+        assert!(container.encode(&memory).is_none());
+
+        // Truncated image
+        assert!(Container::import(&vmp[..(vmp.len() - 1)]).is_err());
+    }
+
+    #[test]
+    fn single_saves() {
+        let save = save(3);
+
+        let mut ar = b"BESLES-01234SAVE".to_vec();
+        ar.resize(ACTION_REPLAY_HEADER_SIZE, 0);
+        ar.extend_from_slice(&save);
+
+        let (container, memory) = Container::import(&ar).unwrap();
+        assert_eq!(container.format(), Format::ActionReplay);
+
+        let card = MemoryCard::new_with_memory(memory);
+        assert!(card.is_format_valid());
+
+        let memory = card.get_memory().unwrap();
+
+        // Directory
+        let frame = &memory[SECTOR_SIZE..(2 * SECTOR_SIZE)];
+        assert_eq!(frame[0], 0x51);
+        assert_eq!(&frame[4..8], &(3 * BLOCK_SIZE as u32).to_le_bytes());
+        assert_eq!(&frame[10..27], b"BESLES-01234SAVE\0");
+        assert_eq!(memory[2 * SECTOR_SIZE], 0x52);
+        assert_eq!(memory[3 * SECTOR_SIZE], 0x53);
+        assert_eq!(
+            &memory[(3 * SECTOR_SIZE + 8)..(3 * SECTOR_SIZE + 10)],
+            &[0xff, 0xff]
+        );
+
+        // Data
+        assert_eq!(&memory[BLOCK_SIZE..(4 * BLOCK_SIZE)], &save[..]);
+
+        // The same save in the MCS format
+        let mut mcs = memory[SECTOR_SIZE..(2 * SECTOR_SIZE)].to_vec();
+        mcs.extend_from_slice(&save);
+
+        let (container, mcs_memory) = Container::import(&mcs).unwrap();
+        assert_eq!(container.format(), Format::Mcs);
+        assert_eq!(&mcs_memory[..], &memory[..]);
+
+        // PS2 saves aren't supported
+        let mut psv = vec![0; PSV_HEADER_SIZE];
+        psv[..4].copy_from_slice(PSV_MAGIC);
+        psv[PSV_NAME_OFFSET..(PSV_NAME_OFFSET + 16)].copy_from_slice(b"BESLES-01234SAVE");
+        psv[PSV_TYPE_OFFSET] = 2;
+        psv.extend_from_slice(&save);
+        assert!(Container::import(&psv).is_err());
+
+        psv[PSV_TYPE_OFFSET] = 1;
+        let (container, _) = Container::import(&psv).unwrap();
+        assert_eq!(container.format(), Format::Psv);
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(Container::import(&[0; 1000]).is_err());
+        assert!(Container::import(&[]).is_err());
+    }
+}
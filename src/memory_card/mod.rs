@@ -0,0 +1,522 @@
+//! Handling of Memory Card saves on disc
+
+pub mod backup;
+pub mod filesystem;
+mod filter;
+mod format;
+
+use crate::box_array::BoxArray;
+use crate::libretro;
+use crate::psx::pad_memcard::devices::memory_card::{MemoryCard, FLASH_SIZE};
+use crate::psx::pad_memcard::devices::DeviceInterface;
+use filesystem::Card;
+use filter::Filter;
+use format::{Container, Format};
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Structure holding the state of the Memory Card image on disc in order to keep it in sync with
+/// the emulated one.
+pub struct MemoryCardFile {
+    /// Path to the Memory Card image
+    file_path: PathBuf,
+    /// Counter used to figure out if we need to flush the MemoryCard to the disc yet. Contains
+    /// "None" if no writes have been detected since the last flush.
+    write_pending_since: Option<u8>,
+    /// Last write counter received from the memory card. Used to detect writes.
+    last_write_counter: u32,
+    /// Format of the file, used to write it back
+    container: Container,
+    /// Set if the console only sees the saves of the current game
+    filter: Option<Filter>,
+    /// Number of backups to keep, 0 to disable backups
+    backups: usize,
+}
+
+impl MemoryCardFile {
+    /// Attempt to load a Memory Card image from `file_path`. If the file does not exist a freshly
+    /// formatted Memory Card image will be created instead.
+    ///
+    /// A valid card that doesn't match its checksum was most likely modified by another program,
+    /// it's accepted as is and its checksum is updated. If `file_path` is unreadable and has a
+    /// valid backup it's moved out of the way and replaced by an empty card, the user can then
+    /// restore the backup with the memory card manager. Without a backup this function will return
+    /// an error if `file_path` contains an unknown or unsupported file format in order to avoid
+    /// data loss. See the `format` module for the supported formats.
+    pub fn load_or_create(file_path: &Path) -> io::Result<(MemoryCardFile, MemoryCard)> {
+        let e = match MemoryCardFile::load(file_path) {
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => e,
+            res => return res,
+        };
+
+        let (index, backup) = match backup::newest_valid(file_path) {
+            Some(b) => b,
+            None => return Err(e),
+        };
+
+        error!(
+            "Memory Card file '{}' is unreadable ({}), newest valid backup is '{}'",
+            file_path.display(),
+            e,
+            backup.path.display()
+        );
+
+        let corrupt = move_aside(file_path, "corrupt")?;
+        warn!(
+            "Unreadable Memory Card file moved to '{}'",
+            corrupt.display()
+        );
+
+        let m = format!(
+            "Memory card unreadable, backup #{} ({}) can be restored from the card manager",
+            index,
+            backup.age()
+        );
+        libretro::set_message(5000, &m);
+
+        MemoryCardFile::load(file_path)
+    }
+
+    /// Load the Memory Card file at `file_path`
+    fn load(file_path: &Path) -> io::Result<(MemoryCardFile, MemoryCard)> {
+        let mut mcf = MemoryCardFile {
+            file_path: file_path.into(),
+            write_pending_since: None,
+            last_write_counter: 0,
+            container: Container::raw(),
+            filter: None,
+            backups: 0,
+        };
+
+        let mut file = match File::open(file_path) {
+            Ok(f) => f,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::NotFound {
+                    // All is good, it just means that the file doesn't exist yet, we can start
+                    // with an fresh memory card and save it when we need to
+                    info!(
+                        "Memory Card file '{}' doesn't appear to exist, using an empty image",
+                        file_path.display()
+                    );
+                    return Ok((mcf, MemoryCard::new_formatted()));
+                } else {
+                    // We can't seem to access this file
+                    return Err(e);
+                }
+            }
+        };
+
+        let metadata = file.metadata()?;
+
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a file!"));
+        }
+
+        // All the formats we support are only slightly larger than the card itself
+        if metadata.len() > MAX_FILE_SIZE {
+            let msg = format!("Memory card file too large ({}B)", metadata.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        let mut data = Vec::new();
+
+        file.read_to_end(&mut data)?;
+
+        let (container, memory) = Container::import(&data)?;
+
+        if container.format() != Format::Raw {
+            info!(
+                "Memory Card file '{}' uses the {} format",
+                file_path.display(),
+                container.format().name()
+            );
+        }
+
+        mcf.container = container;
+
+        let card = MemoryCard::new_with_memory(memory);
+
+        // Let's add one more test to see if this looks like a proper memory card image
+        if !card.is_format_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unsupported or broken memory card format",
+            ));
+        }
+
+        if !backup::checksum_matches(file_path, &data) {
+            warn!(
+                "Memory Card file '{}' has been modified outside of the emulator",
+                file_path.display()
+            );
+            libretro::set_message(3000, "Memory card modified outside of the emulator");
+
+            if let Err(e) = backup::write_checksum(file_path, &data) {
+                warn!(
+                    "Can't update the checksum of Memory Card file '{}': {}",
+                    file_path.display(),
+                    e
+                );
+            }
+        }
+
+        mcf.last_write_counter = card.write_counter();
+
+        Ok((mcf, card))
+    }
+
+    /// Like `load_or_create` but the returned card only contains the saves of the game with the
+    /// serial numbers `serials`. The saves are merged back into the complete card when it's
+    /// dumped.
+    pub fn load_filtered(
+        file_path: &Path,
+        serials: &[String],
+    ) -> io::Result<(MemoryCardFile, MemoryCard)> {
+        let (mut mcf, mc) = MemoryCardFile::load_or_create(file_path)?;
+
+        let memory = mc.get_memory().expect("Memory card without memory");
+        let master = Card::new(memory.to_vec()).expect("Bad memory card size");
+
+        let (filter, view) = Filter::new(serials, master);
+
+        info!(
+            "Memory Card '{}' filtered for {}: {} saves",
+            file_path.display(),
+            serials.join(", "),
+            view.saves().len()
+        );
+
+        let mc = MemoryCard::new_with_memory(BoxArray::from_vec(view.into_memory()));
+
+        mcf.last_write_counter = mc.write_counter();
+        mcf.filter = Some(filter);
+
+        Ok((mcf, mc))
+    }
+
+    /// Allocates a dummy MemoryCardFile that won't do anything
+    pub fn dummy() -> MemoryCardFile {
+        MemoryCardFile {
+            file_path: PathBuf::new(),
+            write_pending_since: None,
+            last_write_counter: 0,
+            container: Container::raw(),
+            filter: None,
+            backups: 0,
+        }
+    }
+
+    /// Set the number of backups to keep, 0 disables backups
+    pub fn set_backups(&mut self, backups: usize) {
+        self.backups = backups;
+    }
+
+    /// Returns the backups of this Memory Card, newest first
+    pub fn list_backups(&self) -> Vec<backup::Backup> {
+        if self.file_path.as_os_str().is_empty() {
+            return Vec::new();
+        }
+
+        backup::list(&self.file_path)
+    }
+
+    /// Replace the Memory Card file with `backup`. The current contents of `mc` are written (and
+    /// backed up) first so that the restoration can be undone. The Memory Card has to be reloaded
+    /// afterwards.
+    pub fn restore_backup(
+        &mut self,
+        mc: &dyn DeviceInterface,
+        backup: &backup::Backup,
+    ) -> io::Result<()> {
+        // Read it first, the backup could be rotated out by the dump
+        let data = backup.read()?;
+
+        self.force_dump(mc);
+
+        backup::write_card(&self.file_path, &data)?;
+
+        info!(
+            "Memory Card '{}' restored from '{}'",
+            self.file_path.display(),
+            backup.path.display()
+        );
+        self.write_pending_since = None;
+
+        Ok(())
+    }
+
+    /// Return the path of the underlying file used to store the Memory Card image
+    pub fn path(&self) -> &Path {
+        &self.file_path
+    }
+
+    /// Check if the memory card contents need to be backed up. Should be called once per frame.
+    pub fn maybe_dump(&mut self, mc: &dyn DeviceInterface) {
+        let new_write_counter = mc.write_counter();
+
+        let new_write = new_write_counter != self.last_write_counter;
+
+        if new_write {
+            self.write_pending_since = Some(0);
+            self.last_write_counter = new_write_counter;
+        } else {
+            // No write since last time
+            self.write_pending_since = self.write_pending_since.map(|n| n + 1);
+        }
+
+        // XXX this algorithm has one potential weakness: if some game writes *continuously* to the
+        // memory card without a break of at least `WRITE_FLUSH_FRAME` frames, we'll never flush
+        // the Memory Card to disk.
+        //
+        // For old-school consoles that use battery-backed RAM for saves (like the Game Boy for
+        // instance) that's a genuine problem because some games use the region as a RAM extension
+        // and write to it continuously, so you have to force a flush from time to time.
+        //
+        // PlayStation Memory cards can't really be used that way however, they're super slow to
+        // access and would probably deteriorate fairly quick if they were written all the time, so
+        // it's unlikely to be a problem.
+        //
+        // Besides, we should call `force_dump` when we're done with the card, so as long as we
+        // don't crash we should be good.
+        if let Some(last_write) = self.write_pending_since {
+            if last_write >= WRITE_FLUSH_FRAME {
+                // We have a write pending and we haven't gotten new writes in `WRITE_FLUSH_FRAME`
+                // frames, commit to disk
+                self.dump(mc);
+            }
+        }
+    }
+
+    /// Like `maybe_dump` but never postpone a Memory Card dump if one is pending. Can be used
+    /// before quitting the emulator or changing memory cards.
+    pub fn force_dump(&mut self, mc: &dyn DeviceInterface) {
+        self.maybe_dump(mc);
+
+        if self.write_pending_since.is_some() {
+            // Still dirty, force dump
+            self.dump(mc);
+        }
+    }
+
+    /// Write the memory card to disk right away. Used when the contents of the card have been
+    /// modified outside of the emulated console, for instance through the memory card manager.
+    pub fn save_modified(&mut self, mc: &dyn DeviceInterface) {
+        self.last_write_counter = mc.write_counter();
+        self.dump(mc);
+    }
+
+    /// Dump the memory card to disk if a write is pending
+    fn dump(&mut self, mc: &dyn DeviceInterface) {
+        let memory = match mc.get_memory() {
+            Some(m) => m,
+            // That shouldn't happen, probably?
+            None => {
+                warn!("Attempting to flush a Memory Card without memory...");
+                return;
+            }
+        };
+
+        if self.file_path.as_os_str().is_empty() {
+            // This is a dummy writer. We probably shouldn't end up here.
+            warn!("Attempt to dump to a dummy Memory Card file");
+            return;
+        }
+
+        let merged;
+        let memory: &[u8; FLASH_SIZE] = match self.filter.as_mut() {
+            None => memory,
+            Some(filter) => {
+                let view = Card::new(memory.to_vec()).expect("Bad memory card size");
+
+                match filter.merge(&view) {
+                    Ok(master) => {
+                        merged = master.memory().to_vec();
+                        merged[..].try_into().expect("Bad memory card size")
+                    }
+                    Err(e) => {
+                        error!(
+                            "Can't merge the saves into memory card '{}': {}",
+                            self.file_path.display(),
+                            e
+                        );
+                        self.dump_overflow(memory);
+                        return;
+                    }
+                }
+            }
+        };
+
+        let data = match self.container.encode(memory) {
+            Some(d) => d,
+            None => {
+                // We can't write this format back, move the original file out of the way and
+                // replace it with a raw image
+                match move_aside(&self.file_path, "orig") {
+                    Ok(backup) => {
+                        warn!(
+                            "Can't write {} memory cards, original file moved to '{}'",
+                            self.container.format().name(),
+                            backup.display()
+                        );
+                        libretro::set_message(3000, "Memory card converted to the raw format");
+                    }
+                    Err(e) => {
+                        error!(
+                            "Can't move memory card file '{}' out of the way: {}",
+                            self.file_path.display(),
+                            e
+                        );
+                        libretro::set_message(3000, "Can't save memory card to disk!");
+                        return;
+                    }
+                }
+
+                self.container = Container::raw();
+
+                memory.to_vec()
+            }
+        };
+
+        if let Err(e) = backup::write_card(&self.file_path, &data) {
+            // This is bad, we can't write the memory card file
+            error!(
+                "Can't write memory card file '{}': {}",
+                self.file_path.display(),
+                e
+            );
+            // Write a message on screen, the user probably wants to know if their progress
+            // can't be saved...
+            libretro::set_message(3000, "Can't save memory card to disk!");
+        } else {
+            info!("Memory Card flushed to '{}'", self.file_path.display());
+
+            if self.backups > 0 {
+                if let Err(e) = backup::create(&self.file_path, &data, self.backups) {
+                    warn!(
+                        "Can't back up memory card '{}': {}",
+                        self.file_path.display(),
+                        e
+                    );
+                }
+            }
+        }
+
+        self.write_pending_since = None;
+    }
+
+    /// Called when the saves of a filtered card don't fit on the complete card anymore. The
+    /// filtered card is written to its own file so that nothing is lost, the saves can then be
+    /// recovered with the memory card manager.
+    fn dump_overflow(&mut self, memory: &[u8; FLASH_SIZE]) {
+        let game = self.filter.as_ref().map(|f| f.game()).unwrap_or("game");
+
+        let mut path = self.file_path.as_os_str().to_owned();
+        path.push(format!(".{}.mcr", game));
+        let path = PathBuf::from(path);
+
+        match backup::write_atomic(&path, memory) {
+            Ok(()) => {
+                warn!("Memory Card full, saves written to '{}'", path.display());
+                libretro::set_message(3000, "Memory card full, saves written to a separate file");
+            }
+            Err(e) => {
+                error!("Can't write memory card file '{}': {}", path.display(), e);
+                libretro::set_message(3000, "Can't save memory card to disk!");
+            }
+        }
+
+        self.write_pending_since = None;
+    }
+}
+
+/// Rename `path` to a free `<path>.<suffix>[.N]` path, returns the new path
+fn move_aside(path: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let mut n = 0;
+
+    let new_path = loop {
+        let mut p = path.as_os_str().to_owned();
+        p.push(format!(".{}", suffix));
+
+        if n > 0 {
+            p.push(format!(".{}", n));
+        }
+
+        let p = PathBuf::from(p);
+
+        if !p.exists() {
+            break p;
+        }
+
+        n += 1;
+    };
+
+    std::fs::rename(path, &new_path)?;
+
+    Ok(new_path)
+}
+
+/// Parse a memory card image or single save file in any of the formats supported by the `format`
+/// module
+pub fn load_card(data: &[u8]) -> io::Result<Card> {
+    let (_, memory) = Container::import(data)?;
+
+    Card::new(memory.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// How many frames do we wait after writes to a Memory Card have stopped before we flush the new
+/// contents to disk.
+///
+/// This value has two purposes: it prevents many spurious writes to disk which may be damaging for
+/// the hardware and it avoids writing incomplete saves to disk, avoiding corruption if the
+/// emulator crashes (or is quitted) mid-save.
+const WRITE_FLUSH_FRAME: u8 = 60;
+
+/// Size of the largest memory card file we're willing to load
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn modified_and_unreadable_cards() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("game.0.mcr");
+
+        let formatted = MemoryCard::new_formatted();
+        let mut memory = formatted.get_memory().unwrap().to_vec();
+        backup::write_card(&path, &memory).unwrap();
+        backup::create(&path, &memory, 5).unwrap();
+
+        // Written by another program, the card is still valid so it's kept and its checksum is
+        // updated
+        memory[FLASH_SIZE - 1] ^= 0xff;
+        fs::write(&path, &memory).unwrap();
+        assert!(!backup::checksum_matches(&path, &memory));
+
+        let (_, mc) = MemoryCardFile::load_or_create(&path).unwrap();
+        assert_eq!(&mc.get_memory().unwrap()[..], &memory[..]);
+        assert_eq!(fs::read(&path).unwrap(), memory);
+        assert!(backup::checksum_matches(&path, &memory));
+
+        // Unreadable: the file is moved aside and the backup isn't applied automatically
+        memory[0] = 0;
+        fs::write(&path, &memory).unwrap();
+
+        let (_, mc) = MemoryCardFile::load_or_create(&path).unwrap();
+        assert!(mc.is_format_valid());
+        assert!(!path.exists());
+        let corrupt = dir.path().join("game.0.mcr.corrupt");
+        assert_eq!(fs::read(corrupt).unwrap(), memory);
+        assert_eq!(backup::list(&path).len(), 1);
+
+        // Without a backup the file is left alone
+        let other = dir.path().join("game.1.mcr");
+        fs::write(&other, &memory).unwrap();
+        assert!(MemoryCardFile::load_or_create(&other).is_err());
+        assert_eq!(fs::read(&other).unwrap(), memory);
+    }
+}
@@ -0,0 +1,231 @@
+//! Memory card manager: list, export, import and delete individual saves from the core options
+//!
+//! Saves are exported in the MCS format to `<save dir>/memcards/export`. Every file placed in
+//! `<save dir>/memcards/import` can be imported: single saves and complete card images in any of
+//! the formats supported by the `memory_card` module, in which case all of their saves are
+//! imported.
+
+use crate::memory_card;
+use crate::memory_card::filesystem::{Card, Save};
+use crate::memory_card::MemoryCardFile;
+use crate::options::MemoryCardAction;
+use crate::psx::pad_memcard::devices::DeviceInterface;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Run `action` on `card`. `save_index` is the 1-based position of the target save in the list
+/// returned by `Card::saves`. Returns the message to display to the user. The backup actions
+/// need the card file and must go through `run_backup` instead.
+pub fn run(
+    action: MemoryCardAction,
+    card: &mut Card,
+    save_index: usize,
+    save_dir: &Path,
+) -> Result<String, String> {
+    let saves = card.saves();
+
+    let target = || match save_index.checked_sub(1).and_then(|i| saves.get(i)) {
+        Some(s) => Ok(s),
+        None => Err(format!("No save #{} on the memory card", save_index)),
+    };
+
+    match action {
+        MemoryCardAction::None => Ok(String::new()),
+        MemoryCardAction::List => {
+            for (i, save) in saves.iter().enumerate() {
+                info!(
+                    "Save #{}: '{}' [{}] {} ({} blocks{})",
+                    i + 1,
+                    save.title,
+                    save.product_code,
+                    save.region,
+                    save.blocks.len(),
+                    if save.deleted { ", deleted" } else { "" }
+                );
+            }
+
+            let mut list: Vec<String> = saves
+                .iter()
+                .enumerate()
+                .map(|(i, s)| {
+                    let deleted = if s.deleted { " (deleted)" } else { "" };
+
+                    format!("#{} {}{}", i + 1, s.title, deleted)
+                })
+                .collect();
+
+            list.push(format!("{} free blocks", card.free_blocks()));
+
+            Ok(list.join(" | "))
+        }
+        MemoryCardAction::Export => {
+            let save = target()?;
+            let path = export(card, save, save_dir)?;
+
+            Ok(format!("Save exported to '{}'", path.display()))
+        }
+        MemoryCardAction::ExportAll => {
+            let mut exported = 0;
+
+            for save in saves.iter().filter(|s| !s.deleted) {
+                export(card, save, save_dir)?;
+                exported += 1;
+            }
+
+            Ok(format!("{} saves exported", exported))
+        }
+        MemoryCardAction::Import => import(card, save_dir),
+        MemoryCardAction::Delete => {
+            let save = target()?;
+
+            card.delete(save.first_block())?;
+
+            Ok(format!("Deleted '{}'", save.title))
+        }
+        MemoryCardAction::Undelete => {
+            let save = target()?;
+
+            card.undelete(save.first_block())?;
+
+            Ok(format!("Restored '{}'", save.title))
+        }
+        MemoryCardAction::Repair => {
+            let fixes = card.repair();
+
+            for fix in &fixes {
+                info!("Memory card repair: {}", fix);
+            }
+
+            if fixes.is_empty() {
+                Ok("No problem found on the memory card".to_string())
+            } else {
+                Ok(format!("Fixed {} problems on the memory card", fixes.len()))
+            }
+        }
+        MemoryCardAction::ListBackups | MemoryCardAction::RestoreBackup => {
+            Err("Backups can't be managed from the card's contents".to_string())
+        }
+    }
+}
+
+/// Run the backup related `action` on the card file `mcf` connected to `mc`. `backup_index` is
+/// the 1-based position of the backup, newest first.
+pub fn run_backup(
+    action: MemoryCardAction,
+    mcf: &mut MemoryCardFile,
+    mc: &dyn DeviceInterface,
+    backup_index: usize,
+) -> Result<String, String> {
+    let backups = mcf.list_backups();
+
+    match action {
+        MemoryCardAction::ListBackups => {
+            if backups.is_empty() {
+                return Ok("No backup of this memory card".to_string());
+            }
+
+            let list: Vec<String> = backups
+                .iter()
+                .enumerate()
+                .map(|(i, b)| format!("#{} {}", i + 1, b.age()))
+                .collect();
+
+            Ok(list.join(" | "))
+        }
+        MemoryCardAction::RestoreBackup => {
+            let backup = match backup_index.checked_sub(1).and_then(|i| backups.get(i)) {
+                Some(b) => b,
+                None => return Err(format!("No backup #{}", backup_index)),
+            };
+
+            mcf.restore_backup(mc, backup)
+                .map_err(|e| format!("Can't restore backup: {}", e))?;
+
+            Ok(format!("Restored the backup from {}", backup.age()))
+        }
+        _ => Err(format!("{:?} is not a backup action", action)),
+    }
+}
+
+/// Export `save` to the export directory, returns the path of the new file
+fn export(card: &Card, save: &Save, save_dir: &Path) -> Result<PathBuf, String> {
+    let dir = save_dir.join("memcards").join("export");
+
+    // The directory filename is supposed to be ASCII but let's not trust it
+    let name: String = save
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    let path = dir.join(format!("{}.mcs", name));
+
+    fs::create_dir_all(&dir)
+        .and_then(|_| fs::write(&path, card.export_mcs(save)))
+        .map_err(|e| format!("Can't export save to '{}': {}", path.display(), e))?;
+
+    info!("Exported save '{}' to '{}'", save.name, path.display());
+
+    Ok(path)
+}
+
+/// Import every save found in the import directory
+fn import(card: &mut Card, save_dir: &Path) -> Result<String, String> {
+    let dir = save_dir.join("memcards").join("import");
+
+    let mut files: Vec<PathBuf> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file())
+            .collect(),
+        Err(e) => return Err(format!("Can't read '{}': {}", dir.display(), e)),
+    };
+
+    // Import in a predictable order
+    files.sort();
+
+    let mut imported = 0;
+    let mut failed = 0;
+
+    for path in files {
+        let source = match fs::read(&path).and_then(|data| memory_card::load_card(&data)) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("Can't import '{}': {}", path.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        for save in source.saves().iter().filter(|s| !s.deleted) {
+            match card.copy_save(&source, save) {
+                Ok(_) => {
+                    info!("Imported save '{}' from '{}'", save.name, path.display());
+                    imported += 1;
+                }
+                Err(e) => {
+                    warn!(
+                        "Can't import '{}' from '{}': {}",
+                        save.name,
+                        path.display(),
+                        e
+                    );
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    if failed > 0 {
+        Ok(format!("{} saves imported, {} failed", imported, failed))
+    } else {
+        Ok(format!("{} saves imported", imported))
+    }
+}
@@ -0,0 +1,81 @@
+//! Bilinear and sharp-bilinear scaling.
+//!
+//! Sharp-bilinear is equivalent to a nearest-neighbor prescale by the integer part of the
+//! scaling factor followed by a bilinear scale to the final size: the source pixels remain sharp
+//! and only their edges are interpolated to avoid uneven pixel sizes with non-integer factors.
+
+use super::{lerp, Image};
+
+/// Sampling position along one axis: index of the first source pixel and weight of the second
+/// one, in 1/256th
+#[derive(Copy, Clone)]
+struct Sample {
+    index: i32,
+    weight: u32,
+}
+
+pub fn bilinear(image: &Image, factor: f32) -> Image {
+    scale(image, factor, 1)
+}
+
+pub fn sharp_bilinear(image: &Image, factor: f32) -> Image {
+    let prescale = (factor as u32).max(1);
+
+    scale(image, factor, prescale)
+}
+
+fn scale(image: &Image, factor: f32, prescale: u32) -> Image {
+    let width = scaled_len(image.width, factor);
+    let height = scaled_len(image.height, factor);
+
+    let columns = samples(image.width, width, prescale);
+    let lines = samples(image.height, height, prescale);
+
+    Image::from_fn(width, height, |x, y| {
+        let c = columns[x as usize];
+        let l = lines[y as usize];
+
+        let top = lerp(
+            image.pixel(c.index, l.index),
+            image.pixel(c.index + 1, l.index),
+            c.weight,
+        );
+        let bottom = lerp(
+            image.pixel(c.index, l.index + 1),
+            image.pixel(c.index + 1, l.index + 1),
+            c.weight,
+        );
+
+        lerp(top, bottom, l.weight)
+    })
+}
+
+fn scaled_len(len: u32, factor: f32) -> u32 {
+    ((len as f32 * factor).round() as u32).max(1)
+}
+
+/// Compute the sampling positions to scale `src_len` pixels to `dst_len`
+fn samples(src_len: u32, dst_len: u32, prescale: u32) -> Vec<Sample> {
+    let src_len = src_len as i64;
+    let dst_len = dst_len as i64;
+    let prescale = prescale as i32;
+
+    (0..dst_len)
+        .map(|i| {
+            // Position of the center of the output pixel in the source in 16.16 fixed point,
+            // relative to the center of the first source pixel
+            let pos = (((2 * i + 1) * src_len) << 16) / (2 * dst_len) - 0x8000;
+
+            let index = (pos >> 16) as i32;
+            let frac = ((pos & 0xffff) >> 8) as i32;
+
+            // Sharpen the transition between the two pixels
+            let weight = ((frac - 0x80) * prescale + 0x80).clamp(0, 0x100);
+
+            Sample {
+                index,
+                weight: weight as u32,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,45 @@
+//! Simple CRT simulation: scanlines and aperture grille mask.
+
+use super::{attenuate, components, from_components, Image};
+
+/// Convert a filter strength to an `attenuate` factor, mapping 0xff to 0x100 (black)
+fn attenuation(strength: u8) -> u32 {
+    let s = strength as u32;
+
+    s + (s >> 7)
+}
+
+pub fn scanlines(mut image: Image, strength: u8) -> Image {
+    let attenuation = attenuation(strength);
+
+    image.map(|_, y, p| {
+        if y & 1 != 0 {
+            attenuate(p, attenuation)
+        } else {
+            p
+        }
+    });
+
+    image
+}
+
+pub fn aperture_mask(mut image: Image, strength: u8) -> Image {
+    let attenuation = attenuation(strength);
+
+    image.map(|x, _, p| {
+        // Index of the phosphor stripe lit at full intensity in this column
+        let lit = (x % 3) as usize;
+
+        let mut c = components(p).map(|c| c as i32);
+
+        for (i, c) in c.iter_mut().enumerate() {
+            if i != lit {
+                *c = ((*c as u32 * (0x100 - attenuation) + 0x80) >> 8) as i32;
+            }
+        }
+
+        from_components(c)
+    });
+
+    image
+}
@@ -0,0 +1,327 @@
+//! Software post-processing of the output frames.
+//!
+//! The filters run on the CPU on the final xRGB 8888 frame, after the rasterizer is done with it
+//! and before it's handed over to the frontend. They're chained in a `FilterChain`, each filter
+//! taking the output of the previous one as input. All filters use integer arithmetic (or
+//! precomputed lookup tables) so that their output is fully deterministic.
+//!
+//! `wasm_unified.rs` includes this module directly so that the browser frontend gets the same
+//! filters, it must not refer to anything else in the crate.
+
+mod bilinear;
+mod color;
+mod crt;
+mod ntsc;
+mod scale;
+
+#[cfg(test)]
+mod tests;
+
+use std::fmt;
+use std::str::FromStr;
+
+pub use color::ColorCorrection;
+
+/// Image in xRGB 8888 format
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Image {
+    /// Image pixels. Its size must always be *exactly* `width * height`.
+    pub pixels: Vec<u32>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32) -> Image {
+        Image {
+            pixels: vec![0; (width * height) as usize],
+            width,
+            height,
+        }
+    }
+
+    /// Returns the pixel at `(x, y)`, coordinates outside of the image are clamped to the nearest
+    /// edge
+    pub fn pixel(&self, x: i32, y: i32) -> u32 {
+        let x = x.clamp(0, self.width as i32 - 1) as usize;
+        let y = y.clamp(0, self.height as i32 - 1) as usize;
+
+        self.pixels[y * self.width as usize + x]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, p: u32) {
+        debug_assert!(x < self.width);
+        debug_assert!(y < self.height);
+
+        self.pixels[(y * self.width + x) as usize] = p;
+    }
+
+    /// Build a new image of the given dimensions by calling `f(x, y)` for every pixel
+    fn from_fn<F>(width: u32, height: u32, mut f: F) -> Image
+    where
+        F: FnMut(u32, u32) -> u32,
+    {
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(f(x, y));
+            }
+        }
+
+        Image {
+            pixels,
+            width,
+            height,
+        }
+    }
+
+    /// Replace every pixel by `f(x, y, pixel)`
+    fn map<F>(&mut self, mut f: F)
+    where
+        F: FnMut(u32, u32, u32) -> u32,
+    {
+        let width = self.width as usize;
+
+        for (i, p) in self.pixels.iter_mut().enumerate() {
+            *p = f((i % width) as u32, (i / width) as u32, *p);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+}
+
+/// A single post-processing step
+#[derive(Clone, PartialEq, Debug)]
+pub enum Filter {
+    /// xBR-style 2x pixel-art upscaler: smooths the edges by blending the corners of each pixel
+    Xbr,
+    /// ScaleFX-style 2x pixel-art upscaler: same edge detection as `Xbr` but the corners are
+    /// replaced instead of blended, so that no new colors are introduced
+    ScaleFx,
+    /// Bilinear scaling by the given factor
+    Bilinear(f32),
+    /// Scaling by the given factor with bilinear filtering restricted to the edges of the source
+    /// pixels, keeping them sharp
+    SharpBilinear(f32),
+    /// Darken every other line by the given amount (0: no effect, 0xff: black)
+    Scanlines(u8),
+    /// Aperture grille CRT mask: every column only keeps one of the color components at full
+    /// intensity, the other two are attenuated by the given amount (0: no effect, 0xff: black)
+    ApertureMask(u8),
+    /// NTSC composite video artifacts: chroma bleeding and luma/chroma crosstalk
+    Ntsc,
+    /// Gamma and saturation correction
+    Color(Box<ColorCorrection>),
+}
+
+impl Filter {
+    /// Returns the filtered image
+    pub fn apply(&self, image: Image) -> Image {
+        if image.is_empty() {
+            return image;
+        }
+
+        match self {
+            Filter::Xbr => scale::xbr(&image),
+            Filter::ScaleFx => scale::scalefx(&image),
+            Filter::Bilinear(factor) => bilinear::bilinear(&image, *factor),
+            Filter::SharpBilinear(factor) => bilinear::sharp_bilinear(&image, *factor),
+            Filter::Scanlines(strength) => crt::scanlines(image, *strength),
+            Filter::ApertureMask(strength) => crt::aperture_mask(image, *strength),
+            Filter::Ntsc => ntsc::composite(&image),
+            Filter::Color(correction) => correction.apply(image),
+        }
+    }
+
+    /// Factor by which this filter scales the image dimensions
+    pub fn scale(&self) -> f32 {
+        match self {
+            Filter::Xbr | Filter::ScaleFx => 2.,
+            Filter::Bilinear(factor) | Filter::SharpBilinear(factor) => *factor,
+            _ => 1.,
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Filter::Xbr => write!(f, "xbr"),
+            Filter::ScaleFx => write!(f, "scalefx"),
+            Filter::Bilinear(factor) => write!(f, "bilinear:{}", factor),
+            Filter::SharpBilinear(factor) => write!(f, "sharp-bilinear:{}", factor),
+            Filter::Scanlines(strength) => write!(f, "scanlines:{}", strength),
+            Filter::ApertureMask(strength) => write!(f, "aperture:{}", strength),
+            Filter::Ntsc => write!(f, "ntsc"),
+            Filter::Color(c) => write!(f, "color:{}:{}", c.gamma(), c.saturation()),
+        }
+    }
+}
+
+/// Parse a filter description in the format `name[:param[:param]]`, for instance `xbr`,
+/// `scanlines:128` or `color:1.1:1.2`
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        let mut params = s.trim().split(':');
+        let name = params.next().unwrap_or("");
+
+        let mut param = |default: f32| -> Result<f32, String> {
+            match params.next() {
+                Some(p) => p
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid parameter '{}' for filter '{}'", p, name)),
+                None => Ok(default),
+            }
+        };
+
+        let strength = |v: f32| -> Result<u8, String> {
+            if (0. ..=255.).contains(&v) {
+                Ok(v as u8)
+            } else {
+                Err(format!("Invalid strength {} for filter '{}'", v, name))
+            }
+        };
+
+        let factor = |v: f32| -> Result<f32, String> {
+            if (0.25..=8.).contains(&v) {
+                Ok(v)
+            } else {
+                Err(format!(
+                    "Invalid scaling factor {} for filter '{}'",
+                    v, name
+                ))
+            }
+        };
+
+        let filter = match name {
+            "xbr" => Filter::Xbr,
+            "scalefx" => Filter::ScaleFx,
+            "bilinear" => Filter::Bilinear(factor(param(2.)?)?),
+            "sharp-bilinear" => Filter::SharpBilinear(factor(param(2.)?)?),
+            "scanlines" => Filter::Scanlines(strength(param(96.)?)?),
+            "aperture" => Filter::ApertureMask(strength(param(64.)?)?),
+            "ntsc" => Filter::Ntsc,
+            "color" => {
+                let gamma = param(1.)?;
+                let saturation = param(1.)?;
+
+                Filter::Color(Box::new(ColorCorrection::new(gamma, saturation)?))
+            }
+            "gamma" => {
+                let gamma = param(ColorCorrection::CRT_GAMMA)?;
+
+                Filter::Color(Box::new(ColorCorrection::new(gamma, 1.)?))
+            }
+            _ => return Err(format!("Unknown filter '{}'", name)),
+        };
+
+        if params.next().is_some() {
+            return Err(format!("Too many parameters for filter '{}'", name));
+        }
+
+        Ok(filter)
+    }
+}
+
+/// Sequence of filters applied to each output frame
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct FilterChain {
+    filters: Vec<Filter>,
+}
+
+impl FilterChain {
+    /// Returns an empty chain which leaves the frames untouched
+    pub fn new() -> FilterChain {
+        FilterChain::default()
+    }
+
+    pub fn push(&mut self, filter: Filter) {
+        self.filters.push(filter);
+    }
+
+    pub fn filters(&self) -> &[Filter] {
+        &self.filters
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Run `image` through all the filters in order
+    pub fn process(&self, image: Image) -> Image {
+        self.filters.iter().fold(image, |image, f| f.apply(image))
+    }
+
+    /// Factor by which the chain scales the image dimensions
+    pub fn scale(&self) -> f32 {
+        self.filters.iter().map(Filter::scale).product()
+    }
+}
+
+impl fmt::Display for FilterChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, filter) in self.filters.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+
+            write!(f, "{}", filter)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a comma-separated list of filters, for instance `ntsc,xbr,scanlines`
+impl FromStr for FilterChain {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FilterChain, String> {
+        let filters = s
+            .split(',')
+            .filter(|f| !f.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+
+        Ok(FilterChain { filters })
+    }
+}
+
+/// Extract the red, green and blue components of `p`
+fn components(p: u32) -> [u32; 3] {
+    [(p >> 16) & 0xff, (p >> 8) & 0xff, p & 0xff]
+}
+
+/// Build an xRGB 8888 pixel from its components, saturating them to 8 bits
+fn from_components(c: [i32; 3]) -> u32 {
+    let [r, g, b] = c.map(|c| c.clamp(0, 0xff) as u32);
+
+    (r << 16) | (g << 8) | b
+}
+
+/// Weighted average of `a` and `b`, `w` is the weight of `b` in 1/256th
+fn lerp(a: u32, b: u32, w: u32) -> u32 {
+    debug_assert!(w <= 0x100);
+
+    let a = components(a);
+    let b = components(b);
+
+    let mut out = [0; 3];
+
+    for i in 0..3 {
+        out[i] = ((a[i] * (0x100 - w) + b[i] * w + 0x80) >> 8) as i32;
+    }
+
+    from_components(out)
+}
+
+/// Multiply every component of `p` by `(0x100 - attenuation) / 0x100`
+fn attenuate(p: u32, attenuation: u32) -> u32 {
+    lerp(p, 0, attenuation)
+}
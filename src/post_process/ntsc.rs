@@ -0,0 +1,84 @@
+//! NTSC composite video artifacts.
+//!
+//! Composite video carries the color information on a subcarrier modulated on top of the luma
+//! signal, with a much lower bandwidth. We approximate the two most visible consequences:
+//!
+//! * Chroma bleeding: the I and Q components are low-pass filtered horizontally.
+//! * Luma/chroma crosstalk: the TV can't fully separate the subcarrier from the luma, which
+//!   shows up as a fine checkerboard ("dot crawl") on saturated colors. The subcarrier phase
+//!   advances by 90 degrees every pixel and by 180 degrees every line.
+
+use super::{components, from_components, Image};
+
+/// Amount of chroma leaking into the luma, in 1/256th
+const CROSSTALK: i32 = 0x20;
+
+/// Horizontal low-pass filter applied to the chroma, the weights add up to 8
+const CHROMA_KERNEL: [i32; 5] = [1, 2, 2, 2, 1];
+
+/// Convert to YIQ with 8 fractional bits
+fn to_yiq(p: u32) -> [i32; 3] {
+    let [r, g, b] = components(p).map(|c| c as i32);
+
+    [
+        77 * r + 150 * g + 29 * b,
+        153 * r - 70 * g - 82 * b,
+        54 * r - 134 * g + 80 * b,
+    ]
+}
+
+/// Convert YIQ with 8 fractional bits back to RGB
+fn from_yiq(yiq: [i32; 3]) -> u32 {
+    let [y, i, q] = yiq;
+
+    // Round to the nearest integer
+    let c = |v: i32| (v + 0x8000) >> 16;
+
+    from_components([
+        c((y << 8) + 245 * i + 159 * q),
+        c((y << 8) - 70 * i - 166 * q),
+        c((y << 8) - 283 * i + 436 * q),
+    ])
+}
+
+pub fn composite(image: &Image) -> Image {
+    let width = image.width as usize;
+
+    let mut out = Image::new(image.width, image.height);
+    let mut line = Vec::with_capacity(width);
+
+    for y in 0..image.height {
+        line.clear();
+        line.extend((0..image.width).map(|x| to_yiq(image.pixel(x as i32, y as i32))));
+
+        for x in 0..width {
+            let [luma, i, q] = line[x];
+
+            let half = CHROMA_KERNEL.len() as i32 / 2;
+            let mut ci = 0;
+            let mut cq = 0;
+
+            for (k, &w) in CHROMA_KERNEL.iter().enumerate() {
+                let sx = (x as i32 + k as i32 - half).clamp(0, width as i32 - 1);
+                let [_, si, sq] = line[sx as usize];
+
+                ci += si * w;
+                cq += sq * w;
+            }
+
+            // Subcarrier, sampled at 4 times its frequency
+            let subcarrier = match (x + 2 * y as usize) & 3 {
+                0 => i,
+                1 => q,
+                2 => -i,
+                _ => -q,
+            };
+
+            let luma = luma + ((subcarrier * CROSSTALK) >> 8);
+
+            out.set_pixel(x as u32, y, from_yiq([luma, ci >> 3, cq >> 3]));
+        }
+    }
+
+    out
+}
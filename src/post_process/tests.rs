@@ -0,0 +1,184 @@
+//! Post-processing tests.
+//!
+//! Every filter is run on a small test pattern and the result is compared with a golden image
+//! stored in `golden/`, see `crate::golden`. The images are regression references: they were
+//! generated from the filters themselves, there's no external reference implementation.
+//! After an intentional change to a filter's output they can be regenerated with:
+//!
+//! `UPDATE_GOLDEN=1 cargo test post_process`
+
+use super::{ColorCorrection, Filter, FilterChain, Image};
+use crate::golden;
+
+/// 32x24 test pattern with a mix of hard edges, diagonals, gradients and saturated colors
+fn test_pattern() -> Image {
+    Image::from_fn(32, 24, |x, y| {
+        let (xi, yi) = (x as i32, y as i32);
+
+        if (xi - 8).pow(2) + (yi - 8).pow(2) <= 25 {
+            // Red disc
+            0xe0_2020
+        } else if x == y + 12 || x == y + 13 {
+            // Thick diagonal line
+            0xff_ff00
+        } else if y >= 16 && x < 16 {
+            // Checkerboard
+            if (x ^ y) & 1 == 0 {
+                0xff_ffff
+            } else {
+                0x00_0000
+            }
+        } else if y >= 16 {
+            // Horizontal gradient
+            let v = (x - 16) * 16;
+
+            (v << 16) | (v << 8) | (0xff - v)
+        } else {
+            0x20_40a0
+        }
+    })
+}
+
+/// Serialize `image` in binary PPM format
+fn to_ppm(image: &Image) -> Vec<u8> {
+    let mut ppm = format!("P6\n{} {}\n255\n", image.width, image.height).into_bytes();
+
+    for &p in &image.pixels {
+        ppm.extend_from_slice(&[(p >> 16) as u8, (p >> 8) as u8, p as u8]);
+    }
+
+    ppm
+}
+
+/// Run `chain` on the test pattern and compare the output with the golden image `name`
+fn check_golden(name: &str, chain: &str) {
+    let chain: FilterChain = chain.parse().unwrap();
+    let out = to_ppm(&chain.process(test_pattern()));
+
+    golden::check(
+        &golden::path(&format!("src/post_process/golden/{}.ppm", name)),
+        &out,
+        3,
+    );
+}
+
+#[test]
+fn golden_xbr() {
+    check_golden("xbr", "xbr");
+}
+
+#[test]
+fn golden_scalefx() {
+    check_golden("scalefx", "scalefx");
+}
+
+#[test]
+fn golden_bilinear() {
+    check_golden("bilinear", "bilinear:1.5");
+}
+
+#[test]
+fn golden_sharp_bilinear() {
+    check_golden("sharp_bilinear", "sharp-bilinear:2.5");
+}
+
+#[test]
+fn golden_scanlines() {
+    check_golden("scanlines", "scanlines:128");
+}
+
+#[test]
+fn golden_aperture() {
+    check_golden("aperture", "aperture:96");
+}
+
+#[test]
+fn golden_ntsc() {
+    check_golden("ntsc", "ntsc");
+}
+
+#[test]
+fn golden_color() {
+    check_golden("color", "color:1.4:1.5");
+}
+
+#[test]
+fn golden_chain() {
+    check_golden("chain", "ntsc,gamma,xbr,scanlines,aperture");
+}
+
+#[test]
+fn neutral_filters() {
+    let pattern = test_pattern();
+
+    for chain in ["", "color:1:1", "scanlines:0", "aperture:0", "bilinear:1"] {
+        let chain: FilterChain = chain.parse().unwrap();
+
+        assert_eq!(chain.process(pattern.clone()), pattern, "{}", chain);
+    }
+}
+
+#[test]
+fn scalers_preserve_flat_areas() {
+    let flat = Image::from_fn(7, 5, |_, _| 0x12_3456);
+
+    for filter in ["xbr", "scalefx", "bilinear:3", "sharp-bilinear:1.7"] {
+        let filter: Filter = filter.parse().unwrap();
+        let out = filter.apply(flat.clone());
+
+        assert_eq!(out.width, (7. * filter.scale()).round() as u32);
+        assert_eq!(out.height, (5. * filter.scale()).round() as u32);
+        assert!(out.pixels.iter().all(|&p| p == 0x12_3456), "{}", filter);
+    }
+}
+
+#[test]
+fn scalefx_keeps_palette() {
+    let pattern = test_pattern();
+    let out = Filter::ScaleFx.apply(pattern.clone());
+
+    assert!(out.pixels.iter().all(|p| pattern.pixels.contains(p)));
+}
+
+#[test]
+fn scanlines_black() {
+    let out = Filter::Scanlines(0xff).apply(test_pattern());
+
+    for y in (1..out.height).step_by(2) {
+        for x in 0..out.width {
+            assert_eq!(out.pixel(x as i32, y as i32), 0);
+        }
+    }
+}
+
+#[test]
+fn parse_chain() {
+    let chain: FilterChain = " ntsc, xbr ,scanlines:200,gamma".parse().unwrap();
+
+    assert_eq!(
+        chain.filters(),
+        [
+            Filter::Ntsc,
+            Filter::Xbr,
+            Filter::Scanlines(200),
+            Filter::Color(Box::new(
+                ColorCorrection::new(ColorCorrection::CRT_GAMMA, 1.).unwrap()
+            )),
+        ]
+    );
+    assert_eq!(chain.scale(), 2.);
+
+    // Round trip
+    assert_eq!(chain.to_string().parse::<FilterChain>().unwrap(), chain);
+
+    for bad in [
+        "crt",
+        "scanlines:256",
+        "xbr:2",
+        "bilinear:x",
+        "bilinear:100",
+        "color:1:1:1",
+    ] {
+        assert!(bad.parse::<FilterChain>().is_err(), "{}", bad);
+    }
+}
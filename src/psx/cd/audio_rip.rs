@@ -0,0 +1,492 @@
+//! Extraction of the audio content of a disc
+//!
+//! PlayStation discs can contain two kinds of audio: regular CD-DA tracks and XA-ADPCM streams.
+//! The latter are stored in Mode 2 Form 2 sectors within regular files of the data track. A single
+//! file usually interleaves several streams (or "channels") identified by the file and channel
+//! numbers of the sector subheader, the game selects the one it wants to play by setting the CD
+//! controller's filter.
+//!
+//! The XA decoding uses the same code as the emulated CD controller so the output should be
+//! identical to what the console would send to the SPU (before the CD volume is applied).
+
+use super::cdc::{xa_decode_audio_block, xa_samples_per_channel, AudioResampler};
+use super::disc::{CdCache, Disc};
+use super::iso9660::{self, Directory};
+use crate::error::{PsxError, Result};
+use cdimage::bcd::Bcd;
+use cdimage::msf::Msf;
+use cdimage::sector::{XaCodingAudio, XaSamplingFreq};
+use cdimage::{Sector, TrackFormat};
+use std::collections::BTreeMap;
+
+/// Sample rate of CD-DA audio
+pub const CDDA_RATE: u32 = 44_100;
+
+/// Number of stereo samples in a CD-DA sector
+pub const CDDA_SECTOR_SAMPLES: usize = 588;
+
+/// Offset of the XA-ADPCM audio block in a raw sector: 12 bytes of sync, 4 bytes of header and 8
+/// bytes of subheader
+const XA_DATA_OFFSET: usize = 24;
+
+/// Length of the XA-ADPCM audio block in a sector
+const XA_DATA_LEN: usize = 2304;
+
+/// Maximum number of samples per channel in an XA-ADPCM sector (4bpp mono)
+const XA_MAX_SECTOR_SAMPLES: usize = 18 * 8 * 28;
+
+/// Submode flag set for audio sectors
+const SUBMODE_AUDIO: u8 = 1 << 2;
+
+/// Submode flag set for Form 2 sectors
+const SUBMODE_FORM2: u8 = 1 << 5;
+
+/// ISO9660 doesn't allow more than 8 levels of directories, we use that to avoid recursing
+/// forever on broken filesystems
+const MAX_DIRECTORY_DEPTH: usize = 8;
+
+/// A CD-DA track
+#[derive(Copy, Clone, Debug)]
+pub struct AudioTrack {
+    /// Track number
+    pub number: Bcd,
+    /// Length of the track
+    pub length: Msf,
+}
+
+impl AudioTrack {
+    pub fn sector_count(&self) -> u32 {
+        self.length.sector_index()
+    }
+
+    /// Duration of the track in seconds
+    pub fn duration(&self) -> f32 {
+        self.sector_count() as f32 / 75.
+    }
+}
+
+/// Returns the list of CD-DA tracks in the disc's table of contents
+pub fn audio_tracks(disc: &Disc) -> Vec<AudioTrack> {
+    disc.toc()
+        .tracks()
+        .iter()
+        .filter(|t| t.format == TrackFormat::Audio)
+        .map(|t| AudioTrack {
+            number: t.track,
+            length: t.length,
+        })
+        .collect()
+}
+
+/// Read the whole contents of CD-DA track `track`. Returns interleaved stereo samples at 44.1kHz.
+pub fn rip_cdda_track(disc: &mut Disc, track: &AudioTrack) -> Result<Vec<i16>> {
+    let sector_count = track.sector_count();
+
+    let mut dp = {
+        let toc_track = disc.toc().track(track.number)?;
+
+        if toc_track.format != TrackFormat::Audio {
+            let m = format!("Track {} is not an audio track", track.number);
+            return Err(PsxError::BadDiscFormat(m));
+        }
+
+        toc_track.disc_position(Msf::from_sector_index(0).unwrap())?
+    };
+
+    let mut samples = Vec::with_capacity(sector_count as usize * CDDA_SECTOR_SAMPLES * 2);
+
+    for i in 0..sector_count {
+        let sector = disc.read_sector(dp).map_err(iso9660::IsoError::from)?;
+
+        for s in sector.data_2352().chunks_exact(2) {
+            samples.push(i16::from_le_bytes([s[0], s[1]]));
+        }
+
+        if i + 1 < sector_count {
+            dp = dp.next().unwrap();
+        }
+    }
+
+    Ok(samples)
+}
+
+/// An XA-ADPCM stream found in a file of the data track
+#[derive(Clone, Debug)]
+pub struct XaStream {
+    /// Path of the file containing the stream, relative to the root of the filesystem
+    pub path: String,
+    /// File number in the sector subheaders
+    pub file: u8,
+    /// Channel number in the sector subheaders
+    pub channel: u8,
+    /// Raw coding information byte of the first sector of the stream
+    coding: u8,
+    /// Index of the stream's sectors in track 01
+    sectors: Vec<u32>,
+}
+
+impl XaStream {
+    fn coding(&self) -> XaCodingAudio {
+        XaCodingAudio(self.coding)
+    }
+
+    pub fn stereo(&self) -> bool {
+        self.coding().stereo()
+    }
+
+    /// Number of channels in the stream (1 for mono, 2 for stereo)
+    pub fn channels(&self) -> u16 {
+        if self.stereo() {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Native sample rate of the stream
+    pub fn sample_rate(&self) -> u32 {
+        sample_rate(self.coding())
+    }
+
+    /// Number of 4bpp or 8bpp bits per sample in the encoded stream
+    pub fn bits_per_sample(&self) -> u8 {
+        match self.coding().bits_per_sample() {
+            cdimage::sector::XaBitsPerSample::S4Bits => 4,
+            cdimage::sector::XaBitsPerSample::S8Bits => 8,
+        }
+    }
+
+    pub fn sector_count(&self) -> usize {
+        self.sectors.len()
+    }
+
+    /// Duration of the stream in seconds
+    pub fn duration(&self) -> f32 {
+        let samples = self.sectors.len() * xa_samples_per_channel(self.coding());
+
+        samples as f32 / self.sample_rate() as f32
+    }
+}
+
+/// Browse the ISO9660 filesystem of the data track looking for XA-ADPCM streams. Every sector of
+/// every file is read so this can take a little while on large discs.
+pub fn find_xa_streams(disc: &mut Disc) -> Result<Vec<XaStream>> {
+    let cache = disc.cache_mut();
+    let root = iso9660::open_image(cache)?;
+
+    let mut streams = Vec::new();
+
+    scan_directory(cache, &root, "", 0, &mut streams)?;
+
+    Ok(streams)
+}
+
+fn scan_directory(
+    cache: &mut CdCache,
+    dir: &Directory,
+    path: &str,
+    depth: usize,
+    streams: &mut Vec<XaStream>,
+) -> Result<()> {
+    if depth >= MAX_DIRECTORY_DEPTH {
+        return Ok(());
+    }
+
+    for entry in dir.ls() {
+        let name = entry.name();
+
+        // Skip the "." and ".." entries
+        if name == b"\0" || name == b"\x01" {
+            continue;
+        }
+
+        // Get rid of the ";1" version suffix
+        let name = name.split(|&b| b == b';').next().unwrap();
+        let path = format!("{}/{}", path, String::from_utf8_lossy(name));
+
+        if entry.is_dir() {
+            let subdir = Directory::new(cache, entry)?;
+
+            scan_directory(cache, &subdir, &path, depth + 1, streams)?;
+        } else {
+            // The extent length of interleaved files is normally given in 2048 bytes sectors
+            let sector_count = entry.extent_len().div_ceil(2048);
+
+            scan_file(cache, &path, entry.extent_location(), sector_count, streams)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Look for XA-ADPCM sectors in the file at `location` and demux them by file and channel number
+fn scan_file(
+    cache: &mut CdCache,
+    path: &str,
+    location: u32,
+    sector_count: u32,
+    streams: &mut Vec<XaStream>,
+) -> Result<()> {
+    let mut file_streams: BTreeMap<(u8, u8), XaStream> = BTreeMap::new();
+
+    for index in location..(location + sector_count) {
+        let sector = read_track1_sector(cache, index)?;
+        let raw = sector.data_2352();
+
+        // Mode byte
+        if raw[15] != 2 {
+            continue;
+        }
+
+        let file = raw[16];
+        let channel = raw[17];
+        let submode = raw[18];
+        let coding = raw[19];
+
+        if submode & (SUBMODE_AUDIO | SUBMODE_FORM2) != (SUBMODE_AUDIO | SUBMODE_FORM2) {
+            continue;
+        }
+
+        file_streams
+            .entry((file, channel))
+            .or_insert_with(|| XaStream {
+                path: path.to_string(),
+                file,
+                channel,
+                coding,
+                sectors: Vec::new(),
+            })
+            .sectors
+            .push(index);
+    }
+
+    streams.extend(file_streams.into_values());
+
+    Ok(())
+}
+
+/// Read the sector at `index` in track 01, the track containing the filesystem
+pub(crate) fn read_track1_sector(cache: &mut CdCache, index: u32) -> Result<Sector> {
+    let msf = match Msf::from_sector_index(index) {
+        Some(m) => m,
+        None => return Err(iso9660::IsoError::BadExtent(index).into()),
+    };
+
+    let dp = cache.toc().track(Bcd::ONE)?.disc_position(msf)?;
+
+    cache
+        .read_sector(dp)
+        .map_err(|e| iso9660::IsoError::from(e).into())
+}
+
+/// Decode `stream` at its native sample rate. Returns interleaved samples if the stream is stereo.
+pub fn decode_xa_stream(disc: &mut Disc, stream: &XaStream) -> Result<Vec<i16>> {
+    let sector_samples = xa_samples_per_channel(stream.coding());
+
+    let mut samples =
+        Vec::with_capacity(stream.sectors.len() * sector_samples * usize::from(stream.channels()));
+    let mut decoder = XaDecoder::new();
+
+    for &index in &stream.sectors {
+        let sector = read_track1_sector(disc.cache_mut(), index)?;
+
+        decoder.decode_sector(sector.data_2352(), &mut samples);
+    }
+
+    Ok(samples)
+}
+
+/// Decoder for the sectors of a single XA-ADPCM stream
+pub struct XaDecoder {
+    /// Last two decoded samples for each channel
+    adpcm_last: [[i16; 2]; 2],
+    buffer: [[i16; 2]; XA_MAX_SECTOR_SAMPLES],
+}
+
+impl XaDecoder {
+    pub fn new() -> XaDecoder {
+        XaDecoder {
+            adpcm_last: [[0; 2]; 2],
+            buffer: [[0; 2]; XA_MAX_SECTOR_SAMPLES],
+        }
+    }
+
+    /// Decode the raw 2352 bytes XA-ADPCM `sector` and append the samples to `out`, interleaved
+    /// if the sector is stereo.
+    pub fn decode_sector(&mut self, sector: &[u8], out: &mut Vec<i16>) {
+        let coding = XaCodingAudio(sector[19]);
+        let sector_samples = xa_samples_per_channel(coding);
+        let data = &sector[XA_DATA_OFFSET..XA_DATA_OFFSET + XA_DATA_LEN];
+
+        xa_decode_audio_block(data, coding, &mut self.adpcm_last, &mut self.buffer);
+
+        for &[l, r] in &self.buffer[..sector_samples] {
+            out.push(l);
+            if coding.stereo() {
+                out.push(r);
+            }
+        }
+    }
+}
+
+impl Default for XaDecoder {
+    fn default() -> XaDecoder {
+        XaDecoder::new()
+    }
+}
+
+/// Returns the sample rate and number of channels of the raw XA-ADPCM `sector`
+pub fn xa_sector_format(sector: &[u8]) -> (u32, u16) {
+    let coding = XaCodingAudio(sector[19]);
+    let channels = if coding.stereo() { 2 } else { 1 };
+
+    (sample_rate(coding), channels)
+}
+
+fn sample_rate(coding: XaCodingAudio) -> u32 {
+    match coding.sampling_frequency() {
+        XaSamplingFreq::F18_9 => 18_900,
+        XaSamplingFreq::F37_8 => 37_800,
+    }
+}
+
+/// Resample the output of `decode_xa_stream` to 44.1kHz stereo using the CD controller's
+/// interpolation filter.
+pub fn resample_xa_44100(stream: &XaStream, samples: &[i16]) -> Vec<i16> {
+    // Ratio of the input frequency to 44.1kHz in 1/7th, see `resample_44100` in the decoder
+    let phase_step = match stream.coding().sampling_frequency() {
+        XaSamplingFreq::F18_9 => 3,
+        XaSamplingFreq::F37_8 => 6,
+    };
+
+    let channels = usize::from(stream.channels());
+    let mut resamplers = [AudioResampler::new(), AudioResampler::new()];
+    let mut phase = 0;
+
+    let mut out = Vec::with_capacity(samples.len() / channels * 2 * 7 / phase_step + 2);
+
+    for frame in samples.chunks_exact(channels) {
+        let l = frame[0];
+        let r = frame[channels - 1];
+
+        while phase < 7 {
+            out.push(resamplers[0].resample(phase));
+            out.push(resamplers[1].resample(phase));
+            phase += phase_step as u8;
+        }
+
+        phase -= 7;
+        resamplers[0].push_sample(l);
+        resamplers[1].push_sample(r);
+    }
+
+    out
+}
+
+/// Write `samples` (interleaved if `channels` is greater than 1) to a 16bit WAV file
+#[cfg(feature = "wav-export")]
+pub fn write_wav<P: AsRef<std::path::Path>>(
+    path: P,
+    channels: u16,
+    sample_rate: u32,
+    samples: &[i16],
+) -> Result<()> {
+    let spec = hound::WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+
+    let mut wav = hound::WavWriter::create(path, spec)?;
+
+    for &s in samples {
+        wav.write_sample(s)?;
+    }
+
+    wav.finalize()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(coding: u8, sector_count: u32) -> XaStream {
+        XaStream {
+            path: "/MUSIC.XA".to_string(),
+            file: 1,
+            channel: 0,
+            coding,
+            sectors: (0..sector_count).collect(),
+        }
+    }
+
+    #[test]
+    fn stream_format() {
+        // 4bpp, 37.8kHz, mono
+        let s = stream(0x00, 75);
+        assert_eq!(s.sample_rate(), 37_800);
+        assert_eq!(s.channels(), 1);
+        assert_eq!(s.bits_per_sample(), 4);
+
+        // 4bpp, 18.9kHz, stereo
+        let s = stream(0x05, 75);
+        assert_eq!(s.sample_rate(), 18_900);
+        assert_eq!(s.channels(), 2);
+        // 2016 samples per channel per sector
+        assert!((s.duration() - 75. * 2016. / 18_900.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn decode_block() {
+        // Single sound group repeated: filter 0, shift 12 for every unit so the nibbles are output
+        // as-is
+        let mut data = [0u8; XA_DATA_LEN];
+
+        for group in data.chunks_exact_mut(128) {
+            for p in &mut group[..16] {
+                *p = 12;
+            }
+            // First sample of units 0 and 1
+            group[16] = 0x71;
+            group[17] = 0x0f;
+        }
+
+        // 4bpp stereo
+        let coding = XaCodingAudio(0x01);
+        let mut last = [[0; 2]; 2];
+        let mut out = [[0; 2]; XA_MAX_SECTOR_SAMPLES];
+
+        xa_decode_audio_block(&data, coding, &mut last, &mut out);
+
+        assert_eq!(xa_samples_per_channel(coding), 2016);
+        // Unit 0 is left, unit 1 is right
+        assert_eq!(out[0], [1, 7]);
+        assert_eq!(out[1], [0, 0]);
+        // Units 2 and 3 follow unit 0 and 1 in their respective channel
+        assert_eq!(out[28], [-1, 0]);
+    }
+
+    #[test]
+    fn resample_length() {
+        for &(coding, ratio) in &[(0x00, 7. / 6.), (0x04, 7. / 3.)] {
+            let s = stream(coding, 1);
+            let input = vec![0x1000; 4032];
+
+            let out = resample_xa_44100(&s, &input);
+
+            // Always stereo
+            let frames = out.len() / 2;
+            let expected = 4032. * ratio;
+
+            assert!((frames as f64 - expected).abs() <= 1.);
+            // Once the history is filled we get a DC output, slightly attenuated by the filter
+            // (about -0.9dB)
+            assert!(out[out.len() - 16..]
+                .iter()
+                .all(|&s| s > 0xe00 && s < 0xf00));
+        }
+    }
+}
@@ -283,28 +283,10 @@ impl Decoder {
         // a total of 2324 bytes (the length of an XA Form2 payload)
         let data = &self.ram[data_start..(data_start + 2304)];
 
-        let shift_4bpp = match self.rtci.bits_per_sample() {
-            XaBitsPerSample::S4Bits => 1,
-            XaBitsPerSample::S8Bits => 0,
-        };
-
-        let units_per_group = 4 << shift_4bpp;
-        let samples_8bpp = shift_4bpp == 0;
-
         let stereo = self.rtci.stereo();
 
-        // Total number of samples we're about to decode
-        let total_samples = 18 * (4 << shift_4bpp) * 28;
-
-        // 1 for stereo, 0 for mono
-        let stereo_one = stereo as usize;
-
         // Number of generated stereo samples
-        let stereo_samples = if stereo {
-            total_samples / 2
-        } else {
-            total_samples
-        };
+        let stereo_samples = xa_samples_per_channel(self.rtci) as u16;
 
         let audio_frequency = match self.rtci.sampling_frequency() {
             XaSamplingFreq::F18_9 => {
@@ -322,90 +304,12 @@ impl Decoder {
                 *s = [0, 0];
             }
         } else {
-            // Offsets in the output buffer, per channel
-            let mut output_offsets = [0; 2];
-
-            // Each audio block contains 18 "sound groups" of 128 bytes
-            for group in 0..18 {
-                let group_off = 128 * group;
-                // Each group has a 16 byte "Sound Parameters" header...
-                let sp = &data[group_off..group_off + 16];
-                // ... and 112 bytes of "Sample Audio Data"
-                let audio_data = &data[group_off + 16..group_off + 128];
-
-                // Each group has between 4 and 8 "Sound Units" depenting on the sample bit depth
-                for unit in 0..units_per_group {
-                    // The params are stored twice, the second time at the same address | 4
-                    let param = sp[((unit << 1) & 8) | (unit & 3)];
-                    let shift = param & 0xf;
-                    let weights: [(i32, i32); 16] = [
-                        (0, 0),
-                        (60, 0),
-                        (115, -52),
-                        (98, -55),
-                        (122, -60),
-                        (0, 0),
-                        (0, 0),
-                        (0, 0),
-                        (0, 0),
-                        (0, 0),
-                        (0, 0),
-                        (0, 0),
-                        (0, 0),
-                        (0, 0),
-                        (0, 0),
-                        (0, 0),
-                    ];
-                    let (wp, wn) = weights[(param >> 4) as usize];
-
-                    let channel = unit & stereo_one;
-
-                    for i in 0..28 {
-                        let encoded = if samples_8bpp {
-                            audio_data[(i << 2) | unit]
-                        } else {
-                            // 4bpp: 2 samples per byte
-                            let s = audio_data[(i << 2) | (unit >> 1)];
-
-                            // Convert the sample to 8 bit by setting the low 4 bits to 0
-                            if unit & 1 == 0 {
-                                s << 4
-                            } else {
-                                s & 0xf0
-                            }
-                        };
-
-                        // Convert to signed 16 bits
-                        let sample = (u16::from(encoded) << 8) as i16;
-                        // Convert to 32bits to handle overflows
-                        let mut sample = i32::from(sample);
-
-                        // ADPCM decode
-                        sample >>= shift;
-                        let sample_1 = i32::from(self.adpcm_last[channel][0]);
-                        let sample_2 = i32::from(self.adpcm_last[channel][1]);
-                        sample += (sample_1 * wp + sample_2 * wn) >> 6;
-
-                        // Saturate to 16 bits
-                        let sample = if sample > i16::max_value() as i32 {
-                            i16::max_value()
-                        } else if sample < i16::min_value() as i32 {
-                            i16::min_value()
-                        } else {
-                            sample as i16
-                        };
-
-                        // Rotate last samples
-                        self.adpcm_last[channel][1] = self.adpcm_last[channel][0];
-                        self.adpcm_last[channel][0] = sample;
-
-                        // Store the data in the output buffer
-                        let sample_off = output_offsets[channel];
-                        self.sample_buffer[sample_off][channel] = sample;
-                        output_offsets[channel] += 1;
-                    }
-                }
-            }
+            xa_decode_audio_block(
+                data,
+                self.rtci,
+                &mut self.adpcm_last,
+                &mut self.sample_buffer,
+            );
         }
 
         let nsamples = self.resample_44100(stereo_samples, stereo, audio_frequency);
@@ -1416,6 +1320,131 @@ impl fmt::Debug for HostFifo {
     }
 }
 
+/// Returns the number of samples per channel in an XA-ADPCM sector with the given coding
+pub fn xa_samples_per_channel(coding: XaCodingAudio) -> usize {
+    let shift_4bpp = match coding.bits_per_sample() {
+        XaBitsPerSample::S4Bits => 1,
+        XaBitsPerSample::S8Bits => 0,
+    };
+
+    // 18 sound groups of 4 or 8 sound units of 28 samples
+    let total_samples = 18 * (4 << shift_4bpp) * 28;
+
+    if coding.stereo() {
+        total_samples / 2
+    } else {
+        total_samples
+    }
+}
+
+/// Decode the 2304 bytes XA-ADPCM audio block `data` into `out`. For stereo sectors the left
+/// channel is stored in `out[n][0]` and the right channel in `out[n][1]`, mono sectors only use
+/// `out[n][0]`. `adpcm_last` contains the last two decoded samples of each channel and is updated.
+///
+/// `out` must be at least `xa_samples_per_channel(coding)` long.
+pub fn xa_decode_audio_block(
+    data: &[u8],
+    coding: XaCodingAudio,
+    adpcm_last: &mut [[i16; 2]; 2],
+    out: &mut [[i16; 2]],
+) {
+    let shift_4bpp = match coding.bits_per_sample() {
+        XaBitsPerSample::S4Bits => 1,
+        XaBitsPerSample::S8Bits => 0,
+    };
+
+    let units_per_group = 4 << shift_4bpp;
+    let samples_8bpp = shift_4bpp == 0;
+
+    // 1 for stereo, 0 for mono
+    let stereo_one = coding.stereo() as usize;
+
+    // Offsets in the output buffer, per channel
+    let mut output_offsets = [0; 2];
+
+    // Each audio block contains 18 "sound groups" of 128 bytes
+    for group in 0..18 {
+        let group_off = 128 * group;
+        // Each group has a 16 byte "Sound Parameters" header...
+        let sp = &data[group_off..group_off + 16];
+        // ... and 112 bytes of "Sample Audio Data"
+        let audio_data = &data[group_off + 16..group_off + 128];
+
+        // Each group has between 4 and 8 "Sound Units" depenting on the sample bit depth
+        for unit in 0..units_per_group {
+            // The params are stored twice, the second time at the same address | 4
+            let param = sp[((unit << 1) & 8) | (unit & 3)];
+            let shift = param & 0xf;
+            let weights: [(i32, i32); 16] = [
+                (0, 0),
+                (60, 0),
+                (115, -52),
+                (98, -55),
+                (122, -60),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+            ];
+            let (wp, wn) = weights[(param >> 4) as usize];
+
+            let channel = unit & stereo_one;
+
+            for i in 0..28 {
+                let encoded = if samples_8bpp {
+                    audio_data[(i << 2) | unit]
+                } else {
+                    // 4bpp: 2 samples per byte
+                    let s = audio_data[(i << 2) | (unit >> 1)];
+
+                    // Convert the sample to 8 bit by setting the low 4 bits to 0
+                    if unit & 1 == 0 {
+                        s << 4
+                    } else {
+                        s & 0xf0
+                    }
+                };
+
+                // Convert to signed 16 bits
+                let sample = (u16::from(encoded) << 8) as i16;
+                // Convert to 32bits to handle overflows
+                let mut sample = i32::from(sample);
+
+                // ADPCM decode
+                sample >>= shift;
+                let sample_1 = i32::from(adpcm_last[channel][0]);
+                let sample_2 = i32::from(adpcm_last[channel][1]);
+                sample += (sample_1 * wp + sample_2 * wn) >> 6;
+
+                // Saturate to 16 bits
+                let sample = if sample > i16::max_value() as i32 {
+                    i16::max_value()
+                } else if sample < i16::min_value() as i32 {
+                    i16::min_value()
+                } else {
+                    sample as i16
+                };
+
+                // Rotate last samples
+                adpcm_last[channel][1] = adpcm_last[channel][0];
+                adpcm_last[channel][0] = sample;
+
+                // Store the data in the output buffer
+                let sample_off = output_offsets[channel];
+                out[sample_off][channel] = sample;
+                output_offsets[channel] += 1;
+            }
+        }
+    }
+}
+
 /// Possible frequencies for CD audio. The values are the ratio of the frequency to the standard
 /// 44.1kHz frequency in 1/7th of a sample.
 #[derive(Copy, Clone, PartialEq, Eq)]
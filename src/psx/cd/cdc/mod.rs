@@ -9,6 +9,8 @@ use super::Disc;
 use crate::PsxError;
 use cdimage::{DiscPosition, Sector};
 pub use uc::ROM_DUMP_SIZE as MC68HC05_ROM_DUMP_SIZE;
+pub(super) use decoder::{xa_decode_audio_block, xa_samples_per_channel};
+pub(super) use resampler::AudioResampler;
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Cdc {
@@ -138,6 +138,15 @@ impl Disc {
         self.cache.read_sector(dp)
     }
 
+    pub fn toc(&self) -> &Toc {
+        self.cache.toc()
+    }
+
+    /// Direct access to the sector cache, used to browse the ISO9660 filesystem
+    pub(crate) fn cache_mut(&mut self) -> &mut CdCache {
+        &mut self.cache
+    }
+
     pub fn region(&self) -> Region {
         // For now I prefer to panic to catch potential issues with the serial number handling
         // code, alternatively we could fallback on `extract_system_region`
@@ -210,7 +219,7 @@ impl<'de> Deserialize<'de> for Disc {
 }
 
 /// Disc region
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Region {
     /// Japan (NTSC): SCEI
     Japan,
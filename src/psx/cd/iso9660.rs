@@ -103,6 +103,31 @@ impl Directory {
         }
     }
 
+    /// Look up the entry at `path` relative to this directory. Both `/` and `\` are accepted as
+    /// separators and the ";1" version suffix can be omitted.
+    pub fn lookup(&self, image: &mut CdCache, path: &str) -> Result<Entry, IsoError> {
+        let is_separator = |c: char| c == '/' || c == '\\';
+
+        let path = path.trim_start_matches(is_separator);
+
+        let (name, rest) = match path.split_once(is_separator) {
+            Some((name, rest)) => (name, rest),
+            None => (path, ""),
+        };
+
+        let name = name.to_ascii_uppercase();
+        let entry = match self.entry_by_name(name.as_bytes()) {
+            Ok(e) => e,
+            Err(_) => self.entry_by_name(format!("{};1", name).as_bytes())?,
+        };
+
+        if rest.is_empty() {
+            Ok(entry.clone())
+        } else {
+            Directory::new(image, entry)?.lookup(image, rest)
+        }
+    }
+
     /// Retreive a list of all the entries in this directory
     #[allow(dead_code)]
     pub fn ls(&self) -> &[Entry] {
@@ -111,6 +136,7 @@ impl Directory {
 }
 
 /// A single directory entry
+#[derive(Clone)]
 pub struct Entry(Vec<u8>);
 
 impl Entry {
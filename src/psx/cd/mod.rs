@@ -1,5 +1,6 @@
 //! LLE CD implementation
 
+pub mod audio_rip;
 mod cdc;
 pub mod chd;
 pub mod disc;
@@ -3,7 +3,7 @@
 //! The timings code is copied from mednafen
 
 use super::cop0::Exception;
-use super::{cop0, map, AccessWidth, Addressable, CycleCount, Psx};
+use super::{cop0, exe, map, AccessWidth, Addressable, CycleCount, Psx};
 
 #[cfg(feature = "debugger")]
 use super::debugger;
@@ -115,6 +115,21 @@ impl Cpu {
         self.delay_slot = false;
     }
 
+    /// Jump to the entry point of a freshly loaded executable, setting up the GP register and,
+    /// if `sp` is not 0, the SP and FP registers.
+    pub fn jump_to_exe(&mut self, pc: u32, gp: u32, sp: u32) {
+        self.set_reg(RegisterIndex(28), gp);
+
+        if sp != 0 {
+            self.set_reg(RegisterIndex(29), sp);
+            self.set_reg(RegisterIndex(30), sp);
+        }
+
+        self.pc = pc;
+        self.next_pc = pc.wrapping_add(4);
+        self.branch = false;
+    }
+
     /// Returns true if the instruction currently being executed is in a delay slot
     pub fn in_delay_slot(&self) -> bool {
         self.delay_slot
@@ -298,6 +313,10 @@ pub fn run_next_instruction(psx: &mut Psx) {
     // So basically when a branch/jump is executed only `psx.cpu.next_pc` is modified, which means
     // that the value of the next instruction to be executed (pointed at by `psx.cpu.pc`) remains
     // in the pipeline. Thus the branch delay slot is emulated accurately.
+    if psx.cpu.pc == exe::SHELL_ENTRY_POINT && psx.exe_sideload.is_some() {
+        exe::sideload(psx);
+    }
+
     psx.cpu.current_pc = psx.cpu.pc;
     psx.cpu.pc = psx.cpu.next_pc;
     psx.cpu.next_pc = psx.cpu.pc.wrapping_add(4);
@@ -0,0 +1,289 @@
+//! Synchronization of the emulated display with the host's
+//!
+//! The PlayStation doesn't refresh at exactly 60Hz (or 50Hz for PAL consoles), so when the host
+//! display runs at a standard refresh rate the frontend has to either drop or repeat a frame every
+//! few seconds, which is very noticeable in scrolling games. When the emulated and host refresh
+//! rates are close enough we can instead run the emulation very slightly faster or slower so that
+//! we output exactly one frame per host refresh. The audio must then be resampled by the same
+//! ratio to keep the pitch (almost) unchanged and avoid buffer under or overruns.
+//!
+//! The host refresh rate is either reported by the frontend (libretro's target refresh rate) or
+//! measured from the interval between host frames (`requestAnimationFrame` timestamps in the
+//! browser).
+
+use std::time::Duration;
+
+/// By default we only lock to the host if that requires changing the emulation speed by less
+/// than 2%
+pub const DEFAULT_MAX_SKEW: f64 = 0.02;
+
+/// Number of host frames to measure before we trust the measured refresh rate
+const MIN_MEASURED_FRAMES: u32 = 30;
+
+/// Smoothing factor of the measured frame interval's moving average
+const MEASURE_SMOOTHING: f64 = 0.05;
+
+/// Number of consecutive intervals rejected as outliers after which we assume that the average
+/// itself is wrong (seeded from an outlier, or the host refresh rate changed) and start over
+const MAX_REJECTED_FRAMES: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DisplaySyncStats {
+    /// Host refresh rate in Hz, if known
+    pub host_refresh: Option<f64>,
+    /// Refresh rate of the emulated console in Hz
+    pub emulated_refresh: f64,
+    /// Emulation speed ratio currently applied (1.0 if not locked)
+    pub speed: f64,
+    /// True if we're currently locked to the host refresh rate
+    pub locked: bool,
+}
+
+pub struct DisplaySync {
+    enabled: bool,
+    /// When the host uses variable refresh rate the display adapts to us, there's nothing to do
+    vrr: bool,
+    /// Maximum relative speed change we're willing to apply
+    max_skew: f64,
+    /// Refresh rate reported by the frontend
+    reported_refresh: Option<f64>,
+    /// Moving average of the measured host frame interval, in seconds
+    measured_interval: f64,
+    /// Number of host frames measured so far
+    measured_frames: u32,
+    /// Number of consecutive host frames rejected as outliers
+    rejected_frames: u32,
+    /// Refresh rate of the emulated console
+    emulated_refresh: f64,
+}
+
+impl DisplaySync {
+    pub fn new() -> DisplaySync {
+        DisplaySync {
+            enabled: false,
+            vrr: false,
+            max_skew: DEFAULT_MAX_SKEW,
+            reported_refresh: None,
+            measured_interval: 0.,
+            measured_frames: 0,
+            rejected_frames: 0,
+            // NTSC, until told otherwise
+            emulated_refresh: 59.81,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_vrr_enabled(&mut self, enabled: bool) {
+        self.vrr = enabled;
+    }
+
+    pub fn set_max_skew(&mut self, max_skew: f64) {
+        self.max_skew = max_skew.max(0.);
+    }
+
+    /// Set the host refresh rate as reported by the frontend. A rate of 0 or less means that the
+    /// frontend doesn't know, in which case we fall back to measuring it.
+    pub fn set_refresh_rate(&mut self, rate: f32) {
+        self.reported_refresh = if rate > 0. {
+            Some(f64::from(rate))
+        } else {
+            None
+        };
+    }
+
+    /// Set the refresh rate of the emulated console, which depends on the video standard
+    pub fn set_emulated_refresh(&mut self, rate: f64) {
+        self.emulated_refresh = rate;
+    }
+
+    /// Called with the time elapsed between two host frames, used to measure the host refresh
+    /// rate when the frontend doesn't report it
+    pub fn update(&mut self, host_interval: Duration) {
+        let interval = host_interval.as_secs_f64();
+
+        if interval <= 0. {
+            return;
+        }
+
+        if self.measured_frames == 0 {
+            self.measured_interval = interval;
+        } else {
+            // Ignore the hiccups (dropped frames, tab in the background...) that would otherwise
+            // throw off the average
+            let ratio = interval / self.measured_interval;
+            if !(0.75..=1.25).contains(&ratio) {
+                self.rejected_frames += 1;
+
+                if self.rejected_frames >= MAX_REJECTED_FRAMES {
+                    // Not a hiccup, re-seed the average from the current interval
+                    self.measured_interval = interval;
+                    self.measured_frames = 1;
+                    self.rejected_frames = 0;
+                }
+
+                return;
+            }
+
+            self.measured_interval += (interval - self.measured_interval) * MEASURE_SMOOTHING;
+        }
+
+        self.rejected_frames = 0;
+        self.measured_frames = self.measured_frames.saturating_add(1);
+    }
+
+    /// Forget the measured host refresh rate, for instance because the window moved to another
+    /// display
+    pub fn reset_measurement(&mut self) {
+        self.measured_interval = 0.;
+        self.measured_frames = 0;
+        self.rejected_frames = 0;
+    }
+
+    /// Returns the host refresh rate, if known
+    pub fn host_refresh(&self) -> Option<f64> {
+        match self.reported_refresh {
+            Some(r) => Some(r),
+            None if self.measured_frames >= MIN_MEASURED_FRAMES => {
+                Some(1. / self.measured_interval)
+            }
+            None => None,
+        }
+    }
+
+    /// Returns the ratio by which the emulation must be sped up (or slowed down if less than 1)
+    /// to lock to the host refresh rate, or 1 if we can't or shouldn't lock
+    pub fn speed(&self) -> f64 {
+        if !self.enabled || self.vrr || self.emulated_refresh <= 0. {
+            return 1.;
+        }
+
+        let host = match self.host_refresh() {
+            Some(h) => h,
+            None => return 1.,
+        };
+
+        let speed = host / self.emulated_refresh;
+
+        if (speed - 1.).abs() <= self.max_skew {
+            speed
+        } else {
+            // Too far off (PAL game on a 60Hz display for instance), let the frontend deal with
+            // it
+            1.
+        }
+    }
+
+    /// Returns the rate at which we output frames, in Hz
+    pub fn output_refresh(&self) -> f64 {
+        self.emulated_refresh * self.speed()
+    }
+
+    pub fn stats(&self) -> DisplaySyncStats {
+        let speed = self.speed();
+
+        DisplaySyncStats {
+            host_refresh: self.host_refresh(),
+            emulated_refresh: self.emulated_refresh,
+            speed,
+            locked: speed != 1.,
+        }
+    }
+}
+
+impl Default for DisplaySync {
+    fn default() -> DisplaySync {
+        DisplaySync::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_to_reported_refresh() {
+        let mut ds = DisplaySync::new();
+        ds.set_emulated_refresh(59.81);
+        ds.set_refresh_rate(60.);
+
+        // Disabled by default
+        assert_eq!(ds.speed(), 1.);
+
+        ds.set_enabled(true);
+        assert!((ds.speed() - 60. / 59.81).abs() < 1e-9);
+        assert!((ds.output_refresh() - 60.).abs() < 1e-9);
+        assert!(ds.stats().locked);
+
+        // PAL on a 60Hz display is too far off
+        ds.set_emulated_refresh(49.76);
+        assert_eq!(ds.speed(), 1.);
+        assert!(!ds.stats().locked);
+
+        // No need to do anything with VRR
+        ds.set_emulated_refresh(59.81);
+        ds.set_vrr_enabled(true);
+        assert_eq!(ds.speed(), 1.);
+    }
+
+    #[test]
+    fn measured_refresh() {
+        let mut ds = DisplaySync::new();
+        ds.set_enabled(true);
+        ds.set_emulated_refresh(59.81);
+
+        let frame = Duration::from_secs_f64(1. / 60.);
+
+        for _ in 0..MIN_MEASURED_FRAMES - 1 {
+            ds.update(frame);
+        }
+        assert_eq!(ds.host_refresh(), None);
+
+        // A dropped frame must not disturb the measurement
+        ds.update(frame * 2);
+        ds.update(frame);
+
+        let host = ds.host_refresh().unwrap();
+        assert!((host - 60.).abs() < 0.01, "{}", host);
+        assert!(ds.stats().locked);
+
+        // A reported rate takes precedence
+        ds.set_refresh_rate(75.);
+        assert_eq!(ds.host_refresh(), Some(75.));
+        ds.set_refresh_rate(0.);
+        assert!((ds.host_refresh().unwrap() - 60.).abs() < 0.01);
+    }
+
+    #[test]
+    fn measured_refresh_outlier_first() {
+        let mut ds = DisplaySync::new();
+
+        let frame = Duration::from_secs_f64(1. / 60.);
+
+        // The very first interval is often bogus (page load, window creation...)
+        ds.update(Duration::from_millis(250));
+
+        for _ in 0..MAX_REJECTED_FRAMES + MIN_MEASURED_FRAMES {
+            ds.update(frame);
+        }
+
+        let host = ds.host_refresh().unwrap();
+        assert!((host - 60.).abs() < 0.01, "{}", host);
+
+        // Same thing if the host refresh rate changes for good
+        let frame = Duration::from_secs_f64(1. / 144.);
+
+        for _ in 0..MAX_REJECTED_FRAMES + MIN_MEASURED_FRAMES {
+            ds.update(frame);
+        }
+
+        let host = ds.host_refresh().unwrap();
+        assert!((host - 144.).abs() < 0.01, "{}", host);
+    }
+}
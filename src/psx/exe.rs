@@ -0,0 +1,164 @@
+//! PS-X EXE executables and sideloading
+//!
+//! Executables are loaded the same way the BIOS would load them from a CD: we let the BIOS boot
+//! normally and when it's about to jump into the shell we copy the executable to RAM and jump to
+//! its entry point instead. At this point the kernel is fully initialized so the program can use
+//! all the BIOS functions.
+
+use super::cd::disc::Region;
+use super::Psx;
+use crate::error::{PsxError, Result};
+
+/// Address of the shell's entry point in RAM. The BIOS jumps there once it's done initializing
+/// the kernel.
+pub const SHELL_ENTRY_POINT: u32 = 0x8003_0000;
+
+/// Size of the EXE header, the program text starts right after it
+const HEADER_SIZE: usize = 0x800;
+
+/// Size of the main RAM
+const RAM_SIZE: u32 = 2 * 1024 * 1024;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Exe {
+    /// Entry point
+    pub pc: u32,
+    /// Initial value of the GP register
+    pub gp: u32,
+    /// Initial value of the SP and FP registers. If 0 we keep the value set by the BIOS.
+    pub sp: u32,
+    /// Address the text is loaded at
+    pub load_addr: u32,
+    /// Program text and data
+    pub text: Vec<u8>,
+    /// Start and length of the BSS section, cleared before starting the program
+    pub bss: (u32, u32),
+    /// Region from the license string in the header, if any
+    pub region: Option<Region>,
+}
+
+impl Exe {
+    pub fn parse(data: &[u8]) -> Result<Exe> {
+        if data.len() < HEADER_SIZE || &data[0..8] != b"PS-X EXE" {
+            return Err(PsxError::BadExe("missing PS-X EXE header".to_string()));
+        }
+
+        let word = |off: usize| {
+            u32::from_le_bytes([data[off], data[off + 1], data[off + 2], data[off + 3]])
+        };
+
+        let pc = word(0x10);
+        let gp = word(0x14);
+        let load_addr = word(0x18);
+        let text_size = word(0x1c) as usize;
+        let bss = (word(0x28), word(0x2c));
+        let stack_base = word(0x30);
+        let stack_size = word(0x34);
+
+        // Some tools don't bother aligning the text size, just load whatever is there
+        let text_end = (HEADER_SIZE + text_size).min(data.len());
+        let text = data[HEADER_SIZE..text_end].to_vec();
+
+        if text.len() as u32 > RAM_SIZE {
+            return Err(PsxError::BadExe(format!(
+                "text is too big ({} bytes)",
+                text.len()
+            )));
+        }
+
+        // Make sure that the BSS fits in RAM, we don't want a bogus header to have us clear
+        // gigabytes of memory
+        if u64::from(ram_offset(bss.0)) + u64::from(bss.1) > u64::from(RAM_SIZE) {
+            return Err(PsxError::BadExe(format!(
+                "BSS doesn't fit in RAM (0x{:08x}, {} bytes)",
+                bss.0, bss.1
+            )));
+        }
+
+        let sp = if stack_base != 0 {
+            stack_base.wrapping_add(stack_size)
+        } else {
+            0
+        };
+
+        let license = &data[0x4c..HEADER_SIZE];
+        let region = if find(license, b"North America") {
+            Some(Region::NorthAmerica)
+        } else if find(license, b"Japan") {
+            Some(Region::Japan)
+        } else if find(license, b"Europe") {
+            Some(Region::Europe)
+        } else {
+            None
+        };
+
+        Ok(Exe {
+            pc,
+            gp,
+            sp,
+            load_addr,
+            text,
+            bss,
+            region,
+        })
+    }
+
+    /// Load `other`'s text on top of ours, growing our text to cover both. The registers and
+    /// region stay untouched.
+    pub fn overlay(&mut self, other: &Exe) {
+        let start = ram_offset(self.load_addr).min(ram_offset(other.load_addr));
+        let end = (ram_offset(self.load_addr) + self.text.len() as u32)
+            .max(ram_offset(other.load_addr) + other.text.len() as u32)
+            .min(RAM_SIZE);
+
+        let mut text = vec![0; (end - start) as usize];
+
+        for exe in [&*self, other] {
+            let off = (ram_offset(exe.load_addr) - start) as usize;
+            let len = exe.text.len().min(text.len() - off);
+
+            text[off..off + len].copy_from_slice(&exe.text[..len]);
+        }
+
+        // Keep the original segment (KUSEG/KSEG0/KSEG1) but point at the new start
+        self.load_addr = (self.load_addr & !(RAM_SIZE - 1)) | start;
+        self.text = text;
+    }
+}
+
+/// Returns true if `needle` appears in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn ram_offset(addr: u32) -> u32 {
+    addr & (RAM_SIZE - 1)
+}
+
+/// Called when the CPU is about to execute the shell: load the pending EXE and jump to it
+pub fn sideload(psx: &mut Psx) {
+    let exe = match psx.exe_sideload.take() {
+        Some(exe) => exe,
+        None => return,
+    };
+
+    info!(
+        "Sideloading EXE: {} bytes at 0x{:08x}, entry point 0x{:08x}",
+        exe.text.len(),
+        exe.load_addr,
+        exe.pc
+    );
+
+    let base = ram_offset(exe.load_addr);
+
+    for (i, &b) in exe.text.iter().enumerate() {
+        psx.xmem.ram_store(base + i as u32, b);
+    }
+
+    let (bss_start, bss_len) = exe.bss;
+    for i in 0..bss_len {
+        psx.xmem.ram_store(ram_offset(bss_start) + i, 0u8);
+    }
+
+    psx.cpu.jump_to_exe(exe.pc, exe.gp, exe.sp);
+}
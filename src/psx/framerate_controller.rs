@@ -0,0 +1,69 @@
+//! Framerate cap
+//!
+//! Decides which emulated frames are presented to the host when the output framerate is capped
+//! below the display refresh rate (to save power on battery for instance). The emulation itself
+//! still runs at full speed, we only skip presenting some frames.
+
+pub struct FramerateController {
+    /// Target number of presented frames per second
+    target_fps: f32,
+    /// Refresh rate of the display
+    refresh_rate: f32,
+    /// Accumulated fraction of a frame we're allowed to present
+    credit: f32,
+    /// Number of frames presented since the controller was created
+    presented: u64,
+    /// Number of frames skipped since the controller was created
+    skipped: u64,
+}
+
+impl FramerateController {
+    pub fn new() -> FramerateController {
+        FramerateController {
+            target_fps: 60.,
+            refresh_rate: 60.,
+            credit: 0.,
+            presented: 0,
+            skipped: 0,
+        }
+    }
+
+    pub fn set_target_fps(&mut self, fps: f32) {
+        self.target_fps = fps.max(1.);
+    }
+
+    pub fn set_refresh_rate(&mut self, rate: f32) {
+        if rate > 0. {
+            self.refresh_rate = rate;
+        }
+    }
+
+    /// Must be called once per emulated frame, returns true if this frame should be presented. If
+    /// it is `frame_presented` must then be called.
+    pub fn should_present_frame(&mut self) -> bool {
+        self.credit = (self.credit + self.target_fps / self.refresh_rate).min(2.);
+
+        if self.credit >= 1. {
+            true
+        } else {
+            self.skipped += 1;
+            false
+        }
+    }
+
+    pub fn frame_presented(&mut self) {
+        self.credit -= 1.;
+        self.presented += 1;
+    }
+
+    /// Returns the number of (presented, skipped) frames
+    pub fn frame_counts(&self) -> (u64, u64) {
+        (self.presented, self.skipped)
+    }
+}
+
+impl Default for FramerateController {
+    fn default() -> FramerateController {
+        FramerateController::new()
+    }
+}
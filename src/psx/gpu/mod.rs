@@ -45,9 +45,9 @@ mod frame_interpolation_tests;
 mod pixel_scaling_tests;
 
 use super::cpu::CPU_FREQ_HZ;
-use super::{irq, sync, timers, AccessWidth, Addressable, CycleCount, Psx};
+use super::{irq, pad_memcard, sync, timers, AccessWidth, Addressable, CycleCount, Psx};
 use commands::{Command, Position};
-pub use rasterizer::{Frame, Pixel, RasterizerOption};
+pub use rasterizer::{CropMode, DeinterlaceMode, Frame, OverscanCrop, Pixel, RasterizerOption};
 use error_handler::{GpuCommandError, ErrorRecoveryAction, report_gpu_error, check_vram_bounds, check_clut_bounds};
 use debug_overlay::{DebugOverlay, DebugOverlayConfig};
 use crate::frame_pacing::FramePacer;
@@ -55,8 +55,10 @@ pub use crate::frame_pacing::{DisplayMode, DisplayCapabilities, FramePacingStats
 use texture_replacement::{TextureReplacementSystem, TextureReplacementConfig};
 use shader_manager::{ShaderManager, DrawState, ShaderHandle};
 use memory_prefetch::{GpuMemoryPrefetcher, PrefetchConfig, MemoryAccess, AccessType};
+use super::mdec::enhanced::MacroblockCache;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
 use frame_interpolation::{FrameInterpolator, InterpolationConfig, InterpolationMode};
 use crt_beam_renderer::{CrtBeamRenderer, CrtBeamConfig};
 use pixel_scaling::{PixelScalingSystem, ScalingConfig, FilterType, FilterChainEditor};
@@ -269,6 +271,16 @@ impl Gpu {
         self.video_standard
     }
 
+    /// Returns the current position of the picture in the video signal
+    pub fn display_area(&self) -> DisplayArea {
+        DisplayArea {
+            mode: self.display_mode,
+            clock_hz: gpu_freq_hz(self.video_standard),
+            columns: (self.display_column_start, self.display_column_end),
+            lines: (self.display_line_start, self.display_line_end),
+        }
+    }
+
     pub fn take_frame(&mut self) -> Option<Frame> {
         // If we were waiting for a VRAM read we must fetch it before attempting to recover a
         // frame, otherwise we'll receive the VRAM read from the rasterizer and think that it's a
@@ -290,6 +302,11 @@ impl Gpu {
     pub fn set_upscale_shift(&mut self, shift: u8) {
         self.set_rasterizer_option(RasterizerOption::UpscaleShift(shift));
     }
+
+    /// Cache the MDEC must store its macroblocks in for the enhanced FMV output
+    pub fn mdec_macroblock_cache(&self) -> Arc<MacroblockCache> {
+        self.rasterizer.macroblock_cache()
+    }
     
     /// Enable or disable the debug overlay
     pub fn set_debug_overlay_enabled(&mut self, enabled: bool) {
@@ -861,6 +878,24 @@ impl Gpu {
         (gpu_cycles / FRACTIONAL_FACTOR) as CycleCount
     }
 
+    /// Returns the number of CPU cycles it takes for `gpu_cycles` GPU cycles to elapse, taking the
+    /// `remaining_fractional_cycles` into account. Rounds *up* since it's used to schedule events
+    /// and we want to be called when the event has occurred, not just before.
+    fn gpu_to_cpu_cycles(&self, gpu_cycles: CycleCount) -> CycleCount {
+        let mut delta = gpu_cycles as u64 * FRACTIONAL_FACTOR;
+        // Don't forget the fractional cycle we have leftover
+        delta = delta.saturating_sub(u64::from(self.remaining_fractional_cycles));
+
+        // Divide by the frequency factor, rounding up. Remember that in order to divide `x` by `y`
+        // rounding up you need to do `(x + y - 1) / y`.
+        let clock_ratio = match self.video_standard {
+            VideoStandard::Ntsc => GPU_CYCLES_PER_CPU_CYCLES_NTSC,
+            VideoStandard::Pal => GPU_CYCLES_PER_CPU_CYCLES_PAL,
+        };
+
+        ((delta + clock_ratio - 1) / clock_ratio) as CycleCount
+    }
+
     /// Returns the total length of a line (including horizontal blanking)
     fn line_length(&self) -> u16 {
         match self.display_mode.standard() {
@@ -968,6 +1003,7 @@ pub fn run(psx: &mut Psx) {
         } else {
             // We reached the EOL
             handle_eol(psx);
+            schedule_light_pen(psx, elapsed_gpu_cycles);
         }
     }
 
@@ -975,19 +1011,7 @@ pub fn run(psx: &mut Psx) {
 
     // New we need to program the next sync at `cycles_to_line_event`. Where it gets tricky is that
     // we program sync events based on the CPU clock, so we need to do the conversion
-    let mut delta = psx.gpu.cycles_to_line_event as u64 * FRACTIONAL_FACTOR;
-    // Don't forget the fractional cycle we have leftover
-    delta -= u64::from(psx.gpu.remaining_fractional_cycles);
-
-    // Finally divide by the frequency factor, rounding *up* (we want to be called when the event
-    // has occurred, not just before). Remember that in order to divide `x` by `y` rounding up you
-    // need to do `(x + y - 1) / y`.
-    let clock_ratio = match psx.gpu.video_standard {
-        VideoStandard::Ntsc => GPU_CYCLES_PER_CPU_CYCLES_NTSC,
-        VideoStandard::Pal => GPU_CYCLES_PER_CPU_CYCLES_PAL,
-    };
-
-    delta = (delta + clock_ratio - 1) / clock_ratio;
+    let mut delta = psx.gpu.gpu_to_cpu_cycles(psx.gpu.cycles_to_line_event);
 
     if delta < 1 {
         delta = 1;
@@ -998,7 +1022,22 @@ pub fn run(psx: &mut Psx) {
         delta = 128;
     }
 
-    sync::next_event(psx, GPUSYNC, delta as CycleCount);
+    sync::next_event(psx, GPUSYNC, delta);
+}
+
+/// Called at the beginning of a new line, `late` GPU cycles after the end of the HSYNC. If a light
+/// gun is aimed at this line we program the light pen interrupt for when the beam reaches it.
+fn schedule_light_pen(psx: &mut Psx, late: CycleCount) {
+    let column = match psx.pad_memcard.light_pen_column(psx.gpu.cur_line) {
+        Some(c) => c,
+        None => return,
+    };
+
+    // The columns are counted from the start of the HSYNC
+    let gpu_cycles = CycleCount::from(column) - HSYNC_LEN_CYCLES - late;
+    let delay = psx.gpu.gpu_to_cpu_cycles(gpu_cycles.max(0));
+
+    pad_memcard::schedule_light_pen_irq(psx, delay);
 }
 
 pub fn dma_can_write(psx: &mut Psx) -> bool {
@@ -1055,6 +1094,10 @@ fn handle_eol(psx: &mut Psx) {
 
         psx.gpu.frame_drawn = false;
         psx.gpu.refresh_lines_per_field();
+
+        // Let the light guns know where the picture will be in this field
+        let area = psx.gpu.display_area();
+        psx.pad_memcard.set_display_area(&area);
     }
 
     if cur_line == psx.gpu.display_line_end && psx.gpu.display_active {
@@ -1642,6 +1685,21 @@ impl DisplayMode {
         }
     }
 
+    /// Number of GPU clock cycles per output pixel
+    pub fn dotclock_divider(self) -> u16 {
+        if (self.0 & (1 << 6)) != 0 {
+            7
+        } else {
+            match self.0 & 3 {
+                0 => 10,
+                1 => 8,
+                2 => 5,
+                3 => 4,
+                _ => unreachable!(),
+            }
+        }
+    }
+
     /// True if we output 24 bits per pixel
     pub fn output_24bpp(self) -> bool {
         self.0 & (1 << 4) != 0
@@ -1667,6 +1725,69 @@ impl DisplayMode {
     }
 }
 
+/// Position of the picture in the video signal, as configured by the display mode and the display
+/// range registers (GP1[0x06] and GP1[0x07]). Used by the light guns to convert a position on
+/// screen into a beam position.
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone)]
+pub struct DisplayArea {
+    pub mode: DisplayMode,
+    /// Frequency of the GPU clock the columns are counted in
+    pub clock_hz: f64,
+    /// First column of the picture and first column *not* in the picture, in GPU clock cycles
+    /// since the start of the HSYNC
+    pub columns: (u16, u16),
+    /// First line of the picture and first line *not* in the picture, counted from the VSYNC
+    pub lines: (u16, u16),
+}
+
+impl DisplayArea {
+    /// Returns the display area set up by the BIOS on a console using `standard`
+    pub fn new(standard: VideoStandard) -> DisplayArea {
+        let mut mode = DisplayMode::new();
+
+        let lines = match standard {
+            VideoStandard::Ntsc => (0x10, 0x10 + 240),
+            VideoStandard::Pal => {
+                mode.set(1 << 3);
+                (0x23, 0x23 + 288)
+            }
+        };
+
+        DisplayArea {
+            mode,
+            clock_hz: gpu_freq_hz(standard),
+            columns: (0x260, 0xc60),
+            lines,
+        }
+    }
+
+    /// Returns the beam position `(column, line)` when it draws the point at `x`, `y` in the
+    /// picture. The coordinates are normalized: `(0., 0.)` is the top-left corner of the picture
+    /// and `(1., 1.)` its bottom-right corner.
+    pub fn beam_position(&self, x: f32, y: f32) -> (u16, u16) {
+        let lerp = |(start, end): (u16, u16), v: f32| {
+            let len = f32::from(end.saturating_sub(start));
+
+            start + (v.clamp(0., 1.) * len) as u16
+        };
+
+        // Round down to the start of the pixel, that's when the dot is drawn
+        let start = self.columns.0;
+        let divider = self.mode.dotclock_divider();
+        let column = start + (lerp(self.columns, x) - start) / divider * divider;
+
+        (column, lerp(self.lines, y))
+    }
+}
+
+/// Returns the frequency of the GPU clock on a console using `standard`
+fn gpu_freq_hz(standard: VideoStandard) -> f64 {
+    match standard {
+        VideoStandard::Ntsc => GPU_FREQ_NTSC_HZ,
+        VideoStandard::Pal => GPU_FREQ_PAL_HZ,
+    }
+}
+
 /// Color depth modes for display output
 #[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum ColorDepth {
@@ -1681,7 +1802,7 @@ pub enum ColorDepth {
 }
 
 /// Wrapper around the Mask Setting register value (set by GP0[0xe6])
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone)]
 struct MaskSettings {
     /// Raw register value
     raw: u32,
@@ -0,0 +1,261 @@
+//! Deinterlacing of the 480i output.
+//!
+//! When the console outputs truly interlaced video only every other line of the frame is
+//! refreshed each field, the other lines still contain the previous field. Displaying that as-is
+//! ("weave") gives a perfect picture for still images but combing on anything that moves, and
+//! flickering in games which draw something different in each field.
+
+use super::super::Frame;
+
+/// Two pixels whose components differ by more than this amount are considered to be in motion
+const MOTION_THRESHOLD: u32 = 24;
+
+/// Deinterlacing method used for truly interlaced output
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum DeinterlaceMode {
+    /// Display both fields interleaved
+    #[default]
+    Weave,
+    /// Only display the last field, interpolating the missing lines
+    Bob,
+    /// Average both fields
+    Blend,
+    /// Weave still areas and bob areas in motion
+    Adaptive,
+}
+
+#[derive(Default)]
+pub struct Deinterlacer {
+    /// Previous woven frame, used for motion detection
+    previous: Frame,
+}
+
+impl Deinterlacer {
+    pub fn new() -> Deinterlacer {
+        Deinterlacer::default()
+    }
+
+    /// Deinterlace `frame`, which contains both fields interleaved. `bottom_field` is true if the
+    /// last field output was the bottom one. Each field line is `1 << upscale_shift` frame lines
+    /// high.
+    pub fn process(
+        &mut self,
+        mode: DeinterlaceMode,
+        frame: &mut Frame,
+        bottom_field: bool,
+        upscale_shift: u8,
+    ) {
+        let fields = Fields {
+            bottom: bottom_field,
+            line_height: 1 << upscale_shift,
+        };
+
+        match mode {
+            DeinterlaceMode::Weave => (),
+            DeinterlaceMode::Bob => bob(frame, fields),
+            DeinterlaceMode::Blend => blend(frame, fields),
+            DeinterlaceMode::Adaptive => self.adaptive(frame, fields),
+        }
+
+        if mode != DeinterlaceMode::Adaptive {
+            // Don't hold on to a stale frame
+            self.previous = Frame::default();
+        }
+    }
+
+    /// Bob the lines where the last field changed since the previous frame, weave elsewhere
+    fn adaptive(&mut self, frame: &mut Frame, fields: Fields) {
+        let woven = frame.clone();
+
+        if self.previous.width == frame.width && self.previous.height == frame.height {
+            let previous = &self.previous;
+
+            for y in (0..frame.height).filter(|&y| !fields.is_last(y)) {
+                let above = fields.above(y);
+                let below = fields.below(y, frame.height);
+
+                for x in 0..frame.width {
+                    let moved = |y: Option<u32>| match y {
+                        Some(y) => differs(woven.pixel(x, y), previous.pixel(x, y)),
+                        None => false,
+                    };
+
+                    if moved(above) || moved(below) {
+                        frame.set_pixel(
+                            x,
+                            y,
+                            interpolate(&woven, x, above, below, woven.pixel(x, y)),
+                        );
+                    }
+                }
+            }
+        }
+
+        self.previous = woven;
+    }
+}
+
+/// Frame line layout
+#[derive(Copy, Clone)]
+struct Fields {
+    /// True if the last field is the bottom one
+    bottom: bool,
+    /// Number of frame lines per field line
+    line_height: u32,
+}
+
+impl Fields {
+    /// Returns true if frame line `y` belongs to the last field output
+    fn is_last(&self, y: u32) -> bool {
+        ((y / self.line_height) & 1 != 0) == self.bottom
+    }
+
+    /// Returns the corresponding frame line in the field line above `y`, if any
+    fn above(&self, y: u32) -> Option<u32> {
+        y.checked_sub(self.line_height)
+    }
+
+    /// Returns the corresponding frame line in the field line below `y`, if any
+    fn below(&self, y: u32, height: u32) -> Option<u32> {
+        Some(y + self.line_height).filter(|&y| y < height)
+    }
+}
+
+/// Replace the lines of the previous field by interpolating the lines of the last field
+fn bob(frame: &mut Frame, fields: Fields) {
+    let woven = frame.clone();
+
+    for y in (0..frame.height).filter(|&y| !fields.is_last(y)) {
+        let above = fields.above(y);
+        let below = fields.below(y, frame.height);
+
+        for x in 0..frame.width {
+            frame.set_pixel(
+                x,
+                y,
+                interpolate(&woven, x, above, below, woven.pixel(x, y)),
+            );
+        }
+    }
+}
+
+/// Average each pair of top/bottom field lines
+fn blend(frame: &mut Frame, fields: Fields) {
+    let pair_height = fields.line_height * 2;
+
+    for top in (0..frame.height).filter(|&y| y % pair_height < fields.line_height) {
+        let bottom = top + fields.line_height;
+
+        if bottom >= frame.height {
+            break;
+        }
+
+        for x in 0..frame.width {
+            let p = average(frame.pixel(x, top), frame.pixel(x, bottom));
+
+            frame.set_pixel(x, top, p);
+            frame.set_pixel(x, bottom, p);
+        }
+    }
+}
+
+/// Interpolate column `x` between lines `above` and `below`, returns `default` if neither exists
+fn interpolate(frame: &Frame, x: u32, above: Option<u32>, below: Option<u32>, default: u32) -> u32 {
+    match (above, below) {
+        (Some(a), Some(b)) => average(frame.pixel(x, a), frame.pixel(x, b)),
+        (Some(y), None) | (None, Some(y)) => frame.pixel(x, y),
+        (None, None) => default,
+    }
+}
+
+/// Average two xRGB 8888 pixels
+fn average(a: u32, b: u32) -> u32 {
+    (a & b) + (((a ^ b) >> 1) & 0x7f7f_7f7f)
+}
+
+/// Returns true if any component of `a` and `b` differs by more than `MOTION_THRESHOLD`
+fn differs(a: u32, b: u32) -> bool {
+    (0..3).any(|c| {
+        let a = (a >> (c * 8)) & 0xff;
+        let b = (b >> (c * 8)) & 0xff;
+
+        a.abs_diff(b) > MOTION_THRESHOLD
+    })
+}
+
+#[cfg(test)]
+fn test_frame(lines: &[u32]) -> Frame {
+    let mut frame = Frame::new(2, lines.len() as u32);
+
+    for (y, &p) in lines.iter().enumerate() {
+        frame.set_pixel(0, y as u32, p);
+        frame.set_pixel(1, y as u32, p);
+    }
+
+    frame
+}
+
+#[cfg(test)]
+fn frame_lines(frame: &Frame) -> Vec<u32> {
+    (0..frame.height).map(|y| frame.pixel(0, y)).collect()
+}
+
+#[test]
+fn test_bob() {
+    let mut d = Deinterlacer::new();
+
+    // Last field is the top one, bottom field lines are interpolated
+    let mut frame = test_frame(&[0x00_0000, 0xff_ffff, 0x20_4060, 0xff_ffff, 0x40_4040]);
+    d.process(DeinterlaceMode::Bob, &mut frame, false, 0);
+    assert_eq!(
+        frame_lines(&frame),
+        [0x00_0000, 0x10_2030, 0x20_4060, 0x30_4050, 0x40_4040]
+    );
+
+    // Last field is the bottom one, the missing top and last lines are copied from the nearest
+    // line
+    let mut frame = test_frame(&[0xff_ffff, 0x10_1010, 0xff_ffff, 0x30_3030]);
+    d.process(DeinterlaceMode::Bob, &mut frame, true, 0);
+    assert_eq!(
+        frame_lines(&frame),
+        [0x10_1010, 0x10_1010, 0x20_2020, 0x30_3030]
+    );
+}
+
+#[test]
+fn test_blend() {
+    let mut d = Deinterlacer::new();
+
+    // Upscaled 2x: each field line is 2 frame lines high
+    let mut frame = test_frame(&[0x00_0000, 0x00_0000, 0x80_8080, 0x80_8080, 0x10_1010]);
+    d.process(DeinterlaceMode::Blend, &mut frame, true, 1);
+    assert_eq!(
+        frame_lines(&frame),
+        [0x40_4040, 0x40_4040, 0x40_4040, 0x40_4040, 0x10_1010]
+    );
+}
+
+#[test]
+fn test_adaptive() {
+    let mut d = Deinterlacer::new();
+
+    let still = [0x10_1010, 0x80_8080, 0x10_1010, 0x80_8080];
+
+    // No previous frame to compare with: weave
+    let mut frame = test_frame(&still);
+    d.process(DeinterlaceMode::Adaptive, &mut frame, false, 0);
+    assert_eq!(frame_lines(&frame), still);
+
+    // Nothing moved: weave
+    let mut frame = test_frame(&still);
+    d.process(DeinterlaceMode::Adaptive, &mut frame, false, 0);
+    assert_eq!(frame_lines(&frame), still);
+
+    // The top field line 2 changed, the bottom lines around it are interpolated
+    let mut frame = test_frame(&[0x10_1010, 0x80_8080, 0x50_5050, 0x80_8080]);
+    d.process(DeinterlaceMode::Adaptive, &mut frame, false, 0);
+    assert_eq!(
+        frame_lines(&frame),
+        [0x10_1010, 0x30_3030, 0x50_5050, 0x50_5050]
+    );
+}
@@ -0,0 +1,404 @@
+//! Placement of the display area in the output frames.
+//!
+//! The display area is configured by the game through GP1(06h) (horizontal range, in GPU clock
+//! cycles) and GP1(07h) (vertical range, in lines). By default the output frame contains exactly
+//! that area, but games can set ranges that would end up off-centre or partially hidden on a TV.
+//! Alternatively we can output the area that a typical TV would display and place the game's
+//! image in it, optionally centered, cropped by a configurable amount of overscan and with any
+//! black borders removed.
+
+use super::super::Frame;
+use crate::psx::gpu::{DisplayMode, VideoStandard};
+
+/// Number of frames over which we look for black borders before shrinking the displayed area
+const BORDER_DETECTION_FRAMES: u32 = 30;
+
+/// Pixels whose components are all below this value are considered black
+const BLACK_THRESHOLD: u32 = 0x10;
+
+/// How the output frames are cropped
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum CropMode {
+    /// Output the display area configured by the game
+    #[default]
+    DisplayArea,
+    /// Output the area visible on a typical TV, the parts not covered by the display area are
+    /// black
+    Overscan,
+    /// Same as `Overscan` then crop the borders that remain black for several frames
+    Borders,
+}
+
+/// Amount of overscan cropped on each edge of the frame, in percents
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct OverscanCrop {
+    pub ntsc: u8,
+    pub pal: u8,
+}
+
+/// User settings for the display area
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct DisplayAreaOptions {
+    pub crop_mode: CropMode,
+    pub overscan_crop: OverscanCrop,
+    /// If true we center the display area in the frame instead of using its position on the
+    /// screen. Has no effect in `CropMode::DisplayArea`.
+    pub auto_center: bool,
+}
+
+/// Area of the screen displayed on a typical TV
+struct Window {
+    /// First visible column, in GPU clock cycles
+    column_start: u16,
+    /// Number of visible GPU clock cycles per line
+    columns: u16,
+    /// First visible line
+    line_start: u16,
+    /// Number of visible lines per field
+    lines: u16,
+    /// Total number of lines per field
+    lines_per_field: u16,
+}
+
+impl Window {
+    fn new(standard: VideoStandard) -> Window {
+        match standard {
+            VideoStandard::Ntsc => Window {
+                column_start: 0x260,
+                columns: 2560,
+                line_start: 16,
+                lines: 240,
+                lines_per_field: 263,
+            },
+            VideoStandard::Pal => Window {
+                column_start: 0x274,
+                columns: 2560,
+                line_start: 20,
+                lines: 288,
+                lines_per_field: 314,
+            },
+        }
+    }
+}
+
+/// Position of the display area in the output frame. Horizontal values are in native pixels,
+/// vertical values in lines of a field.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Placement {
+    /// Width of the frame
+    pub width: u16,
+    /// Height of the frame
+    pub height: u16,
+    /// Position of the first pixel of the display area in the frame. Can be negative if the
+    /// display area extends past the left edge.
+    pub x: i32,
+    /// Position of the first line of the display area in the frame. Can be negative if the
+    /// display area extends past the top edge.
+    pub y: i32,
+    /// Number of pixels output for each line of the display area
+    pub active_width: u16,
+}
+
+impl Placement {
+    pub fn new(
+        options: &DisplayAreaOptions,
+        display_mode: DisplayMode,
+        columns: (u16, u16),
+        lines: (u16, u16),
+    ) -> Placement {
+        let standard = display_mode.standard();
+        let window = Window::new(standard);
+
+        let (column_start, column_end) = columns;
+        let (line_start, line_end) = lines;
+
+        // Lines past the end of the field are never output. That happens for instance when a PAL
+        // game runs with NTSC timings.
+        let line_end = line_end.min(window.lines_per_field);
+        let active_lines = line_end.saturating_sub(line_start);
+
+        let mut placement = match options.crop_mode {
+            CropMode::DisplayArea => {
+                let xres = display_mode.xres();
+
+                Placement {
+                    width: xres,
+                    height: active_lines,
+                    x: 0,
+                    y: 0,
+                    active_width: xres,
+                }
+            }
+            CropMode::Overscan | CropMode::Borders => {
+                let divider = display_mode.dotclock_divider();
+
+                let width = window.columns / divider;
+                let height = window.lines;
+
+                // The hardware rounds the number of pixels to a multiple of 4
+                let active_width = column_end.saturating_sub(column_start) / divider;
+                let active_width = (active_width + 2) & !3;
+
+                let (x, y) = if options.auto_center {
+                    (
+                        (i32::from(width) - i32::from(active_width)) / 2,
+                        (i32::from(height) - i32::from(active_lines)) / 2,
+                    )
+                } else {
+                    (
+                        (i32::from(column_start) - i32::from(window.column_start))
+                            / i32::from(divider),
+                        i32::from(line_start) - i32::from(window.line_start),
+                    )
+                };
+
+                Placement {
+                    width,
+                    height,
+                    x,
+                    y,
+                    active_width,
+                }
+            }
+        };
+
+        let crop = match standard {
+            VideoStandard::Ntsc => options.overscan_crop.ntsc,
+            VideoStandard::Pal => options.overscan_crop.pal,
+        };
+
+        placement.crop(crop);
+
+        placement
+    }
+
+    /// Crop `percent` of the frame on each edge
+    fn crop(&mut self, percent: u8) {
+        let percent = u32::from(percent.min(25));
+
+        let crop_x = (u32::from(self.width) * percent / 100) as u16;
+        let crop_y = (u32::from(self.height) * percent / 100) as u16;
+
+        self.width -= crop_x * 2;
+        self.height -= crop_y * 2;
+        self.x -= i32::from(crop_x);
+        self.y -= i32::from(crop_y);
+    }
+}
+
+/// Rectangle in frame pixels
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Rect {
+    left: u32,
+    top: u32,
+    right: u32,
+    bottom: u32,
+}
+
+impl Rect {
+    fn union(self, other: Rect) -> Rect {
+        Rect {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+
+    fn width(&self) -> u32 {
+        self.right - self.left
+    }
+
+    fn height(&self) -> u32 {
+        self.bottom - self.top
+    }
+}
+
+/// Detects the borders that remain black over several frames and crops them. The displayed area
+/// grows immediately when something is drawn in the borders, but only shrinks once nothing has
+/// been drawn outside of the new area for `BORDER_DETECTION_FRAMES`.
+#[derive(Default)]
+pub struct BorderDetector {
+    /// Area currently displayed, `None` to display the full frame
+    bounds: Option<Rect>,
+    /// Union of everything that's been drawn during the current detection period
+    seen: Option<Rect>,
+    /// Number of frames in the current detection period
+    frames: u32,
+    /// Dimensions of the frames, the detection restarts from scratch if they change
+    frame_size: (u32, u32),
+}
+
+impl BorderDetector {
+    pub fn new() -> BorderDetector {
+        BorderDetector::default()
+    }
+
+    /// Returns `frame` with its black borders cropped
+    pub fn process(&mut self, frame: Frame) -> Frame {
+        if (frame.width, frame.height) != self.frame_size {
+            *self = BorderDetector::new();
+            self.frame_size = (frame.width, frame.height);
+        }
+
+        if let Some(content) = content_bounds(&frame) {
+            if let Some(bounds) = self.bounds {
+                self.bounds = Some(bounds.union(content));
+            }
+
+            self.seen = Some(self.seen.map_or(content, |s| s.union(content)));
+        }
+
+        self.frames += 1;
+
+        if self.frames >= BORDER_DETECTION_FRAMES {
+            // Ignore the periods where only a small part of the frame is used (logos on a black
+            // background etc...), it's probably not a border
+            if let Some(seen) = self.seen.take() {
+                if seen.width() * 2 >= frame.width && seen.height() * 2 >= frame.height {
+                    self.bounds = Some(seen);
+                }
+            }
+
+            self.frames = 0;
+        }
+
+        match self.bounds {
+            Some(bounds) if bounds.width() != frame.width || bounds.height() != frame.height => {
+                crop(&frame, bounds)
+            }
+            _ => frame,
+        }
+    }
+}
+
+fn is_black(p: u32) -> bool {
+    let r = (p >> 16) & 0xff;
+    let g = (p >> 8) & 0xff;
+    let b = p & 0xff;
+
+    r < BLACK_THRESHOLD && g < BLACK_THRESHOLD && b < BLACK_THRESHOLD
+}
+
+/// Returns the smallest rectangle containing all the non-black pixels of `frame`, or `None` if
+/// the frame is completely black
+fn content_bounds(frame: &Frame) -> Option<Rect> {
+    let row_is_black = |y: u32| (0..frame.width).all(|x| is_black(frame.pixel(x, y)));
+
+    let top = (0..frame.height).find(|&y| !row_is_black(y))?;
+    let bottom = (top..frame.height).rev().find(|&y| !row_is_black(y))? + 1;
+
+    let column_is_black = |x: u32| (top..bottom).all(|y| is_black(frame.pixel(x, y)));
+
+    let left = (0..frame.width).find(|&x| !column_is_black(x))?;
+    let right = (left..frame.width).rev().find(|&x| !column_is_black(x))? + 1;
+
+    Some(Rect {
+        left,
+        top,
+        right,
+        bottom,
+    })
+}
+
+fn crop(frame: &Frame, rect: Rect) -> Frame {
+    let mut cropped = Frame::new(rect.width(), rect.height());
+
+    for y in 0..rect.height() {
+        for x in 0..rect.width() {
+            cropped.set_pixel(x, y, frame.pixel(rect.left + x, rect.top + y));
+        }
+    }
+
+    cropped
+}
+
+#[cfg(test)]
+fn display_mode(mode: u32) -> DisplayMode {
+    let mut display_mode = DisplayMode::new();
+
+    display_mode.set(mode);
+
+    display_mode
+}
+
+#[test]
+fn test_display_area() {
+    let options = DisplayAreaOptions::default();
+
+    // PAL display range with NTSC timings: the lines past the end of the field are never output
+    let p = Placement::new(&options, display_mode(1), (0x260, 0xc60), (0x23, 0x133));
+
+    assert_eq!(p.width, 320);
+    assert_eq!(p.height, 263 - 0x23);
+    assert_eq!((p.x, p.y), (0, 0));
+}
+
+#[test]
+fn test_overscan() {
+    let mut options = DisplayAreaOptions {
+        crop_mode: CropMode::Overscan,
+        ..DisplayAreaOptions::default()
+    };
+
+    // 320x224 display area shifted to the right and down
+    let p = Placement::new(&options, display_mode(1), (0x2a0, 0xca0), (0x20, 0x100));
+    assert_eq!((p.width, p.height, p.active_width), (320, 240, 320));
+    assert_eq!((p.x, p.y), (8, 16));
+
+    // Same area centered
+    options.auto_center = true;
+    let p = Placement::new(&options, display_mode(1), (0x2a0, 0xca0), (0x20, 0x100));
+    assert_eq!((p.x, p.y), (0, 8));
+
+    // Narrow 256 pixel area in 320 pixel mode, centered and cropped by 5% of each edge
+    options.overscan_crop = OverscanCrop { ntsc: 5, pal: 0 };
+    let p = Placement::new(&options, display_mode(1), (0x2a0, 0xaa0), (0x10, 0x100));
+    assert_eq!((p.width, p.height, p.active_width), (288, 216, 256));
+    assert_eq!((p.x, p.y), (16, -12));
+
+    // The NTSC crop doesn't apply to PAL
+    let p = Placement::new(
+        &options,
+        display_mode(1 | 1 << 3),
+        (0x274, 0xc74),
+        (0x23, 0x133),
+    );
+    assert_eq!((p.width, p.height, p.active_width), (320, 288, 320));
+    assert_eq!((p.x, p.y), (0, 8));
+}
+
+#[test]
+fn test_border_detector() {
+    let mut detector = BorderDetector::new();
+
+    // 16x8 frame with the content in the 8x4 center
+    let mut frame = Frame::new(16, 8);
+    for y in 2..6 {
+        for x in 4..12 {
+            frame.set_pixel(x, y, 0x80_8080);
+        }
+    }
+
+    // Nothing is cropped until we've seen enough frames
+    for _ in 1..BORDER_DETECTION_FRAMES {
+        assert_eq!(detector.process(frame.clone()).width, 16);
+    }
+
+    let cropped = detector.process(frame.clone());
+    assert_eq!((cropped.width, cropped.height), (8, 4));
+    assert!(cropped.pixels.iter().all(|&p| p == 0x80_8080));
+
+    // Something is drawn in the border, grow the area immediately
+    frame.set_pixel(1, 3, 0xff_ffff);
+    let cropped = detector.process(frame.clone());
+    assert_eq!((cropped.width, cropped.height), (11, 4));
+    assert_eq!(cropped.pixel(0, 1), 0xff_ffff);
+
+    // Small logo on black background: don't zoom in
+    let mut logo = Frame::new(16, 8);
+    logo.set_pixel(8, 4, 0xff_ffff);
+    for _ in 0..(BORDER_DETECTION_FRAMES * 2) {
+        assert_eq!(detector.process(logo.clone()).width, 11);
+    }
+}
@@ -150,7 +150,7 @@ impl Mul<i32> for FpCoord {
 /// drawing algorithm is really implemented).
 ///
 /// Mednafen uses 12bits for the fractional part
-const FP_VAR_SHIFT: u32 = 12;
+pub const FP_VAR_SHIFT: u32 = 12;
 
 /// Fixed point representation of an interpolated variable (i.e. Gouraud shading color component or
 /// texture sampling coordinate)
@@ -178,6 +178,11 @@ impl FpVar {
     pub fn truncate(self) -> i32 {
         self.0 >> FP_VAR_SHIFT
     }
+
+    /// Returns the raw fixed point value
+    pub fn raw(self) -> i32 {
+        self.0
+    }
 }
 
 impl Add for FpVar {
@@ -0,0 +1,247 @@
+//! Redraw of the movie frames uploaded to the VRAM at the internal resolution, from the full
+//! precision macroblocks recorded by the MDEC (see `psx::mdec::enhanced`)
+//!
+//! The top-left subpixel of every native pixel is what the console reads back (VRAM loads,
+//! texture fetches, save states) so it's left alone: only the other subpixels are redrawn.
+
+use super::{Pixel, VRam};
+use crate::psx::mdec::enhanced::{
+    macroblock_key, DecodedMacroblock, MacroblockCache, MacroblockGrid, MdecEnhancement,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Upper bound on the number of redrawn macroblocks we remember
+const MAX_PLACED: usize = 4096;
+
+/// Pixels of a VRAM store, kept to look for the macroblocks decoded by the MDEC
+#[derive(Debug)]
+pub struct Upload {
+    left: u16,
+    top: u16,
+    width: u16,
+    height: u16,
+    /// Raw 16bpp pixels, line by line
+    pixels: Vec<u16>,
+}
+
+impl Upload {
+    /// Returns `None` if the store can't contain a full macroblock or wraps around the VRAM
+    pub fn new(left: u16, top: u16, width: u16, height: u16) -> Option<Upload> {
+        if width < 16 || height < 16 || left + width > 1024 || top + height > 512 {
+            return None;
+        }
+
+        Some(Upload {
+            left,
+            top,
+            width,
+            height,
+            pixels: Vec::with_capacity(usize::from(width) * usize::from(height)),
+        })
+    }
+
+    pub fn push(&mut self, p: u16) {
+        self.pixels.push(p);
+    }
+
+    /// Returns the pixels of the macroblock at `column`, `row` in the upload, line by line
+    fn macroblock(&self, column: usize, row: usize) -> [u16; 16 * 16] {
+        let width = usize::from(self.width);
+        let mut pixels = [0; 16 * 16];
+
+        for y in 0..16 {
+            let o = (row * 16 + y) * width + column * 16;
+
+            pixels[y * 16..y * 16 + 16].copy_from_slice(&self.pixels[o..o + 16]);
+        }
+
+        pixels
+    }
+}
+
+/// Macroblock redrawn at the internal resolution
+struct Placed {
+    block: Arc<DecodedMacroblock>,
+    /// `checksum` of the redrawn pixels, to detect when they get overwritten
+    checksum: u64,
+}
+
+pub struct FmvRedraw {
+    cache: Option<Arc<MacroblockCache>>,
+    settings: Option<MdecEnhancement>,
+    /// Recently redrawn macroblocks, by VRAM position of their top-left corner. Used to filter
+    /// across the macroblocks of successive uploads since games often upload the frames one
+    /// column of macroblocks at a time.
+    placed: HashMap<(u16, u16), Placed>,
+}
+
+impl FmvRedraw {
+    pub fn new() -> FmvRedraw {
+        FmvRedraw {
+            cache: None,
+            settings: None,
+            placed: HashMap::new(),
+        }
+    }
+
+    pub fn set_cache(&mut self, cache: Arc<MacroblockCache>) {
+        self.cache = Some(cache);
+    }
+
+    pub fn set_settings(&mut self, settings: Option<MdecEnhancement>) {
+        self.settings = settings;
+        self.placed.clear();
+    }
+
+    /// True if the VRAM stores should be checked for decoded macroblocks
+    pub fn is_enabled(&self) -> bool {
+        self.settings.is_some() && self.cache.is_some()
+    }
+
+    /// Redraw the macroblocks decoded by the MDEC found in `upload`, which must just have been
+    /// stored to `vram`
+    pub fn redraw(&mut self, vram: &mut VRam, upload: &Upload) {
+        let (settings, cache) = match (self.settings, &self.cache) {
+            (Some(s), Some(c)) => (s, c.clone()),
+            _ => return,
+        };
+
+        // At the native resolution every pixel is seen by the console, there's nothing we can
+        // redraw
+        if vram.upscale_shift == 0 {
+            return;
+        }
+
+        let columns = usize::from(upload.width / 16);
+        let rows = usize::from(upload.height / 16);
+
+        // We keep a margin of one macroblock around the upload for the neighbours redrawn
+        // previously
+        let grid_columns = columns + 2;
+        let grid_rows = rows + 2;
+        let mut blocks = vec![None; grid_columns * grid_rows];
+        let mut found = false;
+
+        for row in 0..rows {
+            for column in 0..columns {
+                let pixels = upload.macroblock(column, row);
+                let x = upload.left + (column * 16) as u16;
+                let y = upload.top + (row * 16) as u16;
+
+                // Pixels protected by the mask bit haven't been stored
+                if !is_stored(vram, x, y, &pixels) {
+                    continue;
+                }
+
+                if let Some(b) = cache.get(macroblock_key(&pixels)) {
+                    blocks[(row + 1) * grid_columns + column + 1] = Some(b);
+                    found = true;
+                }
+            }
+        }
+
+        if !found {
+            return;
+        }
+
+        for row in 0..grid_rows {
+            for column in 0..grid_columns {
+                if row != 0 && row != grid_rows - 1 && column != 0 && column != grid_columns - 1 {
+                    continue;
+                }
+
+                let x = i32::from(upload.left) + (column as i32 - 1) * 16;
+                let y = i32::from(upload.top) + (row as i32 - 1) * 16;
+
+                if x < 0 || y < 0 || x + 16 > 1024 || y + 16 > 512 {
+                    continue;
+                }
+
+                let pos = (x as u16, y as u16);
+
+                if let Some(p) = self.placed.get(&pos) {
+                    if p.checksum == checksum(vram, pos.0, pos.1) {
+                        blocks[row * grid_columns + column] = Some(p.block.clone());
+                    }
+                }
+            }
+        }
+
+        let grid = MacroblockGrid::new(grid_columns, grid_rows, blocks, settings.deblocking);
+
+        if self.placed.len() > MAX_PLACED {
+            self.placed.clear();
+        }
+
+        let scale = 1usize << vram.upscale_shift;
+
+        // The neighbours are redrawn as well since their edges now have something to be
+        // filtered with
+        for row in 0..grid_rows {
+            for column in 0..grid_columns {
+                let block = match grid.get(column, row) {
+                    Some(b) => b.clone(),
+                    None => continue,
+                };
+
+                let x = (i32::from(upload.left) + (column as i32 - 1) * 16) as u16;
+                let y = (i32::from(upload.top) + (row as i32 - 1) * 16) as u16;
+                let x_start = u32::from(x) << vram.upscale_shift;
+                let y_start = u32::from(y) << vram.upscale_shift;
+
+                grid.render(column, row, scale, settings.chroma, |ox, oy, [r, g, b]| {
+                    if ox % scale == 0 && oy % scale == 0 {
+                        // Native pixel
+                        return;
+                    }
+
+                    let x = x_start + ox as u32;
+                    let y = y_start + oy as u32;
+
+                    let mut p = Pixel::from_rgb(r, g, b);
+                    if vram.pixel(x, y).mask() {
+                        p.set_mask();
+                    }
+
+                    vram.set_pixel(x, y, p);
+                });
+
+                let checksum = checksum(vram, x, y);
+                self.placed.insert((x, y), Placed { block, checksum });
+            }
+        }
+    }
+}
+
+impl Default for FmvRedraw {
+    fn default() -> FmvRedraw {
+        FmvRedraw::new()
+    }
+}
+
+/// Returns true if the 16x16 `pixels` at `x`, `y` in `vram` have been stored
+fn is_stored(vram: &VRam, x: u16, y: u16, pixels: &[u16; 16 * 16]) -> bool {
+    pixels.iter().enumerate().all(|(i, &p)| {
+        let stored = vram.native_pixel(x + (i % 16) as u16, y + (i / 16) as u16);
+
+        stored.to_rgb888() == Pixel::from_mbgr1555(p).to_rgb888()
+    })
+}
+
+/// Hash of the internal resolution pixels of the 16x16 native pixels at `x`, `y`
+fn checksum(vram: &VRam, x: u16, y: u16) -> u64 {
+    let size = 16u32 << vram.upscale_shift;
+    let x = u32::from(x) << vram.upscale_shift;
+    let y = u32::from(y) << vram.upscale_shift;
+
+    let mut h = 0xcbf2_9ce4_8422_2325u64;
+
+    for y in y..y + size {
+        for x in x..x + size {
+            h = (h ^ u64::from(vram.pixel(x, y).0)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    h
+}
@@ -1,11 +1,16 @@
+mod deinterlace;
+mod display_area;
 mod fixed_point;
+mod fmv;
+mod parallel;
+mod simd;
 #[cfg(feature = "pgxp")]
 mod pgxp_renderer;
 
 #[cfg(test)]
 mod tests;
 
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 
 use super::{Command, CommandBuffer, Frame, RasterizerOption};
 use crate::psx::gpu::commands::{vram_access_dimensions, Shaded};
@@ -13,10 +18,14 @@ use crate::psx::gpu::commands::{NoShading, Position, Transparent};
 use crate::psx::gpu::commands::{NoTexture, Opaque, ShadingMode, TextureBlending, TextureRaw};
 use crate::psx::gpu::commands::{TextureMode, TransparencyMode};
 use crate::psx::gpu::texture_cache::GpuCache;
+use crate::psx::mdec::enhanced::MacroblockCache;
 use crate::VRamDisplayMode;
 
 use crate::psx::gpu::{ColorDepth, DisplayMode, DrawMode, MaskSettings, TextureWindow, TransparencyFunction};
 use fixed_point::{FpCoord, FpVar};
+
+pub use deinterlace::DeinterlaceMode;
+pub use display_area::{CropMode, OverscanCrop};
 use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
 use serde::ser::{Serialize, SerializeTuple, Serializer};
 use std::cmp::{max, min};
@@ -76,6 +85,15 @@ pub struct Rasterizer {
     display_vram_y_start: u16,
     /// True if the display is disabled,
     display_off: bool,
+    /// How the display area is placed in the output frames
+    #[serde(skip)]
+    display_area: display_area::DisplayAreaOptions,
+    /// Placement of the display area in the frame currently being drawn
+    #[serde(skip)]
+    placement: display_area::Placement,
+    /// Black border detection for `CropMode::Borders`
+    #[serde(skip)]
+    border_detector: display_area::BorderDetector,
     /// True to draw opaque pixel as semi-transparent
     force_transparency: bool,
     /// Dithering tables, used for dithering, 8-to-5bit color component truncation and saturation.
@@ -88,7 +106,7 @@ pub struct Rasterizer {
     ///   position.
     /// * `[_; 0x200]`: input value, from 0x000 to 0x1ff. Values above 0xff are saturated to 0xff
     #[serde(with = "serialize_dither_table")]
-    dither_table: [[[u8; 0x200]; 4]; 4],
+    dither_table: DitherTable,
     /// True if dithering is currently enabled
     dither_enabled: bool,
     /// If true we force disable dithering, regardless of the draw mode. Should probably only be
@@ -101,13 +119,36 @@ pub struct Rasterizer {
     draw_24bpp: bool,
     /// True if we're interlaced and display the bottom field
     display_bottom_field: bool,
+    /// True if the last line output belonged to the bottom field
+    #[serde(skip)]
+    output_bottom_field: bool,
+    /// Deinterlacing method used for truly interlaced output
+    #[serde(skip)]
+    deinterlace_mode: DeinterlaceMode,
+    /// Deinterlacing state
+    #[serde(skip)]
+    deinterlacer: deinterlace::Deinterlacer,
+    /// If true we draw and display both fields every frame when the game renders full frames in
+    /// interlaced mode
+    #[serde(skip)]
+    progressive_hack: bool,
     /// Draw the outline of triangles and quads
     draw_wireframe: bool,
     /// If false we don't draw triangles or quads
     draw_polygons: bool,
+    /// Triangles waiting to be drawn by the worker threads
+    #[serde(skip)]
+    batch: parallel::Batch,
+    /// Worker threads used to draw the batched triangles. `None` if we draw everything on the
+    /// rasterizer thread.
+    #[serde(skip)]
+    workers: Option<parallel::Workers>,
     /// Enhanced texture and CLUT cache
     #[serde(skip)]
     gpu_cache: GpuCache,
+    /// Redraw of the movies decoded by the MDEC at the internal resolution
+    #[serde(skip)]
+    fmv: fmv::FmvRedraw,
     /// PGXP-enhanced renderer
     #[cfg(feature = "pgxp")]
     #[serde(skip)]
@@ -137,15 +178,25 @@ impl Rasterizer {
             display_vram_x_start: 0,
             display_vram_y_start: 0,
             display_off: true,
+            display_area: display_area::DisplayAreaOptions::default(),
+            placement: display_area::Placement::default(),
+            border_detector: display_area::BorderDetector::new(),
             force_transparency: false,
             dither_table: [[[0; 0x200]; 4]; 4],
             dither_enabled: false,
             dithering_force_disable: false,
             draw_24bpp: false,
             display_bottom_field: false,
+            output_bottom_field: false,
+            deinterlace_mode: DeinterlaceMode::Weave,
+            deinterlacer: deinterlace::Deinterlacer::new(),
+            progressive_hack: false,
             draw_wireframe: false,
             draw_polygons: true,
+            batch: parallel::Batch::new(),
+            workers: None,
             gpu_cache: GpuCache::new(),
+            fmv: fmv::FmvRedraw::new(),
             #[cfg(feature = "pgxp")]
             pgxp_renderer: pgxp_renderer::PgxpRasterizer::new(),
         }
@@ -222,12 +273,14 @@ impl Rasterizer {
                                 }
                             }
                             State::VRamStore(ref mut store) => {
-                                let p0 = Pixel::from_mbgr1555(*v as u16);
-                                let p1 = Pixel::from_mbgr1555((*v >> 16) as u16);
-
-                                for &p in [p0, p1].iter() {
+                                for &raw in [*v as u16, (*v >> 16) as u16].iter() {
+                                    let p = Pixel::from_mbgr1555(raw);
                                     let (x, y) = store.target_vram_offset();
 
+                                    if let Some(upload) = store.fmv_upload.as_mut() {
+                                        upload.push(raw);
+                                    }
+
                                     let target = self.vram.native_pixel(x, y);
                                     if self.mask_settings.can_draw_to(target) {
                                         self.vram.set_native_pixel(
@@ -241,7 +294,13 @@ impl Rasterizer {
 
                                     if store.next().is_none() {
                                         // End of store
+                                        let upload = store.fmv_upload.take();
+
                                         self.state = State::WaitingForCommand;
+
+                                        if let Some(upload) = upload {
+                                            self.fmv.redraw(&mut self.vram, &upload);
+                                        }
                                         break;
                                     }
                                 }
@@ -275,7 +334,10 @@ impl Rasterizer {
                         }
                     }
                     Command::Gp1(v) => self.gp1(*v),
-                    Command::Quit => return,
+                    Command::Quit => {
+                        self.flush_batch();
+                        return;
+                    }
                     // XXX draw one line at a time
                     Command::EndOfLine(l) => self.finish_line(*l),
                     Command::EndOfFrame => {
@@ -304,6 +366,8 @@ impl Rasterizer {
                         // serialization process
                         assert!(command_i.next().is_none());
 
+                        self.flush_batch();
+
                         let mut fb = flexbuffers::FlexbufferSerializer::new();
                         self.serialize(&mut fb).unwrap();
 
@@ -317,25 +381,47 @@ impl Rasterizer {
     /// Returns `false` if the GPU config forbids writing to this line because it's currently
     /// displayed (currently only useful for interlaced output)
     pub fn can_draw_to_line(&self, y: i32) -> bool {
-        if self.tex_mapper.draw_mode.draw_to_display_area() {
-            // We can draw to display, no worries
-            return true;
-        }
+        self.draw_env().can_draw_to_line(y, self.tex_mapper.draw_mode)
+    }
 
-        if !self.display_mode.is_true_interlaced() {
-            // XXX We only implement the test for interlaced output for now, since that's the most
-            // common situation where this leads to visual glitches
-            return true;
+    /// Snapshot of the state needed to draw triangles
+    fn draw_env(&self) -> DrawEnv {
+        let interlaced_field = if self.display_mode.is_true_interlaced() && !self.progressive_output()
+        {
+            Some((self.display_vram_y_start, self.display_bottom_field))
+        } else {
+            None
+        };
+
+        DrawEnv {
+            clip_x_min: self.clip_x_min,
+            clip_y_min: self.clip_y_min,
+            clip_x_max: self.clip_x_max,
+            clip_y_max: self.clip_y_max,
+            mask_settings: self.mask_settings,
+            force_transparency: self.force_transparency,
+            interlaced_field,
+            dither: simd::SpanDither::new(self.dither_enabled, self.draw_24bpp),
         }
+    }
 
-        // XXX This is how mednafen does it so it's probably safe enough but in practice this is
-        // probably very wrong: we should probably still be able to draw to these lines if the X is
-        // outside of the display. We should also be able to draw to these lines if they're below
-        // or above the display area. In practice interlaced is uncommon enough that it's probably
-        // good enough.
-        let y_is_bottom = ((y + self.display_vram_y_start as i32) & 1) != 0;
+    /// Returns a `Painter` drawing directly to our VRAM with the current state
+    fn painter(&mut self) -> Painter<'_> {
+        let env = self.draw_env();
+
+        Painter {
+            vram: VRamView::new(&mut self.vram),
+            env,
+            dither_table: &self.dither_table,
+            tex_mapper: &mut self.tex_mapper,
+            band: parallel::Band::all(),
+            trace: None,
+        }
+    }
 
-        y_is_bottom != self.display_bottom_field
+    /// Set the cache where the MDEC stores the macroblocks for the enhanced FMV output
+    pub fn set_macroblock_cache(&mut self, cache: Arc<MacroblockCache>) {
+        self.fmv.set_cache(cache);
     }
 
     pub fn set_option(&mut self, opt: RasterizerOption) {
@@ -383,7 +469,28 @@ impl Rasterizer {
             RasterizerOption::ColorBanding(_) => {
                 // Color banding is handled in the rendering pipeline
             }
+            RasterizerOption::WorkerThreads(v) => self.set_worker_threads(v),
+            RasterizerOption::Deinterlace(v) => self.deinterlace_mode = v,
+            RasterizerOption::ProgressiveHack(v) => self.progressive_hack = v,
+            RasterizerOption::CropMode(v) => self.display_area.crop_mode = v,
+            RasterizerOption::OverscanCrop(v) => self.display_area.overscan_crop = v,
+            RasterizerOption::AutoCenter(v) => self.display_area.auto_center = v,
+            RasterizerOption::MdecEnhancement(v) => self.fmv.set_settings(v),
+        }
+    }
+
+    /// Returns true if we should draw and display both interlaced fields every frame. Only
+    /// possible if the game renders full frames: if the drawing area isn't taller than a single
+    /// field the game probably draws each field separately.
+    fn progressive_output(&self) -> bool {
+        if !self.progressive_hack || !self.display_mode.is_true_interlaced() {
+            return false;
         }
+
+        let field_lines = i32::from(self.display_line_end.saturating_sub(self.display_line_start));
+        let draw_lines = ((self.clip_y_max - self.clip_y_min) >> self.vram.upscale_shift) + 1;
+
+        draw_lines > field_lines
     }
 
     pub fn set_upscale_shift(&mut self, upscale_shift: u8) {
@@ -391,6 +498,8 @@ impl Rasterizer {
             return;
         }
 
+        self.flush_batch();
+
         self.clip_x_min >>= self.vram.upscale_shift;
         self.clip_y_min >>= self.vram.upscale_shift;
         self.clip_x_max >>= self.vram.upscale_shift;
@@ -433,30 +542,72 @@ impl Rasterizer {
             return;
         }
 
-        let mut frame_y = line - self.display_line_start;
+        // Line within the display area
+        let mut area_y = line - self.display_line_start;
+        // Line within the frame
+        let frame_y = self.placement.y + i32::from(area_y);
+
+        if frame_y < 0 {
+            // Cropped
+            return;
+        }
+
+        let mut frame_y = frame_y as u32;
+
         if self.display_mode.is_true_interlaced() {
-            frame_y = (frame_y << 1) | (self.display_bottom_field as u16);
+            if self.progressive_output() {
+                // Output the lines of both fields at once
+                for field in 0..2 {
+                    self.finish_frame_line((area_y << 1) | field, (frame_y << 1) | u32::from(field));
+                }
+
+                return;
+            }
+
+            area_y = (area_y << 1) | (self.display_bottom_field as u16);
+            frame_y = (frame_y << 1) | (self.display_bottom_field as u32);
+            self.output_bottom_field = self.display_bottom_field;
         }
 
-        let vram_y = self.display_vram_y_start + frame_y;
+        self.finish_frame_line(area_y, frame_y);
+    }
+
+    /// Output line `area_y` of the display area to line `frame_y` of the frame
+    fn finish_frame_line(&mut self, area_y: u16, frame_y: u32) {
+        let vram_y = self.display_vram_y_start + area_y;
+
+        self.flush_batch_for_line(vram_y);
 
         self.output_line(self.display_vram_x_start, vram_y, frame_y);
     }
 
-    fn output_line(&mut self, x_start: u16, vram_y: u16, frame_y: u16) {
+    fn output_line(&mut self, x_start: u16, vram_y: u16, frame_y: u32) {
         let x_start = u32::from(x_start) << self.vram.upscale_shift;
-        let frame_y = u32::from(frame_y) << self.vram.upscale_shift;
+        let frame_y = frame_y << self.vram.upscale_shift;
         let vram_y = i32::from(vram_y) << self.vram.upscale_shift;
 
         if frame_y >= self.cur_frame.height {
             // Out-of-frame. This should only happen if the video mode changed within the current
-            // frame, or for the very last line of the bottom field when we're interlaced.
+            // frame, for the very last line of the bottom field when we're interlaced or if the
+            // display area is taller than the frame.
             return;
         }
 
-        let xres = u32::from(self.display_mode.xres()) << self.vram.upscale_shift;
+        // Position of the display area in the frame
+        let frame_x = self.placement.x << self.vram.upscale_shift;
+        let active_width = u32::from(self.placement.active_width) << self.vram.upscale_shift;
+
+        // Range of pixels of the display area that are within the frame
+        let first = max(-frame_x, 0) as u32;
+        let end = min(
+            i64::from(active_width),
+            i64::from(self.cur_frame.width) - i64::from(frame_x),
+        );
+        let end = max(end, 0) as u32;
 
-        let width = min(self.cur_frame.width, xres);
+        if first >= end {
+            return;
+        }
 
         match self.display_mode.color_depth() {
             ColorDepth::Bpp24 => {
@@ -464,9 +615,9 @@ impl Rasterizer {
                 // correctly
 
                 // X position in the framebuffer, in Byte
-                let mut fb_x = (x_start * 2) as i32;
+                let mut fb_x = (x_start * 2 + first * 3) as i32;
 
-                for x in 0..width {
+                for x in first..end {
                     // We need two consecutive pixels
                     let p_x = fb_x >> 1;
                     let p1 = self.read_pixel(p_x, vram_y);
@@ -487,7 +638,7 @@ impl Rasterizer {
                     out |= p >> 16;
                     out |= p << 16;
 
-                    self.cur_frame.set_pixel(x, frame_y, out);
+                    self.cur_frame.set_pixel((frame_x + x as i32) as u32, frame_y, out);
 
                     fb_x = (fb_x + 3) & 0x7ff;
                 }
@@ -495,7 +646,7 @@ impl Rasterizer {
             ColorDepth::Bpp8 => {
                 // 8bpp indexed color mode - each byte in VRAM is a palette index
                 for y in 0..(1i32 << self.vram.upscale_shift) {
-                    for x in 0..width {
+                    for x in first..end {
                         // Calculate VRAM pixel position (2 texels per pixel in 8bpp)
                         let vram_x = (x_start + x) >> 1;
                         let pixel = self.read_pixel(vram_x as i32, vram_y + y);
@@ -510,14 +661,18 @@ impl Rasterizer {
                         let clut_y = (palette_index >> 4) as i32;
                         let color = self.read_pixel(clut_x, clut_y);
                         
-                        self.cur_frame.set_pixel(x, frame_y + (y as u32), color.to_rgb888());
+                        self.cur_frame.set_pixel(
+                            (frame_x + x as i32) as u32,
+                            frame_y + (y as u32),
+                            color.to_rgb888(),
+                        );
                     }
                 }
             }
             ColorDepth::Bpp4 => {
                 // 4bpp indexed color mode - each nibble in VRAM is a palette index
                 for y in 0..(1i32 << self.vram.upscale_shift) {
-                    for x in 0..width {
+                    for x in first..end {
                         // Calculate VRAM pixel position (4 texels per pixel in 4bpp)
                         let vram_x = (x_start + x) >> 2;
                         let pixel = self.read_pixel(vram_x as i32, vram_y + y);
@@ -532,17 +687,24 @@ impl Rasterizer {
                         // 16-color palette is typically stored in first 16 pixels
                         let color = self.read_pixel(palette_index, 0);
                         
-                        self.cur_frame.set_pixel(x, frame_y + (y as u32), color.to_rgb888());
+                        self.cur_frame.set_pixel(
+                            (frame_x + x as i32) as u32,
+                            frame_y + (y as u32),
+                            color.to_rgb888(),
+                        );
                     }
                 }
             }
             ColorDepth::Bpp15 => {
                 // GPU outputs pixels "normally", 15bpp native
                 for y in 0..(1i32 << self.vram.upscale_shift) {
-                    for x in 0..width {
+                    for x in first..end {
                         let p = self.read_pixel((x_start + x) as i32, vram_y + y);
-                        self.cur_frame
-                            .set_pixel(x, frame_y + (y as u32), p.to_rgb888());
+                        self.cur_frame.set_pixel(
+                            (frame_x + x as i32) as u32,
+                            frame_y + (y as u32),
+                            p.to_rgb888(),
+                        );
                     }
                 }
             }
@@ -583,21 +745,29 @@ impl Rasterizer {
 
     /// Creates a new, blank frame and returns the previous one
     fn new_frame(&mut self) -> Frame {
+        self.flush_batch();
+
         let interlaced = self.display_mode.is_true_interlaced();
+        let previous_placement = self.placement;
 
         let (width, height) = match self.vram_display_mode {
             VRamDisplayMode::Native => {
                 // XXX For now we approximate the dimensions of the visible area of the image.
                 // For better accuracy we should be emulating the output video timings more accurately
                 // but it's probably not worth it for now.
+                self.placement = display_area::Placement::new(
+                    &self.display_area,
+                    self.display_mode,
+                    (self.display_column_start, self.display_column_end),
+                    (self.display_line_start, self.display_line_end),
+                );
 
-                let width = self.display_mode.xres();
+                let width = self.placement.width;
 
-                let mut height = self.display_line_end - self.display_line_start;
+                let mut height = self.placement.height;
                 if interlaced {
-                    height *= 2;
                     // Last line of the bottom field isn't drawn
-                    height -= 1;
+                    height = (height * 2).saturating_sub(1);
                 }
 
                 let w = u32::from(width) << self.vram.upscale_shift;
@@ -674,16 +844,39 @@ impl Rasterizer {
             }
         };
 
-        if width == self.cur_frame.width && height == self.cur_frame.height {
+        let same_layout = width == self.cur_frame.width
+            && height == self.cur_frame.height
+            && self.placement == previous_placement;
+
+        let mut frame = if same_layout {
             self.cur_frame.clone()
         } else {
-            // Resolution changed, create a whole new frame
+            // Resolution or placement changed, create a whole new frame
             let mut new_frame = Frame::new(width, height);
 
             ::std::mem::swap(&mut new_frame, &mut self.cur_frame);
 
             new_frame
+        };
+
+        // `cur_frame` keeps the previous field's lines, so that's where we get them from when we
+        // need to deinterlace
+        if self.vram_display_mode == VRamDisplayMode::Native && interlaced && !self.progressive_output() {
+            self.deinterlacer.process(
+                self.deinterlace_mode,
+                &mut frame,
+                self.output_bottom_field,
+                self.vram.upscale_shift,
+            );
         }
+
+        if self.vram_display_mode == VRamDisplayMode::Native
+            && self.display_area.crop_mode == CropMode::Borders
+        {
+            frame = self.border_detector.process(frame);
+        }
+
+        frame
     }
 
     /// Create a new frame with the given `width` and `height` and containing the pixels in the VRAM
@@ -708,14 +901,8 @@ impl Rasterizer {
 
     /// Rebuild `dither_tables` based on the various dithering and color depth settings
     fn rebuild_dither_table(&mut self) {
-        // When dithering is enabled DITHER_OFFSETS[x % 4][y % 4] is added to the 8bit value before
-        // truncation to 5 bits
-        const DITHER_OFFSETS: [[i16; 4]; 4] = [
-            [-4, 0, -3, 1],
-            [2, -2, 3, -1],
-            [-3, 1, -4, 0],
-            [3, -1, 2, -2],
-        ];
+        // Batched triangles must be drawn with the previous table
+        self.flush_batch();
 
         self.dither_enabled = self.dither_enable();
 
@@ -764,6 +951,8 @@ impl Rasterizer {
     }
 
     fn reset(&mut self) {
+        self.flush_batch();
+
         self.clip_x_min = 0;
         self.clip_y_min = 0;
         self.clip_x_max = 0;
@@ -803,30 +992,388 @@ impl Rasterizer {
         self.vram.pixel(x, y)
     }
 
+    fn draw_pixel<Transparency, Texture>(&mut self, x: i32, y: i32, color: Pixel)
+    where
+        Transparency: TransparencyMode,
+        Texture: TextureMode,
+    {
+        self.painter().draw_pixel::<Transparency, Texture>(x, y, color);
+    }
+
+    fn draw_triangle<Transparency, Texture, Shading>(&mut self, vertices: [Vertex; 3])
+    where
+        Transparency: TransparencyMode,
+        Texture: TextureMode,
+        Shading: ShadingMode,
+    {
+        if self.workers.is_some() {
+            self.queue_triangle::<Transparency, Texture, Shading>(vertices);
+        } else {
+            self.painter()
+                .draw_triangle::<Transparency, Texture, Shading>(vertices);
+        }
+    }
+
+    fn draw_rect<Transparency, Texture>(&mut self, origin: Vertex, width: i32, height: i32)
+    where
+        Transparency: TransparencyMode,
+        Texture: TextureMode,
+    {
+        // Rects are drawn immediately, on top of the batched triangles
+        self.flush_batch();
+
+        let mut u_start = origin.u;
+        let mut v = origin.v;
+
+        let (u_inc, v_inc) = if Texture::is_textured() {
+            // Per-No$ these bits aren't supposed to function in early PSX models. If that's true
+            // they probably aren't used in many games.
+            let flip_x = self.tex_mapper.draw_mode.flip_rect_x();
+            let flip_y = self.tex_mapper.draw_mode.flip_rect_y();
+
+            let u_inc = if flip_x {
+                // XXX Taken from Mednafen, not sure what this does
+                u_start |= 1;
+                -1
+            } else {
+                1
+            };
+
+            let v_inc = if flip_y { -1 } else { 1 };
+
+            (u_inc, v_inc)
+        } else {
+            (0, 0)
+        };
+
+        /* We always draw rects at native res */
+        let clip_x_min = self.clip_x_min >> self.vram.upscale_shift;
+        let clip_y_min = self.clip_y_min >> self.vram.upscale_shift;
+        let clip_x_max = self.clip_x_max >> self.vram.upscale_shift;
+        let clip_y_max = self.clip_y_max >> self.vram.upscale_shift;
+
+        let mut x_start = origin.x();
+        let x_end = min(x_start + width, clip_x_max + 1);
+
+        let mut y_start = origin.y();
+        let y_end = min(y_start + height, clip_y_max + 1);
+
+        if x_start < clip_x_min {
+            if Texture::is_textured() {
+                let skip = (clip_x_min - x_start) * u_inc;
+
+                u_start = u_start.wrapping_add(skip as u8);
+            }
+            x_start = clip_x_min;
+        }
+
+        if y_start < clip_y_min {
+            if Texture::is_textured() {
+                let skip = (clip_y_min - y_start) * u_inc;
+
+                v = v.wrapping_add(skip as u8);
+            }
+            y_start = clip_y_min;
+        }
+
+        if x_end <= x_start || y_end <= y_start {
+            // Rect is 0-width or completely clipped
+            return;
+        }
+
+        let mut color = origin.color;
+
+        if !Texture::is_textured() {
+            // We're only going to copy this color everywhere, let's truncate it here once and for
+            // all
+            color = self.truncate_color(color);
+        }
+
+        let upscale_shift = self.vram.upscale_shift;
+        let mut painter = self.painter();
+
+        for y in y_start..y_end {
+            if !painter.can_draw_to_line(y) {
+                v = v.wrapping_add(v_inc as u8);
+                continue;
+            }
+
+            let mut u = u_start;
+            for x in x_start..x_end {
+                if Texture::is_textured() {
+                    let texel = painter.get_texel(u, v);
+                    // If the pixel is equal to 0 (including mask bit) then we don't draw it
+                    if !texel.is_nul() {
+                        for y in (y << upscale_shift)..((y + 1) << upscale_shift) {
+                            for x in (x << upscale_shift)..((x + 1) << upscale_shift) {
+                                if Texture::is_raw_texture() {
+                                    painter.draw_pixel::<Transparency, Texture>(x, y, texel);
+                                } else {
+                                    // Texture blending: the final color is a combination of the texel and
+                                    // the solid color. Rect are never dithered.
+                                    let blend = painter.blend(texel, origin.color);
+                                    painter.draw_pixel::<Transparency, Texture>(x, y, blend);
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    // No texture
+                    for y in (y << upscale_shift)..((y + 1) << upscale_shift) {
+                        for x in (x << upscale_shift)..((x + 1) << upscale_shift) {
+                            painter.draw_pixel::<Transparency, Texture>(x, y, color);
+                        }
+                    }
+                }
+                u = u.wrapping_add(u_inc as u8);
+            }
+
+            v = v.wrapping_add(v_inc as u8);
+        }
+    }
+
+    fn draw_line<Transparency, Shading>(&mut self, mut start: Vertex, mut end: Vertex)
+    where
+        Transparency: TransparencyMode,
+        Shading: ShadingMode,
+    {
+        // Lines are drawn immediately, on top of the batched triangles
+        self.flush_batch();
+
+        // Start at the leftmost edge.
+        // XXX Apparently if both sides have the same X we start from the end? This is what
+        // mednafen does.
+        if start.x() >= end.x() {
+            ::std::mem::swap(&mut start, &mut end);
+        }
+
+        let start_x = start.x();
+        let start_y = start.y();
+        let end_x = end.x();
+        let end_y = end.y();
+
+        let dx = (start_x - end_x).abs();
+        let dy = (start_y - end_y).abs();
+
+        let long_edge = max(dx, dy);
+
+        if long_edge == 0 {
+            // 0-length line, nothing to do
+            return;
+        }
+
+        if dx >= (1024 << self.vram.upscale_shift) || dy >= (512 << self.vram.upscale_shift) {
+            // Line is too long, ignore
+            return;
+        }
+
+        let min_x = min(start_x, end_x);
+        let max_x = max(start_x, end_x);
+        let min_y = min(start_y, end_y);
+        let max_y = max(start_y, end_y);
+
+        let clipped = min_y > self.clip_y_max
+            || max_y < self.clip_y_min
+            || min_x > self.clip_x_max
+            || max_x < self.clip_x_min;
+
+        if clipped {
+            // The line is completely outside of the clipping area
+            return;
+        }
+
+        // We're going to follow the long edge one pixel at a time. That means that one of the
+        // values below will necessarily be +1 or -1.
+        let dx_dt = FpCoord::new_dxdy(end_x - start_x, long_edge);
+        let dy_dt = FpCoord::new_dxdy(end_y - start_y, long_edge);
+
+        let dr_dt;
+        let dg_dt;
+        let db_dt;
+
+        if Shading::is_shaded() {
+            dr_dt = FpVar::new(end.red() - start.red()) / long_edge;
+            dg_dt = FpVar::new(end.green() - start.green()) / long_edge;
+            db_dt = FpVar::new(end.blue() - start.blue()) / long_edge;
+        } else {
+            dr_dt = FpVar::new(0);
+            dg_dt = FpVar::new(0);
+            db_dt = FpVar::new(0);
+        }
+
+        let mut lx = FpCoord::new_line_x(start_x);
+        let mut ly = FpCoord::new_line_y(start_y, end_y < start_y);
+
+        let mut painter = self.painter();
+
+        let mut red = FpVar::new_center(start.red());
+        let mut green = FpVar::new_center(start.green());
+        let mut blue = FpVar::new_center(start.blue());
+
+        for _t in 0..=long_edge {
+            let x = lx.truncate() & 0x7ff;
+            let y = ly.truncate() & 0x7ff;
+            let r = red.truncate();
+            let g = green.truncate();
+            let b = blue.truncate();
+
+            lx += dx_dt;
+            ly += dy_dt;
+
+            if Shading::is_shaded() {
+                red += dr_dt;
+                green += dg_dt;
+                blue += db_dt;
+            }
+
+            if !painter.can_draw_to_line(y) {
+                continue;
+            }
+
+            let clipped = y > painter.env.clip_y_max
+                || y < painter.env.clip_y_min
+                || x > painter.env.clip_x_max
+                || x < painter.env.clip_x_min;
+
+            if clipped {
+                continue;
+            }
+
+            // Lines are *always* dithered, even when not shaded (unlike triangles)
+            let r = painter.dither(x, y, r as u32);
+            let g = painter.dither(x, y, g as u32);
+            let b = painter.dither(x, y, b as u32);
+
+            let color = Pixel::from_rgb(r, g, b);
+
+            painter.draw_pixel::<Transparency, NoTexture>(x, y, color);
+        }
+    }
+
+    fn set_clut(&mut self, clut: u32) {
+        // The CLUT is read right away, make sure the batched triangles have been drawn
+        if let Some(clut_area) = self.tex_mapper.clut_area(clut) {
+            if self.batch.draws_to(clut_area) {
+                self.flush_batch();
+            }
+        }
+
+        self.tex_mapper.set_clut(clut, &self.vram);
+    }
+
+    /// Apply 8-to-5bit truncation if enabled
+    fn truncate_color(&self, color: Pixel) -> Pixel {
+        let r = color.red();
+        let g = color.green();
+        let b = color.blue();
+        let mask = color.0 & 0xff00_0000;
+
+        let r = self.truncate_component(r) as u32;
+        let g = self.truncate_component(g) as u32;
+        let b = self.truncate_component(b) as u32;
+
+        Pixel(mask | b | (g << 8) | (r << 16))
+    }
+
+    fn truncate_component(&self, c: u8) -> u8 {
+        // If you look at DITHER_OFFSETS when we build the table you can see that
+        // DITHER_OFFSETS[0][1] is equal to 0, therefore even if dithering is disabled this won't
+        // actually modify the value of the pixel beyond normal saturation and truncation.
+        //
+        // If draw_24bpp is true this is a nop since the entry in the table will be the same value
+        // as the index in the table
+        self.dither_table[0][1][c as usize]
+    }
+}
+
+/// Snapshot of the drawing state needed to rasterize a triangle, so that batched triangles can be
+/// drawn later with the state that was current when they were submitted
+#[derive(Copy, Clone)]
+struct DrawEnv {
+    /// Left edge of the clipping area
+    clip_x_min: i32,
+    /// Top edge of the clipping area
+    clip_y_min: i32,
+    /// Right edge of the clipping area
+    clip_x_max: i32,
+    /// Bottom edge of the clipping area
+    clip_y_max: i32,
+    /// Mask bit settings
+    mask_settings: MaskSettings,
+    /// True to draw opaque pixel as semi-transparent
+    force_transparency: bool,
+    /// If the output is truly interlaced: first line of the display area in VRAM and whether
+    /// we're currently displaying the bottom field
+    interlaced_field: Option<(u16, bool)>,
+    /// Dithering settings matching the current `Rasterizer::dither_table`
+    dither: simd::SpanDither,
+}
+
+impl DrawEnv {
+    /// Returns `false` if the GPU config forbids writing to this line because it's currently
+    /// displayed (currently only useful for interlaced output)
+    fn can_draw_to_line(&self, y: i32, draw_mode: DrawMode) -> bool {
+        if draw_mode.draw_to_display_area() {
+            // We can draw to display, no worries
+            return true;
+        }
+
+        // XXX We only implement the test for interlaced output for now, since that's the most
+        // common situation where this leads to visual glitches
+        let (display_vram_y_start, display_bottom_field) = match self.interlaced_field {
+            Some(f) => f,
+            None => return true,
+        };
+
+        // XXX This is how mednafen does it so it's probably safe enough but in practice this is
+        // probably very wrong: we should probably still be able to draw to these lines if the X is
+        // outside of the display. We should also be able to draw to these lines if they're below
+        // or above the display area. In practice interlaced is uncommon enough that it's probably
+        // good enough.
+        let y_is_bottom = ((y + display_vram_y_start as i32) & 1) != 0;
+
+        y_is_bottom != display_bottom_field
+    }
+}
+
+/// Draws pixels and triangles to the VRAM. Either borrowed from the `Rasterizer` to draw
+/// immediately, or created by a worker thread to draw its band of a triangle batch.
+struct Painter<'a> {
+    vram: VRamView<'a>,
+    env: DrawEnv,
+    dither_table: &'a DitherTable,
+    tex_mapper: &'a mut TextureMapper,
+    /// VRAM lines we're allowed to draw to
+    band: parallel::Band,
+    /// Texture cache accesses, tracked when drawing in parallel
+    trace: Option<&'a mut parallel::CacheTrace>,
+}
+
+impl Painter<'_> {
     fn draw_pixel<Transparency, Texture>(&mut self, x: i32, y: i32, mut color: Pixel)
     where
         Transparency: TransparencyMode,
         Texture: TextureMode,
     {
         debug_assert!(
-            (0..(1024 << self.vram.upscale_shift)).contains(&x),
+            (0..(1024 << self.vram.upscale_shift())).contains(&x),
             "x out of bounds ({})",
             x
         );
         debug_assert!(
-            (0..(1024 << self.vram.upscale_shift)).contains(&y),
+            (0..(1024 << self.vram.upscale_shift())).contains(&y),
             "y out of bounds ({})",
             y
         );
 
         // Apparently the PlayStation GPU supports 2MB VRAM (1024x1024, used in some arcade
         // machines apparently) but the bottom half isn't installed so it wraps around.
-        let y = (y & ((0x200 << self.vram.upscale_shift) - 1)) as u32;
+        let y = (y & ((0x200 << self.vram.upscale_shift()) - 1)) as u32;
         let x = x as u32;
 
         let bg_pixel = self.vram.pixel(x, y);
 
-        if !self.mask_settings.can_draw_to(bg_pixel) {
+        if !self.env.mask_settings.can_draw_to(bg_pixel) {
             // Masked
             return;
         }
@@ -848,11 +1395,11 @@ impl Rasterizer {
                 // XXX Not entirely sure about this.
                 color.set_mask();
             }
-        } else if self.force_transparency {
+        } else if self.env.force_transparency {
             color.apply_transparency(bg_pixel, TransparencyFunction::Average);
         }
 
-        color = self.mask_settings.mask(color);
+        color = self.env.mask_settings.mask(color);
 
         self.vram.set_pixel(x, y, color);
     }
@@ -902,12 +1449,12 @@ impl Rasterizer {
         let y_min = a.position.y;
         let y_max = c.position.y;
 
-        if y_max - y_min >= (512 << self.vram.upscale_shift) {
+        if y_max - y_min >= (512 << self.vram.upscale_shift()) {
             // Triangle is too tall, give up
             return;
         }
 
-        if y_max < self.clip_y_min || y_min > self.clip_y_max {
+        if y_max < self.env.clip_y_min || y_min > self.env.clip_y_max {
             // The triangle is fully above or below the clip area, we don't have anything to draw
             return;
         }
@@ -932,12 +1479,12 @@ impl Rasterizer {
         let x_max = vertices.iter().map(|v| v.position.x).max()
             .expect("Triangle must have at least one vertex");
 
-        if x_max - x_min >= (1024 << self.vram.upscale_shift) {
+        if x_max - x_min >= (1024 << self.vram.upscale_shift()) {
             // Triangle is too large, give up
             return;
         }
 
-        if x_max < self.clip_x_min || x_min > self.clip_x_max {
+        if x_max < self.env.clip_x_min || x_min > self.env.clip_x_max {
             // The triangle is fully to the left or right of the draw area, we don't have anything
             // to draw
             return;
@@ -1145,12 +1692,14 @@ impl Rasterizer {
                 left_x -= rc.left_dxdy;
                 right_x -= rc.right_dxdy;
 
-                if y < self.clip_y_min {
+                if y < self.env.clip_y_min {
                     // We left the drawing area
                     break;
                 }
 
-                if y <= self.clip_y_max {
+                self.next_scanline();
+
+                if y <= self.env.clip_y_max && self.owns_line(y) {
                     self.rasterize_scanline::<Transparency, Texture, Shading>(
                         y,
                         left_x.truncate(),
@@ -1162,12 +1711,14 @@ impl Rasterizer {
             }
         } else {
             while y != rc.end_y {
-                if y > self.clip_y_max {
+                if y > self.env.clip_y_max {
                     // We left the drawing area
                     break;
                 }
 
-                if y >= self.clip_y_min {
+                self.next_scanline();
+
+                if y >= self.env.clip_y_min && self.owns_line(y) {
                     self.rasterize_scanline::<Transparency, Texture, Shading>(
                         y,
                         left_x.truncate(),
@@ -1197,292 +1748,204 @@ impl Rasterizer {
         Texture: TextureMode,
         Shading: ShadingMode,
     {
-        let start_x = max(left_x, self.clip_x_min);
-        let end_x = min(right_x, self.clip_x_max + 1);
-
-        if !self.can_draw_to_line(y) {
-            return;
-        }
-
-        if start_x >= end_x {
-            // Line is either 0-length or clipped
-            return;
-        }
-
-        // We "move" the variables to the start of the line
-        vars.translate_by::<Texture, Shading>(deltas, start_x, y);
-
-        for x in start_x..end_x {
-            if Texture::is_textured() {
-                let texel = self.get_texel(vars.u(), vars.v());
-                // If the pixel is equal to 0 (including mask bit) then we don't draw it
-                if !texel.is_nul() {
-                    if Texture::is_raw_texture() {
-                        // No need to worry about truncation here since textures are always 555
-                        // anyway
-                        self.draw_pixel::<Transparency, Texture>(x, y, texel);
-                    } else {
-                        // Texture blending: the final color is a combination of the texel and
-                        // the computed gouraud color
-                        let blend = self.blend_and_dither(x, y, texel, vars.color());
-                        self.draw_pixel::<Transparency, Texture>(x, y, blend);
-                    }
-                }
-            } else {
-                // No texture
-                let (mut r, mut g, mut b) = vars.color_components();
-
-                if Shading::is_shaded() {
-                    r = self.dither(x, y, r as u32);
-                    g = self.dither(x, y, g as u32);
-                    b = self.dither(x, y, b as u32);
-                }
-
-                let color = Pixel::from_rgb(r, g, b);
-
-                self.draw_pixel::<Transparency, Texture>(x, y, color);
-            }
-            vars.translate_right::<Texture, Shading>(deltas);
-        }
-    }
-
-    fn draw_rect<Transparency, Texture>(&mut self, origin: Vertex, width: i32, height: i32)
-    where
-        Transparency: TransparencyMode,
-        Texture: TextureMode,
-    {
-        let mut u_start = origin.u;
-        let mut v = origin.v;
-
-        let (u_inc, v_inc) = if Texture::is_textured() {
-            // Per-No$ these bits aren't supposed to function in early PSX models. If that's true
-            // they probably aren't used in many games.
-            let flip_x = self.tex_mapper.draw_mode.flip_rect_x();
-            let flip_y = self.tex_mapper.draw_mode.flip_rect_y();
-
-            let u_inc = if flip_x {
-                // XXX Taken from Mednafen, not sure what this does
-                u_start |= 1;
-                -1
-            } else {
-                1
-            };
-
-            let v_inc = if flip_y { -1 } else { 1 };
-
-            (u_inc, v_inc)
-        } else {
-            (0, 0)
-        };
-
-        /* We always draw rects at native res */
-        let clip_x_min = self.clip_x_min >> self.vram.upscale_shift;
-        let clip_y_min = self.clip_y_min >> self.vram.upscale_shift;
-        let clip_x_max = self.clip_x_max >> self.vram.upscale_shift;
-        let clip_y_max = self.clip_y_max >> self.vram.upscale_shift;
-
-        let mut x_start = origin.x();
-        let x_end = min(x_start + width, clip_x_max + 1);
-
-        let mut y_start = origin.y();
-        let y_end = min(y_start + height, clip_y_max + 1);
-
-        if x_start < clip_x_min {
-            if Texture::is_textured() {
-                let skip = (clip_x_min - x_start) * u_inc;
-
-                u_start = u_start.wrapping_add(skip as u8);
-            }
-            x_start = clip_x_min;
-        }
-
-        if y_start < clip_y_min {
-            if Texture::is_textured() {
-                let skip = (clip_y_min - y_start) * u_inc;
-
-                v = v.wrapping_add(skip as u8);
-            }
-            y_start = clip_y_min;
-        }
-
-        if x_end <= x_start || y_end <= y_start {
-            // Rect is 0-width or completely clipped
-            return;
-        }
-
-        let mut color = origin.color;
-
-        if !Texture::is_textured() {
-            // We're only going to copy this color everywhere, let's truncate it here once and for
-            // all
-            color = self.truncate_color(color);
-        }
-
-        for y in y_start..y_end {
-            if !self.can_draw_to_line(y) {
-                v = v.wrapping_add(v_inc as u8);
-                continue;
-            }
-
-            let mut u = u_start;
-            for x in x_start..x_end {
-                if Texture::is_textured() {
-                    let texel = self.get_texel(u, v);
-                    // If the pixel is equal to 0 (including mask bit) then we don't draw it
-                    if !texel.is_nul() {
-                        for y in
-                            (y << self.vram.upscale_shift)..((y + 1) << self.vram.upscale_shift)
-                        {
-                            for x in
-                                (x << self.vram.upscale_shift)..((x + 1) << self.vram.upscale_shift)
-                            {
-                                if Texture::is_raw_texture() {
-                                    self.draw_pixel::<Transparency, Texture>(x, y, texel);
-                                } else {
-                                    // Texture blending: the final color is a combination of the texel and
-                                    // the solid color. Rect are never dithered.
-                                    let blend = self.blend(texel, origin.color);
-                                    self.draw_pixel::<Transparency, Texture>(x, y, blend);
-                                }
-                            }
-                        }
-                    }
-                } else {
-                    // No texture
-                    for y in (y << self.vram.upscale_shift)..((y + 1) << self.vram.upscale_shift) {
-                        for x in
-                            (x << self.vram.upscale_shift)..((x + 1) << self.vram.upscale_shift)
-                        {
-                            self.draw_pixel::<Transparency, Texture>(x, y, color);
-                        }
-                    }
-                }
-                u = u.wrapping_add(u_inc as u8);
-            }
+        let start_x = max(left_x, self.env.clip_x_min);
+        let end_x = min(right_x, self.env.clip_x_max + 1);
 
-            v = v.wrapping_add(v_inc as u8);
+        if !self.can_draw_to_line(y) {
+            return;
+        }
+
+        if start_x >= end_x {
+            // Line is either 0-length or clipped
+            return;
+        }
+
+        // We "move" the variables to the start of the line
+        vars.translate_by::<Texture, Shading>(deltas, start_x, y);
+
+        // Apparently the PlayStation GPU supports 2MB VRAM (1024x1024, used in some arcade
+        // machines apparently) but the bottom half isn't installed so it wraps around.
+        let vram_y = (y & ((0x200 << self.vram.upscale_shift()) - 1)) as u32;
+
+        if Texture::is_textured() && self.span_samples_itself(start_x, end_x, vram_y) {
+            // The texels could be fetched from pixels we draw on this very line, in which case
+            // we have to draw them one at a time
+            self.rasterize_pixels::<Transparency, Texture, Shading>(
+                y, start_x, end_x, vars, deltas,
+            );
+        } else {
+            self.rasterize_span::<Transparency, Texture, Shading>(
+                vram_y, start_x, end_x, vars, deltas,
+            );
         }
     }
 
-    fn draw_line<Transparency, Shading>(&mut self, mut start: Vertex, mut end: Vertex)
-    where
+    /// Rasterize `start_x..end_x` on VRAM line `vram_y` using the span routines, `SPAN_CHUNK`
+    /// pixels at a time
+    fn rasterize_span<Transparency, Texture, Shading>(
+        &mut self,
+        vram_y: u32,
+        start_x: i32,
+        end_x: i32,
+        mut vars: RasterVars,
+        deltas: &RasterVarDeltas,
+    ) where
         Transparency: TransparencyMode,
+        Texture: TextureMode,
         Shading: ShadingMode,
     {
-        // Start at the leftmost edge.
-        // XXX Apparently if both sides have the same X we start from the end? This is what
-        // mednafen does.
-        if start.x() >= end.x() {
-            ::std::mem::swap(&mut start, &mut end);
-        }
+        let ops = simd::span_ops();
+        // Since the chunks are a multiple of 4 pixels the dithering pattern doesn't change from
+        // one chunk to the next
+        let dither = self.env.dither.row(start_x, vram_y as i32);
+
+        let params = simd::WriteParams {
+            textured: Texture::is_textured(),
+            transparency: if Transparency::is_transparent() {
+                Some(self.tex_mapper.draw_mode.transparency_mode())
+            } else {
+                None
+            },
+            force_transparency: self.env.force_transparency,
+            mask_settings: self.env.mask_settings,
+        };
 
-        let start_x = start.x();
-        let start_y = start.y();
-        let end_x = end.x();
-        let end_y = end.y();
+        let mut fg = [Pixel::black(); simd::SPAN_CHUNK];
+        let mut texels = [Pixel::black(); simd::SPAN_CHUNK];
+        let mut colors = [Pixel::black(); simd::SPAN_CHUNK];
 
-        let dx = (start_x - end_x).abs();
-        let dy = (start_y - end_y).abs();
+        let mut x = start_x;
 
-        let long_edge = max(dx, dy);
+        while x < end_x {
+            let len = min(end_x - x, simd::SPAN_CHUNK as i32) as usize;
+            let fg = &mut fg[..len];
 
-        if long_edge == 0 {
-            // 0-length line, nothing to do
-            return;
-        }
+            if Texture::is_textured() {
+                let texels = &mut texels[..len];
 
-        if dx >= (1024 << self.vram.upscale_shift) || dy >= (512 << self.vram.upscale_shift) {
-            // Line is too long, ignore
-            return;
-        }
+                // The texture cache has to be accessed in order, one texel at a time
+                for t in texels.iter_mut() {
+                    *t = self.get_texel(vars.u(), vars.v());
+                    vars.translate_right::<Texture, NoShading>(deltas);
+                }
 
-        let min_x = min(start_x, end_x);
-        let max_x = max(start_x, end_x);
-        let min_y = min(start_y, end_y);
-        let max_y = max(start_y, end_y);
+                fg.copy_from_slice(texels);
 
-        let clipped = min_y > self.clip_y_max
-            || max_y < self.clip_y_min
-            || min_x > self.clip_x_max
-            || max_x < self.clip_x_min;
+                if !Texture::is_raw_texture() {
+                    // Texture blending: the final color is a combination of the texel and the
+                    // computed gouraud color
+                    let colors = &mut colors[..len];
 
-        if clipped {
-            // The line is completely outside of the clipping area
-            return;
-        }
+                    if Shading::is_shaded() {
+                        (ops.shade)(colors, &vars.gradient(deltas));
+                    } else {
+                        colors.fill(vars.color());
+                    }
 
-        // We're going to follow the long edge one pixel at a time. That means that one of the
-        // values below will necessarily be +1 or -1.
-        let dx_dt = FpCoord::new_dxdy(end_x - start_x, long_edge);
-        let dy_dt = FpCoord::new_dxdy(end_y - start_y, long_edge);
+                    (ops.modulate)(fg, colors, &dither);
+                }
 
-        let dr_dt;
-        let dg_dt;
-        let db_dt;
+                vars.translate_by::<NoTexture, Shading>(deltas, len as i32, 0);
 
-        if Shading::is_shaded() {
-            dr_dt = FpVar::new(end.red() - start.red()) / long_edge;
-            dg_dt = FpVar::new(end.green() - start.green()) / long_edge;
-            db_dt = FpVar::new(end.blue() - start.blue()) / long_edge;
-        } else {
-            dr_dt = FpVar::new(0);
-            dg_dt = FpVar::new(0);
-            db_dt = FpVar::new(0);
-        }
+                let line = self.vram.span_mut(x as u32, vram_y, len);
+                (ops.write)(line, fg, texels, &params);
+            } else {
+                if Shading::is_shaded() {
+                    (ops.shade)(fg, &vars.gradient(deltas));
+                    (ops.dither)(fg, &dither);
+                } else {
+                    fg.fill(vars.color());
+                }
 
-        let mut lx = FpCoord::new_line_x(start_x);
-        let mut ly = FpCoord::new_line_y(start_y, end_y < start_y);
+                vars.translate_by::<Texture, Shading>(deltas, len as i32, 0);
 
-        let mut red = FpVar::new_center(start.red());
-        let mut green = FpVar::new_center(start.green());
-        let mut blue = FpVar::new_center(start.blue());
+                let line = self.vram.span_mut(x as u32, vram_y, len);
+                (ops.write)(line, fg, fg, &params);
+            }
 
-        for _t in 0..=long_edge {
-            let x = lx.truncate() & 0x7ff;
-            let y = ly.truncate() & 0x7ff;
-            let r = red.truncate();
-            let g = green.truncate();
-            let b = blue.truncate();
+            x += len as i32;
+        }
+    }
 
-            lx += dx_dt;
-            ly += dy_dt;
+    /// Returns true if the current texture page overlaps `start_x..end_x` on VRAM line `vram_y`
+    fn span_samples_itself(&self, start_x: i32, end_x: i32, vram_y: u32) -> bool {
+        let shift = self.vram.upscale_shift();
+        let left = (start_x >> shift) as u16;
+        let right = ((end_x - 1) >> shift) as u16;
 
-            if Shading::is_shaded() {
-                red += dr_dt;
-                green += dg_dt;
-                blue += db_dt;
-            }
+        let span = parallel::VRamRect::new(left, (vram_y >> shift) as u16, right - left + 1, 1);
 
-            if !self.can_draw_to_line(y) {
-                continue;
-            }
+        self.tex_mapper.texture_area().intersects(span)
+    }
+
+    /// Rasterize `start_x..end_x` on line `y` one pixel at a time
+    fn rasterize_pixels<Transparency, Texture, Shading>(
+        &mut self,
+        y: i32,
+        start_x: i32,
+        end_x: i32,
+        mut vars: RasterVars,
+        deltas: &RasterVarDeltas,
+    ) where
+        Transparency: TransparencyMode,
+        Texture: TextureMode,
+        Shading: ShadingMode,
+    {
+        for x in start_x..end_x {
+            if Texture::is_textured() {
+                let texel = self.get_texel(vars.u(), vars.v());
+                // If the pixel is equal to 0 (including mask bit) then we don't draw it
+                if !texel.is_nul() {
+                    if Texture::is_raw_texture() {
+                        // No need to worry about truncation here since textures are always 555
+                        // anyway
+                        self.draw_pixel::<Transparency, Texture>(x, y, texel);
+                    } else {
+                        // Texture blending: the final color is a combination of the texel and
+                        // the computed gouraud color
+                        let blend = self.blend_and_dither(x, y, texel, vars.color());
+                        self.draw_pixel::<Transparency, Texture>(x, y, blend);
+                    }
+                }
+            } else {
+                // No texture
+                let (mut r, mut g, mut b) = vars.color_components();
 
-            let clipped = y > self.clip_y_max
-                || y < self.clip_y_min
-                || x > self.clip_x_max
-                || x < self.clip_x_min;
+                if Shading::is_shaded() {
+                    r = self.dither(x, y, r as u32);
+                    g = self.dither(x, y, g as u32);
+                    b = self.dither(x, y, b as u32);
+                }
 
-            if clipped {
-                continue;
+                let color = Pixel::from_rgb(r, g, b);
+
+                self.draw_pixel::<Transparency, Texture>(x, y, color);
             }
+            vars.translate_right::<Texture, Shading>(deltas);
+        }
+    }
 
-            // Lines are *always* dithered, even when not shaded (unlike triangles)
-            let r = self.dither(x, y, r as u32);
-            let g = self.dither(x, y, g as u32);
-            let b = self.dither(x, y, b as u32);
+    /// Returns true if `y` (in upscaled coordinates, possibly outside of the VRAM) belongs to our
+    /// band
+    fn owns_line(&self, y: i32) -> bool {
+        let y = y & ((0x200 << self.vram.upscale_shift()) - 1);
 
-            let color = Pixel::from_rgb(r, g, b);
+        self.band.contains(y as u32)
+    }
 
-            self.draw_pixel::<Transparency, NoTexture>(x, y, color);
+    fn next_scanline(&mut self) {
+        if let Some(trace) = self.trace.as_deref_mut() {
+            trace.next_scanline();
         }
     }
 
-    fn set_clut(&mut self, clut: u32) {
-        self.tex_mapper.set_clut(clut, &self.vram);
+    fn can_draw_to_line(&self, y: i32) -> bool {
+        self.env.can_draw_to_line(y, self.tex_mapper.draw_mode)
     }
 
     fn get_texel(&mut self, u: u8, v: u8) -> Pixel {
-        self.tex_mapper.get_texel(u, v, &self.vram, &mut self.gpu_cache)
+        self.tex_mapper
+            .get_texel(u, v, self.vram, self.trace.as_deref_mut())
     }
 
     fn blend(&self, texel: Pixel, color: Pixel) -> Pixel {
@@ -1525,31 +1988,19 @@ impl Rasterizer {
 
         self.dither_table[x][y][input]
     }
+}
 
-    /// Apply 8-to-5bit truncation if enabled
-    fn truncate_color(&self, color: Pixel) -> Pixel {
-        let r = color.red();
-        let g = color.green();
-        let b = color.blue();
-        let mask = color.0 & 0xff00_0000;
-
-        let r = self.truncate_component(r) as u32;
-        let g = self.truncate_component(g) as u32;
-        let b = self.truncate_component(b) as u32;
-
-        Pixel(mask | b | (g << 8) | (r << 16))
-    }
+/// Dithering, truncation and saturation tables, see `Rasterizer::dither_table`
+type DitherTable = [[[u8; 0x200]; 4]; 4];
 
-    fn truncate_component(&self, c: u8) -> u8 {
-        // If you look at DITHER_OFFSETS when we build the table you can see that
-        // DITHER_OFFSETS[0][1] is equal to 0, therefore even if dithering is disabled this won't
-        // actually modify the value of the pixel beyond normal saturation and truncation.
-        //
-        // If draw_24bpp is true this is a nop since the entry in the table will be the same value
-        // as the index in the table
-        self.dither_table[0][1][c as usize]
-    }
-}
+/// When dithering is enabled DITHER_OFFSETS[x % 4][y % 4] is added to the 8bit value before
+/// truncation to 5 bits
+const DITHER_OFFSETS: [[i16; 4]; 4] = [
+    [-4, 0, -3, 1],
+    [2, -2, 3, -1],
+    [-3, 1, -4, 0],
+    [3, -1, 2, -2],
+];
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 enum RasterDir {
@@ -1733,6 +2184,14 @@ impl RasterVars {
         }
     }
 
+    /// Returns the Gouraud shading starting at the current position
+    fn gradient(&self, deltas: &RasterVarDeltas) -> simd::Gradient {
+        simd::Gradient::new(
+            [self.red, self.green, self.blue],
+            [deltas.drdx, deltas.dgdx, deltas.dbdx],
+        )
+    }
+
     fn color(&self) -> Pixel {
         let (r, g, b) = self.color_components();
 
@@ -1780,7 +2239,7 @@ fn cross_product(a: Position, b: Position, c: Position) -> i32 {
 /// Structure keeping track of the state needed to convert the extrapolated 8bit U/V values of the
 /// rasterizer into absolute coordinates in VRAM. The mapping is non-trivial because the PSX GPU
 /// uses 256x256 texture pages, coordinate masking and CLUTs of various depths.
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
 struct TextureMapper {
     /// Draw mode configuration
     draw_mode: DrawMode,
@@ -1892,18 +2351,64 @@ impl TextureMapper {
     }
 
     pub fn set_draw_mode(&mut self, mode: u32) {
+        if self.draw_mode_invalidates_cache(mode) {
+            self.cache_invalidate();
+        }
+
+        self.draw_mode = DrawMode::new(mode);
+        self.update_texture_params();
+    }
+
+    /// Returns true if setting the draw mode to `mode` would invalidate the texture cache
+    pub fn draw_mode_invalidates_cache(&self, mode: u32) -> bool {
         let new_mode = DrawMode::new(mode);
 
-        if new_mode.texture_page_x() != self.draw_mode.texture_page_x()
+        new_mode.texture_page_x() != self.draw_mode.texture_page_x()
             || new_mode.texture_page_y() != self.draw_mode.texture_page_y()
             || new_mode.texture_depth() != self.draw_mode.texture_depth()
             || new_mode.texture_disable() != self.draw_mode.texture_disable()
-        {
-            self.cache_invalidate();
+    }
+
+    /// Returns the VRAM area the CLUT would be loaded from, if the current texture depth uses one
+    pub fn clut_area(&self, clut: u32) -> Option<parallel::VRamRect> {
+        let pts = self.pixel_to_texel_shift as u32;
+        if pts == 0 {
+            return None;
         }
 
-        self.draw_mode = new_mode;
-        self.update_texture_params();
+        let clut = (clut >> 16) & 0x7fff;
+
+        let clut_x = ((clut & 0x3f) << 4) as u16;
+        let clut_y = ((clut >> 6) & 0x1ff) as u16;
+        let nentries = 256u16 >> ((pts - 1) * 4);
+
+        Some(parallel::VRamRect::new(clut_x, clut_y, nentries, 1))
+    }
+
+    /// Returns the VRAM area textured primitives can currently read texels from
+    pub fn texture_area(&self) -> parallel::VRamRect {
+        // The texture window can move the texels anywhere within the page, so we consider the
+        // full 256x256 texel page
+        let x = self.draw_mode.texture_page_x();
+        let y = self.draw_mode.texture_page_y();
+        let width = 256 >> self.pixel_to_texel_shift;
+
+        parallel::VRamRect::new(x, y, width, 256)
+    }
+
+    /// Returns true if all valid texture cachelines contain the same pixels as the VRAM. Should
+    /// be the case unless the game modified a texture without flushing the cache.
+    pub fn cache_is_coherent(&self, vram: &VRam) -> bool {
+        self.texture_cache.iter().all(|line| {
+            if line.tag == CacheLine::TAG_INVALID {
+                return true;
+            }
+
+            let x = (line.tag % 1024) as u16;
+            let y = (line.tag / 1024) as u16;
+
+            (0..4).all(|i| line.pixels[usize::from(i)] == vram.native_pixel(x + i, y))
+        })
     }
 
     pub fn update_mode_from_poly(&mut self, mode: u32) {
@@ -1933,7 +2438,13 @@ impl TextureMapper {
         self.v_offset += tp_y;
     }
 
-    pub fn get_texel(&mut self, u: u8, v: u8, vram: &VRam, gpu_cache: &mut GpuCache) -> Pixel {
+    pub fn get_texel(
+        &mut self,
+        u: u8,
+        v: u8,
+        vram: VRamView,
+        trace: Option<&mut parallel::CacheTrace>,
+    ) -> Pixel {
         let pts = u16::from(self.pixel_to_texel_shift);
         let fb_u = u16::from(u & self.u_mask) + self.u_offset;
         let fb_v = u16::from(v & self.v_mask) + self.v_offset;
@@ -1955,6 +2466,10 @@ impl TextureMapper {
             ((fb_y << 3) | ((fb_x >> 2) & 7)) & 0xff
         };
 
+        if let Some(trace) = trace {
+            trace.touch(cache_index);
+        }
+
         let cacheline = &mut self.texture_cache[usize::from(cache_index)];
 
         let tag = (u32::from(fb_y) * 1024 + u32::from(fb_x)) & !3;
@@ -2001,6 +2516,7 @@ impl TextureMapper {
 /// pixels. Internal representation is a single `u32` containing the values as 8888xRGB, meaning
 /// that it can normally be passed straight to the frontend without conversion
 #[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq)]
+#[repr(transparent)]
 pub struct Pixel(pub u32);
 
 impl Pixel {
@@ -2601,6 +3117,9 @@ struct VRamStore {
     x: u16,
     /// Current Y coordinate, from y_min to y_max
     y: u16,
+    /// Copy of the stored pixels when looking for movie frames to redraw
+    #[serde(skip)]
+    fmv_upload: Option<fmv::Upload>,
 }
 
 impl VRamStore {
@@ -2614,6 +3133,7 @@ impl VRamStore {
             y_max: top + height,
             x: left,
             y: top,
+            fmv_upload: None,
         }
     }
 
@@ -2642,6 +3162,8 @@ impl VRamStore {
 }
 
 fn cmd_vram_copy(rasterizer: &mut Rasterizer, params: &[u32]) {
+    rasterizer.flush_batch();
+
     let src = params[1];
     let dst = params[2];
     let dim = params[3];
@@ -2778,12 +3300,17 @@ fn cmd_vram_store(rasterizer: &mut Rasterizer, params: &[u32]) {
 
     let (width, height) = vram_access_dimensions(dim, false);
 
+    rasterizer.flush_batch();
     rasterizer.tex_mapper.cache_invalidate();
     
     // Invalidate the enhanced cache for the store region
     rasterizer.gpu_cache.invalidate_region(left, top, width as u16, height as u16);
 
-    let store = VRamStore::new(left, top, width as u16, height as u16);
+    let mut store = VRamStore::new(left, top, width as u16, height as u16);
+
+    if rasterizer.fmv.is_enabled() {
+        store.fmv_upload = fmv::Upload::new(left, top, width as u16, height as u16);
+    }
 
     rasterizer.state = State::VRamStore(store);
 }
@@ -2797,6 +3324,7 @@ fn cmd_vram_load(rasterizer: &mut Rasterizer, params: &[u32], frame_channel: &mp
 
     let (width, height) = vram_access_dimensions(dim, true);
 
+    rasterizer.flush_batch();
     rasterizer.tex_mapper.cache_invalidate();
 
     if width == 0 || height == 0 {
@@ -2824,6 +3352,8 @@ fn cmd_fill_rect(rasterizer: &mut Rasterizer, params: &[u32]) {
     // XXX Pretty sure there's no dithering for this commands
     let color = rasterizer.truncate_color(color);
 
+    rasterizer.flush_batch();
+
     // Invalidate cache for the fill area
     rasterizer.gpu_cache.invalidate_region(start_x, start_y, width, height);
 
@@ -2870,6 +3400,11 @@ fn cmd_clip_bot_right(rasterizer: &mut Rasterizer, params: &[u32]) {
 fn cmd_draw_mode(rasterizer: &mut Rasterizer, params: &[u32]) {
     let mode = params[0];
 
+    // The batched triangles must see the texture cache as it was when they were submitted
+    if rasterizer.tex_mapper.draw_mode_invalidates_cache(mode) {
+        rasterizer.flush_batch();
+    }
+
     rasterizer.tex_mapper.set_draw_mode(mode);
     rasterizer.maybe_rebuild_dither_table();
 }
@@ -2892,6 +3427,7 @@ fn cmd_mask_settings(rasterizer: &mut Rasterizer, params: &[u32]) {
 }
 
 fn cmd_clear_cache(rasterizer: &mut Rasterizer, _params: &[u32]) {
+    rasterizer.flush_batch();
     rasterizer.tex_mapper.cache_invalidate();
 }
 
@@ -4038,6 +4574,78 @@ impl VRam {
     }
 }
 
+/// Shared view of the VRAM pixels, used to draw from several threads at once.
+///
+/// Writes go through a raw pointer: it's up to the user to make sure that different threads never
+/// touch the same line concurrently (see `parallel::Band`).
+#[derive(Copy, Clone)]
+struct VRamView<'a> {
+    pixels: *mut Pixel,
+    len: usize,
+    upscale_shift: u8,
+    _vram: PhantomData<&'a mut VRam>,
+}
+
+unsafe impl Send for VRamView<'_> {}
+unsafe impl Sync for VRamView<'_> {}
+
+impl VRamView<'_> {
+    fn new(vram: &mut VRam) -> VRamView<'_> {
+        VRamView {
+            pixels: vram.pixels.as_mut_ptr(),
+            len: vram.pixels.len(),
+            upscale_shift: vram.upscale_shift,
+            _vram: PhantomData,
+        }
+    }
+
+    fn upscale_shift(&self) -> u8 {
+        self.upscale_shift
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        let i = (1024 << self.upscale_shift) * (y as usize) + (x as usize);
+
+        assert!(i < self.len, "VRAM access out of bounds ({}, {})", x, y);
+
+        i
+    }
+
+    /// Returns the pixel at x, y where x an y are in native 1x coordinates.
+    fn native_pixel(&self, x: u16, y: u16) -> Pixel {
+        self.pixel(
+            u32::from(x) << self.upscale_shift,
+            u32::from(y) << self.upscale_shift,
+        )
+    }
+
+    /// Returns the pixel at x, y where x and y are in upscaled coordinates
+    fn pixel(&self, x: u32, y: u32) -> Pixel {
+        unsafe { *self.pixels.add(self.index(x, y)) }
+    }
+
+    /// Sets the pixel at x, y where x and y are in upscaled coordinates
+    fn set_pixel(&mut self, x: u32, y: u32, p: Pixel) {
+        unsafe { *self.pixels.add(self.index(x, y)) = p }
+    }
+
+    /// Returns the `len` pixels of line `y` starting at `x` where x and y are in upscaled
+    /// coordinates
+    fn span_mut(&mut self, x: u32, y: u32, len: usize) -> &mut [Pixel] {
+        assert!(
+            x as usize + len <= (1024 << self.upscale_shift),
+            "VRAM span out of bounds ({}, {}, {})",
+            x,
+            y,
+            len
+        );
+
+        let start = self.index(x, y);
+
+        unsafe { std::slice::from_raw_parts_mut(self.pixels.add(start), len) }
+    }
+}
+
 impl Serialize for VRam {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
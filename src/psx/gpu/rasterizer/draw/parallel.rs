@@ -0,0 +1,521 @@
+//! Multithreaded triangle rasterization.
+//!
+//! When more than one worker thread is configured, triangles aren't drawn immediately: they're
+//! queued in a `Batch` along with a snapshot of the state they depend on. When the batch is
+//! flushed every worker draws the full batch in submission order, but only touches the VRAM lines
+//! belonging to its own `Band`. Since every pixel is only ever written by a single thread, in the
+//! same order as the sequential rasterizer, the result is bit-identical.
+//!
+//! The batch is flushed before anything that could observe its result: rects, lines, VRAM
+//! transfers, display output, texture or CLUT reads from an area the batch draws to, etc...
+
+use super::{
+    DitherTable, DrawEnv, Painter, Pixel, Rasterizer, TextureMapper, VRamView, Vertex,
+};
+use crate::psx::gpu::commands::{ShadingMode, TextureMode, TransparencyMode};
+use crate::psx::gpu::{DrawMode, TextureWindow};
+use rayon::prelude::*;
+use std::cmp::{max, min};
+
+/// Number of triangles after which a batch is flushed unconditionally, to avoid starving the
+/// workers while a big batch is being built
+const MAX_BATCH_LEN: usize = 2048;
+
+/// Rectangular area of the VRAM, in native coordinates
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct VRamRect {
+    left: u16,
+    top: u16,
+    /// First column *not* in the rect
+    right: u16,
+    /// First line *not* in the rect
+    bottom: u16,
+}
+
+impl VRamRect {
+    /// Create a rect starting at `x`, `y` with the given dimensions. Rects wrapping around the
+    /// edges of the VRAM are extended to the full width or height.
+    pub fn new(x: u16, y: u16, width: u16, height: u16) -> VRamRect {
+        let x = x & 0x3ff;
+        let y = y & 0x1ff;
+
+        let (left, right) = if x + width > 1024 {
+            (0, 1024)
+        } else {
+            (x, x + width)
+        };
+
+        let (top, bottom) = if y + height > 512 {
+            (0, 512)
+        } else {
+            (y, y + height)
+        };
+
+        VRamRect {
+            left,
+            top,
+            right,
+            bottom,
+        }
+    }
+
+    pub fn intersects(&self, other: VRamRect) -> bool {
+        self.left < other.right
+            && other.left < self.right
+            && self.top < other.bottom
+            && other.top < self.bottom
+    }
+
+    /// Returns the smallest rect containing both `self` and `other`
+    pub fn union(&self, other: VRamRect) -> VRamRect {
+        VRamRect {
+            left: min(self.left, other.left),
+            top: min(self.top, other.top),
+            right: max(self.right, other.right),
+            bottom: max(self.bottom, other.bottom),
+        }
+    }
+}
+
+/// Set of VRAM lines a `Painter` is allowed to draw to. Lines are grouped in tiles of
+/// `1 << tile_shift` lines which are dealt round-robin between `count` bands, this way the work is
+/// spread evenly even if the geometry is concentrated in a small portion of the screen.
+#[derive(Copy, Clone)]
+pub struct Band {
+    index: u32,
+    count: u32,
+    tile_shift: u32,
+}
+
+impl Band {
+    /// Band containing all the VRAM lines
+    pub fn all() -> Band {
+        Band {
+            index: 0,
+            count: 1,
+            tile_shift: 0,
+        }
+    }
+
+    /// Returns true if line `y` (in upscaled coordinates) belongs to this band
+    pub fn contains(&self, y: u32) -> bool {
+        (y >> self.tile_shift) % self.count == self.index
+    }
+}
+
+/// Keeps track of the order in which a worker accessed the texture cachelines, so that we can
+/// reconstruct the state of the texture cache the sequential rasterizer would have ended up with.
+pub struct CacheTrace {
+    /// Position of the current triangle in the batch
+    triangle: u32,
+    /// Number of scanlines iterated since the start of the triangle, including the ones that
+    /// belong to other bands
+    scanline: u32,
+    /// Number of texel reads since the start of the scanline
+    read: u32,
+    /// For every cacheline, position of the last access (if any)
+    last_use: [Option<(u32, u32, u32)>; 0x100],
+}
+
+impl CacheTrace {
+    fn new() -> CacheTrace {
+        CacheTrace {
+            triangle: 0,
+            scanline: 0,
+            read: 0,
+            last_use: [None; 0x100],
+        }
+    }
+
+    fn start_triangle(&mut self, triangle: u32) {
+        self.triangle = triangle;
+        self.scanline = 0;
+        self.read = 0;
+    }
+
+    pub fn next_scanline(&mut self) {
+        self.scanline += 1;
+        self.read = 0;
+    }
+
+    /// Called every time the texture cacheline `line` is accessed
+    pub fn touch(&mut self, line: u16) {
+        self.last_use[usize::from(line)] = Some((self.triangle, self.scanline, self.read));
+        self.read += 1;
+    }
+}
+
+/// Thread pool used to draw the batches
+pub struct Workers {
+    pool: rayon::ThreadPool,
+    threads: u32,
+    /// Bands are interleaved in tiles of `1 << tile_shift` native lines
+    pub(super) tile_shift: u32,
+    /// Batches smaller than this are drawn on the rasterizer thread directly, it's not worth
+    /// waking up the workers for them
+    pub(super) min_batch_len: usize,
+}
+
+impl Workers {
+    fn new(threads: u32) -> Result<Workers, rayon::ThreadPoolBuildError> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .thread_name(|i| format!("RSX band {}", i))
+            .build()?;
+
+        Ok(Workers {
+            pool,
+            threads,
+            tile_shift: 3,
+            min_batch_len: 16,
+        })
+    }
+}
+
+type DrawFn = fn(&mut Painter<'_>, [Vertex; 3]);
+
+/// A triangle waiting to be drawn, along with the state it needs
+struct QueuedTriangle {
+    vertices: [Vertex; 3],
+    env: DrawEnv,
+    draw_mode: DrawMode,
+    tex_window: TextureWindow,
+    /// Index of the CLUT in `Batch::cluts` if the triangle uses a paletted texture
+    clut: Option<usize>,
+    draw: DrawFn,
+}
+
+/// Triangles waiting to be drawn by the workers
+#[derive(Default)]
+pub struct Batch {
+    triangles: Vec<QueuedTriangle>,
+    /// Copies of the CLUTs used by the triangles
+    cluts: Vec<[Pixel; 0x100]>,
+    /// `clut_tag` of the last entry in `cluts`
+    last_clut_tag: Option<u32>,
+    /// VRAM area the batched triangles may draw to
+    dirty: Option<VRamRect>,
+    /// VRAM area the batched triangles may read texels from
+    sampled: Option<VRamRect>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    /// Returns true if the batched triangles may draw to `area`
+    pub fn draws_to(&self, area: VRamRect) -> bool {
+        self.dirty.map(|d| d.intersects(area)).unwrap_or(false)
+    }
+
+    /// Returns true if the batched triangles may read texels from `area`
+    fn samples(&self, area: VRamRect) -> bool {
+        self.sampled.map(|s| s.intersects(area)).unwrap_or(false)
+    }
+
+    fn clear(&mut self) {
+        self.triangles.clear();
+        self.cluts.clear();
+        self.last_clut_tag = None;
+        self.dirty = None;
+        self.sampled = None;
+    }
+}
+
+fn draw_triangle<Transparency, Texture, Shading>(painter: &mut Painter<'_>, vertices: [Vertex; 3])
+where
+    Transparency: TransparencyMode,
+    Texture: TextureMode,
+    Shading: ShadingMode,
+{
+    painter.draw_triangle::<Transparency, Texture, Shading>(vertices);
+}
+
+/// Draw all the triangles in `batch`, skipping the lines that don't belong to `band`
+fn draw_triangles(
+    batch: &Batch,
+    vram: VRamView<'_>,
+    dither_table: &DitherTable,
+    tex_mapper: &mut TextureMapper,
+    band: Band,
+    mut trace: Option<&mut CacheTrace>,
+) {
+    let mut cur_clut = None;
+
+    for (i, t) in batch.triangles.iter().enumerate() {
+        tex_mapper.draw_mode = t.draw_mode;
+        tex_mapper.tex_window = t.tex_window;
+        tex_mapper.update_texture_params();
+
+        if let Some(clut) = t.clut {
+            if cur_clut != Some(clut) {
+                cur_clut = Some(clut);
+                tex_mapper.clut_cache = batch.cluts[clut];
+            }
+        }
+
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.start_triangle(i as u32);
+        }
+
+        let mut painter = Painter {
+            vram,
+            env: t.env,
+            dither_table,
+            tex_mapper: &mut *tex_mapper,
+            band,
+            trace: trace.as_deref_mut(),
+        };
+
+        (t.draw)(&mut painter, t.vertices.clone());
+    }
+}
+
+impl Rasterizer {
+    /// Set the number of threads used to draw triangles. 0 means one per CPU core.
+    pub(super) fn set_worker_threads(&mut self, threads: u8) {
+        let threads = if threads == 0 {
+            num_cpus::get() as u32
+        } else {
+            u32::from(threads)
+        };
+
+        let cur_threads = self.workers.as_ref().map(|w| w.threads).unwrap_or(1);
+        if threads == cur_threads {
+            return;
+        }
+
+        self.flush_batch();
+
+        if threads <= 1 {
+            info!("Rasterizing triangles on a single thread");
+            self.workers = None;
+            return;
+        }
+
+        self.workers = match Workers::new(threads) {
+            Ok(w) => {
+                info!("Rasterizing triangles on {} threads", threads);
+                Some(w)
+            }
+            Err(e) => {
+                error!("Failed to start the rasterizer worker threads: {}", e);
+                None
+            }
+        };
+    }
+
+    /// Add a triangle to the batch. Must only be called after the CLUT and draw mode for the
+    /// triangle have been set.
+    pub(super) fn queue_triangle<Transparency, Texture, Shading>(
+        &mut self,
+        vertices: [Vertex; 3],
+    ) where
+        Transparency: TransparencyMode,
+        Texture: TextureMode,
+        Shading: ShadingMode,
+    {
+        let draw_area = match self.triangle_draw_area(&vertices) {
+            Some(a) => a,
+            // Fully clipped, nothing to draw
+            None => return,
+        };
+
+        let texture_area = if Texture::is_textured() {
+            Some(self.tex_mapper.texture_area())
+        } else {
+            None
+        };
+
+        // The batch is drawn out-of-order, we can't let triangles read from an area it draws to
+        // or the other way around.
+        let conflict = self.batch.samples(draw_area)
+            || texture_area
+                .map(|a| self.batch.draws_to(a))
+                .unwrap_or(false);
+
+        if conflict {
+            self.flush_batch();
+        }
+
+        if texture_area.map(|a| a.intersects(draw_area)).unwrap_or(false) {
+            // The triangle reads from the area it's drawing to, that can only be done
+            // sequentially
+            self.flush_batch();
+            self.painter()
+                .draw_triangle::<Transparency, Texture, Shading>(vertices);
+            return;
+        }
+
+        let clut = if Texture::is_textured() && self.tex_mapper.pixel_to_texel_shift != 0 {
+            let tag = self.tex_mapper.clut_tag;
+
+            if self.batch.last_clut_tag != Some(tag) {
+                self.batch.cluts.push(self.tex_mapper.clut_cache);
+                self.batch.last_clut_tag = Some(tag);
+            }
+
+            Some(self.batch.cluts.len() - 1)
+        } else {
+            None
+        };
+
+        let env = self.draw_env();
+        let batch = &mut self.batch;
+
+        batch.triangles.push(QueuedTriangle {
+            vertices,
+            env,
+            draw_mode: self.tex_mapper.draw_mode,
+            tex_window: self.tex_mapper.tex_window,
+            clut,
+            draw: draw_triangle::<Transparency, Texture, Shading>,
+        });
+
+        batch.dirty = Some(batch.dirty.map_or(draw_area, |d| d.union(draw_area)));
+
+        if let Some(a) = texture_area {
+            batch.sampled = Some(batch.sampled.map_or(a, |s| s.union(a)));
+        }
+
+        if batch.triangles.len() >= MAX_BATCH_LEN {
+            self.flush_batch();
+        }
+    }
+
+    /// Returns the VRAM area a triangle may draw to, or `None` if it's fully clipped
+    fn triangle_draw_area(&self, vertices: &[Vertex; 3]) -> Option<VRamRect> {
+        let upscale_shift = self.vram.upscale_shift;
+
+        let xs = vertices.iter().map(|v| v.x());
+        let ys = vertices.iter().map(|v| v.y());
+
+        let x_min = max(xs.clone().min()?, self.clip_x_min);
+        let x_max = min(xs.max()?, self.clip_x_max);
+        let y_min = max(ys.clone().min()?, self.clip_y_min);
+        let y_max = min(ys.max()?, self.clip_y_max);
+
+        if x_min > x_max || y_min > y_max {
+            return None;
+        }
+
+        let left = x_min >> upscale_shift;
+        let top = y_min >> upscale_shift;
+        let width = (x_max >> upscale_shift) - left + 1;
+        let height = (y_max >> upscale_shift) - top + 1;
+
+        Some(VRamRect::new(
+            left as u16,
+            top as u16,
+            width as u16,
+            height as u16,
+        ))
+    }
+
+    /// Draw all the batched triangles
+    pub(super) fn flush_batch(&mut self) {
+        if self.batch.triangles.is_empty() {
+            return;
+        }
+
+        let batch = std::mem::take(&mut self.batch);
+
+        let parallel = match self.workers {
+            Some(ref w) => batch.triangles.len() >= w.min_batch_len,
+            None => false,
+        };
+
+        // If the game modified a texture without flushing the cache the cache contents depend on
+        // the exact order the texels are fetched, we have to draw sequentially in this case.
+        if parallel && self.tex_mapper.cache_is_coherent(&self.vram) {
+            self.draw_batch_parallel(&batch);
+        } else {
+            self.draw_batch_sequential(&batch);
+        }
+
+        // Keep the allocations around for the next batch
+        self.batch = batch;
+        self.batch.clear();
+    }
+
+    /// Flush the batch if it may draw to the VRAM line `vram_y` (native coordinates)
+    pub(super) fn flush_batch_for_line(&mut self, vram_y: u16) {
+        if self.batch.draws_to(VRamRect::new(0, vram_y, 1024, 1)) {
+            self.flush_batch();
+        }
+    }
+
+    fn draw_batch_sequential(&mut self, batch: &Batch) {
+        // The batched triangles use their own texture state, we need to restore the current one
+        // afterwards
+        let draw_mode = self.tex_mapper.draw_mode;
+        let tex_window = self.tex_mapper.tex_window;
+        let clut_cache = self.tex_mapper.clut_cache;
+
+        draw_triangles(
+            batch,
+            VRamView::new(&mut self.vram),
+            &self.dither_table,
+            &mut self.tex_mapper,
+            Band::all(),
+            None,
+        );
+
+        self.tex_mapper.draw_mode = draw_mode;
+        self.tex_mapper.tex_window = tex_window;
+        self.tex_mapper.clut_cache = clut_cache;
+        self.tex_mapper.update_texture_params();
+    }
+
+    fn draw_batch_parallel(&mut self, batch: &Batch) {
+        let workers = match self.workers {
+            Some(ref w) => w,
+            None => unreachable!(),
+        };
+
+        let count = workers.threads;
+        let tile_shift = workers.tile_shift + u32::from(self.vram.upscale_shift);
+        let vram = VRamView::new(&mut self.vram);
+        let dither_table = &self.dither_table;
+        let tex_mapper = &self.tex_mapper;
+
+        let results: Vec<(TextureMapper, CacheTrace)> = workers.pool.install(|| {
+            (0..count)
+                .into_par_iter()
+                .map(|index| {
+                    let mut mapper = tex_mapper.clone();
+                    let mut trace = CacheTrace::new();
+                    let band = Band {
+                        index,
+                        count,
+                        tile_shift,
+                    };
+
+                    draw_triangles(
+                        batch,
+                        vram,
+                        dither_table,
+                        &mut mapper,
+                        band,
+                        Some(&mut trace),
+                    );
+
+                    (mapper, trace)
+                })
+                .collect()
+        });
+
+        // Every cacheline ends up in the state left by the band that accessed it last
+        for line in 0..0x100 {
+            let last = results
+                .iter()
+                .filter_map(|(mapper, trace)| trace.last_use[line].map(|k| (k, mapper)))
+                .max_by_key(|&(k, _)| k);
+
+            if let Some((_, mapper)) = last {
+                self.tex_mapper.texture_cache[line] = mapper.texture_cache[line];
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! AVX2 span routines, processing 8 pixels at a time. The remaining pixels are handed over to the
+//! SSE2 routines.
+
+use super::{sse2, DitherRow, Gradient, SpanOps, WriteParams, MASK_BITS};
+use crate::psx::gpu::rasterizer::draw::fixed_point::FP_VAR_SHIFT;
+use crate::psx::gpu::rasterizer::draw::Pixel;
+use crate::psx::gpu::TransparencyFunction;
+use std::arch::x86_64::*;
+
+/// Number of pixels in a vector
+const LANES: usize = 8;
+
+/// Returns the AVX2 routines if the host CPU supports them
+pub fn ops() -> Option<SpanOps> {
+    if !is_x86_feature_detected!("avx2") {
+        return None;
+    }
+
+    // Safety: the routines below are only reachable once we've made sure that AVX2 is available
+    Some(SpanOps {
+        name: "AVX2",
+        shade: |out, gradient| unsafe { shade(out, gradient) },
+        dither: |pixels, row| unsafe { dither(pixels, row) },
+        modulate: |pixels, colors, dither| unsafe { modulate(pixels, colors, dither) },
+        write: |line, fg, texels, params| unsafe { write(line, fg, texels, params) },
+    })
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn load(pixels: &[Pixel], i: usize) -> __m256i {
+    debug_assert!(i + LANES <= pixels.len());
+
+    _mm256_loadu_si256(pixels.as_ptr().add(i) as *const __m256i)
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn store(pixels: &mut [Pixel], i: usize, v: __m256i) {
+    debug_assert!(i + LANES <= pixels.len());
+
+    _mm256_storeu_si256(pixels.as_mut_ptr().add(i) as *mut __m256i, v)
+}
+
+/// Returns `a` where `mask` is set, `b` elsewhere
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn select(mask: __m256i, a: __m256i, b: __m256i) -> __m256i {
+    _mm256_blendv_epi8(b, a, mask)
+}
+
+/// Replace the mask bits of `color` with those of `mask`
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn with_mask(color: __m256i, mask: __m256i) -> __m256i {
+    select(_mm256_set1_epi32(MASK_BITS as i32), mask, color)
+}
+
+/// Returns the dithering offsets for the low and high halves of a vector unpacked to 16bit
+/// components. AVX2 unpacks each 128bit lane separately, so the low half contains pixels 0, 1, 4
+/// and 5.
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn dither_offsets(dither: &DitherRow) -> (__m256i, __m256i) {
+    let [o0, o1, o2, o3] = dither.offsets;
+
+    (
+        _mm256_setr_epi16(
+            o0, o0, o0, o0, o1, o1, o1, o1, o0, o0, o0, o0, o1, o1, o1, o1,
+        ),
+        _mm256_setr_epi16(
+            o2, o2, o2, o2, o3, o3, o3, o3, o2, o2, o2, o2, o3, o3, o3, o3,
+        ),
+    )
+}
+
+/// Dither, saturate and truncate 16bit components
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn dither_components(c: __m256i, offsets: __m256i, truncate: bool) -> __m256i {
+    let c = _mm256_add_epi16(c, offsets);
+    let c = _mm256_max_epi16(c, _mm256_setzero_si256());
+    let c = _mm256_min_epi16(c, _mm256_set1_epi16(0xff));
+
+    if truncate {
+        let c = _mm256_and_si256(c, _mm256_set1_epi16(0xf8));
+
+        _mm256_or_si256(c, _mm256_srli_epi16::<5>(c))
+    } else {
+        c
+    }
+}
+
+/// Semi-transparency blending of the foreground pixels `f` with the background pixels `b`. The
+/// mask bit of the result is always cleared.
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn blend(f: __m256i, b: __m256i, mode: TransparencyFunction) -> __m256i {
+    let c = match mode {
+        TransparencyFunction::Average => {
+            // (f + b) / 2 without overflowing into the next component
+            let half = _mm256_and_si256(
+                _mm256_srli_epi32::<1>(_mm256_xor_si256(f, b)),
+                _mm256_set1_epi8(0x7f),
+            );
+
+            _mm256_add_epi8(_mm256_and_si256(f, b), half)
+        }
+        TransparencyFunction::Add => _mm256_adds_epu8(f, b),
+        TransparencyFunction::Sub => _mm256_subs_epu8(b, f),
+        TransparencyFunction::QuarterAdd => {
+            let quarter = _mm256_and_si256(_mm256_srli_epi32::<2>(f), _mm256_set1_epi8(0x3f));
+
+            _mm256_adds_epu8(quarter, b)
+        }
+    };
+
+    _mm256_andnot_si256(_mm256_set1_epi32(MASK_BITS as i32), c)
+}
+
+/// Returns `[s, s + d, s + 2 * d, ...]` with wrapping arithmetic
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn ramp(s: i32, d: i32) -> __m256i {
+    let mut v = [0i32; LANES];
+
+    for (i, v) in v.iter_mut().enumerate() {
+        *v = s.wrapping_add(d.wrapping_mul(i as i32));
+    }
+
+    _mm256_loadu_si256(v.as_ptr() as *const __m256i)
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn shade(out: &mut [Pixel], gradient: &Gradient) {
+    let [r, g, b] = gradient.start;
+    let [dr, dg, db] = gradient.step;
+
+    let mut r = ramp(r, dr);
+    let mut g = ramp(g, dg);
+    let mut b = ramp(b, db);
+
+    let dr = _mm256_set1_epi32(dr.wrapping_mul(LANES as i32));
+    let dg = _mm256_set1_epi32(dg.wrapping_mul(LANES as i32));
+    let db = _mm256_set1_epi32(db.wrapping_mul(LANES as i32));
+
+    let byte = _mm256_set1_epi32(0xff);
+    let n = out.len() - out.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let cr = _mm256_and_si256(_mm256_srai_epi32::<{ FP_VAR_SHIFT as i32 }>(r), byte);
+        let cg = _mm256_and_si256(_mm256_srai_epi32::<{ FP_VAR_SHIFT as i32 }>(g), byte);
+        let cb = _mm256_and_si256(_mm256_srai_epi32::<{ FP_VAR_SHIFT as i32 }>(b), byte);
+
+        let p = _mm256_or_si256(_mm256_slli_epi32::<16>(cr), _mm256_slli_epi32::<8>(cg));
+
+        store(out, i, _mm256_or_si256(p, cb));
+
+        r = _mm256_add_epi32(r, dr);
+        g = _mm256_add_epi32(g, dg);
+        b = _mm256_add_epi32(b, db);
+    }
+
+    sse2::shade(&mut out[n..], &gradient.advance(n));
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn dither(pixels: &mut [Pixel], dither: &DitherRow) {
+    let (off_lo, off_hi) = dither_offsets(dither);
+    let zero = _mm256_setzero_si256();
+    let n = pixels.len() - pixels.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let p = load(pixels, i);
+
+        let lo = dither_components(_mm256_unpacklo_epi8(p, zero), off_lo, dither.truncate);
+        let hi = dither_components(_mm256_unpackhi_epi8(p, zero), off_hi, dither.truncate);
+
+        store(pixels, i, with_mask(_mm256_packus_epi16(lo, hi), p));
+    }
+
+    sse2::dither(&mut pixels[n..], dither);
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn modulate(pixels: &mut [Pixel], colors: &[Pixel], dither: &DitherRow) {
+    assert_eq!(pixels.len(), colors.len());
+
+    let (off_lo, off_hi) = dither_offsets(dither);
+    let zero = _mm256_setzero_si256();
+    let n = pixels.len() - pixels.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let t = load(pixels, i);
+        let c = load(colors, i);
+
+        // The products fit in 16 bits since both operands are 8bit wide
+        let lo = _mm256_mullo_epi16(_mm256_unpacklo_epi8(t, zero), _mm256_unpacklo_epi8(c, zero));
+        let hi = _mm256_mullo_epi16(_mm256_unpackhi_epi8(t, zero), _mm256_unpackhi_epi8(c, zero));
+
+        let lo = dither_components(_mm256_srli_epi16::<7>(lo), off_lo, dither.truncate);
+        let hi = dither_components(_mm256_srli_epi16::<7>(hi), off_hi, dither.truncate);
+
+        store(pixels, i, with_mask(_mm256_packus_epi16(lo, hi), t));
+    }
+
+    sse2::modulate(&mut pixels[n..], &colors[n..], dither);
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn write(line: &mut [Pixel], fg: &[Pixel], texels: &[Pixel], params: &WriteParams) {
+    assert_eq!(line.len(), fg.len());
+    assert_eq!(line.len(), texels.len());
+
+    let zero = _mm256_setzero_si256();
+    let ones = _mm256_set1_epi32(-1);
+    let mask_bits = _mm256_set1_epi32(MASK_BITS as i32);
+    let set_mask = _mm256_set1_epi32(1 << 24);
+    let or_mask = _mm256_set1_epi32(params.or_mask() as i32);
+    let n = line.len() - line.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let b = load(line, i);
+        let f = load(fg, i);
+
+        // Pixels that must be left untouched
+        let mut skip = zero;
+
+        if params.textured {
+            skip = _mm256_cmpeq_epi32(load(texels, i), zero);
+        }
+
+        if params.check_mask_bit() {
+            let unmasked = _mm256_cmpeq_epi32(_mm256_and_si256(b, mask_bits), zero);
+
+            skip = _mm256_or_si256(skip, _mm256_andnot_si256(unmasked, ones));
+        }
+
+        let opaque = if params.force_transparency {
+            blend(f, b, TransparencyFunction::Average)
+        } else {
+            f
+        };
+
+        let color = match params.transparency {
+            Some(mode) if params.textured => {
+                // Only the texels with the mask bit set are semi-transparent
+                let transparent = _mm256_or_si256(blend(f, b, mode), set_mask);
+                let is_opaque = _mm256_cmpeq_epi32(_mm256_and_si256(f, mask_bits), zero);
+
+                select(is_opaque, opaque, transparent)
+            }
+            Some(mode) => blend(f, b, mode),
+            None => opaque,
+        };
+
+        let color = _mm256_or_si256(color, or_mask);
+
+        store(line, i, select(skip, b, color));
+    }
+
+    sse2::write(&mut line[n..], &fg[n..], &texels[n..], params);
+}
@@ -0,0 +1,200 @@
+//! Vectorized implementations of the innermost rasterization loops.
+//!
+//! `Painter::rasterize_scanline` processes triangle spans in chunks of up to `SPAN_CHUNK` pixels:
+//! it first computes the foreground colors (Gouraud shading, texture blending and dithering) then
+//! merges them with the VRAM line (semi-transparency and mask bit handling). Each of these steps
+//! has a portable scalar implementation and SSE2/AVX2 (x86_64) or NEON (aarch64) variants, the
+//! best one supported by the host CPU is picked at runtime. All variants must produce exactly the
+//! same output as the scalar code.
+
+mod scalar;
+
+#[cfg(target_arch = "x86_64")]
+mod avx2;
+#[cfg(target_arch = "aarch64")]
+mod neon;
+#[cfg(target_arch = "x86_64")]
+mod sse2;
+
+#[cfg(test)]
+mod tests;
+
+use super::fixed_point::FpVar;
+use super::{Pixel, DITHER_OFFSETS};
+use crate::psx::gpu::{MaskSettings, TransparencyFunction};
+use std::sync::OnceLock;
+
+/// Maximum number of pixels processed at once by the span routines. Must be a multiple of 4 so
+/// that the dithering pattern remains aligned from one chunk to the next.
+pub const SPAN_CHUNK: usize = 64;
+
+const _: () = assert!(SPAN_CHUNK.is_multiple_of(4));
+
+/// Value of the mask bits in a `Pixel`
+const MASK_BITS: u32 = 0xff00_0000;
+
+/// Set of span routines for a given instruction set
+#[derive(Copy, Clone)]
+pub struct SpanOps {
+    /// Name of the instruction set used by these routines
+    pub name: &'static str,
+    /// Fill the span with the (undithered) Gouraud shading colors
+    pub shade: fn(&mut [Pixel], &Gradient),
+    /// Dither, saturate and truncate the color components of the span
+    pub dither: fn(&mut [Pixel], &DitherRow),
+    /// Blend the texels in the span with the provided colors, then dither
+    pub modulate: fn(&mut [Pixel], &[Pixel], &DitherRow),
+    /// Draw the foreground pixels onto the VRAM line. The texels are used to skip nul pixels when
+    /// drawing textured primitives, they're ignored otherwise.
+    pub write: fn(&mut [Pixel], &[Pixel], &[Pixel], &WriteParams),
+}
+
+impl SpanOps {
+    /// Returns all the implementations supported by the host CPU, from slowest to fastest
+    pub fn available() -> Vec<SpanOps> {
+        let mut ops = vec![scalar::ops()];
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            ops.push(sse2::ops());
+            ops.extend(avx2::ops());
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        ops.push(neon::ops());
+
+        ops
+    }
+}
+
+/// Returns the fastest span routines supported by the host CPU
+pub fn span_ops() -> &'static SpanOps {
+    static OPS: OnceLock<SpanOps> = OnceLock::new();
+
+    OPS.get_or_init(|| {
+        let ops = *SpanOps::available().last().unwrap();
+
+        info!("Using {} span rasterization routines", ops.name);
+
+        ops
+    })
+}
+
+/// Gouraud shading color components at the start of a span and their per-pixel increments, as raw
+/// `FpVar` values
+#[derive(Copy, Clone, Debug)]
+pub struct Gradient {
+    /// Red, green and blue values for the first pixel
+    pub start: [i32; 3],
+    /// Red, green and blue increments from one pixel to the next
+    pub step: [i32; 3],
+}
+
+impl Gradient {
+    pub fn new(start: [FpVar; 3], step: [FpVar; 3]) -> Gradient {
+        Gradient {
+            start: start.map(FpVar::raw),
+            step: step.map(FpVar::raw),
+        }
+    }
+
+    /// Returns the gradient starting `n` pixels to the right
+    fn advance(&self, n: usize) -> Gradient {
+        let mut start = self.start;
+
+        for (s, &d) in start.iter_mut().zip(self.step.iter()) {
+            *s = s.wrapping_add(d.wrapping_mul(n as i32));
+        }
+
+        Gradient {
+            start,
+            step: self.step,
+        }
+    }
+}
+
+/// Dithering and truncation settings, equivalent to the contents of the `DitherTable`
+#[derive(Copy, Clone)]
+pub struct SpanDither {
+    /// Offsets added to the components before saturation, indexed by `[x % 4][y % 4]`. All 0 if
+    /// dithering is disabled.
+    offsets: [[i16; 4]; 4],
+    /// True if the 8bit components must be truncated to 5 bits
+    truncate: bool,
+}
+
+impl SpanDither {
+    pub fn new(dither_enabled: bool, draw_24bpp: bool) -> SpanDither {
+        SpanDither {
+            offsets: if dither_enabled {
+                DITHER_OFFSETS
+            } else {
+                [[0; 4]; 4]
+            },
+            truncate: !draw_24bpp,
+        }
+    }
+
+    /// Returns the dithering settings for the span of line `y` starting at `x`
+    pub fn row(&self, x: i32, y: i32) -> DitherRow {
+        let y = (y & 3) as usize;
+        let mut offsets = [0; 4];
+
+        for (i, o) in offsets.iter_mut().enumerate() {
+            let x = ((x + i as i32) & 3) as usize;
+
+            *o = self.offsets[x][y];
+        }
+
+        DitherRow {
+            offsets,
+            truncate: self.truncate,
+        }
+    }
+}
+
+/// Dithering settings for a given span: pixel `i` of the span uses `offsets[i % 4]`
+#[derive(Copy, Clone, Debug)]
+pub struct DitherRow {
+    pub offsets: [i16; 4],
+    pub truncate: bool,
+}
+
+impl DitherRow {
+    /// Dither, saturate and truncate `input` (which can be up to 9 bits wide) for the pixel at
+    /// index `i` in the span
+    fn apply(&self, i: usize, input: u32) -> u32 {
+        let out = (input as i16 + self.offsets[i & 3]).clamp(0, 0xff) as u32;
+
+        if self.truncate {
+            let out = out & 0xf8;
+
+            out | (out >> 5)
+        } else {
+            out
+        }
+    }
+}
+
+/// Parameters for drawing a span to the VRAM, see `Painter::draw_pixel`
+#[derive(Copy, Clone)]
+pub struct WriteParams {
+    /// True if we're drawing a textured primitive
+    pub textured: bool,
+    /// Semi-transparency mode if the primitive is semi-transparent
+    pub transparency: Option<TransparencyFunction>,
+    /// True to draw opaque pixel as semi-transparent
+    pub force_transparency: bool,
+    /// Mask bit settings
+    pub mask_settings: MaskSettings,
+}
+
+impl WriteParams {
+    fn check_mask_bit(&self) -> bool {
+        self.mask_settings.check_mask_bit()
+    }
+
+    fn or_mask(&self) -> u32 {
+        self.mask_settings.or_mask.0
+    }
+}
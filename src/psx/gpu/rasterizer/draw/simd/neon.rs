@@ -0,0 +1,243 @@
+//! NEON span routines, processing 4 pixels at a time. NEON is mandatory on aarch64 so these are
+//! always available.
+
+use super::{scalar, DitherRow, Gradient, SpanOps, WriteParams, MASK_BITS};
+use crate::psx::gpu::rasterizer::draw::fixed_point::FP_VAR_SHIFT;
+use crate::psx::gpu::rasterizer::draw::Pixel;
+use crate::psx::gpu::TransparencyFunction;
+use std::arch::aarch64::*;
+
+/// Number of pixels in a vector
+const LANES: usize = 4;
+
+pub fn ops() -> SpanOps {
+    SpanOps {
+        name: "NEON",
+        shade: |out, gradient| unsafe { shade(out, gradient) },
+        dither: |pixels, row| unsafe { dither(pixels, row) },
+        modulate: |pixels, colors, dither| unsafe { modulate(pixels, colors, dither) },
+        write: |line, fg, texels, params| unsafe { write(line, fg, texels, params) },
+    }
+}
+
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn load(pixels: &[Pixel], i: usize) -> uint32x4_t {
+    debug_assert!(i + LANES <= pixels.len());
+
+    vld1q_u32(pixels.as_ptr().add(i) as *const u32)
+}
+
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn store(pixels: &mut [Pixel], i: usize, v: uint32x4_t) {
+    debug_assert!(i + LANES <= pixels.len());
+
+    vst1q_u32(pixels.as_mut_ptr().add(i) as *mut u32, v)
+}
+
+/// Replace the mask bits of `color` with those of `mask`
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn with_mask(color: uint32x4_t, mask: uint32x4_t) -> uint32x4_t {
+    vbslq_u32(vdupq_n_u32(MASK_BITS), mask, color)
+}
+
+/// Returns the dithering offsets for the low and high halves of a vector widened to 16bit
+/// components
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn dither_offsets(dither: &DitherRow) -> (int16x8_t, int16x8_t) {
+    let [o0, o1, o2, o3] = dither.offsets;
+
+    let lo = [o0, o0, o0, o0, o1, o1, o1, o1];
+    let hi = [o2, o2, o2, o2, o3, o3, o3, o3];
+
+    (vld1q_s16(lo.as_ptr()), vld1q_s16(hi.as_ptr()))
+}
+
+/// Dither, saturate and truncate 16bit components
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn dither_components(c: uint16x8_t, offsets: int16x8_t, truncate: bool) -> uint8x8_t {
+    let c = vaddq_s16(vreinterpretq_s16_u16(c), offsets);
+    let c = vmaxq_s16(c, vdupq_n_s16(0));
+    let c = vminq_s16(c, vdupq_n_s16(0xff));
+    let c = vmovn_u16(vreinterpretq_u16_s16(c));
+
+    if truncate {
+        let c = vand_u8(c, vdup_n_u8(0xf8));
+
+        vorr_u8(c, vshr_n_u8::<5>(c))
+    } else {
+        c
+    }
+}
+
+/// Semi-transparency blending of the foreground pixels `f` with the background pixels `b`. The
+/// mask bit of the result is always cleared.
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn blend(f: uint32x4_t, b: uint32x4_t, mode: TransparencyFunction) -> uint32x4_t {
+    let f = vreinterpretq_u8_u32(f);
+    let b = vreinterpretq_u8_u32(b);
+
+    let c = match mode {
+        TransparencyFunction::Average => vhaddq_u8(f, b),
+        TransparencyFunction::Add => vqaddq_u8(f, b),
+        TransparencyFunction::Sub => vqsubq_u8(b, f),
+        TransparencyFunction::QuarterAdd => vqaddq_u8(vshrq_n_u8::<2>(f), b),
+    };
+
+    vbicq_u32(vreinterpretq_u32_u8(c), vdupq_n_u32(MASK_BITS))
+}
+
+/// Returns `[s, s + d, s + 2 * d, s + 3 * d]` with wrapping arithmetic
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn ramp(s: i32, d: i32) -> int32x4_t {
+    let v = [
+        s,
+        s.wrapping_add(d),
+        s.wrapping_add(d.wrapping_mul(2)),
+        s.wrapping_add(d.wrapping_mul(3)),
+    ];
+
+    vld1q_s32(v.as_ptr())
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn shade(out: &mut [Pixel], gradient: &Gradient) {
+    let [r, g, b] = gradient.start;
+    let [dr, dg, db] = gradient.step;
+
+    let mut r = ramp(r, dr);
+    let mut g = ramp(g, dg);
+    let mut b = ramp(b, db);
+
+    let dr = vdupq_n_s32(dr.wrapping_mul(LANES as i32));
+    let dg = vdupq_n_s32(dg.wrapping_mul(LANES as i32));
+    let db = vdupq_n_s32(db.wrapping_mul(LANES as i32));
+
+    let byte = vdupq_n_u32(0xff);
+    let n = out.len() - out.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let component = |v: int32x4_t| {
+            vandq_u32(
+                vreinterpretq_u32_s32(vshrq_n_s32::<{ FP_VAR_SHIFT as i32 }>(v)),
+                byte,
+            )
+        };
+
+        let p = vorrq_u32(
+            vshlq_n_u32::<16>(component(r)),
+            vshlq_n_u32::<8>(component(g)),
+        );
+
+        store(out, i, vorrq_u32(p, component(b)));
+
+        r = vaddq_s32(r, dr);
+        g = vaddq_s32(g, dg);
+        b = vaddq_s32(b, db);
+    }
+
+    scalar::shade(&mut out[n..], &gradient.advance(n));
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn dither(pixels: &mut [Pixel], dither: &DitherRow) {
+    let (off_lo, off_hi) = dither_offsets(dither);
+    let n = pixels.len() - pixels.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let p = load(pixels, i);
+        let c = vreinterpretq_u8_u32(p);
+
+        let lo = dither_components(vmovl_u8(vget_low_u8(c)), off_lo, dither.truncate);
+        let hi = dither_components(vmovl_u8(vget_high_u8(c)), off_hi, dither.truncate);
+
+        let c = vreinterpretq_u32_u8(vcombine_u8(lo, hi));
+
+        store(pixels, i, with_mask(c, p));
+    }
+
+    scalar::dither(&mut pixels[n..], dither);
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn modulate(pixels: &mut [Pixel], colors: &[Pixel], dither: &DitherRow) {
+    assert_eq!(pixels.len(), colors.len());
+
+    let (off_lo, off_hi) = dither_offsets(dither);
+    let n = pixels.len() - pixels.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let p = load(pixels, i);
+        let t = vreinterpretq_u8_u32(p);
+        let c = vreinterpretq_u8_u32(load(colors, i));
+
+        let lo = vshrq_n_u16::<7>(vmull_u8(vget_low_u8(t), vget_low_u8(c)));
+        let hi = vshrq_n_u16::<7>(vmull_u8(vget_high_u8(t), vget_high_u8(c)));
+
+        let lo = dither_components(lo, off_lo, dither.truncate);
+        let hi = dither_components(hi, off_hi, dither.truncate);
+
+        let c = vreinterpretq_u32_u8(vcombine_u8(lo, hi));
+
+        store(pixels, i, with_mask(c, p));
+    }
+
+    scalar::modulate(&mut pixels[n..], &colors[n..], dither);
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn write(line: &mut [Pixel], fg: &[Pixel], texels: &[Pixel], params: &WriteParams) {
+    assert_eq!(line.len(), fg.len());
+    assert_eq!(line.len(), texels.len());
+
+    let mask_bits = vdupq_n_u32(MASK_BITS);
+    let set_mask = vdupq_n_u32(1 << 24);
+    let or_mask = vdupq_n_u32(params.or_mask());
+    let n = line.len() - line.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let b = load(line, i);
+        let f = load(fg, i);
+
+        // Pixels that must be left untouched
+        let mut skip = vdupq_n_u32(0);
+
+        if params.textured {
+            skip = vceqzq_u32(load(texels, i));
+        }
+
+        if params.check_mask_bit() {
+            skip = vorrq_u32(skip, vtstq_u32(b, mask_bits));
+        }
+
+        let opaque = if params.force_transparency {
+            blend(f, b, TransparencyFunction::Average)
+        } else {
+            f
+        };
+
+        let color = match params.transparency {
+            Some(mode) if params.textured => {
+                // Only the texels with the mask bit set are semi-transparent
+                let transparent = vorrq_u32(blend(f, b, mode), set_mask);
+                let is_transparent = vtstq_u32(f, mask_bits);
+
+                vbslq_u32(is_transparent, transparent, opaque)
+            }
+            Some(mode) => blend(f, b, mode),
+            None => opaque,
+        };
+
+        let color = vorrq_u32(color, or_mask);
+
+        store(line, i, vbslq_u32(skip, b, color));
+    }
+
+    scalar::write(&mut line[n..], &fg[n..], &texels[n..], params);
+}
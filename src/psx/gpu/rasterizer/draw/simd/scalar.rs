@@ -0,0 +1,93 @@
+//! Portable span routines, used as a fallback and as the reference for the vectorized variants
+
+use super::{DitherRow, Gradient, SpanOps, WriteParams, MASK_BITS};
+use crate::psx::gpu::rasterizer::draw::fixed_point::FP_VAR_SHIFT;
+use crate::psx::gpu::rasterizer::draw::Pixel;
+use crate::psx::gpu::TransparencyFunction;
+
+pub fn ops() -> SpanOps {
+    SpanOps {
+        name: "scalar",
+        shade,
+        dither,
+        modulate,
+        write,
+    }
+}
+
+/// Convert a raw `FpVar` to an 8bit color component
+fn component(v: i32) -> u32 {
+    ((v >> FP_VAR_SHIFT) as u8) as u32
+}
+
+pub fn shade(out: &mut [Pixel], gradient: &Gradient) {
+    let [mut r, mut g, mut b] = gradient.start;
+    let [dr, dg, db] = gradient.step;
+
+    for p in out {
+        *p = Pixel((component(r) << 16) | (component(g) << 8) | component(b));
+
+        r = r.wrapping_add(dr);
+        g = g.wrapping_add(dg);
+        b = b.wrapping_add(db);
+    }
+}
+
+pub fn dither(pixels: &mut [Pixel], dither: &DitherRow) {
+    for (i, p) in pixels.iter_mut().enumerate() {
+        let r = dither.apply(i, p.red() as u32);
+        let g = dither.apply(i, p.green() as u32);
+        let b = dither.apply(i, p.blue() as u32);
+
+        p.0 = (p.0 & MASK_BITS) | (r << 16) | (g << 8) | b;
+    }
+}
+
+pub fn modulate(pixels: &mut [Pixel], colors: &[Pixel], dither: &DitherRow) {
+    for (i, (p, c)) in pixels.iter_mut().zip(colors.iter()).enumerate() {
+        // In order to normalize the value we should be shifting by 8, but texture blending
+        // actually doubles the value, hence the - 1.
+        let r = (p.red() as u32 * c.red() as u32) >> (8 - 1);
+        let g = (p.green() as u32 * c.green() as u32) >> (8 - 1);
+        let b = (p.blue() as u32 * c.blue() as u32) >> (8 - 1);
+
+        let r = dither.apply(i, r);
+        let g = dither.apply(i, g);
+        let b = dither.apply(i, b);
+
+        p.0 = (p.0 & MASK_BITS) | (r << 16) | (g << 8) | b;
+    }
+}
+
+pub fn write(line: &mut [Pixel], fg: &[Pixel], texels: &[Pixel], params: &WriteParams) {
+    for (i, (bg, &fg)) in line.iter_mut().zip(fg.iter()).enumerate() {
+        if params.textured && texels[i].is_nul() {
+            continue;
+        }
+
+        if !params.mask_settings.can_draw_to(*bg) {
+            continue;
+        }
+
+        let mut color = fg;
+
+        match params.transparency {
+            // If the draw command is semi-transparent and the texture mask bit is set, this is a
+            // transparent pixel. If the draw command is not textured all pixels are transparent.
+            Some(mode) if !params.textured || color.mask() => {
+                color.apply_transparency(*bg, mode);
+
+                if params.textured {
+                    color.set_mask();
+                }
+            }
+            _ => {
+                if params.force_transparency {
+                    color.apply_transparency(*bg, TransparencyFunction::Average);
+                }
+            }
+        }
+
+        *bg = params.mask_settings.mask(color);
+    }
+}
@@ -0,0 +1,248 @@
+//! SSE2 span routines, processing 4 pixels at a time. SSE2 is part of the x86_64 baseline so
+//! these are always available.
+
+use super::{scalar, DitherRow, Gradient, SpanOps, WriteParams, MASK_BITS};
+use crate::psx::gpu::rasterizer::draw::fixed_point::FP_VAR_SHIFT;
+use crate::psx::gpu::rasterizer::draw::Pixel;
+use crate::psx::gpu::TransparencyFunction;
+use std::arch::x86_64::*;
+
+/// Number of pixels in a vector
+const LANES: usize = 4;
+
+pub fn ops() -> SpanOps {
+    SpanOps {
+        name: "SSE2",
+        shade: |out, gradient| unsafe { shade(out, gradient) },
+        dither: |pixels, row| unsafe { dither(pixels, row) },
+        modulate: |pixels, colors, dither| unsafe { modulate(pixels, colors, dither) },
+        write: |line, fg, texels, params| unsafe { write(line, fg, texels, params) },
+    }
+}
+
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn load(pixels: &[Pixel], i: usize) -> __m128i {
+    debug_assert!(i + LANES <= pixels.len());
+
+    _mm_loadu_si128(pixels.as_ptr().add(i) as *const __m128i)
+}
+
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn store(pixels: &mut [Pixel], i: usize, v: __m128i) {
+    debug_assert!(i + LANES <= pixels.len());
+
+    _mm_storeu_si128(pixels.as_mut_ptr().add(i) as *mut __m128i, v)
+}
+
+/// Returns `a` where `mask` is set, `b` elsewhere
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn select(mask: __m128i, a: __m128i, b: __m128i) -> __m128i {
+    _mm_or_si128(_mm_and_si128(mask, a), _mm_andnot_si128(mask, b))
+}
+
+/// Replace the mask bits of `color` with those of `mask`
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn with_mask(color: __m128i, mask: __m128i) -> __m128i {
+    select(_mm_set1_epi32(MASK_BITS as i32), mask, color)
+}
+
+/// Returns the dithering offsets for the low and high halves of a vector unpacked to 16bit
+/// components
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn dither_offsets(dither: &DitherRow) -> (__m128i, __m128i) {
+    let [o0, o1, o2, o3] = dither.offsets;
+
+    (
+        _mm_setr_epi16(o0, o0, o0, o0, o1, o1, o1, o1),
+        _mm_setr_epi16(o2, o2, o2, o2, o3, o3, o3, o3),
+    )
+}
+
+/// Dither, saturate and truncate 16bit components
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn dither_components(c: __m128i, offsets: __m128i, truncate: bool) -> __m128i {
+    let c = _mm_add_epi16(c, offsets);
+    let c = _mm_max_epi16(c, _mm_setzero_si128());
+    let c = _mm_min_epi16(c, _mm_set1_epi16(0xff));
+
+    if truncate {
+        let c = _mm_and_si128(c, _mm_set1_epi16(0xf8));
+
+        _mm_or_si128(c, _mm_srli_epi16::<5>(c))
+    } else {
+        c
+    }
+}
+
+/// Semi-transparency blending of the foreground pixels `f` with the background pixels `b`. The
+/// mask bit of the result is always cleared.
+#[inline]
+#[target_feature(enable = "sse2")]
+unsafe fn blend(f: __m128i, b: __m128i, mode: TransparencyFunction) -> __m128i {
+    let c = match mode {
+        TransparencyFunction::Average => {
+            // (f + b) / 2 without overflowing into the next component
+            let half = _mm_and_si128(
+                _mm_srli_epi32::<1>(_mm_xor_si128(f, b)),
+                _mm_set1_epi8(0x7f),
+            );
+
+            _mm_add_epi8(_mm_and_si128(f, b), half)
+        }
+        TransparencyFunction::Add => _mm_adds_epu8(f, b),
+        TransparencyFunction::Sub => _mm_subs_epu8(b, f),
+        TransparencyFunction::QuarterAdd => {
+            let quarter = _mm_and_si128(_mm_srli_epi32::<2>(f), _mm_set1_epi8(0x3f));
+
+            _mm_adds_epu8(quarter, b)
+        }
+    };
+
+    _mm_andnot_si128(_mm_set1_epi32(MASK_BITS as i32), c)
+}
+
+#[target_feature(enable = "sse2")]
+pub unsafe fn shade(out: &mut [Pixel], gradient: &Gradient) {
+    let [r, g, b] = gradient.start;
+    let [dr, dg, db] = gradient.step;
+
+    let ramp = |s: i32, d: i32| {
+        _mm_setr_epi32(
+            s,
+            s.wrapping_add(d),
+            s.wrapping_add(d.wrapping_mul(2)),
+            s.wrapping_add(d.wrapping_mul(3)),
+        )
+    };
+
+    let mut r = ramp(r, dr);
+    let mut g = ramp(g, dg);
+    let mut b = ramp(b, db);
+
+    let dr = _mm_set1_epi32(dr.wrapping_mul(LANES as i32));
+    let dg = _mm_set1_epi32(dg.wrapping_mul(LANES as i32));
+    let db = _mm_set1_epi32(db.wrapping_mul(LANES as i32));
+
+    let byte = _mm_set1_epi32(0xff);
+    let n = out.len() - out.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let cr = _mm_and_si128(_mm_srai_epi32::<{ FP_VAR_SHIFT as i32 }>(r), byte);
+        let cg = _mm_and_si128(_mm_srai_epi32::<{ FP_VAR_SHIFT as i32 }>(g), byte);
+        let cb = _mm_and_si128(_mm_srai_epi32::<{ FP_VAR_SHIFT as i32 }>(b), byte);
+
+        let p = _mm_or_si128(_mm_slli_epi32::<16>(cr), _mm_slli_epi32::<8>(cg));
+
+        store(out, i, _mm_or_si128(p, cb));
+
+        r = _mm_add_epi32(r, dr);
+        g = _mm_add_epi32(g, dg);
+        b = _mm_add_epi32(b, db);
+    }
+
+    scalar::shade(&mut out[n..], &gradient.advance(n));
+}
+
+#[target_feature(enable = "sse2")]
+pub unsafe fn dither(pixels: &mut [Pixel], dither: &DitherRow) {
+    let (off_lo, off_hi) = dither_offsets(dither);
+    let zero = _mm_setzero_si128();
+    let n = pixels.len() - pixels.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let p = load(pixels, i);
+
+        let lo = dither_components(_mm_unpacklo_epi8(p, zero), off_lo, dither.truncate);
+        let hi = dither_components(_mm_unpackhi_epi8(p, zero), off_hi, dither.truncate);
+
+        store(pixels, i, with_mask(_mm_packus_epi16(lo, hi), p));
+    }
+
+    scalar::dither(&mut pixels[n..], dither);
+}
+
+#[target_feature(enable = "sse2")]
+pub unsafe fn modulate(pixels: &mut [Pixel], colors: &[Pixel], dither: &DitherRow) {
+    assert_eq!(pixels.len(), colors.len());
+
+    let (off_lo, off_hi) = dither_offsets(dither);
+    let zero = _mm_setzero_si128();
+    let n = pixels.len() - pixels.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let t = load(pixels, i);
+        let c = load(colors, i);
+
+        // The products fit in 16 bits since both operands are 8bit wide
+        let lo = _mm_mullo_epi16(_mm_unpacklo_epi8(t, zero), _mm_unpacklo_epi8(c, zero));
+        let hi = _mm_mullo_epi16(_mm_unpackhi_epi8(t, zero), _mm_unpackhi_epi8(c, zero));
+
+        let lo = dither_components(_mm_srli_epi16::<7>(lo), off_lo, dither.truncate);
+        let hi = dither_components(_mm_srli_epi16::<7>(hi), off_hi, dither.truncate);
+
+        store(pixels, i, with_mask(_mm_packus_epi16(lo, hi), t));
+    }
+
+    scalar::modulate(&mut pixels[n..], &colors[n..], dither);
+}
+
+#[target_feature(enable = "sse2")]
+pub unsafe fn write(line: &mut [Pixel], fg: &[Pixel], texels: &[Pixel], params: &WriteParams) {
+    assert_eq!(line.len(), fg.len());
+    assert_eq!(line.len(), texels.len());
+
+    let zero = _mm_setzero_si128();
+    let ones = _mm_set1_epi32(-1);
+    let mask_bits = _mm_set1_epi32(MASK_BITS as i32);
+    let set_mask = _mm_set1_epi32(1 << 24);
+    let or_mask = _mm_set1_epi32(params.or_mask() as i32);
+    let n = line.len() - line.len() % LANES;
+
+    for i in (0..n).step_by(LANES) {
+        let b = load(line, i);
+        let f = load(fg, i);
+
+        // Pixels that must be left untouched
+        let mut skip = zero;
+
+        if params.textured {
+            skip = _mm_cmpeq_epi32(load(texels, i), zero);
+        }
+
+        if params.check_mask_bit() {
+            let unmasked = _mm_cmpeq_epi32(_mm_and_si128(b, mask_bits), zero);
+
+            skip = _mm_or_si128(skip, _mm_andnot_si128(unmasked, ones));
+        }
+
+        let opaque = if params.force_transparency {
+            blend(f, b, TransparencyFunction::Average)
+        } else {
+            f
+        };
+
+        let color = match params.transparency {
+            Some(mode) if params.textured => {
+                // Only the texels with the mask bit set are semi-transparent
+                let transparent = _mm_or_si128(blend(f, b, mode), set_mask);
+                let is_opaque = _mm_cmpeq_epi32(_mm_and_si128(f, mask_bits), zero);
+
+                select(is_opaque, opaque, transparent)
+            }
+            Some(mode) => blend(f, b, mode),
+            None => opaque,
+        };
+
+        let color = _mm_or_si128(color, or_mask);
+
+        store(line, i, select(skip, b, color));
+    }
+
+    scalar::write(&mut line[n..], &fg[n..], &texels[n..], params);
+}
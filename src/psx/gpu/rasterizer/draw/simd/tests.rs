@@ -0,0 +1,300 @@
+//! Span routine tests: all the accelerated implementations must match the scalar code bit for bit
+
+use super::super::tests::Rng;
+use super::{scalar, Gradient, SpanDither, SpanOps, WriteParams, SPAN_CHUNK};
+use crate::psx::gpu::rasterizer::draw::{Pixel, Rasterizer};
+use crate::psx::gpu::{MaskSettings, TransparencyFunction};
+use std::hint::black_box;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+/// Span lengths to test, covering all combinations of full vectors and leftover pixels
+const LENGTHS: RangeInclusive<usize> = 0..=(SPAN_CHUNK + 7);
+
+const TRANSPARENCY_MODES: [Option<TransparencyFunction>; 5] = [
+    None,
+    Some(TransparencyFunction::Average),
+    Some(TransparencyFunction::Add),
+    Some(TransparencyFunction::Sub),
+    Some(TransparencyFunction::QuarterAdd),
+];
+
+/// Run `check` with every accelerated implementation supported by the host
+fn for_each_accelerated<F>(mut check: F)
+where
+    F: FnMut(&SpanOps),
+{
+    for ops in SpanOps::available().iter().skip(1) {
+        check(ops);
+    }
+}
+
+/// Random VRAM pixel, with or without the mask bit
+fn random_pixel(rng: &mut Rng) -> Pixel {
+    Pixel((rng.next() & 0xff_ffff) | ((rng.next() & 1) << 24))
+}
+
+/// Random texel, including some nul texels
+fn random_texel(rng: &mut Rng) -> Pixel {
+    if rng.next() & 7 == 0 {
+        Pixel::black()
+    } else {
+        random_pixel(rng)
+    }
+}
+
+fn random_span(rng: &mut Rng, len: usize, gen: fn(&mut Rng) -> Pixel) -> Vec<Pixel> {
+    (0..len).map(|_| gen(rng)).collect()
+}
+
+fn random_gradient(rng: &mut Rng) -> Gradient {
+    let mut component = || (rng.next() as i32, rng.range(-0x4_0000, 0x4_0000));
+
+    let (r, dr) = component();
+    let (g, dg) = component();
+    let (b, db) = component();
+
+    Gradient {
+        start: [r, g, b],
+        step: [dr, dg, db],
+    }
+}
+
+#[test]
+fn span_dither_matches_table() {
+    for &dither in &[false, true] {
+        for &draw_24bpp in &[false, true] {
+            let mut rasterizer = Rasterizer::new();
+
+            rasterizer
+                .tex_mapper
+                .set_draw_mode(if dither { 1 << 9 } else { 0 });
+            rasterizer.draw_24bpp = draw_24bpp;
+            rasterizer.rebuild_dither_table();
+
+            let span_dither = SpanDither::new(rasterizer.dither_enabled, draw_24bpp);
+
+            for x in 0..4 {
+                for y in 0..4 {
+                    let row = span_dither.row(x as i32, y as i32);
+
+                    for input in 0..0x200 {
+                        assert_eq!(
+                            row.apply(0, input as u32),
+                            rasterizer.dither_table[x][y][input] as u32,
+                            "dither {} 24bpp {} x {} y {} input {}",
+                            dither,
+                            draw_24bpp,
+                            x,
+                            y,
+                            input
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn shade_matches_scalar() {
+    let mut rng = Rng(0x1234_5678);
+
+    for_each_accelerated(|ops| {
+        for len in LENGTHS {
+            let gradient = random_gradient(&mut rng);
+
+            let mut expected = vec![Pixel::black(); len];
+            let mut out = vec![Pixel::black(); len];
+
+            scalar::shade(&mut expected, &gradient);
+            (ops.shade)(&mut out, &gradient);
+
+            assert_eq!(out, expected, "{} {:?}", ops.name, gradient);
+        }
+    });
+}
+
+#[test]
+fn dither_matches_scalar() {
+    let mut rng = Rng(0x8765_4321);
+
+    for_each_accelerated(|ops| {
+        for &(dither, draw_24bpp) in &[(false, false), (false, true), (true, false), (true, true)] {
+            for len in LENGTHS {
+                let row = SpanDither::new(dither, draw_24bpp).row(rng.range(0, 4), rng.range(0, 4));
+                let mut expected = random_span(&mut rng, len, random_pixel);
+                let mut out = expected.clone();
+
+                scalar::dither(&mut expected, &row);
+                (ops.dither)(&mut out, &row);
+
+                assert_eq!(out, expected, "{} {:?}", ops.name, row);
+            }
+        }
+    });
+}
+
+#[test]
+fn modulate_matches_scalar() {
+    let mut rng = Rng(0x0bad_cafe);
+
+    for_each_accelerated(|ops| {
+        for &(dither, draw_24bpp) in &[(false, false), (false, true), (true, false), (true, true)] {
+            for len in LENGTHS {
+                let row = SpanDither::new(dither, draw_24bpp).row(rng.range(0, 4), rng.range(0, 4));
+                let colors = random_span(&mut rng, len, random_pixel);
+                let mut expected = random_span(&mut rng, len, random_texel);
+                let mut out = expected.clone();
+
+                scalar::modulate(&mut expected, &colors, &row);
+                (ops.modulate)(&mut out, &colors, &row);
+
+                assert_eq!(out, expected, "{} {:?}", ops.name, row);
+            }
+        }
+    });
+}
+
+#[test]
+fn write_matches_scalar() {
+    let mut rng = Rng(0xdead_beef);
+
+    for_each_accelerated(|ops| {
+        for &transparency in &TRANSPARENCY_MODES {
+            for &textured in &[false, true] {
+                for &force_transparency in &[false, true] {
+                    for mask in 0..4 {
+                        let mut mask_settings = MaskSettings::new();
+                        mask_settings.set(mask);
+
+                        let params = WriteParams {
+                            textured,
+                            transparency,
+                            force_transparency,
+                            mask_settings,
+                        };
+
+                        for len in LENGTHS {
+                            let fg = random_span(&mut rng, len, random_pixel);
+                            let texels = random_span(&mut rng, len, random_texel);
+                            let mut expected = random_span(&mut rng, len, random_pixel);
+                            let mut out = expected.clone();
+
+                            scalar::write(&mut expected, &fg, &texels, &params);
+                            (ops.write)(&mut out, &fg, &texels, &params);
+
+                            assert_eq!(
+                                out, expected,
+                                "{} {:?} textured {} force {} mask {}",
+                                ops.name, transparency, textured, force_transparency, mask
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Number of timed samples for each benchmark
+const BENCH_SAMPLES: usize = 30;
+/// Number of spans processed in each sample
+const BENCH_ITERATIONS: usize = 5000;
+
+/// Criterion-style benchmark: run `f` for a while to warm up, then time `BENCH_SAMPLES` samples
+/// of `BENCH_ITERATIONS` calls each and report the throughput
+fn bench<F>(ops: &str, routine: &str, mut f: F)
+where
+    F: FnMut(),
+{
+    let warmup = Instant::now();
+    while warmup.elapsed() < Duration::from_millis(100) {
+        f();
+    }
+
+    let mut samples: Vec<Duration> = (0..BENCH_SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+
+            for _ in 0..BENCH_ITERATIONS {
+                f();
+            }
+
+            start.elapsed()
+        })
+        .collect();
+
+    samples.sort();
+
+    let mpixels = (BENCH_ITERATIONS * SPAN_CHUNK) as f64 / 1_000_000.;
+    let throughput = |d: Duration| mpixels / d.as_secs_f64();
+
+    let mean = samples.iter().sum::<Duration>() / BENCH_SAMPLES as u32;
+
+    println!(
+        "{:<6} {:<22} median {:>9.1} Mpix/s  mean {:>9.1} Mpix/s  [{:.1} .. {:.1}]",
+        ops,
+        routine,
+        throughput(samples[BENCH_SAMPLES / 2]),
+        throughput(mean),
+        throughput(samples[BENCH_SAMPLES - 1]),
+        throughput(samples[0]),
+    );
+}
+
+/// Compare the throughput of the span routines. Run with:
+///
+/// `cargo test --release bench_span_ops -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn bench_span_ops() {
+    let mut rng = Rng(0x5eed_5eed);
+
+    let gradient = random_gradient(&mut rng);
+    let row = SpanDither::new(true, false).row(1, 2);
+    let colors = random_span(&mut rng, SPAN_CHUNK, random_pixel);
+    let texels = random_span(&mut rng, SPAN_CHUNK, random_texel);
+    let fg = random_span(&mut rng, SPAN_CHUNK, random_pixel);
+    let bg = random_span(&mut rng, SPAN_CHUNK, random_pixel);
+
+    let mut mask_settings = MaskSettings::new();
+    mask_settings.set(3);
+
+    for ops in SpanOps::available() {
+        let mut out = vec![Pixel::black(); SPAN_CHUNK];
+
+        bench(ops.name, "shade", || {
+            (ops.shade)(black_box(&mut out), black_box(&gradient))
+        });
+
+        bench(ops.name, "shade + dither", || {
+            (ops.shade)(&mut out, black_box(&gradient));
+            (ops.dither)(black_box(&mut out), &row);
+        });
+
+        bench(ops.name, "modulate", || {
+            out.copy_from_slice(&texels);
+            (ops.modulate)(black_box(&mut out), black_box(&colors), &row);
+        });
+
+        for &transparency in &TRANSPARENCY_MODES {
+            let params = WriteParams {
+                textured: true,
+                transparency,
+                force_transparency: false,
+                mask_settings,
+            };
+
+            let name = match transparency {
+                Some(mode) => format!("write {:?}", mode),
+                None => "write opaque".to_string(),
+            };
+
+            bench(ops.name, &name, || {
+                out.copy_from_slice(&bg);
+                (ops.write)(black_box(&mut out), black_box(&fg), &texels, &params);
+            });
+        }
+    }
+}
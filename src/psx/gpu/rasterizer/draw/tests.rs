@@ -3,17 +3,38 @@
 //! Unless otherwise noted the expected output was generated on a real PlayStation (model
 //! SCPH-7502, PAL).
 
-use super::{Command, CommandBuffer, Pixel, Rasterizer};
-use std::sync::mpsc;
-
-fn build_rasterizer() -> (
-    Rasterizer,
-    mpsc::Sender<CommandBuffer>,
-    mpsc::Receiver<CommandBuffer>,
-) {
+use super::{Command, CommandBuffer, Pixel, Rasterizer, RasterizerOption};
+use crate::psx::mdec::enhanced::{ChromaUpsampling, MacroblockCache, MdecEnhancement, Recorder};
+use std::sync::{mpsc, Arc};
+
+/// Run `commands` on a freshly reset rasterizer. The commands are run once on a single thread,
+/// then again with several worker threads to make sure that we end up in the exact same state.
+fn run_commands(commands: CommandBuffer) -> Rasterizer {
+    let rasterizer = run_with_threads(&commands, 1);
+
+    for threads in 2..=4 {
+        let r = run_with_threads(&commands, threads);
+
+        check_same_state(&rasterizer, &r, threads);
+    }
+
+    rasterizer
+}
+
+fn run_with_threads(commands: &[Command], threads: u8) -> Rasterizer {
     let (command_sender, command_receiver) = mpsc::channel();
+    let (frame_sender, _frame_receiver) = mpsc::channel();
+    let (serialization_sender, _serialization_receiver) = mpsc::channel();
 
-    let rasterizer = Rasterizer::new();
+    let mut rasterizer = Rasterizer::new();
+
+    rasterizer.set_worker_threads(threads);
+    if let Some(workers) = rasterizer.workers.as_mut() {
+        // Interleave the bands line-by-line and dispatch every batch to the workers to make the
+        // tests as thorough as possible
+        workers.tile_shift = 0;
+        workers.min_batch_len = 1;
+    }
 
     let init_commands = vec![
         // Reset
@@ -27,8 +48,43 @@ fn build_rasterizer() -> (
     ];
 
     command_sender.send(init_commands).unwrap();
+    command_sender.send(commands.to_vec()).unwrap();
+
+    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+
+    rasterizer
+}
+
+/// Check that the multithreaded rasterizer `r` ended up in the same state as the reference
+fn check_same_state(reference: &Rasterizer, r: &Rasterizer, threads: u8) {
+    assert_eq!(reference.vram.upscale_shift, r.vram.upscale_shift);
+
+    let width = 1024usize << r.vram.upscale_shift;
+
+    for (i, (expected, p)) in reference.vram.pixels.iter().zip(&r.vram.pixels).enumerate() {
+        assert_eq!(
+            expected.0,
+            p.0,
+            "{} threads: VRAM {}x{}: expected 0x{:x} got 0x{:x}",
+            threads,
+            i % width,
+            i / width,
+            expected.0,
+            p.0
+        );
+    }
+
+    let expected_cache = reference.tex_mapper.texture_cache.iter();
+    let cache = r.tex_mapper.texture_cache.iter();
 
-    (rasterizer, command_sender, command_receiver)
+    for (i, (expected, line)) in expected_cache.zip(cache).enumerate() {
+        assert!(
+            expected.tag == line.tag && expected.pixels == line.pixels,
+            "{} threads: texture cacheline {} mismatch",
+            threads,
+            i
+        );
+    }
 }
 
 fn vertex_coord(x: i16, y: i16) -> Command {
@@ -63,10 +119,6 @@ fn check_rasterizer(rasterizer: &Rasterizer, expected: &[&[Pixel]]) {
 
 #[test]
 fn quad_rect_solid_opaque() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         // Draw a red quad
         Command::Gp0(0x280000ff),
@@ -77,9 +129,7 @@ fn quad_rect_solid_opaque() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -103,10 +153,6 @@ fn quad_rect_solid_opaque() {
 
 #[test]
 fn triangle_solid_opaque_pyramid_up() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(5, 2),
@@ -115,9 +161,7 @@ fn triangle_solid_opaque_pyramid_up() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -136,10 +180,6 @@ fn triangle_solid_opaque_pyramid_up() {
 
 #[test]
 fn triangle_solid_opaque_pyramid_down() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(5, 5),
@@ -148,9 +188,7 @@ fn triangle_solid_opaque_pyramid_down() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -169,10 +207,6 @@ fn triangle_solid_opaque_pyramid_down() {
 
 #[test]
 fn triangle_solid_opaque_flat_up() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(5, 1),
@@ -181,9 +215,7 @@ fn triangle_solid_opaque_flat_up() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -203,10 +235,6 @@ fn triangle_solid_opaque_flat_up() {
 
 #[test]
 fn triangle_solid_opaque_flat_down() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(5, 6),
@@ -215,9 +243,7 @@ fn triangle_solid_opaque_flat_down() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -237,10 +263,6 @@ fn triangle_solid_opaque_flat_down() {
 
 #[test]
 fn triangle_solid_opaque_flat_right() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(6, 5),
@@ -249,9 +271,7 @@ fn triangle_solid_opaque_flat_right() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -274,10 +294,6 @@ fn triangle_solid_opaque_flat_right() {
 
 #[test]
 fn triangle_solid_opaque_flat_left() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(1, 5),
@@ -286,9 +302,7 @@ fn triangle_solid_opaque_flat_left() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -311,10 +325,6 @@ fn triangle_solid_opaque_flat_left() {
 
 #[test]
 fn triangle_solid_opaque_slant_top_left() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(2, 2),
@@ -323,9 +333,7 @@ fn triangle_solid_opaque_slant_top_left() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -345,10 +353,6 @@ fn triangle_solid_opaque_slant_top_left() {
 
 #[test]
 fn triangle_solid_opaque_slant_top_right() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(8, 2),
@@ -357,9 +361,7 @@ fn triangle_solid_opaque_slant_top_right() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -379,10 +381,6 @@ fn triangle_solid_opaque_slant_top_right() {
 
 #[test]
 fn triangle_solid_opaque_slant_bot_left() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(2, 6),
@@ -391,9 +389,7 @@ fn triangle_solid_opaque_slant_bot_left() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -413,10 +409,6 @@ fn triangle_solid_opaque_slant_bot_left() {
 
 #[test]
 fn triangle_solid_opaque_slant_bot_right() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(9, 6),
@@ -425,9 +417,7 @@ fn triangle_solid_opaque_slant_bot_right() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -447,10 +437,6 @@ fn triangle_solid_opaque_slant_bot_right() {
 
 #[test]
 fn triangle_solid_opaque_mid_right() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(1, 1),
@@ -459,9 +445,7 @@ fn triangle_solid_opaque_mid_right() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -483,10 +467,6 @@ fn triangle_solid_opaque_mid_right() {
 
 #[test]
 fn triangle_solid_opaque_mid_left() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(9, 0),
@@ -495,9 +475,7 @@ fn triangle_solid_opaque_mid_left() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -521,10 +499,6 @@ fn triangle_solid_opaque_mid_left() {
 /// Draw a large triangle with a non-trivial edge slope to catch precision errors
 #[test]
 fn triangle_solid_opaque_big1() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(0, 0),
@@ -533,9 +507,7 @@ fn triangle_solid_opaque_big1() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -569,10 +541,6 @@ fn triangle_solid_opaque_big1() {
 /// Same as big1 but with a small x offset change, enough to change the drawing slightly
 #[test]
 fn triangle_solid_opaque_big2() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x200000ff),
         vertex_coord(0, 0),
@@ -581,9 +549,7 @@ fn triangle_solid_opaque_big2() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -617,10 +583,6 @@ fn triangle_solid_opaque_big2() {
 /// The PSX only allows to draw triangles up to 512 pixels in height and 1024 pixels in width
 #[test]
 fn triangle_solid_opaque_draw_limits() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         // First a red triangle with the max possible size
         Command::Gp0(0x200000ff),
@@ -640,9 +602,7 @@ fn triangle_solid_opaque_draw_limits() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -665,10 +625,6 @@ fn triangle_solid_opaque_draw_limits() {
 /// same pixels
 #[test]
 fn triangle_solid_opaque_false_friends() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x2000_00ff),
         vertex_coord(1, 1),
@@ -683,9 +639,7 @@ fn triangle_solid_opaque_false_friends() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -715,10 +669,6 @@ fn triangle_solid_opaque_false_friends() {
 
 #[test]
 fn gouraud_rgb_right() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         Command::Gp0(0x300000ff),
         vertex_coord(1, 0),
@@ -729,9 +679,7 @@ fn gouraud_rgb_right() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let p = mbgr_px;
 
@@ -842,10 +790,6 @@ fn gouraud_rgb_right() {
 /// Test for an overflow issue in Spyro (PAL)
 #[test]
 fn test_overflow() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         // Clip top-left
         Command::Gp0(0xe3000000),
@@ -865,18 +809,12 @@ fn test_overflow() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    run_commands(commands);
 }
 
 /// Test for a broken triangle in PSX's intro when FpCoord::epsilon() is set to 1.
 #[test]
 fn test_bad_draw_psx_logo() {
-    let (mut rasterizer, command_channel, command_receiver) = build_rasterizer();
-    let (frame_sender, _frame_receiver) = mpsc::channel();
-    let (serialization_sender, _serialization_receiver) = mpsc::channel();
-
     let commands = vec![
         // Triangle
         Command::Gp0(0x200000ff),
@@ -886,9 +824,7 @@ fn test_bad_draw_psx_logo() {
         Command::Quit,
     ];
 
-    command_channel.send(commands).unwrap();
-
-    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+    let rasterizer = run_commands(commands);
 
     let x = Pixel::black();
     let r = bgr_px(0x0000ff);
@@ -938,3 +874,301 @@ fn test_bad_draw_psx_logo() {
 
     check_rasterizer(&rasterizer, &expected);
 }
+
+/*
+ * Multithreaded rasterization tests
+ */
+
+/// Small xorshift PRNG so that the random tests are reproducible
+pub(super) struct Rng(pub(super) u32);
+
+impl Rng {
+    pub(super) fn next(&mut self) -> u32 {
+        let mut x = self.0;
+
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+
+        self.0 = x;
+
+        x
+    }
+
+    pub(super) fn range(&mut self, min: i32, max: i32) -> i32 {
+        min + (self.next() % ((max - min) as u32)) as i32
+    }
+}
+
+/// Generate a random command stream mixing all polygon types with rects, lines, VRAM transfers
+/// and environment changes. Textures are read from the area we draw to to exercise the
+/// render-to-texture paths.
+fn random_commands(seed: u32, upscale_shift: u8, nprims: usize) -> CommandBuffer {
+    let mut rng = Rng(seed);
+    let mut commands = vec![Command::Option(RasterizerOption::UpscaleShift(upscale_shift))];
+
+    // Upload some random texture data to the top of the VRAM
+    commands.push(Command::Gp0(0xa0000000));
+    commands.push(Command::Gp0(0));
+    commands.push(Command::Gp0(768 | (256 << 16)));
+    for _ in 0..(768 * 256 / 2) {
+        commands.push(Command::Gp0(rng.next()));
+    }
+
+    let rand_pos = |rng: &mut Rng| {
+        let x = rng.range(-32, 320) as u16 as u32;
+        let y = rng.range(-32, 280) as u16 as u32;
+
+        Command::Gp0(x | (y << 16))
+    };
+
+    for _ in 0..nprims {
+        match rng.next() % 32 {
+            0 => {
+                // Draw mode, with random texture page, depth, dithering and transparency
+                let page_x = [0, 1, 2, 8, 9, 10][(rng.next() % 6) as usize];
+                commands.push(Command::Gp0(0xe1000000 | page_x | (rng.next() & 0x7f0)));
+            }
+            1 => commands.push(Command::Gp0(0xe2000000 | (rng.next() & 0xf_ffff))),
+            2 => {
+                let left = rng.range(0, 64) as u32;
+                let top = rng.range(0, 64) as u32;
+                let right = rng.range(128, 400) as u32;
+                let bottom = rng.range(128, 300) as u32;
+
+                commands.push(Command::Gp0(0xe3000000 | left | (top << 10)));
+                commands.push(Command::Gp0(0xe4000000 | right | (bottom << 10)));
+            }
+            3 => {
+                let x = rng.range(-16, 16) as u32 & 0x7ff;
+                let y = rng.range(-16, 16) as u32 & 0x7ff;
+
+                commands.push(Command::Gp0(0xe5000000 | x | (y << 11)));
+            }
+            4 => commands.push(Command::Gp0(0xe6000000 | (rng.next() & 3))),
+            5 => {
+                // Fill
+                commands.push(Command::Gp0(0x02000000 | (rng.next() & 0xff_ffff)));
+                commands.push(rand_pos(&mut rng));
+                commands.push(Command::Gp0(rng.next() & 0x003f_003f));
+            }
+            6 => {
+                // VRAM copy
+                commands.push(Command::Gp0(0x80000000));
+                commands.push(rand_pos(&mut rng));
+                commands.push(rand_pos(&mut rng));
+                commands.push(Command::Gp0(rng.next() & 0x003f_003f));
+            }
+            7 => {
+                // Rect
+                let opcode = 0x60 | (rng.next() & 0x1f);
+                let textured = opcode & 4 != 0;
+                let variable = (opcode >> 3) & 3 == 0;
+
+                commands.push(Command::Gp0((opcode << 24) | (rng.next() & 0xff_ffff)));
+                commands.push(rand_pos(&mut rng));
+                if textured {
+                    commands.push(Command::Gp0(rng.next() & 0x7fff_ffff));
+                }
+                if variable {
+                    commands.push(Command::Gp0(rng.next() & 0x003f_003f));
+                }
+            }
+            8 => {
+                // Line
+                let opcode = 0x40 | (rng.next() & 0x12);
+                let shaded = opcode & 0x10 != 0;
+
+                commands.push(Command::Gp0((opcode << 24) | (rng.next() & 0xff_ffff)));
+                commands.push(rand_pos(&mut rng));
+                if shaded {
+                    commands.push(Command::Gp0(rng.next() & 0xff_ffff));
+                }
+                commands.push(rand_pos(&mut rng));
+            }
+            9 => commands.push(Command::Gp0(0x01000000)),
+            _ => {
+                // Polygon
+                let opcode = 0x20 | (rng.next() & 0x1f);
+                let quad = opcode & 8 != 0;
+                let textured = opcode & 4 != 0;
+                let shaded = opcode & 0x10 != 0;
+                let nvertices = if quad { 4 } else { 3 };
+
+                commands.push(Command::Gp0((opcode << 24) | (rng.next() & 0xff_ffff)));
+                for v in 0..nvertices {
+                    if shaded && v > 0 {
+                        commands.push(Command::Gp0(rng.next() & 0xff_ffff));
+                    }
+                    commands.push(rand_pos(&mut rng));
+                    if textured {
+                        let uv = rng.next() & 0xffff;
+                        let extra = match v {
+                            // CLUT somewhere in the top of the VRAM
+                            0 => ((rng.next() % 64) | ((rng.next() % 300) << 6)) << 16,
+                            // Texture page and depth
+                            1 => {
+                                let page_x = [0, 1, 2, 8, 9, 10][(rng.next() % 6) as usize];
+                                (page_x | (rng.next() & 0x1f0)) << 16
+                            }
+                            _ => 0,
+                        };
+                        commands.push(Command::Gp0(uv | extra));
+                    }
+                }
+            }
+        }
+    }
+
+    commands.push(Command::Quit);
+
+    commands
+}
+
+#[test]
+fn multithreaded_random_native() {
+    for seed in 1..=3u32 {
+        run_commands(random_commands(seed.wrapping_mul(0x1234_5677), 0, 800));
+    }
+}
+
+#[test]
+fn multithreaded_random_upscaled() {
+    for seed in 1..=2u32 {
+        run_commands(random_commands(seed.wrapping_mul(0x8765_4321), 1, 300));
+    }
+}
+
+#[test]
+fn multithreaded_interlaced() {
+    let mut commands = vec![
+        // 640x480i
+        Command::Gp1(0x08000027),
+        Command::Gp1(0x03000000),
+        Command::Gp0(0xe4000000 | 639 | (479 << 10)),
+    ];
+
+    let mut rng = Rng(0xdead_beef);
+
+    for frame in 0..4 {
+        commands.push(Command::FieldChanged(frame & 1 != 0));
+
+        for line in 0..64 {
+            // Big shaded triangles over the display area
+            commands.push(Command::Gp0(0x30000000 | (rng.next() & 0xff_ffff)));
+            for _ in 0..3 {
+                let x = rng.range(-64, 700) as u16 as u32;
+                let y = rng.range(-64, 540) as u16 as u32;
+
+                commands.push(Command::Gp0(x | (y << 16)));
+                commands.push(Command::Gp0(rng.next() & 0xff_ffff));
+            }
+            commands.pop();
+
+            commands.push(Command::EndOfLine(0x10 + line * 4));
+        }
+
+        commands.push(Command::EndOfFrame);
+    }
+
+    commands.push(Command::Quit);
+
+    run_commands(commands);
+}
+
+#[test]
+fn progressive_hack() {
+    for &hack in &[false, true] {
+        let commands = vec![
+            Command::Option(RasterizerOption::ProgressiveHack(hack)),
+            // 640x480i
+            Command::Gp1(0x08000027),
+            Command::Gp1(0x03000000),
+            Command::Gp0(0xe4000000 | 639 | (479 << 10)),
+            // We're displaying the top field
+            Command::FieldChanged(false),
+            // Monochrome quad over the top-left of the display area
+            Command::Gp0(0x28ffffff),
+            Command::Gp0(0),
+            Command::Gp0(64),
+            Command::Gp0(64 << 16),
+            Command::Gp0(64 | (64 << 16)),
+            Command::Quit,
+        ];
+
+        let rasterizer = run_commands(commands);
+
+        for y in 0..64 {
+            let drawn = rasterizer.vram.native_pixel(8, y) != Pixel::black();
+
+            // Without the hack the lines of the displayed field are left untouched
+            assert_eq!(drawn, hack || y & 1 != 0, "hack: {} line: {}", hack, y);
+        }
+    }
+}
+
+#[test]
+fn fmv_redraw() {
+    let cache = Arc::new(MacroblockCache::new());
+    let mut recorder = Recorder::new(cache.clone());
+
+    // Two flat macroblocks side by side, red on the left and cyan on the right
+    let mut upload = vec![0u16; 32 * 16];
+    for (i, &(cr, color)) in [(-40i8, 0x0011u16), (40, 0x4600)].iter().enumerate() {
+        for b in 0..4 {
+            recorder.record_block(b, &[0; 64], &[0; 64], &[cr; 64], &[color; 64], 1);
+        }
+
+        for y in 0..16 {
+            for x in 0..16 {
+                upload[y * 32 + i * 16 + x] = color;
+            }
+        }
+    }
+
+    let enhancement = MdecEnhancement {
+        chroma: ChromaUpsampling::Bilinear,
+        deblocking: false,
+    };
+
+    let mut commands = vec![
+        Command::Gp1(0x00000000),
+        Command::Option(RasterizerOption::UpscaleShift(1)),
+        Command::Option(RasterizerOption::MdecEnhancement(Some(enhancement))),
+        Command::Gp0(0xa0000000),
+        Command::Gp0(0),
+        Command::Gp0(32 | (16 << 16)),
+    ];
+    for p in upload.chunks(2) {
+        commands.push(Command::Gp0(u32::from(p[0]) | (u32::from(p[1]) << 16)));
+    }
+    commands.push(Command::Quit);
+
+    let (command_sender, command_receiver) = mpsc::channel();
+    let (frame_sender, _frame_receiver) = mpsc::channel();
+    let (serialization_sender, _serialization_receiver) = mpsc::channel();
+
+    let mut rasterizer = Rasterizer::new();
+    rasterizer.set_macroblock_cache(cache);
+
+    command_sender.send(commands).unwrap();
+    rasterizer.run(command_receiver, frame_sender, serialization_sender);
+
+    let red = |x: u32| rasterizer.vram.pixel(x, 0).red();
+
+    // Full precision color instead of the 15bpp one
+    assert_eq!(red(1), 72);
+    // Smooth transition across the macroblock boundary at the internal resolution
+    for x in (29..33).step_by(2) {
+        assert!(red(x) < red(x + 2), "{}: {} >= {}", x, red(x), red(x + 2));
+    }
+
+    // The console still reads back the data it uploaded
+    let mut rasterizer = rasterizer;
+    let frame = rasterizer.copy_vram_rect(0, 0, 32, 16);
+    for (i, &p) in upload.iter().enumerate() {
+        let (x, y) = ((i % 32) as u32, (i / 32) as u32);
+
+        assert_eq!(frame.pixel(x, y), u32::from(p), "VRAM {}x{}", x, y);
+    }
+}
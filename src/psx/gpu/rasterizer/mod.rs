@@ -7,13 +7,14 @@ mod optimized;
 mod config;
 mod benchmark;
 
-pub use draw::Pixel;
+pub use draw::{CropMode, DeinterlaceMode, OverscanCrop, Pixel};
 pub use optimized::{OptimizedRasterizer, RenderStats, ScanlineRenderer};
 pub use config::{RendererConfig, ProfileData};
 pub use benchmark::{RendererBenchmark, compare_renderers};
+use crate::psx::mdec::enhanced::{MacroblockCache, MdecEnhancement};
 use draw::Rasterizer;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::thread;
 
 /// This is the handle used from the main thread to communicate with the rasterizer
@@ -24,6 +25,8 @@ pub struct Handle {
     command_channel: mpsc::Sender<CommandBuffer>,
     frame_channel: mpsc::Receiver<Frame>,
     serialization_channel: mpsc::Receiver<Vec<u8>>,
+    /// Macroblocks recorded by the MDEC for the enhanced FMV output, shared with the rasterizer
+    macroblock_cache: Arc<MacroblockCache>,
 }
 
 impl Handle {
@@ -95,6 +98,10 @@ impl Handle {
         self.frame_channel.recv().unwrap()
     }
 
+    pub fn macroblock_cache(&self) -> Arc<MacroblockCache> {
+        self.macroblock_cache.clone()
+    }
+
     pub fn push_gp0(&mut self, gp0: u32) {
         self.push_command(Command::Gp0(gp0));
     }
@@ -171,6 +178,9 @@ pub fn start_from_state(command_buffer: CommandBuffer, mut rasterizer: Rasterize
     let (command_sender, command_receiver) = mpsc::channel();
     let (frame_sender, frame_receiver) = mpsc::channel();
     let (serialization_sender, serialization_receiver) = mpsc::channel();
+    let macroblock_cache = Arc::new(MacroblockCache::new());
+
+    rasterizer.set_macroblock_cache(macroblock_cache.clone());
 
     // macOS has smaller default thread stack sizes, so we need to be more conservative
     let stack_size = if cfg!(target_os = "macos") {
@@ -196,6 +206,7 @@ pub fn start_from_state(command_buffer: CommandBuffer, mut rasterizer: Rasterize
         command_channel: command_sender,
         frame_channel: frame_receiver,
         serialization_channel: serialization_receiver,
+        macroblock_cache,
     }
 }
 
@@ -245,6 +256,22 @@ pub enum RasterizerOption {
     PerspectiveCorrection(bool),
     SubPixelPrecision(bool),
     ColorBanding(bool),
+    /// Number of threads used to draw triangles. 0 means one thread per CPU core.
+    WorkerThreads(u8),
+    /// Deinterlacing method used for truly interlaced output
+    Deinterlace(DeinterlaceMode),
+    /// Draw and display both fields every frame in interlaced mode when the game renders full
+    /// frames
+    ProgressiveHack(bool),
+    /// How the display area is placed and cropped in the output frames
+    CropMode(CropMode),
+    /// Amount of overscan cropped on each edge of the output frames
+    OverscanCrop(OverscanCrop),
+    /// Center the display area in the output frames, ignoring its position on the screen
+    AutoCenter(bool),
+    /// Redraw the movies decoded by the MDEC at the internal resolution, `None` to display them as
+    /// uploaded by the game
+    MdecEnhancement(Option<MdecEnhancement>),
 }
 
 /// Buffer containing one rendered frame
@@ -267,6 +294,16 @@ impl Frame {
         }
     }
 
+    fn pixel(&self, x: u32, y: u32) -> u32 {
+        debug_assert!(x < self.width);
+        debug_assert!(y < self.height);
+
+        let x = x as usize;
+        let y = y as usize;
+
+        self.pixels[(y * self.width as usize) + x]
+    }
+
     fn set_pixel(&mut self, x: u32, y: u32, p: u32) {
         debug_assert!(x < self.width);
         debug_assert!(y < self.height);
@@ -2,7 +2,7 @@
 
 use super::{cpu, Psx};
 
-/// The PlayStation supports 10 interrupts
+/// The PlayStation supports 11 interrupts
 #[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Interrupt {
     /// Display in vertical blanking
@@ -21,6 +21,8 @@ pub enum Interrupt {
     PadMemCard = 7,
     /// SPU interrupt
     Spu = 9,
+    /// Light pen interrupt, triggered through the controller port by some light guns
+    LightPen = 10,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Copy)]
@@ -92,7 +94,7 @@ pub fn trigger(psx: &mut Psx, which: Interrupt) {
 
 pub fn set_mask(psx: &mut Psx, mask: u16) {
     // Temporary hack: trigger an error if a non-implemented interrupt is requested
-    let supported: [Interrupt; 8] = [
+    let supported: [Interrupt; 9] = [
         Interrupt::VBlank,
         Interrupt::CdRom,
         Interrupt::Dma,
@@ -101,6 +103,7 @@ pub fn set_mask(psx: &mut Psx, mask: u16) {
         Interrupt::Timer2,
         Interrupt::PadMemCard,
         Interrupt::Spu,
+        Interrupt::LightPen,
     ];
 
     let rem = supported
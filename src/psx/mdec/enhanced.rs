@@ -0,0 +1,565 @@
+//! High precision MDEC output
+//!
+//! Most movies are decoded to 15bpp and the hardware uses the same chroma sample for every 2x2
+//! group of pixels, which makes FMVs look very blocky once the rest of the picture is upscaled.
+//! When the enhanced output is enabled the decoder keeps the luma and chroma of every macroblock
+//! it decodes to 15bpp in a `MacroblockCache` shared with the rasterizer. When the game then
+//! uploads these pixels to the VRAM the rasterizer recognizes them and redraws them at the
+//! internal resolution from the full precision data, interpolating the chroma across macroblock
+//! boundaries. The data seen by the emulated console is never modified.
+//!
+//! 24bpp movies are displayed straight from the VRAM bytes so they can't be redrawn that way.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Number of macroblocks kept in the cache, a bit more than 3 frames of a 640x480 movie
+const CACHE_CAPACITY: usize = 4096;
+
+/// Filter used to upsample the chroma of the decoded movies
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ChromaUpsampling {
+    Bilinear,
+    /// Catmull-Rom bicubic interpolation
+    Bicubic,
+}
+
+/// Settings of the enhanced MDEC output
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MdecEnhancement {
+    /// Chroma upsampling filter. Also used for the luma when drawing above the native resolution.
+    pub chroma: ChromaUpsampling,
+    /// Smooth the edges between the 8x8 blocks
+    pub deblocking: bool,
+}
+
+/// Full precision macroblock decoded by the MDEC
+#[derive(Clone)]
+pub struct DecodedMacroblock {
+    /// `macroblock_key` of the 15bpp pixels output by the hardware
+    key: u64,
+    /// 16x16 luma, line by line
+    luma: [i8; 16 * 16],
+    /// 8x8 blue chroma
+    cb: [i8; 8 * 8],
+    /// 8x8 red chroma
+    cr: [i8; 8 * 8],
+    /// Highest quantization scale of the macroblock's blocks
+    qscale: u8,
+}
+
+impl DecodedMacroblock {
+    fn new() -> DecodedMacroblock {
+        DecodedMacroblock {
+            key: 0,
+            luma: [0; 16 * 16],
+            cb: [0; 8 * 8],
+            cr: [0; 8 * 8],
+            qscale: 0,
+        }
+    }
+}
+
+/// Hash of the 16x16 15bpp pixels of a macroblock (line by line), used to match the VRAM uploads
+/// with the decoded macroblocks
+pub fn macroblock_key(pixels: &[u16]) -> u64 {
+    // FNV-1a
+    pixels.iter().fold(0xcbf2_9ce4_8422_2325, |h, &p| {
+        (h ^ u64::from(p)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Macroblocks recently decoded by the MDEC, indexed by `macroblock_key`
+pub struct MacroblockCache {
+    inner: Mutex<CacheInner>,
+}
+
+struct CacheInner {
+    blocks: HashMap<u64, Arc<DecodedMacroblock>>,
+    /// Keys in insertion order, to evict the oldest macroblocks
+    order: VecDeque<u64>,
+}
+
+impl MacroblockCache {
+    pub fn new() -> MacroblockCache {
+        MacroblockCache {
+            inner: Mutex::new(CacheInner {
+                blocks: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn insert(&self, block: DecodedMacroblock) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = block.key;
+
+        if inner.blocks.insert(key, Arc::new(block)).is_none() {
+            inner.order.push_back(key);
+        }
+
+        if inner.order.len() > CACHE_CAPACITY {
+            if let Some(old) = inner.order.pop_front() {
+                inner.blocks.remove(&old);
+            }
+        }
+    }
+
+    /// Returns the macroblock whose 15bpp pixels hash to `key`, if it's been decoded recently
+    pub fn get(&self, key: u64) -> Option<Arc<DecodedMacroblock>> {
+        self.inner.lock().unwrap().blocks.get(&key).cloned()
+    }
+}
+
+impl Default for MacroblockCache {
+    fn default() -> MacroblockCache {
+        MacroblockCache::new()
+    }
+}
+
+/// Reassembles the macroblocks from the blocks generated by the MDEC and stores them in the cache
+pub struct Recorder {
+    cache: Arc<MacroblockCache>,
+    current: DecodedMacroblock,
+    /// 15bpp output of `current`, line by line
+    pixels: [u16; 16 * 16],
+}
+
+impl Recorder {
+    pub fn new(cache: Arc<MacroblockCache>) -> Recorder {
+        Recorder {
+            cache,
+            current: DecodedMacroblock::new(),
+            pixels: [0; 16 * 16],
+        }
+    }
+
+    /// Record the 8x8 `luma` block number `index` in the current macroblock (0: top-left,
+    /// 1: top-right, 2: bottom-left, 3: bottom-right) along with the macroblock's chroma and the
+    /// 15bpp `pixels` generated by the hardware. The macroblock is stored in the cache once its 4
+    /// luma blocks have been received.
+    pub fn record_block(
+        &mut self,
+        index: usize,
+        luma: &[i8; 64],
+        cb: &[i8; 64],
+        cr: &[i8; 64],
+        pixels: &[u16; 64],
+        qscale: u8,
+    ) {
+        if index == 0 {
+            self.current.cb = *cb;
+            self.current.cr = *cr;
+            self.current.qscale = 0;
+        }
+
+        self.current.qscale = self.current.qscale.max(qscale);
+
+        let x_off = (index & 1) * 8;
+        let y_off = (index >> 1) * 8;
+
+        for y in 0..8 {
+            let o = (y_off + y) * 16 + x_off;
+
+            self.current.luma[o..o + 8].copy_from_slice(&luma[y * 8..y * 8 + 8]);
+            self.pixels[o..o + 8].copy_from_slice(&pixels[y * 8..y * 8 + 8]);
+        }
+
+        if index == 3 {
+            self.current.key = macroblock_key(&self.pixels);
+            self.cache.insert(self.current.clone());
+        }
+    }
+}
+
+/// Macroblocks laid out the way they're displayed, used to filter across their boundaries
+pub struct MacroblockGrid {
+    columns: usize,
+    rows: usize,
+    blocks: Vec<Option<Arc<DecodedMacroblock>>>,
+    /// Luma of the whole grid, `columns * 16` samples per line
+    luma: Vec<f32>,
+    /// Blue chroma of the whole grid, `columns * 8` samples per line
+    cb: Vec<f32>,
+    /// Red chroma of the whole grid, `columns * 8` samples per line
+    cr: Vec<f32>,
+}
+
+impl MacroblockGrid {
+    /// Build a grid of `columns` x `rows` macroblocks from `blocks`, line by line. `None` entries
+    /// are holes in the grid: the filters never sample across them.
+    pub fn new(
+        columns: usize,
+        rows: usize,
+        blocks: Vec<Option<Arc<DecodedMacroblock>>>,
+        deblocking: bool,
+    ) -> MacroblockGrid {
+        assert_eq!(blocks.len(), columns * rows);
+
+        let mut grid = MacroblockGrid {
+            columns,
+            rows,
+            blocks,
+            luma: vec![0.; columns * rows * 16 * 16],
+            cb: vec![0.; columns * rows * 8 * 8],
+            cr: vec![0.; columns * rows * 8 * 8],
+        };
+
+        for (i, block) in grid.blocks.iter().enumerate() {
+            let block = match block {
+                Some(b) => b,
+                None => continue,
+            };
+
+            let (column, row) = (i % columns, i / columns);
+
+            for y in 0..16 {
+                for x in 0..16 {
+                    let o = (row * 16 + y) * columns * 16 + column * 16 + x;
+                    grid.luma[o] = f32::from(block.luma[y * 16 + x]);
+                }
+            }
+
+            for y in 0..8 {
+                for x in 0..8 {
+                    let o = (row * 8 + y) * columns * 8 + column * 8 + x;
+                    grid.cb[o] = f32::from(block.cb[y * 8 + x]);
+                    grid.cr[o] = f32::from(block.cr[y * 8 + x]);
+                }
+            }
+        }
+
+        if deblocking {
+            grid.deblock();
+        }
+
+        grid
+    }
+
+    /// Returns the macroblock at `column`, `row`
+    pub fn get(&self, column: usize, row: usize) -> Option<&Arc<DecodedMacroblock>> {
+        self.blocks[row * self.columns + column].as_ref()
+    }
+
+    /// Returns the macroblock containing the sample at `x`, `y` of a plane with `size` samples
+    /// per macroblock side
+    fn block_at(&self, size: usize, x: usize, y: usize) -> Option<&Arc<DecodedMacroblock>> {
+        self.get(x / size, y / size)
+    }
+
+    fn deblock(&mut self) {
+        let mut luma = std::mem::take(&mut self.luma);
+        let mut cb = std::mem::take(&mut self.cb);
+        let mut cr = std::mem::take(&mut self.cr);
+
+        // Luma has two 8x8 blocks per macroblock side, chroma a single one
+        self.deblock_plane(&mut luma, 16);
+        self.deblock_plane(&mut cb, 8);
+        self.deblock_plane(&mut cr, 8);
+
+        self.luma = luma;
+        self.cb = cb;
+        self.cr = cr;
+    }
+
+    /// Filter the edges between the 8x8 blocks of `plane`
+    fn deblock_plane(&self, plane: &mut [f32], size: usize) {
+        let width = self.columns * size;
+        let height = self.rows * size;
+
+        // Vertical edges
+        for x in (8..width).step_by(8) {
+            for y in 0..height {
+                if let Some(qscale) = self.edge_qscale(size, (x - 1, y), (x, y)) {
+                    let o = y * width + x;
+                    filter_edge(plane, [o - 2, o - 1, o, o + 1], qscale);
+                }
+            }
+        }
+
+        // Horizontal edges
+        for y in (8..height).step_by(8) {
+            for x in 0..width {
+                if let Some(qscale) = self.edge_qscale(size, (x, y - 1), (x, y)) {
+                    let o = y * width + x;
+                    filter_edge(plane, [o - 2 * width, o - width, o, o + width], qscale);
+                }
+            }
+        }
+    }
+
+    /// Returns the quantization scale used to filter the edge between samples `p` and `q`, or
+    /// `None` if one of them is in a hole
+    fn edge_qscale(&self, size: usize, p: (usize, usize), q: (usize, usize)) -> Option<u8> {
+        let p = self.block_at(size, p.0, p.1)?;
+        let q = self.block_at(size, q.0, q.1)?;
+
+        Some(p.qscale.max(q.qscale))
+    }
+
+    /// Draw the macroblock at `column`, `row` with `scale` output pixels per native pixel on each
+    /// axis. `put` is called with the coordinates of each output pixel relative to the top-left of
+    /// the macroblock and its RGB color.
+    pub fn render<F>(
+        &self,
+        column: usize,
+        row: usize,
+        scale: usize,
+        filter: ChromaUpsampling,
+        mut put: F,
+    ) where
+        F: FnMut(usize, usize, [u8; 3]),
+    {
+        let home = (column, row);
+
+        for oy in 0..16 * scale {
+            // Position of the center of the output pixel, in native pixels
+            let v = (row * 16) as f32 + (oy as f32 + 0.5) / scale as f32;
+
+            for ox in 0..16 * scale {
+                let u = (column * 16) as f32 + (ox as f32 + 0.5) / scale as f32;
+
+                let y = self.interpolate(&self.luma, 16, u - 0.5, v - 0.5, home, filter);
+                // Chroma samples are centered between two luma pixels on each axis
+                let cb = self.interpolate(&self.cb, 8, u / 2. - 0.5, v / 2. - 0.5, home, filter);
+                let cr = self.interpolate(&self.cr, 8, u / 2. - 0.5, v / 2. - 0.5, home, filter);
+
+                put(ox, oy, yuv_to_rgb(y, cb, cr));
+            }
+        }
+    }
+
+    /// Interpolate `plane` at `x`, `y` (in samples). `size` is the number of samples per
+    /// macroblock side and `home` the macroblock being drawn.
+    fn interpolate(
+        &self,
+        plane: &[f32],
+        size: usize,
+        x: f32,
+        y: f32,
+        home: (usize, usize),
+        filter: ChromaUpsampling,
+    ) -> f32 {
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let (tx, ty) = (x - x0, y - y0);
+
+        let (first, wx, wy) = match filter {
+            ChromaUpsampling::Bilinear => (0, [1. - tx, tx, 0., 0.], [1. - ty, ty, 0., 0.]),
+            ChromaUpsampling::Bicubic => (-1, catmull_rom(tx), catmull_rom(ty)),
+        };
+
+        let x0 = x0 as isize + first;
+        let y0 = y0 as isize + first;
+
+        let mut v = 0.;
+
+        for (j, &wy) in wy.iter().enumerate().filter(|(_, &w)| w != 0.) {
+            for (i, &wx) in wx.iter().enumerate().filter(|(_, &w)| w != 0.) {
+                let s = self.sample(plane, size, x0 + i as isize, y0 + j as isize, home);
+
+                v += s * wx * wy;
+            }
+        }
+
+        v
+    }
+
+    /// Returns the sample at `x`, `y` in `plane`. Samples outside of the grid or in a hole are
+    /// replaced by the closest sample on the same line or column of the `home` macroblock, or by
+    /// the closest sample in the `home` macroblock itself.
+    fn sample(&self, plane: &[f32], size: usize, x: isize, y: isize, home: (usize, usize)) -> f32 {
+        let (hx, hy) = ((home.0 * size) as isize, (home.1 * size) as isize);
+        let home_x = x.clamp(hx, hx + size as isize - 1);
+        let home_y = y.clamp(hy, hy + size as isize - 1);
+
+        let (x, y) = [(x, y), (x, home_y), (home_x, y)]
+            .iter()
+            .copied()
+            .find(|&(x, y)| self.is_valid(size, x, y))
+            .unwrap_or((home_x, home_y));
+
+        plane[y as usize * self.columns * size + x as usize]
+    }
+
+    /// Returns true if the sample at `x`, `y` of a plane with `size` samples per macroblock side
+    /// is part of a macroblock
+    fn is_valid(&self, size: usize, x: isize, y: isize) -> bool {
+        let width = (self.columns * size) as isize;
+        let height = (self.rows * size) as isize;
+
+        x >= 0
+            && y >= 0
+            && x < width
+            && y < height
+            && self.block_at(size, x as usize, y as usize).is_some()
+    }
+}
+
+/// Weak deblocking filter across the edge between `p0` and `q0`, modeled after H.264's. Steps
+/// that are too large compared to the quantization are probably real edges in the picture and are
+/// left alone.
+fn filter_edge(plane: &mut [f32], [p1, p0, q0, q1]: [usize; 4], qscale: u8) {
+    let q = f32::from(qscale);
+    let alpha = (q * 2. + 2.).min(64.);
+    let beta = q / 2. + 1.;
+    let tc = q / 4. + 1.;
+
+    let (a, b, c, d) = (plane[p1], plane[p0], plane[q0], plane[q1]);
+
+    if (b - c).abs() >= alpha || (a - b).abs() >= beta || (d - c).abs() >= beta {
+        return;
+    }
+
+    let delta = (((c - b) * 4. + (a - d)) / 8.).clamp(-tc, tc);
+
+    plane[p0] = b + delta;
+    plane[q0] = c - delta;
+}
+
+/// Catmull-Rom weights for the 4 samples around a position `t` past the second one
+fn catmull_rom(t: f32) -> [f32; 4] {
+    [
+        ((-t + 2.) * t - 1.) * t / 2.,
+        ((3. * t - 5.) * t * t + 2.) / 2.,
+        ((-3. * t + 4.) * t + 1.) * t / 2.,
+        (t - 1.) * t * t / 2.,
+    ]
+}
+
+/// Same color conversion as the hardware, without truncating the intermediate values
+fn yuv_to_rgb(y: f32, cb: f32, cr: f32) -> [u8; 3] {
+    let r = y + cr * (359. / 256.);
+    let g = y + cb * (-88. / 256.) + cr * (-183. / 256.);
+    let b = y + cb * (454. / 256.);
+
+    let to_u8 = |v: f32| (v.round().clamp(-128., 127.) + 128.) as u8;
+
+    [to_u8(r), to_u8(g), to_u8(b)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Command, MDec};
+    use super::*;
+
+    fn flat_macroblock(luma: i8, cb: i8, cr: i8, qscale: u8) -> Arc<DecodedMacroblock> {
+        Arc::new(DecodedMacroblock {
+            key: 0,
+            luma: [luma; 16 * 16],
+            cb: [cb; 8 * 8],
+            cr: [cr; 8 * 8],
+            qscale,
+        })
+    }
+
+    fn render(grid: &MacroblockGrid, column: usize, scale: usize) -> Vec<[u8; 3]> {
+        let mut out = vec![[0; 3]; 16 * 16 * scale * scale];
+
+        grid.render(column, 0, scale, ChromaUpsampling::Bilinear, |x, y, c| {
+            out[y * 16 * scale + x] = c
+        });
+
+        out
+    }
+
+    #[test]
+    fn flat_macroblock_matches_hardware_colors() {
+        let (l, cb, cr) = (-20, 37, -64);
+        let grid = MacroblockGrid::new(1, 1, vec![Some(flat_macroblock(l, cb, cr, 1))], true);
+        let (r, g, b) = super::super::yuv_to_rgb(l, cb, cr);
+
+        for scale in [1, 4] {
+            for &c in &render(&grid, 0, scale) {
+                // The hardware truncates the intermediate values
+                assert!((i32::from(c[0]) - i32::from(r)).abs() <= 1);
+                assert!((i32::from(c[1]) - i32::from(g)).abs() <= 1);
+                assert!((i32::from(c[2]) - i32::from(b)).abs() <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn chroma_is_interpolated_across_macroblocks() {
+        let left = flat_macroblock(0, 0, -40, 1);
+        let right = flat_macroblock(0, 0, 40, 1);
+
+        let grid = MacroblockGrid::new(2, 1, vec![Some(left.clone()), Some(right)], false);
+        let red = |c: [u8; 3]| i32::from(c[0]);
+
+        let out = render(&grid, 0, 1);
+        // Away from the edge we get the macroblock's own color
+        assert_eq!(red(out[8 * 16]), red(out[8 * 16 + 14]));
+        // The last pixel is a quarter of the way towards the neighbour's chroma sample
+        assert_eq!(red(out[8 * 16 + 15]) - red(out[8 * 16]), 28);
+
+        // Without a neighbour the edge is left alone
+        let alone = MacroblockGrid::new(2, 1, vec![Some(left), None], false);
+        let out = render(&alone, 0, 1);
+        assert_eq!(red(out[8 * 16]), red(out[8 * 16 + 15]));
+    }
+
+    #[test]
+    fn deblocking_smooths_small_steps_only() {
+        let smooth = |step: i8| {
+            let left = flat_macroblock(0, 0, 0, 8);
+            let right = flat_macroblock(step, 0, 0, 8);
+            let grid = MacroblockGrid::new(2, 1, vec![Some(left), Some(right)], true);
+
+            (grid.luma[15], grid.luma[16])
+        };
+
+        // Quantization noise gets smoothed
+        let (p, q) = smooth(6);
+        assert!(p > 0. && q < 6.);
+
+        // Real edges remain
+        assert_eq!(smooth(60), (0., 60.));
+    }
+
+    #[test]
+    fn recorded_macroblock_matches_15bpp_output() {
+        let cache = Arc::new(MacroblockCache::new());
+        let mut mdec = MDec::new();
+
+        mdec.load_default_tables();
+        mdec.set_macroblock_cache(Some(cache.clone()));
+        mdec.command = Command(0x3800_0000);
+
+        // 6 blocks with a few AC coefficients each
+        let mut pixels = [0u16; 16 * 16];
+        for b in 0..6u16 {
+            let block = mdec.current_block as usize;
+
+            for &code in &[(2 << 10) | (0x40 + b * 0x31), 0x0405 + b, 0x0bfe, 0xfe00] {
+                mdec.decode_rle(code);
+            }
+
+            if block < 4 {
+                let (x_off, y_off) = ((block & 1) * 8, (block >> 1) * 8);
+                for i in 0..64 {
+                    let lo = mdec.output_buffer.pop_byte();
+                    let hi = mdec.output_buffer.pop_byte();
+
+                    pixels[(y_off + i / 8) * 16 + x_off + i % 8] = u16::from_le_bytes([lo, hi]);
+                }
+                mdec.output_buffer.clear();
+            }
+        }
+
+        let block = cache.get(macroblock_key(&pixels)).unwrap();
+        assert_eq!(block.qscale, 2);
+
+        // Redrawing at native resolution should give the same picture, minus the 15bpp
+        // truncation and with smoother chroma
+        let grid = MacroblockGrid::new(1, 1, vec![Some(block)], false);
+        grid.render(0, 0, 1, ChromaUpsampling::Bilinear, |x, y, c| {
+            let p = pixels[y * 16 + x];
+            let hw = [p & 0x1f, (p >> 5) & 0x1f, (p >> 10) & 0x1f];
+
+            for (&c, &h) in c.iter().zip(hw.iter()) {
+                assert!((i32::from(c >> 3) - i32::from(h)).abs() <= 2);
+            }
+        });
+    }
+}
@@ -1,10 +1,14 @@
+pub mod enhanced;
 mod fifo;
+pub mod movie;
 
 use super::{sync, AccessWidth, Addressable, CycleCount, Psx};
 use crate::bitwise::Bitwise;
+use enhanced::{MacroblockCache, Recorder};
 use fifo::Fifo;
 use std::cmp::min;
 use std::ops::{Index, IndexMut};
+use std::sync::Arc;
 
 const MDECSYNC: sync::SyncToken = sync::SyncToken::MDec;
 
@@ -73,6 +77,9 @@ pub struct MDec {
     macroblocks_in_frame: u16,
     /// Expected macroblocks per frame (typically 330 for 320x240)
     expected_macroblocks: u16,
+    /// Records the full precision 15bpp macroblocks when the enhanced output is enabled
+    #[serde(skip)]
+    recorder: Option<Recorder>,
 }
 
 impl MDec {
@@ -110,6 +117,7 @@ impl MDec {
             frame_timing_error: 0,
             macroblocks_in_frame: 0,
             expected_macroblocks: 330, // Default for 320x240
+            recorder: None,
         }
     }
 
@@ -417,6 +425,53 @@ impl MDec {
             && self.dma_wait_cycles == 0
     }
 
+    /// Store the full precision version of the macroblocks decoded to 15bpp in `cache` for the
+    /// enhanced output (see the `enhanced` module). `None` for the hardware exact output only.
+    pub fn set_macroblock_cache(&mut self, cache: Option<Arc<MacroblockCache>>) {
+        self.recorder = cache.map(Recorder::new);
+    }
+
+    /// Load the quantization and IDCT tables uploaded by Sony's libraries (`DecDCTReset`), which
+    /// are the ones all STR movies are encoded for.
+    pub fn load_default_tables(&mut self) {
+        self.quant_matrices[..64].copy_from_slice(&DEFAULT_QUANT_MATRIX);
+        self.quant_matrices[64..].copy_from_slice(&DEFAULT_QUANT_MATRIX);
+
+        for (i, &c) in DEFAULT_IDCT_MATRIX.iter().enumerate() {
+            self.idct_matrix.set(i as u8, c >> 3);
+        }
+    }
+
+    /// Decode the run-length `codes` of a sequence of color macroblocks to 24bpp RGB, going
+    /// through the same IDCT and color conversion as the emulated decoder but bypassing the FIFOs
+    /// and the timings. Used to decode movies offline.
+    ///
+    /// `output` is called for every 8x8 luma block with the index of the block in the 16x16
+    /// macroblock (0: top-left, 1: top-right, 2: bottom-left, 3: bottom-right) and the 192 bytes of
+    /// the RGB pixels.
+    pub fn decode_rgb24<F>(&mut self, codes: &[u16], mut output: F)
+    where
+        F: FnMut(usize, &[u8]),
+    {
+        self.command = Command(DECODE_RGB24_COMMAND);
+        self.current_block = BlockType::CrMono;
+        self.block_index = 0;
+        self.output_buffer.clear();
+
+        for &code in codes {
+            let block = self.current_block;
+
+            self.decode_rle(code);
+
+            if !self.output_buffer.is_empty() {
+                let len = usize::from(self.output_buffer.write_idx);
+
+                output(block as usize, &self.output_buffer.buf[..len]);
+                self.output_buffer.clear();
+            }
+        }
+    }
+
     fn decode_rle(&mut self, rle: u16) {
         if self.block_index == 0 {
             if rle == 0xfe00 {
@@ -621,6 +676,8 @@ impl MDec {
         // combine it into the XOR for the same result
         xor_mask |= (self.command.d15_msb() as u16) << 15;
 
+        let mut pixels = [0u16; 8 * 8];
+
         for y in 0..8 {
             let uv_y = (y >> 1) | (t & 2) << 1;
             let uv_x = (t & 1) << 2;
@@ -641,9 +698,24 @@ impl MDec {
 
                 let v = (r | (g << 5) | (b << 10)) ^ xor_mask;
 
+                pixels[y * 8 + x] = v;
                 self.output_buffer.push_halfword(v);
             }
         }
+
+        // We can't redraw signed pixels since we don't know how the game will convert them
+        if let Some(recorder) = self.recorder.as_mut() {
+            if !self.command.output_signed() {
+                recorder.record_block(
+                    t,
+                    &self.block_y.block,
+                    &self.block_u.block,
+                    &self.block_v.block,
+                    &pixels,
+                    self.qscale,
+                );
+            }
+        }
     }
 
     fn generate_pixels_rgb24(&mut self, block_type: BlockType) {
@@ -816,6 +888,26 @@ pub fn dma_load(psx: &mut Psx) -> (u32, u32) {
     (v, offset << 2)
 }
 
+/// "Decode macroblock" command with 24bpp unsigned output and no length
+const DECODE_RGB24_COMMAND: u32 = 0x3000_0000;
+
+/// Quantization matrix used by Sony's libraries for both luma and chroma, in zigzag order. It's
+/// the default MPEG-1 intra matrix except for the DC factor.
+const DEFAULT_QUANT_MATRIX: [u8; 64] = [
+    2, 16, 16, 19, 16, 19, 22, 22, 22, 22, 22, 22, 26, 24, 26, 27, 27, 27, 26, 26, 26, 26, 27, 27,
+    27, 29, 29, 29, 34, 34, 34, 29, 29, 29, 27, 27, 29, 29, 32, 32, 34, 34, 37, 38, 37, 35, 35, 34,
+    35, 38, 38, 40, 40, 40, 48, 48, 46, 46, 56, 56, 58, 69, 69, 83,
+];
+
+/// IDCT matrix used by Sony's libraries
+const DEFAULT_IDCT_MATRIX: [i16; 64] = [
+    23170, 23170, 23170, 23170, 23170, 23170, 23170, 23170, 32138, 27245, 18204, 6392, -6393,
+    -18205, -27246, -32139, 30273, 12539, -12540, -30274, -30274, -12540, 12539, 30273, 27245,
+    -6393, -32139, -18205, 18204, 32138, 6392, -27246, 23170, -23171, -23171, 23170, 23170, -23171,
+    -23171, 23170, 18204, -32139, 6392, 27245, -27246, -6393, 32138, -18205, 12539, -30274, 30273,
+    -12540, -12540, 30273, -30274, 12539, 6392, -18205, 27245, -32139, 32138, -27246, 18204, -6393,
+];
+
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Debug)]
 enum State {
     Idle,
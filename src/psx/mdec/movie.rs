@@ -0,0 +1,1012 @@
+//! STR movie demuxer and decoder
+//!
+//! Offline decoding of the FMVs found on PlayStation discs, without having to run the game. STR
+//! files interleave video sectors, each containing a chunk of the compressed bitstream of a frame
+//! behind a 32 byte header, with XA-ADPCM audio sectors.
+//!
+//! The bitstream is a variant of MPEG-1 intra coding: the games decode the variable length codes
+//! in software to get the run-length codes the MDEC expects, we do the same here and then feed
+//! them to the same IDCT and color conversion code as the emulated MDEC. Version 2 (and the
+//! identical version 1) and version 3 bitstreams are supported.
+
+use super::MDec;
+use crate::error::{PsxError, Result};
+use crate::psx::cd::audio_rip::{self, XaDecoder};
+use crate::psx::cd::disc::Disc;
+use crate::psx::cd::iso9660;
+
+/// Size of the data payload of a video sector (Mode 2 Form 1)
+const VIDEO_SECTOR_DATA_LEN: usize = 2048;
+
+/// Size of the header at the beginning of every video sector
+const VIDEO_SECTOR_HEADER_LEN: usize = 0x20;
+
+/// Size of the bitstream chunk in every video sector
+const CHUNK_LEN: usize = VIDEO_SECTOR_DATA_LEN - VIDEO_SECTOR_HEADER_LEN;
+
+/// Offset of the data payload in a raw sector: 12 bytes of sync, 4 bytes of header and 8 bytes of
+/// subheader
+const SECTOR_DATA_OFFSET: usize = 24;
+
+/// Submode flag set for audio sectors
+const SUBMODE_AUDIO: u8 = 1 << 2;
+
+/// The first 4 bytes of every video sector
+const VIDEO_SECTOR_MAGIC: [u8; 4] = [0x60, 0x01, 0x01, 0x80];
+
+/// The second halfword of the bitstream header
+const BITSTREAM_MAGIC: u16 = 0x3800;
+
+/// Run-length code marking the end of a block
+const END_OF_BLOCK: u16 = 0xfe00;
+
+/// Variable length AC codes (without the sign bit that follows them) with the corresponding run
+/// of zeroes and level. That's table B.14 of the MPEG-1 specification.
+static AC_CODES: [(&str, u8, u8); 111] = [
+    ("11", 0, 1),
+    ("011", 1, 1),
+    ("0100", 0, 2),
+    ("0101", 2, 1),
+    ("00101", 0, 3),
+    ("00111", 3, 1),
+    ("00110", 4, 1),
+    ("000110", 1, 2),
+    ("000111", 5, 1),
+    ("000101", 6, 1),
+    ("000100", 7, 1),
+    ("0000110", 0, 4),
+    ("0000100", 2, 2),
+    ("0000111", 8, 1),
+    ("0000101", 9, 1),
+    ("00100110", 0, 5),
+    ("00100001", 0, 6),
+    ("00100101", 1, 3),
+    ("00100100", 3, 2),
+    ("00100111", 10, 1),
+    ("00100011", 11, 1),
+    ("00100010", 12, 1),
+    ("00100000", 13, 1),
+    ("0000001010", 0, 7),
+    ("0000001100", 1, 4),
+    ("0000001011", 2, 3),
+    ("0000001111", 4, 2),
+    ("0000001001", 5, 2),
+    ("0000001110", 14, 1),
+    ("0000001101", 15, 1),
+    ("0000001000", 16, 1),
+    ("000000011101", 0, 8),
+    ("000000011000", 0, 9),
+    ("000000010011", 0, 10),
+    ("000000010000", 0, 11),
+    ("000000011011", 1, 5),
+    ("000000010100", 2, 4),
+    ("000000011100", 3, 3),
+    ("000000010010", 4, 3),
+    ("000000011110", 6, 2),
+    ("000000010101", 7, 2),
+    ("000000010001", 8, 2),
+    ("000000011111", 17, 1),
+    ("000000011010", 18, 1),
+    ("000000011001", 19, 1),
+    ("000000010111", 20, 1),
+    ("000000010110", 21, 1),
+    ("0000000011010", 0, 12),
+    ("0000000011001", 0, 13),
+    ("0000000011000", 0, 14),
+    ("0000000010111", 0, 15),
+    ("0000000010110", 1, 6),
+    ("0000000010101", 1, 7),
+    ("0000000010100", 2, 5),
+    ("0000000010011", 3, 4),
+    ("0000000010010", 5, 3),
+    ("0000000010001", 9, 2),
+    ("0000000010000", 10, 2),
+    ("0000000011111", 22, 1),
+    ("0000000011110", 23, 1),
+    ("0000000011101", 24, 1),
+    ("0000000011100", 25, 1),
+    ("0000000011011", 26, 1),
+    ("00000000011111", 0, 16),
+    ("00000000011110", 0, 17),
+    ("00000000011101", 0, 18),
+    ("00000000011100", 0, 19),
+    ("00000000011011", 0, 20),
+    ("00000000011010", 0, 21),
+    ("00000000011001", 0, 22),
+    ("00000000011000", 0, 23),
+    ("00000000010111", 0, 24),
+    ("00000000010110", 0, 25),
+    ("00000000010101", 0, 26),
+    ("00000000010100", 0, 27),
+    ("00000000010011", 0, 28),
+    ("00000000010010", 0, 29),
+    ("00000000010001", 0, 30),
+    ("00000000010000", 0, 31),
+    ("000000000011000", 0, 32),
+    ("000000000010111", 0, 33),
+    ("000000000010110", 0, 34),
+    ("000000000010101", 0, 35),
+    ("000000000010100", 0, 36),
+    ("000000000010011", 0, 37),
+    ("000000000010010", 0, 38),
+    ("000000000010001", 0, 39),
+    ("000000000010000", 0, 40),
+    ("000000000011111", 1, 8),
+    ("000000000011110", 1, 9),
+    ("000000000011101", 1, 10),
+    ("000000000011100", 1, 11),
+    ("000000000011011", 1, 12),
+    ("000000000011010", 1, 13),
+    ("000000000011001", 1, 14),
+    ("0000000000010011", 1, 15),
+    ("0000000000010010", 1, 16),
+    ("0000000000010001", 1, 17),
+    ("0000000000010000", 1, 18),
+    ("0000000000010100", 6, 3),
+    ("0000000000011010", 11, 2),
+    ("0000000000011001", 12, 2),
+    ("0000000000011000", 13, 2),
+    ("0000000000010111", 14, 2),
+    ("0000000000010110", 15, 2),
+    ("0000000000010101", 16, 2),
+    ("0000000000011111", 27, 1),
+    ("0000000000011110", 28, 1),
+    ("0000000000011101", 29, 1),
+    ("0000000000011100", 30, 1),
+    ("0000000000011011", 31, 1),
+];
+
+/// End of block code
+const EOB_CODE: &str = "10";
+
+/// Escape code, followed by the 16bit run-length code
+const ESCAPE_CODE: &str = "000001";
+
+/// Version 3 DC size codes for the luma blocks (MPEG-1 table B.12)
+static DC_LUMA_SIZES: [(&str, u8); 9] = [
+    ("100", 0),
+    ("00", 1),
+    ("01", 2),
+    ("101", 3),
+    ("110", 4),
+    ("1110", 5),
+    ("11110", 6),
+    ("111110", 7),
+    ("1111110", 8),
+];
+
+/// Version 3 DC size codes for the chroma blocks (MPEG-1 table B.13)
+static DC_CHROMA_SIZES: [(&str, u8); 9] = [
+    ("00", 0),
+    ("01", 1),
+    ("10", 2),
+    ("110", 3),
+    ("1110", 4),
+    ("11110", 5),
+    ("111110", 6),
+    ("1111110", 7),
+    ("11111110", 8),
+];
+
+/// Entry of the AC code lookup table
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum AcCode {
+    Invalid,
+    EndOfBlock,
+    Escape,
+    RunLevel { len: u8, run: u8, level: u8 },
+}
+
+/// Lookup table indexed by the next 16 bits of the bitstream
+fn build_ac_table() -> Vec<AcCode> {
+    let mut table = vec![AcCode::Invalid; 1 << 16];
+
+    let mut set = |code: &str, v: AcCode| {
+        let len = code.len();
+        let first = (u32::from_str_radix(code, 2).unwrap() as usize) << (16 - len);
+
+        for e in &mut table[first..first + (1 << (16 - len))] {
+            *e = v;
+        }
+    };
+
+    set(EOB_CODE, AcCode::EndOfBlock);
+    set(ESCAPE_CODE, AcCode::Escape);
+
+    for &(code, run, level) in AC_CODES.iter() {
+        let len = code.len() as u8;
+
+        set(code, AcCode::RunLevel { len, run, level });
+    }
+
+    table
+}
+
+/// Reader for the bitstream: 16bit little endian words read MSB first
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, pos: 0 }
+    }
+
+    fn word(&self, index: usize) -> u64 {
+        let off = index * 2;
+
+        match self.data.get(off..off + 2) {
+            Some(w) => u64::from(u16::from_le_bytes([w[0], w[1]])),
+            // Pad with zeroes past the end, `is_past_end` will catch truncated streams
+            None => 0,
+        }
+    }
+
+    /// Returns the next `n` bits without consuming them, `n` must not be greater than 17
+    fn peek(&self, n: usize) -> u32 {
+        let index = self.pos / 16;
+        let shift = self.pos % 16;
+
+        let window = (self.word(index) << 32) | (self.word(index + 1) << 16) | self.word(index + 2);
+
+        ((window >> (48 - shift - n)) & ((1 << n) - 1)) as u32
+    }
+
+    fn skip(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    fn read(&mut self, n: usize) -> u32 {
+        let v = self.peek(n);
+        self.skip(n);
+        v
+    }
+
+    fn is_past_end(&self) -> bool {
+        self.pos > self.data.len() * 8
+    }
+}
+
+fn bad_movie(m: &str) -> PsxError {
+    PsxError::BadMovie(m.to_string())
+}
+
+/// Decoder for the compressed frames
+pub struct FrameDecoder {
+    mdec: MDec,
+    ac_table: Vec<AcCode>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        let mut mdec = MDec::new();
+
+        mdec.load_default_tables();
+
+        FrameDecoder {
+            mdec,
+            ac_table: build_ac_table(),
+        }
+    }
+
+    /// Decode the bitstream of a `width`x`height` frame. Returns the 24bpp RGB pixels, line by
+    /// line.
+    pub fn decode(&mut self, bitstream: &[u8], width: u16, height: u16) -> Result<Vec<u8>> {
+        let codes = self.decode_bitstream(bitstream, macroblock_count(width, height))?;
+
+        let mut frame = vec![0; usize::from(width) * usize::from(height) * 3];
+        let mut macroblock = 0;
+
+        self.mdec.decode_rgb24(&codes, |block, pixels| {
+            put_block(&mut frame, width, height, macroblock, block, pixels);
+
+            if block == 3 {
+                macroblock += 1;
+            }
+        });
+
+        Ok(frame)
+    }
+
+    /// Decode the variable length codes of `bitstream` into the run-length codes of
+    /// `macroblock_count` macroblocks
+    fn decode_bitstream(&self, bitstream: &[u8], macroblock_count: usize) -> Result<Vec<u16>> {
+        if bitstream.len() < 8 {
+            return Err(bad_movie("truncated frame header"));
+        }
+
+        let header = |i: usize| u16::from_le_bytes([bitstream[i * 2], bitstream[i * 2 + 1]]);
+
+        if header(1) != BITSTREAM_MAGIC {
+            return Err(bad_movie("bad bitstream magic"));
+        }
+
+        let qscale = header(2) & 0x3f;
+        let version = header(3);
+
+        if !(1..=3).contains(&version) {
+            let m = format!("unsupported bitstream version {}", version);
+            return Err(PsxError::BadMovie(m));
+        }
+
+        let mut bits = BitReader::new(&bitstream[8..]);
+        let mut codes = Vec::with_capacity(macroblock_count * 6 * 16);
+
+        // DC predictors for version 3: Cr, Cb and Y
+        let mut dc_predictors = [0i32; 3];
+
+        for _ in 0..macroblock_count {
+            // Cr, Cb, Y1, Y2, Y3, Y4
+            for block in 0..6 {
+                let dc = if version == 3 {
+                    let component = block.min(2);
+                    let diff = read_dc_diff(&mut bits, component == 2)?;
+
+                    dc_predictors[component] += diff;
+
+                    // The DC is coded with 8 bits of precision
+                    (dc_predictors[component] * 4) as u16 & 0x3ff
+                } else {
+                    bits.read(10) as u16
+                };
+
+                codes.push((qscale << 10) | dc);
+
+                self.decode_ac(&mut bits, &mut codes)?;
+            }
+        }
+
+        Ok(codes)
+    }
+
+    /// Decode the AC coefficients of a block up to and including the end of block code
+    fn decode_ac(&self, bits: &mut BitReader, codes: &mut Vec<u16>) -> Result<()> {
+        // Number of coefficients in the block so far, including the DC
+        let mut coeffs = 1;
+
+        loop {
+            let code = match self.ac_table[bits.peek(16) as usize] {
+                AcCode::EndOfBlock => {
+                    bits.skip(EOB_CODE.len());
+                    END_OF_BLOCK
+                }
+                AcCode::Escape => {
+                    bits.skip(ESCAPE_CODE.len());
+                    bits.read(16) as u16
+                }
+                AcCode::RunLevel { len, run, level } => {
+                    bits.skip(usize::from(len));
+
+                    let level = if bits.read(1) != 0 {
+                        -i16::from(level)
+                    } else {
+                        i16::from(level)
+                    };
+
+                    (u16::from(run) << 10) | (level as u16 & 0x3ff)
+                }
+                AcCode::Invalid => return Err(bad_movie("invalid AC code")),
+            };
+
+            if bits.is_past_end() {
+                return Err(bad_movie("truncated bitstream"));
+            }
+
+            codes.push(code);
+
+            if code == END_OF_BLOCK {
+                return Ok(());
+            }
+
+            coeffs += usize::from(code >> 10) + 1;
+
+            if coeffs > 64 {
+                return Err(bad_movie("too many coefficients in block"));
+            }
+        }
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> FrameDecoder {
+        FrameDecoder::new()
+    }
+}
+
+/// Read a version 3 DC difference
+fn read_dc_diff(bits: &mut BitReader, luma: bool) -> Result<i32> {
+    let sizes = if luma {
+        &DC_LUMA_SIZES
+    } else {
+        &DC_CHROMA_SIZES
+    };
+
+    let next = bits.peek(8);
+
+    let size = sizes.iter().find_map(|&(code, size)| {
+        let len = code.len();
+        let code = u32::from_str_radix(code, 2).unwrap();
+
+        if next >> (8 - len) == code {
+            bits.skip(len);
+            Some(size)
+        } else {
+            None
+        }
+    });
+
+    let size = match size {
+        Some(s) => usize::from(s),
+        None => return Err(bad_movie("invalid DC size code")),
+    };
+
+    if size == 0 {
+        return Ok(0);
+    }
+
+    let v = bits.read(size) as i32;
+
+    // Negative differences have their MSB cleared
+    if v & (1 << (size - 1)) == 0 {
+        Ok(v - (1 << size) + 1)
+    } else {
+        Ok(v)
+    }
+}
+
+fn macroblock_count(width: u16, height: u16) -> usize {
+    let columns = usize::from(width).div_ceil(16);
+    let rows = usize::from(height).div_ceil(16);
+
+    columns * rows
+}
+
+/// Copy an 8x8 block of RGB pixels into `frame`. Macroblocks are stored column by column.
+fn put_block(
+    frame: &mut [u8],
+    width: u16,
+    height: u16,
+    macroblock: usize,
+    block: usize,
+    pixels: &[u8],
+) {
+    let (width, height) = (usize::from(width), usize::from(height));
+    let rows = height.div_ceil(16);
+
+    let x_base = (macroblock / rows) * 16 + (block & 1) * 8;
+    let y_base = (macroblock % rows) * 16 + (block >> 1) * 8;
+
+    for y in 0..8 {
+        let fy = y_base + y;
+
+        if fy >= height {
+            break;
+        }
+
+        for x in 0..8 {
+            let fx = x_base + x;
+
+            if fx >= width {
+                break;
+            }
+
+            let src = (y * 8 + x) * 3;
+            let dst = (fy * width + fx) * 3;
+
+            frame[dst..dst + 3].copy_from_slice(&pixels[src..src + 3]);
+        }
+    }
+}
+
+/// A decoded movie frame
+pub struct StrFrame {
+    /// Frame number, starting at 1
+    pub number: u32,
+    pub width: u16,
+    pub height: u16,
+    /// 24bpp RGB pixels, line by line
+    pub pixels: Vec<u8>,
+    /// Audio samples decoded since the previous frame, interleaved if the audio is stereo
+    pub audio: Vec<i16>,
+}
+
+/// Frame being reassembled from its video sectors
+struct PendingFrame {
+    number: u32,
+    width: u16,
+    height: u16,
+    /// Size of the bitstream
+    size: usize,
+    chunk_count: u16,
+    received: Vec<bool>,
+    bitstream: Vec<u8>,
+}
+
+impl PendingFrame {
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|&r| r)
+    }
+}
+
+/// Iterator over the frames of an STR movie
+pub struct StrReader<'a> {
+    disc: &'a mut Disc,
+    /// First sector of the movie, in track 01
+    start: u32,
+    /// Next sector to read
+    sector: u32,
+    /// End of the movie
+    end: u32,
+    decoder: FrameDecoder,
+    pending: Option<PendingFrame>,
+    /// XA channel to decode, the first one encountered if `None`
+    audio_channel: Option<u8>,
+    audio_decoder: XaDecoder,
+    audio_format: Option<(u32, u16)>,
+    audio: Vec<i16>,
+}
+
+impl<'a> StrReader<'a> {
+    /// Open the movie at `path` in the disc's filesystem
+    pub fn open(disc: &'a mut Disc, path: &str) -> Result<StrReader<'a>> {
+        let entry = {
+            let cache = disc.cache_mut();
+            let root = iso9660::open_image(cache)?;
+
+            root.lookup(cache, path)?
+        };
+
+        // The extent length of interleaved files is normally given in 2048 bytes sectors
+        let sector_count = entry.extent_len().div_ceil(2048);
+
+        Ok(StrReader::from_sectors(
+            disc,
+            entry.extent_location(),
+            sector_count,
+        ))
+    }
+
+    /// Read a movie from `sector_count` sectors starting at `start` in track 01. Useful for the
+    /// movies that aren't in the filesystem.
+    pub fn from_sectors(disc: &'a mut Disc, start: u32, sector_count: u32) -> StrReader<'a> {
+        StrReader {
+            disc,
+            start,
+            sector: start,
+            end: start + sector_count,
+            decoder: FrameDecoder::new(),
+            pending: None,
+            audio_channel: None,
+            audio_decoder: XaDecoder::new(),
+            audio_format: None,
+            audio: Vec::new(),
+        }
+    }
+
+    /// Select the XA channel to decode the audio from
+    pub fn set_audio_channel(&mut self, channel: u8) {
+        self.audio_channel = Some(channel);
+    }
+
+    /// Sample rate and number of channels of the audio, once the first audio sector has been
+    /// encountered
+    pub fn audio_format(&self) -> Option<(u32, u16)> {
+        self.audio_format
+    }
+
+    /// Duration of the movie in seconds, assuming it's played back at double speed like almost
+    /// all STR movies are
+    pub fn duration(&self) -> f32 {
+        (self.end - self.start) as f32 / 150.
+    }
+
+    /// Handle a raw sector, returns the frame if the sector completed one
+    fn handle_sector(&mut self, raw: &[u8]) -> Result<Option<StrFrame>> {
+        // Mode byte
+        if raw[15] != 2 {
+            return Ok(None);
+        }
+
+        let channel = raw[17];
+        let submode = raw[18];
+
+        if submode & SUBMODE_AUDIO != 0 {
+            let channel = *self.audio_channel.get_or_insert(channel);
+
+            if raw[17] == channel {
+                self.audio_format = Some(audio_rip::xa_sector_format(raw));
+                self.audio_decoder.decode_sector(raw, &mut self.audio);
+            }
+
+            return Ok(None);
+        }
+
+        let data = &raw[SECTOR_DATA_OFFSET..SECTOR_DATA_OFFSET + VIDEO_SECTOR_DATA_LEN];
+
+        if data[0..4] != VIDEO_SECTOR_MAGIC {
+            return Ok(None);
+        }
+
+        let u16_at = |off: usize| u16::from_le_bytes([data[off], data[off + 1]]);
+        let u32_at = |off: usize| u32::from(u16_at(off)) | (u32::from(u16_at(off + 2)) << 16);
+
+        let chunk = u16_at(0x04);
+        let chunk_count = u16_at(0x06);
+        let number = u32_at(0x08);
+        let size = u32_at(0x0c) as usize;
+        let width = u16_at(0x10);
+        let height = u16_at(0x12);
+
+        if chunk >= chunk_count || size > usize::from(chunk_count) * CHUNK_LEN {
+            return Err(bad_movie("invalid video sector header"));
+        }
+
+        let restart = match &self.pending {
+            Some(p) => p.number != number || p.chunk_count != chunk_count,
+            None => true,
+        };
+
+        if restart {
+            if let Some(p) = &self.pending {
+                warn!("Dropping incomplete STR frame {}", p.number);
+            }
+
+            self.pending = Some(PendingFrame {
+                number,
+                width,
+                height,
+                size,
+                chunk_count,
+                received: vec![false; usize::from(chunk_count)],
+                bitstream: vec![0; usize::from(chunk_count) * CHUNK_LEN],
+            });
+        }
+
+        let pending = self.pending.as_mut().unwrap();
+
+        let off = usize::from(chunk) * CHUNK_LEN;
+        pending.bitstream[off..off + CHUNK_LEN].copy_from_slice(&data[VIDEO_SECTOR_HEADER_LEN..]);
+        pending.received[usize::from(chunk)] = true;
+
+        if !pending.is_complete() {
+            return Ok(None);
+        }
+
+        let frame = self.pending.take().unwrap();
+
+        let pixels =
+            self.decoder
+                .decode(&frame.bitstream[..frame.size], frame.width, frame.height)?;
+
+        Ok(Some(StrFrame {
+            number: frame.number,
+            width: frame.width,
+            height: frame.height,
+            pixels,
+            audio: std::mem::take(&mut self.audio),
+        }))
+    }
+}
+
+impl<'a> Iterator for StrReader<'a> {
+    type Item = Result<StrFrame>;
+
+    fn next(&mut self) -> Option<Result<StrFrame>> {
+        while self.sector < self.end {
+            let index = self.sector;
+            self.sector += 1;
+
+            let sector = match audio_rip::read_track1_sector(self.disc.cache_mut(), index) {
+                Ok(s) => s,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match self.handle_sector(sector.data_2352()) {
+                Ok(Some(frame)) => return Some(Ok(frame)),
+                Ok(None) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        if let Some(p) = self.pending.take() {
+            warn!("Dropping incomplete STR frame {}", p.number);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DEFAULT_IDCT_MATRIX, DEFAULT_QUANT_MATRIX};
+    use super::*;
+
+    /// Writer for the bitstream, the opposite of `BitReader`
+    struct BitWriter {
+        words: Vec<u16>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn new() -> BitWriter {
+            BitWriter {
+                words: Vec::new(),
+                bits: 0,
+            }
+        }
+
+        fn put(&mut self, v: u32, n: usize) {
+            for i in (0..n).rev() {
+                if self.bits & 15 == 0 {
+                    self.words.push(0);
+                }
+
+                let bit = ((v >> i) & 1) as u16;
+                *self.words.last_mut().unwrap() |= bit << (15 - self.bits % 16);
+                self.bits += 1;
+            }
+        }
+
+        fn put_code(&mut self, code: &str) {
+            self.put(u32::from_str_radix(code, 2).unwrap(), code.len());
+        }
+
+        /// Returns the frame with its header
+        fn finish(self, qscale: u16, version: u16) -> Vec<u8> {
+            let mut frame = Vec::new();
+
+            for h in &[0, BITSTREAM_MAGIC, qscale, version] {
+                frame.extend_from_slice(&h.to_le_bytes());
+            }
+            for w in &self.words {
+                frame.extend_from_slice(&w.to_le_bytes());
+            }
+
+            frame
+        }
+    }
+
+    /// Simple deterministic pseudo-random generator for the test data
+    struct Lcg(u32);
+
+    impl Lcg {
+        fn next(&mut self, max: u32) -> u32 {
+            self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (self.0 >> 16) % max
+        }
+    }
+
+    /// Generate a version 2 bitstream of `macroblock_count` macroblocks, returns the bitstream
+    /// and the run-length codes it should decode to
+    fn gen_bitstream(macroblock_count: usize, qscale: u16) -> (Vec<u8>, Vec<u16>) {
+        let mut rng = Lcg(0x1234);
+        let mut bits = BitWriter::new();
+        let mut codes = Vec::new();
+
+        for _ in 0..macroblock_count * 6 {
+            let dc = rng.next(0x400) as u16;
+            bits.put(u32::from(dc), 10);
+            codes.push((qscale << 10) | dc);
+
+            let mut coeffs = 1;
+
+            for _ in 0..rng.next(12) {
+                if rng.next(4) == 0 {
+                    // Escape code
+                    let run = rng.next(4) as u16;
+                    let level = rng.next(0x400) as u16;
+
+                    if coeffs + run + 1 > 64 {
+                        break;
+                    }
+
+                    bits.put_code(ESCAPE_CODE);
+                    bits.put(u32::from((run << 10) | level), 16);
+                    codes.push((run << 10) | level);
+                    coeffs += run + 1;
+                } else {
+                    let (code, run, level) = AC_CODES[rng.next(AC_CODES.len() as u32) as usize];
+                    let negative = rng.next(2) == 1;
+
+                    if coeffs + u16::from(run) + 1 > 64 {
+                        break;
+                    }
+
+                    bits.put_code(code);
+                    bits.put(negative as u32, 1);
+
+                    let level = if negative {
+                        -i16::from(level)
+                    } else {
+                        i16::from(level)
+                    };
+                    codes.push((u16::from(run) << 10) | (level as u16 & 0x3ff));
+                    coeffs += u16::from(run) + 1;
+                }
+            }
+
+            bits.put_code(EOB_CODE);
+            codes.push(END_OF_BLOCK);
+        }
+
+        (bits.finish(qscale, 2), codes)
+    }
+
+    /// Decode `codes` by going through the emulated MDEC's command FIFOs, like a game would.
+    /// Returns the 24bpp luma blocks in output order.
+    fn decode_emulated(codes: &[u16]) -> Vec<Vec<u8>> {
+        let mut mdec = MDec::new();
+        let mut output = Vec::new();
+
+        // Upload the tables like `DecDCTReset`
+        let mut commands = vec![0x4000_0001];
+        for _ in 0..2 {
+            for q in DEFAULT_QUANT_MATRIX.chunks(4) {
+                commands.push(u32::from_le_bytes([q[0], q[1], q[2], q[3]]));
+            }
+        }
+
+        commands.push(0x6000_0000);
+        for c in DEFAULT_IDCT_MATRIX.chunks(2) {
+            commands.push(u32::from(c[0] as u16) | (u32::from(c[1] as u16) << 16));
+        }
+
+        let mut codes = codes.to_vec();
+        // Pad to a full number of words
+        if codes.len() & 1 != 0 {
+            codes.push(END_OF_BLOCK);
+        }
+
+        commands.push(0x3000_0000 | (codes.len() / 2) as u32);
+        for c in codes.chunks(2) {
+            commands.push(u32::from(c[0]) | (u32::from(c[1]) << 16));
+        }
+
+        let mut drain = |mdec: &mut MDec| {
+            mdec.run(2048);
+
+            while !mdec.output_fifo.is_empty() {
+                output.extend_from_slice(&mdec.output_fifo.pop().to_le_bytes());
+            }
+        };
+
+        for &c in &commands {
+            while mdec.input_fifo.is_full() {
+                drain(&mut mdec);
+            }
+
+            mdec.push_command(c);
+        }
+
+        for _ in 0..100_000 {
+            if !mdec.is_busy() && mdec.input_fifo.is_empty() {
+                break;
+            }
+
+            drain(&mut mdec);
+        }
+
+        assert!(!mdec.is_busy());
+
+        output.chunks(192).map(|b| b.to_vec()).collect()
+    }
+
+    #[test]
+    fn ac_table_is_prefix_free() {
+        let mut used = vec![false; 1 << 16];
+
+        let codes = AC_CODES
+            .iter()
+            .map(|&(c, _, _)| c)
+            .chain([EOB_CODE, ESCAPE_CODE].iter().cloned());
+
+        for code in codes {
+            let len = code.len();
+            let first = (u32::from_str_radix(code, 2).unwrap() as usize) << (16 - len);
+
+            for u in &mut used[first..first + (1 << (16 - len))] {
+                assert!(!*u, "{} overlaps another code", code);
+                *u = true;
+            }
+        }
+
+        // The table is complete except for the codes starting with 12 zeroes
+        assert_eq!(used.iter().filter(|&&u| !u).count(), 16);
+
+        let table = build_ac_table();
+        let run_level = AcCode::RunLevel {
+            len: 4,
+            run: 0,
+            level: 2,
+        };
+        assert_eq!(table[0b0100_0000_0000_0000], run_level);
+        assert_eq!(table[0b1000_0000_0000_0000], AcCode::EndOfBlock);
+        assert_eq!(table[0b0000_0100_0000_0000], AcCode::Escape);
+        assert_eq!(table[0], AcCode::Invalid);
+    }
+
+    #[test]
+    fn bitstream_v2() {
+        let decoder = FrameDecoder::new();
+        let (bitstream, expected) = gen_bitstream(12, 7);
+
+        let codes = decoder.decode_bitstream(&bitstream, 12).unwrap();
+
+        assert_eq!(codes, expected);
+
+        // Missing the last macroblock
+        assert!(decoder
+            .decode_bitstream(&bitstream[..bitstream.len() / 2], 12)
+            .is_err());
+    }
+
+    #[test]
+    fn bitstream_v3_dc() {
+        let decoder = FrameDecoder::new();
+        let mut bits = BitWriter::new();
+
+        // Cr: size 2, +3
+        bits.put_code("10");
+        bits.put(0b11, 2);
+        bits.put_code(EOB_CODE);
+        // Cb: size 0
+        bits.put_code("00");
+        bits.put_code(EOB_CODE);
+        // Y1: size 1, -1
+        bits.put_code("00");
+        bits.put(0b0, 1);
+        bits.put_code(EOB_CODE);
+        // Y2: size 3, -5 relative to Y1
+        bits.put_code("101");
+        bits.put(0b010, 3);
+        bits.put_code(EOB_CODE);
+        // Y3, Y4: size 0, same as Y2
+        for _ in 0..2 {
+            bits.put_code("100");
+            bits.put_code(EOB_CODE);
+        }
+
+        let codes = decoder.decode_bitstream(&bits.finish(1, 3), 1).unwrap();
+
+        let dc: Vec<u16> = codes
+            .chunks(2)
+            .map(|c| {
+                assert_eq!(c[1], END_OF_BLOCK);
+                assert_eq!(c[0] >> 10, 1);
+                c[0] & 0x3ff
+            })
+            .collect();
+
+        let expected: Vec<u16> = [3, 0, -1, -6, -6, -6]
+            .iter()
+            .map(|&d: &i32| (d * 4) as u16 & 0x3ff)
+            .collect();
+
+        assert_eq!(dc, expected);
+    }
+
+    /// Check that the offline decoder produces exactly the same frame as the emulated MDEC
+    #[test]
+    fn frame_matches_emulated_mdec() {
+        let (width, height) = (48, 40);
+        let macroblocks = macroblock_count(width, height);
+        let (bitstream, codes) = gen_bitstream(macroblocks, 2);
+
+        let mut decoder = FrameDecoder::new();
+        let frame = decoder.decode(&bitstream, width, height).unwrap();
+
+        let blocks = decode_emulated(&codes);
+        assert_eq!(blocks.len(), macroblocks * 4);
+
+        let mut emulated = vec![0; frame.len()];
+        for (i, b) in blocks.iter().enumerate() {
+            put_block(&mut emulated, width, height, i / 4, i % 4, b);
+        }
+
+        assert!(frame == emulated);
+        // Make sure we're not comparing two black frames
+        assert!(frame.iter().filter(|&&p| p != 0).count() > frame.len() / 2);
+    }
+}
@@ -16,6 +16,7 @@ mod cpu_pipeline_tests;
 pub mod debugger;
 mod cache;
 mod dma;
+pub mod exe;
 mod expansion;
 pub mod gpu;
 pub mod ggpo;
@@ -32,6 +33,7 @@ pub mod pgxp;
 pub mod ml_input_prediction;
 pub mod overlay;
 pub mod pad_memcard;
+pub mod psf;
 mod spu;
 mod sync;
 mod timers;
@@ -60,8 +62,10 @@ pub mod arm_optimizer;
 use crate::error::{PsxError, Result};
 pub use cd::{disc, iso9660, CDC_ROM_SHA256, CDC_ROM_SIZE};
 pub use gpu::{Frame, VideoStandard};
+pub use mdec::enhanced::{ChromaUpsampling, MdecEnhancement};
+pub use mdec::movie;
 pub use overlay::{DeveloperOverlay, renderer::OverlayRenderData};
-pub use spu::{SpuDebugOverlay, interpolation};
+pub use spu::{AudioBufferStats, SpuDebugOverlay, capture, interpolation, snapshot};
 pub use zram::{ZramSystem, GpuMemoryCompressor, CompressionStats};
 use serde::de::{Deserialize, Deserializer};
 use std::cmp::min;
@@ -121,6 +125,9 @@ pub struct Psx {
     dma_timing_penalty: CycleCount,
     /// When this variable is `true` the CPU is stopped for DMA operation
     cpu_stalled_for_dma: bool,
+    /// Executable waiting to be sideloaded once the BIOS reaches the shell
+    #[serde(default)]
+    exe_sideload: Option<exe::Exe>,
     /// Developer overlay system (not serialized)
     #[serde(skip)]
     developer_overlay: overlay::DeveloperOverlay,
@@ -166,10 +173,25 @@ impl Psx {
         psx.compatibility_manager.detect_game(serial);
         let profile = psx.compatibility_manager.current_profile().clone();
         psx.apply_game_patches(&profile);
+
+        psx.set_game_id_for_spu(serial.to_string());
         
         Ok(psx)
     }
 
+    /// Build a disc-less console that boots the BIOS and then runs `psf`'s payload
+    pub fn new_with_psf(
+        psf: &psf::Psf,
+        bios: bios::Bios,
+        cdc_firmware: [u8; cd::CDC_ROM_SIZE],
+    ) -> Result<Psx> {
+        let mut psx = Psx::new_with_bios(None, bios, psf.video_standard(), cdc_firmware)?;
+
+        psx.sideload_exe(psf.exe().clone());
+
+        Ok(psx)
+    }
+
     pub fn new_with_bios(
         disc: Option<disc::Disc>,
         bios: bios::Bios,
@@ -213,6 +235,7 @@ impl Psx {
             cache_control: 0,
             dma_timing_penalty: 0,
             cpu_stalled_for_dma: false,
+            exe_sideload: None,
             developer_overlay: overlay::DeveloperOverlay::new(),
             zram_system: zram::ZramSystem::new(4096, num_cpus::get()),
             gpu_compressor: zram::GpuMemoryCompressor::new(),
@@ -239,9 +262,18 @@ impl Psx {
     where
         D: Deserializer<'de>,
     {
-        let mut psx = Box::<Psx>::deserialize(reader)
+        let psx = Box::<Psx>::deserialize(reader)
             .map_err(|e| PsxError::DeserializationError(format!("{}", e)))?;
 
+        self.load(psx)
+    }
+
+    /// Transfer our disc, BIOS, CDC ROM and peripherals to `psx`, a freshly deserialized console,
+    /// then replace our current state with it.
+    ///
+    /// Will generate an error if there's a mismatch (that is, if the serialized Psx used a
+    /// different disc, BIOS or ROM). In this case the state remains unchanged.
+    pub fn load(&mut self, mut psx: Box<Psx>) -> Result<()> {
         psx.xmem.copy_bios(&self.xmem)?;
         psx.cd.cdc.copy_rom(&self.cd.cdc);
 
@@ -256,7 +288,7 @@ impl Psx {
             return Err(e);
         }
 
-        // Move pads to the new instance
+        // Move pads to the new instance and restore their state
         {
             let mut old_pads = self.pad_memcard.gamepads_mut();
             let mut new_pads = psx.pad_memcard.gamepads_mut();
@@ -264,10 +296,11 @@ impl Psx {
             for (old, new) in old_pads.iter_mut().zip(new_pads.iter_mut()) {
                 let pad = old.disconnect_device();
                 new.connect_device(pad);
+                new.restore_loaded_state();
             }
         }
 
-        // Move memory cards to the new instance
+        // Move memory cards to the new instance and restore their state
         {
             let mut old_mc = self.pad_memcard.memory_cards_mut();
             let mut new_mc = psx.pad_memcard.memory_cards_mut();
@@ -275,6 +308,7 @@ impl Psx {
             for (old, new) in old_mc.iter_mut().zip(new_mc.iter_mut()) {
                 let mc = old.disconnect_device();
                 new.connect_device(mc);
+                new.restore_loaded_state();
             }
         }
 
@@ -283,10 +317,26 @@ impl Psx {
         Ok(())
     }
 
+    /// Load `exe` and jump to its entry point as soon as the BIOS is done booting. Must be called
+    /// before the BIOS reaches the shell, so generally right after a reset.
+    pub fn sideload_exe(&mut self, exe: exe::Exe) {
+        self.exe_sideload = Some(exe);
+    }
+
     pub fn video_standard(&self) -> VideoStandard {
         self.gpu.video_standard()
     }
 
+    /// Contents of the main RAM
+    pub fn ram(&self) -> &[u8] {
+        self.xmem.ram()
+    }
+
+    /// Contents of the BIOS ROM
+    pub fn bios(&self) -> &[u8] {
+        self.xmem.bios()
+    }
+
     /// Check if a frame is ready
     pub fn frame_ready(&self) -> bool {
         self.frame_done
@@ -344,12 +394,7 @@ impl Psx {
                 fc.frame_presented();
             }
         }
-        
-        // Update display sync
-        if let Some(ds) = self.display_sync.as_mut() {
-            ds.update(frame_time);
-        }
-        
+
         // Update power management metrics
         if let Some(pm) = self.power_management.as_mut() {
             let fps = 1.0 / frame_time.as_secs_f32();
@@ -375,6 +420,17 @@ impl Psx {
         spu::get_samples(self)
     }
 
+    /// Get at most `frames` pending stereo frames, the rest stays in the audio buffer. Used by
+    /// frontends that pace the audio output themselves.
+    pub fn pop_audio_samples(&mut self, frames: usize) -> Vec<i16> {
+        spu::pop_samples(self, frames)
+    }
+
+    /// Get the statistics of the audio buffer, including its current fill level
+    pub fn audio_buffer_stats(&self) -> AudioBufferStats {
+        spu::get_audio_buffer_stats(self)
+    }
+
     /// Clear any pending audio samples. This must be called at least once per frame.
     pub fn clear_audio_samples(&mut self) {
         spu::clear_samples(self)
@@ -410,18 +466,59 @@ impl Psx {
         self.spu.add_game_profile(profile);
     }
 
+    /// Mute or unmute SPU `voice` (0-23) in the audio output
+    pub fn set_spu_voice_mute(&mut self, voice: u8, muted: bool) {
+        self.spu.set_voice_muted(voice, muted);
+    }
+
+    /// Solo or unsolo SPU `voice` (0-23) in the audio output
+    pub fn set_spu_voice_solo(&mut self, voice: u8, soloed: bool) {
+        self.spu.set_voice_soloed(voice, soloed);
+    }
+
+    /// Start capturing every SPU voice, the CD audio and the reverb into separate tracks
+    pub fn start_spu_capture(&mut self) {
+        self.spu.start_capture();
+    }
+
+    /// Stop the SPU capture and return the captured tracks
+    pub fn stop_spu_capture(&mut self) -> Option<spu::capture::SpuCapture> {
+        self.spu.stop_capture()
+    }
+
+    /// Returns the state of the SPU voices, reverb and interrupt for debugging
+    pub fn spu_snapshot(&self) -> spu::snapshot::SpuSnapshot {
+        spu::snapshot::SpuSnapshot::new(&self.spu)
+    }
+
+    /// Decode `block_count` ADPCM blocks starting at byte `address` in SPU RAM
+    pub fn decode_spu_adpcm(&self, address: u32, block_count: usize) -> Vec<i16> {
+        spu::snapshot::decode_adpcm(&self.spu, address, block_count)
+    }
+
     /// Set the internal resolution upscaling factor
     /// 0 = 1x (native), 1 = 2x, 2 = 4x, etc.
     pub fn set_upscale_shift(&mut self, shift: u8) {
         self.gpu.set_upscale_shift(shift);
     }
 
+    /// Select the enhanced MDEC output, where the movies are redrawn at the internal resolution
+    /// with smooth chroma, or the hardware exact one with `None`
+    pub fn set_mdec_enhancement(&mut self, enhancement: Option<MdecEnhancement>) {
+        let cache = enhancement.map(|_| self.gpu.mdec_macroblock_cache());
+
+        self.mdec.set_macroblock_cache(cache);
+        self.gpu
+            .set_rasterizer_option(gpu::RasterizerOption::MdecEnhancement(enhancement));
+    }
+
     /// Enable or disable SPU reverb
     pub fn set_spu_reverb_enable(&mut self, enable: bool) {
         self.spu.set_reverb_enable(enable);
     }
 
-    /// Enable or disable enhanced SPU reverb mode for better audio quality
+    /// Select the high resolution SPU reverb (44.1kHz, no intermediate clipping) instead of the
+    /// accurate one
     pub fn set_spu_reverb_enhanced(&mut self, enhanced: bool) {
         self.spu.set_reverb_enhanced(enhanced);
     }
@@ -588,6 +685,26 @@ impl Psx {
         }
     }
 
+    /// Enable/disable locking the emulation speed to the host refresh rate
+    pub fn set_display_sync_enabled(&mut self, enabled: bool) {
+        if let Some(ds) = self.display_sync.as_mut() {
+            ds.set_enabled(enabled);
+        }
+    }
+
+    /// Report the time elapsed between two host frames, used to measure the host refresh rate
+    /// when the frontend can't tell us
+    pub fn report_host_frame(&mut self, interval: std::time::Duration) {
+        if let Some(ds) = self.display_sync.as_mut() {
+            ds.update(interval);
+        }
+    }
+
+    /// Returns the display sync statistics, including the emulation speed ratio currently applied
+    pub fn display_sync_stats(&self) -> Option<display_sync::DisplaySyncStats> {
+        self.display_sync.as_ref().map(|ds| ds.stats())
+    }
+
     /// Update battery information
     pub fn update_battery(&mut self, charge_percent: f32, is_charging: bool, current_draw_ma: f32) {
         if let Some(pm) = self.power_management.as_mut() {
@@ -3,7 +3,7 @@
 //! The dance mat is essentially a digital controller with foot-operated buttons
 //! arranged in a 3x3 grid pattern.
 
-use super::{PeripheralTrait, Response};
+use super::{DeviceState, PeripheralTrait, Response};
 
 /// Dance mat controller state
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -154,11 +154,29 @@ impl PeripheralTrait for DanceMat {
         }
     }
 
+    fn select(&mut self) {
+        self.transfer_state = TransferState::Idle;
+    }
+
     fn new_frame(&mut self) {
         // Update pressure simulation
         self.update_pressure();
     }
 
+    fn save_state(&self) -> DeviceState {
+        DeviceState::DanceMat(self.clone())
+    }
+
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        match state {
+            DeviceState::DanceMat(s) => {
+                *self = s;
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn clone_box(&self) -> Box<dyn PeripheralTrait> {
         Box::new(self.clone())
     }
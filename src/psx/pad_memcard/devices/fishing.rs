@@ -5,7 +5,7 @@
 //! - Reel with rotation sensor
 //! - Buttons for menu navigation
 
-use super::{PeripheralTrait, Response};
+use super::{DeviceState, PeripheralTrait, Response};
 
 /// Fishing controller state
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -231,13 +231,35 @@ impl PeripheralTrait for FishingController {
         }
     }
 
+    fn select(&mut self) {
+        self.transfer_state = TransferState::Idle;
+    }
+
     fn new_frame(&mut self) {
         self.update_physics();
     }
 
+    fn save_state(&self) -> DeviceState {
+        DeviceState::FishingController(self.clone())
+    }
+
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        match state {
+            DeviceState::FishingController(s) => {
+                *self = s;
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn clone_box(&self) -> Box<dyn PeripheralTrait> {
         Box::new(self.clone())
     }
+
+    fn description(&self) -> String {
+        "Fishing Controller".to_string()
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
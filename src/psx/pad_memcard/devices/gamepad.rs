@@ -1,4 +1,4 @@
-use super::{DeviceInterface, DsrState};
+use super::{DeviceInterface, DeviceState, DsrState};
 
 /// Digital buttons on a PlayStation controller. The value assigned to each button is the bit
 /// position in the 16bit word returned in the serial protocol
@@ -38,6 +38,7 @@ impl ButtonState {
 /// SCPH-1080: Digital gamepad.
 ///
 /// Full state is only two bytes since we only need one bit per button.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct DigitalPad(u16);
 
 impl DigitalPad {
@@ -104,9 +105,24 @@ impl DeviceInterface for DigitalPad {
         // Digital pads don't support L3/R3, so those bits are always set to 1
         self.0 |= 0x6;
     }
+
+    fn save_state(&self) -> DeviceState {
+        DeviceState::DigitalPad(self.clone())
+    }
+
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        match state {
+            DeviceState::DigitalPad(s) => {
+                *self = s;
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
 /// SCPH-1200: DualShock controller
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct DualShock {
     /// State of the digital buttons
     buttons: u16,
@@ -662,9 +678,23 @@ impl DeviceInterface for DualShock {
     fn get_rumble(&self) -> (u8, u8) {
         self.rumble
     }
+
+    fn save_state(&self) -> DeviceState {
+        DeviceState::DualShock(Box::new(self.clone()))
+    }
+
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        match state {
+            DeviceState::DualShock(s) => {
+                *self = *s;
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 enum DsAccessType {
     ReadInput,
     /// Change mode while we're in normal mode
@@ -4,90 +4,112 @@
 //! - Screen position detection via CRT timing
 //! - Trigger and two action buttons (A, B)
 //! - Compatible with games like Time Crisis, Point Blank
+//!
+//! The gun latches the position of the beam when its sensor sees it go by. The position is
+//! reported as a number of 8MHz clock ticks since the HSYNC and a number of lines since the VSYNC,
+//! so the values for a given spot on the screen depend on the current video mode:
+//!
+//! ```text
+//! Send     Reply
+//! 01h      HiZ    Address
+//! 42h      63h    ID low
+//! TAP      5Ah    ID high
+//! MOT      xxh    Buttons low (bit 3: A)
+//! MOT      xxh    Buttons high (bit 13: trigger, bit 14: B)
+//! 00h      xxh    X low
+//! 00h      xxh    X high
+//! 00h      xxh    Y low
+//! 00h      xxh    Y high
+//! ```
+//!
+//! When the gun isn't aimed at the screen it returns `X = 0x0001` and `Y = 0x000a`. Games use that
+//! to reload: the player pulls the trigger while aiming away from the screen.
+
+use super::{DeviceState, PeripheralTrait, Response};
+use crate::psx::gpu::{DisplayArea, VideoStandard};
 
-use super::{PeripheralTrait, Response};
+/// Frequency of the clock used to count the X coordinate
+const X_CLOCK_HZ: f64 = 8_000_000.;
+
+/// Offset applied to the X coordinate. Taken from mednafen, it puts the coordinates in the
+/// 0x4d...0x1cd range the real hardware returns with the standard display area.
+const X_OFFSET: i32 = -12;
+
+/// `(X, Y)` returned when the gun isn't aimed at the screen
+const OFF_SCREEN: (u16, u16) = (0x0001, 0x000a);
 
 /// GunCon light gun state
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct GunCon {
-    /// X coordinate on screen (0-1023, though typically 0-380 for visible area)
-    x_position: u16,
-    /// Y coordinate on screen (0-262 for NTSC, 0-312 for PAL)
-    y_position: u16,
-    /// Button states (trigger, A, B)
-    buttons: u8,
+    /// Horizontal aim: 0.0 is the left edge of the picture, 1.0 the right edge
+    x: f32,
+    /// Vertical aim: 0.0 is the top of the picture, 1.0 the bottom
+    y: f32,
+    /// Button states (trigger, A, B), active low
+    buttons: u16,
     /// Current transfer state
     transfer_state: TransferState,
     /// Whether the gun is currently aimed at the screen
     on_screen: bool,
-    /// Video standard (affects Y coordinate range)
-    video_standard: VideoStandard,
+    /// Position of the picture in the video signal
+    display_area: DisplayArea,
+    /// `(X, Y)` latched at the beginning of the current transaction
+    position: (u16, u16),
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 enum TransferState {
     Idle,
     Connected,
-    SendId1,
-    SendId2,
-    SendButtons,
+    SendIdHigh,
+    SendButtonsLow,
+    SendButtonsHigh,
     SendXLow,
     SendXHigh,
     SendYLow,
     SendYHigh,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
-pub enum VideoStandard {
-    NTSC,
-    PAL,
-}
-
 impl GunCon {
     pub fn new(video_standard: VideoStandard) -> Self {
         GunCon {
-            x_position: 0x100, // Center of screen
-            y_position: 0x80,  // Center of screen
-            buttons: 0xFF,     // All buttons released
+            // Center of screen
+            x: 0.5,
+            y: 0.5,
+            // All buttons released
+            buttons: 0xffff,
             transfer_state: TransferState::Idle,
             on_screen: false,
-            video_standard,
+            display_area: DisplayArea::new(video_standard),
+            position: OFF_SCREEN,
         }
     }
 
-    /// Set the gun's aim position in screen coordinates
-    pub fn set_position(&mut self, x: u16, y: u16) {
-        self.x_position = x.min(1023);
-        let y_max = match self.video_standard {
-            VideoStandard::NTSC => 262,
-            VideoStandard::PAL => 312,
-        };
-        self.y_position = y.min(y_max);
-        self.on_screen = true;
-    }
-
-    /// Set position from normalized coordinates (0.0 to 1.0)
+    /// Set the gun's aim from normalized coordinates (0.0 to 1.0) relative to the picture
     pub fn set_normalized_position(&mut self, x: f32, y: f32) {
-        let x_pos = (x.max(0.0).min(1.0) * 380.0) as u16; // Visible area is ~380 pixels
-        let y_max = match self.video_standard {
-            VideoStandard::NTSC => 240.0,
-            VideoStandard::PAL => 288.0,
-        };
-        let y_pos = (y.max(0.0).min(1.0) * y_max) as u16;
-        self.set_position(x_pos, y_pos);
+        self.x = x.clamp(0.0, 1.0);
+        self.y = y.clamp(0.0, 1.0);
+        self.on_screen = true;
     }
 
     /// Set whether the gun is aimed at the screen
     pub fn set_on_screen(&mut self, on_screen: bool) {
         self.on_screen = on_screen;
-        if !on_screen {
-            // When off-screen, set to maximum values
-            self.x_position = 0x3FF;
-            self.y_position = match self.video_standard {
-                VideoStandard::NTSC => 0x106,
-                VideoStandard::PAL => 0x138,
-            };
+    }
+
+    /// Returns the `(X, Y)` coordinates reported for the current aim
+    pub fn position(&self) -> (u16, u16) {
+        if !self.on_screen {
+            return OFF_SCREEN;
         }
+
+        let area = &self.display_area;
+        let (column, line) = area.beam_position(self.x, self.y);
+
+        let ticks = (f64::from(column) * X_CLOCK_HZ / area.clock_hz) as i32;
+        let x = (ticks + X_OFFSET).max(0) as u16;
+
+        (x, line)
     }
 
     /// Press a button
@@ -115,11 +137,13 @@ impl PeripheralTrait for GunCon {
     fn send_byte(&mut self, cmd: u8, _target_device: bool) -> Response {
         use self::TransferState::*;
 
+        let (x, y) = self.position;
+
         let (response, next_state, request_dsr) = match self.transfer_state {
             Idle => {
                 if cmd == 0x01 {
                     // Start GunCon access
-                    (0x63, Connected, true) // GunCon returns 0x63 instead of 0xFF
+                    (0xFF, Connected, true)
                 } else {
                     // Unknown command
                     (0xFF, Idle, false)
@@ -128,40 +152,21 @@ impl PeripheralTrait for GunCon {
             Connected => {
                 if cmd == 0x42 {
                     // Read GunCon state
-                    (0x5A, SendId1, true) // Standard controller ID
+                    self.position = self.position();
+                    (0x63, SendIdHigh, true)
                 } else {
                     // Unsupported command
                     (0xFF, Idle, false)
                 }
             }
-            SendId1 => {
-                // Send button states
-                (self.buttons, SendButtons, true)
-            }
-            SendButtons => {
-                // Send X coordinate low byte
-                (self.x_position as u8, SendXLow, true)
-            }
-            SendXLow => {
-                // Send X coordinate high byte
-                ((self.x_position >> 8) as u8, SendXHigh, true)
-            }
-            SendXHigh => {
-                // Send Y coordinate low byte
-                (self.y_position as u8, SendYLow, true)
-            }
-            SendYLow => {
-                // Send Y coordinate high byte
-                ((self.y_position >> 8) as u8, SendYHigh, false) // Last byte, no DSR
-            }
-            SendYHigh => {
-                // Transfer complete
-                (0xFF, Idle, false)
-            }
-            _ => {
-                // Should not happen
-                (0xFF, Idle, false)
-            }
+            SendIdHigh => (0x5A, SendButtonsLow, true),
+            SendButtonsLow => (self.buttons as u8, SendButtonsHigh, true),
+            SendButtonsHigh => ((self.buttons >> 8) as u8, SendXLow, true),
+            SendXLow => (x as u8, SendXHigh, true),
+            SendXHigh => ((x >> 8) as u8, SendYLow, true),
+            SendYLow => (y as u8, SendYHigh, true),
+            // Last byte, no DSR
+            SendYHigh => ((y >> 8) as u8, Idle, false),
         };
 
         self.transfer_state = next_state;
@@ -199,35 +204,53 @@ impl PeripheralTrait for GunCon {
                     self.release_button(GunConButton::B);
                 }
             }
+            // Gun aimed away from the screen (used to reload in most games). Must be set after
+            // the axes since setting the position puts the gun back on screen.
+            3 if pressed => self.set_on_screen(false),
             _ => {}
         }
     }
 
     fn set_axis(&mut self, axis: usize, value: i16) {
-        // Map axis inputs to screen position
+        // The axes cover the picture from edge to edge
+        let normalized = (value as f32 + 32768.0) / 65536.0;
+
         match axis {
-            0 => {
-                // X-axis -> Screen X position
-                let normalized = (value as f32 + 32768.0) / 65536.0;
-                let current_y = (self.y_position as f32) / match self.video_standard {
-                    VideoStandard::NTSC => 240.0,
-                    VideoStandard::PAL => 288.0,
-                };
-                self.set_normalized_position(normalized, current_y);
-            }
-            1 => {
-                // Y-axis -> Screen Y position
-                let normalized = (value as f32 + 32768.0) / 65536.0;
-                let current_x = (self.x_position as f32) / 380.0;
-                self.set_normalized_position(current_x, normalized);
-            }
+            0 => self.set_normalized_position(normalized, self.y),
+            1 => self.set_normalized_position(self.x, normalized),
             _ => {}
         }
     }
-    
+
+    fn select(&mut self) {
+        self.transfer_state = TransferState::Idle;
+    }
+
+    fn set_display_area(&mut self, area: &DisplayArea) {
+        self.display_area = *area;
+    }
+
+    fn save_state(&self) -> DeviceState {
+        DeviceState::GunCon(self.clone())
+    }
+
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        match state {
+            DeviceState::GunCon(s) => {
+                *self = s;
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn clone_box(&self) -> Box<dyn PeripheralTrait> {
         Box::new(self.clone())
     }
+
+    fn description(&self) -> String {
+        "GunCon (NPC-103)".to_string()
+    }
 }
 
 /// GunCon button enumeration
@@ -239,11 +262,11 @@ pub enum GunConButton {
 }
 
 impl GunConButton {
-    fn mask(self) -> u8 {
+    fn mask(self) -> u16 {
         match self {
-            GunConButton::Trigger => 1 << 5,
+            GunConButton::Trigger => 1 << 13,
             GunConButton::A => 1 << 3,
-            GunConButton::B => 1 << 6,
+            GunConButton::B => 1 << 14,
         }
     }
 }
@@ -251,4 +274,43 @@ impl GunConButton {
 /// Create a new GunCon light gun peripheral
 pub fn guncon(video_standard: VideoStandard) -> Box<dyn PeripheralTrait> {
     Box::new(GunCon::new(video_standard))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(gun: &mut GunCon) -> Vec<u8> {
+        gun.select();
+
+        [0x01, 0x42, 0, 0, 0, 0, 0, 0, 0]
+            .iter()
+            .map(|&b| gun.send_byte(b, false).data)
+            .collect()
+    }
+
+    #[test]
+    fn beam_position() {
+        let mut gun = GunCon::new(VideoStandard::Ntsc);
+
+        // Aim at the center of the picture and pull the trigger
+        gun.set_axis(0, 0);
+        gun.set_axis(1, 0);
+        gun.set_button(0, true);
+
+        // Column 0x760, line 0x88
+        assert_eq!(gun.position(), (0x10d, 0x88));
+        assert_eq!(
+            read(&mut gun),
+            [0xff, 0x63, 0x5a, 0xff, 0xdf, 0x0d, 0x01, 0x88, 0x00]
+        );
+
+        // Reload
+        gun.set_button(3, true);
+
+        assert_eq!(
+            read(&mut gun),
+            [0xff, 0x63, 0x5a, 0xff, 0xdf, 0x01, 0x00, 0x0a, 0x00]
+        );
+    }
+}
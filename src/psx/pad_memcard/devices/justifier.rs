@@ -0,0 +1,201 @@
+//! Konami Justifier/Hyper Blaster light gun implementation
+//!
+//! Unlike the GunCon the Justifier doesn't report its position over the serial link. Instead it
+//! raises the light pen interrupt (IRQ10) through the controller port when its sensor sees the
+//! beam go by, and the game reads the timers in the interrupt handler to figure out where the gun
+//! is aimed. The serial link is only used for the buttons:
+//!
+//! ```text
+//! Send     Reply
+//! 01h      HiZ    Address
+//! 42h      31h    ID low
+//! TAP      5Ah    ID high. Bit 4 of TAP enables the interrupt
+//! 00h      xxh    Buttons low (bit 3: Start)
+//! 00h      xxh    Buttons high (bit 14: Back, bit 15: Trigger)
+//! ```
+//!
+//! Aiming away from the screen prevents the interrupt from occurring, games use that to reload.
+
+use super::{DeviceState, PeripheralTrait, Response};
+use crate::psx::gpu::{DisplayArea, VideoStandard};
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Justifier {
+    /// Horizontal aim: 0.0 is the left edge of the picture, 1.0 the right edge
+    x: f32,
+    /// Vertical aim: 0.0 is the top of the picture, 1.0 the bottom
+    y: f32,
+    /// Button states, active low
+    buttons: u16,
+    /// Current transfer state
+    transfer_state: TransferState,
+    /// Whether the gun is currently aimed at the screen
+    on_screen: bool,
+    /// Position of the picture in the video signal
+    display_area: DisplayArea,
+    /// True if the game enabled the light pen interrupt during the last poll
+    irq_enabled: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+enum TransferState {
+    Idle,
+    Connected,
+    SendIdHigh,
+    SendButtonsLow,
+    SendButtonsHigh,
+}
+
+impl Justifier {
+    pub fn new(video_standard: VideoStandard) -> Justifier {
+        Justifier {
+            x: 0.5,
+            y: 0.5,
+            buttons: 0xffff,
+            transfer_state: TransferState::Idle,
+            on_screen: false,
+            display_area: DisplayArea::new(video_standard),
+            irq_enabled: false,
+        }
+    }
+
+    fn set_button_state(&mut self, mask: u16, pressed: bool) {
+        if pressed {
+            self.buttons &= !mask;
+        } else {
+            self.buttons |= mask;
+        }
+    }
+}
+
+impl PeripheralTrait for Justifier {
+    fn send_byte(&mut self, cmd: u8, _target_device: bool) -> Response {
+        use self::TransferState::*;
+
+        let (response, next_state, request_dsr) = match self.transfer_state {
+            Idle if cmd == 0x01 => (0xff, Connected, true),
+            Connected if cmd == 0x42 => (0x31, SendIdHigh, true),
+            SendIdHigh => {
+                self.irq_enabled = cmd & 0x10 != 0;
+                (0x5a, SendButtonsLow, true)
+            }
+            SendButtonsLow => (self.buttons as u8, SendButtonsHigh, true),
+            // Last byte, no DSR
+            SendButtonsHigh => ((self.buttons >> 8) as u8, Idle, false),
+            // Unknown command
+            _ => (0xff, Idle, false),
+        };
+
+        self.transfer_state = next_state;
+
+        Response {
+            data: response,
+            request_dsr,
+        }
+    }
+
+    fn set_button(&mut self, button: usize, pressed: bool) {
+        match button {
+            // Trigger
+            0 => self.set_button_state(1 << 15, pressed),
+            // Back
+            1 => self.set_button_state(1 << 14, pressed),
+            // Start
+            2 => self.set_button_state(1 << 3, pressed),
+            // Gun aimed away from the screen. Must be set after the axes since setting the
+            // position puts the gun back on screen.
+            3 if pressed => self.on_screen = false,
+            _ => (),
+        }
+    }
+
+    fn set_axis(&mut self, axis: usize, value: i16) {
+        // The axes cover the picture from edge to edge
+        let normalized = (f32::from(value) + 32768.) / 65536.;
+
+        match axis {
+            0 => self.x = normalized,
+            1 => self.y = normalized,
+            _ => return,
+        }
+
+        self.on_screen = true;
+    }
+
+    fn select(&mut self) {
+        self.transfer_state = TransferState::Idle;
+    }
+
+    fn set_display_area(&mut self, area: &DisplayArea) {
+        self.display_area = *area;
+    }
+
+    fn light_pen_target(&self) -> Option<(u16, u16)> {
+        if self.irq_enabled && self.on_screen {
+            Some(self.display_area.beam_position(self.x, self.y))
+        } else {
+            None
+        }
+    }
+
+    fn save_state(&self) -> DeviceState {
+        DeviceState::Justifier(self.clone())
+    }
+
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        match state {
+            DeviceState::Justifier(s) => {
+                *self = s;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn PeripheralTrait> {
+        Box::new(self.clone())
+    }
+
+    fn description(&self) -> String {
+        "Konami Justifier".to_string()
+    }
+}
+
+/// Create a new Justifier light gun peripheral
+pub fn justifier(video_standard: VideoStandard) -> Box<dyn PeripheralTrait> {
+    Box::new(Justifier::new(video_standard))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(gun: &mut Justifier, tap: u8) -> Vec<u8> {
+        gun.select();
+
+        [0x01, 0x42, tap, 0, 0]
+            .iter()
+            .map(|&b| gun.send_byte(b, false).data)
+            .collect()
+    }
+
+    #[test]
+    fn light_pen_interrupt() {
+        let mut gun = Justifier::new(VideoStandard::Ntsc);
+
+        // Top-left corner of the picture
+        gun.set_axis(0, i16::MIN);
+        gun.set_axis(1, i16::MIN);
+        gun.set_button(0, true);
+
+        assert_eq!(read(&mut gun, 0x00), [0xff, 0x31, 0x5a, 0xff, 0x7f]);
+        assert_eq!(gun.light_pen_target(), None);
+
+        assert_eq!(read(&mut gun, 0x10), [0xff, 0x31, 0x5a, 0xff, 0x7f]);
+        assert_eq!(gun.light_pen_target(), Some((0x260, 0x10)));
+
+        // Reload
+        gun.set_button(3, true);
+        assert_eq!(gun.light_pen_target(), None);
+    }
+}
@@ -1,4 +1,4 @@
-use super::{DeviceInterface, DsrState};
+use super::{DeviceInterface, DeviceState, DsrState};
 use crate::box_array::BoxArray;
 use crate::psx::CycleCount;
 
@@ -374,11 +374,9 @@ impl DeviceInterface for MemoryCard {
     }
 
     fn connected(&mut self) {
-        // This may prevent *some* data corruption when a savestate is loaded (since it triggers a
-        // reconnection). The idea is that if the BIOS sees that the write flag has been reset it
-        // will *probably* think it's a new memory card and reload all the contents.
-        //
-        // Of course if the savestate was made during a memory card access, all bets are off.
+        // This may prevent *some* data corruption when a savestate made without a memory card is
+        // loaded. The idea is that if the BIOS sees that the write flag has been reset it will
+        // *probably* think it's a new memory card and reload all the contents.
         self.has_been_written = false;
 
         // Disable the memory card (that is, make it look as if there's no memory card connected)
@@ -391,9 +389,50 @@ impl DeviceInterface for MemoryCard {
             self.disabled_frames -= 1;
         }
     }
+
+    fn save_state(&self) -> DeviceState {
+        DeviceState::MemoryCard(Box::new(MemoryCardState {
+            has_been_written: self.has_been_written,
+            access_type: self.access_type,
+            sector_index: self.sector_index,
+            last_command: self.last_command,
+            write_buffer: self.write_buffer,
+            disabled_frames: self.disabled_frames,
+        }))
+    }
+
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        let s = match state {
+            DeviceState::MemoryCard(s) => s,
+            _ => return false,
+        };
+
+        self.has_been_written = s.has_been_written;
+        self.access_type = s.access_type;
+        self.sector_index = s.sector_index;
+        self.last_command = s.last_command;
+        self.write_buffer = s.write_buffer;
+        self.disabled_frames = s.disabled_frames;
+
+        true
+    }
+}
+
+/// Protocol state of a `MemoryCard` stored in save states. The flash contents are not part of it
+/// since they're saved to disk separately.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct MemoryCardState {
+    has_been_written: bool,
+    access_type: AccessType,
+    sector_index: u16,
+    last_command: u8,
+    #[serde(with = "serde_big_array::BigArray")]
+    write_buffer: [u8; 129],
+    disabled_frames: u16,
 }
 
 /// The various types of accesses to a Memory Card
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
 enum AccessType {
     /// Read a sector
     Read = b'R' as isize,
@@ -404,7 +443,7 @@ enum AccessType {
 }
 
 /// Basic 8bit XOR checksum used by the memory card
-fn checksum(d: &[u8]) -> u8 {
+pub fn checksum(d: &[u8]) -> u8 {
     d.iter().fold(0, |c, b| c ^ b)
 }
 
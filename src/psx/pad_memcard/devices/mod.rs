@@ -4,6 +4,7 @@ pub mod peripheral_trait;
 pub mod negcon;
 pub mod mouse;
 pub mod guncon;
+pub mod justifier;
 pub mod multitap;
 pub mod fishing;
 pub mod dance_mat;
@@ -31,6 +32,7 @@ pub use link_cable::NetworkAdapter;
 pub use usb_adapter::AdapterType;
 
 use super::DsrState;
+use crate::psx::gpu::DisplayArea;
 use gamepad::{Button, ButtonState};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
@@ -41,6 +43,9 @@ pub struct Peripheral {
     seq: u8,
     /// False if the device is done processing the current command
     active: bool,
+    /// Device state read from a save state, waiting for the device to be connected (see
+    /// `restore_loaded_state`)
+    loaded_state: Option<DeviceState>,
 }
 
 impl Peripheral {
@@ -49,6 +54,7 @@ impl Peripheral {
             device,
             seq: 0,
             active: false,
+            loaded_state: None,
         }
     }
 
@@ -109,12 +115,84 @@ impl Peripheral {
     pub fn disconnect_device(&mut self) -> Box<dyn DeviceInterface> {
         self.connect_device(Box::new(DisconnectedDevice))
     }
+
+    /// Returns the state of the current transaction and of the connected device
+    pub fn save_state(&self) -> PeripheralState {
+        PeripheralState {
+            seq: self.seq,
+            active: self.active,
+            device: self.device.save_state(),
+        }
+    }
+
+    /// Restore a state returned by `save_state`. If it was saved with another type of device
+    /// connected the device is reset as if it had just been plugged in and the current transaction
+    /// is aborted, in which case we return false.
+    pub fn load_state(&mut self, state: PeripheralState) -> bool {
+        if self.device.load_state(state.device) {
+            self.seq = state.seq;
+            self.active = state.active;
+            true
+        } else {
+            self.device.connected();
+            self.seq = 0;
+            self.active = false;
+            false
+        }
+    }
+
+    /// When a Peripheral is deserialized the device isn't connected yet, this method must be
+    /// called once it is to restore the device state found in the save state
+    pub fn restore_loaded_state(&mut self) {
+        let device = match self.loaded_state.take() {
+            Some(d) => d,
+            None => return,
+        };
+
+        let state = PeripheralState {
+            seq: self.seq,
+            active: self.active,
+            device,
+        };
+
+        if !self.load_state(state) {
+            warn!(
+                "Save state made with a different device, resetting {}",
+                self.device.description()
+            );
+        }
+    }
 }
 
+/// State of a `Peripheral` stored in save states
 #[derive(Serialize, Deserialize)]
-struct SerializedPeripheral {
+pub struct PeripheralState {
     seq: u8,
     active: bool,
+    /// Protocol state of the connected device. Missing from the save states created before it was
+    /// saved, the device is then reset when the state is loaded.
+    #[serde(default)]
+    device: DeviceState,
+}
+
+/// Protocol state of a device. The variant identifies the type of device the state belongs to, so
+/// that we don't attempt to restore it if another type of device is connected when the save state
+/// is loaded.
+#[derive(Serialize, Deserialize, Default)]
+pub enum DeviceState {
+    /// Devices without any state, including empty slots
+    #[default]
+    Stateless,
+    DigitalPad(gamepad::DigitalPad),
+    DualShock(Box<gamepad::DualShock>),
+    MemoryCard(Box<memory_card::MemoryCardState>),
+    NeGcon(negcon::NeGcon),
+    Mouse(mouse::Mouse),
+    GunCon(guncon::GunCon),
+    Justifier(justifier::Justifier),
+    FishingController(fishing::FishingController),
+    DanceMat(dance_mat::DanceMat),
+    Multitap(Box<multitap::MultitapState>),
 }
 
 impl Serialize for Peripheral {
@@ -122,14 +200,9 @@ impl Serialize for Peripheral {
     where
         S: Serializer,
     {
-        // We don't serialize the device, because it could potentially change independently of the
-        // savestate (like changing the controller type). At worst it could mean a glitchy
-        let s = SerializedPeripheral {
-            seq: self.seq,
-            active: self.active,
-        };
-
-        s.serialize(serializer)
+        // The device itself isn't serialized since it could change independently of the savestate
+        // (like changing the controller type), only its state is.
+        self.save_state().serialize(serializer)
     }
 }
 
@@ -138,12 +211,13 @@ impl<'de> Deserialize<'de> for Peripheral {
     where
         D: Deserializer<'de>,
     {
-        let s = SerializedPeripheral::deserialize(deserializer)?;
+        let s = PeripheralState::deserialize(deserializer)?;
 
         let mut peripheral = Peripheral::new(Box::new(DisconnectedDevice));
 
         peripheral.seq = s.seq;
         peripheral.active = s.active;
+        peripheral.loaded_state = Some(s.device);
 
         Ok(peripheral)
     }
@@ -199,6 +273,97 @@ pub trait DeviceInterface {
 
     /// Called once per frame
     fn new_frame(&mut self) {}
+
+    /// Returns the protocol state of the device, to be stored in save states
+    fn save_state(&self) -> DeviceState {
+        DeviceState::Stateless
+    }
+
+    /// Restore a state returned by `save_state`. Returns false without changing anything if
+    /// `state` belongs to a different type of device.
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        matches!(state, DeviceState::Stateless)
+    }
+
+    /// Called at the beginning of each field with the position of the picture in the video
+    /// signal. Used by the light guns to figure out where they're aimed.
+    fn set_display_area(&mut self, _area: &DisplayArea) {}
+
+    /// Returns the beam position `(column, line)` at which the device wants to trigger the light
+    /// pen interrupt (IRQ10) in the current field, if any
+    fn light_pen_target(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    /// Returns the wrapped device if this is a `PeripheralAdapter`, used to feed the inputs of
+    /// the peripherals that don't map to a standard gamepad
+    fn peripheral_mut(&mut self) -> Option<&mut dyn PeripheralTrait> {
+        None
+    }
+
+    /// Returns the multitap if this device is one
+    fn multitap(&self) -> Option<&multitap::Multitap> {
+        None
+    }
+
+    /// Returns the multitap if this device is one
+    fn multitap_mut(&mut self) -> Option<&mut multitap::Multitap> {
+        None
+    }
+}
+
+/// Adapter used to plug the devices implementing `PeripheralTrait` (NeGcon, mouse, GunCon...)
+/// into a `Peripheral`
+pub struct PeripheralAdapter(Box<dyn PeripheralTrait>);
+
+impl PeripheralAdapter {
+    pub fn new(device: Box<dyn PeripheralTrait>) -> PeripheralAdapter {
+        PeripheralAdapter(device)
+    }
+}
+
+impl DeviceInterface for PeripheralAdapter {
+    fn description(&self) -> String {
+        self.0.description()
+    }
+
+    fn select(&mut self) {
+        self.0.select()
+    }
+
+    fn handle_command(&mut self, _seq: u8, cmd: u8) -> (u8, DsrState) {
+        // These devices keep track of their position in the transaction themselves. They're
+        // always connected to the pad port so they're never the target of memory card commands.
+        self.0.send_byte(cmd, false).to_tuple()
+    }
+
+    fn get_rumble(&self) -> (u8, u8) {
+        self.0.get_rumble()
+    }
+
+    fn new_frame(&mut self) {
+        self.0.new_frame()
+    }
+
+    fn save_state(&self) -> DeviceState {
+        self.0.save_state()
+    }
+
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        self.0.load_state(state)
+    }
+
+    fn set_display_area(&mut self, area: &DisplayArea) {
+        self.0.set_display_area(area)
+    }
+
+    fn light_pen_target(&self) -> Option<(u16, u16)> {
+        self.0.light_pen_target()
+    }
+
+    fn peripheral_mut(&mut self) -> Option<&mut dyn PeripheralTrait> {
+        Some(&mut *self.0)
+    }
 }
 
 /// Dummy profile emulating an empty pad or memory card slot
@@ -222,3 +387,161 @@ pub fn disconnected_gamepad() -> Peripheral {
 pub fn disconnected_memory_card() -> Peripheral {
     Peripheral::new(Box::new(DisconnectedDevice))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negcon_through_adapter() {
+        let mut pad = disconnected_gamepad();
+
+        pad.connect_device(Box::new(PeripheralAdapter::new(negcon::negcon())));
+
+        if let Some(p) = pad.device_mut().peripheral_mut() {
+            // A button
+            p.set_button(0, true);
+        }
+
+        // Abort a transaction mid-way, the next one must start from scratch
+        pad.select();
+        pad.exchange_byte(0x01);
+        pad.exchange_byte(0x42);
+
+        pad.select();
+
+        let reply: Vec<u8> = [0x01, 0x42, 0, 0, 0, 0, 0, 0, 0]
+            .iter()
+            .map(|&b| pad.exchange_byte(b).0)
+            .collect();
+
+        assert_eq!(reply, [0xff, 0x23, 0x5a, 0xff, 0xdf, 0x80, 0x00, 0x00, 0x00]);
+
+        // The last byte doesn't request DSR, the transaction is over
+        assert_eq!(pad.exchange_byte(0).0, 0xff);
+    }
+
+    /// Save `p` then load it back with `device` connected
+    fn save_and_load(p: &Peripheral, device: Box<dyn DeviceInterface>) -> Peripheral {
+        let state = bincode::serialize(p).unwrap();
+
+        let mut loaded: Peripheral = bincode::deserialize(&state).unwrap();
+
+        loaded.connect_device(device);
+        loaded.restore_loaded_state();
+
+        loaded
+    }
+
+    fn exchange(p: &mut Peripheral, cmds: &[u8]) -> Vec<u8> {
+        cmds.iter().map(|&b| p.exchange_byte(b).0).collect()
+    }
+
+    #[test]
+    fn dualshock_load_mid_transaction() {
+        let mut pad = disconnected_gamepad();
+
+        let mut ds = gamepad::DualShock::new();
+        ds.set_button_state(Button::Cross, ButtonState::Pressed);
+        pad.connect_device(Box::new(ds));
+
+        // Switch to analog mode and lock it: 0x44 (set mode), analog, locked
+        pad.select();
+        exchange(&mut pad, &[0x01, 0x43, 0x00, 0x01, 0x00]);
+        pad.select();
+        exchange(&mut pad, &[0x01, 0x44, 0x00, 0x01, 0x03, 0, 0, 0, 0]);
+        pad.select();
+        exchange(&mut pad, &[0x01, 0x43, 0x00, 0x00, 0x00, 0, 0, 0, 0]);
+
+        pad.select();
+        assert_eq!(exchange(&mut pad, &[0x01, 0x42, 0x00]), [0xff, 0x73, 0x5a]);
+
+        let mut loaded = save_and_load(&pad, Box::new(gamepad::DualShock::new()));
+
+        let rest = [0, 0, 0, 0, 0, 0];
+        let expected = exchange(&mut pad, &rest);
+
+        assert_eq!(expected[..2], [0xff, 0xbf]);
+        assert_eq!(exchange(&mut loaded, &rest), expected);
+    }
+
+    #[test]
+    fn multitap_memory_card_load_mid_transaction() {
+        let mut port = disconnected_gamepad();
+
+        let mut tap = multitap::Multitap::new();
+        tap.connect_memory_card(2, Box::new(memory_card::MemoryCard::new_formatted()));
+        port.connect_device(Box::new(tap));
+
+        // Newly connected memory cards are disabled for 120 frames
+        for _ in 0..120 {
+            port.device_mut().new_frame();
+        }
+
+        // Start reading sector 0 from the card in slot C
+        port.select();
+        assert_eq!(
+            exchange(&mut port, &[0x83, b'R', 0, 0, 0x00, 0x00]),
+            [0xff, 0x08, 0x5a, 0x5d, 0x00, 0x00]
+        );
+
+        let mut tap = multitap::Multitap::new();
+        tap.connect_memory_card(2, Box::new(memory_card::MemoryCard::new_formatted()));
+
+        let mut loaded = save_and_load(&port, Box::new(tap));
+
+        let rest = [0; 4];
+        let expected = exchange(&mut port, &rest);
+
+        // Command ack, sector address and then the "MC" header
+        assert_eq!(expected, [0x5c, 0x5d, 0x00, 0x00]);
+        assert_eq!(exchange(&mut loaded, &rest), expected);
+        assert_eq!(exchange(&mut loaded, &[0, 0]), [b'M', b'C']);
+    }
+
+    #[test]
+    fn load_with_other_device() {
+        let mut pad = disconnected_gamepad();
+
+        pad.connect_device(Box::new(gamepad::DigitalPad::new()));
+
+        pad.select();
+        exchange(&mut pad, &[0x01, 0x42]);
+
+        let mut loaded = save_and_load(&pad, Box::new(PeripheralAdapter::new(negcon::negcon())));
+
+        // The transaction can't be resumed
+        assert_eq!(loaded.exchange_byte(0), (0xff, DsrState::Idle));
+
+        // But the next one works normally
+        loaded.select();
+        assert_eq!(exchange(&mut loaded, &[0x01, 0x42, 0]), [0xff, 0x23, 0x5a]);
+    }
+
+    /// Save states made before the device state was saved only contain the transaction state
+    #[test]
+    fn load_state_without_device() {
+        #[derive(Serialize)]
+        struct OldState {
+            seq: u8,
+            active: bool,
+        }
+
+        let state = flexbuffers::to_vec(OldState {
+            seq: 2,
+            active: true,
+        })
+        .unwrap();
+
+        let mut loaded: Peripheral = flexbuffers::from_slice(&state).unwrap();
+
+        loaded.connect_device(Box::new(gamepad::DigitalPad::new()));
+        loaded.restore_loaded_state();
+
+        // The device is reset and the transaction is aborted
+        assert_eq!(loaded.exchange_byte(0), (0xff, DsrState::Idle));
+
+        loaded.select();
+        assert_eq!(exchange(&mut loaded, &[0x01, 0x42, 0]), [0xff, 0x41, 0x5a]);
+    }
+}
@@ -5,7 +5,7 @@
 //! - Left and right buttons
 //! - 256 counts per inch resolution
 
-use super::{PeripheralTrait, Response};
+use super::{DeviceState, PeripheralTrait, Response};
 
 /// PlayStation Mouse state
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -180,9 +180,31 @@ impl PeripheralTrait for Mouse {
         }
     }
     
+    fn select(&mut self) {
+        self.transfer_state = TransferState::Idle;
+    }
+
+    fn save_state(&self) -> DeviceState {
+        DeviceState::Mouse(self.clone())
+    }
+
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        match state {
+            DeviceState::Mouse(s) => {
+                *self = s;
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn clone_box(&self) -> Box<dyn PeripheralTrait> {
         Box::new(self.clone())
     }
+
+    fn description(&self) -> String {
+        "PlayStation Mouse (SCPH-1090)".to_string()
+    }
 }
 
 /// Mouse button enumeration
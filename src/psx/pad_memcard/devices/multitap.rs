@@ -1,465 +1,374 @@
-//! PlayStation Multitap implementation
+//! PlayStation Multitap (SCPH-1070)
 //!
-//! The Multitap (SCPH-1070) allows connecting up to 4 controllers and 4 memory cards
-//! to a single controller port, enabling 4-player games.
+//! The multitap connects up to four controllers and four memory cards (slots A to D) to a single
+//! port. Devices are addressed through the first byte of the transaction:
+//!
+//! * `0x01` to `0x04` address the controller in slot A to D. The access is passed through to the
+//!   controller.
+//! * `0x81` to `0x84` address the memory card in slot A to D, again passed through.
+//!
+//! The multitap also implements a "read all controllers" mode: if the 3rd byte of a `0x01, 0x42`
+//! transaction has its LSB set the next `0x01` transaction returns the state of the four
+//! controllers at once:
+//!
+//! ```text
+//! Send     Reply
+//! 01h      HiZ    Address
+//! 42h      80h    Multitap ID
+//! TAP      5Ah    LSB of TAP sets the mode of the next transaction
+//! 00h * 8  ...    Controller A: ID (2 bytes) and state (6 bytes), FFh padded
+//! 00h * 8  ...    Controller B
+//! 00h * 8  ...    Controller C
+//! 00h * 8  ...    Controller D
+//! ```
+//!
+//! The command byte is repeated to each controller in place of the first byte of its group, the
+//! 7 other bytes sent by the console are passed through (for instance to drive the rumble).
 
-use super::{PeripheralTrait, Response};
+use super::{
+    disconnected_gamepad, disconnected_memory_card, DeviceInterface, DeviceState, DsrState,
+    Peripheral, PeripheralState,
+};
+use crate::psx::gpu::DisplayArea;
 
-/// A disconnected peripheral placeholder
-#[derive(Clone)]
-struct DisconnectedPeripheral;
+/// Number of controller and memory card slots
+pub const SLOTS: usize = 4;
 
-impl PeripheralTrait for DisconnectedPeripheral {
-    fn send_byte(&mut self, _cmd: u8, _target_device: bool) -> Response {
-        Response {
-            data: 0xFF,
-            request_dsr: false,
-        }
-    }
-    
-    fn clone_box(&self) -> Box<dyn PeripheralTrait> {
-        Box::new(self.clone())
-    }
-}
+/// Number of bytes returned for each controller in the "read all controllers" mode
+const PAD_REPLY_LEN: u8 = 8;
 
-/// Multitap adapter state
-#[derive(Clone)]
 pub struct Multitap {
-    /// Four controller slots (A, B, C, D)
-    controllers: [Box<dyn PeripheralTrait>; 4],
-    /// Four memory card slots
-    memory_cards: [Box<dyn PeripheralTrait>; 4],
-    /// Currently selected slot (0-3)
-    current_slot: u8,
-    /// Whether we're accessing controllers or memory cards
-    accessing_memory_cards: bool,
-    /// Current transfer state
-    transfer_state: TransferState,
-    /// Data buffer for current transaction
-    data_buffer: Vec<u8>,
-    /// Current position in data buffer
-    buffer_position: usize,
-}
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-enum TransferState {
-    Idle,
-    SelectSlot,
-    ForwardCommand,
-    RelayData,
+    /// Controllers in slots A to D
+    pads: [Peripheral; SLOTS],
+    /// Memory cards in slots A to D
+    memory_cards: [Peripheral; SLOTS],
+    /// Device addressed by the current transaction
+    target: TapTarget,
+    /// Command byte of the current transaction
+    command: u8,
+    /// If true the next `0x01` transaction reads all the controllers at once
+    read_all: bool,
 }
 
 impl Multitap {
-    pub fn new() -> Self {
+    pub fn new() -> Multitap {
         Multitap {
-            controllers: [
-                Box::new(DisconnectedPeripheral),
-                Box::new(DisconnectedPeripheral),
-                Box::new(DisconnectedPeripheral),
-                Box::new(DisconnectedPeripheral),
+            pads: [
+                disconnected_gamepad(),
+                disconnected_gamepad(),
+                disconnected_gamepad(),
+                disconnected_gamepad(),
             ],
             memory_cards: [
-                Box::new(DisconnectedPeripheral),
-                Box::new(DisconnectedPeripheral),
-                Box::new(DisconnectedPeripheral),
-                Box::new(DisconnectedPeripheral),
+                disconnected_memory_card(),
+                disconnected_memory_card(),
+                disconnected_memory_card(),
+                disconnected_memory_card(),
             ],
-            current_slot: 0,
-            accessing_memory_cards: false,
-            transfer_state: TransferState::Idle,
-            data_buffer: Vec::with_capacity(256),
-            buffer_position: 0,
+            target: TapTarget::None,
+            command: 0,
+            read_all: false,
         }
     }
 
-    /// Connect a controller to the specified slot (0-3)
-    pub fn connect_controller(&mut self, slot: usize, controller: Box<dyn PeripheralTrait>) {
-        if slot < 4 {
-            self.controllers[slot] = controller;
-            info!("Multitap: Connected controller to slot {}", slot);
-        }
+    /// Connect a controller in `slot`, returning the previous one
+    pub fn connect_pad(
+        &mut self,
+        slot: usize,
+        device: Box<dyn DeviceInterface>,
+    ) -> Box<dyn DeviceInterface> {
+        self.pads[slot].connect_device(device)
     }
 
-    /// Connect a memory card to the specified slot (0-3)
-    pub fn connect_memory_card(&mut self, slot: usize, memory_card: Box<dyn PeripheralTrait>) {
-        if slot < 4 {
-            self.memory_cards[slot] = memory_card;
-            info!("Multitap: Connected memory card to slot {}", slot);
-        }
+    /// Connect a memory card in `slot`, returning the previous one
+    pub fn connect_memory_card(
+        &mut self,
+        slot: usize,
+        device: Box<dyn DeviceInterface>,
+    ) -> Box<dyn DeviceInterface> {
+        self.memory_cards[slot].connect_device(device)
     }
 
-    /// Get the currently selected peripheral
-    fn current_peripheral(&mut self) -> &mut Box<dyn PeripheralTrait> {
-        if self.accessing_memory_cards {
-            &mut self.memory_cards[self.current_slot as usize]
-        } else {
-            &mut self.controllers[self.current_slot as usize]
-        }
+    pub fn pad(&self, slot: usize) -> &Peripheral {
+        &self.pads[slot]
     }
-}
 
-impl PeripheralTrait for Multitap {
-    fn send_byte(&mut self, cmd: u8, target_device: bool) -> Response {
-        use self::TransferState::*;
-
-        match self.transfer_state {
-            Idle => {
-                // Check for multitap-specific commands
-                match cmd {
-                    0x01 => {
-                        // Standard controller/memory card access
-                        self.accessing_memory_cards = target_device;
-                        self.transfer_state = SelectSlot;
-                        Response {
-                            data: 0xFF,
-                            request_dsr: true,
-                        }
-                    }
-                    0x11 | 0x12 | 0x13 | 0x14 => {
-                        // Direct slot access (0x11 = slot 0, 0x12 = slot 1, etc.)
-                        self.current_slot = (cmd & 0x0F) - 1;
-                        self.accessing_memory_cards = false;
-                        self.transfer_state = ForwardCommand;
-                        Response {
-                            data: 0x80, // Multitap identification
-                            request_dsr: true,
-                        }
-                    }
-                    0x21 | 0x22 | 0x23 | 0x24 => {
-                        // Direct memory card slot access
-                        self.current_slot = (cmd & 0x0F) - 1;
-                        self.accessing_memory_cards = true;
-                        self.transfer_state = ForwardCommand;
-                        Response {
-                            data: 0x80, // Multitap identification
-                            request_dsr: true,
-                        }
-                    }
-                    _ => {
-                        // Unknown command
-                        Response {
-                            data: 0xFF,
-                            request_dsr: false,
-                        }
-                    }
-                }
+    pub fn pad_mut(&mut self, slot: usize) -> &mut Peripheral {
+        &mut self.pads[slot]
+    }
+
+    pub fn memory_card(&self, slot: usize) -> &Peripheral {
+        &self.memory_cards[slot]
+    }
+
+    pub fn memory_card_mut(&mut self, slot: usize) -> &mut Peripheral {
+        &mut self.memory_cards[slot]
+    }
+
+    /// Handle the first byte of a transaction
+    fn address(&mut self, addr: u8) -> (u8, DsrState) {
+        let slot = usize::from(addr & 0xf).wrapping_sub(1);
+
+        self.target = TapTarget::None;
+
+        if slot >= SLOTS {
+            return (0xff, DsrState::Idle);
+        }
+
+        match addr & 0xf0 {
+            0x00 if slot == 0 && self.read_all => {
+                self.target = TapTarget::AllPads;
+                (0xff, DSR)
             }
-            SelectSlot => {
-                // The next byte selects which slot to access
-                if cmd < 4 {
-                    self.current_slot = cmd;
-                    self.transfer_state = ForwardCommand;
-                    Response {
-                        data: 0x5A, // Acknowledge slot selection
-                        request_dsr: true,
-                    }
-                } else {
-                    // Invalid slot
-                    self.transfer_state = Idle;
-                    Response {
-                        data: 0xFF,
-                        request_dsr: false,
-                    }
-                }
+            0x00 => {
+                self.target = TapTarget::Pad(slot);
+
+                let pad = &mut self.pads[slot];
+                pad.select();
+                pad.exchange_byte(0x01)
+            }
+            0x80 => {
+                self.target = TapTarget::MemoryCard(slot);
+
+                let card = &mut self.memory_cards[slot];
+                card.select();
+                card.exchange_byte(0x81)
             }
-            ForwardCommand => {
-                // Forward the command to the selected peripheral
-                let peripheral = self.current_peripheral();
-                let response = peripheral.send_byte(cmd, target_device);
-                
-                if response.request_dsr {
-                    self.transfer_state = RelayData;
+            _ => (0xff, DsrState::Idle),
+        }
+    }
+
+    /// Handle the bytes following the address in "read all controllers" mode
+    fn read_all_pads(&mut self, seq: u8, cmd: u8) -> (u8, DsrState) {
+        match seq {
+            1 => {
+                self.command = cmd;
+
+                if cmd == 0x42 {
+                    (0x80, DSR)
                 } else {
-                    self.transfer_state = Idle;
+                    self.target = TapTarget::None;
+                    (0xff, DsrState::Idle)
                 }
-                
-                response
             }
-            RelayData => {
-                // Continue relaying data to/from the selected peripheral
-                let peripheral = self.current_peripheral();
-                let response = peripheral.send_byte(cmd, target_device);
-                
-                if !response.request_dsr {
-                    // Transaction complete
-                    self.transfer_state = Idle;
-                }
-                
-                response
+            2 => {
+                self.read_all = cmd & 1 != 0;
+                (0x5a, DSR)
             }
-        }
-    }
+            _ => {
+                let pos = seq - 3;
+                let offset = pos % PAD_REPLY_LEN;
+                let pad = &mut self.pads[usize::from(pos / PAD_REPLY_LEN)];
+
+                let (resp, _) = if offset == 0 {
+                    pad.select();
+                    pad.exchange_byte(0x01);
+                    pad.exchange_byte(self.command)
+                } else {
+                    pad.exchange_byte(cmd)
+                };
 
-    fn set_button(&mut self, button: usize, pressed: bool) {
-        // Route button input to all connected controllers
-        for controller in &mut self.controllers {
-            controller.set_button(button, pressed);
-        }
-    }
+                let last = pos + 1 == PAD_REPLY_LEN * SLOTS as u8;
+
+                if last {
+                    self.target = TapTarget::None;
+                }
 
-    fn set_axis(&mut self, axis: usize, value: i16) {
-        // Route axis input to all connected controllers
-        for controller in &mut self.controllers {
-            controller.set_axis(axis, value);
+                (resp, if last { DsrState::Idle } else { DSR })
+            }
         }
     }
-    
-    fn clone_box(&self) -> Box<dyn PeripheralTrait> {
-        Box::new(self.clone())
-    }
 }
 
-/// Create a new Multitap peripheral
-pub fn multitap() -> Box<dyn PeripheralTrait> {
-    Box::new(Multitap::new())
-}
-
-/// Create a Multitap with 4 controllers connected
-pub fn multitap_with_controllers(controllers: [Box<dyn PeripheralTrait>; 4]) -> Box<dyn PeripheralTrait> {
-    let mut tap = Multitap::new();
-    tap.controllers = controllers;
-    tap.memory_cards = [
-        Box::new(DisconnectedPeripheral),
-        Box::new(DisconnectedPeripheral),
-        Box::new(DisconnectedPeripheral),
-        Box::new(DisconnectedPeripheral),
-    ];
-    Box::new(tap)
+impl Default for Multitap {
+    fn default() -> Multitap {
+        Multitap::new()
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl DeviceInterface for Multitap {
+    fn description(&self) -> String {
+        "Multitap (SCPH-1070)".to_string()
+    }
 
-    /// Mock controller for testing
-    #[derive(Clone)]
-    struct MockController {
-        id: u8,
-        button_pressed: bool,
-        axis_value: i16,
+    fn select(&mut self) {
+        // The devices will be selected when they're addressed
+        self.target = TapTarget::None;
     }
 
-    impl PeripheralTrait for MockController {
-        fn send_byte(&mut self, cmd: u8, _target_device: bool) -> Response {
-            match cmd {
-                0x01 => Response { data: 0xFF, request_dsr: true },
-                0x42 => Response { data: self.id, request_dsr: true },
-                _ => Response { data: 0xFF, request_dsr: false },
-            }
+    fn handle_command(&mut self, seq: u8, cmd: u8) -> (u8, DsrState) {
+        if seq == 0 {
+            return self.address(cmd);
         }
 
-        fn set_button(&mut self, _button: usize, pressed: bool) {
-            self.button_pressed = pressed;
-        }
+        match self.target {
+            TapTarget::None => (0xff, DsrState::Idle),
+            TapTarget::Pad(slot) => {
+                if seq == 1 {
+                    self.command = cmd;
+                }
 
-        fn set_axis(&mut self, _axis: usize, value: i16) {
-            self.axis_value = value;
-        }
+                // The TAP byte is only latched by the "read controller" command
+                if seq == 2 && slot == 0 && self.command == 0x42 {
+                    self.read_all = cmd & 1 != 0;
+                }
 
-        fn clone_box(&self) -> Box<dyn PeripheralTrait> {
-            Box::new(self.clone())
+                self.pads[slot].exchange_byte(cmd)
+            }
+            TapTarget::MemoryCard(slot) => self.memory_cards[slot].exchange_byte(cmd),
+            TapTarget::AllPads => self.read_all_pads(seq, cmd),
         }
     }
 
-    #[test]
-    fn test_multitap_creation() {
-        let multitap = Multitap::new();
-        assert_eq!(multitap.current_slot, 0);
-        assert_eq!(multitap.accessing_memory_cards, false);
-        assert_eq!(multitap.transfer_state, TransferState::Idle);
+    fn connected(&mut self) {
+        for p in self.pads.iter_mut().chain(self.memory_cards.iter_mut()) {
+            p.device_mut().connected();
+        }
     }
 
-    #[test]
-    fn test_multitap_slot_selection() {
-        let mut multitap = Multitap::new();
-        
-        // Start multitap access
-        let response = multitap.send_byte(0x01, false);
-        assert_eq!(response.data, 0xFF);
-        assert_eq!(response.request_dsr, true);
-        assert_eq!(multitap.transfer_state, TransferState::SelectSlot);
-        
-        // Select slot 2
-        let response = multitap.send_byte(2, false);
-        assert_eq!(response.data, 0x5A);
-        assert_eq!(response.request_dsr, true);
-        assert_eq!(multitap.current_slot, 2);
-        assert_eq!(multitap.transfer_state, TransferState::ForwardCommand);
+    fn new_frame(&mut self) {
+        for p in self.pads.iter_mut().chain(self.memory_cards.iter_mut()) {
+            p.device_mut().new_frame();
+        }
     }
 
-    #[test]
-    fn test_direct_slot_access() {
-        let mut multitap = Multitap::new();
-        
-        // Direct access to controller slot 1 (0x12)
-        let response = multitap.send_byte(0x12, false);
-        assert_eq!(response.data, 0x80); // Multitap identification
-        assert_eq!(response.request_dsr, true);
-        assert_eq!(multitap.current_slot, 1);
-        assert_eq!(multitap.accessing_memory_cards, false);
-        
-        // Reset
-        multitap.transfer_state = TransferState::Idle;
-        
-        // Direct access to memory card slot 2 (0x23)
-        let response = multitap.send_byte(0x23, false);
-        assert_eq!(response.data, 0x80); // Multitap identification
-        assert_eq!(response.request_dsr, true);
-        assert_eq!(multitap.current_slot, 2);
-        assert_eq!(multitap.accessing_memory_cards, true);
+    fn save_state(&self) -> DeviceState {
+        DeviceState::Multitap(Box::new(MultitapState {
+            pads: self.pads.iter().map(Peripheral::save_state).collect(),
+            memory_cards: self
+                .memory_cards
+                .iter()
+                .map(Peripheral::save_state)
+                .collect(),
+            target: self.target,
+            command: self.command,
+            read_all: self.read_all,
+        }))
     }
 
-    #[test]
-    fn test_connect_controller() {
-        let mut multitap = Multitap::new();
-        let controller = Box::new(MockController { 
-            id: 0x41, 
-            button_pressed: false,
-            axis_value: 0 
-        });
-        
-        multitap.connect_controller(2, controller);
-        
-        // Access the connected controller directly
-        multitap.current_slot = 2;
-        multitap.accessing_memory_cards = false;
-        multitap.transfer_state = TransferState::ForwardCommand;
-        
-        let response = multitap.send_byte(0x42, false);
-        assert_eq!(response.data, 0x41); // Controller ID
-    }
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        let s = match state {
+            DeviceState::Multitap(s) => *s,
+            _ => return false,
+        };
 
-    #[test]
-    fn test_button_routing() {
-        let mut multitap = Multitap::new();
-        
-        // Connect mock controllers
-        for i in 0..4 {
-            let controller = Box::new(MockController {
-                id: 0x40 + i as u8,
-                button_pressed: false,
-                axis_value: 0,
-            });
-            multitap.connect_controller(i, controller);
+        // Each slot is restored or reset independently depending on what's connected to it
+        for (p, state) in self.pads.iter_mut().zip(s.pads) {
+            p.load_state(state);
         }
-        
-        // Press button - should route to all controllers
-        multitap.set_button(0, true);
-        
-        // Verify all controllers received the button press
-        // Note: In real implementation, we'd need getters to verify this
-        // For now, this test ensures the routing doesn't panic
+
+        for (p, state) in self.memory_cards.iter_mut().zip(s.memory_cards) {
+            p.load_state(state);
+        }
+
+        self.target = s.target;
+        self.command = s.command;
+        self.read_all = s.read_all;
+
+        true
     }
 
-    #[test]
-    fn test_axis_routing() {
-        let mut multitap = Multitap::new();
-        
-        // Connect mock controllers
-        for i in 0..4 {
-            let controller = Box::new(MockController {
-                id: 0x40 + i as u8,
-                button_pressed: false,
-                axis_value: 0,
-            });
-            multitap.connect_controller(i, controller);
+    fn set_display_area(&mut self, area: &DisplayArea) {
+        for p in self.pads.iter_mut() {
+            p.device_mut().set_display_area(area);
         }
-        
-        // Set axis - should route to all controllers
-        multitap.set_axis(0, 12345);
-        
-        // Verify all controllers received the axis value
-        // Note: In real implementation, we'd need getters to verify this
-        // For now, this test ensures the routing doesn't panic
     }
 
-    #[test]
-    fn test_invalid_slot_selection() {
-        let mut multitap = Multitap::new();
-        
-        // Start multitap access
-        multitap.send_byte(0x01, false);
-        
-        // Try to select invalid slot (4 or higher)
-        let response = multitap.send_byte(5, false);
-        assert_eq!(response.data, 0xFF);
-        assert_eq!(response.request_dsr, false);
-        assert_eq!(multitap.transfer_state, TransferState::Idle);
+    fn light_pen_target(&self) -> Option<(u16, u16)> {
+        // All the slots share the port's light pen line
+        self.pads.iter().find_map(|p| p.device().light_pen_target())
     }
 
-    #[test]
-    fn test_forward_command_to_peripheral() {
-        let mut multitap = Multitap::new();
-        
-        // Connect a mock controller to slot 1
-        let controller = Box::new(MockController {
-            id: 0x73,
-            button_pressed: false,
-            axis_value: 0,
-        });
-        multitap.connect_controller(1, controller);
-        
-        // Direct access to slot 1
-        multitap.send_byte(0x12, false);
-        
-        // Send command to the controller
-        let response = multitap.send_byte(0x01, false);
-        assert_eq!(response.data, 0xFF);
-        assert_eq!(response.request_dsr, true);
-        assert_eq!(multitap.transfer_state, TransferState::RelayData);
-        
-        // Send another command
-        let response = multitap.send_byte(0x42, false);
-        assert_eq!(response.data, 0x73); // Controller ID
+    fn multitap(&self) -> Option<&Multitap> {
+        Some(self)
     }
 
-    #[test]
-    fn test_disconnected_peripheral() {
-        let mut peripheral = DisconnectedPeripheral;
-        
-        // Any command should return 0xFF with no DSR
-        let response = peripheral.send_byte(0x42, false);
-        assert_eq!(response.data, 0xFF);
-        assert_eq!(response.request_dsr, false);
-        
-        // Clone should work
-        let mut cloned = peripheral.clone_box();
-        let response = cloned.send_byte(0x01, true);
-        assert_eq!(response.data, 0xFF);
-        assert_eq!(response.request_dsr, false);
+    fn multitap_mut(&mut self) -> Option<&mut Multitap> {
+        Some(self)
+    }
+}
+
+/// State of a `Multitap` and of the devices connected to it, stored in save states
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct MultitapState {
+    pads: Vec<PeripheralState>,
+    memory_cards: Vec<PeripheralState>,
+    target: TapTarget,
+    command: u8,
+    read_all: bool,
+}
+
+/// DSR pulse sent by the multitap itself in "read all controllers" mode
+const DSR: DsrState = DsrState::Pending(360, 90);
+
+/// Device addressed by the current transaction
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+enum TapTarget {
+    /// Not addressed or invalid address
+    None,
+    /// Controller access, passed through
+    Pad(usize),
+    /// Memory card access, passed through
+    MemoryCard(usize),
+    /// Read all the controllers at once
+    AllPads,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::gamepad::{Button, ButtonState, DigitalPad};
+    use super::super::memory_card::MemoryCard;
+    use super::*;
+
+    fn transaction(tap: &mut Multitap, cmds: &[u8]) -> Vec<u8> {
+        tap.select();
+
+        cmds.iter()
+            .enumerate()
+            .map(|(seq, &c)| tap.handle_command(seq as u8, c).0)
+            .collect()
     }
 
     #[test]
-    fn test_multitap_clone() {
-        let mut multitap = Multitap::new();
-        multitap.current_slot = 3;
-        multitap.accessing_memory_cards = true;
-        
-        let cloned = multitap.clone();
-        assert_eq!(cloned.current_slot, 3);
-        assert_eq!(cloned.accessing_memory_cards, true);
+    fn pass_through() {
+        let mut tap = Multitap::new();
+
+        let mut pad = DigitalPad::new();
+        pad.set_button_state(Button::Start, ButtonState::Pressed);
+
+        tap.connect_pad(3, Box::new(pad));
+        tap.connect_memory_card(1, Box::new(MemoryCard::new_formatted()));
+
+        // Newly connected memory cards are disabled for 120 frames
+        for _ in 0..120 {
+            tap.new_frame();
+        }
+
+        assert_eq!(
+            transaction(&mut tap, &[0x04, 0x42, 0, 0, 0]),
+            [0xff, 0x41, 0x5a, 0xf7, 0xff]
+        );
+        // Nothing in slot A
+        assert_eq!(transaction(&mut tap, &[0x01, 0x42, 0]), [0xff, 0xff, 0xff]);
+
+        assert_eq!(
+            transaction(&mut tap, &[0x82, b'S', 0, 0]),
+            [0xff, 0x08, 0x5a, 0x5d]
+        );
+        assert_eq!(transaction(&mut tap, &[0x83, b'S']), [0xff, 0xff]);
+
+        // Invalid slot
+        assert_eq!(transaction(&mut tap, &[0x05, 0x42]), [0xff, 0xff]);
     }
 
     #[test]
-    fn test_memory_card_access() {
-        let mut multitap = Multitap::new();
-        
-        // Connect a mock memory card
-        let memory_card = Box::new(MockController {
-            id: 0x5A,
-            button_pressed: false,
-            axis_value: 0,
-        });
-        multitap.connect_memory_card(1, memory_card);
-        
-        // Direct access to memory card slot 1
-        let response = multitap.send_byte(0x22, false);
-        assert_eq!(response.data, 0x80);
-        assert_eq!(multitap.current_slot, 1);
-        assert_eq!(multitap.accessing_memory_cards, true);
-        
-        // Send command to memory card
-        let response = multitap.send_byte(0x42, false);
-        assert_eq!(response.data, 0x5A);
+    fn invalid_read_all_command() {
+        let mut tap = Multitap::new();
+
+        tap.read_all = true;
+
+        assert_eq!(tap.handle_command(0, 0x01), (0xff, DSR));
+        assert_eq!(tap.handle_command(1, 0x43), (0xff, DsrState::Idle));
+        assert_eq!(tap.target, TapTarget::None);
     }
-}
\ No newline at end of file
+}
@@ -6,7 +6,7 @@
 //! - Digital buttons (A, B, Start)
 //! - L shoulder button (digital)
 
-use super::{PeripheralTrait, Response};
+use super::{DeviceState, PeripheralTrait, Response};
 
 /// NeGcon controller state
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -123,10 +123,20 @@ impl PeripheralTrait for NeGcon {
                 }
             }
             SendId1 => {
-                // Send ID byte 2 (0x23 for NeGcon)
-                (0x23, SendId2, true)
+                // Send ID byte 2, always 0x5A
+                (0x5A, SendId2, true)
             }
             SendId2 => {
+                // Send digital buttons byte 1
+                let buttons1 = self.digital_buttons as u8;
+                (buttons1, SendButtons1, true)
+            }
+            SendButtons1 => {
+                // Send digital buttons byte 2
+                let buttons2 = (self.digital_buttons >> 8) as u8;
+                (buttons2, SendButtons2, true)
+            }
+            SendButtons2 => {
                 // Send twist value
                 (self.twist, SendTwist, true)
             }
@@ -140,19 +150,9 @@ impl PeripheralTrait for NeGcon {
             }
             SendButtonII => {
                 // Send button L value
-                (self.button_l, SendButtonL, true)
+                (self.button_l, SendButtonL, false) // Last byte, no DSR
             }
             SendButtonL => {
-                // Send digital buttons byte 1
-                let buttons1 = self.digital_buttons as u8;
-                (buttons1, SendButtons1, true)
-            }
-            SendButtons1 => {
-                // Send digital buttons byte 2
-                let buttons2 = (self.digital_buttons >> 8) as u8;
-                (buttons2, SendButtons2, false) // Last byte, no DSR
-            }
-            SendButtons2 => {
                 // Transfer complete
                 (0xFF, Idle, false)
             }
@@ -193,6 +193,22 @@ impl PeripheralTrait for NeGcon {
                     self.release_button(NeGconButton::Start);
                 }
             }
+            2..=6 => {
+                // R button and D-pad
+                let b = match button {
+                    2 => NeGconButton::R,
+                    3 => NeGconButton::Up,
+                    4 => NeGconButton::Right,
+                    5 => NeGconButton::Down,
+                    _ => NeGconButton::Left,
+                };
+
+                if pressed {
+                    self.press_button(b);
+                } else {
+                    self.release_button(b);
+                }
+            }
             _ => {}
         }
     }
@@ -214,18 +230,45 @@ impl PeripheralTrait for NeGcon {
                     self.set_brake(0.0);
                 } else {
                     // Negative = brake
-                    let normalized = (-value as f32) / 32768.0;
+                    let normalized = -(value as f32) / 32768.0;
                     self.set_brake(normalized);
                     self.set_throttle(0.0);
                 }
             }
+            2 => {
+                // Analog L button, 0 when released
+                let normalized = (value as f32) / 32767.0;
+                self.button_l = (normalized.max(0.0).min(1.0) * 255.0) as u8;
+            }
             _ => {}
         }
     }
     
+    fn select(&mut self) {
+        self.transfer_state = TransferState::Idle;
+    }
+
+    fn save_state(&self) -> DeviceState {
+        DeviceState::NeGcon(self.clone())
+    }
+
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        match state {
+            DeviceState::NeGcon(s) => {
+                *self = s;
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn clone_box(&self) -> Box<dyn PeripheralTrait> {
         Box::new(self.clone())
     }
+
+    fn description(&self) -> String {
+        "NeGcon (NPC-101)".to_string()
+    }
 }
 
 /// NeGcon button enumeration
@@ -250,9 +293,9 @@ impl NeGconButton {
             Right => 1 << 5,
             Down => 1 << 6,
             Left => 1 << 7,
-            R => 1 << 8,
-            B => 1 << 9,
-            A => 1 << 10,
+            R => 1 << 11,
+            B => 1 << 12,
+            A => 1 << 13,
         }
     }
 }
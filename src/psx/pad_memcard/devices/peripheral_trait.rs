@@ -1,6 +1,8 @@
 //! Common trait for all PlayStation peripherals
 
 use super::super::DsrState;
+use super::DeviceState;
+use crate::psx::gpu::DisplayArea;
 
 /// Response from a peripheral device
 pub struct Response {
@@ -44,6 +46,24 @@ pub trait Peripheral: Send {
     /// Called once per frame
     fn new_frame(&mut self) {}
     
+    /// Called at the beginning of each field with the position of the picture (light guns)
+    fn set_display_area(&mut self, _area: &DisplayArea) {}
+    
+    /// Beam position `(column, line)` of the light pen interrupt requested for this field
+    fn light_pen_target(&self) -> Option<(u16, u16)> {
+        None
+    }
+    
+    /// Returns the protocol state of the device, to be stored in save states
+    fn save_state(&self) -> DeviceState {
+        DeviceState::Stateless
+    }
+    
+    /// Restore a state returned by `save_state`, returns false if it belongs to another device type
+    fn load_state(&mut self, state: DeviceState) -> bool {
+        matches!(state, DeviceState::Stateless)
+    }
+    
     /// Get device description
     fn description(&self) -> String {
         "Generic Peripheral".to_string()
@@ -2,12 +2,15 @@
 
 pub mod devices;
 
+use super::gpu::DisplayArea;
 use super::{irq, sync, AccessWidth, Addressable, CycleCount, Psx};
 use irq::Interrupt;
 
-use self::devices::{Peripheral, DeviceInterface};
+use self::devices::multitap::Multitap;
+use self::devices::{DeviceInterface, DisconnectedDevice, Peripheral};
 
 const PADSYNC: sync::SyncToken = sync::SyncToken::PadMemCard;
+const LIGHTPENSYNC: sync::SyncToken = sync::SyncToken::LightPen;
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct PadMemCard {
@@ -59,6 +62,9 @@ pub struct PadMemCard {
     memcard2_dsr: DsrState,
     /// Bus state machine
     transfer_state: TransferState,
+    /// True if the light pen interrupt has been scheduled by a light gun
+    #[serde(default)]
+    light_pen_pending: bool,
 }
 
 impl PadMemCard {
@@ -85,6 +91,7 @@ impl PadMemCard {
             memcard2: devices::disconnected_memory_card(),
             memcard2_dsr: DsrState::Idle,
             transfer_state: TransferState::Idle,
+            light_pen_pending: false,
         }
     }
     
@@ -114,6 +121,7 @@ impl PadMemCard {
         self.memcard1_dsr = DsrState::Idle;
         self.memcard2_dsr = DsrState::Idle;
         self.transfer_state = TransferState::Idle;
+        self.light_pen_pending = false;
         
         // Restore connected devices
         self.pad1 = saved_pad1;
@@ -182,6 +190,141 @@ impl PadMemCard {
         }
     }
 
+    /// Return the gamepad and memory card peripherals of `port`
+    fn port_mut(&mut self, port: usize) -> (&mut Peripheral, &mut Peripheral) {
+        match port {
+            0 => (&mut self.pad1, &mut self.memcard1),
+            1 => (&mut self.pad2, &mut self.memcard2),
+            _ => panic!("Invalid controller port: {}", port),
+        }
+    }
+
+    /// Plug a multitap in `port`. The gamepad and memory card currently connected to the port are
+    /// moved to the multitap's slot A.
+    pub fn connect_multitap(&mut self, port: usize) {
+        if self.multitap(port).is_some() {
+            return;
+        }
+
+        let (pad, memcard) = self.port_mut(port);
+
+        let mut tap = Multitap::new();
+        tap.connect_pad(0, pad.disconnect_device());
+        tap.connect_memory_card(0, memcard.disconnect_device());
+
+        pad.connect_device(Box::new(tap));
+
+        self.pad1_dsr = DsrState::Idle;
+        self.pad2_dsr = DsrState::Idle;
+    }
+
+    /// Remove the multitap from `port`. The devices in its slot A are connected back to the port,
+    /// the others are dropped.
+    pub fn disconnect_multitap(&mut self, port: usize) {
+        let (pad, memcard) = self.port_mut(port);
+
+        let tap = match pad.device_mut().multitap_mut() {
+            Some(tap) => tap,
+            None => return,
+        };
+
+        let tap_pad = tap.connect_pad(0, Box::new(DisconnectedDevice));
+        let tap_memcard = tap.connect_memory_card(0, Box::new(DisconnectedDevice));
+
+        pad.connect_device(tap_pad);
+        memcard.connect_device(tap_memcard);
+
+        self.pad1_dsr = DsrState::Idle;
+        self.pad2_dsr = DsrState::Idle;
+        self.memcard1_dsr = DsrState::Idle;
+        self.memcard2_dsr = DsrState::Idle;
+    }
+
+    /// Return the multitap connected to `port`, if any
+    pub fn multitap(&self, port: usize) -> Option<&Multitap> {
+        let pad = match port {
+            0 => &self.pad1,
+            1 => &self.pad2,
+            _ => return None,
+        };
+
+        pad.device().multitap()
+    }
+
+    /// Return the gamepad in `slot` of `port`. Slots 1 to 3 (multitap slots B to D) only exist if
+    /// a multitap is connected.
+    pub fn gamepad_mut(&mut self, port: usize, slot: usize) -> Option<&mut Peripheral> {
+        let has_tap = self.multitap(port).is_some();
+        let (pad, _) = self.port_mut(port);
+
+        if has_tap {
+            pad.device_mut().multitap_mut().map(|tap| tap.pad_mut(slot))
+        } else if slot == 0 {
+            Some(pad)
+        } else {
+            None
+        }
+    }
+
+    /// Return the memory card in `slot` of `port`. Slots 1 to 3 (multitap slots B to D) only
+    /// exist if a multitap is connected.
+    pub fn memory_card(&self, port: usize, slot: usize) -> Option<&Peripheral> {
+        if let Some(tap) = self.multitap(port) {
+            return Some(tap.memory_card(slot));
+        }
+
+        match (port, slot) {
+            (0, 0) => Some(&self.memcard1),
+            (1, 0) => Some(&self.memcard2),
+            _ => None,
+        }
+    }
+
+    /// Mutable version of `memory_card`
+    pub fn memory_card_mut(&mut self, port: usize, slot: usize) -> Option<&mut Peripheral> {
+        let has_tap = self.multitap(port).is_some();
+        let (pad, memcard) = self.port_mut(port);
+
+        if has_tap {
+            pad.device_mut()
+                .multitap_mut()
+                .map(|tap| tap.memory_card_mut(slot))
+        } else if slot == 0 {
+            Some(memcard)
+        } else {
+            None
+        }
+    }
+
+    /// Must be called by the frontend at the end of every frame. The multitaps forward the new
+    /// frame to all their slots.
+    pub fn new_frame(&mut self) {
+        for mc in self.memory_cards_mut().iter_mut() {
+            mc.device_mut().new_frame();
+        }
+
+        for gp in self.gamepads_mut().iter_mut() {
+            gp.device_mut().new_frame();
+        }
+    }
+
+    /// Tell the devices connected to the controller ports where the picture is in the video
+    /// signal. Called by the GPU at the beginning of each field.
+    pub fn set_display_area(&mut self, area: &DisplayArea) {
+        self.pad1.device_mut().set_display_area(area);
+        self.pad2.device_mut().set_display_area(area);
+    }
+
+    /// If a light gun wants to trigger the light pen interrupt on `line`, returns the column (in
+    /// GPU cycles since the start of the HSYNC) where it should occur
+    pub fn light_pen_column(&self, line: u16) -> Option<u16> {
+        [&self.pad1, &self.pad2]
+            .iter()
+            .filter_map(|p| p.device().light_pen_target())
+            .find(|&(_, l)| l == line)
+            .map(|(column, _)| column)
+    }
+
     fn maybe_exchange_byte(&mut self) {
         let to_send = match self.tx_pending {
             Some(b) => b,
@@ -527,6 +670,24 @@ pub fn run(psx: &mut Psx) {
     predict_next_sync(psx);
 }
 
+/// Program the light pen interrupt `delay` CPU cycles from now
+pub fn schedule_light_pen_irq(psx: &mut Psx, delay: CycleCount) {
+    psx.pad_memcard.light_pen_pending = true;
+
+    sync::next_event(psx, LIGHTPENSYNC, delay);
+}
+
+/// Called when the beam reaches the position aimed by a light gun
+pub fn run_light_pen(psx: &mut Psx) {
+    if psx.pad_memcard.light_pen_pending {
+        psx.pad_memcard.light_pen_pending = false;
+        irq::trigger(psx, Interrupt::LightPen);
+    }
+
+    // Nothing to do until the GPU schedules a new event
+    sync::next_event(psx, LIGHTPENSYNC, 1_000_000);
+}
+
 pub fn store<T: Addressable>(psx: &mut Psx, off: u32, val: T) {
     run_controller(psx);
 
@@ -692,3 +853,139 @@ impl DsrState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::devices::gamepad::{Button, ButtonState, DigitalPad};
+    use super::devices::memory_card::MemoryCard;
+    use super::*;
+
+    /// Run a full transaction on port 1, returning the response and whether DSR was asserted for
+    /// every byte
+    fn transaction(pm: &mut PadMemCard, cmds: &[u8]) -> Vec<(u8, bool)> {
+        pm.set_control(0);
+        pm.set_control(0x3);
+
+        cmds.iter()
+            .map(|&cmd| {
+                pm.tx_pending = Some(cmd);
+                pm.maybe_exchange_byte();
+
+                let response = match pm.transfer_state {
+                    TransferState::TxStart(_, _, r) => r,
+                    ref s => panic!("Unexpected transfer state {:?}", s),
+                };
+
+                pm.transfer_state = TransferState::Idle;
+
+                let dsr = pm.pad1_dsr != DsrState::Idle || pm.memcard1_dsr != DsrState::Idle;
+
+                (response, dsr)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn multitap_protocol() {
+        let mut pm = PadMemCard::new();
+        pm.baud_div = 0x88;
+
+        let mut pad_a = DigitalPad::new();
+        pad_a.set_button_state(Button::Start, ButtonState::Pressed);
+        pm.gamepads_mut()[0].connect_device(Box::new(pad_a));
+
+        pm.connect_multitap(0);
+
+        let mut pad_c = DigitalPad::new();
+        pad_c.set_button_state(Button::Cross, ButtonState::Pressed);
+        pm.gamepad_mut(0, 2)
+            .unwrap()
+            .connect_device(Box::new(pad_c));
+
+        let card = Box::new(MemoryCard::new_formatted());
+        pm.memory_card_mut(0, 1).unwrap().connect_device(card);
+
+        // Newly connected memory cards are disabled for 120 frames
+        for _ in 0..120 {
+            pm.gamepads_mut()[0].device_mut().new_frame();
+        }
+
+        // Regular access to slot A, the TAP byte enables the "read all" mode for the next
+        // transaction
+        let r = transaction(&mut pm, &[0x01, 0x42, 0x01, 0x00, 0x00]);
+        assert_eq!(
+            r,
+            [
+                (0xff, true),
+                (0x41, true),
+                (0x5a, true),
+                (0xf7, true),
+                (0xff, false)
+            ]
+        );
+
+        // Read all the controllers at once
+        let mut cmds = vec![0x01, 0x42, 0x00];
+        cmds.extend_from_slice(&[0; 32]);
+
+        let r = transaction(&mut pm, &cmds);
+        let data: Vec<u8> = r.iter().map(|&(b, _)| b).collect();
+
+        let mut expected = vec![0xff, 0x80, 0x5a];
+        // Slot A
+        expected.extend_from_slice(&[0x41, 0x5a, 0xf7, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        // Slot B
+        expected.extend_from_slice(&[0xff; 8]);
+        // Slot C
+        expected.extend_from_slice(&[0x41, 0x5a, 0xff, 0xbf, 0xff, 0xff, 0xff, 0xff]);
+        // Slot D
+        expected.extend_from_slice(&[0xff; 8]);
+
+        assert_eq!(data.len(), 35);
+        assert_eq!(data, expected);
+        // DSR is asserted for every byte but the last
+        assert!(r[..34].iter().all(|&(_, dsr)| dsr));
+        assert!(!r[34].1);
+
+        // The TAP byte was 0, back to single controller accesses
+        let r = transaction(&mut pm, &[0x01, 0x42, 0x00, 0x00, 0x00]);
+        assert_eq!(r[1], (0x41, true));
+
+        // Direct access to slot C
+        let r = transaction(&mut pm, &[0x03, 0x42, 0x00, 0x00, 0x00]);
+        let data: Vec<u8> = r.iter().map(|&(b, _)| b).collect();
+        assert_eq!(data, [0xff, 0x41, 0x5a, 0xff, 0xbf]);
+
+        // Memory card in slot B
+        let r = transaction(&mut pm, &[0x82, b'S', 0x00, 0x00]);
+        assert_eq!(r, [(0xff, true), (0x08, true), (0x5a, true), (0x5d, true)]);
+
+        // No memory card in slot A
+        let r = transaction(&mut pm, &[0x81, b'S']);
+        assert_eq!(r[0], (0xff, false));
+
+        // Unplugging the multitap puts the devices of slot A back on the port
+        pm.disconnect_multitap(0);
+        assert!(pm.multitap(0).is_none());
+        assert!(pm.gamepad_mut(0, 2).is_none());
+
+        let r = transaction(&mut pm, &[0x01, 0x42, 0x00, 0x00, 0x00]);
+        assert_eq!(r[1], (0x41, true));
+    }
+
+    /// Save states created before the light guns were implemented don't have `light_pen_pending`
+    #[test]
+    fn load_state_without_light_pen() {
+        let mut state = toml::Value::try_from(PadMemCard::new()).unwrap();
+        state
+            .as_table_mut()
+            .unwrap()
+            .remove("light_pen_pending")
+            .unwrap();
+
+        let state = flexbuffers::to_vec(&state).unwrap();
+        let pm: PadMemCard = flexbuffers::from_slice(&state).unwrap();
+
+        assert!(!pm.light_pen_pending);
+    }
+}
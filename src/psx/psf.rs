@@ -0,0 +1,564 @@
+//! PSF (Portable Sound Format) music rips
+//!
+//! A PSF1 file contains a zlib-compressed PS-X EXE with the game's sound driver and music data
+//! (stripped of everything else) followed by optional tags. MINIPSF files only contain the data
+//! for a single song and reference a shared PSFLIB containing the driver through the `_lib` tag.
+//! Additional libraries can be loaded on top of the main file with `_lib2`, `_lib3` etc...
+//!
+//! The resulting executable is sideloaded once the BIOS has booted, no disc is needed.
+
+use super::cd::disc::Region;
+use super::exe::Exe;
+use super::gpu::VideoStandard;
+use crate::error::{PsxError, Result};
+use flate2::read::ZlibDecoder;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+/// Version byte for PlayStation PSF files (PSF1)
+const PSF_VERSION_PSX: u8 = 0x01;
+
+/// Libraries can reference other libraries, this limits the depth to catch loops
+const MAX_LIB_DEPTH: u32 = 10;
+
+/// Playing time used when the file doesn't have a `length` tag
+pub const DEFAULT_LENGTH_MS: u32 = 180_000;
+
+/// Fade-out duration used when the file doesn't have a `fade` tag
+pub const DEFAULT_FADE_MS: u32 = 10_000;
+
+/// SPU output sample rate
+const SAMPLE_RATE: u64 = 44_100;
+
+/// A fully resolved PSF: executable with all the libraries loaded and tags of the main file
+#[derive(Clone, Debug)]
+pub struct Psf {
+    exe: Exe,
+    tags: Tags,
+}
+
+impl Psf {
+    /// Load the PSF or MINIPSF at `path`. Libraries are looked for in the same directory.
+    pub fn load(path: &Path) -> Result<Psf> {
+        Psf::load_with(path, |p| Ok(fs::read(p)?))
+    }
+
+    /// Same as `load` but using `read` to get the contents of the file and its libraries
+    pub fn load_with<F>(path: &Path, mut read: F) -> Result<Psf>
+    where
+        F: FnMut(&Path) -> Result<Vec<u8>>,
+    {
+        let (exe, tags) = load_exe(path, &mut read, 0)?;
+
+        let exe = exe.ok_or_else(|| PsxError::BadPsf("no program data".to_string()))?;
+
+        Ok(Psf { exe, tags })
+    }
+
+    pub fn exe(&self) -> &Exe {
+        &self.exe
+    }
+
+    pub fn tags(&self) -> &Tags {
+        &self.tags
+    }
+
+    /// Region of the executable, used to select a matching BIOS
+    pub fn region(&self) -> Option<Region> {
+        self.exe.region
+    }
+
+    /// Video standard to run the console in. The `_refresh` tag takes precedence over the
+    /// executable's region.
+    pub fn video_standard(&self) -> VideoStandard {
+        match self.tags.get("_refresh").map(str::trim) {
+            Some("50") => VideoStandard::Pal,
+            Some("60") => VideoStandard::Ntsc,
+            _ => self
+                .region()
+                .map(Region::video_standard)
+                .unwrap_or(VideoStandard::Ntsc),
+        }
+    }
+
+    /// Playing time before the fade-out, in milliseconds
+    pub fn length_ms(&self) -> u32 {
+        self.tags.length_ms().unwrap_or(DEFAULT_LENGTH_MS)
+    }
+
+    /// Fade-out duration in milliseconds
+    pub fn fade_ms(&self) -> u32 {
+        self.tags.fade_ms().unwrap_or(DEFAULT_FADE_MS)
+    }
+
+    /// Returns a `Fader` set up for this file's length and fade-out
+    pub fn fader(&self) -> Fader {
+        Fader::new(self.length_ms(), self.fade_ms())
+    }
+}
+
+/// Returns true if `path` has one of the extensions used for PSF1 files. Libraries (.psflib) are
+/// not included since they can't be played on their own.
+pub fn is_psf_path(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("psf") || ext.eq_ignore_ascii_case("minipsf"),
+        None => false,
+    }
+}
+
+/// Load the file at `path` and all its libraries and return the resulting executable (if any)
+/// along with the file's tags.
+///
+/// The `_lib` is loaded first, then the file's own executable, then `_lib2`, `_lib3` etc... The
+/// registers of the first loaded executable are used.
+fn load_exe<F>(path: &Path, read: &mut F, depth: u32) -> Result<(Option<Exe>, Tags)>
+where
+    F: FnMut(&Path) -> Result<Vec<u8>>,
+{
+    if depth > MAX_LIB_DEPTH {
+        return Err(PsxError::BadPsf(format!(
+            "{}: too many nested libraries",
+            path.display()
+        )));
+    }
+
+    let (program, tags) = parse(&read(path)?)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut load_lib = |name: &str| -> Result<Option<Exe>> {
+        let (exe, _) = load_exe(&dir.join(name.trim()), read, depth + 1)?;
+
+        Ok(exe)
+    };
+
+    let mut exe = match tags.get("_lib") {
+        Some(lib) => load_lib(lib)?,
+        None => None,
+    };
+
+    let add = |exe: &mut Option<Exe>, other: Option<Exe>| match (exe.as_mut(), other) {
+        (Some(exe), Some(other)) => exe.overlay(&other),
+        (None, other) => *exe = other,
+        (_, None) => (),
+    };
+
+    if !program.is_empty() {
+        add(&mut exe, Some(Exe::parse(&program)?));
+    }
+
+    for n in 2.. {
+        match tags.get(&format!("_lib{}", n)) {
+            Some(lib) => add(&mut exe, load_lib(lib)?),
+            None => break,
+        }
+    }
+
+    Ok((exe, tags))
+}
+
+/// Parse a single PSF file and return the decompressed program and the tags
+fn parse(data: &[u8]) -> Result<(Vec<u8>, Tags)> {
+    if data.len() < 16 || &data[0..3] != b"PSF" {
+        return Err(PsxError::BadPsf("missing PSF header".to_string()));
+    }
+
+    if data[3] != PSF_VERSION_PSX {
+        return Err(PsxError::BadPsf(format!(
+            "not a PlayStation PSF (version 0x{:02x})",
+            data[3]
+        )));
+    }
+
+    let word = |off: usize| {
+        u32::from_le_bytes([data[off], data[off + 1], data[off + 2], data[off + 3]]) as usize
+    };
+
+    let reserved_len = word(4);
+    let program_len = word(8);
+    let crc = word(12) as u32;
+
+    let program_start = 16usize.saturating_add(reserved_len);
+    let program_end = program_start.saturating_add(program_len);
+
+    if program_end > data.len() {
+        return Err(PsxError::BadPsf("truncated file".to_string()));
+    }
+
+    let compressed = &data[program_start..program_end];
+
+    let mut program = Vec::new();
+
+    if !compressed.is_empty() {
+        let mut hasher = flate2::Crc::new();
+        hasher.update(compressed);
+
+        if hasher.sum() != crc {
+            return Err(PsxError::BadPsf("program CRC mismatch".to_string()));
+        }
+
+        ZlibDecoder::new(compressed)
+            .read_to_end(&mut program)
+            .map_err(|e| PsxError::BadPsf(format!("can't decompress program: {}", e)))?;
+    }
+
+    let tags = match data[program_end..].strip_prefix(b"[TAG]") {
+        Some(tags) => Tags::parse(tags),
+        None => Tags::default(),
+    };
+
+    Ok((program, tags))
+}
+
+/// PSF tags, in the order they appear in the file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tags {
+    tags: Vec<(String, String)>,
+}
+
+impl Tags {
+    /// Parse the tag area (everything after the "[TAG]" marker). Each line has the format
+    /// `name=value`, names are case insensitive and multi-line values are made of several lines
+    /// with the same name.
+    fn parse(data: &[u8]) -> Tags {
+        let text = String::from_utf8_lossy(data);
+        let mut tags = Tags::default();
+
+        for line in text.split('\n') {
+            let (name, value) = match line.split_once('=') {
+                Some(t) => t,
+                None => continue,
+            };
+
+            // The spec says that all the characters from 0x01 to 0x20 count as whitespace
+            let trim = |s: &str| s.trim_matches(|c| ('\u{1}'..=' ').contains(&c)).to_string();
+
+            let name = trim(name).to_ascii_lowercase();
+            let value = trim(value);
+
+            if name.is_empty() {
+                continue;
+            }
+
+            match tags.tags.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, v)) => {
+                    v.push('\n');
+                    v.push_str(&value);
+                }
+                None => tags.tags.push((name, value)),
+            }
+        }
+
+        tags
+    }
+
+    /// Returns the value of tag `name` (case insensitive)
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.tags.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.get("title")
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        self.get("artist")
+    }
+
+    pub fn game(&self) -> Option<&str> {
+        self.get("game")
+    }
+
+    pub fn length_ms(&self) -> Option<u32> {
+        self.get("length").and_then(parse_duration_ms)
+    }
+
+    pub fn fade_ms(&self) -> Option<u32> {
+        self.get("fade").and_then(parse_duration_ms)
+    }
+}
+
+/// Parse a duration in the format `[[hours:]minutes:]seconds[.decimals]`. Some taggers use a
+/// comma as the decimal separator.
+fn parse_duration_ms(s: &str) -> Option<u32> {
+    let s = s.trim().replace(',', ".");
+
+    let mut ms = 0u32;
+
+    let mut fields = s.rsplit(':');
+
+    let seconds = fields.next()?;
+    let seconds: f64 = seconds.parse().ok()?;
+
+    if !(0. ..1e6).contains(&seconds) {
+        return None;
+    }
+
+    ms += (seconds * 1000.).round() as u32;
+
+    for mult in [60_000, 3_600_000] {
+        match fields.next() {
+            Some(f) => {
+                let v: u32 = f.trim().parse().ok()?;
+                ms = ms.checked_add(v.checked_mul(mult)?)?;
+            }
+            None => break,
+        }
+    }
+
+    if fields.next().is_some() {
+        return None;
+    }
+
+    Some(ms)
+}
+
+/// Limits playback to a fixed length and applies a linear fade-out at the end
+#[derive(Clone, Debug)]
+pub struct Fader {
+    /// Number of stereo samples played at full volume
+    length: u64,
+    /// Duration of the fade-out, in stereo samples
+    fade: u64,
+    /// Number of stereo samples processed so far
+    position: u64,
+}
+
+impl Fader {
+    pub fn new(length_ms: u32, fade_ms: u32) -> Fader {
+        Fader {
+            length: u64::from(length_ms) * SAMPLE_RATE / 1000,
+            fade: u64::from(fade_ms) * SAMPLE_RATE / 1000,
+            position: 0,
+        }
+    }
+
+    /// Apply the fade to `samples` (interleaved stereo). Anything past the end of the fade is
+    /// silenced.
+    pub fn process(&mut self, samples: &mut [i16]) {
+        for frame in samples.chunks_mut(2) {
+            let gain = self.gain();
+
+            if gain < 0x1_0000 {
+                for s in frame.iter_mut() {
+                    *s = ((i64::from(*s) * gain) >> 16) as i16;
+                }
+            }
+
+            self.position += 1;
+        }
+    }
+
+    /// Current gain in 16.16 fixed point
+    fn gain(&self) -> i64 {
+        let end = self.length + self.fade;
+
+        if self.position < self.length {
+            0x1_0000
+        } else if self.position < end {
+            (((end - self.position) << 16) / self.fade) as i64
+        } else {
+            0
+        }
+    }
+
+    /// Returns true once the fade-out is complete
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.length + self.fade
+    }
+
+    /// Total number of stereo samples in the render, fade-out included
+    pub fn total_samples(&self) -> u64 {
+        self.length + self.fade
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    /// Build an EXE loading `text` at `addr` with entry point `pc`
+    fn build_exe(pc: u32, addr: u32, text: &[u8]) -> Vec<u8> {
+        let mut exe = vec![0; 0x800];
+
+        exe[0..8].copy_from_slice(b"PS-X EXE");
+        exe[0x10..0x14].copy_from_slice(&pc.to_le_bytes());
+        exe[0x18..0x1c].copy_from_slice(&addr.to_le_bytes());
+        exe[0x1c..0x20].copy_from_slice(&(text.len() as u32).to_le_bytes());
+        exe[0x30..0x34].copy_from_slice(&0x801f_fff0u32.to_le_bytes());
+
+        let license = b"Sony Computer Entertainment Inc. for Europe area";
+        exe[0x4c..0x4c + license.len()].copy_from_slice(license);
+
+        exe.extend_from_slice(text);
+        exe
+    }
+
+    fn build_psf(exe: &[u8], tags: &str) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(exe).unwrap();
+        let program = encoder.finish().unwrap();
+
+        let mut crc = flate2::Crc::new();
+        crc.update(&program);
+
+        let mut psf = b"PSF\x01".to_vec();
+        psf.extend_from_slice(&0u32.to_le_bytes());
+        psf.extend_from_slice(&(program.len() as u32).to_le_bytes());
+        psf.extend_from_slice(&crc.sum().to_le_bytes());
+        psf.extend_from_slice(&program);
+
+        if !tags.is_empty() {
+            psf.extend_from_slice(b"[TAG]");
+            psf.extend_from_slice(tags.as_bytes());
+        }
+
+        psf
+    }
+
+    fn load(files: &HashMap<PathBuf, Vec<u8>>, path: &str) -> Result<Psf> {
+        Psf::load_with(Path::new(path), |p| {
+            files.get(p).cloned().ok_or(PsxError::FileNotFound)
+        })
+    }
+
+    #[test]
+    fn tags() {
+        let tags = Tags::parse(
+            b"title=Battle Theme\n  ARTIST =  Someone \ncomment=line 1\ncomment=line 2\n\
+              garbage\nlength=1:02.5\nfade=8\n",
+        );
+
+        assert_eq!(tags.title(), Some("Battle Theme"));
+        assert_eq!(tags.artist(), Some("Someone"));
+        assert_eq!(tags.get("Comment"), Some("line 1\nline 2"));
+        assert_eq!(tags.game(), None);
+        assert_eq!(tags.length_ms(), Some(62_500));
+        assert_eq!(tags.fade_ms(), Some(8_000));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration_ms("90"), Some(90_000));
+        assert_eq!(parse_duration_ms("2:30"), Some(150_000));
+        assert_eq!(parse_duration_ms("1:00:01.25"), Some(3_601_250));
+        assert_eq!(parse_duration_ms("0,5"), Some(500));
+        assert_eq!(parse_duration_ms("1:2:3:4"), None);
+        assert_eq!(parse_duration_ms("abc"), None);
+        assert_eq!(parse_duration_ms(""), None);
+    }
+
+    #[test]
+    fn single_psf() {
+        let exe = build_exe(0x8001_0000, 0x8001_0000, &[1, 2, 3, 4]);
+        let mut files = HashMap::new();
+
+        files.insert(
+            PathBuf::from("dir/song.psf"),
+            build_psf(&exe, "title=Song\n_refresh=60\n"),
+        );
+
+        let psf = load(&files, "dir/song.psf").unwrap();
+
+        assert_eq!(psf.exe().pc, 0x8001_0000);
+        assert_eq!(psf.exe().sp, 0x801f_fff0);
+        assert_eq!(psf.exe().text, [1, 2, 3, 4]);
+        assert_eq!(psf.region(), Some(Region::Europe));
+        // `_refresh` overrides the region
+        assert!(matches!(psf.video_standard(), VideoStandard::Ntsc));
+        assert_eq!(psf.tags().title(), Some("Song"));
+        assert_eq!(psf.length_ms(), DEFAULT_LENGTH_MS);
+        assert_eq!(psf.fade_ms(), DEFAULT_FADE_MS);
+    }
+
+    #[test]
+    fn minipsf_chain() {
+        let mut files = HashMap::new();
+
+        // Driver library, also sets the registers
+        let lib = build_exe(0x8001_0000, 0x8001_0000, &[0xaa; 16]);
+        files.insert(PathBuf::from("rip/driver.psflib"), build_psf(&lib, ""));
+
+        // Song data, partially overwrites the library
+        let song = build_exe(0, 0x8001_0008, &[0xbb; 16]);
+        files.insert(
+            PathBuf::from("rip/song.minipsf"),
+            build_psf(
+                &song,
+                "_lib=driver.psflib\n_lib2=patch.psflib\nlength=1:00\n",
+            ),
+        );
+
+        // Secondary library loaded last
+        let patch = build_exe(0, 0x8001_0014, &[0xcc; 4]);
+        files.insert(PathBuf::from("rip/patch.psflib"), build_psf(&patch, ""));
+
+        let psf = load(&files, "rip/song.minipsf").unwrap();
+
+        let mut expected = vec![0xaa; 8];
+        expected.extend_from_slice(&[0xbb; 12]);
+        expected.extend_from_slice(&[0xcc; 4]);
+
+        assert_eq!(psf.exe().pc, 0x8001_0000);
+        assert_eq!(psf.exe().load_addr, 0x8001_0000);
+        assert_eq!(psf.exe().text, expected);
+        assert_eq!(psf.length_ms(), 60_000);
+
+        // Missing library
+        files.remove(&PathBuf::from("rip/patch.psflib"));
+        assert!(load(&files, "rip/song.minipsf").is_err());
+    }
+
+    #[test]
+    fn library_loop() {
+        let exe = build_exe(0x8001_0000, 0x8001_0000, &[0; 4]);
+        let mut files = HashMap::new();
+
+        files.insert(PathBuf::from("a.psf"), build_psf(&exe, "_lib=a.psf\n"));
+
+        assert!(load(&files, "a.psf").is_err());
+    }
+
+    #[test]
+    fn bad_crc() {
+        let exe = build_exe(0x8001_0000, 0x8001_0000, &[0; 4]);
+        let mut psf = build_psf(&exe, "");
+        psf[12] ^= 1;
+
+        assert!(parse(&psf).is_err());
+        // PSF2 (PlayStation 2)
+        assert!(parse(b"PSF\x02\0\0\0\0\0\0\0\0\0\0\0\0").is_err());
+    }
+
+    #[test]
+    fn fader() {
+        // 1ms of playback followed by 1ms of fade-out
+        let mut fader = Fader::new(1, 1);
+        let total = fader.total_samples() as usize;
+
+        assert_eq!(total, 88);
+
+        let mut samples = vec![1000i16; (total + 10) * 2];
+        fader.process(&mut samples);
+
+        assert!(fader.is_finished());
+        assert!(samples[..88].iter().all(|&s| s == 1000));
+        // Fading out
+        assert!(samples[88..176].windows(2).all(|w| w[0] >= w[1]));
+        assert!(samples[88] > 950 && samples[174] < 50);
+        // Silence after the end
+        assert!(samples[176..].iter().all(|&s| s == 0));
+    }
+}
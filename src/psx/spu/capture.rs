@@ -0,0 +1,297 @@
+//! Multitrack audio capture
+//!
+//! When enabled the SPU records the output of every voice, the CD audio input and the reverb
+//! return into separate tracks, on top of generating the normal stereo mix. This is meant for
+//! soundtrack rips where the individual stems are needed.
+//!
+//! Voices are captured after the ADSR envelope and the left/right volume but before the SPU mute
+//! bit, the user mute/solo controls and the main volume. The CD audio and reverb tracks contain
+//! exactly what's added to the mix, after their respective volumes.
+//!
+//! Every track is stereo and all tracks always have the same length. Capturing is fairly memory
+//! hungry: 26 stereo tracks at 44.1kHz take a bit over 4.5MB per second of emulated time.
+
+#[cfg(feature = "wav-export")]
+use crate::error::Result;
+#[cfg(feature = "wav-export")]
+use std::io::{Seek, Write};
+#[cfg(feature = "wav-export")]
+use std::path::Path;
+
+/// Number of voices in the SPU
+const VOICE_COUNT: u8 = 24;
+
+/// Total number of captured tracks: one per voice, plus CD audio and reverb
+pub const TRACK_COUNT: usize = VOICE_COUNT as usize + 2;
+
+/// SPU output sample rate
+pub const SAMPLE_RATE: u32 = 44_100;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Track {
+    Voice(u8),
+    CdAudio,
+    Reverb,
+}
+
+impl Track {
+    /// Returns all the tracks in the order they're stored in a multichannel WAV file
+    pub fn all() -> impl Iterator<Item = Track> {
+        (0..VOICE_COUNT)
+            .map(Track::Voice)
+            .chain([Track::CdAudio, Track::Reverb])
+    }
+
+    pub fn index(self) -> usize {
+        match self {
+            Track::Voice(v) => {
+                debug_assert!(v < VOICE_COUNT);
+                usize::from(v)
+            }
+            Track::CdAudio => usize::from(VOICE_COUNT),
+            Track::Reverb => usize::from(VOICE_COUNT) + 1,
+        }
+    }
+
+    /// Name of the track, used for the file name when exporting one WAV per track
+    pub fn name(self) -> String {
+        match self {
+            Track::Voice(v) => format!("voice_{:02}", v),
+            Track::CdAudio => "cd_audio".to_string(),
+            Track::Reverb => "reverb".to_string(),
+        }
+    }
+}
+
+/// Captured audio tracks
+#[derive(Clone, Debug)]
+pub struct SpuCapture {
+    /// Interleaved stereo samples for each track, indexed by `Track::index`
+    tracks: Vec<Vec<i16>>,
+}
+
+impl SpuCapture {
+    pub fn new() -> SpuCapture {
+        SpuCapture {
+            tracks: vec![Vec::new(); TRACK_COUNT],
+        }
+    }
+
+    /// Append a stereo sample to `track`, saturating it to 16 bits
+    pub fn push(&mut self, track: Track, left: i32, right: i32) {
+        let t = &mut self.tracks[track.index()];
+
+        t.push(saturate(left));
+        t.push(saturate(right));
+    }
+
+    /// Returns the interleaved stereo samples captured for `track`
+    pub fn track(&self, track: Track) -> &[i16] {
+        &self.tracks[track.index()]
+    }
+
+    /// Returns the number of stereo samples captured in each track
+    pub fn len(&self) -> usize {
+        self.tracks[0].len() / 2
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns true if `track` only contains silence
+    pub fn is_silent(&self, track: Track) -> bool {
+        self.track(track).iter().all(|&s| s == 0)
+    }
+
+    /// Write all the tracks into a single WAV file with two channels per track. The channels are
+    /// in the order given by `Track::all`.
+    #[cfg(feature = "wav-export")]
+    pub fn write_multichannel_wav<W: Write + Seek>(&self, writer: W) -> Result<()> {
+        let spec = wav_spec((TRACK_COUNT * 2) as u16);
+        let mut wav = hound::WavWriter::new(writer, spec)?;
+
+        for i in 0..self.len() {
+            for track in &self.tracks {
+                wav.write_sample(track[i * 2])?;
+                wav.write_sample(track[i * 2 + 1])?;
+            }
+        }
+
+        wav.finalize()?;
+
+        Ok(())
+    }
+
+    /// Write one stereo WAV file per track in `dir`, named after `Track::name`. If `skip_silent`
+    /// is true the tracks that contain only silence are ignored.
+    #[cfg(feature = "wav-export")]
+    pub fn write_track_wavs(&self, dir: &Path, skip_silent: bool) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+
+        for track in Track::all() {
+            if skip_silent && self.is_silent(track) {
+                continue;
+            }
+
+            let path = dir.join(format!("{}.wav", track.name()));
+            let mut wav = hound::WavWriter::create(path, wav_spec(2))?;
+
+            for &s in self.track(track) {
+                wav.write_sample(s)?;
+            }
+
+            wav.finalize()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for SpuCapture {
+    fn default() -> SpuCapture {
+        SpuCapture::new()
+    }
+}
+
+/// User controls to silence some voices in the mix. Unlike the SPU's own mute bit these don't
+/// affect the emulated state in any way: the voices keep running and are still captured.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct VoiceMask {
+    /// One bit per muted voice
+    muted: u32,
+    /// One bit per soloed voice. If any voice is soloed, only soloed voices are audible.
+    soloed: u32,
+}
+
+impl VoiceMask {
+    pub fn set_muted(&mut self, voice: u8, muted: bool) {
+        set_bit(&mut self.muted, voice, muted);
+    }
+
+    pub fn is_muted(&self, voice: u8) -> bool {
+        self.muted & (1 << voice) != 0
+    }
+
+    pub fn set_soloed(&mut self, voice: u8, soloed: bool) {
+        set_bit(&mut self.soloed, voice, soloed);
+    }
+
+    pub fn is_soloed(&self, voice: u8) -> bool {
+        self.soloed & (1 << voice) != 0
+    }
+
+    /// Returns true if `voice` should be added to the mix
+    pub fn is_audible(&self, voice: u8) -> bool {
+        !self.is_muted(voice) && (self.soloed == 0 || self.is_soloed(voice))
+    }
+}
+
+fn set_bit(mask: &mut u32, voice: u8, set: bool) {
+    debug_assert!(voice < VOICE_COUNT);
+
+    if set {
+        *mask |= 1 << voice;
+    } else {
+        *mask &= !(1 << voice);
+    }
+}
+
+fn saturate(v: i32) -> i16 {
+    v.clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16
+}
+
+#[cfg(feature = "wav-export")]
+fn wav_spec(channels: u16) -> hound::WavSpec {
+    hound::WavSpec {
+        channels,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn voice_mask() {
+        let mut mask = VoiceMask::default();
+
+        assert!((0..VOICE_COUNT).all(|v| mask.is_audible(v)));
+
+        mask.set_muted(3, true);
+        assert!(!mask.is_audible(3));
+        assert!(mask.is_audible(4));
+
+        // Solo takes over: only soloed voices are audible
+        mask.set_soloed(4, true);
+        mask.set_soloed(5, true);
+        assert!(mask.is_audible(4));
+        assert!(mask.is_audible(5));
+        assert!(!mask.is_audible(6));
+
+        // Mute still applies to soloed voices
+        mask.set_muted(5, true);
+        assert!(!mask.is_audible(5));
+
+        mask.set_soloed(4, false);
+        mask.set_soloed(5, false);
+        mask.set_muted(3, false);
+        mask.set_muted(5, false);
+        assert_eq!(mask, VoiceMask::default());
+    }
+
+    #[test]
+    fn capture_tracks() {
+        let mut capture = SpuCapture::new();
+
+        for i in 0..10 {
+            for track in Track::all() {
+                capture.push(track, track.index() as i32 * 100 + i, -i);
+            }
+        }
+
+        assert_eq!(capture.len(), 10);
+        assert_eq!(Track::all().count(), TRACK_COUNT);
+        assert_eq!(capture.track(Track::Voice(7))[..4], [700, 0, 701, -1]);
+        assert_eq!(capture.track(Track::Reverb)[18..], [2509, -9]);
+        assert!(!capture.is_silent(Track::CdAudio));
+
+        // Saturation
+        capture.push(Track::CdAudio, 0x1_0000, -0x1_0000);
+        assert_eq!(capture.track(Track::CdAudio)[20..], [i16::MAX, i16::MIN]);
+    }
+
+    #[cfg(feature = "wav-export")]
+    #[test]
+    fn multichannel_wav() {
+        let mut capture = SpuCapture::new();
+
+        for i in 0..100 {
+            for track in Track::all() {
+                capture.push(track, track.index() as i32, i);
+            }
+        }
+
+        let mut wav = std::io::Cursor::new(Vec::new());
+        capture.write_multichannel_wav(&mut wav).unwrap();
+        wav.set_position(0);
+
+        let mut reader = hound::WavReader::new(wav).unwrap();
+        assert_eq!(reader.spec().channels as usize, TRACK_COUNT * 2);
+        assert_eq!(reader.duration(), 100);
+
+        let samples: Vec<i16> = reader.samples().map(|s| s.unwrap()).collect();
+
+        for (i, frame) in samples.chunks(TRACK_COUNT * 2).enumerate() {
+            for track in Track::all() {
+                let t = track.index();
+
+                assert_eq!(frame[t * 2], t as i16);
+                assert_eq!(frame[t * 2 + 1], i as i16);
+            }
+        }
+    }
+}
@@ -14,6 +14,35 @@ pub fn filter(phase: u8, samples: [i16; 4]) -> i32 {
     r >> 15
 }
 
+/// Same as `filter` but using the full 12-bit `phase`: the coefficients are linearly interpolated
+/// between two consecutive table entries. Not accurate, the hardware only uses 8 bits.
+pub fn filter_precise(phase: u16, samples: [i16; 4]) -> i32 {
+    let index = usize::from(phase >> 4);
+    let frac = i32::from(phase & 0xf);
+
+    let cur = FIR_COEFFS[index];
+    let next = match FIR_COEFFS.get(index + 1) {
+        Some(&c) => c,
+        // The entry after the last one is the first one, shifted by one sample. The tap that
+        // falls off is effectively 0.
+        None => {
+            let first = FIR_COEFFS[0];
+            [0, first[0], first[1], first[2]]
+        }
+    };
+
+    let mut r = 0;
+
+    for i in 0..4 {
+        let s = samples[i] as i32;
+        let c = ((cur[i] as i32) * (16 - frac) + (next[i] as i32) * frac) >> 4;
+
+        r += s * c;
+    }
+
+    r >> 15
+}
+
 /// SPU FIR filter: 4 taps, 256 phases
 ///
 /// FIR coefficients taken from Mednafen. No$ seems to have a very similar table although I haven't
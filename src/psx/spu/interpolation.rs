@@ -0,0 +1,502 @@
+//! Voice sample interpolation
+//!
+//! The SPU plays back samples at arbitrary pitches by stepping through the decoded ADPCM samples
+//! with a 12-bit fractional phase. The real hardware then runs the 4 surrounding samples through a
+//! 4-tap "gaussian" FIR filter to get the output sample. That's what `Gaussian` does and it's the
+//! only accurate option, the other methods are there for users who prefer a brighter (or, with
+//! `None`, crunchier) sound.
+//!
+//! In all cases the interpolated position lies between `samples[1]` and `samples[2]`, `samples[0]`
+//! being the oldest sample.
+
+use super::fir;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Number of fractional phase bits
+const PHASE_BITS: u32 = 12;
+
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum InterpolationMethod {
+    /// Hardware 4-tap gaussian filter, using the same coefficients as Mednafen
+    #[default]
+    Gaussian,
+    /// Cubic Lagrange polynomial going through the 4 samples
+    Cubic,
+    /// Cubic Hermite (Catmull-Rom) spline
+    Hermite,
+    /// 4-tap windowed sinc (Lanczos, a = 2)
+    Sinc,
+    /// No interpolation, the previous sample is held until the next one
+    None,
+}
+
+impl fmt::Display for InterpolationMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            InterpolationMethod::Gaussian => "gaussian",
+            InterpolationMethod::Cubic => "cubic",
+            InterpolationMethod::Hermite => "hermite",
+            InterpolationMethod::Sinc => "sinc",
+            InterpolationMethod::None => "none",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub struct InterpolationConfig {
+    /// If true the gaussian filter only uses the top 8 bits of the phase to index the coefficient
+    /// table like the real hardware. Otherwise the coefficients are linearly interpolated using
+    /// the full 12-bit phase, which isn't accurate but slightly reduces aliasing.
+    pub gaussian_8bit_accuracy: bool,
+}
+
+impl Default for InterpolationConfig {
+    fn default() -> InterpolationConfig {
+        InterpolationConfig {
+            gaussian_8bit_accuracy: true,
+        }
+    }
+}
+
+/// Interpolation method override for a specific game
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct GameProfile {
+    /// Disc serial number, for instance "SCUS-94163"
+    pub serial: String,
+    pub method: InterpolationMethod,
+}
+
+impl GameProfile {
+    pub fn new(serial: &str, method: InterpolationMethod) -> GameProfile {
+        GameProfile {
+            serial: normalize_serial(serial),
+            method,
+        }
+    }
+}
+
+/// Keeps track of the interpolation settings and of the per-game overrides
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct InterpolationEngine {
+    /// Method selected by the user
+    method: InterpolationMethod,
+    config: InterpolationConfig,
+    /// Serial number of the running game, if known
+    game_id: Option<String>,
+    /// Per-game profiles, keyed by normalized serial number
+    profiles: HashMap<String, GameProfile>,
+}
+
+impl InterpolationEngine {
+    pub fn new() -> InterpolationEngine {
+        InterpolationEngine::default()
+    }
+
+    pub fn set_method(&mut self, method: InterpolationMethod) {
+        self.method = method;
+    }
+
+    /// Returns the method to use for the current game: the profile's if there's one, otherwise
+    /// the one selected with `set_method`
+    pub fn get_method(&self) -> InterpolationMethod {
+        self.current_profile()
+            .map(|p| p.method)
+            .unwrap_or(self.method)
+    }
+
+    /// Set the serial number of the running game
+    pub fn set_game_id(&mut self, game_id: String) {
+        self.game_id = Some(normalize_serial(&game_id));
+
+        if let Some(p) = self.current_profile() {
+            info!("Using {} SPU interpolation for {}", p.method, p.serial);
+        }
+    }
+
+    pub fn set_config(&mut self, config: InterpolationConfig) {
+        self.config = config;
+    }
+
+    pub fn get_config(&self) -> &InterpolationConfig {
+        &self.config
+    }
+
+    /// Add a game profile, replacing any existing profile for the same serial number
+    pub fn add_game_profile(&mut self, profile: GameProfile) {
+        let profile = GameProfile {
+            serial: normalize_serial(&profile.serial),
+            ..profile
+        };
+
+        self.profiles.insert(profile.serial.clone(), profile);
+    }
+
+    fn current_profile(&self) -> Option<&GameProfile> {
+        self.game_id.as_ref().and_then(|id| self.profiles.get(id))
+    }
+}
+
+/// Serial numbers are found in various formats in the wild ("SLUS_005.94", "slus-00594"...), we
+/// normalize them to the format used by `disc::SerialNumber`: "SLUS-00594"
+fn normalize_serial(serial: &str) -> String {
+    serial
+        .trim()
+        .chars()
+        .filter(|&c| c != '.')
+        .map(|c| match c {
+            '_' => '-',
+            c => c.to_ascii_uppercase(),
+        })
+        .collect()
+}
+
+/// Interpolate between `samples[1]` and `samples[2]`. `phase` is the 12-bit fractional position
+/// between the two.
+pub fn interpolate(
+    method: InterpolationMethod,
+    phase: u16,
+    samples: [i16; 4],
+    gaussian_8bit_accuracy: bool,
+) -> i32 {
+    debug_assert!(phase < (1 << PHASE_BITS));
+
+    match method {
+        InterpolationMethod::Gaussian => {
+            if gaussian_8bit_accuracy {
+                fir::filter((phase >> 4) as u8, samples)
+            } else {
+                fir::filter_precise(phase, samples)
+            }
+        }
+        InterpolationMethod::Cubic => cubic(phase, samples),
+        InterpolationMethod::Hermite => hermite(phase, samples),
+        InterpolationMethod::Sinc => sinc(phase, samples),
+        InterpolationMethod::None => i32::from(samples[1]),
+    }
+}
+
+/// Cubic Lagrange interpolation. The polynomial goes through all 4 samples which means that it can
+/// overshoot, the output is therefore saturated.
+fn cubic(phase: u16, samples: [i16; 4]) -> i32 {
+    let one = 1i64 << PHASE_BITS;
+    let x = i64::from(phase);
+    let [s0, s1, s2, s3] = samples.map(i64::from);
+
+    // Weights for the samples at positions -1, 0, 1 and 2, multiplied by 6 * one^3
+    let w0 = -x * (x - one) * (x - 2 * one);
+    let w1 = 3 * (x + one) * (x - one) * (x - 2 * one);
+    let w2 = -3 * (x + one) * x * (x - 2 * one);
+    let w3 = (x + one) * x * (x - one);
+
+    let r = w0 * s0 + w1 * s1 + w2 * s2 + w3 * s3;
+
+    saturate(r.div_euclid(6 << (3 * PHASE_BITS)))
+}
+
+/// Catmull-Rom spline: cubic Hermite interpolation between `samples[1]` and `samples[2]` with the
+/// tangents estimated from the neighbouring samples
+fn hermite(phase: u16, samples: [i16; 4]) -> i32 {
+    let one = 1i64 << PHASE_BITS;
+    let x = i64::from(phase);
+    let [s0, s1, s2, s3] = samples.map(i64::from);
+
+    let a = -s0 + 3 * s1 - 3 * s2 + s3;
+    let b = 2 * s0 - 5 * s1 + 4 * s2 - s3;
+    let c = s2 - s0;
+    let d = 2 * s1;
+
+    // Horner's method, every step is multiplied by `one`
+    let r = ((a * x + b * one) * x + c * one * one) * x + d * one * one * one;
+
+    saturate(r >> (3 * PHASE_BITS + 1))
+}
+
+/// Lanczos (a = 2) windowed sinc interpolation
+fn sinc(phase: u16, samples: [i16; 4]) -> i32 {
+    static COEFFS: OnceLock<Vec<[i32; 4]>> = OnceLock::new();
+
+    let coeffs = COEFFS.get_or_init(lanczos_table);
+    let c = coeffs[usize::from(phase)];
+
+    let r: i32 = (0..4).map(|i| c[i] * i32::from(samples[i])).sum();
+
+    saturate(i64::from(r >> 15))
+}
+
+/// Build the Lanczos coefficient table, one entry per phase. Each entry is normalized so that the
+/// 4 coefficients sum to exactly 0x8000, otherwise a constant signal would come out slightly
+/// attenuated or amplified.
+fn lanczos_table() -> Vec<[i32; 4]> {
+    use std::f64::consts::PI;
+
+    fn lanczos(x: f64) -> f64 {
+        if x == 0. {
+            1.
+        } else if x.abs() >= 2. {
+            0.
+        } else {
+            let px = PI * x;
+
+            2. * px.sin() * (px / 2.).sin() / (px * px)
+        }
+    }
+
+    let one = (1 << PHASE_BITS) as f64;
+
+    (0..1u32 << PHASE_BITS)
+        .map(|phase| {
+            let x = phase as f64 / one;
+            let w = [x + 1., x, x - 1., x - 2.].map(lanczos);
+            let sum: f64 = w.iter().sum();
+
+            let mut c = w.map(|w| (w / sum * 32768.).round() as i32);
+
+            // Put any rounding error on the biggest tap
+            let err = 0x8000 - c.iter().sum::<i32>();
+            let big = if phase < (1 << (PHASE_BITS - 1)) {
+                1
+            } else {
+                2
+            };
+            c[big] += err;
+
+            c
+        })
+        .collect()
+}
+
+fn saturate(v: i64) -> i32 {
+    v.clamp(i64::from(i16::MIN), i64::from(i16::MAX)) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::AdpcmHeader;
+    use super::*;
+    use crate::golden;
+
+    /// Pitches used to record the gaussian traces: slow, native (0x1000), odd and maximum
+    const TRACE_PITCHES: [u16; 4] = [0x0400, 0x1000, 0x1234, 0x3fff];
+
+    /// Number of output samples recorded for each pitch
+    const TRACE_LEN: usize = 1024;
+
+    /// Deterministic test signal: a mix of a slow triangle wave, a fast square wave and
+    /// pseudo-random noise, with a few full scale steps to exercise the extremes of the filter
+    fn test_signal() -> Vec<i16> {
+        let mut lfsr = 0xace1u16;
+
+        (0..0x4000)
+            .map(|i: i32| {
+                lfsr = (lfsr >> 1) ^ (0u16.wrapping_sub(lfsr & 1) & 0xb400);
+
+                let triangle = ((i & 0xff) - 0x80).abs() * 150 - 9600;
+                let square = if i & 4 == 0 { 5000 } else { -5000 };
+                let noise = i32::from(lfsr as i16) >> 3;
+
+                match (i / 256) % 8 {
+                    3 => i16::MAX,
+                    5 => i16::MIN,
+                    _ => (triangle + square + noise) as i16,
+                }
+            })
+            .collect()
+    }
+
+    /// Run the test signal through `method` at all the trace pitches, stepping through the samples
+    /// the same way the voices do
+    fn run_trace(method: InterpolationMethod, gaussian_8bit_accuracy: bool) -> Vec<i32> {
+        let signal = test_signal();
+        let mut out = Vec::new();
+
+        for &pitch in &TRACE_PITCHES {
+            let mut pos = 0;
+            let mut phase = 0u16;
+
+            for _ in 0..TRACE_LEN {
+                let samples = [
+                    signal[pos],
+                    signal[pos + 1],
+                    signal[pos + 2],
+                    signal[pos + 3],
+                ];
+
+                out.push(interpolate(method, phase, samples, gaussian_8bit_accuracy));
+
+                let step = phase + pitch;
+                phase = step & 0xfff;
+                pos += usize::from(step >> 12);
+            }
+        }
+
+        out
+    }
+
+    /// Regression test for the hardware filter: the trace was recorded from this implementation
+    /// when it was introduced (the baseline had no gaussian filter to compare against), any
+    /// change to the gaussian output shows up here
+    #[test]
+    fn gaussian_matches_recorded_trace() {
+        let out = run_trace(InterpolationMethod::Gaussian, true);
+        let bytes: Vec<u8> = out.iter().flat_map(|s| s.to_le_bytes()).collect();
+
+        golden::check(
+            &golden::path("src/psx/spu/traces/gaussian.trace"),
+            &bytes,
+            4,
+        );
+    }
+
+    /// `Gaussian` with 8-bit accuracy must be exactly the FIR filter the voices used before the
+    /// interpolation method became selectable: feed both the same decoded ADPCM history while
+    /// sweeping the pitch
+    #[test]
+    fn gaussian_matches_baseline_fir() {
+        let mut lfsr = 0xace1u16;
+        let mut last_samples = [0; 2];
+        let mut history = Vec::new();
+
+        // Go through all the filters and shifts, including the invalid ones
+        for block in 0..160u16 {
+            let header = AdpcmHeader(((block % 5) << 4) | (block % 16));
+
+            for _ in 0..7 {
+                lfsr = (lfsr >> 1) ^ (0u16.wrapping_sub(lfsr & 1) & 0xb400);
+
+                history.extend_from_slice(&header.decode(lfsr, &mut last_samples));
+            }
+        }
+
+        let mut pos = 0;
+        let mut phase = 0u16;
+        let mut pitch = 0u16;
+
+        while pos + 3 < history.len() {
+            let samples = [
+                history[pos],
+                history[pos + 1],
+                history[pos + 2],
+                history[pos + 3],
+            ];
+
+            assert_eq!(
+                interpolate(InterpolationMethod::Gaussian, phase, samples, true),
+                fir::filter((phase >> 4) as u8, samples),
+                "pitch {:x} phase {:x}",
+                pitch,
+                phase
+            );
+
+            let step = phase + pitch;
+            phase = step & 0xfff;
+            pos += usize::from(step >> 12);
+            pitch = (pitch + 0x13) & 0x3fff;
+        }
+    }
+
+    #[test]
+    fn gaussian_is_default() {
+        let engine = InterpolationEngine::new();
+
+        assert_eq!(engine.get_method(), InterpolationMethod::Gaussian);
+        assert!(engine.get_config().gaussian_8bit_accuracy);
+    }
+
+    #[test]
+    fn gaussian_precise() {
+        // With a phase multiple of 16 the precise gaussian is the same as the hardware one
+        for phase in (0..0x1000).step_by(16) {
+            let s = [1000, -2000, 3000, -4000];
+
+            assert_eq!(
+                interpolate(InterpolationMethod::Gaussian, phase, s, true),
+                interpolate(InterpolationMethod::Gaussian, phase, s, false)
+            );
+        }
+
+        // Otherwise it should be between the two neighbouring phases
+        let s = [0, 0x4000, -0x4000, 0];
+        for phase in 0..0xff0u16 {
+            let a = interpolate(InterpolationMethod::Gaussian, phase & !0xf, s, true);
+            let b = interpolate(InterpolationMethod::Gaussian, (phase & !0xf) + 16, s, true);
+            let p = interpolate(InterpolationMethod::Gaussian, phase, s, false);
+
+            assert!(p >= a.min(b) - 1 && p <= a.max(b) + 1, "{:x}", phase);
+        }
+    }
+
+    #[test]
+    fn exact_at_sample_points() {
+        let s = [-1234, 5678, -9012, 3456];
+
+        for method in [
+            InterpolationMethod::Cubic,
+            InterpolationMethod::Hermite,
+            InterpolationMethod::Sinc,
+            InterpolationMethod::None,
+        ] {
+            assert_eq!(interpolate(method, 0, s, true), 5678, "{}", method);
+        }
+    }
+
+    #[test]
+    fn constant_signal() {
+        for method in [
+            InterpolationMethod::Cubic,
+            InterpolationMethod::Hermite,
+            InterpolationMethod::Sinc,
+            InterpolationMethod::None,
+        ] {
+            for phase in 0..0x1000 {
+                let v = interpolate(method, phase, [0x1234; 4], true);
+
+                assert!((v - 0x1234).abs() <= 1, "{} {:x}: {}", method, phase, v);
+            }
+        }
+    }
+
+    #[test]
+    fn linear_signal() {
+        // Both cubic methods reproduce a straight line exactly
+        let s = [-3000, -1000, 1000, 3000];
+
+        for method in [InterpolationMethod::Cubic, InterpolationMethod::Hermite] {
+            for phase in 0..0x1000 {
+                let expected = -1000 + (2000 * i32::from(phase)) / 0x1000;
+                let v = interpolate(method, phase, s, true);
+
+                assert!((v - expected).abs() <= 1, "{} {:x}: {}", method, phase, v);
+            }
+        }
+    }
+
+    #[test]
+    fn cubic_saturates() {
+        let s = [i16::MIN, i16::MAX, i16::MAX, i16::MIN];
+
+        for method in [InterpolationMethod::Cubic, InterpolationMethod::Hermite] {
+            assert_eq!(interpolate(method, 0x800, s, true), i32::from(i16::MAX));
+        }
+    }
+
+    #[test]
+    fn game_profiles() {
+        let mut engine = InterpolationEngine::new();
+
+        engine.set_method(InterpolationMethod::Hermite);
+        engine.add_game_profile(GameProfile::new("SLUS_005.94", InterpolationMethod::Sinc));
+
+        // No game running yet
+        assert_eq!(engine.get_method(), InterpolationMethod::Hermite);
+
+        engine.set_game_id("slus-00594".to_string());
+        assert_eq!(engine.get_method(), InterpolationMethod::Sinc);
+
+        engine.set_game_id("SCUS-94163".to_string());
+        assert_eq!(engine.get_method(), InterpolationMethod::Hermite);
+    }
+}
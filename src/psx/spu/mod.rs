@@ -2,15 +2,19 @@
 //!
 //! Most of the code is based on Mednafen's implementation
 
+pub mod capture;
 mod fifo;
 mod fir;
 pub mod interpolation;
+mod reverb;
 mod reverb_resampler;
+pub mod snapshot;
 pub mod spatial_audio;
 
 use super::{cd, cpu, irq, sync, AccessWidth, Addressable, CycleCount, Psx};
+use capture::{SpuCapture, Track, VoiceMask};
 use fifo::DecoderFifo;
-use reverb_resampler::ReverbResampler;
+use reverb::{Reverb, ReverbMemory};
 use std::ops::{Index, IndexMut};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use log::warn;
@@ -215,6 +219,7 @@ impl AudioRingBuffer {
             total_written: self.total_written,
             total_dropped: self.total_dropped,
             current_fill: self.current_fill,
+            target_latency: self.target_latency,
             max_fill_level: self.max_fill_level,
             buffer_size: self.size,
             drop_rate: if self.total_written > 0 {
@@ -242,6 +247,9 @@ pub struct AudioBufferStats {
     pub total_written: u64,
     pub total_dropped: u64,
     pub current_fill: usize,
+    /// Fill level the buffer should ideally be kept at
+    #[serde(default)]
+    pub target_latency: usize,
     pub max_fill_level: usize,
     pub buffer_size: usize,
     pub drop_rate: f32,
@@ -322,6 +330,7 @@ impl SpuDebugOverlay {
                 total_written: 0,
                 total_dropped: 0,
                 current_fill: 0,
+                target_latency: 0,
                 max_fill_level: 0,
                 buffer_size: 8192,
                 drop_rate: 0.0,
@@ -415,28 +424,13 @@ pub struct Spu {
     /// Start address of the working memory for the reverb
     #[serde(default)]
     reverb_start: RamIndex,
-    /// Current index in the working memory for the reverb
-    #[serde(default)]
-    reverb_index: RamIndex,
-    /// Which stereo side should we run reverb on next
-    #[serde(default)]
-    reverb_run_right: bool,
-    /// Reverb input sample downsampler, left
-    #[serde(default)]
-    reverb_downsampler_left: ReverbResampler,
-    /// Reverb input sample downsampler, right
-    #[serde(default)]
-    reverb_downsampler_right: ReverbResampler,
-    /// Reverb output sample upsampler, left
-    #[serde(default)]
-    reverb_upsampler_left: ReverbResampler,
-    /// Reverb outpu sample upsampler, right
-    #[serde(default)]
-    reverb_upsampler_right: ReverbResampler,
+    /// Reverberation unit
+    #[serde(flatten)]
+    reverb: Reverb,
     /// Used to override the emulation and force reverb off
     #[serde(default)]
     reverb_enable_override: bool,
-    /// Enhanced reverb configuration for better accuracy
+    /// If true use the high resolution reverb implementation instead of the accurate one
     #[serde(default)]
     reverb_enhanced_mode: bool,
     /// Debug overlay data for SPU state visualization
@@ -445,6 +439,12 @@ pub struct Spu {
     /// Advanced interpolation engine
     #[serde(default)]
     interpolation_engine: interpolation::InterpolationEngine,
+    /// User mute/solo controls for the individual voices
+    #[serde(skip)]
+    voice_mask: VoiceMask,
+    /// Multitrack capture, if enabled
+    #[serde(skip)]
+    capture: Option<SpuCapture>,
 }
 
 impl Spu {
@@ -500,16 +500,13 @@ impl Spu {
             reverb_out_volume_left: 0,
             reverb_out_volume_right: 0,
             reverb_start: 0,
-            reverb_index: 0,
-            reverb_run_right: false,
-            reverb_downsampler_left: ReverbResampler::new(),
-            reverb_downsampler_right: ReverbResampler::new(),
-            reverb_upsampler_left: ReverbResampler::new(),
-            reverb_upsampler_right: ReverbResampler::new(),
+            reverb: Reverb::new(),
             reverb_enable_override: false,  // Enable reverb by default for better audio
-            reverb_enhanced_mode: true,
+            reverb_enhanced_mode: false,
             debug_overlay: None,
             interpolation_engine: interpolation::InterpolationEngine::new(),
+            voice_mask: VoiceMask::default(),
+            capture: None,
         }
     }
 
@@ -517,9 +514,13 @@ impl Spu {
         self.reverb_enable_override = en
     }
 
-    /// Enable enhanced reverb mode for better accuracy
+    /// Select the high resolution reverb implementation instead of the accurate one
     pub fn set_reverb_enhanced(&mut self, enhanced: bool) {
         self.reverb_enhanced_mode = enhanced;
+
+        if !enhanced {
+            self.reverb.clear_enhanced();
+        }
     }
 
     /// Enable SPU debug overlay
@@ -561,6 +562,35 @@ impl Spu {
         self.interpolation_engine.add_game_profile(profile);
     }
 
+    /// Mute or unmute `voice` in the output mix. This doesn't affect the emulation.
+    pub fn set_voice_muted(&mut self, voice: u8, muted: bool) {
+        self.voice_mask.set_muted(voice, muted);
+    }
+
+    /// Solo or unsolo `voice`: if any voice is soloed only the soloed voices are mixed
+    pub fn set_voice_soloed(&mut self, voice: u8, soloed: bool) {
+        self.voice_mask.set_soloed(voice, soloed);
+    }
+
+    pub fn voice_mask(&self) -> VoiceMask {
+        self.voice_mask
+    }
+
+    /// Start a new multitrack capture, discarding any capture in progress
+    pub fn start_capture(&mut self) {
+        self.capture = Some(SpuCapture::new());
+    }
+
+    /// Stop the capture in progress and return the captured tracks
+    pub fn stop_capture(&mut self) -> Option<SpuCapture> {
+        self.capture.take()
+    }
+
+    /// Returns the capture in progress, if any
+    pub fn capture(&self) -> Option<&SpuCapture> {
+        self.capture.as_ref()
+    }
+
     /// Get current debug overlay data if enabled
     pub fn get_debug_overlay(&self) -> Option<&SpuDebugOverlay> {
         self.debug_overlay.as_ref()
@@ -714,6 +744,11 @@ pub fn get_samples(psx: &mut Psx) -> Vec<i16> {
     psx.spu.audio_ring_buffer.pop_stereo(available)
 }
 
+/// Pop at most `frames` stereo frames from the sample buffer, leaving the rest for later
+pub fn pop_samples(psx: &mut Psx, frames: usize) -> Vec<i16> {
+    psx.spu.audio_ring_buffer.pop_stereo(frames)
+}
+
 /// Clear the sample buffer
 pub fn clear_samples(psx: &mut Psx) {
     // Reset the ring buffer statistics
@@ -797,16 +832,6 @@ pub fn update_debug_overlay(psx: &mut Psx) {
 
 /// Put the provided stereo pair in the output buffer with overflow protection
 fn output_samples(psx: &mut Psx, left: i16, right: i16) {
-    // Apply any final processing in enhanced mode
-    let (left, right) = if psx.spu.reverb_enhanced_mode {
-        // Apply subtle compression for better dynamic range
-        let left_compressed = apply_soft_compression(left);
-        let right_compressed = apply_soft_compression(right);
-        (left_compressed, right_compressed)
-    } else {
-        (left, right)
-    };
-
     // Push to ring buffer with automatic overflow handling
     let success = psx.spu.audio_ring_buffer.push_stereo(left, right);
     
@@ -825,20 +850,6 @@ fn output_samples(psx: &mut Psx, left: i16, right: i16) {
     }
 }
 
-/// Apply soft compression to improve dynamic range
-fn apply_soft_compression(sample: i16) -> i16 {
-    let s = i32::from(sample);
-    let threshold = 24576; // 75% of max
-    if s.abs() > threshold {
-        let excess = s.abs() - threshold;
-        let compressed = threshold + (excess >> 1); // Gentle 2:1 compression
-        let sign = if s < 0 { -1 } else { 1 };
-        saturate_to_i16(sign * compressed)
-    } else {
-        sample
-    }
-}
-
 /// Emulate one cycle of the SPU
 fn run_cycle(psx: &mut Psx) {
     psx.spu.update_status();
@@ -858,14 +869,18 @@ fn run_cycle(psx: &mut Psx) {
     for voice in 0..24 {
         let (left, right) = run_voice_cycle(psx, voice, &mut sweep_factor);
 
-        left_mix += left;
-        right_mix += right;
-
-        if psx.spu.is_voice_reverberated(voice) {
-            left_reverb += left;
-            right_reverb += right;
+        if let Some(capture) = &mut psx.spu.capture {
+            capture.push(Track::Voice(voice), left, right);
         }
 
+        let reverberated = psx.spu.is_voice_reverberated(voice);
+        let (dry, wet) = mix_voice(psx.spu.voice_mask, voice, reverberated, (left, right));
+
+        left_mix += dry.0;
+        right_mix += dry.1;
+        left_reverb += wet.0;
+        right_reverb += wet.1;
+
         // Update debug overlay if enabled
         if let Some(ref mut overlay) = psx.spu.debug_overlay {
             let voice_active = psx.spu[voice].level() != 0;
@@ -913,7 +928,7 @@ fn run_cycle(psx: &mut Psx) {
         overlay.cd_audio = (cd_left, cd_right);
     }
 
-    if psx.spu.cd_audio_enabled() {
+    let (cd_left, cd_right) = if psx.spu.cd_audio_enabled() {
         let cd_left = (i32::from(cd_left) * i32::from(psx.spu.cd_volume_left)) >> 15;
         let cd_right = (i32::from(cd_right) * i32::from(psx.spu.cd_volume_right)) >> 15;
 
@@ -924,18 +939,26 @@ fn run_cycle(psx: &mut Psx) {
             left_reverb += cd_left;
             right_reverb += cd_right;
         }
+
+        (cd_left, cd_right)
+    } else {
+        (0, 0)
+    };
+
+    if let Some(capture) = &mut psx.spu.capture {
+        capture.push(Track::CdAudio, cd_left, cd_right);
     }
 
     // Reverb
     {
-        let reverb_samples = (saturate_to_i16(left_reverb), saturate_to_i16(right_reverb));
+        let (reverb_left, reverb_right) = run_reverb_cycle(psx, (left_reverb, right_reverb));
 
-        let (reverb_left, reverb_right) = run_reverb_cycle(psx, reverb_samples);
+        let reverb_left = (reverb_left * i32::from(psx.spu.reverb_out_volume_left)) >> 15;
+        let reverb_right = (reverb_right * i32::from(psx.spu.reverb_out_volume_right)) >> 15;
 
-        let reverb_left =
-            (i32::from(reverb_left) * i32::from(psx.spu.reverb_out_volume_left)) >> 15;
-        let reverb_right =
-            (i32::from(reverb_right) * i32::from(psx.spu.reverb_out_volume_right)) >> 15;
+        if let Some(capture) = &mut psx.spu.capture {
+            capture.push(Track::Reverb, reverb_left, reverb_right);
+        }
 
         left_mix += reverb_left;
         right_mix += reverb_right;
@@ -972,317 +995,88 @@ fn run_cycle(psx: &mut Psx) {
     output_samples(psx, final_left, final_right);
 }
 
-fn reverb_sample_index(psx: &mut Psx, addr: u16, neg_offset: u32) -> RamIndex {
-    let idx = psx.spu.reverb_index + to_ram_index(addr) - neg_offset;
+/// Returns the contribution of `sample`, the output of `voice`, to the dry mix and to the reverb
+/// input. `mask` only applies to the dry mix: the reverb writes to SPU RAM so it must see every
+/// voice.
+fn mix_voice(
+    mask: VoiceMask,
+    voice: u8,
+    reverberated: bool,
+    sample: (i32, i32),
+) -> ((i32, i32), (i32, i32)) {
+    let dry = if mask.is_audible(voice) { sample } else { (0, 0) };
+    let wet = if reverberated { sample } else { (0, 0) };
 
-    if idx <= 0x3_ffff {
-        idx
-    } else {
-        // Overflow, wrap around to the start of the reverb working area
-        psx.spu.reverb_start.wrapping_add(idx) & 0x3_ffff
-    }
+    (dry, wet)
 }
 
-fn store_reverb_sample(psx: &mut Psx, addr: u16, v: i16) {
-    let idx = reverb_sample_index(psx, addr, 0);
+/// Advance the reverb by one 44.1kHz cycle
+fn run_reverb_cycle(psx: &mut Psx, (left_in, right_in): (i32, i32)) -> (i32, i32) {
+    let spu = &mut psx.spu;
+    let enabled = spu.reverb_enabled() && spu.reverb_enable_override;
 
-    ram_write(psx, idx, v as u16)
-}
+    if spu.reverb_enhanced_mode {
+        return spu
+            .reverb
+            .run_enhanced(&spu.regs, spu.reverb_start, enabled, (left_in, right_in));
+    }
 
-fn load_reverb_sample(psx: &mut Psx, addr: u16) -> i16 {
-    let idx = reverb_sample_index(psx, addr, 0);
+    let irq_enabled = spu.irq_enabled();
 
-    ram_read(psx, idx) as i16
-}
+    let mut mem = SpuReverbMemory {
+        ram: &mut spu.ram,
+        irq_enabled,
+        irq_addr: spu.irq_addr,
+        irq_hit: false,
+    };
 
-fn load_reverb_sample_before(psx: &mut Psx, addr: u16) -> i16 {
-    let idx = reverb_sample_index(psx, addr, 1);
+    let (left, right) = spu.reverb.run_accurate(
+        &mut mem,
+        &spu.regs,
+        spu.reverb_start,
+        enabled,
+        (saturate_to_i16(left_in), saturate_to_i16(right_in)),
+    );
+
+    if mem.irq_hit {
+        psx.spu.irq = true;
+        irq::set_high(psx, irq::Interrupt::Spu);
+    }
 
-    ram_read(psx, idx) as i16
+    (i32::from(left), i32::from(right))
 }
 
-/// Advance the reverb state machine. Should be called at 44.1kHz with the new reverb samples.
-fn run_reverb_cycle(psx: &mut Psx, (left_in, right_in): (i16, i16)) -> (i16, i16) {
-    // Reverb downsamples from 44.1Khz to 22.05kHz using a simple FIR filter
-    psx.spu.reverb_downsampler_left.push_sample(left_in);
-    psx.spu.reverb_downsampler_right.push_sample(right_in);
+/// Gives the reverb access to SPU RAM. We can't borrow the whole `Psx` while the reverb state is
+/// borrowed so interrupts are only flagged here and raised once the reverb cycle is over.
+struct SpuReverbMemory<'a> {
+    ram: &'a mut [u16; SPU_RAM_SIZE],
+    irq_enabled: bool,
+    irq_addr: RamIndex,
+    irq_hit: bool,
+}
 
-    fn iir_mul(a: i16, b: i16) -> i32 {
-        // Enhanced IIR multiplication for better accuracy
-        (if a > i16::MIN {
-            (32768 - i32::from(a)) * i32::from(b)
-        } else if b > i16::MIN {
-            i32::from(b) * 32768
-        } else {
-            0
-        }) >> 14
-    }
-
-    // Enhanced reverb coefficient calculation for better quality
-    fn apply_enhanced_reverb_coeff(sample: i32, coeff: i16, enhanced: bool) -> i32 {
-        if enhanced {
-            // Use higher precision calculation in enhanced mode
-            let result = (sample * i32::from(coeff)) >> 14;
-            // Apply subtle saturation for warmer sound
-            if result > 30000 {
-                30000 + ((result - 30000) >> 2)
-            } else if result < -30000 {
-                -30000 + ((result + 30000) >> 2)
-            } else {
-                result
-            }
-        } else {
-            (sample * i32::from(coeff)) >> 15
+impl SpuReverbMemory<'_> {
+    fn check_for_irq(&mut self, index: RamIndex) {
+        if self.irq_enabled && index == self.irq_addr {
+            self.irq_hit = true;
         }
     }
+}
 
-    if psx.spu.reverb_enabled() && psx.spu.reverb_enable_override {
-        if psx.spu.reverb_run_right {
-            // IIR processing
-            let sample = i32::from(psx.spu.reverb_downsampler_right.resample());
-
-            let in_mix =
-                (sample * i32::from(psx.spu.regs[regmap::REVERB_INPUT_VOLUME_RIGHT] as i16)) >> 15;
-
-            let reflect_vol = psx.spu.regs[regmap::REVERB_REFLECT_VOLUME2] as i16;
-            let enhanced = psx.spu.reverb_enhanced_mode;
-
-            let same_side_sample = i32::from(load_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_REFLECT_SAME_RIGHT2],
-            ));
-            let same_side_mix = apply_enhanced_reverb_coeff(same_side_sample, reflect_vol, enhanced);
-
-            let diff_side_sample = i32::from(load_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_REFLECT_DIFF_RIGHT2],
-            ));
-            let diff_side_mix = apply_enhanced_reverb_coeff(diff_side_sample, reflect_vol, enhanced);
-
-            let input_same = saturate_to_i16(same_side_mix + in_mix);
-            let input_diff = saturate_to_i16(diff_side_mix + in_mix);
-
-            let reflect_iir_vol = psx.spu.regs[regmap::REVERB_REFLECT_VOLUME1] as i16;
-            let input_same_alpha = (i32::from(input_same) * i32::from(reflect_iir_vol)) >> 14;
-            let input_diff_alpha = (i32::from(input_diff) * i32::from(reflect_iir_vol)) >> 14;
-
-            let iir_same = saturate_to_i16(
-                (input_same_alpha
-                    + iir_mul(
-                        reflect_iir_vol,
-                        load_reverb_sample_before(
-                            psx,
-                            psx.spu.regs[regmap::REVERB_REFLECT_SAME_RIGHT1],
-                        ),
-                    ))
-                    >> 1,
-            );
-            let iir_diff = saturate_to_i16(
-                (input_diff_alpha
-                    + iir_mul(
-                        reflect_iir_vol,
-                        load_reverb_sample_before(
-                            psx,
-                            psx.spu.regs[regmap::REVERB_REFLECT_DIFF_RIGHT1],
-                        ),
-                    ))
-                    >> 1,
-            );
-
-            store_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_REFLECT_SAME_RIGHT1],
-                iir_same,
-            );
-            store_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_REFLECT_DIFF_RIGHT1],
-                iir_diff,
-            );
-
-            let early_echo = saturate_to_i16(
-                (((i32::from(load_reverb_sample(
-                    psx,
-                    psx.spu.regs[regmap::REVERB_COMB_RIGHT1],
-                )) * i32::from(psx.spu.regs[regmap::REVERB_COMB_VOLUME1] as i16))
-                    >> 14)
-                    + ((i32::from(load_reverb_sample(
-                        psx,
-                        psx.spu.regs[regmap::REVERB_COMB_RIGHT2],
-                    )) * i32::from(psx.spu.regs[regmap::REVERB_COMB_VOLUME2] as i16))
-                        >> 14)
-                    + ((i32::from(load_reverb_sample(
-                        psx,
-                        psx.spu.regs[regmap::REVERB_COMB_RIGHT3],
-                    )) * i32::from(psx.spu.regs[regmap::REVERB_COMB_VOLUME3] as i16))
-                        >> 14)
-                    + ((i32::from(load_reverb_sample(
-                        psx,
-                        psx.spu.regs[regmap::REVERB_COMB_RIGHT4],
-                    )) * i32::from(psx.spu.regs[regmap::REVERB_COMB_VOLUME4] as i16))
-                        >> 14))
-                    >> 1,
-            );
-
-            let apf_in1 = i32::from(load_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_APF_RIGHT1]
-                    .wrapping_add(psx.spu.regs[regmap::REVERB_APF_OFFSET1]),
-            ));
-            let apf_in2 = i32::from(load_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_APF_RIGHT2]
-                    .wrapping_add(psx.spu.regs[regmap::REVERB_APF_OFFSET2]),
-            ));
-
-            let apf_vol1 = i32::from(psx.spu.regs[regmap::REVERB_APF_VOLUME1] as i16);
-            let apf_vol2 = i32::from(psx.spu.regs[regmap::REVERB_APF_VOLUME2] as i16);
-
-            let out_1 = saturate_to_i16(i32::from(early_echo) - ((apf_in1 * apf_vol1) >> 15));
-            let out_2 = saturate_to_i16(
-                ((i32::from(early_echo) * apf_vol1) >> 15)
-                    - ((apf_in1 * -apf_vol1) >> 15)
-                    - ((apf_in2 * apf_vol2) >> 15),
-            );
-
-            store_reverb_sample(psx, psx.spu.regs[regmap::REVERB_APF_RIGHT1], out_1);
-            store_reverb_sample(psx, psx.spu.regs[regmap::REVERB_APF_RIGHT2], out_2);
-
-            psx.spu.reverb_upsampler_left.push_sample(0);
-            psx.spu
-                .reverb_upsampler_right
-                .push_sample(saturate_to_i16((i32::from(out_1) + i32::from(out_2)) >> 1));
-        } else {
-            // IIR processing
-            let sample = i32::from(psx.spu.reverb_downsampler_left.resample());
-
-            let in_mix =
-                (sample * i32::from(psx.spu.regs[regmap::REVERB_INPUT_VOLUME_LEFT] as i16)) >> 15;
-
-            let reflect_vol = psx.spu.regs[regmap::REVERB_REFLECT_VOLUME2] as i16;
-            let enhanced = psx.spu.reverb_enhanced_mode;
-
-            let same_side_sample = i32::from(load_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_REFLECT_SAME_LEFT2],
-            ));
-            let same_side_mix = apply_enhanced_reverb_coeff(same_side_sample, reflect_vol, enhanced);
-
-            let diff_side_sample = i32::from(load_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_REFLECT_DIFF_LEFT2],
-            ));
-            let diff_side_mix = apply_enhanced_reverb_coeff(diff_side_sample, reflect_vol, enhanced);
-
-            let input_same = saturate_to_i16(same_side_mix + in_mix);
-            let input_diff = saturate_to_i16(diff_side_mix + in_mix);
-
-            let reflect_iir_vol = psx.spu.regs[regmap::REVERB_REFLECT_VOLUME1] as i16;
-            let input_same_alpha = (i32::from(input_same) * i32::from(reflect_iir_vol)) >> 14;
-            let input_diff_alpha = (i32::from(input_diff) * i32::from(reflect_iir_vol)) >> 14;
-
-            let iir_same = saturate_to_i16(
-                (input_same_alpha
-                    + iir_mul(
-                        reflect_iir_vol,
-                        load_reverb_sample_before(
-                            psx,
-                            psx.spu.regs[regmap::REVERB_REFLECT_SAME_LEFT1],
-                        ),
-                    ))
-                    >> 1,
-            );
-            let iir_diff = saturate_to_i16(
-                (input_diff_alpha
-                    + iir_mul(
-                        reflect_iir_vol,
-                        load_reverb_sample_before(
-                            psx,
-                            psx.spu.regs[regmap::REVERB_REFLECT_DIFF_LEFT1],
-                        ),
-                    ))
-                    >> 1,
-            );
-
-            store_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_REFLECT_SAME_LEFT1],
-                iir_same,
-            );
-            store_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_REFLECT_DIFF_LEFT1],
-                iir_diff,
-            );
-
-            let early_echo = saturate_to_i16(
-                (((i32::from(load_reverb_sample(
-                    psx,
-                    psx.spu.regs[regmap::REVERB_COMB_LEFT1],
-                )) * i32::from(psx.spu.regs[regmap::REVERB_COMB_VOLUME1] as i16))
-                    >> 14)
-                    + ((i32::from(load_reverb_sample(
-                        psx,
-                        psx.spu.regs[regmap::REVERB_COMB_LEFT2],
-                    )) * i32::from(psx.spu.regs[regmap::REVERB_COMB_VOLUME2] as i16))
-                        >> 14)
-                    + ((i32::from(load_reverb_sample(
-                        psx,
-                        psx.spu.regs[regmap::REVERB_COMB_LEFT3],
-                    )) * i32::from(psx.spu.regs[regmap::REVERB_COMB_VOLUME3] as i16))
-                        >> 14)
-                    + ((i32::from(load_reverb_sample(
-                        psx,
-                        psx.spu.regs[regmap::REVERB_COMB_LEFT4],
-                    )) * i32::from(psx.spu.regs[regmap::REVERB_COMB_VOLUME4] as i16))
-                        >> 14))
-                    >> 1,
-            );
-
-            // All-pass filter
-            let apf_in1 = i32::from(load_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_APF_LEFT1]
-                    .wrapping_add(psx.spu.regs[regmap::REVERB_APF_OFFSET1]),
-            ));
-            let apf_in2 = i32::from(load_reverb_sample(
-                psx,
-                psx.spu.regs[regmap::REVERB_APF_LEFT2]
-                    .wrapping_add(psx.spu.regs[regmap::REVERB_APF_OFFSET2]),
-            ));
-
-            let apf_vol1 = i32::from(psx.spu.regs[regmap::REVERB_APF_VOLUME1] as i16);
-            let apf_vol2 = i32::from(psx.spu.regs[regmap::REVERB_APF_VOLUME2] as i16);
-
-            let out_1 = saturate_to_i16(i32::from(early_echo) - ((apf_in1 * apf_vol1) >> 15));
-            let out_2 = saturate_to_i16(
-                ((i32::from(early_echo) * apf_vol1) >> 15)
-                    - ((apf_in1 * -apf_vol1) >> 15)
-                    - ((apf_in2 * apf_vol2) >> 15),
-            );
-
-            store_reverb_sample(psx, psx.spu.regs[regmap::REVERB_APF_LEFT1], out_1);
-            store_reverb_sample(psx, psx.spu.regs[regmap::REVERB_APF_LEFT2], out_2);
-
-            psx.spu
-                .reverb_upsampler_left
-                .push_sample(saturate_to_i16((i32::from(out_1) + i32::from(out_2)) >> 1));
-            psx.spu.reverb_upsampler_right.push_sample(0);
-        }
-    }
-
-    if psx.spu.reverb_run_right {
-        psx.spu.reverb_index = psx.spu.reverb_index.wrapping_add(1);
-        if psx.spu.reverb_index > 0x3_ffff {
-            psx.spu.reverb_index = psx.spu.reverb_start;
-        }
-    }
-    psx.spu.reverb_run_right = !psx.spu.reverb_run_right;
-
-    let reverb_left = psx.spu.reverb_upsampler_left.resample();
-    let reverb_right = psx.spu.reverb_upsampler_right.resample();
-
-    (reverb_left, reverb_right)
+impl ReverbMemory for SpuReverbMemory<'_> {
+    fn load(&mut self, index: RamIndex) -> i16 {
+        self.check_for_irq(index);
+
+        self.ram.get(index as usize).map_or(0, |&v| v as i16)
+    }
+
+    fn store(&mut self, index: RamIndex, v: i16) {
+        self.check_for_irq(index);
+
+        if let Some(r) = self.ram.get_mut(index as usize) {
+            *r = v as u16;
+        }
+    }
 }
 
 /// Run `voice` for one cycle and return a pair of stereo samples
@@ -1559,10 +1353,10 @@ fn store16(psx: &mut Psx, off: u32, val: u16) {
                     warn!("SPU: Invalid reverb base address 0x{:05x}, clamping to RAM size", idx);
                     let safe_idx = idx & 0x3_ffff;
                     psx.spu.reverb_start = safe_idx;
-                    psx.spu.reverb_index = safe_idx;
+                    psx.spu.reverb.set_start(safe_idx);
                 } else {
                     psx.spu.reverb_start = idx;
-                    psx.spu.reverb_index = idx;
+                    psx.spu.reverb.set_start(idx);
                 }
             }
             regmap::IRQ_ADDRESS => {
@@ -1881,52 +1675,21 @@ impl Voice {
     }
 
     /// Decode 4 samples from an ADPCM block
-    fn decode(&mut self, mut encoded: u16) {
-        let (wp, wn) = self.block_header.weights();
-        let mut shift = self.block_header.shift();
-
-        // Taken from Mednafen: normally the shift value should be between 0 and 12 since otherwise
-        // you lose precision. Apparently when that happens we only keep the sign bit and extend it
-        // 8 times.
-        //
-        // XXX Should probably be tested on real hardware and added as a unit test.
-        if shift > 12 {
-            encoded &= 0x8888;
-            shift = 8;
-        }
-
-        // Decode the four 4bit samples
-        for i in 0..4 {
-            // Extract the 4 bits and convert to signed to get proper sign extension when shifting
-            let mut sample = (encoded << (12 - i * 4) & 0xf000) as i16;
-
-            sample >>= shift;
-
-            let mut sample = i32::from(sample);
-
-            // Previous sample
-            let sample_1 = i32::from(self.last_samples[0]);
-            // Antepenultimate sample
-            let sample_2 = i32::from(self.last_samples[1]);
-
-            // Extrapolate with sample -1 using the positive weight
-            sample += (sample_1 * wp) >> 6;
-            // Extrapolate with sample -2 using the negative weight
-            sample += (sample_2 * wn) >> 6;
+    fn decode(&mut self, encoded: u16) {
+        let samples = self.block_header.decode(encoded, &mut self.last_samples);
 
-            let sample = saturate_to_i16(sample);
+        for sample in samples {
             self.decoder_fifo.push(sample);
-
-            // Shift `last_samples` for the next sample
-            self.last_samples[1] = self.last_samples[0];
-            self.last_samples[0] = sample;
         }
     }
 
     /// Returns the next "raw" decoded sample for this voice, meaning the post-ADPCM decode and
     /// resampling but pre-ADSR.
-    fn next_raw_sample(&self, interpolation_method: interpolation::InterpolationMethod, use_8bit_accuracy: bool) -> i32 {
-        let phase = (self.phase >> 4) as u8;
+    fn next_raw_sample(
+        &self,
+        interpolation_method: interpolation::InterpolationMethod,
+        gaussian_8bit_accuracy: bool,
+    ) -> i32 {
         let samples = [
             self.decoder_fifo[0],
             self.decoder_fifo[1],
@@ -1934,14 +1697,11 @@ impl Voice {
             self.decoder_fifo[3],
         ];
 
-        // Use the advanced interpolation system
         interpolation::interpolate(
             interpolation_method,
-            phase,
+            self.phase,
             samples,
-            None,  // No extended samples for sinc in this context
-            None,  // No sinc coeffs in this context
-            use_8bit_accuracy,
+            gaussian_8bit_accuracy,
         )
     }
 
@@ -2601,6 +2361,53 @@ impl AdpcmHeader {
     fn shift(self) -> u8 {
         (self.0 & 0xf) as u8
     }
+
+    /// Decode the four 4bit samples in `encoded`. `last_samples` contains the two previously
+    /// decoded samples (most recent first) and is updated.
+    fn decode(self, mut encoded: u16, last_samples: &mut [i16; 2]) -> [i16; 4] {
+        let (wp, wn) = self.weights();
+        let mut shift = self.shift();
+
+        // Taken from Mednafen: normally the shift value should be between 0 and 12 since otherwise
+        // you lose precision. Apparently when that happens we only keep the sign bit and extend it
+        // 8 times.
+        //
+        // XXX Should probably be tested on real hardware and added as a unit test.
+        if shift > 12 {
+            encoded &= 0x8888;
+            shift = 8;
+        }
+
+        let mut decoded = [0; 4];
+
+        for (i, d) in decoded.iter_mut().enumerate() {
+            // Extract the 4 bits and convert to signed to get proper sign extension when shifting
+            let mut sample = (encoded << (12 - i * 4) & 0xf000) as i16;
+
+            sample >>= shift;
+
+            let mut sample = i32::from(sample);
+
+            // Previous sample
+            let sample_1 = i32::from(last_samples[0]);
+            // Antepenultimate sample
+            let sample_2 = i32::from(last_samples[1]);
+
+            // Extrapolate with sample -1 using the positive weight
+            sample += (sample_1 * wp) >> 6;
+            // Extrapolate with sample -2 using the negative weight
+            sample += (sample_2 * wn) >> 6;
+
+            let sample = saturate_to_i16(sample);
+            *d = sample;
+
+            // Shift `last_samples` for the next sample
+            last_samples[1] = last_samples[0];
+            last_samples[0] = sample;
+        }
+
+        decoded
+    }
 }
 
 /// Convert a register value to a ram index
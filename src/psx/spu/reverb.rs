@@ -0,0 +1,720 @@
+//! Reverberation unit
+//!
+//! Two implementations are available:
+//!
+//! * The accurate one emulates the hardware exactly: the reverb runs at 22.05kHz, alternating
+//!   between the left and right sides every 44.1kHz cycle, all intermediate values are clipped to
+//!   16 bits and the working area lives in SPU RAM where the game can access it.
+//! * The enhanced one runs the same network at 44.1kHz for both sides every cycle, with floating
+//!   point intermediate values and delay taps that glide smoothly when the game reconfigures the
+//!   reverb. This removes the aliasing and the grit of the original at the cost of accuracy: the
+//!   working area is kept in a private buffer so the reverb contents of SPU RAM aren't updated
+//!   anymore. That's not a problem for the vast majority of games.
+
+use super::regmap;
+use super::reverb_resampler::ReverbResampler;
+use super::{saturate_to_i16, to_ram_index, RamIndex};
+
+/// Interface to the memory containing the reverb working area
+pub trait ReverbMemory {
+    fn load(&mut self, index: RamIndex) -> i16;
+    fn store(&mut self, index: RamIndex, v: i16);
+}
+
+/// Register indexes used by one side of the reverb
+struct Side {
+    input_volume: usize,
+    same1: usize,
+    same2: usize,
+    diff1: usize,
+    diff2: usize,
+    comb: [usize; 4],
+    apf1: usize,
+    apf2: usize,
+}
+
+static LEFT: Side = Side {
+    input_volume: regmap::REVERB_INPUT_VOLUME_LEFT,
+    same1: regmap::REVERB_REFLECT_SAME_LEFT1,
+    same2: regmap::REVERB_REFLECT_SAME_LEFT2,
+    diff1: regmap::REVERB_REFLECT_DIFF_LEFT1,
+    diff2: regmap::REVERB_REFLECT_DIFF_LEFT2,
+    comb: [
+        regmap::REVERB_COMB_LEFT1,
+        regmap::REVERB_COMB_LEFT2,
+        regmap::REVERB_COMB_LEFT3,
+        regmap::REVERB_COMB_LEFT4,
+    ],
+    apf1: regmap::REVERB_APF_LEFT1,
+    apf2: regmap::REVERB_APF_LEFT2,
+};
+
+static RIGHT: Side = Side {
+    input_volume: regmap::REVERB_INPUT_VOLUME_RIGHT,
+    same1: regmap::REVERB_REFLECT_SAME_RIGHT1,
+    same2: regmap::REVERB_REFLECT_SAME_RIGHT2,
+    diff1: regmap::REVERB_REFLECT_DIFF_RIGHT1,
+    diff2: regmap::REVERB_REFLECT_DIFF_RIGHT2,
+    comb: [
+        regmap::REVERB_COMB_RIGHT1,
+        regmap::REVERB_COMB_RIGHT2,
+        regmap::REVERB_COMB_RIGHT3,
+        regmap::REVERB_COMB_RIGHT4,
+    ],
+    apf1: regmap::REVERB_APF_RIGHT1,
+    apf2: regmap::REVERB_APF_RIGHT2,
+};
+
+static COMB_VOLUMES: [usize; 4] = [
+    regmap::REVERB_COMB_VOLUME1,
+    regmap::REVERB_COMB_VOLUME2,
+    regmap::REVERB_COMB_VOLUME3,
+    regmap::REVERB_COMB_VOLUME4,
+];
+
+/// Reverb state. The field names match the ones the `Spu` used before the reverb got its own
+/// module so that the savestate format doesn't change.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Reverb {
+    /// Current index in the working memory
+    #[serde(rename = "reverb_index")]
+    index: RamIndex,
+    /// Which stereo side should we run reverb on next
+    #[serde(rename = "reverb_run_right")]
+    run_right: bool,
+    /// Reverb input sample downsampler (44.1kHz -> 22.05kHz), left
+    #[serde(rename = "reverb_downsampler_left", default)]
+    downsampler_left: ReverbResampler,
+    /// Reverb input sample downsampler (44.1kHz -> 22.05kHz), right
+    #[serde(rename = "reverb_downsampler_right", default)]
+    downsampler_right: ReverbResampler,
+    /// Reverb output sample upsampler (22.05kHz -> 44.1kHz), left
+    #[serde(rename = "reverb_upsampler_left", default)]
+    upsampler_left: ReverbResampler,
+    /// Reverb output sample upsampler (22.05kHz -> 44.1kHz), right
+    #[serde(rename = "reverb_upsampler_right", default)]
+    upsampler_right: ReverbResampler,
+    /// State of the enhanced implementation. It's rebuilt from scratch when needed, no need to
+    /// save it.
+    #[serde(skip)]
+    enhanced: Option<EnhancedReverb>,
+}
+
+impl Reverb {
+    pub fn new() -> Reverb {
+        Reverb {
+            index: 0,
+            run_right: false,
+            downsampler_left: ReverbResampler::new(),
+            downsampler_right: ReverbResampler::new(),
+            upsampler_left: ReverbResampler::new(),
+            upsampler_right: ReverbResampler::new(),
+            enhanced: None,
+        }
+    }
+
+    /// Called when the start address of the working area is changed
+    pub fn set_start(&mut self, start: RamIndex) {
+        self.index = start;
+    }
+
+    /// Advance the accurate reverb state machine. Should be called at 44.1kHz with the new reverb
+    /// input samples.
+    pub fn run_accurate<M: ReverbMemory>(
+        &mut self,
+        mem: &mut M,
+        regs: &[u16],
+        start: RamIndex,
+        enabled: bool,
+        (left_in, right_in): (i16, i16),
+    ) -> (i16, i16) {
+        // Reverb downsamples from 44.1Khz to 22.05kHz using a simple FIR filter
+        self.downsampler_left.push_sample(left_in);
+        self.downsampler_right.push_sample(right_in);
+
+        if enabled {
+            if self.run_right {
+                let sample = self.downsampler_right.resample();
+                let out = self.run_accurate_side(mem, regs, start, &RIGHT, sample);
+
+                self.upsampler_left.push_sample(0);
+                self.upsampler_right.push_sample(out);
+            } else {
+                let sample = self.downsampler_left.resample();
+                let out = self.run_accurate_side(mem, regs, start, &LEFT, sample);
+
+                self.upsampler_left.push_sample(out);
+                self.upsampler_right.push_sample(0);
+            }
+        }
+
+        if self.run_right {
+            self.index = self.index.wrapping_add(1);
+            if self.index > 0x3_ffff {
+                self.index = start;
+            }
+        }
+        self.run_right = !self.run_right;
+
+        let reverb_left = self.upsampler_left.resample();
+        let reverb_right = self.upsampler_right.resample();
+
+        (reverb_left, reverb_right)
+    }
+
+    fn run_accurate_side<M: ReverbMemory>(
+        &self,
+        mem: &mut M,
+        regs: &[u16],
+        start: RamIndex,
+        side: &Side,
+        sample: i16,
+    ) -> i16 {
+        let load = |mem: &mut M, addr: u16| mem.load(self.sample_index(start, addr, 0));
+        let load_before = |mem: &mut M, addr: u16| mem.load(self.sample_index(start, addr, 1));
+        let store =
+            |mem: &mut M, addr: u16, v: i16| mem.store(self.sample_index(start, addr, 0), v);
+
+        // IIR processing
+        let sample = i32::from(sample);
+
+        let in_mix = (sample * i32::from(regs[side.input_volume] as i16)) >> 15;
+
+        let reflect_vol = i32::from(regs[regmap::REVERB_REFLECT_VOLUME2] as i16);
+
+        let same_side_mix = (i32::from(load(mem, regs[side.same2])) * reflect_vol) >> 15;
+        let diff_side_mix = (i32::from(load(mem, regs[side.diff2])) * reflect_vol) >> 15;
+
+        let input_same = saturate_to_i16(same_side_mix + in_mix);
+        let input_diff = saturate_to_i16(diff_side_mix + in_mix);
+
+        let reflect_iir_vol = regs[regmap::REVERB_REFLECT_VOLUME1] as i16;
+        let input_same_alpha = (i32::from(input_same) * i32::from(reflect_iir_vol)) >> 14;
+        let input_diff_alpha = (i32::from(input_diff) * i32::from(reflect_iir_vol)) >> 14;
+
+        let iir_same = saturate_to_i16(
+            (input_same_alpha + iir_mul(reflect_iir_vol, load_before(mem, regs[side.same1]))) >> 1,
+        );
+        let iir_diff = saturate_to_i16(
+            (input_diff_alpha + iir_mul(reflect_iir_vol, load_before(mem, regs[side.diff1]))) >> 1,
+        );
+
+        store(mem, regs[side.same1], iir_same);
+        store(mem, regs[side.diff1], iir_diff);
+
+        let mut comb_sum = 0;
+        for (&comb, &vol) in side.comb.iter().zip(COMB_VOLUMES.iter()) {
+            comb_sum += (i32::from(load(mem, regs[comb])) * i32::from(regs[vol] as i16)) >> 14;
+        }
+        let early_echo = saturate_to_i16(comb_sum >> 1);
+
+        let apf_in1 = i32::from(load(
+            mem,
+            regs[side.apf1].wrapping_add(regs[regmap::REVERB_APF_OFFSET1]),
+        ));
+        let apf_in2 = i32::from(load(
+            mem,
+            regs[side.apf2].wrapping_add(regs[regmap::REVERB_APF_OFFSET2]),
+        ));
+
+        let apf_vol1 = i32::from(regs[regmap::REVERB_APF_VOLUME1] as i16);
+        let apf_vol2 = i32::from(regs[regmap::REVERB_APF_VOLUME2] as i16);
+
+        let out_1 = saturate_to_i16(i32::from(early_echo) - ((apf_in1 * apf_vol1) >> 15));
+        let out_2 = saturate_to_i16(
+            ((i32::from(early_echo) * apf_vol1) >> 15)
+                - ((apf_in1 * -apf_vol1) >> 15)
+                - ((apf_in2 * apf_vol2) >> 15),
+        );
+
+        store(mem, regs[side.apf1], out_1);
+        store(mem, regs[side.apf2], out_2);
+
+        saturate_to_i16((i32::from(out_1) + i32::from(out_2)) >> 1)
+    }
+
+    /// Returns the index in SPU RAM of the reverb sample at `addr`, `neg_offset` samples in the
+    /// past
+    fn sample_index(&self, start: RamIndex, addr: u16, neg_offset: u32) -> RamIndex {
+        let idx = self
+            .index
+            .wrapping_add(to_ram_index(addr))
+            .wrapping_sub(neg_offset);
+
+        if idx <= 0x3_ffff {
+            idx
+        } else {
+            // Overflow, wrap around to the start of the reverb working area
+            start.wrapping_add(idx) & 0x3_ffff
+        }
+    }
+
+    /// Advance the enhanced reverb by one 44.1kHz cycle. The input and output samples aren't
+    /// clipped.
+    pub fn run_enhanced(
+        &mut self,
+        regs: &[u16],
+        start: RamIndex,
+        enabled: bool,
+        input: (i32, i32),
+    ) -> (i32, i32) {
+        let len = EnhancedReverb::buffer_len(start);
+
+        let enhanced = match &mut self.enhanced {
+            Some(e) if e.buffer.len() == len => e,
+            e => e.insert(EnhancedReverb::new(len)),
+        };
+
+        enhanced.run(regs, enabled, input)
+    }
+
+    /// Free the enhanced reverb's buffer when switching back to accurate mode
+    pub fn clear_enhanced(&mut self) {
+        self.enhanced = None;
+    }
+}
+
+impl Default for Reverb {
+    fn default() -> Reverb {
+        Reverb::new()
+    }
+}
+
+fn iir_mul(a: i16, b: i16) -> i32 {
+    (if a > i16::MIN {
+        (32768 - i32::from(a)) * i32::from(b)
+    } else if b > i16::MIN {
+        i32::from(b) * 32768
+    } else {
+        0
+    }) >> 14
+}
+
+/// Number of 44.1kHz samples per 22.05kHz sample
+const OVERSAMPLING: usize = 2;
+
+/// Speed at which the delay taps move towards their new position when the game reconfigures the
+/// reverb, in samples per cycle
+const TAP_GLIDE_SPEED: f32 = 0.25;
+
+/// Gain of the accurate implementation's resampling (the upsampler's zero-stuffing halves the
+/// level), we apply the same one to keep the volume the same between modes
+const OUTPUT_GAIN: f32 = 0.5;
+
+/// The output isn't clipped to 16 bits but it must still fit once multiplied by the reverb output
+/// volume
+const OUTPUT_LIMIT: f32 = 65535.;
+
+/// Delay taps of one side of the network, in samples at 44.1kHz
+#[derive(Clone, Copy, Default)]
+struct Taps {
+    same1: f32,
+    same2: f32,
+    diff1: f32,
+    diff2: f32,
+    comb: [f32; 4],
+    apf1: f32,
+    apf2: f32,
+    apf1_in: f32,
+    apf2_in: f32,
+}
+
+impl Taps {
+    fn from_regs(regs: &[u16], side: &Side) -> Taps {
+        let tap = |addr: u16| (to_ram_index(addr) as usize * OVERSAMPLING) as f32;
+
+        Taps {
+            same1: tap(regs[side.same1]),
+            same2: tap(regs[side.same2]),
+            diff1: tap(regs[side.diff1]),
+            diff2: tap(regs[side.diff2]),
+            comb: [
+                tap(regs[side.comb[0]]),
+                tap(regs[side.comb[1]]),
+                tap(regs[side.comb[2]]),
+                tap(regs[side.comb[3]]),
+            ],
+            apf1: tap(regs[side.apf1]),
+            apf2: tap(regs[side.apf2]),
+            apf1_in: tap(regs[side.apf1].wrapping_add(regs[regmap::REVERB_APF_OFFSET1])),
+            apf2_in: tap(regs[side.apf2].wrapping_add(regs[regmap::REVERB_APF_OFFSET2])),
+        }
+    }
+
+    /// Move all the taps towards `target` by at most `TAP_GLIDE_SPEED`
+    fn glide_to(&mut self, target: &Taps) {
+        fn glide(cur: &mut f32, target: f32) {
+            let delta = (target - *cur).clamp(-TAP_GLIDE_SPEED, TAP_GLIDE_SPEED);
+
+            *cur += delta;
+        }
+
+        glide(&mut self.same1, target.same1);
+        glide(&mut self.same2, target.same2);
+        glide(&mut self.diff1, target.diff1);
+        glide(&mut self.diff2, target.diff2);
+        for (c, &t) in self.comb.iter_mut().zip(target.comb.iter()) {
+            glide(c, t);
+        }
+        glide(&mut self.apf1, target.apf1);
+        glide(&mut self.apf2, target.apf2);
+        glide(&mut self.apf1_in, target.apf1_in);
+        glide(&mut self.apf2_in, target.apf2_in);
+    }
+}
+
+/// High resolution implementation of the reverb network
+struct EnhancedReverb {
+    /// Working area, `OVERSAMPLING` times as long as the one in SPU RAM
+    buffer: Vec<f32>,
+    /// Current position in `buffer`
+    index: usize,
+    /// Current tap positions for each side. `None` until the first cycle, at which point we jump
+    /// directly to the configured positions.
+    taps: Option<[Taps; 2]>,
+}
+
+impl EnhancedReverb {
+    fn new(len: usize) -> EnhancedReverb {
+        EnhancedReverb {
+            buffer: vec![0.; len],
+            index: 0,
+            taps: None,
+        }
+    }
+
+    fn buffer_len(start: RamIndex) -> usize {
+        (0x4_0000 - (start as usize & 0x3_ffff)) * OVERSAMPLING
+    }
+
+    /// Read the buffer `offset` samples after the current position, interpolating linearly
+    /// between samples for fractional offsets
+    fn read(&self, offset: f32) -> f32 {
+        let len = self.buffer.len();
+        let pos = offset.max(0.);
+        let i = pos as usize;
+        let frac = pos - i as f32;
+
+        let a = self.buffer[(self.index + i) % len];
+        let b = self.buffer[(self.index + i + 1) % len];
+
+        a + (b - a) * frac
+    }
+
+    /// Write the sample at the (rounded) `offset`
+    fn write(&mut self, offset: f32, v: f32) {
+        let len = self.buffer.len();
+        let i = offset.max(0.).round() as usize;
+
+        self.buffer[(self.index + i) % len] = v;
+    }
+
+    fn run(&mut self, regs: &[u16], enabled: bool, (left_in, right_in): (i32, i32)) -> (i32, i32) {
+        let target = [Taps::from_regs(regs, &LEFT), Taps::from_regs(regs, &RIGHT)];
+
+        let taps = match &mut self.taps {
+            Some(taps) => {
+                taps[0].glide_to(&target[0]);
+                taps[1].glide_to(&target[1]);
+                *taps
+            }
+            t => *t.insert(target),
+        };
+
+        let out = if enabled {
+            let l = self.run_side(regs, &LEFT, &taps[0], left_in as f32);
+            let r = self.run_side(regs, &RIGHT, &taps[1], right_in as f32);
+
+            let limit = |v: f32| (v * OUTPUT_GAIN).clamp(-OUTPUT_LIMIT, OUTPUT_LIMIT) as i32;
+
+            (limit(l), limit(r))
+        } else {
+            (0, 0)
+        };
+
+        self.index = (self.index + 1) % self.buffer.len();
+
+        out
+    }
+
+    fn run_side(&mut self, regs: &[u16], side: &Side, taps: &Taps, sample: f32) -> f32 {
+        let vol = |r: usize| f32::from(regs[r] as i16) / 32768.;
+
+        let in_mix = sample * vol(side.input_volume);
+        let reflect_vol = vol(regmap::REVERB_REFLECT_VOLUME2);
+
+        let input_same = self.read(taps.same2) * reflect_vol + in_mix;
+        let input_diff = self.read(taps.diff2) * reflect_vol + in_mix;
+
+        // The wall reflections go through a one-pole low-pass filter. Since we run twice as fast
+        // as the hardware we have to adjust the coefficient to get the same cutoff frequency.
+        let alpha = vol(regmap::REVERB_REFLECT_VOLUME1).clamp(-1., 1.);
+        let alpha = 1. - (1. - alpha).max(0.).sqrt();
+
+        let iir_same = input_same * alpha + self.read(taps.same1 - 1.) * (1. - alpha);
+        let iir_diff = input_diff * alpha + self.read(taps.diff1 - 1.) * (1. - alpha);
+
+        self.write(taps.same1, iir_same);
+        self.write(taps.diff1, iir_diff);
+
+        let mut early_echo = 0.;
+        for (&tap, &v) in taps.comb.iter().zip(COMB_VOLUMES.iter()) {
+            early_echo += self.read(tap) * vol(v);
+        }
+
+        let apf_in1 = self.read(taps.apf1_in);
+        let apf_in2 = self.read(taps.apf2_in);
+
+        let apf_vol1 = vol(regmap::REVERB_APF_VOLUME1);
+        let apf_vol2 = vol(regmap::REVERB_APF_VOLUME2);
+
+        let out_1 = early_echo - apf_in1 * apf_vol1;
+        let out_2 = early_echo * apf_vol1 + apf_in1 * apf_vol1 - apf_in2 * apf_vol2;
+
+        self.write(taps.apf1, out_1);
+        self.write(taps.apf2, out_2);
+
+        (out_1 + out_2) / 2.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden;
+
+    /// "Room" preset used by the BIOS and many games
+    const ROOM: [u16; 32] = [
+        0x007d, 0x005b, 0x6d80, 0x54b8, 0xbed0, 0x0000, 0x0000, 0xba80, 0x5800, 0x5300, 0x04d6,
+        0x0333, 0x03f0, 0x0227, 0x0374, 0x01ef, 0x0334, 0x01b5, 0x0000, 0x0000, 0x0000, 0x0000,
+        0x0000, 0x0000, 0x0000, 0x0000, 0x01b4, 0x0136, 0x00b8, 0x005c, 0x8000, 0x8000,
+    ];
+
+    /// Size of the "Room" working area in SPU RAM, in halfwords
+    const ROOM_SIZE: RamIndex = 0x26c0;
+
+    /// Number of 44.1kHz cycles to run, a bit more than a third of a second
+    const RUN_CYCLES: usize = 0x4000;
+
+    struct Ram(Vec<u16>);
+
+    impl ReverbMemory for Ram {
+        fn load(&mut self, index: RamIndex) -> i16 {
+            self.0[index as usize] as i16
+        }
+
+        fn store(&mut self, index: RamIndex, v: i16) {
+            self.0[index as usize] = v as u16;
+        }
+    }
+
+    fn room_regs() -> [u16; 0x100] {
+        let mut regs = [0; 0x100];
+
+        regs[regmap::REVERB_APF_OFFSET1..].copy_from_slice(&ROOM);
+
+        regs
+    }
+
+    fn room_start() -> RamIndex {
+        0x4_0000 - ROOM_SIZE
+    }
+
+    /// Synthetic input: a full scale impulse on both sides followed by silence
+    fn impulse(cycle: usize) -> i16 {
+        if cycle == 0 {
+            0x4000
+        } else {
+            0
+        }
+    }
+
+    fn run_accurate(input: impl Fn(usize) -> (i16, i16)) -> Vec<(i32, i32)> {
+        let regs = room_regs();
+        let start = room_start();
+        let mut ram = Ram(vec![0; 0x4_0000]);
+        let mut reverb = Reverb::new();
+
+        reverb.set_start(start);
+
+        (0..RUN_CYCLES)
+            .map(|c| {
+                let (l, r) = reverb.run_accurate(&mut ram, &regs, start, true, input(c));
+
+                (i32::from(l), i32::from(r))
+            })
+            .collect()
+    }
+
+    fn run_enhanced(input: impl Fn(usize) -> (i16, i16)) -> Vec<(i32, i32)> {
+        let regs = room_regs();
+        let start = room_start();
+        let mut reverb = Reverb::new();
+
+        reverb.set_start(start);
+
+        (0..RUN_CYCLES)
+            .map(|c| {
+                let (l, r) = input(c);
+
+                reverb.run_enhanced(&regs, start, true, (i32::from(l), i32::from(r)))
+            })
+            .collect()
+    }
+
+    fn energy(samples: &[(i32, i32)]) -> f64 {
+        samples
+            .iter()
+            .map(|&(l, r)| (l as f64).powi(2) + (r as f64).powi(2))
+            .sum()
+    }
+
+    /// Index of the first non-silent output sample
+    fn onset(out: &[(i32, i32)]) -> usize {
+        out.iter().position(|&(l, r)| l != 0 || r != 0).unwrap()
+    }
+
+    /// The accurate reverb output must be bit-identical to the hardware-accurate reverb that lived
+    /// in `spu/mod.rs` before the enhanced mode was added: the trace was recorded from that code
+    /// (with its enhanced mode disabled) using the same input and "Room" setup
+    #[test]
+    fn accurate_matches_recorded_trace() {
+        // Impulse on the left, pseudo-random bursts on the right
+        let out = run_accurate(|c| {
+            let noise = (c as u32).wrapping_mul(0x9e37_79b9) >> 16;
+            let right = if c % 0x1000 < 0x100 { noise as i16 } else { 0 };
+
+            (impulse(c), right)
+        });
+        let bytes: Vec<u8> = out
+            .iter()
+            .flat_map(|&(l, r)| [l as i16, r as i16])
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+
+        golden::check(
+            &golden::path("src/psx/spu/traces/reverb_accurate.trace"),
+            &bytes,
+            4,
+        );
+    }
+
+    /// Muting or soloing voices in the mix must not change what the reverb writes to SPU RAM
+    #[test]
+    fn voice_mask_keeps_reverb_ram() {
+        use super::super::capture::VoiceMask;
+        use super::super::mix_voice;
+
+        let run = |mask: VoiceMask| {
+            let regs = room_regs();
+            let start = room_start();
+            let mut ram = Ram(vec![0; 0x4_0000]);
+            let mut reverb = Reverb::new();
+
+            reverb.set_start(start);
+
+            for c in 0..RUN_CYCLES {
+                // Voice 0 plays an impulse, voice 1 a square wave, both are reverberated
+                let square = if c & 0x40 != 0 { 0x1000 } else { -0x1000 };
+                let voices = [(i32::from(impulse(c)), 0), (square, square)];
+
+                let mut input = (0, 0);
+                for (voice, &sample) in voices.iter().enumerate() {
+                    let (_, wet) = mix_voice(mask, voice as u8, true, sample);
+
+                    input.0 += wet.0;
+                    input.1 += wet.1;
+                }
+
+                let input = (saturate_to_i16(input.0), saturate_to_i16(input.1));
+                reverb.run_accurate(&mut ram, &regs, start, true, input);
+            }
+
+            ram.0
+        };
+
+        let reference = run(VoiceMask::default());
+        assert!(reference.iter().any(|&v| v != 0));
+
+        let mut muted = VoiceMask::default();
+        muted.set_muted(0, true);
+        assert!(run(muted) == reference);
+
+        let mut soloed = VoiceMask::default();
+        soloed.set_soloed(1, true);
+        assert!(run(soloed) == reference);
+    }
+
+    /// Both modes should produce a similar reverb tail for the same impulse
+    #[test]
+    fn impulse_response() {
+        let input = |c| (impulse(c), impulse(c));
+
+        let accurate = run_accurate(input);
+        let enhanced = run_enhanced(input);
+
+        // Reference values for the "Room" preset. The enhanced mode runs the network at twice the
+        // rate with interpolated taps, so its tail starts a bit earlier and isn't attenuated by
+        // the 22.05kHz resampling filters.
+        assert_eq!(onset(&accurate), 1863);
+        assert_eq!(onset(&enhanced), 1840);
+        assert_eq!(accurate[1863..1867], [(-1, 0), (0, 0), (1, 0), (0, 0)]);
+        assert_eq!(
+            enhanced[1840..1844],
+            [(-2835, 0), (-1078, 0), (-409, 0), (-155, 0)]
+        );
+        assert_eq!(energy(&accurate), 37_960_859.);
+        assert_eq!(energy(&enhanced), 46_672_334.);
+
+        let accurate_energy = energy(&accurate);
+        let enhanced_energy = energy(&enhanced);
+
+        assert!(accurate_energy > 0.);
+
+        let ratio = enhanced_energy / accurate_energy;
+        assert!(
+            (0.5..2.).contains(&ratio),
+            "Enhanced/accurate energy ratio: {}",
+            ratio
+        );
+
+        // The reverb must decay
+        for out in [&accurate, &enhanced] {
+            let half = out.len() / 2;
+
+            assert!(energy(&out[half..]) < energy(&out[..half]) / 4.);
+        }
+
+        // Both tails should start at the same time, give or take the resampling delay
+        let delta = onset(&accurate) as i64 - onset(&enhanced) as i64;
+
+        assert!(delta.abs() < 64, "Onset delta: {}", delta);
+    }
+
+    /// The enhanced mode must not clip, even when the input exceeds the 16 bit range
+    #[test]
+    fn enhanced_no_clipping() {
+        let regs = room_regs();
+        let start = room_start();
+
+        let run = |amplitude: i32| -> Vec<(i32, i32)> {
+            let mut reverb = Reverb::new();
+
+            (0..RUN_CYCLES)
+                .map(|c| {
+                    let s = i32::from(impulse(c)) * amplitude;
+
+                    reverb.run_enhanced(&regs, start, true, (s, s))
+                })
+                .collect()
+        };
+
+        let quiet = run(1);
+        let loud = run(3);
+
+        for (&(ql, qr), &(ll, lr)) in quiet.iter().zip(loud.iter()) {
+            assert!((ll - 3 * ql).abs() <= 3);
+            assert!((lr - 3 * qr).abs() <= 3);
+        }
+    }
+}
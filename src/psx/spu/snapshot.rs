@@ -0,0 +1,308 @@
+//! Structured view of the SPU state
+//!
+//! Meant for debugging tools (GDB monitor, web frontend...) that need to see what the SPU is
+//! doing when a game's audio breaks: which voices are playing, where in SPU RAM they're reading,
+//! how the reverb is configured... Everything is serializable so that it can be shipped as JSON
+//! (`to_json` needs the `json` feature).
+//!
+//! All addresses are *byte* addresses in SPU RAM (0 to 0x7ffff), not the 8-byte units used by the
+//! registers nor the halfword indexes used internally.
+
+use super::{regmap, AdpcmHeader, AdsrState, RamIndex, Spu, SPU_RAM_SIZE};
+
+/// Size of an ADPCM block in bytes
+pub const ADPCM_BLOCK_SIZE: u32 = 16;
+
+/// Number of PCM samples in an ADPCM block
+pub const ADPCM_BLOCK_SAMPLES: usize = 28;
+
+/// Number of ADPCM blocks in SPU RAM. Decoding more than that just wraps around and decodes the
+/// same blocks again.
+pub const MAX_ADPCM_BLOCKS: usize = SPU_RAM_SIZE * 2 / ADPCM_BLOCK_SIZE as usize;
+
+/// Envelope phase of a voice
+#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AdsrPhase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+impl From<AdsrState> for AdsrPhase {
+    fn from(state: AdsrState) -> AdsrPhase {
+        match state {
+            AdsrState::Attack => AdsrPhase::Attack,
+            AdsrState::Decay => AdsrPhase::Decay,
+            AdsrState::Sustain => AdsrPhase::Sustain,
+            AdsrState::Release => AdsrPhase::Release,
+        }
+    }
+}
+
+/// State of a single voice
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct VoiceSnapshot {
+    /// Voice number, 0 to 23
+    pub index: u8,
+    /// True if the voice has been keyed on and not released yet
+    pub key_on: bool,
+    /// Current envelope phase
+    pub adsr_phase: AdsrPhase,
+    /// Current envelope level, 0 to 0x7fff
+    pub adsr_level: i16,
+    /// Raw value of the ADSR configuration register
+    pub adsr_config: u32,
+    /// Sample rate of the voice, 0x1000 is 44.1kHz
+    pub pitch: u16,
+    /// Current left volume
+    pub volume_left: i16,
+    /// Current right volume
+    pub volume_right: i16,
+    /// Address the voice starts playing from when keyed on
+    pub start_address: u32,
+    /// Address the voice jumps to at the end of a loop
+    pub repeat_address: u32,
+    /// Address the voice is currently decoding
+    pub current_address: u32,
+    /// True if the voice's pitch is modulated by the previous voice
+    pub frequency_modulated: bool,
+    /// True if the voice outputs noise instead of its ADPCM samples
+    pub noise: bool,
+    /// True if the voice is fed to the reverb
+    pub reverb: bool,
+    /// True if the voice has reached a loop end block since it was keyed on
+    pub looped: bool,
+}
+
+/// Reverb configuration
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ReverbSnapshot {
+    /// True if the reverb is enabled in the control register
+    pub enabled: bool,
+    /// Start address of the reverb working area
+    pub start_address: u32,
+    /// Reverb output volume, left
+    pub volume_left: i16,
+    /// Reverb output volume, right
+    pub volume_right: i16,
+    /// Raw value of the 32 reverb configuration registers (0x1f801dc0 to 0x1f801dfe)
+    pub registers: Vec<u16>,
+}
+
+/// State of the whole SPU
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SpuSnapshot {
+    /// Raw value of the control register
+    pub control: u16,
+    /// Raw value of the status register
+    pub status: u16,
+    /// True if the SPU is enabled in the control register
+    pub enabled: bool,
+    /// True if the SPU output is muted in the control register
+    pub muted: bool,
+    /// Current main volume, left
+    pub main_volume_left: i16,
+    /// Current main volume, right
+    pub main_volume_right: i16,
+    /// True if the SPU interrupt is enabled
+    pub irq_enabled: bool,
+    /// Address that triggers the SPU interrupt when accessed
+    pub irq_address: u32,
+    /// True if the interrupt has been triggered and not yet acknowledged
+    pub irq_pending: bool,
+    /// Current address of the manual/DMA transfer
+    pub transfer_address: u32,
+    /// The 24 voices
+    pub voices: Vec<VoiceSnapshot>,
+    pub reverb: ReverbSnapshot,
+}
+
+impl SpuSnapshot {
+    pub fn new(spu: &Spu) -> SpuSnapshot {
+        let voices = (0..24).map(|v| voice_snapshot(spu, v)).collect();
+
+        let reverb = ReverbSnapshot {
+            enabled: spu.reverb_enabled(),
+            start_address: to_byte_address(spu.reverb_start),
+            volume_left: spu.reverb_out_volume_left,
+            volume_right: spu.reverb_out_volume_right,
+            registers: spu.regs[regmap::REVERB_APF_OFFSET1..=regmap::REVERB_INPUT_VOLUME_RIGHT]
+                .to_vec(),
+        };
+
+        SpuSnapshot {
+            control: spu.control(),
+            status: spu.regs[regmap::STATUS],
+            enabled: spu.enabled(),
+            muted: spu.muted(),
+            main_volume_left: spu.main_volume_left.level(),
+            main_volume_right: spu.main_volume_right.level(),
+            irq_enabled: spu.irq_enabled(),
+            irq_address: to_byte_address(spu.irq_addr),
+            irq_pending: spu.irq,
+            transfer_address: to_byte_address(spu.ram_index),
+            voices,
+            reverb,
+        }
+    }
+
+    /// Returns the state of the voices that are currently keyed on
+    pub fn active_voices(&self) -> impl Iterator<Item = &VoiceSnapshot> {
+        self.voices.iter().filter(|v| v.key_on)
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        // Can't fail: all the fields are plain data and there are no non-string map keys
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+fn voice_snapshot(spu: &Spu, index: u8) -> VoiceSnapshot {
+    let voice = &spu[index];
+    let mask = 1 << index;
+
+    VoiceSnapshot {
+        index,
+        key_on: voice.adsr.state != AdsrState::Release && voice.is_running(),
+        adsr_phase: voice.adsr.state.into(),
+        adsr_level: voice.level(),
+        adsr_config: voice.adsr.config.0,
+        pitch: voice.step_length,
+        volume_left: voice.volume_left.level(),
+        volume_right: voice.volume_right.level(),
+        start_address: to_byte_address(voice.start_index),
+        repeat_address: to_byte_address(voice.loop_index),
+        current_address: to_byte_address(voice.cur_index),
+        frequency_modulated: spu.voice_frequency_modulated & mask != 0,
+        noise: spu.voice_noise & mask != 0,
+        reverb: spu.voice_reverb & mask != 0,
+        looped: spu.voice_looped & mask != 0,
+    }
+}
+
+/// Decode `block_count` ADPCM blocks starting at byte address `address` in SPU RAM into PCM
+/// samples. `address` is rounded down to the block size and the decoding wraps around at the end
+/// of the RAM. The decoder starts with a silent history, like a voice that's just been keyed on,
+/// and the loop flags are ignored.
+pub fn decode_adpcm(spu: &Spu, address: u32, block_count: usize) -> Vec<i16> {
+    let mut index = (address / 2) & !7;
+    let mut last_samples = [0; 2];
+    let mut pcm = Vec::with_capacity(block_count * ADPCM_BLOCK_SAMPLES);
+
+    for _ in 0..block_count {
+        let header = AdpcmHeader(spu.ram[ram_offset(index)]);
+
+        for i in 1..8 {
+            let encoded = spu.ram[ram_offset(index + i)];
+
+            pcm.extend_from_slice(&header.decode(encoded, &mut last_samples));
+        }
+
+        index = (index + 8) % SPU_RAM_SIZE as RamIndex;
+    }
+
+    pcm
+}
+
+fn ram_offset(index: RamIndex) -> usize {
+    index as usize % SPU_RAM_SIZE
+}
+
+fn to_byte_address(index: RamIndex) -> u32 {
+    index << 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Store an ADPCM block at byte address `address`
+    fn store_block(spu: &mut Spu, address: u32, header: u16, data: [u8; 14]) {
+        let index = (address / 2) as usize;
+
+        spu.ram[index] = header;
+        for (i, b) in data.chunks(2).enumerate() {
+            spu.ram[index + 1 + i] = u16::from(b[0]) | (u16::from(b[1]) << 8);
+        }
+    }
+
+    #[test]
+    fn decode_simple_block() {
+        let mut spu = Spu::new();
+
+        // Filter 0, shift 12: the nibbles are output as-is (sign extended)
+        let mut data = [0; 14];
+        data[0] = 0x71;
+        data[1] = 0xf8;
+        store_block(&mut spu, 0x1000, 12, data);
+
+        let pcm = decode_adpcm(&spu, 0x1000, 1);
+
+        assert_eq!(pcm.len(), ADPCM_BLOCK_SAMPLES);
+        assert_eq!(&pcm[..4], &[1, 7, -8, -1]);
+        assert!(pcm[4..].iter().all(|&s| s == 0));
+
+        // Unaligned addresses are rounded down to the block
+        assert_eq!(decode_adpcm(&spu, 0x100f, 1), pcm);
+    }
+
+    #[test]
+    fn decode_with_filter() {
+        let mut spu = Spu::new();
+
+        // Filter 1 (60/64 of the previous sample), shift 0
+        let mut data = [0; 14];
+        data[0] = 0x07;
+        store_block(&mut spu, 0, 1 << 4, data);
+
+        let pcm = decode_adpcm(&spu, 0, 1);
+
+        assert_eq!(pcm[0], 0x7000);
+        assert_eq!(pcm[1], ((0x7000 * 60) >> 6) as i16);
+        assert!(pcm.windows(2).skip(1).all(|w| w[1] <= w[0]));
+    }
+
+    #[test]
+    fn decode_wraps_around() {
+        let mut spu = Spu::new();
+
+        let mut data = [0; 14];
+        data[0] = 0x10;
+        store_block(&mut spu, 0, 12, data);
+
+        let last = (SPU_RAM_SIZE as u32 * 2) - ADPCM_BLOCK_SIZE;
+        let pcm = decode_adpcm(&spu, last, 2);
+
+        assert_eq!(pcm.len(), 2 * ADPCM_BLOCK_SAMPLES);
+        assert_eq!(pcm[ADPCM_BLOCK_SAMPLES + 1], 1);
+    }
+
+    #[test]
+    fn snapshot_json() {
+        let mut spu = Spu::new();
+
+        spu.irq_addr = 0x1234;
+        spu.voice_noise = 1 << 3;
+        spu[5].start_index = 0x800;
+
+        let snapshot = SpuSnapshot::new(&spu);
+
+        assert_eq!(snapshot.voices.len(), 24);
+        assert_eq!(snapshot.irq_address, 0x2468);
+        assert!(snapshot.voices[3].noise);
+        assert!(!snapshot.voices[4].noise);
+        assert_eq!(snapshot.voices[5].start_address, 0x1000);
+        assert_eq!(snapshot.reverb.registers.len(), 32);
+        assert_eq!(snapshot.active_voices().count(), 0);
+
+        #[cfg(feature = "json")]
+        {
+            let json = snapshot.to_json();
+            let parsed: SpuSnapshot = serde_json::from_str(&json).unwrap();
+
+            assert_eq!(parsed, snapshot);
+        }
+    }
+}
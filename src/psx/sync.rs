@@ -1,4 +1,5 @@
 use super::{dma, gpu, mdec, pad_memcard, spu, timers, CycleCount, Psx};
+use serde::de::{Deserialize, Deserializer, Error};
 
 /// Tokens used to keep track of the progress of each module individually
 #[derive(serde::Serialize, serde::Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
@@ -10,6 +11,9 @@ pub enum SyncToken {
     PadMemCard,
     MDec,
     LinkCable,
+    /// Light pen interrupt triggered by the light guns connected to the controller ports. Kept
+    /// last so that the other tokens keep their index in save states.
+    LightPen,
 
     NumTokens,
 }
@@ -17,8 +21,10 @@ pub enum SyncToken {
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Synchronizer {
     /// Array containing, for each module, the date corresponding to the last sync.
+    #[serde(deserialize_with = "deserialize_token_array")]
     last_sync: [CycleCount; SyncToken::NumTokens as usize],
     /// Array containing, for each module, the date at which we should force a resync.
+    #[serde(deserialize_with = "deserialize_token_array")]
     next_event: [CycleCount; SyncToken::NumTokens as usize],
     /// The date of the event in `next_event` that occurs first
     first_event: CycleCount,
@@ -44,6 +50,30 @@ impl Synchronizer {
     }
 }
 
+/// Save states created before the last tokens were added have shorter arrays, the missing entries
+/// are set to 0 which makes the corresponding modules sync on the next event
+fn deserialize_token_array<'de, D>(
+    deserializer: D,
+) -> Result<[CycleCount; SyncToken::NumTokens as usize], D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let v = Vec::<CycleCount>::deserialize(deserializer)?;
+
+    let mut array = [0; SyncToken::NumTokens as usize];
+
+    if v.len() > array.len() {
+        return Err(D::Error::invalid_length(
+            v.len(),
+            &"at most one entry per sync token",
+        ));
+    }
+
+    array[..v.len()].copy_from_slice(&v);
+
+    Ok(array)
+}
+
 /// Resynchronize `who` with the CPU, returning the number of CPU cycles elapsed since the last
 /// sync date
 pub fn resync(psx: &mut Psx, who: SyncToken) -> CycleCount {
@@ -127,6 +157,10 @@ pub fn handle_events(psx: &mut Psx) {
             pad_memcard::run(psx);
         }
 
+        if psx.sync.first_event >= psx.sync.next_event[SyncToken::LightPen as usize] {
+            pad_memcard::run_light_pen(psx);
+        }
+
         if psx.sync.first_event >= psx.sync.next_event[SyncToken::MDec as usize] {
             mdec::run(psx);
         }
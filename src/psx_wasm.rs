@@ -333,6 +333,16 @@ impl Psx {
     pub fn get_audio_samples(&mut self) -> Vec<i16> {
         self.spu.get_samples()
     }
+
+    /// Returns the state of the SPU voices, reverb and interrupt for debugging
+    pub fn spu_snapshot(&self) -> spu::snapshot::SpuSnapshot {
+        spu::snapshot::SpuSnapshot::new(&self.spu)
+    }
+
+    /// Decode `block_count` ADPCM blocks starting at byte `address` in SPU RAM
+    pub fn decode_spu_adpcm(&self, address: u32, block_count: usize) -> Vec<i16> {
+        spu::snapshot::decode_adpcm(&self.spu, address, block_count)
+    }
     
     pub fn set_controller_state(&mut self, port: usize, state: u16) {
         if port < 2 {
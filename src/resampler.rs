@@ -0,0 +1,485 @@
+//! Resampling of the SPU output to the host's sample rate.
+//!
+//! The SPU always runs at 44.1kHz. Most hosts run their audio at 48kHz however, and when the
+//! emulated refresh rate doesn't exactly match the host's we also need to stretch or compress the
+//! audio very slightly to avoid buffer underruns (crackling) or ever-growing latency. The
+//! `OutputResampler` takes care of both: it converts the native samples to the requested output
+//! rate using a windowed sinc filter and, if dynamic rate control is enabled, constantly nudges the
+//! conversion ratio (by at most a fraction of a percent, which is inaudible) to keep the fill
+//! level of the audio buffer close to its target.
+
+use rubato::{
+    calculate_cutoff, ResampleError, Resampler, SincFixedIn, SincInterpolationParameters,
+    SincInterpolationType, WindowFunction,
+};
+
+/// Native SPU output rate
+pub const NATIVE_RATE: u32 = 44_100;
+
+/// Lowest output rate supported
+pub const MIN_OUTPUT_RATE: u32 = 8_000;
+
+/// Highest output rate supported
+pub const MAX_OUTPUT_RATE: u32 = 192_000;
+
+/// Number of input frames fed to the resampler at once. Smaller values reduce the latency at the
+/// cost of a bit of overhead.
+const CHUNK_FRAMES: usize = 256;
+
+/// Length of the sinc filter
+const SINC_LEN: usize = 128;
+
+/// Speed changes up to this relative amount are applied to the running resampler, bigger ones
+/// (fast forward, slow motion...) rebuild it
+const MAX_SPEED_DEVIATION: f64 = 0.05;
+
+/// Dynamic rate control settings
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct RateControl {
+    /// Maximum relative deviation from the nominal ratio. 0.005 (0.5%) is well below what can be
+    /// heard as a pitch change.
+    pub max_deviation: f64,
+}
+
+impl RateControl {
+    /// Returns the relative ratio adjustment for a buffer containing `fill` samples when we'd
+    /// like it to contain `target` samples. If the buffer is running low we produce a bit more
+    /// output, if it's getting too full we produce a bit less.
+    pub fn adjustment(&self, fill: usize, target: usize) -> f64 {
+        if target == 0 {
+            return 1.;
+        }
+
+        let target = target as f64;
+        let delta = ((target - fill as f64) / target).clamp(-1., 1.);
+
+        1. + self.max_deviation * delta
+    }
+}
+
+impl Default for RateControl {
+    fn default() -> RateControl {
+        RateControl {
+            max_deviation: 0.005,
+        }
+    }
+}
+
+/// Stereo resampler from `NATIVE_RATE` to an arbitrary output rate
+pub struct OutputResampler {
+    output_rate: u32,
+    rate_control: Option<RateControl>,
+    /// Emulation speed relative to real time
+    speed: f64,
+    /// Relative adjustment currently applied by the rate control
+    adjustment: f64,
+    /// Sinc resampler, `None` if we're running in passthrough mode
+    resampler: Option<SincFixedIn<f32>>,
+    /// Input frames waiting for a full chunk, one vector per channel
+    pending: [Vec<f32>; 2],
+    /// Resampler output buffer, one vector per channel
+    output: [Vec<f32>; 2],
+    /// Fractional input frames carried over by `input_frames_for`
+    input_remainder: f64,
+}
+
+impl OutputResampler {
+    /// Create a new resampler producing samples at `output_rate`. The rate is clamped to
+    /// `MIN_OUTPUT_RATE..=MAX_OUTPUT_RATE`.
+    pub fn new(output_rate: u32) -> OutputResampler {
+        let mut r = OutputResampler {
+            output_rate: NATIVE_RATE,
+            rate_control: None,
+            speed: 1.,
+            adjustment: 1.,
+            resampler: None,
+            pending: [Vec::new(), Vec::new()],
+            output: [Vec::new(), Vec::new()],
+            input_remainder: 0.,
+        };
+
+        r.set_output_rate(output_rate);
+
+        r
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    /// Change the output rate. Any pending sample is discarded.
+    pub fn set_output_rate(&mut self, output_rate: u32) {
+        self.output_rate = output_rate.clamp(MIN_OUTPUT_RATE, MAX_OUTPUT_RATE);
+        self.rebuild();
+    }
+
+    pub fn rate_control(&self) -> Option<RateControl> {
+        self.rate_control
+    }
+
+    /// Enable or disable dynamic rate control. Any pending sample is discarded.
+    pub fn set_rate_control(&mut self, rate_control: Option<RateControl>) {
+        if rate_control != self.rate_control {
+            self.rate_control = rate_control;
+            self.rebuild();
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Set the emulation speed relative to real time. When the emulator runs faster it generates
+    /// more samples per second, we compensate for it so that the output still plays in real time.
+    ///
+    /// The display sync adjusts the speed continuously so small changes are ramped in without
+    /// interrupting the output. Bigger ones discard any pending sample.
+    pub fn set_speed(&mut self, speed: f64) {
+        if speed <= 0. || speed == self.speed {
+            return;
+        }
+
+        self.speed = speed;
+
+        let ratio = self.ratio();
+        let passthrough = self.can_passthrough();
+
+        let applied = match self.resampler.as_mut() {
+            Some(r) if !passthrough => r.set_resample_ratio(ratio, true).is_ok(),
+            _ => false,
+        };
+
+        if !applied {
+            self.rebuild();
+        }
+    }
+
+    /// Returns true if the samples are passed through untouched. That's the case when the output
+    /// rate is the native rate, the speed is 1 and the rate control is disabled.
+    pub fn is_passthrough(&self) -> bool {
+        self.resampler.is_none()
+    }
+
+    /// Returns the current ratio between the output and input rates, including the rate control
+    /// adjustment
+    pub fn ratio(&self) -> f64 {
+        self.nominal_ratio() * self.adjustment
+    }
+
+    /// Returns the ratio between the output and input rates, without rate control
+    pub fn nominal_ratio(&self) -> f64 {
+        f64::from(self.output_rate) / (f64::from(NATIVE_RATE) * self.speed)
+    }
+
+    /// Feed the current fill level of the audio buffer to the rate control. `fill` and `target`
+    /// are both in samples. Does nothing if the rate control is disabled.
+    pub fn update_fill(&mut self, fill: usize, target: usize) {
+        let nominal = self.nominal_ratio();
+
+        let (rc, resampler) = match (self.rate_control, self.resampler.as_mut()) {
+            (Some(rc), Some(r)) => (rc, r),
+            _ => return,
+        };
+
+        let adjustment = rc.adjustment(fill, target);
+
+        // Ramping the ratio over the chunk avoids audible steps. The adjustment is always well
+        // within the max ratio given at construction, if the resampler still refuses it we just
+        // keep the previous one.
+        if adjustment != self.adjustment
+            && resampler
+                .set_resample_ratio(nominal * adjustment, true)
+                .is_ok()
+        {
+            self.adjustment = adjustment;
+        }
+    }
+
+    /// Returns the number of native stereo frames to consume in order to generate `out_frames`
+    /// output frames at the current ratio. Rounding errors are carried over to the next call so
+    /// that the average is exact.
+    pub fn input_frames_for(&mut self, out_frames: usize) -> usize {
+        let exact = out_frames as f64 / self.ratio() + self.input_remainder;
+        // Don't let floating point errors round 43.99999 down
+        let frames = (exact + 1e-9).floor();
+
+        self.input_remainder = (exact - frames).max(0.);
+
+        frames as usize
+    }
+
+    /// Resample `input`, a buffer of interleaved stereo samples at `NATIVE_RATE`, and append the
+    /// result to `output`. The resampler works in fixed chunks so some of the input may be held
+    /// back until the next call.
+    ///
+    /// On error the chunks already resampled are still appended to `output`, the rest of the
+    /// input is kept pending.
+    pub fn process(&mut self, input: &[i16], output: &mut Vec<i16>) -> Result<(), ResampleError> {
+        let resampler = match self.resampler.as_mut() {
+            Some(r) => r,
+            None => {
+                output.extend_from_slice(input);
+                return Ok(());
+            }
+        };
+
+        for frame in input.chunks_exact(2) {
+            self.pending[0].push(f32::from(frame[0]) / 32768.);
+            self.pending[1].push(f32::from(frame[1]) / 32768.);
+        }
+
+        let mut consumed = 0;
+        let mut res = Ok(());
+
+        while self.pending[0].len() - consumed >= resampler.input_frames_next() {
+            let input = [&self.pending[0][consumed..], &self.pending[1][consumed..]];
+
+            match resampler.process_into_buffer(&input, &mut self.output, None) {
+                Ok((frames_in, frames_out)) => {
+                    consumed += frames_in;
+
+                    push_interleaved(&self.output, frames_out, output);
+                }
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+
+        for p in &mut self.pending {
+            p.drain(..consumed);
+        }
+
+        res
+    }
+
+    /// Push out all the samples still held in the resampler, padding the input with silence.
+    /// Meant to be called at the end of an offline render. The pending samples are discarded even
+    /// if resampling fails.
+    pub fn flush(&mut self, output: &mut Vec<i16>) -> Result<(), ResampleError> {
+        let resampler = match self.resampler.as_mut() {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        let pending = [&self.pending[0][..], &self.pending[1][..]];
+
+        let res = resampler
+            .process_partial_into_buffer(Some(&pending), &mut self.output, None)
+            .and_then(|(_, frames_out)| {
+                push_interleaved(&self.output, frames_out, output);
+
+                // And once more to flush the filter's delay line
+                resampler.process_partial_into_buffer(None::<&[&[f32]]>, &mut self.output, None)
+            })
+            .map(|(_, frames_out)| push_interleaved(&self.output, frames_out, output));
+
+        for p in &mut self.pending {
+            p.clear();
+        }
+
+        res
+    }
+
+    /// Discard any pending sample and reset the rate control
+    pub fn reset(&mut self) {
+        self.adjustment = 1.;
+        self.input_remainder = 0.;
+
+        for p in &mut self.pending {
+            p.clear();
+        }
+
+        if let Some(r) = self.resampler.as_mut() {
+            r.reset();
+        }
+    }
+
+    fn rebuild(&mut self) {
+        self.adjustment = 1.;
+        self.input_remainder = 0.;
+
+        for p in &mut self.pending {
+            p.clear();
+        }
+
+        if self.can_passthrough() {
+            self.resampler = None;
+            return;
+        }
+
+        let window = WindowFunction::BlackmanHarris2;
+        let params = SincInterpolationParameters {
+            sinc_len: SINC_LEN,
+            f_cutoff: calculate_cutoff(SINC_LEN, window),
+            oversampling_factor: 128,
+            interpolation: SincInterpolationType::Linear,
+            window,
+        };
+
+        // Leave some headroom for the rate control and the speed changes
+        let max_relative = match self.rate_control {
+            Some(rc) => 1. + rc.max_deviation * 2.,
+            None => 1.,
+        } * (1. + MAX_SPEED_DEVIATION);
+
+        // The output rate is clamped to a sane range so this can't fail
+        let resampler =
+            SincFixedIn::new(self.nominal_ratio(), max_relative, params, CHUNK_FRAMES, 2)
+                .expect("Couldn't create resampler");
+
+        for o in &mut self.output {
+            o.clear();
+            o.resize(resampler.output_frames_max(), 0.);
+        }
+
+        self.resampler = Some(resampler);
+    }
+
+    /// Returns true if the current settings don't need any resampling
+    fn can_passthrough(&self) -> bool {
+        self.output_rate == NATIVE_RATE && self.speed == 1. && self.rate_control.is_none()
+    }
+}
+
+/// Convert the first `frames` frames of `channels` back to interleaved 16bit samples and append
+/// them to `output`
+fn push_interleaved(channels: &[Vec<f32>; 2], frames: usize, output: &mut Vec<i16>) {
+    output.reserve(frames * 2);
+
+    for i in 0..frames {
+        for c in channels {
+            let s = (c[i] * 32768.).round().clamp(-32768., 32767.);
+
+            output.push(s as i16);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Generate `frames` stereo frames of a sine wave at `freq` Hz
+    fn sine(freq: f64, frames: usize) -> Vec<i16> {
+        (0..frames)
+            .flat_map(|i| {
+                let t = i as f64 / f64::from(NATIVE_RATE);
+                let s = ((t * freq * 2. * std::f64::consts::PI).sin() * 16384.) as i16;
+
+                [s, -s]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn passthrough() {
+        let mut r = OutputResampler::new(NATIVE_RATE);
+        assert!(r.is_passthrough());
+
+        let input = sine(440., 1000);
+        let mut output = Vec::new();
+        r.process(&input, &mut output).unwrap();
+
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn output_length() {
+        let mut r = OutputResampler::new(48_000);
+        assert!(!r.is_passthrough());
+
+        let input = sine(440., NATIVE_RATE as usize);
+        let mut output = Vec::new();
+
+        // Feed one frame's worth at a time like a frontend would
+        for chunk in input.chunks(735 * 2) {
+            r.process(chunk, &mut output).unwrap();
+        }
+        r.flush(&mut output).unwrap();
+
+        // Flushing pads the input with silence up to two full chunks
+        let frames = output.len() / 2;
+        assert!(
+            (48_000..48_000 + 3 * CHUNK_FRAMES).contains(&frames),
+            "{} frames",
+            frames
+        );
+
+        // Channels must not get swapped or mixed
+        assert!(output
+            .chunks_exact(2)
+            .all(|f| (f[0] as i32 + f[1] as i32).abs() <= 2));
+        // And the signal must survive
+        assert!(output.iter().any(|&s| s > 15000));
+    }
+
+    #[test]
+    fn rate_control() {
+        let rc = RateControl::default();
+
+        assert_eq!(rc.adjustment(1000, 1000), 1.);
+        assert_eq!(rc.adjustment(0, 1000), 1. + rc.max_deviation);
+        assert_eq!(rc.adjustment(5000, 1000), 1. - rc.max_deviation);
+        assert!(rc.adjustment(900, 1000) > 1.);
+        assert!(rc.adjustment(1100, 1000) < 1.);
+
+        let mut r = OutputResampler::new(48_000);
+        r.set_rate_control(Some(rc));
+
+        let nominal = r.ratio();
+        r.update_fill(0, 1000);
+        assert!(r.ratio() > nominal);
+        r.update_fill(2000, 1000);
+        assert!(r.ratio() < nominal);
+    }
+
+    #[test]
+    fn speed_compensation() {
+        let mut r = OutputResampler::new(NATIVE_RATE);
+
+        r.set_speed(1.01);
+        assert!(!r.is_passthrough());
+
+        let input = sine(440., NATIVE_RATE as usize);
+        let mut output = Vec::new();
+        r.process(&input, &mut output).unwrap();
+
+        // Running 1% faster we get 1% fewer samples out
+        let expected = (f64::from(NATIVE_RATE) / 1.01) as usize;
+        let frames = output.len() / 2;
+        assert!(frames <= expected && frames + 2 * CHUNK_FRAMES > expected);
+
+        r.set_speed(1.);
+        assert!(r.is_passthrough());
+    }
+
+    #[test]
+    fn small_speed_changes_keep_samples() {
+        let mut r = OutputResampler::new(48_000);
+
+        let input = sine(440., CHUNK_FRAMES / 2);
+        let mut output = Vec::new();
+        r.process(&input, &mut output).unwrap();
+        assert_eq!(r.pending[0].len(), CHUNK_FRAMES / 2);
+
+        r.set_speed(1.003);
+        assert_eq!(r.pending[0].len(), CHUNK_FRAMES / 2);
+        assert!((r.ratio() - 48_000. / (f64::from(NATIVE_RATE) * 1.003)).abs() < 1e-9);
+
+        // Fast forward rebuilds the resampler
+        r.set_speed(2.);
+        assert!(r.pending[0].is_empty());
+    }
+
+    #[test]
+    fn input_frames_remainder() {
+        let mut r = OutputResampler::new(48_000);
+
+        let total: usize = (0..60).map(|_| r.input_frames_for(800)).sum();
+
+        // 48000 output frames must consume exactly one second of input
+        assert_eq!(total, NATIVE_RATE as usize);
+    }
+}
@@ -291,82 +291,7 @@ impl FrameBuffer {
 // Audio Processing
 // ============================================================================
 
-pub struct AudioProcessor {
-    sample_rate: u32,
-    buffer: Vec<f32>,
-    resampler: Option<AudioResampler>,
-}
-
-impl AudioProcessor {
-    pub fn new(sample_rate: u32) -> Self {
-        Self {
-            sample_rate,
-            buffer: Vec::with_capacity(4096),
-            resampler: None,
-        }
-    }
-    
-    pub fn set_output_rate(&mut self, output_rate: u32) {
-        if output_rate != self.sample_rate {
-            self.resampler = Some(AudioResampler::new(self.sample_rate, output_rate));
-        }
-    }
-    
-    pub fn push_samples(&mut self, samples: &[i16]) {
-        for &sample in samples {
-            self.buffer.push(sample as f32 / 32768.0);
-        }
-    }
-    
-    pub fn get_output_samples(&mut self) -> Vec<f32> {
-        if let Some(resampler) = &mut self.resampler {
-            resampler.resample(&self.buffer)
-        } else {
-            self.buffer.clone()
-        }
-    }
-    
-    pub fn clear(&mut self) {
-        self.buffer.clear();
-    }
-}
-
-struct AudioResampler {
-    input_rate: u32,
-    output_rate: u32,
-    accumulator: f32,
-}
-
-impl AudioResampler {
-    fn new(input_rate: u32, output_rate: u32) -> Self {
-        Self {
-            input_rate,
-            output_rate,
-            accumulator: 0.0,
-        }
-    }
-    
-    fn resample(&mut self, input: &[f32]) -> Vec<f32> {
-        let ratio = self.input_rate as f32 / self.output_rate as f32;
-        let output_len = (input.len() as f32 / ratio) as usize;
-        let mut output = Vec::with_capacity(output_len);
-        
-        for i in 0..output_len {
-            let pos = i as f32 * ratio;
-            let index = pos as usize;
-            let frac = pos - index as f32;
-            
-            if index + 1 < input.len() {
-                let sample = input[index] * (1.0 - frac) + input[index + 1] * frac;
-                output.push(sample);
-            } else if index < input.len() {
-                output.push(input[index]);
-            }
-        }
-        
-        output
-    }
-}
+pub use crate::audio_processor::AudioProcessor;
 
 // ============================================================================
 // Memory Management Helpers
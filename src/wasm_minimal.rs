@@ -394,6 +394,26 @@ impl PsxEmulator {
         }
     }
 
+    /// Returns the state of the SPU voices, reverb and interrupt serialized as JSON
+    #[cfg(feature = "json")]
+    pub fn get_spu_snapshot(&self) -> Result<String, JsValue> {
+        match self.psx {
+            Some(ref psx) => Ok(psx.spu_snapshot().to_json()),
+            None => Err(JsValue::from_str("BIOS must be loaded first")),
+        }
+    }
+
+    /// Decode `block_count` ADPCM blocks starting at byte `address` in SPU RAM to 16bit PCM. The
+    /// count is clamped to the number of blocks in SPU RAM.
+    pub fn decode_spu_adpcm(&self, address: u32, block_count: u32) -> Result<Vec<i16>, JsValue> {
+        let block_count = (block_count as usize).min(spu::snapshot::MAX_ADPCM_BLOCKS);
+
+        match self.psx {
+            Some(ref psx) => Ok(psx.decode_spu_adpcm(address, block_count)),
+            None => Err(JsValue::from_str("BIOS must be loaded first")),
+        }
+    }
+
     pub fn get_frame_buffer(&self) -> Vec<u8> {
         self.frame_buffer.clone()
     }
@@ -31,10 +31,28 @@ mod bitwise;
 #[path = "box_array.rs"]
 mod box_array;
 
+#[path = "post_process/mod.rs"]
+mod post_process;
+
+#[path = "psx/display_sync.rs"]
+mod display_sync;
+
+#[path = "memory_card/filesystem.rs"]
+mod memory_card_fs;
+
+#[path = "resampler.rs"]
+mod resampler;
+
+#[path = "audio_processor.rs"]
+mod audio_processor;
+
 // Include test modules when testing
 #[cfg(test)]
 mod tests;
 
+#[cfg(test)]
+mod golden;
+
 // Performance dashboard module (commented out - module not yet implemented)
 // pub mod performance_dashboard;
 
@@ -64,7 +82,13 @@ pub struct PsxEmulator {
     context: CanvasRenderingContext2d,
     audio_context: Option<AudioContext>,
     frame_buffer: Vec<u8>,
-    audio_buffer: Vec<f32>,
+    /// Post-processing filters applied to the frames before they're displayed
+    post_process: post_process::FilterChain,
+    /// Locks the emulation speed to the browser's refresh rate
+    display_sync: display_sync::DisplaySync,
+    /// Timestamp of the last `requestAnimationFrame` callback, in milliseconds
+    last_host_frame: Option<f64>,
+    audio: audio_processor::AudioProcessor,
     input_state: InputState,
     running: RefCell<bool>,
     disc_loaded: RefCell<bool>,
@@ -107,6 +131,106 @@ impl InputState {
     }
 }
 
+/// Raw memory card image, used by the frontend to manage individual saves
+#[wasm_bindgen]
+pub struct MemoryCardImage {
+    card: memory_card_fs::Card,
+}
+
+#[wasm_bindgen]
+impl MemoryCardImage {
+    /// Create a freshly formatted memory card
+    pub fn new() -> MemoryCardImage {
+        MemoryCardImage {
+            card: memory_card_fs::Card::formatted(),
+        }
+    }
+
+    /// Load a raw memory card image (.mcr, .mcd...)
+    pub fn from_bytes(data: &[u8]) -> std::result::Result<MemoryCardImage, JsValue> {
+        let card = memory_card_fs::Card::new(data.to_vec()).map_err(|e| JsValue::from_str(&e))?;
+
+        Ok(MemoryCardImage { card })
+    }
+
+    /// Returns the raw memory card image
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.card.memory().to_vec()
+    }
+
+    /// Returns a JSON array describing the saves on the card, including the deleted ones that can
+    /// still be recovered. The other methods take the index of a save in this array.
+    #[cfg(feature = "json")]
+    pub fn list_saves(&self) -> String {
+        serde_json::to_string(&self.card.saves()).unwrap_or_else(|_| "[]".to_string())
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        self.card.free_blocks()
+    }
+
+    /// Returns animation frame `frame` of the icon of a save as 16x16 RGBA pixels
+    pub fn icon(&self, index: usize, frame: usize) -> Option<Vec<u8>> {
+        let save = self.card.saves().into_iter().nth(index)?;
+
+        self.card.icon(&save, frame)
+    }
+
+    /// Export a save in the MCS format
+    pub fn export_save(&self, index: usize) -> std::result::Result<Vec<u8>, JsValue> {
+        let save = self.save(index)?;
+
+        Ok(self.card.export_mcs(&save))
+    }
+
+    /// Import a save in the MCS format
+    pub fn import_save(&mut self, mcs: &[u8]) -> std::result::Result<(), JsValue> {
+        self.card.import_mcs(mcs).map_err(|e| {
+            console_error!("Can't import save: {}", e);
+            JsValue::from_str(&e)
+        })?;
+
+        Ok(())
+    }
+
+    pub fn delete_save(&mut self, index: usize) -> std::result::Result<(), JsValue> {
+        let save = self.save(index)?;
+
+        self.card
+            .delete(save.first_block())
+            .map_err(|e| JsValue::from_str(&e))
+    }
+
+    pub fn undelete_save(&mut self, index: usize) -> std::result::Result<(), JsValue> {
+        let save = self.save(index)?;
+
+        self.card
+            .undelete(save.first_block())
+            .map_err(|e| JsValue::from_str(&e))
+    }
+
+    /// Fix the inconsistencies of the filesystem, returns a description of every fix
+    pub fn repair(&mut self) -> Vec<String> {
+        let fixes = self.card.repair();
+
+        for fix in &fixes {
+            console_log!("Memory card repair: {}", fix);
+        }
+
+        fixes
+    }
+}
+
+impl MemoryCardImage {
+    fn save(&self, index: usize) -> std::result::Result<memory_card_fs::Save, JsValue> {
+        self.card
+            .saves()
+            .into_iter()
+            .nth(index)
+            .ok_or_else(|| JsValue::from_str(&format!("No save #{}", index)))
+    }
+}
+
 // CUE file parser
 struct CueParser;
 
@@ -259,6 +383,10 @@ impl PsxEmulator {
         let audio_context = web_sys::window()
             .and_then(|w| w.document())
             .and_then(|_| AudioContext::new().ok());
+        let output_rate = audio_context
+            .as_ref()
+            .map(|ctx| ctx.sample_rate() as u32)
+            .unwrap_or(resampler::NATIVE_RATE);
 
         Ok(PsxEmulator {
             psx,
@@ -266,7 +394,10 @@ impl PsxEmulator {
             context,
             audio_context,
             frame_buffer: vec![0; 640 * 480 * 4],
-            audio_buffer: Vec::with_capacity(4096),
+            post_process: post_process::FilterChain::new(),
+            display_sync: display_sync::DisplaySync::new(),
+            last_host_frame: None,
+            audio: audio_processor::AudioProcessor::with_output_rate(output_rate),
             input_state: InputState::new(),
             running: RefCell::new(false),
             disc_loaded: RefCell::new(false),
@@ -511,8 +642,32 @@ impl PsxEmulator {
     fn render_frame(&mut self) -> std::result::Result<(), JsValue> {
         self.psx.get_framebuffer(&mut self.frame_buffer);
 
-        let width = 640u32;
-        let height = 480u32;
+        let mut width = 640u32;
+        let mut height = 480u32;
+
+        if !self.post_process.is_empty() {
+            let pixels = self
+                .frame_buffer
+                .chunks_exact(4)
+                .map(|p| u32::from_be_bytes([0, p[0], p[1], p[2]]))
+                .collect();
+
+            let image = self.post_process.process(post_process::Image {
+                pixels,
+                width,
+                height,
+            });
+
+            width = image.width;
+            height = image.height;
+
+            self.frame_buffer.clear();
+            self.frame_buffer.extend(image.pixels.iter().flat_map(|&p| {
+                let [_, r, g, b] = p.to_be_bytes();
+
+                [r, g, b, 0xff]
+            }));
+        }
 
         // Debug: Check if we have any non-zero pixels
         static mut RENDER_COUNT: u32 = 0;
@@ -554,10 +709,59 @@ impl PsxEmulator {
     fn process_audio(&mut self) -> std::result::Result<(), JsValue> {
         // Audio processing disabled for now - would require more web-sys features
         // to be properly implemented
-        self.audio_buffer.clear();
+        self.audio.clear();
         Ok(())
     }
 
+    /// Set the post-processing filters from a comma-separated list such as `"ntsc,xbr,scanlines"`.
+    /// An empty string disables post-processing.
+    pub fn set_post_processing(&mut self, filters: &str) -> std::result::Result<(), JsValue> {
+        self.post_process = filters.parse().map_err(|e: String| {
+            console_error!("Invalid post-processing filters: {}", e);
+            JsValue::from_str(&e)
+        })?;
+
+        console_log!("Post-processing filters: [{}]", self.post_process);
+        Ok(())
+    }
+
+    pub fn get_post_processing(&self) -> String {
+        self.post_process.to_string()
+    }
+
+    /// Enable or disable locking the emulation speed to the display's refresh rate
+    pub fn set_display_sync(&mut self, enabled: bool) {
+        self.display_sync.set_enabled(enabled);
+    }
+
+    /// Must be called from every `requestAnimationFrame` callback with its timestamp, this is
+    /// used to measure the display's refresh rate
+    pub fn host_frame(&mut self, timestamp_ms: f64) {
+        if let Some(last) = self.last_host_frame {
+            let interval = (timestamp_ms - last) / 1000.;
+
+            if interval > 0. {
+                self.display_sync
+                    .update(std::time::Duration::from_secs_f64(interval));
+            }
+        }
+
+        self.last_host_frame = Some(timestamp_ms);
+        self.audio.set_speed(self.display_sync.speed());
+    }
+
+    /// Returns the emulation speed ratio currently applied to lock to the display, 1.0 if not
+    /// locked. When locked the caller should run exactly one frame per animation frame, otherwise
+    /// it should pace the emulation on the wall clock.
+    pub fn get_display_sync_speed(&self) -> f64 {
+        self.display_sync.speed()
+    }
+
+    /// Returns the measured display refresh rate in Hz, or 0 if it's not known yet
+    pub fn get_host_refresh_rate(&self) -> f64 {
+        self.display_sync.host_refresh().unwrap_or(0.)
+    }
+
     pub fn start(&mut self) {
         *self.running.borrow_mut() = true;
         console_log!("▶️ Emulator started");